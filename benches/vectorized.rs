@@ -0,0 +1,119 @@
+//! Row-at-a-time vs vectorized execution of scan-heavy queries.
+//!
+//! Loads `RUSTDB_BENCH_ROWS` rows (default 10,000,000) into a temporary database, then
+//! times each query with `vectorized_execution` off and on:
+//!
+//!     cargo bench --bench vectorized
+//!     RUSTDB_BENCH_ROWS=1000000 cargo bench --bench vectorized
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rustdb::query::udf::TableUdf;
+use rustdb::storage::{ColumnType, Value};
+use rustdb::{Config, Database, Result};
+
+/// Rows inserted per statement while loading.
+const CHUNK: i64 = 100_000;
+
+const QUERIES: &[&str] = &[
+    "SELECT count(*) FROM t",
+    "SELECT count(*), sum(x) FROM t WHERE x > 500.0 AND g <> 3",
+    "SELECT g, count(*), sum(id), max(x) FROM t WHERE id % 4 = 0 GROUP BY g",
+];
+
+/// `rows(start, n)`: rows `start .. start + n` of the benchmark table.
+struct Rows;
+
+impl TableUdf for Rows {
+    fn params(&self) -> &[ColumnType] {
+        &[ColumnType::Int, ColumnType::Int]
+    }
+
+    fn columns(&self) -> Vec<(String, ColumnType)> {
+        vec![
+            ("id".into(), ColumnType::Int),
+            ("g".into(), ColumnType::Int),
+            ("x".into(), ColumnType::Float),
+            ("name".into(), ColumnType::Text),
+        ]
+    }
+
+    fn call(&self, args: &[Value]) -> Result<Vec<Vec<Value>>> {
+        let (Value::Int(start), Value::Int(n)) = (&args[0], &args[1]) else {
+            return Ok(vec![]);
+        };
+        Ok((*start..start + n)
+            .map(|i| {
+                vec![
+                    Value::Int(i),
+                    Value::Int(i % 16),
+                    Value::Float((i % 1000) as f64 + 0.5),
+                    Value::Text(format!("row {}", i % 100)),
+                ]
+            })
+            .collect())
+    }
+}
+
+fn open(dir: &Path, vectorized: bool) -> Result<Arc<Database>> {
+    Database::open(Config {
+        data_dir: dir.to_string_lossy().into_owned(),
+        wal_sync: false,
+        autovacuum: false,
+        buffer_pool_size: 16_384,
+        vectorized_execution: vectorized,
+        ..Config::default()
+    })
+}
+
+/// Run every query twice, the first time to warm the buffer pool and the OS page cache,
+/// and keep the second run's time and rows.
+fn run(dir: &Path, vectorized: bool) -> Result<Vec<(Duration, Vec<Vec<Value>>)>> {
+    let db = open(dir, vectorized)?;
+    let mut s = db.session();
+    let mut out = Vec::new();
+    for sql in QUERIES {
+        s.execute(sql)?;
+        let start = Instant::now();
+        let mut rows = s.execute(sql)?.rows;
+        let elapsed = start.elapsed();
+        rows.sort_by_key(|r| format!("{:?}", r));
+        out.push((elapsed, rows));
+    }
+    Ok(out)
+}
+
+fn main() -> Result<()> {
+    let n: i64 = match std::env::var("RUSTDB_BENCH_ROWS") {
+        Ok(v) => v.parse()?,
+        Err(_) => 10_000_000,
+    };
+    let dir = tempfile::tempdir()?;
+    {
+        let db = open(dir.path(), false)?;
+        db.register_table_function("rows", Rows)?;
+        let mut s = db.session();
+        s.execute("CREATE TABLE t (id INT, g INT, x FLOAT, name TEXT)")?;
+        let start = Instant::now();
+        for chunk in (0..n).step_by(CHUNK as usize) {
+            s.execute(&format!("INSERT INTO t SELECT * FROM rows({}, {})", chunk, CHUNK.min(n - chunk)))?;
+        }
+        s.execute("ANALYZE t")?;
+        println!("loaded {} rows in {:.1?}", n, start.elapsed());
+    }
+    let rows = run(dir.path(), false)?;
+    let batches = run(dir.path(), true)?;
+    for (sql, ((row_time, expected), (batch_time, got))) in QUERIES.iter().zip(rows.iter().zip(&batches)) {
+        assert_eq!(got, expected, "{}", sql);
+        println!(
+            "{}\n    row executor {:>10.1?}   vectorized {:>10.1?}   speedup {:.2}x",
+            sql,
+            row_time,
+            batch_time,
+            row_time.as_secs_f64() / batch_time.as_secs_f64()
+        );
+    }
+    Ok(())
+}
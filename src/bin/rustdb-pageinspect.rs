@@ -0,0 +1,39 @@
+//! Page inspector: dump one page of a heap or B-tree index file.
+//! Usage: rustdb-pageinspect [--json] [--schema int,text,...] FILE PAGE_ID
+
+use anyhow::{bail, Result};
+use rustdb::storage::{inspect_file, parse_schema};
+use std::env;
+
+fn main() -> Result<()> {
+    let mut json = false;
+    let mut schema = None;
+    let mut positional = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--schema" => match args.next() {
+                Some(s) => schema = Some(parse_schema(&s)?),
+                None => bail!("--schema needs a value, e.g. --schema int,text"),
+            },
+            "-h" | "--help" => {
+                println!("usage: rustdb-pageinspect [--json] [--schema int,text,...] FILE PAGE_ID");
+                return Ok(());
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let (path, page_id) = match positional.as_slice() {
+        [path, page_id] => (path, page_id.parse::<u32>()?),
+        _ => bail!("usage: rustdb-pageinspect [--json] [--schema int,text,...] FILE PAGE_ID"),
+    };
+
+    let report = inspect_file(path, page_id, schema.as_deref())?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{}", report);
+    }
+    Ok(())
+}
@@ -1 +1,197 @@
-//! Buffer pool: page cache, LRU eviction, pin/unpin.
+//! Buffer pool: a page cache shared by every heap and index file of a database, with LRU
+//! eviction. Files write through it, so a cached page is never dirty.
+//!
+//! Page accesses are also counted per thread (found in the pool versus read from disk), so
+//! a statement can see how much I/O it caused; `EXPLAIN ANALYZE` reports these counts.
+
+use serde::Serialize;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::storage::{Page, PageId};
+
+/// Identifies one file registered with a pool.
+pub type FileId = u64;
+
+/// Page accesses: served from the buffer pool, or read from disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct IoCounters {
+    pub hits: u64,
+    pub reads: u64,
+}
+
+thread_local! {
+    static THREAD_IO: Cell<IoCounters> = const { Cell::new(IoCounters { hits: 0, reads: 0 }) };
+}
+
+impl IoCounters {
+    /// Page accesses made by the current thread so far.
+    pub fn current() -> Self {
+        THREAD_IO.with(Cell::get)
+    }
+
+    /// Accesses made between `earlier` and `self`.
+    pub fn since(self, earlier: Self) -> Self {
+        Self {
+            hits: self.hits - earlier.hits,
+            reads: self.reads - earlier.reads,
+        }
+    }
+
+    pub fn add(&mut self, other: Self) {
+        self.hits += other.hits;
+        self.reads += other.reads;
+    }
+
+    /// Record a page access by the current thread.
+    pub(crate) fn count(hit: bool) {
+        THREAD_IO.with(|c| {
+            let mut io = c.get();
+            if hit {
+                io.hits += 1;
+            } else {
+                io.reads += 1;
+            }
+            c.set(io);
+        });
+    }
+}
+
+#[derive(Default)]
+struct Frames {
+    /// Cached pages and the tick of their last use.
+    pages: HashMap<(FileId, PageId), (Page, u64)>,
+    /// Last-use tick → page; the first entry is the eviction victim.
+    lru: BTreeMap<u64, (FileId, PageId)>,
+    tick: u64,
+}
+
+/// Cache of up to `capacity` pages.
+pub struct BufferPool {
+    capacity: usize,
+    frames: Mutex<Frames>,
+    next_file: AtomicU64,
+    hits: AtomicU64,
+    reads: AtomicU64,
+}
+
+impl BufferPool {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            frames: Mutex::new(Frames::default()),
+            next_file: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            reads: AtomicU64::new(0),
+        }
+    }
+
+    /// A fresh id for a file whose pages will be cached here.
+    pub fn register_file(&self) -> FileId {
+        self.next_file.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// The cached copy of a page, marking it recently used.
+    pub fn get(&self, file: FileId, page_id: PageId) -> Option<Page> {
+        let mut f = self.frames.lock().unwrap();
+        f.tick += 1;
+        let tick = f.tick;
+        let Some((page, used)) = f.pages.get_mut(&(file, page_id)) else {
+            drop(f);
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        let (page, old) = (page.clone(), std::mem::replace(used, tick));
+        f.lru.remove(&old);
+        f.lru.insert(tick, (file, page_id));
+        drop(f);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(page)
+    }
+
+    /// Cache the current contents of a page, evicting the least recently used page if full.
+    pub fn put(&self, file: FileId, page_id: PageId, page: &Page) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut f = self.frames.lock().unwrap();
+        f.tick += 1;
+        let tick = f.tick;
+        if let Some((_, old)) = f.pages.insert((file, page_id), (page.clone(), tick)) {
+            f.lru.remove(&old);
+        }
+        f.lru.insert(tick, (file, page_id));
+        while f.pages.len() > self.capacity {
+            let (_, victim) = f.lru.pop_first().expect("lru tracks every cached page");
+            f.pages.remove(&victim);
+        }
+    }
+
+    /// Drop the cached pages of `file` numbered `first` or higher.
+    pub fn forget(&self, file: FileId, first: PageId) {
+        let mut f = self.frames.lock().unwrap();
+        let Frames { pages, lru, .. } = &mut *f;
+        pages.retain(|&(fid, pid), (_, tick)| {
+            let keep = fid != file || pid < first;
+            if !keep {
+                lru.remove(tick);
+            }
+            keep
+        });
+    }
+
+    /// Number of cached pages.
+    pub fn len(&self) -> usize {
+        self.frames.lock().unwrap().pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Lookups served from the pool and lookups that had to go to disk, since creation.
+    pub fn counters(&self) -> IoCounters {
+        IoCounters {
+            hits: self.hits.load(Ordering::Relaxed),
+            reads: self.reads.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::PageFlags;
+
+    fn page(id: PageId) -> Page {
+        Page::new(id, PageFlags::Heap)
+    }
+
+    #[test]
+    fn lru_eviction_and_counters() {
+        let pool = BufferPool::new(2);
+        let (a, b) = (pool.register_file(), pool.register_file());
+        assert!(pool.get(a, 0).is_none());
+        pool.put(a, 0, &page(0));
+        pool.put(b, 0, &page(0));
+        assert!(pool.get(a, 0).is_some());
+        // b/0 is now the least recently used page.
+        pool.put(a, 1, &page(1));
+        assert_eq!(pool.len(), 2);
+        assert!(pool.get(b, 0).is_none());
+        assert!(pool.get(a, 0).is_some() && pool.get(a, 1).is_some());
+        assert_eq!(pool.counters(), IoCounters { hits: 3, reads: 2 });
+
+        pool.forget(a, 1);
+        assert_eq!(pool.len(), 1);
+        assert!(pool.get(a, 1).is_none());
+        pool.forget(a, 0);
+        assert!(pool.is_empty());
+    }
+}
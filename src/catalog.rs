@@ -1 +1,685 @@
 //! Schema and catalog: tables, columns, types.
+//! Persisted as JSON in `catalog.json` under the data directory; rewritten atomically
+//! (write to a temp file, then rename) on every change.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::storage::{ColumnType, Value, ROW_FORMAT};
+
+pub const CATALOG_FILE: &str = "catalog.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ColumnDef {
+    pub name: String,
+    pub ty: ColumnType,
+    /// Left out of `SELECT *` (the implicit `rowid` key of tables without a primary key).
+    #[serde(default)]
+    pub hidden: bool,
+    /// Declared NOT NULL. The key column is NOT NULL regardless.
+    #[serde(default)]
+    pub not_null: bool,
+    /// Set for an identity (or SERIAL) column, filled from a sequence when INSERT leaves
+    /// it out.
+    #[serde(default)]
+    pub identity: Option<Identity>,
+    /// The DEFAULT expression as written, filled in when INSERT leaves the column out or
+    /// writes DEFAULT for it.
+    #[serde(default)]
+    pub default: Option<String>,
+}
+
+impl ColumnDef {
+    pub fn new(name: impl Into<String>, ty: ColumnType) -> Self {
+        Self {
+            name: name.into(),
+            ty,
+            hidden: false,
+            not_null: false,
+            identity: None,
+            default: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TableDef {
+    pub name: String,
+    pub columns: Vec<ColumnDef>,
+    /// Column holding the i64 row key the primary index is built on.
+    pub key_col: usize,
+    #[serde(default)]
+    pub constraints: Vec<Constraint>,
+    /// In name order, the order they fire in.
+    #[serde(default)]
+    pub triggers: Vec<TriggerDef>,
+}
+
+impl TableDef {
+    pub fn schema(&self) -> Vec<ColumnType> {
+        self.columns.iter().map(|c| c.ty).collect()
+    }
+
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|c| c.name == name)
+    }
+
+    pub fn constraint(&self, name: &str) -> Option<&Constraint> {
+        self.constraints.iter().find(|c| c.name == name)
+    }
+
+    pub fn trigger(&self, name: &str) -> Option<&TriggerDef> {
+        self.triggers.iter().find(|t| t.name == name)
+    }
+}
+
+/// Where an identity column gets its values.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identity {
+    /// The sequence, owned by the column's table.
+    pub sequence: String,
+    /// GENERATED ALWAYS: INSERT and UPDATE may not set the column. Otherwise (GENERATED BY
+    /// DEFAULT, SERIAL) the sequence only fills it in when INSERT leaves it out.
+    pub always: bool,
+}
+
+/// A UNIQUE, CHECK or FOREIGN KEY constraint of a table. NOT NULL is a flag of the column,
+/// and the primary key is the table's key column.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Constraint {
+    pub name: String,
+    pub kind: ConstraintKind,
+    /// May be checked at commit instead (see `SET CONSTRAINTS`).
+    #[serde(default)]
+    pub deferrable: bool,
+    /// Checked at commit unless `SET CONSTRAINTS` says otherwise.
+    #[serde(default)]
+    pub initially_deferred: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConstraintKind {
+    /// No two rows have the same values in `columns` unless one of them is NULL. Backed by
+    /// a unique index, a table of its own (see `query::constraint`).
+    Unique { columns: Vec<usize> },
+    /// `expr`, SQL over the table's columns, is not false for any row.
+    Check { expr: String },
+    /// Unless one of them is NULL, the values of `columns` are those of `parent_columns`
+    /// (its key column or the columns of one of its UNIQUE constraints) in a row of `parent`.
+    ForeignKey {
+        columns: Vec<usize>,
+        parent: String,
+        parent_columns: Vec<usize>,
+        on_delete: ReferentialAction,
+    },
+}
+
+/// What deleting a row of the parent of a foreign key does to the rows referencing it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReferentialAction {
+    /// Fail, unless no row references it by the time the constraint is checked.
+    #[default]
+    NoAction,
+    /// Fail right away.
+    Restrict,
+    /// Delete them too.
+    Cascade,
+    /// Set their referencing columns to NULL.
+    SetNull,
+}
+
+/// A trigger of a table: what runs, inside the changing statement's transaction, as rows
+/// of the table are inserted, updated or deleted (see `query::trigger`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TriggerDef {
+    pub name: String,
+    pub timing: TriggerTiming,
+    /// The events it fires on, in the order CREATE TRIGGER named them.
+    pub events: Vec<TriggerEvent>,
+    /// Fired for each row changed, instead of once per statement.
+    pub for_each_row: bool,
+    pub body: TriggerBody,
+}
+
+impl TriggerDef {
+    pub fn fires_on(&self, event: TriggerEvent) -> bool {
+        self.events.contains(&event)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TriggerTiming {
+    Before,
+    After,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TriggerEvent {
+    Insert,
+    Update,
+    Delete,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TriggerBody {
+    /// `;`-separated statements, as SQL text, in which `OLD.column` and `NEW.column` stand
+    /// for values of the row.
+    Sql(String),
+    /// A trigger function registered from Rust (see `Database::register_trigger_function`).
+    Function(String),
+}
+
+/// A sequence: a counter handing out INT values from `start` in steps of `increment`,
+/// outside of any transaction. How far it got is kept in the WAL, not here (see
+/// `sequence`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SequenceDef {
+    pub name: String,
+    pub start: i64,
+    /// Never zero; negative for a descending sequence.
+    pub increment: i64,
+    pub min_value: i64,
+    pub max_value: i64,
+    /// Values allocated at a time: logging the end of a block is what makes them durable.
+    pub cache: i64,
+    /// Start over at the other bound once past one, instead of failing.
+    pub cycle: bool,
+    /// The table it belongs to (that of an identity column, or from OWNED BY), which takes
+    /// it along when dropped.
+    #[serde(default)]
+    pub owned_by: Option<String>,
+}
+
+/// Planner statistics for one column, gathered by ANALYZE from a row sample.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ColumnStats {
+    /// Fraction of rows where the column is NULL.
+    pub null_frac: f64,
+    /// Estimated number of distinct non-NULL values.
+    pub n_distinct: f64,
+    /// Average encoded width of non-NULL values, in bytes.
+    pub avg_width: f64,
+    /// Most common values with their frequencies (fraction of all rows), most common first.
+    pub mcv: Vec<(Value, f64)>,
+    /// Equi-depth histogram bounds over the non-NULL values not in `mcv`, ascending. Each
+    /// adjacent pair bounds an equal share of those rows.
+    pub histogram: Vec<Value>,
+}
+
+/// Planner statistics for one table, in column order of its definition.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TableStats {
+    pub row_count: u64,
+    pub pages: u32,
+    pub columns: Vec<ColumnStats>,
+}
+
+/// Relative change in a table's row count or a column's number of distinct values (or,
+/// absolute, in its NULL fraction) that makes new statistics significantly different.
+pub const STATS_CHANGE_THRESHOLD: f64 = 0.1;
+
+impl TableStats {
+    /// Whether plans built on these statistics may be badly off for a table described by
+    /// `newer`.
+    pub fn differs_significantly(&self, newer: &TableStats) -> bool {
+        let moved = |old: f64, new: f64| (new - old).abs() > STATS_CHANGE_THRESHOLD * old.max(1.0);
+        moved(self.row_count as f64, newer.row_count as f64)
+            || self.columns.len() != newer.columns.len()
+            || self.columns.iter().zip(&newer.columns).any(|(old, new)| {
+                moved(old.n_distinct, new.n_distinct) || (new.null_frac - old.null_frac).abs() > STATS_CHANGE_THRESHOLD
+            })
+    }
+}
+
+/// A view: a stored query, planned wherever the view is read. A materialized view instead
+/// keeps the query's rows in a table of the same name until it is refreshed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ViewDef {
+    pub name: String,
+    /// The SELECT, as SQL text.
+    pub query: String,
+    /// Output column names, from the query or the column list of CREATE VIEW.
+    pub columns: Vec<String>,
+    #[serde(default)]
+    pub materialized: bool,
+    /// Set for a materialized view kept up to date as its base table changes.
+    #[serde(default)]
+    pub incremental: Option<IncrementalView>,
+    /// Tables and views the query reads; none of them can be dropped while this view exists.
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+}
+
+/// What an incrementally maintained materialized view computes: one row per group of
+/// `base` rows, with counts and sums that changes to `base` adjust in place.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IncrementalView {
+    pub base: String,
+    /// What each visible column of the view holds, in order.
+    pub columns: Vec<ViewColumn>,
+}
+
+/// One column of an incrementally maintained view, over columns of its base table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ViewColumn {
+    /// A GROUP BY column.
+    Group(usize),
+    CountStar,
+    Count(usize),
+    Sum(usize),
+}
+
+/// All table, view and sequence definitions of one database, with the statistics ANALYZE
+/// collected.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Catalog {
+    /// Format of the rows in the database's table files; 0 if written by a build that did
+    /// not record it.
+    #[serde(default)]
+    row_format: u32,
+    tables: BTreeMap<String, TableDef>,
+    #[serde(default)]
+    stats: BTreeMap<String, TableStats>,
+    #[serde(default)]
+    views: BTreeMap<String, ViewDef>,
+    #[serde(default)]
+    sequences: BTreeMap<String, SequenceDef>,
+}
+
+impl Default for Catalog {
+    fn default() -> Self {
+        Self {
+            row_format: ROW_FORMAT,
+            tables: BTreeMap::new(),
+            stats: BTreeMap::new(),
+            views: BTreeMap::new(),
+            sequences: BTreeMap::new(),
+        }
+    }
+}
+
+impl Catalog {
+    pub fn path(dir: &Path) -> PathBuf {
+        dir.join(CATALOG_FILE)
+    }
+
+    /// Load the catalog of `dir`; a missing file is an empty catalog. Fails if the rows of
+    /// the database are in another format than this build's.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = Self::path(dir);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let s = std::fs::read_to_string(&path)?;
+        let catalog: Self = serde_json::from_str(&s).with_context(|| format!("parsing {}", path.display()))?;
+        match catalog.row_format {
+            ROW_FORMAT => Ok(catalog),
+            0 => bail!(
+                "data directory {} was written by a build that did not record its row format; this build \
+                 reads row format {}",
+                dir.display(),
+                ROW_FORMAT
+            ),
+            found => bail!(
+                "data directory {} holds rows in format {}, but this build reads row format {}",
+                dir.display(),
+                found,
+                ROW_FORMAT
+            ),
+        }
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let path = Self::path(dir);
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    pub fn add_table(&mut self, def: TableDef) -> Result<()> {
+        if self.tables.contains_key(&def.name) {
+            bail!("table {} already exists", def.name);
+        }
+        if self.views.contains_key(&def.name) {
+            bail!("view {} already exists", def.name);
+        }
+        if self.sequences.contains_key(&def.name) {
+            bail!("sequence {} already exists", def.name);
+        }
+        self.tables.insert(def.name.clone(), def);
+        Ok(())
+    }
+
+    pub fn remove_table(&mut self, name: &str) -> Result<TableDef> {
+        match self.tables.remove(name) {
+            Some(def) => {
+                self.stats.remove(name);
+                Ok(def)
+            }
+            None => bail!("table {} does not exist", name),
+        }
+    }
+
+    pub fn table(&self, name: &str) -> Option<&TableDef> {
+        self.tables.get(name)
+    }
+
+    /// Tables in name order.
+    pub fn tables(&self) -> impl Iterator<Item = &TableDef> {
+        self.tables.values()
+    }
+
+    /// The foreign keys referencing table `name`, with the tables they belong to.
+    pub fn references_to<'a>(&'a self, name: &'a str) -> impl Iterator<Item = (&'a TableDef, &'a Constraint)> {
+        self.tables.values().flat_map(move |t| {
+            t.constraints
+                .iter()
+                .filter(move |c| matches!(&c.kind, ConstraintKind::ForeignKey { parent, .. } if parent == name))
+                .map(move |c| (t, c))
+        })
+    }
+
+    pub fn set_stats(&mut self, name: &str, stats: TableStats) -> Result<()> {
+        if !self.tables.contains_key(name) {
+            bail!("table {} does not exist", name);
+        }
+        self.stats.insert(name.to_string(), stats);
+        Ok(())
+    }
+
+    pub fn stats(&self, name: &str) -> Option<&TableStats> {
+        self.stats.get(name)
+    }
+
+    /// Add a view; a materialized one must come after the table holding its rows.
+    pub fn add_view(&mut self, def: ViewDef) -> Result<()> {
+        if self.views.contains_key(&def.name) {
+            bail!("view {} already exists", def.name);
+        }
+        if self.sequences.contains_key(&def.name) {
+            bail!("sequence {} already exists", def.name);
+        }
+        if self.tables.contains_key(&def.name) != def.materialized {
+            match def.materialized {
+                true => bail!("materialized view {} has no table", def.name),
+                false => bail!("table {} already exists", def.name),
+            }
+        }
+        self.views.insert(def.name.clone(), def);
+        Ok(())
+    }
+
+    /// Remove a view, leaving the table of a materialized one to the caller.
+    pub fn remove_view(&mut self, name: &str) -> Result<ViewDef> {
+        match self.views.remove(name) {
+            Some(def) => Ok(def),
+            None => bail!("view {} does not exist", name),
+        }
+    }
+
+    pub fn view(&self, name: &str) -> Option<&ViewDef> {
+        self.views.get(name)
+    }
+
+    /// Views in name order.
+    pub fn views(&self) -> impl Iterator<Item = &ViewDef> {
+        self.views.values()
+    }
+
+    /// A view other than `name` itself that reads table or view `name`.
+    pub fn dependent_view(&self, name: &str) -> Option<&ViewDef> {
+        self.views().find(|v| v.name != name && v.depends_on.iter().any(|d| d == name))
+    }
+
+    /// Add a sequence; names are shared with tables and views.
+    pub fn add_sequence(&mut self, def: SequenceDef) -> Result<()> {
+        if self.sequences.contains_key(&def.name) {
+            bail!("sequence {} already exists", def.name);
+        }
+        if self.tables.contains_key(&def.name) || self.views.contains_key(&def.name) {
+            bail!("relation {} already exists", def.name);
+        }
+        self.sequences.insert(def.name.clone(), def);
+        Ok(())
+    }
+
+    pub fn remove_sequence(&mut self, name: &str) -> Result<SequenceDef> {
+        match self.sequences.remove(name) {
+            Some(def) => Ok(def),
+            None => bail!("sequence {} does not exist", name),
+        }
+    }
+
+    pub fn sequence(&self, name: &str) -> Option<&SequenceDef> {
+        self.sequences.get(name)
+    }
+
+    /// Sequences in name order.
+    pub fn sequences(&self) -> impl Iterator<Item = &SequenceDef> {
+        self.sequences.values()
+    }
+
+    /// Add a trigger to table `table`; trigger names are per table.
+    pub fn add_trigger(&mut self, table: &str, def: TriggerDef) -> Result<()> {
+        let Some(t) = self.tables.get_mut(table) else {
+            bail!("table {} does not exist", table);
+        };
+        let at = match t.triggers.binary_search_by(|other| other.name.cmp(&def.name)) {
+            Ok(_) => bail!("trigger {} for relation {} already exists", def.name, table),
+            Err(at) => at,
+        };
+        t.triggers.insert(at, def);
+        Ok(())
+    }
+
+    pub fn remove_trigger(&mut self, table: &str, name: &str) -> Result<TriggerDef> {
+        let Some(t) = self.tables.get_mut(table) else {
+            bail!("table {} does not exist", table);
+        };
+        match t.triggers.iter().position(|other| other.name == name) {
+            Some(at) => Ok(t.triggers.remove(at)),
+            None => bail!("trigger {} for table {} does not exist", name, table),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(Catalog::load(dir.path()).unwrap().tables().count(), 0);
+        let mut c = Catalog::default();
+        let def = TableDef {
+            name: "users".into(),
+            columns: vec![
+                ColumnDef::new("id", ColumnType::Int),
+                ColumnDef::new("name", ColumnType::Text),
+            ],
+            key_col: 0,
+            constraints: vec![Constraint {
+                name: "users_name_key".into(),
+                kind: ConstraintKind::Unique { columns: vec![1] },
+                deferrable: false,
+                initially_deferred: false,
+            }],
+            triggers: Vec::new(),
+        };
+        c.add_table(def.clone()).unwrap();
+        assert!(c.add_table(def.clone()).is_err());
+        let stats = TableStats {
+            row_count: 2,
+            pages: 1,
+            columns: vec![
+                ColumnStats { n_distinct: 2.0, ..Default::default() },
+                ColumnStats {
+                    null_frac: 0.5,
+                    mcv: vec![(Value::Text("a".into()), 0.5)],
+                    ..Default::default()
+                },
+            ],
+        };
+        c.set_stats("users", stats.clone()).unwrap();
+        assert!(c.set_stats("nope", stats.clone()).is_err());
+        c.save(dir.path()).unwrap();
+        let mut c = Catalog::load(dir.path()).unwrap();
+        assert_eq!(c.table("users"), Some(&def));
+        assert_eq!(c.stats("users"), Some(&stats));
+        assert_eq!(def.column_index("name"), Some(1));
+        assert!(def.constraint("users_name_key").is_some());
+        c.remove_table("users").unwrap();
+        assert_eq!(c.stats("users"), None);
+    }
+
+    #[test]
+    fn views_share_names_with_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut c = Catalog::default();
+        let table = |name: &str| TableDef {
+            name: name.into(),
+            columns: vec![ColumnDef::new("id", ColumnType::Int)],
+            key_col: 0,
+            constraints: Vec::new(),
+            triggers: Vec::new(),
+        };
+        let view = |name: &str, materialized| ViewDef {
+            name: name.into(),
+            query: "SELECT id FROM t".into(),
+            columns: vec!["id".into()],
+            materialized,
+            incremental: None,
+            depends_on: vec!["t".into()],
+        };
+        c.add_table(table("t")).unwrap();
+        assert!(c.add_view(view("t", false)).is_err());
+        c.add_view(view("v", false)).unwrap();
+        assert!(c.add_table(table("v")).is_err());
+        assert!(c.add_view(view("m", true)).is_err());
+        c.add_table(table("m")).unwrap();
+        let mut m = view("m", true);
+        m.incremental = Some(IncrementalView {
+            base: "t".into(),
+            columns: vec![ViewColumn::Group(0), ViewColumn::CountStar],
+        });
+        c.add_view(m.clone()).unwrap();
+        c.save(dir.path()).unwrap();
+        let mut c = Catalog::load(dir.path()).unwrap();
+        assert_eq!(c.view("m"), Some(&m));
+        assert_eq!(c.views().map(|v| v.name.as_str()).collect::<Vec<_>>(), ["m", "v"]);
+        c.remove_view("v").unwrap();
+        assert!(c.remove_view("v").is_err());
+    }
+
+    #[test]
+    fn sequences_share_names_with_tables_and_views() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut c = Catalog::default();
+        let seq = |name: &str| SequenceDef {
+            name: name.into(),
+            start: 1,
+            increment: 1,
+            min_value: 1,
+            max_value: i64::MAX,
+            cache: 32,
+            cycle: false,
+            owned_by: Some("t".into()),
+        };
+        let mut id = ColumnDef::new("id", ColumnType::Int);
+        id.identity = Some(Identity {
+            sequence: "t_id_seq".into(),
+            always: true,
+        });
+        let def = TableDef {
+            name: "t".into(),
+            columns: vec![id],
+            key_col: 0,
+            constraints: Vec::new(),
+            triggers: Vec::new(),
+        };
+        c.add_sequence(seq("t_id_seq")).unwrap();
+        c.add_table(def.clone()).unwrap();
+        assert!(c.add_sequence(seq("t_id_seq")).is_err());
+        assert!(c.add_sequence(seq("t")).is_err());
+        c.add_sequence(seq("s")).unwrap();
+        assert!(c.add_table(TableDef { name: "s".into(), ..def.clone() }).is_err());
+        c.save(dir.path()).unwrap();
+        let mut c = Catalog::load(dir.path()).unwrap();
+        assert_eq!(c.table("t"), Some(&def));
+        assert_eq!(c.sequence("t_id_seq"), Some(&seq("t_id_seq")));
+        assert_eq!(c.sequences().map(|s| s.name.as_str()).collect::<Vec<_>>(), ["s", "t_id_seq"]);
+        c.remove_sequence("s").unwrap();
+        assert!(c.remove_sequence("s").is_err());
+    }
+
+    #[test]
+    fn triggers_belong_to_their_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut c = Catalog::default();
+        let trigger = |name: &str| TriggerDef {
+            name: name.into(),
+            timing: TriggerTiming::After,
+            events: vec![TriggerEvent::Insert, TriggerEvent::Delete],
+            for_each_row: true,
+            body: TriggerBody::Function("audit".into()),
+        };
+        c.add_table(TableDef {
+            name: "t".into(),
+            columns: vec![ColumnDef::new("id", ColumnType::Int)],
+            key_col: 0,
+            constraints: Vec::new(),
+            triggers: Vec::new(),
+        })
+        .unwrap();
+        c.add_trigger("t", trigger("b")).unwrap();
+        c.add_trigger("t", trigger("a")).unwrap();
+        assert!(c.add_trigger("t", trigger("a")).is_err());
+        assert!(c.add_trigger("nope", trigger("a")).is_err());
+        c.save(dir.path()).unwrap();
+        let mut c = Catalog::load(dir.path()).unwrap();
+        let def = c.table("t").unwrap();
+        assert_eq!(def.triggers.iter().map(|t| t.name.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+        assert!(def.trigger("b").unwrap().fires_on(TriggerEvent::Delete));
+        assert!(!def.trigger("b").unwrap().fires_on(TriggerEvent::Update));
+        assert_eq!(c.remove_trigger("t", "a").unwrap(), trigger("a"));
+        let err = c.remove_trigger("t", "a").unwrap_err();
+        assert_eq!(err.to_string(), "trigger a for table t does not exist");
+    }
+
+    #[test]
+    fn significant_statistics_changes() {
+        let stats = |row_count, n_distinct, null_frac| TableStats {
+            row_count,
+            pages: 10,
+            columns: vec![ColumnStats {
+                n_distinct,
+                null_frac,
+                ..Default::default()
+            }],
+        };
+        let old = stats(1000, 50.0, 0.0);
+        assert!(!old.differs_significantly(&stats(1050, 53.0, 0.05)));
+        assert!(old.differs_significantly(&stats(1200, 50.0, 0.0)));
+        assert!(old.differs_significantly(&stats(1000, 30.0, 0.0)));
+        assert!(old.differs_significantly(&stats(1000, 50.0, 0.2)));
+        assert!(stats(0, 0.0, 0.0).differs_significantly(&stats(5, 5.0, 0.0)));
+    }
+
+    #[test]
+    fn row_format_must_match() {
+        let dir = tempfile::tempdir().unwrap();
+        Catalog::default().save(dir.path()).unwrap();
+        Catalog::load(dir.path()).unwrap();
+        let path = Catalog::path(dir.path());
+        std::fs::write(&path, r#"{"tables": {}}"#).unwrap();
+        let err = Catalog::load(dir.path()).unwrap_err();
+        assert!(err.to_string().contains("did not record its row format"), "{}", err);
+        std::fs::write(&path, r#"{"row_format": 2, "tables": {}}"#).unwrap();
+        let err = Catalog::load(dir.path()).unwrap_err();
+        assert!(err.to_string().contains("holds rows in format 2"), "{}", err);
+    }
+}
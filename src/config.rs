@@ -25,6 +25,57 @@ pub struct Config {
 
     /// Data directory (heap, WAL, catalog). Default ".".
     pub data_dir: String,
+
+    /// How long a transaction waits for a lock before failing, in ms. Default 10000.
+    pub lock_timeout_ms: u64,
+
+    /// How often lock waiters check the waits-for graph for deadlocks, in ms. Default 100.
+    pub deadlock_check_ms: u64,
+
+    /// Run the background autovacuum task. Default true.
+    pub autovacuum: bool,
+
+    /// Pause between autovacuum passes over the tables, in ms. Default 60000.
+    pub autovacuum_naptime_ms: u64,
+
+    /// Dead versions a table needs before autovacuum processes it, on top of the scale
+    /// factor. Default 50.
+    pub autovacuum_vacuum_threshold: u64,
+
+    /// Fraction of a table's versions that must be dead, added to the threshold. Default 0.2.
+    pub autovacuum_vacuum_scale_factor: f64,
+
+    /// Memory one query operator (hash table, sort) may use before spilling to temporary
+    /// files, in KB. Default 4096.
+    pub work_mem_kb: usize,
+
+    /// Most steps a `WITH RECURSIVE` query may take before it fails, so a cycle under
+    /// UNION ALL ends in an error instead of running forever. 0 means no limit. Default
+    /// 10000.
+    pub max_recursion_depth: u64,
+
+    /// Run scans, filters, projections, hash joins and hash aggregates on columnar batches
+    /// of rows instead of one row at a time (see `query::vector`). Default false.
+    pub vectorized_execution: bool,
+
+    /// Most threads one scan, aggregate or hash-join build may be split across; 1 means
+    /// no parallel plans. Sessions may change it with `SET parallel_workers`. Default 1.
+    pub parallel_workers: usize,
+
+    /// Pages a table needs before a scan of it is split across workers. Default 128.
+    pub parallel_min_pages: u32,
+
+    /// How long a statement may run before it is cancelled, in milliseconds. 0 means no
+    /// limit. Default 0.
+    pub statement_timeout_ms: u64,
+
+    /// Memory the operators of one statement may hold together, in KB, past which it fails
+    /// instead of exhausting the process. 0 means no limit. Default 0.
+    pub query_memory_limit_kb: usize,
+
+    /// Generic plans kept for statements sent as SQL text, by normalized statement (see
+    /// `query::plan_cache`). 0 turns the cache off. Default 1000.
+    pub plan_cache_size: usize,
 }
 
 impl Default for Config {
@@ -36,6 +87,20 @@ impl Default for Config {
             listen_addr: "127.0.0.1:7643".to_string(),
             max_connections: 16,
             data_dir: ".".to_string(),
+            lock_timeout_ms: 10_000,
+            deadlock_check_ms: 100,
+            autovacuum: true,
+            autovacuum_naptime_ms: 60_000,
+            autovacuum_vacuum_threshold: 50,
+            autovacuum_vacuum_scale_factor: 0.2,
+            work_mem_kb: 4096,
+            max_recursion_depth: 10_000,
+            vectorized_execution: false,
+            parallel_workers: 1,
+            parallel_min_pages: 128,
+            statement_timeout_ms: 0,
+            query_memory_limit_kb: 0,
+            plan_cache_size: 1000,
         }
     }
 }
@@ -59,12 +124,27 @@ impl Config {
     }
 
     fn validate(&self) -> Result<()> {
-        if self.page_size == 0 || self.page_size % 256 != 0 {
+        if self.page_size == 0 || !self.page_size.is_multiple_of(256) {
             anyhow::bail!("page_size must be a positive multiple of 256");
         }
         if self.buffer_pool_size == 0 {
             anyhow::bail!("buffer_pool_size must be positive");
         }
+        if self.deadlock_check_ms == 0 {
+            anyhow::bail!("deadlock_check_ms must be positive");
+        }
+        if self.autovacuum_naptime_ms == 0 {
+            anyhow::bail!("autovacuum_naptime_ms must be positive");
+        }
+        if self.autovacuum_vacuum_scale_factor.is_nan() || self.autovacuum_vacuum_scale_factor < 0.0 {
+            anyhow::bail!("autovacuum_vacuum_scale_factor must be non-negative");
+        }
+        if self.work_mem_kb == 0 {
+            anyhow::bail!("work_mem_kb must be positive");
+        }
+        if self.parallel_workers == 0 {
+            anyhow::bail!("parallel_workers must be positive");
+        }
         Ok(())
     }
 }
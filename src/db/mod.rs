@@ -0,0 +1,537 @@
+//! Database handle: catalog, open tables, transaction manager and WAL under one data
+//! directory. Catalog changes (tables, sequences, views, triggers) are in `schema`; the
+//! per-connection `Session` that executes SQL text is in `session`.
+
+mod schema;
+mod session;
+
+pub use session::Session;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, Weak};
+use std::time::Duration;
+
+use crate::buffer::BufferPool;
+use crate::catalog::{Catalog, Constraint, SequenceDef, TableDef, TableStats, ViewDef};
+use crate::config::Config;
+use crate::query::limits::CancelToken;
+use crate::query::plan_cache::PlanCache;
+use crate::query::trigger::TriggerCache;
+use crate::query::udf::{AggregateUdf, FunctionRegistry, ScalarUdf, TableUdf, TriggerUdf};
+use crate::query::{constraint, spill, stats};
+use crate::sequence::Sequences;
+use crate::storage::{Table, VacuumStats};
+use crate::txn::{IsolationLevel, Transaction, TxnManager};
+use crate::wal::Wal;
+
+pub const WAL_FILE: &str = "rustdb.wal";
+
+/// An open database. Share it as `Arc<Database>`; each connection gets a `Session`.
+pub struct Database {
+    config: Config,
+    dir: PathBuf,
+    catalog: Mutex<Catalog>,
+    tables: RwLock<HashMap<String, Arc<Table>>>,
+    txns: Arc<TxnManager>,
+    /// Where each sequence of the catalog got to.
+    sequences: Arc<Sequences>,
+    buffer_pool: Arc<BufferPool>,
+    /// Bumped by every catalog change plans depend on: tables, views or triggers created or
+    /// dropped, functions registered, and statistics that moved significantly. Cached plans
+    /// (trigger bodies included) built against an older version are planned again.
+    catalog_version: AtomicU64,
+    /// Statistics of each table as of the last version bump they caused, which those of
+    /// the next ANALYZE are compared with.
+    planned_stats: Mutex<HashMap<String, TableStats>>,
+    plan_cache: PlanCache,
+    trigger_cache: TriggerCache,
+    /// Functions registered from Rust. Replaced, not changed, on registration, so plans
+    /// being built keep the set they started with.
+    functions: RwLock<Arc<FunctionRegistry>>,
+    /// Cancel tokens of the open sessions, by session id, with their secrets.
+    sessions: Mutex<HashMap<u64, (u64, CancelToken)>>,
+    next_session: AtomicU64,
+    /// Hashes session ids into cancel key secrets.
+    secrets: RandomState,
+}
+
+/// Identifies a session to `Database::cancel`. Clients get it when they connect and send it
+/// back over another connection to cancel the session's running statement; the secret
+/// keeps other clients from cancelling statements that are not theirs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CancelKey {
+    pub session: u64,
+    pub secret: u64,
+}
+
+impl Database {
+    /// Open the database in `config.data_dir`, creating it if needed, and recover
+    /// transaction status from the WAL.
+    pub fn open(config: Config) -> Result<Arc<Self>> {
+        let dir = PathBuf::from(&config.data_dir);
+        std::fs::create_dir_all(&dir)?;
+        let catalog = Catalog::load(&dir)?;
+        // Spill files of queries that were running when the server stopped.
+        let temp_dir = dir.join(spill::TEMP_DIR);
+        if temp_dir.exists() {
+            std::fs::remove_dir_all(&temp_dir)?;
+        }
+        let buffer_pool = Arc::new(BufferPool::new(config.buffer_pool_size));
+        let mut tables = HashMap::new();
+        let mut next_id = 1;
+        for def in catalog.tables() {
+            let indexes = constraint::indexes(def).into_iter().map(|(name, schema)| (name, schema.len() - 1, schema));
+            let table = (def.name.clone(), def.key_col, def.schema());
+            for (name, key_col, schema) in std::iter::once(table).chain(indexes) {
+                let t = Table::open(&dir, &name, schema, key_col)?;
+                next_id = next_id.max(t.max_txn_id_at_open() + 1);
+                t.attach_buffer_pool(&buffer_pool);
+                tables.insert(name, Arc::new(t));
+            }
+        }
+        let (wal, records) = Wal::open(dir.join(WAL_FILE), config.wal_sync)?;
+        let txns = Arc::new(TxnManager::recover(&config, wal, &records, next_id));
+        let sequences = Arc::new(Sequences::recover(Arc::clone(&txns), catalog.sequences().cloned(), &records));
+        tracing::info!(data_dir = %dir.display(), tables = tables.len(), "database opened");
+        let plan_cache = PlanCache::new(config.plan_cache_size);
+        Ok(Arc::new(Self {
+            config,
+            dir,
+            catalog: Mutex::new(catalog),
+            tables: RwLock::new(tables),
+            txns,
+            sequences,
+            buffer_pool,
+            catalog_version: AtomicU64::new(0),
+            planned_stats: Mutex::default(),
+            plan_cache,
+            trigger_cache: TriggerCache::default(),
+            functions: RwLock::default(),
+            sessions: Mutex::default(),
+            next_session: AtomicU64::new(1),
+            secrets: RandomState::new(),
+        }))
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Where query operators put their spill files.
+    pub fn temp_dir(&self) -> PathBuf {
+        self.dir.join(spill::TEMP_DIR)
+    }
+
+    pub fn txn_manager(&self) -> &Arc<TxnManager> {
+        &self.txns
+    }
+
+    pub fn sequences(&self) -> &Arc<Sequences> {
+        &self.sequences
+    }
+
+    /// Page cache shared by every table's heap and index.
+    pub fn buffer_pool(&self) -> &Arc<BufferPool> {
+        &self.buffer_pool
+    }
+
+    fn catalog(&self) -> MutexGuard<'_, Catalog> {
+        self.catalog.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Changes whenever a table, view or trigger is created or dropped, a function is
+    /// registered or a table's statistics change significantly (see
+    /// `TableStats::differs_significantly`).
+    pub fn catalog_version(&self) -> u64 {
+        self.catalog_version.load(Ordering::Acquire)
+    }
+
+    fn catalog_changed(&self) {
+        self.catalog_version.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Generic plans of statements sent as SQL text, shared by every session.
+    pub fn plan_cache(&self) -> &PlanCache {
+        &self.plan_cache
+    }
+
+    /// Planned trigger bodies, shared by every session.
+    pub fn trigger_cache(&self) -> &TriggerCache {
+        &self.trigger_cache
+    }
+
+    /// The user-defined functions registered so far.
+    pub fn functions(&self) -> Arc<FunctionRegistry> {
+        Arc::clone(&self.functions.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    fn register(&self, add: impl FnOnce(&mut FunctionRegistry) -> Result<()>) -> Result<()> {
+        let mut functions = self.functions.write().unwrap_or_else(|e| e.into_inner());
+        let mut next = FunctionRegistry::clone(&functions);
+        add(&mut next)?;
+        *functions = Arc::new(next);
+        self.catalog_changed();
+        Ok(())
+    }
+
+    /// Make `func` callable from SQL as `name(...)`. Names are case-insensitive and must
+    /// differ from those of built-in functions and of functions registered before.
+    pub fn register_scalar_function(&self, name: &str, func: impl ScalarUdf + 'static) -> Result<()> {
+        self.register(|f| f.add_scalar(name, Arc::new(func)))
+    }
+
+    /// Make `func` callable from SQL as the aggregate `name(...)`, with GROUP BY or OVER.
+    pub fn register_aggregate_function(&self, name: &str, func: impl AggregateUdf + 'static) -> Result<()> {
+        self.register(|f| f.add_aggregate(name, Arc::new(func)))
+    }
+
+    /// Make `func` callable from SQL as `SELECT * FROM name(...)`.
+    pub fn register_table_function(&self, name: &str, func: impl TableUdf + 'static) -> Result<()> {
+        self.register(|f| f.add_table(name, Arc::new(func)))
+    }
+
+    /// Make `func` what triggers created with `EXECUTE FUNCTION name()` run. Register it
+    /// again after reopening the database, before changing the tables it is a trigger of.
+    pub fn register_trigger_function(&self, name: &str, func: impl TriggerUdf + 'static) -> Result<()> {
+        self.register(|f| f.add_trigger(name, Arc::new(func)))
+    }
+
+    pub fn session(self: &Arc<Self>) -> Session {
+        Session::new(Arc::clone(self))
+    }
+
+    fn cancel_tokens(&self) -> MutexGuard<'_, HashMap<u64, (u64, CancelToken)>> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Cancel the running statement of the session `key` names, which then fails with
+    /// `StatementError::Cancelled`. False if no open session has that key.
+    pub fn cancel(&self, key: CancelKey) -> bool {
+        match self.cancel_tokens().get(&key.session) {
+            Some((secret, token)) if *secret == key.secret => {
+                tracing::info!(session = key.session, "statement cancel requested");
+                token.cancel();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn begin(&self, isolation: IsolationLevel) -> Transaction {
+        self.txns.begin(isolation)
+    }
+
+    pub fn table(&self, name: &str) -> Result<Arc<Table>> {
+        match self.tables.read().unwrap_or_else(|e| e.into_inner()).get(name) {
+            Some(t) => Ok(Arc::clone(t)),
+            None => bail!("table {} does not exist", name),
+        }
+    }
+
+    pub fn table_def(&self, name: &str) -> Option<TableDef> {
+        self.catalog().table(name).cloned()
+    }
+
+    pub fn sequence_def(&self, name: &str) -> Option<SequenceDef> {
+        self.catalog().sequence(name).cloned()
+    }
+
+    /// The foreign keys referencing table `name`, with the names of the tables they belong to.
+    pub fn references_to(&self, name: &str) -> Vec<(String, Constraint)> {
+        self.catalog()
+            .references_to(name)
+            .map(|(t, c)| (t.name.clone(), c.clone()))
+            .collect()
+    }
+
+    /// A constraint called `name`, of any table.
+    pub fn find_constraint(&self, name: &str) -> Option<Constraint> {
+        self.catalog().tables().find_map(|t| t.constraint(name)).cloned()
+    }
+
+    pub fn view_def(&self, name: &str) -> Option<ViewDef> {
+        self.catalog().view(name).cloned()
+    }
+
+    /// The incrementally maintained views over table `name`.
+    pub fn incremental_views(&self, name: &str) -> Vec<ViewDef> {
+        self.catalog()
+            .views()
+            .filter(|v| v.incremental.as_ref().is_some_and(|i| i.base == name))
+            .cloned()
+            .collect()
+    }
+
+    /// Statistics of the last ANALYZE of `name`, if any.
+    pub fn table_stats(&self, name: &str) -> Option<TableStats> {
+        self.catalog().stats(name).cloned()
+    }
+
+    /// Replace the statistics of table `name`. Plans built on the old ones are planned
+    /// again only if the new ones differ significantly.
+    pub fn set_table_stats(&self, name: &str, stats: TableStats) -> Result<()> {
+        let mut catalog = self.catalog();
+        catalog.set_stats(name, stats.clone())?;
+        catalog.save(&self.dir)?;
+        let mut planned = self.planned_stats.lock().unwrap_or_else(|e| e.into_inner());
+        if planned.get(name).is_none_or(|old| old.differs_significantly(&stats)) {
+            tracing::debug!(table = name, "statistics changed significantly");
+            planned.insert(name.to_string(), stats);
+            self.catalog_changed();
+        }
+        Ok(())
+    }
+
+    /// ANALYZE one table as `txn` sees it and store the statistics in the catalog.
+    pub fn analyze_table(&self, txn: &mut Transaction, name: &str) -> Result<TableStats> {
+        let table = self.table(name)?;
+        let stats = stats::analyze(txn, &table)?;
+        self.set_table_stats(name, stats.clone())?;
+        tracing::debug!(table = name, rows = stats.row_count, "analyzed");
+        Ok(stats)
+    }
+
+    /// Table names in catalog order.
+    pub fn table_names(&self) -> Vec<String> {
+        self.catalog().tables().map(|d| d.name.clone()).collect()
+    }
+
+    /// `names`, each followed by the names of the unique indexes of that table.
+    fn with_indexes(&self, names: Vec<String>) -> Vec<String> {
+        let catalog = self.catalog();
+        names
+            .into_iter()
+            .flat_map(|name| {
+                let indexes = catalog.table(&name).map(constraint::indexes).unwrap_or_default();
+                std::iter::once(name).chain(indexes.into_iter().map(|(n, _)| n))
+            })
+            .collect()
+    }
+
+    /// Vacuum one table: drop versions no running or future transaction can see.
+    pub fn vacuum_table(&self, table: &Table) -> Result<VacuumStats> {
+        let horizon = self.txns.vacuum_horizon();
+        let stats = table.vacuum(|h| self.txns.is_dead(h, horizon))?;
+        tracing::debug!(?stats, horizon, "vacuumed");
+        Ok(stats)
+    }
+
+    /// `VACUUM [table]`: one table, or every table, with their unique indexes.
+    pub fn vacuum(&self, table: Option<&str>) -> Result<Vec<VacuumStats>> {
+        let names = match table {
+            Some(name) => vec![name.to_string()],
+            None => self.table_names(),
+        };
+        let names = self.with_indexes(names);
+        names
+            .iter()
+            .map(|name| self.vacuum_table(&*self.table(name)?))
+            .collect()
+    }
+
+    /// Fold the log up to the oldest running transaction into one checkpoint record (see
+    /// `TxnManager::checkpoint`). Returns how many records the log shrank by.
+    pub fn checkpoint(&self) -> Result<usize> {
+        self.txns.checkpoint()
+    }
+
+    /// Whether enough versions died since the last vacuum for autovacuum to process `table`.
+    pub fn needs_vacuum(&self, table: &Table) -> bool {
+        let (versions, dead) = table.version_counts();
+        let threshold = self.config.autovacuum_vacuum_threshold as f64
+            + self.config.autovacuum_vacuum_scale_factor * versions as f64;
+        dead > 0 && dead as f64 >= threshold
+    }
+
+    /// One autovacuum pass: vacuum every table over its threshold, then checkpoint the log.
+    pub fn autovacuum_pass(&self) -> Result<Vec<VacuumStats>> {
+        let mut out = Vec::new();
+        for name in self.with_indexes(self.table_names()) {
+            let Ok(table) = self.table(&name) else {
+                continue;
+            };
+            if self.needs_vacuum(&table) {
+                out.push(self.vacuum_table(&table)?);
+            }
+        }
+        self.checkpoint()?;
+        Ok(out)
+    }
+
+    /// Start the autovacuum task on the current tokio runtime. It runs a pass every
+    /// `autovacuum_naptime_ms` and stops once the database is dropped. Returns `None` when
+    /// autovacuum is disabled.
+    pub fn spawn_autovacuum(self: &Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        if !self.config.autovacuum {
+            return None;
+        }
+        let nap = Duration::from_millis(self.config.autovacuum_naptime_ms);
+        let db: Weak<Self> = Arc::downgrade(self);
+        Some(tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(nap).await;
+                let Some(db) = db.upgrade() else {
+                    break;
+                };
+                match tokio::task::spawn_blocking(move || db.autovacuum_pass()).await {
+                    Ok(Ok(stats)) => {
+                        for s in stats {
+                            tracing::info!(table = %s.table, removed = s.versions_removed,
+                                truncated = s.pages_truncated, "autovacuum");
+                        }
+                    }
+                    Ok(Err(e)) => tracing::warn!(error = %e, "autovacuum failed"),
+                    Err(e) => tracing::warn!(error = %e, "autovacuum task panicked"),
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::ColumnDef;
+    use crate::storage::{ColumnType, Value};
+
+    fn open(dir: &Path) -> Arc<Database> {
+        Database::open(Config {
+            data_dir: dir.to_string_lossy().into_owned(),
+            wal_sync: false,
+            ..Config::default()
+        })
+        .unwrap()
+    }
+
+    fn accounts() -> TableDef {
+        TableDef {
+            name: "accounts".into(),
+            columns: vec![
+                ColumnDef::new("id", ColumnType::Int),
+                ColumnDef::new("balance", ColumnType::Int),
+            ],
+            key_col: 0,
+            constraints: Vec::new(),
+            triggers: Vec::new(),
+        }
+    }
+
+    fn row(k: i64, v: i64) -> Vec<Value> {
+        vec![Value::Int(k), Value::Int(v)]
+    }
+
+    #[test]
+    fn vacuum_waits_for_old_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(dir.path());
+        let t = db.create_table(accounts()).unwrap();
+        let mut a = db.begin(IsolationLevel::RepeatableRead);
+        for k in 0..10 {
+            a.insert(&t, row(k, 100)).unwrap();
+        }
+        a.commit().unwrap();
+
+        let mut old = db.begin(IsolationLevel::RepeatableRead);
+        old.get(&t, 0).unwrap();
+        let mut w = db.begin(IsolationLevel::RepeatableRead);
+        for k in 0..10 {
+            w.update(&t, k, row(k, 200)).unwrap();
+        }
+        w.delete(&t, 9).unwrap();
+        w.commit().unwrap();
+
+        let mut s = db.session();
+        let r = s.execute("VACUUM accounts").unwrap();
+        assert_eq!(r.rows[0][1], Value::Int(0));
+        assert_eq!(old.scan(&t).unwrap().len(), 10);
+        old.commit().unwrap();
+
+        let r = s.execute("VACUUM").unwrap();
+        assert_eq!(r.tag, "VACUUM");
+        assert_eq!(r.rows[0][1], Value::Int(11));
+        assert_eq!(r.rows[0][3], Value::Int(1));
+        let mut c = db.begin(IsolationLevel::RepeatableRead);
+        assert_eq!(c.range(&t, 0, 100).unwrap(), (0..9).map(|k| row(k, 200)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn vacuum_refuses_transaction_block_and_unknown_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(dir.path());
+        let mut s = db.session();
+        assert!(s.execute("VACUUM nope").is_err());
+        s.execute("BEGIN ISOLATION LEVEL SERIALIZABLE").unwrap();
+        assert!(s.execute("VACUUM").is_err());
+        s.execute("SAVEPOINT a; RELEASE SAVEPOINT a").unwrap();
+        s.execute("ROLLBACK").unwrap();
+        assert!(!s.in_transaction());
+        assert!(s.execute("SAVEPOINT a").is_err());
+    }
+
+    #[test]
+    fn commits_survive_reopen_and_uncommitted_rows_do_not() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let db = open(dir.path());
+            let t = db.create_table(accounts()).unwrap();
+            let mut a = db.begin(IsolationLevel::RepeatableRead);
+            a.insert(&t, row(1, 1)).unwrap();
+            a.savepoint("s");
+            a.insert(&t, row(2, 2)).unwrap();
+            a.commit().unwrap();
+            let mut b = db.begin(IsolationLevel::RepeatableRead);
+            b.insert(&t, row(3, 3)).unwrap();
+            // Crash with b in flight: its versions are on disk but never committed.
+            std::mem::forget(b);
+        }
+        let db = open(dir.path());
+        let t = db.table("accounts").unwrap();
+        let mut c = db.begin(IsolationLevel::RepeatableRead);
+        assert!(c.id() > 3);
+        assert_eq!(c.scan(&t).unwrap(), vec![row(1, 1), row(2, 2)]);
+        c.insert(&t, row(3, 30)).unwrap();
+        c.commit().unwrap();
+        let stats = db.vacuum(Some("accounts")).unwrap();
+        assert_eq!(stats[0].versions_removed, 1);
+        let mut d = db.begin(IsolationLevel::RepeatableRead);
+        assert_eq!(d.get(&t, 3).unwrap(), Some(row(3, 30)));
+    }
+
+    #[test]
+    fn autovacuum_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(dir.path());
+        let t = db.create_table(accounts()).unwrap();
+        let mut a = db.begin(IsolationLevel::RepeatableRead);
+        for k in 0..100 {
+            a.insert(&t, row(k, 0)).unwrap();
+        }
+        a.commit().unwrap();
+        // 50 + 0.2 * versions: 40 deletes are not enough, 80 are.
+        let mut b = db.begin(IsolationLevel::RepeatableRead);
+        for k in 0..40 {
+            b.delete(&t, k).unwrap();
+        }
+        b.commit().unwrap();
+        assert!(!db.needs_vacuum(&t));
+        assert!(db.autovacuum_pass().unwrap().is_empty());
+        let mut c = db.begin(IsolationLevel::RepeatableRead);
+        for k in 40..80 {
+            c.delete(&t, k).unwrap();
+        }
+        c.commit().unwrap();
+        assert!(db.needs_vacuum(&t));
+        let stats = db.autovacuum_pass().unwrap();
+        assert_eq!(stats[0].versions_removed, 80);
+        assert!(!db.needs_vacuum(&t));
+    }
+}
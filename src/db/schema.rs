@@ -0,0 +1,432 @@
+//! Catalog changes: creating and dropping tables, sequences, views and triggers, and the
+//! session side of the statements that ask for them.
+
+use anyhow::{bail, Result};
+use sqlparser::ast::{self, CreateTableOptions, ObjectType, Query, Statement};
+use std::sync::{Arc, MutexGuard};
+
+use crate::catalog::{Catalog, ConstraintKind, SequenceDef, TableDef, TriggerDef, ViewDef};
+use crate::query::exec::ExecContext;
+use crate::query::{constraint, ddl, trigger, view, QueryResult};
+use crate::storage::{ColumnType, Table};
+
+use super::{Database, Session};
+
+impl Database {
+    /// Create a table's files and record it in the catalog.
+    pub fn create_table(&self, def: TableDef) -> Result<Arc<Table>> {
+        self.create_table_for(def, Vec::new(), None)
+    }
+
+    /// Create a table along with `sequences`, those of its identity columns.
+    pub fn create_table_with_sequences(&self, def: TableDef, sequences: Vec<SequenceDef>) -> Result<Arc<Table>> {
+        self.create_table_for(def, sequences, None)
+    }
+
+    /// Create the table holding the rows of materialized view `view`, and record both.
+    pub fn create_materialized_view(&self, view: ViewDef, def: TableDef) -> Result<Arc<Table>> {
+        self.create_table_for(def, Vec::new(), Some(view))
+    }
+
+    fn create_table_for(
+        &self,
+        def: TableDef,
+        sequences: Vec<SequenceDef>,
+        view: Option<ViewDef>,
+    ) -> Result<Arc<Table>> {
+        let mut catalog = self.catalog();
+        if catalog.table(&def.name).is_some() {
+            bail!("table {} already exists", def.name);
+        }
+        if catalog.view(&def.name).is_some() {
+            bail!("view {} already exists", def.name);
+        }
+        for s in &sequences {
+            if s.name == def.name || catalog.sequence(&s.name).is_some() || catalog.table(&s.name).is_some() {
+                bail!("relation {} already exists", s.name);
+            }
+        }
+        for c in &def.columns {
+            let Some(identity) = &c.identity else {
+                continue;
+            };
+            let new = sequences.iter().any(|s| s.name == identity.sequence);
+            if !new && catalog.sequence(&identity.sequence).is_none() {
+                bail!("sequence {} of column {} does not exist", identity.sequence, c.name);
+            }
+        }
+        for c in &def.constraints {
+            if let ConstraintKind::ForeignKey { parent, .. } = &c.kind {
+                if *parent != def.name && catalog.table(parent).is_none() {
+                    bail!("referenced table {} does not exist", parent);
+                }
+            }
+        }
+        let indexes = constraint::indexes(&def);
+        let mut tables = self.tables.write().unwrap_or_else(|e| e.into_inner());
+        let mut names = std::iter::once(&def.name).chain(indexes.iter().map(|(name, _)| name));
+        if let Some(taken) = names.find(|name| tables.contains_key(*name)) {
+            bail!("relation {} already exists", taken);
+        }
+        let table = Arc::new(Table::create(&self.dir, &def.name, def.schema(), def.key_col)?);
+        table.attach_buffer_pool(&self.buffer_pool);
+        let mut created = vec![(def.name.clone(), Arc::clone(&table))];
+        for (name, schema) in indexes {
+            let index = Table::create(&self.dir, &name, schema.clone(), schema.len() - 1)?;
+            index.attach_buffer_pool(&self.buffer_pool);
+            created.push((name, Arc::new(index)));
+        }
+        catalog.add_table(def)?;
+        if let Some(view) = view {
+            catalog.add_view(view)?;
+        }
+        for s in sequences {
+            catalog.add_sequence(s.clone())?;
+            self.sequences.create(s)?;
+        }
+        catalog.save(&self.dir)?;
+        self.catalog_changed();
+        tables.extend(created);
+        Ok(table)
+    }
+
+    /// Remove a table from the catalog and delete its files.
+    pub fn drop_table(&self, name: &str) -> Result<()> {
+        let mut catalog = self.catalog();
+        if catalog.view(name).is_some() {
+            bail!("{} is a materialized view; use DROP MATERIALIZED VIEW", name);
+        }
+        if let Some(view) = catalog.dependent_view(name) {
+            let kind = if view.materialized { "materialized view" } else { "view" };
+            bail!("cannot drop table {} because {} {} depends on it", name, kind, view.name);
+        }
+        if let Some((child, c)) = catalog.references_to(name).find(|(child, _)| child.name != name) {
+            bail!("cannot drop table {} because constraint {} on table {} depends on it", name, c.name, child.name);
+        }
+        let def = catalog.remove_table(name)?;
+        let owned: Vec<String> =
+            catalog.sequences().filter(|s| s.owned_by.as_deref() == Some(name)).map(|s| s.name.clone()).collect();
+        for s in &owned {
+            catalog.remove_sequence(s)?;
+        }
+        self.table_removed(catalog, &def)?;
+        for s in &owned {
+            self.sequences.remove(s);
+        }
+        Ok(())
+    }
+
+    /// Record sequence `def` and start counting.
+    pub fn create_sequence(&self, def: SequenceDef) -> Result<()> {
+        let mut catalog = self.catalog();
+        if self.tables.read().unwrap_or_else(|e| e.into_inner()).contains_key(&def.name) {
+            bail!("relation {} already exists", def.name);
+        }
+        if let Some(owner) = def.owned_by.as_deref().filter(|t| catalog.table(t).is_none()) {
+            bail!("table {} does not exist", owner);
+        }
+        catalog.add_sequence(def.clone())?;
+        if let Err(e) = self.sequences.create(def.clone()) {
+            catalog.remove_sequence(&def.name)?;
+            return Err(e);
+        }
+        catalog.save(&self.dir)
+    }
+
+    /// Remove a sequence no identity column draws from.
+    pub fn drop_sequence(&self, name: &str) -> Result<()> {
+        let mut catalog = self.catalog();
+        let user = catalog
+            .tables()
+            .find(|t| t.columns.iter().any(|c| c.identity.as_ref().is_some_and(|i| i.sequence == name)));
+        if let Some(t) = user {
+            bail!("cannot drop sequence {} because table {} requires it", name, t.name);
+        }
+        catalog.remove_sequence(name)?;
+        catalog.save(&self.dir)?;
+        self.sequences.remove(name);
+        Ok(())
+    }
+
+    /// Add `trigger` to table `table`, once its function is registered or its statements
+    /// can be planned.
+    pub fn create_trigger(&self, table: &str, trigger: TriggerDef) -> Result<()> {
+        let Some(def) = self.table_def(table) else {
+            bail!("table {} does not exist", table);
+        };
+        if self.view_def(table).is_some() {
+            bail!("{} is a materialized view; triggers can only be created on tables", table);
+        }
+        trigger::validate(self, &def, &trigger)?;
+        let mut catalog = self.catalog();
+        catalog.add_trigger(table, trigger)?;
+        catalog.save(&self.dir)?;
+        self.catalog_changed();
+        Ok(())
+    }
+
+    pub fn drop_trigger(&self, table: &str, name: &str) -> Result<()> {
+        let mut catalog = self.catalog();
+        catalog.remove_trigger(table, name)?;
+        catalog.save(&self.dir)?;
+        self.catalog_changed();
+        Ok(())
+    }
+
+    /// Save `catalog`, from which table `def` was just removed, and delete its files and
+    /// those of its unique indexes.
+    fn table_removed(&self, catalog: MutexGuard<'_, Catalog>, def: &TableDef) -> Result<()> {
+        catalog.save(&self.dir)?;
+        self.planned_stats.lock().unwrap_or_else(|e| e.into_inner()).remove(&def.name);
+        self.catalog_changed();
+        let names = std::iter::once(def.name.clone()).chain(constraint::indexes(def).into_iter().map(|(n, _)| n));
+        let mut tables = self.tables.write().unwrap_or_else(|e| e.into_inner());
+        for name in names {
+            tables.remove(&name);
+            for path in [Table::heap_path(&self.dir, &name), Table::index_path(&self.dir, &name)] {
+                if let Err(e) = std::fs::remove_file(&path) {
+                    tracing::warn!(path = %path.display(), error = %e, "could not remove table file");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Record view `def`, replacing a view of that name if `replace`.
+    pub fn create_view(&self, def: ViewDef, replace: bool) -> Result<()> {
+        let mut catalog = self.catalog();
+        match catalog.view(&def.name) {
+            Some(old) if replace && old.materialized => bail!("{} is a materialized view", def.name),
+            Some(_) if replace => {
+                catalog.remove_view(&def.name)?;
+            }
+            _ => {}
+        }
+        catalog.add_view(def)?;
+        catalog.save(&self.dir)?;
+        self.catalog_changed();
+        Ok(())
+    }
+
+    /// Remove view `name`, with its table if it is `materialized` (as it must then be).
+    pub fn drop_view(&self, name: &str, materialized: bool) -> Result<()> {
+        let mut catalog = self.catalog();
+        match catalog.view(name) {
+            Some(v) if v.materialized && !materialized => {
+                bail!("{} is a materialized view; use DROP MATERIALIZED VIEW", name)
+            }
+            Some(v) if !v.materialized && materialized => bail!("{} is not a materialized view", name),
+            _ => {}
+        }
+        if let Some(view) = catalog.dependent_view(name) {
+            let kind = if view.materialized { "materialized view" } else { "view" };
+            bail!("cannot drop view {} because {} {} depends on it", name, kind, view.name);
+        }
+        catalog.remove_view(name)?;
+        if !materialized {
+            catalog.save(&self.dir)?;
+            self.catalog_changed();
+            return Ok(());
+        }
+        let def = catalog.remove_table(name)?;
+        self.table_removed(catalog, &def)
+    }
+}
+
+impl Session {
+    /// CREATE and DROP of tables, sequences and views.
+    pub(super) fn run_ddl(&mut self, stmt: Statement) -> Result<QueryResult> {
+        match stmt {
+            Statement::CreateTable {
+                name,
+                columns,
+                constraints,
+                if_not_exists,
+                query: None,
+                ..
+            } => {
+                let parent = |parent: &str| self.db.table_def(parent);
+                let (def, sequences) = ddl::create_table(&name, &columns, &constraints, &parent)?;
+                if if_not_exists && self.db.table_def(&def.name).is_some() {
+                    return Ok(QueryResult::command("CREATE TABLE"));
+                }
+                constraint::validate(&self.db, &def)?;
+                let planner = self.planner();
+                for c in &def.columns {
+                    planner.column_default(c)?;
+                }
+                if !planner.param_types().is_empty() {
+                    bail!("cannot use parameters in a DEFAULT expression");
+                }
+                // A new foreign key changes what writes to its parent table must check.
+                let parents: Vec<String> = def
+                    .constraints
+                    .iter()
+                    .filter_map(|c| match &c.kind {
+                        ConstraintKind::ForeignKey { parent, .. } if *parent != def.name => Some(parent.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                self.with_table_locks(&parents, |db| db.create_table_with_sequences(def, sequences))?;
+                Ok(QueryResult::command("CREATE TABLE"))
+            }
+            Statement::CreateSequence {
+                temporary,
+                if_not_exists,
+                name,
+                data_type,
+                sequence_options,
+                owned_by,
+            } => {
+                if temporary {
+                    bail!("temporary sequences are not supported");
+                }
+                let def = ddl::create_sequence(&name, data_type.as_ref(), &sequence_options, owned_by.as_ref())?;
+                if if_not_exists && self.db.sequence_def(&def.name).is_some() {
+                    return Ok(QueryResult::command("CREATE SEQUENCE"));
+                }
+                self.db.create_sequence(def)?;
+                Ok(QueryResult::command("CREATE SEQUENCE"))
+            }
+            Statement::Drop {
+                object_type: ObjectType::Sequence,
+                if_exists,
+                names,
+                ..
+            } => {
+                for name in &names {
+                    let name = ddl::object_name(name)?;
+                    if if_exists && self.db.sequence_def(&name).is_none() {
+                        continue;
+                    }
+                    self.db.drop_sequence(&name)?;
+                }
+                Ok(QueryResult::command("DROP SEQUENCE"))
+            }
+            Statement::Drop {
+                object_type: ObjectType::Table,
+                if_exists,
+                names,
+                ..
+            } => {
+                for name in &names {
+                    let name = ddl::object_name(name)?;
+                    if if_exists && self.db.table_def(&name).is_none() {
+                        continue;
+                    }
+                    self.with_table_locks(std::slice::from_ref(&name), |db| db.drop_table(&name))?;
+                }
+                Ok(QueryResult::command("DROP TABLE"))
+            }
+            Statement::CreateView {
+                or_replace,
+                materialized,
+                name,
+                columns,
+                query,
+                options,
+                cluster_by,
+                with_no_schema_binding,
+                if_not_exists,
+                temporary,
+            } => {
+                if !cluster_by.is_empty() || with_no_schema_binding || temporary {
+                    bail!("unsupported CREATE VIEW syntax");
+                }
+                let name = ddl::object_name(&name)?;
+                self.create_view(name, &columns, &query, &options, materialized, or_replace, if_not_exists)
+            }
+            Statement::Drop {
+                object_type: ObjectType::View,
+                if_exists,
+                names,
+                ..
+            } => {
+                for name in &names {
+                    let name = ddl::object_name(name)?;
+                    if if_exists && self.db.view_def(&name).is_none() {
+                        continue;
+                    }
+                    self.db.drop_view(&name, false)?;
+                }
+                Ok(QueryResult::command("DROP VIEW"))
+            }
+            other => bail!("unsupported statement: {}", other),
+        }
+    }
+
+    /// CREATE [OR REPLACE] [MATERIALIZED] VIEW. The query is planned now, which checks it
+    /// and names the view's columns; a materialized view is filled by the same statement.
+    #[allow(clippy::too_many_arguments)]
+    fn create_view(
+        &mut self,
+        name: String,
+        columns: &[ast::ViewColumnDef],
+        query: &Query,
+        options: &CreateTableOptions,
+        materialized: bool,
+        or_replace: bool,
+        if_not_exists: bool,
+    ) -> Result<QueryResult> {
+        let tag = if materialized { "CREATE MATERIALIZED VIEW" } else { "CREATE VIEW" };
+        if if_not_exists && (self.db.view_def(&name).is_some() || self.db.table_def(&name).is_some()) {
+            return Ok(QueryResult::command(tag));
+        }
+        let mut incremental = false;
+        match options {
+            CreateTableOptions::None => {}
+            CreateTableOptions::With(options) if materialized => {
+                for o in options {
+                    match (ddl::ident(&o.name).as_str(), &o.value) {
+                        ("incremental", ast::Expr::Value(ast::Value::Boolean(b))) => incremental = *b,
+                        _ => bail!("unrecognized materialized view option {}", o),
+                    }
+                }
+            }
+            _ => bail!("unsupported options for view {}", name),
+        }
+        let planner = self.planner();
+        let plan = planner.plan_query(query)?;
+        if !planner.param_types().is_empty() {
+            bail!("a view query cannot have parameters");
+        }
+        let names: Vec<String> = match columns {
+            [] => plan.columns.iter().map(|(n, _)| n.clone()).collect(),
+            columns if columns.len() == plan.columns.len() => columns.iter().map(|c| ddl::ident(&c.name)).collect(),
+            columns => {
+                let (given, returned) = (columns.len(), plan.columns.len());
+                bail!("view {} has {} columns but its query returns {}", name, given, returned)
+            }
+        };
+        if let Some(dup) = names.iter().enumerate().find(|(i, n)| names[..*i].contains(n)) {
+            bail!("column {} specified more than once", dup.1);
+        }
+        let def = ViewDef {
+            name: name.clone(),
+            query: query.to_string(),
+            columns: names,
+            materialized,
+            incremental: incremental.then(|| view::incremental(query, &self.db)).transpose()?,
+            depends_on: view::dependencies(query, &self.db)?,
+        };
+        if !materialized {
+            self.db.create_view(def, or_replace)?;
+            return Ok(QueryResult::command(tag));
+        }
+        if or_replace {
+            bail!("CREATE OR REPLACE is not supported for materialized views");
+        }
+        let types: Vec<ColumnType> = plan.columns.iter().map(|(_, t)| *t).collect();
+        self.db.create_materialized_view(def.clone(), view::table_def(&def, &types)?)?;
+        let db = Arc::clone(&self.db);
+        let limits = Arc::clone(&self.limits);
+        let filled = self.in_statement(true, |txn| {
+            view::refresh(&mut ExecContext { db: &db, txn, limits, trigger_depth: 0 }, &def, &plan, true)
+        });
+        if let Err(e) = filled {
+            self.db.drop_view(&name, true)?;
+            return Err(e);
+        }
+        Ok(QueryResult::command(tag))
+    }
+}
@@ -0,0 +1,1042 @@
+//! Sessions: one client's statements, its open transaction, prepared statements and
+//! settings.
+
+use anyhow::{bail, Result};
+use sqlparser::ast::{self, AnalyzeFormat, Statement, TransactionIsolationLevel, TransactionMode};
+use sqlparser::dialect::GenericDialect;
+use sqlparser::parser::Parser;
+use sqlparser::tokenizer::{Token, Tokenizer};
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::query::command::{self, Command};
+use crate::query::exec::{self, ExecContext};
+use crate::query::explain::{self, Explain};
+use crate::query::expr::coerce;
+use crate::query::limits::{CancelToken, Limits};
+use crate::query::plan::{Node, Plan};
+use crate::query::plan_cache::{self, CacheKey};
+use crate::query::planner::Planner;
+use crate::query::{constraint, ddl, view, QueryResult, StatementDescription};
+use crate::sequence::SessionSequences;
+use crate::storage::{ColumnType, Value};
+use crate::txn::{IsolationLevel, LockMode, Transaction, TxnError};
+
+use super::{CancelKey, Database};
+
+/// Savepoint wrapped around each data-changing statement inside a transaction block, so a
+/// failing statement leaves no partial effects.
+const STATEMENT_SAVEPOINT: &str = "\u{0}statement";
+
+/// One client's view of the database: executes statements, tracking the open transaction
+/// and its prepared statements. Statements block their thread on I/O and lock waits, so on
+/// a tokio runtime a session runs under `spawn_blocking` (see `txn::lock`).
+pub struct Session {
+    pub(super) db: Arc<Database>,
+    txn: Option<Transaction>,
+    prepared: HashMap<String, Prepared>,
+    /// Prepared statements with parameter values bound, by portal name.
+    portals: HashMap<String, (String, Vec<Value>)>,
+    settings: Settings,
+    cancel_key: CancelKey,
+    cancel: CancelToken,
+    /// Limits of the statement running or last run.
+    pub(super) limits: Arc<Limits>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.db.cancel_tokens().remove(&self.cancel_key.session);
+        self.db.sequences.end_session(self.cancel_key.session);
+    }
+}
+
+/// Settings of one session, changed with SET and RESET and read with SHOW. Each starts
+/// out as the database's `Config` has it.
+#[derive(Debug, Clone)]
+struct Settings {
+    parallel_workers: usize,
+    statement_timeout_ms: u64,
+    query_memory_limit_kb: usize,
+}
+
+impl Settings {
+    fn new(config: &Config) -> Self {
+        Self {
+            parallel_workers: config.parallel_workers,
+            statement_timeout_ms: config.statement_timeout_ms,
+            query_memory_limit_kb: config.query_memory_limit_kb,
+        }
+    }
+
+    /// Set `name` to `value`, or back to the configured value for None.
+    fn set(&mut self, name: &str, value: Option<Value>, config: &Config) -> Result<()> {
+        let default = Settings::new(config);
+        match name {
+            "parallel_workers" => {
+                self.parallel_workers = match value {
+                    None => default.parallel_workers,
+                    Some(Value::Int(n)) if n >= 1 => n as usize,
+                    Some(v) => bail!("parallel_workers must be a positive integer, not {}", v),
+                }
+            }
+            "statement_timeout" => {
+                self.statement_timeout_ms = match value {
+                    None => default.statement_timeout_ms,
+                    Some(Value::Int(n)) if n >= 0 => n as u64,
+                    Some(v) => bail!("statement_timeout must be a non-negative number of milliseconds, not {}", v),
+                }
+            }
+            "query_memory_limit" => {
+                self.query_memory_limit_kb = match value {
+                    None => default.query_memory_limit_kb,
+                    Some(Value::Int(n)) if n >= 0 => n as usize,
+                    Some(v) => bail!("query_memory_limit must be a non-negative number of KB, not {}", v),
+                }
+            }
+            _ => bail!("unrecognized configuration parameter {}", name),
+        }
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> Result<Value> {
+        Ok(match name {
+            "parallel_workers" => Value::Int(self.parallel_workers as i64),
+            "statement_timeout" => Value::Int(self.statement_timeout_ms as i64),
+            "query_memory_limit" => Value::Int(self.query_memory_limit_kb as i64),
+            _ => bail!("unrecognized configuration parameter {}", name),
+        })
+    }
+}
+
+/// A statement prepared by PREPARE or the protocol, with its plan.
+struct Prepared {
+    statement: Statement,
+    param_types: Vec<ColumnType>,
+    plan: Plan,
+    /// `Database::catalog_version` when `plan` was built.
+    version: u64,
+}
+
+impl Prepared {
+    /// `params` as values of the statement's parameter types.
+    fn bind(&self, name: &str, params: Vec<Value>) -> Result<Vec<Value>> {
+        if params.len() != self.param_types.len() {
+            bail!(
+                "prepared statement {} expects {} parameters but got {}",
+                name,
+                self.param_types.len(),
+                params.len()
+            );
+        }
+        params
+            .into_iter()
+            .zip(&self.param_types)
+            .enumerate()
+            .map(|(i, (v, ty))| coerce(v, *ty).map_err(|e| anyhow::anyhow!("parameter ${}: {}", i + 1, e)))
+            .collect()
+    }
+}
+
+/// The columns a statement's plan returns rows of; none for data-changing statements
+/// without RETURNING.
+fn result_columns(plan: &Plan) -> Vec<(String, ColumnType)> {
+    match plan.node {
+        Node::Insert { .. } | Node::Update { .. } | Node::Delete { .. } => vec![],
+        _ => plan.columns.clone(),
+    }
+}
+
+/// Parse `sql`, numbering `?` placeholders `$1`, `$2`, ... in order of appearance.
+fn parse_sql(sql: &str) -> Result<Vec<Statement>> {
+    let dialect = GenericDialect {};
+    let Ok(mut tokens) = Tokenizer::new(&dialect, sql).tokenize() else {
+        return Ok(Parser::parse_sql(&dialect, sql)?);
+    };
+    let mut next = 0;
+    let mut numbered = false;
+    for t in &mut tokens {
+        if let Token::Placeholder(p) = t {
+            if p == "?" {
+                next += 1;
+                *p = format!("${}", next);
+            } else if let Some(n) = p.strip_prefix('?') {
+                *p = format!("${}", n);
+            } else {
+                numbered = true;
+            }
+        }
+    }
+    if numbered && next > 0 {
+        bail!("cannot mix ? and $n parameter placeholders");
+    }
+    Ok(Parser::new(&dialect).with_tokens(tokens).parse_statements()?)
+}
+
+impl Session {
+    /// A new session of `db`, registered for cancellation.
+    pub(super) fn new(db: Arc<Database>) -> Self {
+        let id = db.next_session.fetch_add(1, Ordering::Relaxed);
+        let cancel_key = CancelKey {
+            session: id,
+            secret: db.secrets.hash_one(id),
+        };
+        let cancel = CancelToken::default();
+        db.cancel_tokens().insert(id, (cancel_key.secret, cancel.clone()));
+        Self {
+            settings: Settings::new(&db.config),
+            db,
+            txn: None,
+            prepared: HashMap::new(),
+            portals: HashMap::new(),
+            cancel_key,
+            cancel,
+            limits: Limits::none(),
+        }
+    }
+
+    pub fn database(&self) -> &Arc<Database> {
+        &self.db
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.txn.is_some()
+    }
+
+    /// Key for cancelling this session's statements from elsewhere (see `Database::cancel`).
+    pub fn cancel_key(&self) -> CancelKey {
+        self.cancel_key
+    }
+
+    /// Start the limits of a statement: its timeout starts now, and a cancel requested
+    /// before it began does not apply to it.
+    fn start_statement(&mut self) {
+        let timeout = (self.settings.statement_timeout_ms > 0)
+            .then(|| Duration::from_millis(self.settings.statement_timeout_ms));
+        self.limits = Limits::new(self.cancel.clone(), timeout, self.settings.query_memory_limit_kb * 1024);
+    }
+
+    /// A planner for this session's statements, under its settings.
+    pub(super) fn planner(&self) -> Planner<'_> {
+        Planner::new(&self.db)
+            .with_parallel_workers(self.settings.parallel_workers)
+            .with_session(self.cancel_key.session)
+    }
+
+    /// The sequences as this session calls them.
+    fn session_sequences(&self) -> SessionSequences {
+        SessionSequences::new(Arc::clone(&self.db.sequences), self.cancel_key.session)
+    }
+
+    /// Execute one or more `;`-separated statements and return the last result.
+    /// A transaction error (deadlock, serialization failure, ...) rolls back the open
+    /// transaction.
+    pub fn execute(&mut self, sql: &str) -> Result<QueryResult> {
+        self.cancel.reset();
+        let result = self.execute_inner(sql);
+        self.end_on_txn_error(result)
+    }
+
+    fn end_on_txn_error<T>(&mut self, result: Result<T>) -> Result<T> {
+        if let Err(e) = &result {
+            if e.downcast_ref::<TxnError>().is_some() {
+                self.txn = None;
+            }
+        }
+        result
+    }
+
+    fn execute_inner(&mut self, sql: &str) -> Result<QueryResult> {
+        if let Some(cmd) = command::parse(sql)? {
+            self.start_statement();
+            return self.run_command(cmd);
+        }
+        let statements = parse_sql(sql)?;
+        let mut last = QueryResult::command("EMPTY");
+        for stmt in statements {
+            self.start_statement();
+            last = self.run_statement(stmt)?;
+        }
+        Ok(last)
+    }
+
+    fn run_command(&mut self, cmd: Command) -> Result<QueryResult> {
+        match cmd {
+            Command::Vacuum { table } => {
+                if self.in_transaction() {
+                    bail!("VACUUM cannot run inside a transaction block");
+                }
+                let stats = self.db.vacuum(table.as_deref())?;
+                Ok(QueryResult {
+                    columns: vec![
+                        ("table".to_string(), ColumnType::Text),
+                        ("versions_removed".to_string(), ColumnType::Int),
+                        ("versions_kept".to_string(), ColumnType::Int),
+                        ("index_entries_removed".to_string(), ColumnType::Int),
+                        ("pages_truncated".to_string(), ColumnType::Int),
+                    ],
+                    rows: stats
+                        .into_iter()
+                        .map(|s| {
+                            vec![
+                                Value::Text(s.table),
+                                Value::Int(s.versions_removed as i64),
+                                Value::Int(s.versions_kept as i64),
+                                Value::Int(s.index_entries_removed as i64),
+                                Value::Int(s.pages_truncated as i64),
+                            ]
+                        })
+                        .collect(),
+                    tag: "VACUUM".to_string(),
+                })
+            }
+            Command::Checkpoint => {
+                self.db.checkpoint()?;
+                Ok(QueryResult::command("CHECKPOINT"))
+            }
+            Command::Reset { name: Some(name) } => {
+                self.settings.set(&name, None, self.db.config())?;
+                Ok(QueryResult::command("RESET"))
+            }
+            Command::Reset { name: None } => {
+                self.settings = Settings::new(self.db.config());
+                Ok(QueryResult::command("RESET"))
+            }
+            Command::Refresh { view, concurrently } => {
+                let Some(def) = self.db.view_def(&view).filter(|v| v.materialized) else {
+                    bail!("{} is not a materialized view", view);
+                };
+                let plan = self.planner().plan_query(&view::parse_query(&def.query)?)?;
+                let db = Arc::clone(&self.db);
+                let limits = Arc::clone(&self.limits);
+                self.in_statement(true, |txn| {
+                    let mut ctx = ExecContext { db: &db, txn, limits, trigger_depth: 0 };
+                    view::refresh(&mut ctx, &def, &plan, concurrently)
+                })?;
+                Ok(QueryResult::command("REFRESH MATERIALIZED VIEW"))
+            }
+            Command::SetConstraints { names, deferred } => {
+                for name in names.iter().flatten() {
+                    match self.db.find_constraint(name) {
+                        Some(c) if !c.deferrable => bail!("constraint {} is not deferrable", name),
+                        Some(_) => {}
+                        None => bail!("constraint {} does not exist", name),
+                    }
+                }
+                let Some(txn) = self.txn.as_mut() else {
+                    bail!("SET CONSTRAINTS can only be used in a transaction block");
+                };
+                txn.set_constraints(names, deferred);
+                if !deferred {
+                    constraint::check_deferred(&self.db, txn, false)?;
+                }
+                Ok(QueryResult::command("SET CONSTRAINTS"))
+            }
+            Command::CreateTrigger { table, trigger } => {
+                self.with_table_locks(std::slice::from_ref(&table), |db| db.create_trigger(&table, trigger))?;
+                Ok(QueryResult::command("CREATE TRIGGER"))
+            }
+            Command::DropTrigger { name, table, if_exists } => {
+                let exists = self.db.table_def(&table).is_some_and(|t| t.trigger(&name).is_some());
+                if exists || !if_exists {
+                    self.with_table_locks(std::slice::from_ref(&table), |db| db.drop_trigger(&table, &name))?;
+                }
+                Ok(QueryResult::command("DROP TRIGGER"))
+            }
+            Command::DropMaterializedView { names, if_exists } => {
+                for name in names {
+                    if if_exists && self.db.view_def(&name).is_none() {
+                        continue;
+                    }
+                    self.with_table_locks(std::slice::from_ref(&name), |db| db.drop_view(&name, true))?;
+                }
+                Ok(QueryResult::command("DROP MATERIALIZED VIEW"))
+            }
+            Command::Analyze { table } => {
+                let names = match table {
+                    Some(name) => {
+                        self.db.table(&name)?;
+                        vec![name]
+                    }
+                    None => self.db.table_names(),
+                };
+                let db = Arc::clone(&self.db);
+                let rows = self.in_statement(false, |txn| {
+                    names
+                        .iter()
+                        .map(|name| {
+                            let s = db.analyze_table(txn, name)?;
+                            Ok(vec![
+                                Value::Text(name.clone()),
+                                Value::Int(s.row_count as i64),
+                                Value::Int(s.pages as i64),
+                            ])
+                        })
+                        .collect::<Result<Vec<_>>>()
+                })?;
+                Ok(QueryResult {
+                    columns: vec![
+                        ("table".to_string(), ColumnType::Text),
+                        ("rows".to_string(), ColumnType::Int),
+                        ("pages".to_string(), ColumnType::Int),
+                    ],
+                    rows,
+                    tag: "ANALYZE".to_string(),
+                })
+            }
+        }
+    }
+
+    /// Run `f` as one statement: inside the open transaction (atomically, if `atomic`), or
+    /// in a transaction of its own that commits when it succeeds.
+    pub(super) fn in_statement<T>(&mut self, atomic: bool, f: impl FnOnce(&mut Transaction) -> Result<T>) -> Result<T> {
+        let Some(txn) = self.txn.as_mut() else {
+            let mut txn = self.db.begin(IsolationLevel::default());
+            let out = f(&mut txn)?;
+            constraint::check_deferred(&self.db, &mut txn, true)?;
+            txn.commit()?;
+            return Ok(out);
+        };
+        txn.begin_statement();
+        if !atomic {
+            return f(txn);
+        }
+        txn.savepoint(STATEMENT_SAVEPOINT);
+        let out = f(txn);
+        // After a transaction error the whole transaction is rolled back by `execute`.
+        if matches!(&out, Err(e) if e.downcast_ref::<TxnError>().is_none()) {
+            txn.rollback_to_savepoint(STATEMENT_SAVEPOINT)?;
+        }
+        txn.release_savepoint(STATEMENT_SAVEPOINT)?;
+        out
+    }
+
+    /// Run DDL `f` as one statement holding exclusive locks on `tables`, so it waits for
+    /// transactions that wrote them or locked their rows to end, and holds up new ones
+    /// until it is done (inside a transaction block, until that ends).
+    pub(super) fn with_table_locks<T>(
+        &mut self,
+        tables: &[String],
+        f: impl FnOnce(&Database) -> Result<T>,
+    ) -> Result<T> {
+        let db = Arc::clone(&self.db);
+        self.in_statement(false, |txn| {
+            // A missing table is left for `f` to report.
+            for table in tables.iter().filter_map(|name| db.table(name).ok()) {
+                txn.lock_table(&table, LockMode::Exclusive)?;
+            }
+            f(&db)
+        })
+    }
+
+    /// Prepare `sql`, a single query or DML statement, as `name`; the empty name is the
+    /// unnamed statement, which the next one replaces. `param_types` declares the types of
+    /// the first parameters.
+    pub fn prepare(&mut self, name: &str, sql: &str, param_types: &[ColumnType]) -> Result<StatementDescription> {
+        let mut statements = parse_sql(sql)?;
+        if statements.len() != 1 {
+            bail!("a prepared statement must be a single statement");
+        }
+        self.prepare_statement(name.to_string(), statements.pop().unwrap(), param_types)
+    }
+
+    fn prepare_statement(
+        &mut self,
+        name: String,
+        statement: Statement,
+        param_types: &[ColumnType],
+    ) -> Result<StatementDescription> {
+        if !name.is_empty() && self.prepared.contains_key(&name) {
+            bail!("prepared statement {} already exists", name);
+        }
+        if !matches!(
+            statement,
+            Statement::Query(_) | Statement::Insert { .. } | Statement::Update { .. } | Statement::Delete { .. }
+        ) {
+            bail!("cannot prepare {}", statement);
+        }
+        let version = self.db.catalog_version();
+        let planner = self.planner().with_param_types(param_types);
+        let plan = planner.plan(&statement)?;
+        let description = StatementDescription {
+            param_types: planner.param_types(),
+            columns: result_columns(&plan),
+        };
+        self.portals.retain(|_, (s, _)| *s != name);
+        self.prepared.insert(
+            name,
+            Prepared {
+                statement,
+                param_types: description.param_types.clone(),
+                plan,
+                version,
+            },
+        );
+        Ok(description)
+    }
+
+    /// Run prepared statement `name` with `params`, planning it again first if the catalog
+    /// changed since its plan was built.
+    pub fn execute_prepared(&mut self, name: &str, params: Vec<Value>) -> Result<QueryResult> {
+        self.cancel.reset();
+        self.start_statement();
+        let result = self.execute_prepared_inner(name, params);
+        self.end_on_txn_error(result)
+    }
+
+    fn execute_prepared_inner(&mut self, name: &str, params: Vec<Value>) -> Result<QueryResult> {
+        let Some(prepared) = self.prepared.get_mut(name) else {
+            bail!("prepared statement {} does not exist", name);
+        };
+        let values = prepared.bind(name, params)?;
+        let version = self.db.catalog_version();
+        if prepared.version != version {
+            let plan = Planner::new(&self.db)
+                .with_parallel_workers(self.settings.parallel_workers)
+                .with_session(self.cancel_key.session)
+                .with_param_types(&prepared.param_types)
+                .plan(&prepared.statement)?;
+            let types = |p: &Plan| result_columns(p).into_iter().map(|(_, t)| t).collect::<Vec<_>>();
+            if types(&plan) != types(&prepared.plan) {
+                bail!("cached plan must not change result type");
+            }
+            prepared.plan = plan;
+            prepared.version = version;
+        }
+        let plan = prepared.plan.bind_params(&values);
+        self.run_planned(plan)
+    }
+
+    /// Bind `params` to prepared statement `statement` as portal `portal`, replacing any
+    /// portal of that name.
+    pub fn bind(&mut self, portal: &str, statement: &str, params: Vec<Value>) -> Result<()> {
+        let Some(prepared) = self.prepared.get(statement) else {
+            bail!("prepared statement {} does not exist", statement);
+        };
+        let values = prepared.bind(statement, params)?;
+        self.portals.insert(portal.to_string(), (statement.to_string(), values));
+        Ok(())
+    }
+
+    /// Run the statement bound to `portal`.
+    pub fn execute_portal(&mut self, portal: &str) -> Result<QueryResult> {
+        let Some((statement, values)) = self.portals.get(portal).cloned() else {
+            bail!("portal {} does not exist", portal);
+        };
+        self.execute_prepared(&statement, values)
+    }
+
+    /// Forget prepared statement `name` and its portals.
+    pub fn deallocate(&mut self, name: &str) -> Result<()> {
+        if self.prepared.remove(name).is_none() {
+            bail!("prepared statement {} does not exist", name);
+        }
+        self.portals.retain(|_, (s, _)| s != name);
+        Ok(())
+    }
+
+    /// Plan and run a query or DML statement, with a cached plan if there is one.
+    fn run_plan(&mut self, stmt: &Statement) -> Result<QueryResult> {
+        let plan = match self.cached_plan(stmt) {
+            Some(plan) => plan,
+            None => self.planner().plan(stmt)?,
+        };
+        self.run_planned(plan)
+    }
+
+    /// `stmt` planned through the plan cache, bound to its literals: the cached plan of
+    /// its normalized form, or a new one, cached for the next statement of that form. None
+    /// if the cache is off, or if the normalized form does not plan (a parameter where the
+    /// planner needs a constant); `stmt` is then planned as it is.
+    fn cached_plan(&self, stmt: &Statement) -> Option<Plan> {
+        let cache = self.db.plan_cache();
+        if !cache.enabled() {
+            return None;
+        }
+        let normalized = plan_cache::normalize(stmt)?;
+        let key = CacheKey {
+            query: normalized.statement.to_string(),
+            param_types: normalized.param_types(),
+            parallel_workers: self.settings.parallel_workers,
+        };
+        let version = self.db.catalog_version();
+        let plan = match cache.get(&key, version) {
+            Some(plan) => plan,
+            None => {
+                let planner = self.planner().with_param_types(&key.param_types);
+                let plan = Arc::new(planner.plan(&normalized.statement).ok()?);
+                cache.insert(key, Arc::clone(&plan), version);
+                plan
+            }
+        };
+        let plan = plan.bind_params(&normalized.params);
+        // The plan may have been built by another session.
+        match plan.calls_sequence() {
+            true => Some(plan.bind_sequences(&self.session_sequences())),
+            false => Some(plan),
+        }
+    }
+
+    fn run_planned(&mut self, plan: Plan) -> Result<QueryResult> {
+        let db = Arc::clone(&self.db);
+        let tag = match plan.modification() {
+            Some(Node::Insert { .. }) => "INSERT 0",
+            Some(Node::Update { .. }) => "UPDATE",
+            Some(Node::Delete { .. }) => "DELETE",
+            _ => "SELECT",
+        };
+        let writes = plan.modification().is_some();
+        let limits = Arc::clone(&self.limits);
+        let rows = self.in_statement(writes, |txn| {
+            exec::execute(&plan, &mut ExecContext { db: &db, txn, limits, trigger_depth: 0 })
+        })?;
+        let tag = format!("{} {}", tag, rows.len());
+        Ok(match result_columns(&plan) {
+            columns if writes && columns.is_empty() => QueryResult::command(tag),
+            columns => QueryResult { columns, tag, rows },
+        })
+    }
+
+    /// EXPLAIN [ANALYZE] of a query or DML statement. ANALYZE really runs it, writes included.
+    fn run_explain(&mut self, stmt: &Statement, analyze: bool, json: bool) -> Result<QueryResult> {
+        if !matches!(
+            stmt,
+            Statement::Query(_) | Statement::Insert { .. } | Statement::Update { .. } | Statement::Delete { .. }
+        ) {
+            bail!("EXPLAIN is not supported for {}", stmt);
+        }
+        let db = Arc::clone(&self.db);
+        let start = Instant::now();
+        let plan = self.planner().plan(stmt)?;
+        let planning_time = start.elapsed();
+        let mut explain = Explain {
+            root: explain::describe(&plan, &db, &mut None),
+            planning_time: None,
+            execution_time: None,
+        };
+        if analyze {
+            let writes = plan.modification().is_some();
+            let limits = Arc::clone(&self.limits);
+            let (stats, execution_time) = self.in_statement(writes, |txn| {
+                let start = Instant::now();
+                let (_, stats) = explain::analyze(&plan, &mut ExecContext { db: &db, txn, limits, trigger_depth: 0 })?;
+                Ok((stats, start.elapsed()))
+            })?;
+            explain.root = explain::describe(&plan, &db, &mut Some(stats.into_iter()));
+            explain.planning_time = Some(planning_time);
+            explain.execution_time = Some(execution_time);
+        }
+        let lines = if json {
+            vec![serde_json::to_string_pretty(&explain.to_json())?]
+        } else {
+            explain.to_text()
+        };
+        Ok(QueryResult {
+            columns: vec![("QUERY PLAN".to_string(), ColumnType::Text)],
+            rows: lines.into_iter().map(|l| vec![Value::Text(l)]).collect(),
+            tag: "EXPLAIN".to_string(),
+        })
+    }
+
+    fn txn_mut(&mut self, what: &str) -> Result<&mut Transaction> {
+        match self.txn.as_mut() {
+            Some(t) => Ok(t),
+            None => bail!("{} can only be used in a transaction block", what),
+        }
+    }
+
+    fn run_statement(&mut self, stmt: Statement) -> Result<QueryResult> {
+        match stmt {
+            Statement::StartTransaction { modes, .. } => {
+                if self.in_transaction() {
+                    bail!("there is already a transaction in progress");
+                }
+                let mut isolation = IsolationLevel::default();
+                for mode in modes {
+                    if let TransactionMode::IsolationLevel(level) = mode {
+                        isolation = match level {
+                            TransactionIsolationLevel::ReadUncommitted
+                            | TransactionIsolationLevel::ReadCommitted => IsolationLevel::ReadCommitted,
+                            TransactionIsolationLevel::RepeatableRead => IsolationLevel::RepeatableRead,
+                            TransactionIsolationLevel::Serializable => IsolationLevel::Serializable,
+                        };
+                    }
+                }
+                self.txn = Some(self.db.begin(isolation));
+                Ok(QueryResult::command("BEGIN"))
+            }
+            Statement::Commit { .. } => {
+                if let Some(mut txn) = self.txn.take() {
+                    constraint::check_deferred(&self.db, &mut txn, true)?;
+                    txn.commit()?;
+                }
+                Ok(QueryResult::command("COMMIT"))
+            }
+            Statement::Rollback { savepoint: Some(name), .. } => {
+                self.txn_mut("ROLLBACK TO SAVEPOINT")?
+                    .rollback_to_savepoint(&name.value)?;
+                Ok(QueryResult::command("ROLLBACK"))
+            }
+            Statement::Rollback { savepoint: None, .. } => {
+                if let Some(txn) = self.txn.take() {
+                    txn.rollback();
+                }
+                Ok(QueryResult::command("ROLLBACK"))
+            }
+            Statement::Savepoint { name } => {
+                self.txn_mut("SAVEPOINT")?.savepoint(&name.value);
+                Ok(QueryResult::command("SAVEPOINT"))
+            }
+            Statement::ReleaseSavepoint { name } => {
+                self.txn_mut("RELEASE SAVEPOINT")?
+                    .release_savepoint(&name.value)?;
+                Ok(QueryResult::command("RELEASE"))
+            }
+            Statement::CreateTable { .. }
+            | Statement::CreateSequence { .. }
+            | Statement::CreateView { .. }
+            | Statement::Drop { .. } => self.run_ddl(stmt),
+            Statement::Query(_) | Statement::Insert { .. } | Statement::Update { .. } | Statement::Delete { .. } => {
+                self.run_plan(&stmt)
+            }
+            Statement::Prepare {
+                name,
+                data_types,
+                statement,
+            } => {
+                let types = data_types.iter().map(ddl::column_type).collect::<Result<Vec<_>>>()?;
+                self.prepare_statement(ddl::ident(&name), *statement, &types)?;
+                Ok(QueryResult::command("PREPARE"))
+            }
+            Statement::Execute { name, parameters, using } => {
+                if !using.is_empty() {
+                    bail!("EXECUTE ... USING is not supported");
+                }
+                let planner = self.planner();
+                let params = parameters.iter().map(|e| planner.constant(e)).collect::<Result<_>>()?;
+                self.execute_prepared(&ddl::ident(&name), params)
+            }
+            Statement::Deallocate { name, .. } => {
+                if name.quote_style.is_none() && name.value.eq_ignore_ascii_case("all") {
+                    self.prepared.clear();
+                    self.portals.clear();
+                } else {
+                    self.deallocate(&ddl::ident(&name))?;
+                }
+                Ok(QueryResult::command("DEALLOCATE"))
+            }
+            Statement::Explain {
+                analyze,
+                statement,
+                format,
+                ..
+            } => {
+                let json = match format {
+                    None | Some(AnalyzeFormat::TEXT) => false,
+                    Some(AnalyzeFormat::JSON) => true,
+                    Some(other) => bail!("EXPLAIN format {} is not supported", other),
+                };
+                self.run_explain(&statement, analyze, json)
+            }
+            Statement::SetVariable {
+                local: false,
+                hivevar: false,
+                variable,
+                value,
+            } => {
+                let name = ddl::object_name(&variable)?;
+                let value = match value.as_slice() {
+                    [ast::Expr::Identifier(id)] if id.quote_style.is_none() && id.value.eq_ignore_ascii_case("default") => {
+                        None
+                    }
+                    [e] => Some(self.planner().constant(e)?),
+                    _ => bail!("SET {} takes one value", name),
+                };
+                self.settings.set(&name, value, self.db.config())?;
+                Ok(QueryResult::command("SET"))
+            }
+            Statement::ShowVariable { variable } => {
+                let name = variable.iter().map(ddl::ident).collect::<Vec<_>>().join(" ");
+                let value = self.settings.get(&name)?;
+                Ok(QueryResult {
+                    columns: vec![(name, value.column_type().unwrap_or(ColumnType::Text))],
+                    rows: vec![vec![value]],
+                    tag: "SHOW".to_string(),
+                })
+            }
+            other => bail!("unsupported statement: {}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::limits::StatementError;
+    use std::path::Path;
+
+    fn open(dir: &Path) -> Arc<Database> {
+        Database::open(Config {
+            data_dir: dir.to_string_lossy().into_owned(),
+            wal_sync: false,
+            ..Config::default()
+        })
+        .unwrap()
+    }
+
+    fn query(s: &mut Session, sql: &str) -> Vec<Vec<Value>> {
+        s.execute(sql).unwrap().rows
+    }
+
+    #[test]
+    fn statement_failure_keeps_transaction_usable() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(dir.path());
+        let mut s = db.session();
+        s.execute("CREATE TABLE t (id INT PRIMARY KEY, v INT)").unwrap();
+        s.execute("BEGIN").unwrap();
+        s.execute("INSERT INTO t VALUES (1, 10)").unwrap();
+        // The second row collides; the first row of the statement is undone with it.
+        assert!(s.execute("INSERT INTO t VALUES (2, 20), (1, 30)").is_err());
+        assert!(s.in_transaction());
+        s.execute("COMMIT").unwrap();
+        assert_eq!(query(&mut s, "SELECT id, v FROM t"), vec![vec![Value::Int(1), Value::Int(10)]]);
+    }
+
+    /// Two sessions on a table with rows 1 and 2.
+    fn two_sessions(dir: &Path) -> (Arc<Database>, Session, Session) {
+        let db = open(dir);
+        let mut a = db.session();
+        a.execute("CREATE TABLE t (id INT PRIMARY KEY, v INT)").unwrap();
+        a.execute("INSERT INTO t VALUES (1, 10), (2, 20)").unwrap();
+        let b = db.session();
+        (db, a, b)
+    }
+
+    #[test]
+    fn select_for_update_locks_the_rows_it_returns() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_db, mut a, mut b) = two_sessions(dir.path());
+        // Until the transaction ends; other rows stay free.
+        a.execute("BEGIN").unwrap();
+        assert_eq!(query(&mut a, "SELECT v FROM t WHERE id = 1 FOR UPDATE"), vec![vec![Value::Int(10)]]);
+        b.execute("UPDATE t SET v = 21 WHERE id = 2").unwrap();
+        let blocked = std::thread::spawn(move || b.execute("UPDATE t SET v = v + 1 WHERE id = 1").map(|_| b));
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(!blocked.is_finished());
+        a.execute("COMMIT").unwrap();
+        let mut b = blocked.join().unwrap().unwrap();
+        assert_eq!(query(&mut b, "SELECT v FROM t ORDER BY id"), vec![vec![Value::Int(11)], vec![Value::Int(21)]]);
+    }
+
+    #[test]
+    fn for_share_locks_are_compatible_with_each_other() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_db, mut a, mut b) = two_sessions(dir.path());
+        a.execute("BEGIN").unwrap();
+        b.execute("BEGIN").unwrap();
+        assert_eq!(query(&mut a, "SELECT id FROM t FOR SHARE").len(), 2);
+        assert_eq!(query(&mut b, "SELECT id FROM t WHERE v > 10 FOR SHARE OF t").len(), 1);
+        a.execute("ROLLBACK").unwrap();
+        b.execute("ROLLBACK").unwrap();
+    }
+
+    #[test]
+    fn locking_clauses_are_refused_where_rows_cannot_be_locked() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_db, mut a, _b) = two_sessions(dir.path());
+        assert!(a.execute("SELECT count(*) FROM t FOR UPDATE").is_err());
+        assert!(a.execute("SELECT * FROM t FOR UPDATE NOWAIT").is_err());
+        assert!(a.execute("SELECT * FROM t x FOR UPDATE OF y").is_err());
+        assert!(a.execute("SELECT * FROM t UNION SELECT * FROM t FOR UPDATE").is_err());
+    }
+
+    #[test]
+    fn drop_table_waits_for_an_open_transaction_that_wrote_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let (db, mut a, mut b) = two_sessions(dir.path());
+        a.execute("BEGIN").unwrap();
+        a.execute("INSERT INTO t VALUES (3, 30)").unwrap();
+        let dropping = std::thread::spawn(move || b.execute("DROP TABLE t").map(|_| ()));
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(!dropping.is_finished());
+        assert!(db.table_def("t").is_some());
+        a.execute("COMMIT").unwrap();
+        dropping.join().unwrap().unwrap();
+        assert!(db.table_def("t").is_none());
+    }
+
+    /// A table of four rows, inserted through the prepared statement `ins`.
+    fn prepared(dir: &Path) -> Session {
+        let mut s = open(dir).session();
+        s.execute("CREATE TABLE t (id INT PRIMARY KEY, name TEXT, score DOUBLE)").unwrap();
+        s.execute("PREPARE ins AS INSERT INTO t VALUES ($1, $2, $3)").unwrap();
+        for (id, name, score) in [(1, "a", 1.5), (2, "b", 2.0), (3, "c'; DROP TABLE t; --", 3.0)] {
+            s.execute_prepared("ins", vec![Value::Int(id), Value::Text(name.into()), Value::Int(score as i64)])
+                .unwrap();
+        }
+        assert_eq!(s.execute("EXECUTE ins (4, 'd', 4.5)").unwrap().tag, "INSERT 0 1");
+        s
+    }
+
+    #[test]
+    fn parameters_are_values_not_sql() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut s = prepared(dir.path());
+        let rows = query(&mut s, "SELECT name FROM t WHERE id = 3");
+        assert_eq!(rows, vec![vec![Value::Text("c'; DROP TABLE t; --".into())]]);
+        assert_eq!(query(&mut s, "SELECT count(*) FROM t"), vec![vec![Value::Int(4)]]);
+    }
+
+    #[test]
+    fn parameter_types_are_inferred() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut s = prepared(dir.path());
+        // From declarations, comparisons, BETWEEN, IN lists and aggregates.
+        let d = s
+            .prepare("range", "SELECT id FROM t WHERE id BETWEEN ? AND ? AND name IN (?) ORDER BY id", &[])
+            .unwrap();
+        assert_eq!(d.param_types, vec![ColumnType::Int, ColumnType::Int, ColumnType::Text]);
+        let rows = s
+            .execute_prepared("range", vec![Value::Int(1), Value::Int(3), Value::Text("b".into())])
+            .unwrap()
+            .rows;
+        assert_eq!(rows, vec![vec![Value::Int(2)]]);
+        let d = s
+            .prepare("agg", "SELECT count(*) FROM t GROUP BY id > 2 HAVING sum(score) > $1", &[])
+            .unwrap();
+        assert_eq!(d.param_types, vec![ColumnType::Float]);
+        let d = s.prepare("declared", "SELECT $1, $2", &[ColumnType::Int]).unwrap();
+        assert_eq!(d.param_types, vec![ColumnType::Int, ColumnType::Text]);
+        s.execute("PREPARE sub (INT) AS SELECT (SELECT max(id) FROM t WHERE id < $1)").unwrap();
+        assert_eq!(query(&mut s, "EXECUTE sub (3)"), vec![vec![Value::Int(2)]]);
+    }
+
+    #[test]
+    fn prepared_statement_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut s = prepared(dir.path());
+        // Parameter count and types, names.
+        s.execute("PREPARE sub (INT) AS SELECT (SELECT max(id) FROM t WHERE id < $1)").unwrap();
+        let err = s.execute("EXECUTE sub (1, 2)").unwrap_err();
+        assert!(err.to_string().contains("expects 1 parameters but got 2"), "{}", err);
+        let err = s.execute("EXECUTE sub ('x')").unwrap_err();
+        assert!(err.to_string().contains("parameter $1: expected INT value"), "{}", err);
+        let err = s.execute("PREPARE sub AS SELECT 1").unwrap_err();
+        assert!(err.to_string().contains("already exists"), "{}", err);
+        let err = s.execute("PREPARE q AS SELECT id FROM t WHERE id >= $1 AND name <> ?").unwrap_err();
+        assert!(err.to_string().contains("cannot mix"), "{}", err);
+        let err = s.execute("PREPARE b AS BEGIN").unwrap_err();
+        assert!(err.to_string().contains("cannot prepare"), "{}", err);
+    }
+
+    #[test]
+    fn a_catalog_change_plans_prepared_statements_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut s = prepared(dir.path());
+        // A different result type is an error.
+        let db = Arc::clone(s.database());
+        s.execute("PREPARE all_rows AS SELECT * FROM t ORDER BY id").unwrap();
+        s.execute("PREPARE one (INT) AS SELECT name FROM t WHERE id = $1").unwrap();
+        let version = db.catalog_version();
+        s.execute("DROP TABLE t").unwrap();
+        s.execute("CREATE TABLE t (id INT PRIMARY KEY, name TEXT)").unwrap();
+        s.execute("INSERT INTO t VALUES (1, 'fresh')").unwrap();
+        assert!(db.catalog_version() > version);
+        assert_eq!(query(&mut s, "EXECUTE one (1)"), vec![vec![Value::Text("fresh".into())]]);
+        let err = s.execute("EXECUTE all_rows").unwrap_err();
+        assert!(err.to_string().contains("must not change result type"), "{}", err);
+        s.execute("DROP TABLE t").unwrap();
+        let err = s.execute("EXECUTE one (1)").unwrap_err();
+        assert!(err.to_string().contains("does not exist"), "{}", err);
+    }
+
+    #[test]
+    fn deallocate() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut s = prepared(dir.path());
+        s.execute("PREPARE one (INT) AS SELECT name FROM t WHERE id = $1").unwrap();
+        s.execute("DEALLOCATE one").unwrap();
+        assert!(s.execute("EXECUTE one (1)").is_err());
+        s.execute("DEALLOCATE ALL").unwrap();
+        assert!(s.execute("EXECUTE ins (5, 'e', 5)").is_err());
+    }
+
+    /// 2000 rows; the three-way cross join over them runs far longer than a test should.
+    const CROSS: &str = "SELECT count(*) FROM t a, t b, t c WHERE a.id + b.id + c.id < 0";
+
+    fn names(dir: &Path) -> Session {
+        let mut s = open(dir).session();
+        s.execute("CREATE TABLE t (id INT PRIMARY KEY, name TEXT)").unwrap();
+        let values: Vec<String> = (0..2000).map(|i| format!("({}, 'name {}')", i, i)).collect();
+        s.execute(&format!("INSERT INTO t VALUES {}", values.join(", "))).unwrap();
+        s
+    }
+
+    #[test]
+    fn statement_timeout_fails_the_statement_not_the_transaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut s = names(dir.path());
+        s.execute("SET statement_timeout = 20").unwrap();
+        assert_eq!(query(&mut s, "SHOW statement_timeout"), vec![vec![Value::Int(20)]]);
+        s.execute("BEGIN").unwrap();
+        s.execute("INSERT INTO t VALUES (5000, 'kept')").unwrap();
+        let err = s.execute(CROSS).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&StatementError::Timeout { timeout_ms: 20 }));
+        assert!(s.in_transaction());
+        s.execute("COMMIT").unwrap();
+        assert_eq!(query(&mut s, "SELECT name FROM t WHERE id = 5000"), vec![vec![Value::Text("kept".into())]]);
+    }
+
+    #[test]
+    fn query_memory_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut s = names(dir.path());
+        s.execute("SET query_memory_limit = 64").unwrap();
+        assert_eq!(query(&mut s, "SELECT count(*) FROM t a, t b WHERE a.id < 10"), vec![vec![Value::Int(20000)]]);
+        let err = s.execute("SELECT a.id, b.name FROM t a, t b WHERE a.id < 10").unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&StatementError::OutOfMemory { limit_kb: 64 }));
+        assert_eq!(s.limits.memory_used(), 0);
+        assert!(s.execute("SET query_memory_limit = -1").is_err());
+        s.execute("RESET ALL").unwrap();
+        let small = "SELECT count(*) FROM t a, t b WHERE a.id < 10 AND b.id < 10";
+        assert_eq!(query(&mut s, small), vec![vec![Value::Int(100)]]);
+    }
+
+    #[test]
+    fn a_sort_past_the_memory_limit_spills_within_what_is_left() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut s = names(dir.path());
+        // Rather than failing, it goes to disk.
+        let sorted = "SELECT a.id, b.id FROM t a, t b WHERE a.id < 20 ORDER BY b.name DESC, a.id \
+                      LIMIT 2 OFFSET 39000";
+        let expected = query(&mut s, sorted);
+        s.execute("SET query_memory_limit = 256").unwrap();
+        assert_eq!(query(&mut s, sorted), expected);
+        assert_eq!(s.limits.memory_used(), 0);
+    }
+
+    #[test]
+    fn cancel_ends_the_running_statement() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut s = names(dir.path());
+        let db = Arc::clone(s.database());
+        // One sent while none runs is forgotten.
+        assert!(db.cancel(s.cancel_key()));
+        assert_eq!(query(&mut s, "SELECT count(*) FROM t"), vec![vec![Value::Int(2000)]]);
+        let key = s.cancel_key();
+        let running = std::thread::spawn(move || s.execute(CROSS).map(|_| ()));
+        while !running.is_finished() {
+            assert!(db.cancel(key));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let err = running.join().unwrap().unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&StatementError::Cancelled));
+        // The session is gone, and its key with it.
+        assert!(!db.cancel(key));
+    }
+}
@@ -2,9 +2,12 @@
 
 pub mod config;
 pub mod catalog;
+pub mod db;
 pub mod storage;
 pub mod buffer;
+pub mod hash;
 pub mod wal;
+pub mod sequence;
 pub mod txn;
 pub mod query;
 pub mod protocol;
@@ -12,4 +15,5 @@ pub mod server;
 
 // re export for convenience.
 pub use config::Config;
+pub use db::{Database, Session};
 pub use anyhow::Result;
@@ -2,12 +2,13 @@
 //! Usage: rustdb [CONFIG_PATH]
 
 use anyhow::Result;
-use rustdb::Config;
+use rustdb::{Config, Database};
 use std::env;
 use std::path::PathBuf;
 use tracing_subscriber::EnvFilter;
 
-fn main() -> Result<()> {
+#[tokio::main]
+async fn main() -> Result<()> {
     let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
     tracing_subscriber::fmt().with_env_filter(filter).init();
 
@@ -16,8 +17,13 @@ fn main() -> Result<()> {
         None => Config::default_config(),
     };
 
-    tracing::info!(listen_addr = %config.listen_addr, "RustDB starting (Phase 0 bootstrap)");
-    // start TCP server and run until shutdown
-    tracing::info!("RustDB exiting (no server yet)");
+    tracing::info!(listen_addr = %config.listen_addr, "RustDB starting");
+    let db = Database::open(config)?;
+    let _autovacuum = db.spawn_autovacuum();
+    tokio::select! {
+        served = rustdb::server::run(db) => served?,
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    tracing::info!("RustDB exiting");
     Ok(())
 }
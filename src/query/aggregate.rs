@@ -0,0 +1,422 @@
+//! Aggregate functions: `COUNT`, `SUM`, `AVG`, `MIN`, `MAX`, `string_agg`, `bool_and` and
+//! `bool_or`, with optional `DISTINCT` and `FILTER (WHERE ...)`. The `Aggregate` plan node
+//! feeds each group's rows to one `Accumulator` per call. Aggregates registered from Rust
+//! (`udf::AggregateUdf`) keep their own state.
+
+use anyhow::{bail, Result};
+use std::collections::HashSet;
+
+use crate::query::expr::Expr;
+use crate::query::function;
+use crate::query::udf::{self, AggregateUdf, Udf};
+use crate::storage::{ColumnType, Value};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AggFunc {
+    /// `COUNT(*)`: every row.
+    CountStar,
+    /// `COUNT(x)`: rows where `x` is not NULL.
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+    StringAgg,
+    BoolAnd,
+    BoolOr,
+    /// An aggregate registered from Rust.
+    User(Udf<dyn AggregateUdf>),
+}
+
+impl AggFunc {
+    /// The aggregate called `name` (lower case), if it is one. `COUNT(*)` is told apart by
+    /// its argument.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "count" => AggFunc::Count,
+            "sum" => AggFunc::Sum,
+            "avg" => AggFunc::Avg,
+            "min" => AggFunc::Min,
+            "max" => AggFunc::Max,
+            "string_agg" => AggFunc::StringAgg,
+            "bool_and" | "every" => AggFunc::BoolAnd,
+            "bool_or" => AggFunc::BoolOr,
+            _ => return None,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            AggFunc::CountStar | AggFunc::Count => "count",
+            AggFunc::Sum => "sum",
+            AggFunc::Avg => "avg",
+            AggFunc::Min => "min",
+            AggFunc::Max => "max",
+            AggFunc::StringAgg => "string_agg",
+            AggFunc::BoolAnd => "bool_and",
+            AggFunc::BoolOr => "bool_or",
+            AggFunc::User(f) => f.name(),
+        }
+    }
+
+    /// Whether accumulators built over parts of a group can be combined with
+    /// `Accumulator::merge`. `string_agg` cannot: its state does not keep the delimiter that
+    /// would join the parts.
+    pub fn mergeable(&self) -> bool {
+        *self != AggFunc::StringAgg
+    }
+
+    /// Result type for arguments of types `args`, or an error if the call does not exist.
+    pub fn result_type(&self, args: &[ColumnType]) -> Result<ColumnType> {
+        use ColumnType::*;
+        Ok(match (self, args) {
+            (AggFunc::User(f), args) => {
+                let args: Vec<_> = args.iter().copied().map(Some).collect();
+                match function::resolve(f.func().signatures(), &args) {
+                    Some(ty) => ty,
+                    None => bail!("function {}({}) does not exist", f.name(), function::type_list(&args)),
+                }
+            }
+            (AggFunc::CountStar, []) | (AggFunc::Count, [_]) => Int,
+            (AggFunc::Sum, [Int]) => Int,
+            (AggFunc::Sum, [Float]) | (AggFunc::Avg, [Int | Float]) => Float,
+            (AggFunc::Min | AggFunc::Max, [t]) => *t,
+            (AggFunc::StringAgg, [Text, Text]) => Text,
+            (AggFunc::BoolAnd | AggFunc::BoolOr, [Bool]) => Bool,
+            _ => bail!(
+                "function {}({}) does not exist",
+                self.name(),
+                args.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(", ")
+            ),
+        })
+    }
+}
+
+/// One aggregate call of a query, over the columns of the aggregated input.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggCall {
+    pub func: AggFunc,
+    pub args: Vec<Expr>,
+    pub distinct: bool,
+    /// Only rows passing this are aggregated.
+    pub filter: Option<Expr>,
+}
+
+impl AggCall {
+    /// SQL-like rendering, naming column `i` by `name(i)`.
+    pub fn display_with(&self, name: &dyn Fn(usize) -> String) -> String {
+        let args = match &self.func {
+            AggFunc::CountStar => "*".to_string(),
+            _ => self.args.iter().map(|a| a.display_with(name)).collect::<Vec<_>>().join(", "),
+        };
+        let mut s = format!(
+            "{}({}{})",
+            self.func.name(),
+            if self.distinct { "DISTINCT " } else { "" },
+            args
+        );
+        if let Some(f) = &self.filter {
+            s.push_str(&format!(" FILTER (WHERE {})", f.display_with(name)));
+        }
+        s
+    }
+
+    /// Argument values for `row`, or None if the row does not take part (filtered out, or
+    /// a NULL first argument, which every aggregate but `COUNT(*)` skips).
+    pub fn inputs(&self, row: &[Value]) -> Result<Option<Vec<Value>>> {
+        if let Some(f) = &self.filter {
+            if !f.eval_predicate(row)? {
+                return Ok(None);
+            }
+        }
+        let args = self.args.iter().map(|a| a.eval(row)).collect::<Result<Vec<_>>>()?;
+        Ok((!args.first().is_some_and(Value::is_null)).then_some(args))
+    }
+}
+
+/// Bytes an accumulator is assumed to take, for `work_mem` accounting. Values kept for
+/// `DISTINCT` and `string_agg` are not counted.
+pub const ACCUMULATOR_SIZE: usize = 64;
+
+#[derive(Debug, Clone)]
+enum State {
+    Count(i64),
+    Sum(Option<Value>),
+    Avg { sum: f64, n: i64 },
+    /// The least (`Min`) or greatest (`Max`) value so far.
+    Extreme(Option<Value>),
+    StringAgg(Option<String>),
+    Bool(Option<bool>),
+    /// The state of a user-defined aggregate.
+    User(Vec<Value>),
+}
+
+/// The running state of one aggregate call for one group.
+#[derive(Debug, Clone)]
+pub struct Accumulator {
+    func: AggFunc,
+    state: State,
+    /// Argument values seen so far, for `DISTINCT`.
+    seen: Option<HashSet<Vec<Value>>>,
+}
+
+impl Accumulator {
+    pub fn new(call: &AggCall) -> Self {
+        let state = match &call.func {
+            AggFunc::CountStar | AggFunc::Count => State::Count(0),
+            AggFunc::Sum => State::Sum(None),
+            AggFunc::Avg => State::Avg { sum: 0.0, n: 0 },
+            AggFunc::Min | AggFunc::Max => State::Extreme(None),
+            AggFunc::StringAgg => State::StringAgg(None),
+            AggFunc::BoolAnd | AggFunc::BoolOr => State::Bool(None),
+            AggFunc::User(f) => State::User(f.func().init()),
+        };
+        Self {
+            func: call.func.clone(),
+            state,
+            seen: call.distinct.then(HashSet::new),
+        }
+    }
+
+    /// Add one row's argument values (from `AggCall::inputs`).
+    pub fn update(&mut self, args: Vec<Value>) -> Result<()> {
+        if let Some(seen) = &mut self.seen {
+            if !seen.insert(args.clone()) {
+                return Ok(());
+            }
+        }
+        let arg = args.first();
+        match (&mut self.state, arg) {
+            (State::Count(n), _) => *n += 1,
+            (State::User(state), _) => {
+                let AggFunc::User(f) = &self.func else {
+                    unreachable!()
+                };
+                f.func().accumulate(state, &args)?
+            }
+            (State::Sum(sum), Some(v)) => {
+                *sum = Some(match (sum.take(), v) {
+                    (None, v) => v.clone(),
+                    (Some(Value::Int(a)), Value::Int(b)) => match a.checked_add(*b) {
+                        Some(n) => Value::Int(n),
+                        None => bail!("integer out of range"),
+                    },
+                    (Some(a), b) => match (a.as_f64(), b.as_f64()) {
+                        (Some(a), Some(b)) => Value::Float(a + b),
+                        _ => bail!("cannot sum {} and {}", a, b),
+                    },
+                })
+            }
+            (State::Avg { sum, n }, Some(v)) => {
+                let Some(x) = v.as_f64() else {
+                    bail!("cannot average {}", v);
+                };
+                *sum += x;
+                *n += 1;
+            }
+            (State::Extreme(best), Some(v)) => {
+                let replace = match best {
+                    None => true,
+                    Some(b) => {
+                        let Some(ord) = v.sql_cmp(b) else {
+                            bail!("cannot compare {} with {}", v, b);
+                        };
+                        if self.func == AggFunc::Min {
+                            ord.is_lt()
+                        } else {
+                            ord.is_gt()
+                        }
+                    }
+                };
+                if replace {
+                    *best = Some(v.clone());
+                }
+            }
+            (State::StringAgg(s), Some(v)) => match s {
+                None => *s = Some(v.to_string()),
+                Some(s) => {
+                    // A NULL delimiter joins with nothing.
+                    if let Some(Value::Text(d)) = args.get(1) {
+                        s.push_str(d);
+                    }
+                    s.push_str(&v.to_string());
+                }
+            },
+            (State::Bool(acc), Some(v)) => {
+                let Value::Bool(b) = v else {
+                    bail!("argument of {} must be BOOL, not {}", self.func.name(), v);
+                };
+                *acc = Some(match (&self.func, *acc) {
+                    (_, None) => *b,
+                    (AggFunc::BoolAnd, Some(a)) => a && *b,
+                    (_, Some(a)) => a || *b,
+                });
+            }
+            (_, None) => bail!("{} needs an argument", self.func.name()),
+        }
+        Ok(())
+    }
+
+    /// Add `other`, an accumulator of the same call over other rows of the group; used to
+    /// combine the partial aggregates of parallel workers. Fails for `string_agg`.
+    pub fn merge(&mut self, other: Accumulator) -> Result<()> {
+        if let Some(seen) = other.seen {
+            // Re-adding the other part's distinct values drops those seen by both.
+            for args in seen {
+                self.update(args)?;
+            }
+            return Ok(());
+        }
+        match (&mut self.state, other.state) {
+            (State::Count(n), State::Count(m)) => *n += m,
+            (State::Avg { sum, n }, State::Avg { sum: s, n: m }) => {
+                *sum += s;
+                *n += m;
+            }
+            (State::Sum(_), State::Sum(v)) | (State::Extreme(_), State::Extreme(v)) => {
+                if let Some(v) = v {
+                    self.update(vec![v])?;
+                }
+            }
+            (State::Bool(_), State::Bool(b)) => {
+                if let Some(b) = b {
+                    self.update(vec![Value::Bool(b)])?;
+                }
+            }
+            (State::User(state), State::User(other)) => {
+                let AggFunc::User(f) = &self.func else {
+                    unreachable!()
+                };
+                f.func().merge(state, other)?
+            }
+            _ => bail!("cannot combine partial results of {}", self.func.name()),
+        }
+        Ok(())
+    }
+
+    /// The aggregate's value; NULL for an empty group except for `COUNT`.
+    pub fn finish(&self) -> Result<Value> {
+        Ok(match &self.state {
+            State::Count(n) => Value::Int(*n),
+            State::Sum(v) | State::Extreme(v) => v.clone().unwrap_or(Value::Null),
+            State::Avg { n: 0, .. } => Value::Null,
+            State::Avg { sum, n } => Value::Float(sum / *n as f64),
+            State::StringAgg(s) => s.clone().map_or(Value::Null, Value::Text),
+            State::Bool(b) => b.map_or(Value::Null, Value::Bool),
+            State::User(state) => {
+                let AggFunc::User(f) = &self.func else {
+                    unreachable!()
+                };
+                let v = f.func().finalize(state.clone())?;
+                return udf::check_result(f.name(), v, udf::fixed_result(f.func().signatures()));
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(func: AggFunc, distinct: bool, rows: &[Vec<Value>]) -> Value {
+        let call = AggCall {
+            args: match func {
+                AggFunc::CountStar => vec![],
+                _ => (0..rows.first().map_or(0, |r| r.len())).map(Expr::Column).collect(),
+            },
+            func,
+            distinct,
+            filter: None,
+        };
+        let mut acc = Accumulator::new(&call);
+        for row in rows {
+            if let Some(args) = call.inputs(row).unwrap() {
+                acc.update(args).unwrap();
+            }
+        }
+        acc.finish().unwrap()
+    }
+
+    #[test]
+    fn accumulators_skip_nulls_and_handle_empty_groups() {
+        let ints: Vec<Vec<Value>> = [Some(3), None, Some(1), Some(3)]
+            .iter()
+            .map(|v| vec![v.map_or(Value::Null, Value::Int)])
+            .collect();
+        assert_eq!(run(AggFunc::CountStar, false, &ints), Value::Int(4));
+        assert_eq!(run(AggFunc::Count, false, &ints), Value::Int(3));
+        assert_eq!(run(AggFunc::Count, true, &ints), Value::Int(2));
+        assert_eq!(run(AggFunc::Sum, false, &ints), Value::Int(7));
+        assert_eq!(run(AggFunc::Sum, true, &ints), Value::Int(4));
+        assert_eq!(run(AggFunc::Avg, false, &ints), Value::Float(7.0 / 3.0));
+        assert_eq!(run(AggFunc::Min, false, &ints), Value::Int(1));
+        assert_eq!(run(AggFunc::Max, false, &ints), Value::Int(3));
+        assert_eq!(run(AggFunc::Sum, false, &[]), Value::Null);
+        assert_eq!(run(AggFunc::CountStar, false, &[]), Value::Int(0));
+
+        let text = |s: &str| Value::Text(s.into());
+        let words = vec![
+            vec![text("a"), text(", ")],
+            vec![Value::Null, text(", ")],
+            vec![text("b"), Value::Null],
+            vec![text("c"), text("-")],
+        ];
+        assert_eq!(run(AggFunc::StringAgg, false, &words), text("ab-c"));
+
+        let bools: Vec<Vec<Value>> = [Some(true), None, Some(false)]
+            .iter()
+            .map(|v| vec![v.map_or(Value::Null, Value::Bool)])
+            .collect();
+        assert_eq!(run(AggFunc::BoolAnd, false, &bools), Value::Bool(false));
+        assert_eq!(run(AggFunc::BoolOr, false, &bools), Value::Bool(true));
+        assert_eq!(run(AggFunc::BoolOr, false, &bools[1..2]), Value::Null);
+
+        let overflow = [vec![Value::Int(i64::MAX)], vec![Value::Int(1)]];
+        let call = AggCall {
+            func: AggFunc::Sum,
+            args: vec![Expr::Column(0)],
+            distinct: false,
+            filter: None,
+        };
+        let mut acc = Accumulator::new(&call);
+        acc.update(overflow[0].clone()).unwrap();
+        assert!(acc.update(overflow[1].clone()).is_err());
+        assert!(AggFunc::Sum.result_type(&[ColumnType::Text]).is_err());
+    }
+
+    #[test]
+    fn merged_partial_accumulators_match_a_single_pass() {
+        let rows: Vec<Vec<Value>> = [4, 1, 7, 4, 2, 9, 1, 3]
+            .iter()
+            .map(|&n| vec![if n == 9 { Value::Null } else { Value::Int(n) }])
+            .collect();
+        for (func, distinct) in [
+            (AggFunc::CountStar, false),
+            (AggFunc::Count, true),
+            (AggFunc::Sum, false),
+            (AggFunc::Sum, true),
+            (AggFunc::Avg, false),
+            (AggFunc::Min, false),
+            (AggFunc::Max, false),
+        ] {
+            let call = AggCall {
+                args: if func == AggFunc::CountStar { vec![] } else { vec![Expr::Column(0)] },
+                func: func.clone(),
+                distinct,
+                filter: None,
+            };
+            let mut parts: Vec<Accumulator> = (0..3).map(|_| Accumulator::new(&call)).collect();
+            for (i, row) in rows.iter().enumerate() {
+                if let Some(args) = call.inputs(row).unwrap() {
+                    parts[i % 3].update(args).unwrap();
+                }
+            }
+            let mut merged = parts.remove(0);
+            for p in parts {
+                merged.merge(p).unwrap();
+            }
+            assert_eq!(merged.finish().unwrap(), run(func, distinct, &rows));
+        }
+        assert!(!AggFunc::StringAgg.mergeable());
+    }
+}
@@ -0,0 +1,408 @@
+//! Utility statements sqlparser does not understand, recognised from the token stream
+//! before the SQL is handed to the parser.
+
+use anyhow::{bail, Result};
+use sqlparser::dialect::GenericDialect;
+use sqlparser::tokenizer::{Location, Token, Tokenizer, Word};
+
+use crate::catalog::{TriggerBody, TriggerDef, TriggerEvent, TriggerTiming};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// `VACUUM [table]`
+    Vacuum { table: Option<String> },
+    /// `CHECKPOINT`
+    Checkpoint,
+    /// `ANALYZE [table]`
+    Analyze { table: Option<String> },
+    /// `RESET name` or `RESET ALL` (None): session settings back to their defaults.
+    Reset { name: Option<String> },
+    /// `REFRESH MATERIALIZED VIEW [CONCURRENTLY] name`
+    Refresh { view: String, concurrently: bool },
+    /// `DROP MATERIALIZED VIEW [IF EXISTS] name, ...`
+    DropMaterializedView { names: Vec<String>, if_exists: bool },
+    /// `SET CONSTRAINTS { ALL | name, ... } { DEFERRED | IMMEDIATE }`; ALL is None.
+    SetConstraints { names: Option<Vec<String>>, deferred: bool },
+    /// `CREATE TRIGGER name { BEFORE | AFTER } event [OR event ...] ON table
+    /// [FOR [EACH] { ROW | STATEMENT }] body`, where the body is `EXECUTE FUNCTION name()`,
+    /// `BEGIN statement; ... END` or a single statement.
+    CreateTrigger { table: String, trigger: TriggerDef },
+    /// `DROP TRIGGER [IF EXISTS] name ON table`
+    DropTrigger { name: String, table: String, if_exists: bool },
+}
+
+fn is_keyword(t: Option<&Token>, keyword: &str) -> bool {
+    matches!(t, Some(Token::Word(w)) if w.quote_style.is_none() && w.value.eq_ignore_ascii_case(keyword))
+}
+
+/// Identifier as the catalog stores it: unquoted names fold to lower case.
+pub fn normalize_ident(word: &Word) -> String {
+    match word.quote_style {
+        Some(_) => word.value.clone(),
+        None => word.value.to_ascii_lowercase(),
+    }
+}
+
+/// Parse `sql` as a utility command. `Ok(None)` means it is not one of ours.
+pub fn parse(sql: &str) -> Result<Option<Command>> {
+    let tokens = match Tokenizer::new(&GenericDialect {}, sql).tokenize() {
+        Ok(t) => t,
+        // Let sqlparser report the error.
+        Err(_) => return Ok(None),
+    };
+    let mut words = tokens
+        .into_iter()
+        .filter(|t| !matches!(t, Token::Whitespace(_) | Token::SemiColon | Token::EOF))
+        .peekable();
+    let Some(Token::Word(first)) = words.next() else {
+        return Ok(None);
+    };
+    if first.quote_style.is_some() {
+        return Ok(None);
+    }
+    let name = first.value.to_ascii_uppercase();
+    if name == "CHECKPOINT" {
+        if let Some(t) = words.next() {
+            bail!("syntax error in CHECKPOINT at {}", t);
+        }
+        return Ok(Some(Command::Checkpoint));
+    }
+    if name == "CREATE" {
+        if !is_keyword(words.peek(), "trigger") {
+            return Ok(None);
+        }
+        return create_trigger(sql).map(Some);
+    }
+    if name == "DROP" && is_keyword(words.peek(), "trigger") {
+        words.next();
+        let if_exists = is_keyword(words.peek(), "if");
+        if if_exists {
+            words.next();
+            if !is_keyword(words.next().as_ref(), "exists") {
+                bail!("syntax error in DROP TRIGGER: expected IF EXISTS");
+            }
+        }
+        let (Some(Token::Word(name)), true, Some(Token::Word(table)), None) =
+            (words.next(), is_keyword(words.next().as_ref(), "on"), words.next(), words.next())
+        else {
+            bail!("syntax error in DROP TRIGGER: expected DROP TRIGGER name ON table");
+        };
+        return Ok(Some(Command::DropTrigger {
+            name: normalize_ident(&name),
+            table: normalize_ident(&table),
+            if_exists,
+        }));
+    }
+    if name == "RESET" {
+        let setting = match (words.next(), words.next()) {
+            (Some(Token::Word(w)), None) if w.quote_style.is_none() && w.value.eq_ignore_ascii_case("all") => None,
+            (Some(Token::Word(w)), None) => Some(normalize_ident(&w)),
+            (None, _) => bail!("RESET needs a setting name or ALL"),
+            (_, Some(t)) | (Some(t), None) => bail!("syntax error in RESET at {}", t),
+        };
+        return Ok(Some(Command::Reset { name: setting }));
+    }
+    if name == "SET" {
+        if !is_keyword(words.peek(), "constraints") {
+            return Ok(None);
+        }
+        words.next();
+        let mut names = Vec::new();
+        if is_keyword(words.peek(), "all") {
+            words.next();
+        } else {
+            loop {
+                match words.next() {
+                    Some(Token::Word(w)) => names.push(normalize_ident(&w)),
+                    Some(t) => bail!("syntax error in SET CONSTRAINTS at {}", t),
+                    None => bail!("SET CONSTRAINTS needs constraint names or ALL"),
+                }
+                if words.peek() != Some(&Token::Comma) {
+                    break;
+                }
+                words.next();
+            }
+        }
+        let deferred = is_keyword(words.peek(), "deferred");
+        if !deferred && !is_keyword(words.peek(), "immediate") {
+            bail!("SET CONSTRAINTS needs DEFERRED or IMMEDIATE");
+        }
+        words.next();
+        if let Some(t) = words.next() {
+            bail!("syntax error in SET CONSTRAINTS at {}", t);
+        }
+        let names = (!names.is_empty()).then_some(names);
+        return Ok(Some(Command::SetConstraints { names, deferred }));
+    }
+    if name == "REFRESH" || name == "DROP" {
+        if !is_keyword(words.peek(), "materialized") {
+            if name == "DROP" {
+                return Ok(None);
+            }
+            bail!("syntax error in REFRESH: expected MATERIALIZED VIEW");
+        }
+        words.next();
+        if !is_keyword(words.next().as_ref(), "view") {
+            bail!("syntax error in {}: expected MATERIALIZED VIEW", name);
+        }
+        let (keyword, rest) = if name == "REFRESH" { ("concurrently", None) } else { ("if", Some("exists")) };
+        let flag = is_keyword(words.peek(), keyword);
+        if flag {
+            words.next();
+            if let Some(rest) = rest {
+                if !is_keyword(words.next().as_ref(), rest) {
+                    bail!("syntax error in {}: expected IF EXISTS", name);
+                }
+            }
+        }
+        let mut names = Vec::new();
+        loop {
+            match words.next() {
+                Some(Token::Word(w)) => names.push(normalize_ident(&w)),
+                Some(t) => bail!("syntax error in {} at {}", name, t),
+                None => bail!("{} MATERIALIZED VIEW needs a view name", name),
+            }
+            match words.next() {
+                None => break,
+                Some(Token::Comma) if name == "DROP" => continue,
+                Some(t) => bail!("syntax error in {} at {}", name, t),
+            }
+        }
+        return Ok(Some(match name.as_str() {
+            "REFRESH" => Command::Refresh {
+                view: names.remove(0),
+                concurrently: flag,
+            },
+            _ => Command::DropMaterializedView { names, if_exists: flag },
+        }));
+    }
+    if name != "VACUUM" && name != "ANALYZE" {
+        return Ok(None);
+    }
+    let table = match words.next() {
+        None => None,
+        Some(Token::Word(w)) => Some(normalize_ident(&w)),
+        Some(t) => bail!("syntax error in {} at {}", name, t),
+    };
+    if let Some(t) = words.next() {
+        bail!("syntax error in {} at {}", name, t);
+    }
+    Ok(Some(match name.as_str() {
+        "VACUUM" => Command::Vacuum { table },
+        _ => Command::Analyze { table },
+    }))
+}
+
+/// Byte offset in `sql` of the character at `location`, counted the way the tokenizer
+/// counts lines and columns.
+fn offset(sql: &str, location: Location) -> usize {
+    let (mut line, mut column) = (1, 1);
+    for (i, c) in sql.char_indices() {
+        if (line, column) == (location.line, location.column) {
+            return i;
+        }
+        match c {
+            '\n' => (line, column) = (line + 1, 1),
+            _ => column += 1,
+        }
+    }
+    sql.len()
+}
+
+fn create_trigger(sql: &str) -> Result<Command> {
+    let tokens = match Tokenizer::new(&GenericDialect {}, sql).tokenize_with_location() {
+        Ok(t) => t,
+        Err(e) => bail!("syntax error in CREATE TRIGGER: {}", e),
+    };
+    let mut words = tokens
+        .into_iter()
+        .filter(|t| !matches!(t.token, Token::Whitespace(_) | Token::EOF))
+        .peekable();
+    // CREATE TRIGGER
+    words.nth(1);
+    let mut word = |what: &str| match words.next().map(|t| t.token) {
+        Some(Token::Word(w)) => Ok(w),
+        Some(t) => bail!("syntax error in CREATE TRIGGER at {}: expected {}", t, what),
+        None => bail!("syntax error in CREATE TRIGGER: expected {}", what),
+    };
+    let name = normalize_ident(&word("a trigger name")?);
+    let timing = match word("BEFORE or AFTER")?.value.to_ascii_uppercase().as_str() {
+        "BEFORE" => TriggerTiming::Before,
+        "AFTER" => TriggerTiming::After,
+        other => bail!("syntax error in CREATE TRIGGER at {}: expected BEFORE or AFTER", other),
+    };
+    let mut events = Vec::new();
+    let table = loop {
+        let w = word("INSERT, UPDATE or DELETE")?;
+        let event = match w.value.to_ascii_uppercase().as_str() {
+            "INSERT" => TriggerEvent::Insert,
+            "UPDATE" => TriggerEvent::Update,
+            "DELETE" => TriggerEvent::Delete,
+            other => bail!("syntax error in CREATE TRIGGER at {}: expected INSERT, UPDATE or DELETE", other),
+        };
+        if events.contains(&event) {
+            bail!("duplicate trigger event {}", w.value.to_ascii_uppercase());
+        }
+        events.push(event);
+        let w = word("OR or ON")?;
+        match w.value.to_ascii_uppercase().as_str() {
+            "OR" => continue,
+            "ON" => break normalize_ident(&word("a table name")?),
+            other => bail!("syntax error in CREATE TRIGGER at {}: expected OR or ON", other),
+        }
+    };
+    let mut for_each_row = false;
+    if words.peek().is_some_and(|t| is_keyword(Some(&t.token), "for")) {
+        words.next();
+        if words.peek().is_some_and(|t| is_keyword(Some(&t.token), "each")) {
+            words.next();
+        }
+        let Some(t) = words.next() else {
+            bail!("syntax error in CREATE TRIGGER: expected ROW or STATEMENT");
+        };
+        for_each_row = is_keyword(Some(&t.token), "row");
+        if !for_each_row && !is_keyword(Some(&t.token), "statement") {
+            bail!("syntax error in CREATE TRIGGER at {}: expected ROW or STATEMENT", t.token);
+        }
+    }
+    let Some(start) = words.next() else {
+        bail!("CREATE TRIGGER needs a body: EXECUTE FUNCTION name() or statements");
+    };
+    let body = if is_keyword(Some(&start.token), "execute") {
+        let rest: Vec<Token> = words.map(|t| t.token).filter(|t| *t != Token::SemiColon).collect();
+        match rest.as_slice() {
+            [Token::Word(kind), Token::Word(function), Token::LParen, Token::RParen]
+                if kind.quote_style.is_none()
+                    && (kind.value.eq_ignore_ascii_case("function") || kind.value.eq_ignore_ascii_case("procedure")) =>
+            {
+                TriggerBody::Function(normalize_ident(function))
+            }
+            _ => bail!("syntax error in CREATE TRIGGER: expected EXECUTE FUNCTION name()"),
+        }
+    } else {
+        let rest: Vec<_> = words.filter(|t| t.token != Token::SemiColon).collect();
+        let mut text = &sql[offset(sql, start.location)..];
+        if is_keyword(Some(&start.token), "begin") {
+            let Some(end) = rest.last().filter(|t| is_keyword(Some(&t.token), "end")) else {
+                bail!("syntax error in CREATE TRIGGER: BEGIN without END");
+            };
+            text = &sql[offset(sql, start.location) + "begin".len()..offset(sql, end.location)];
+        }
+        let text = text.trim().trim_end_matches(|c: char| c == ';' || c.is_whitespace());
+        if text.is_empty() {
+            bail!("CREATE TRIGGER needs a body: EXECUTE FUNCTION name() or statements");
+        }
+        TriggerBody::Sql(text.to_string())
+    };
+    Ok(Command::CreateTrigger {
+        table,
+        trigger: TriggerDef {
+            name,
+            timing,
+            events,
+            for_each_row,
+            body,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_utility_commands() {
+        assert_eq!(parse("VACUUM").unwrap(), Some(Command::Vacuum { table: None }));
+        assert_eq!(
+            parse("vacuum Users;").unwrap(),
+            Some(Command::Vacuum { table: Some("users".into()) })
+        );
+        assert_eq!(
+            parse("VACUUM \"Users\"").unwrap(),
+            Some(Command::Vacuum { table: Some("Users".into()) })
+        );
+        assert!(parse("VACUUM a b").is_err());
+        assert_eq!(parse("checkpoint;").unwrap(), Some(Command::Checkpoint));
+        assert!(parse("CHECKPOINT now").is_err());
+        assert_eq!(
+            parse("analyze t").unwrap(),
+            Some(Command::Analyze { table: Some("t".into()) })
+        );
+        assert_eq!(parse("SELECT 1").unwrap(), None);
+        assert_eq!(
+            parse("RESET Parallel_Workers").unwrap(),
+            Some(Command::Reset { name: Some("parallel_workers".into()) })
+        );
+        assert_eq!(parse("reset all;").unwrap(), Some(Command::Reset { name: None }));
+        assert!(parse("RESET").is_err());
+
+        assert_eq!(
+            parse("refresh materialized view Totals").unwrap(),
+            Some(Command::Refresh { view: "totals".into(), concurrently: false })
+        );
+        assert_eq!(
+            parse("REFRESH MATERIALIZED VIEW CONCURRENTLY totals;").unwrap(),
+            Some(Command::Refresh { view: "totals".into(), concurrently: true })
+        );
+        assert!(parse("REFRESH MATERIALIZED VIEW a, b").is_err());
+        assert!(parse("REFRESH totals").is_err());
+        assert_eq!(
+            parse("DROP MATERIALIZED VIEW IF EXISTS a, b").unwrap(),
+            Some(Command::DropMaterializedView { names: vec!["a".into(), "b".into()], if_exists: true })
+        );
+        assert!(parse("DROP MATERIALIZED VIEW IF a").is_err());
+        assert_eq!(parse("DROP VIEW a").unwrap(), None);
+
+        assert_eq!(
+            parse("SET CONSTRAINTS ALL DEFERRED").unwrap(),
+            Some(Command::SetConstraints { names: None, deferred: true })
+        );
+        assert_eq!(
+            parse("set constraints A, \"b\" immediate;").unwrap(),
+            Some(Command::SetConstraints { names: Some(vec!["a".into(), "b".into()]), deferred: false })
+        );
+        assert!(parse("SET CONSTRAINTS ALL").is_err());
+        assert!(parse("SET CONSTRAINTS a b DEFERRED").is_err());
+        assert_eq!(parse("SET x = 1").unwrap(), None);
+
+        let trigger = |sql| match parse(sql).unwrap() {
+            Some(Command::CreateTrigger { table, trigger }) => (table, trigger),
+            other => panic!("{}: {:?}", sql, other),
+        };
+        let (table, t) = trigger("create trigger Audit after insert or DELETE on Accounts execute function log()");
+        assert_eq!(table, "accounts");
+        assert_eq!(
+            t,
+            TriggerDef {
+                name: "audit".into(),
+                timing: TriggerTiming::After,
+                events: vec![TriggerEvent::Insert, TriggerEvent::Delete],
+                for_each_row: false,
+                body: TriggerBody::Function("log".into()),
+            }
+        );
+        let (_, t) = trigger(
+            "CREATE TRIGGER t BEFORE UPDATE ON a FOR EACH ROW BEGIN\n  INSERT INTO log VALUES ('it''s', NEW.id);\n  \
+             UPDATE c SET n = n + 1;\nEND;",
+        );
+        assert_eq!((t.timing, t.for_each_row), (TriggerTiming::Before, true));
+        assert_eq!(
+            t.body,
+            TriggerBody::Sql("INSERT INTO log VALUES ('it''s', NEW.id);\n  UPDATE c SET n = n + 1".into())
+        );
+        let (_, t) = trigger("CREATE TRIGGER t AFTER DELETE ON a FOR STATEMENT DELETE FROM b;");
+        assert_eq!(t.body, TriggerBody::Sql("DELETE FROM b".into()));
+        assert!(parse("CREATE TRIGGER t DURING INSERT ON a EXECUTE FUNCTION f()").is_err());
+        assert!(parse("CREATE TRIGGER t AFTER INSERT OR INSERT ON a EXECUTE FUNCTION f()").is_err());
+        assert!(parse("CREATE TRIGGER t AFTER INSERT ON a FOR EACH COLUMN EXECUTE FUNCTION f()").is_err());
+        assert!(parse("CREATE TRIGGER t AFTER INSERT ON a EXECUTE FUNCTION f(1)").is_err());
+        assert!(parse("CREATE TRIGGER t AFTER INSERT ON a BEGIN SELECT 1").is_err());
+        assert!(parse("CREATE TRIGGER t AFTER INSERT ON a").is_err());
+        assert_eq!(parse("CREATE TABLE t (a INT)").unwrap(), None);
+        assert_eq!(
+            parse("drop trigger if exists T on A").unwrap(),
+            Some(Command::DropTrigger { name: "t".into(), table: "a".into(), if_exists: true })
+        );
+        assert!(parse("DROP TRIGGER t").is_err());
+    }
+}
@@ -0,0 +1,583 @@
+//! Integrity constraints. NOT NULL and CHECK constraints are checked on each row INSERT or
+//! UPDATE is about to write. A UNIQUE constraint is backed by a unique index: a table of
+//! its own, named `table.constraint`, with a row per row of the table that has no NULL in
+//! the constrained columns. Index rows are keyed, like the rows of materialized views (see
+//! `view`), by a stable hash of their values, probing onwards past keys other values took.
+//! Inserting an index row locks its key, so two transactions adding the same values wait
+//! for each other and the second finds the first's row.
+//!
+//! A FOREIGN KEY is checked as the statement ends, or at commit when deferred. The parent
+//! row is read with `Transaction::get_for_share`, which keeps it from being deleted until
+//! the checking transaction ends. Deleting a parent row applies the ON DELETE action to the
+//! rows referencing it straight away, except NO ACTION, which is checked like the foreign
+//! key itself: by then, another parent row may have taken its place. The rows an action
+//! deletes or updates fire the row-level triggers of their table; one a BEFORE trigger
+//! skips is left referencing nothing, which fails the foreign key as the statement ends.
+
+use anyhow::{bail, Result};
+use sqlparser::dialect::GenericDialect;
+use sqlparser::parser::Parser;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::catalog::{Constraint, ConstraintKind, ReferentialAction, TableDef, TriggerEvent};
+use crate::db::Database;
+use crate::query::exec::{ExecContext, Row};
+use crate::query::expr::Expr;
+use crate::query::planner::Planner;
+use crate::query::trigger::Triggers;
+use crate::query::view::{close_gap, home_key, Maintenance};
+use crate::storage::{ColumnType, Table, Value};
+use crate::txn::{DeferredCheck, Transaction};
+
+/// Name of the table backing unique constraint `constraint` of table `table`.
+pub fn index_name(table: &str, constraint: &str) -> String {
+    format!("{}.{}", table, constraint)
+}
+
+/// The unique indexes of `def`: their names and schemas (the constrained columns, then the
+/// INT key).
+pub fn indexes(def: &TableDef) -> Vec<(String, Vec<ColumnType>)> {
+    def.constraints
+        .iter()
+        .filter_map(|c| match &c.kind {
+            ConstraintKind::Unique { columns } => {
+                let mut schema: Vec<ColumnType> = columns.iter().map(|i| def.columns[*i].ty).collect();
+                schema.push(ColumnType::Int);
+                Some((index_name(&def.name, &c.name), schema))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Check that the CHECK constraints of new table `def` are boolean expressions over its
+/// columns.
+pub fn validate(db: &Database, def: &TableDef) -> Result<()> {
+    checks(db, def).map(|_| ())
+}
+
+/// The CHECK constraints of `def`, bound to its rows.
+fn checks(db: &Database, def: &TableDef) -> Result<Vec<(String, Expr)>> {
+    let mut out = Vec::new();
+    for c in &def.constraints {
+        let ConstraintKind::Check { expr } = &c.kind else {
+            continue;
+        };
+        let planner = Planner::new(db);
+        let ast = Parser::new(&GenericDialect {}).try_with_sql(expr)?.parse_expr()?;
+        let bound = planner.row_expr(def, &ast)?;
+        if !planner.param_types().is_empty() {
+            bail!("CHECK constraint {} cannot have parameters", c.name);
+        }
+        if !matches!(bound.data_type(&def.schema()), ColumnType::Bool) {
+            bail!("CHECK constraint {} is not a boolean expression: {}", c.name, expr);
+        }
+        out.push((c.name.clone(), bound));
+    }
+    Ok(out)
+}
+
+/// The values of `columns` of `row`, or None if one of them is NULL (so no constraint on
+/// them applies).
+fn values(row: &[Value], columns: &[usize]) -> Option<Vec<Value>> {
+    columns
+        .iter()
+        .map(|c| Some(row[*c].clone()).filter(|v| !v.is_null()))
+        .collect()
+}
+
+/// Whether `c` is checked at commit, as SET CONSTRAINTS or its definition says.
+fn deferred(txn: &Transaction, c: &Constraint) -> bool {
+    c.deferrable && txn.constraint_deferred(&c.name).unwrap_or(c.initially_deferred)
+}
+
+/// Check constraint `c` of table `table` for `key` or `values` at commit if it is deferred,
+/// else as the statement ends (by adding the check to `pending`).
+fn queue(
+    pending: &mut Vec<DeferredCheck>,
+    txn: &mut Transaction,
+    table: &str,
+    c: &Constraint,
+    key: Option<i64>,
+    values: Vec<Value>,
+) {
+    let check = DeferredCheck {
+        table: table.to_string(),
+        constraint: c.name.clone(),
+        key,
+        values,
+    };
+    match deferred(txn, c) {
+        true => txn.defer(check),
+        false => pending.push(check),
+    }
+}
+
+fn duplicate(c: &Constraint, values: &[Value]) -> anyhow::Error {
+    let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+    anyhow::anyhow!("duplicate key value violates unique constraint {}: ({}) already exists", c.name, values.join(", "))
+}
+
+fn index_entry(values: &[Value], key: i64) -> Row {
+    values.iter().cloned().chain([Value::Int(key)]).collect()
+}
+
+/// Keys of the entries of `index` for `values`, found with `read`, which reads an entry.
+fn find_entries(
+    txn: &mut Transaction,
+    index: &Table,
+    values: &[Value],
+    read: fn(&mut Transaction, &Table, i64) -> Result<Option<Row>>,
+    all: bool,
+) -> Result<Vec<i64>> {
+    let mut found = Vec::new();
+    let mut key = home_key(values);
+    while let Some(entry) = read(txn, index, key)? {
+        if entry[..values.len()] == *values {
+            found.push(key);
+            if !all {
+                break;
+            }
+        }
+        key += 1;
+    }
+    Ok(found)
+}
+
+/// Whether a row of `parent` has `values` in `columns` (its key column or those of one of
+/// its UNIQUE constraints). If `share`, that row is locked against changes.
+fn parent_has(
+    db: &Database,
+    txn: &mut Transaction,
+    parent: &TableDef,
+    columns: &[usize],
+    values: &[Value],
+    share: bool,
+) -> Result<bool> {
+    let read = match share {
+        true => Transaction::get_for_share,
+        false => Transaction::get,
+    };
+    if columns == [parent.key_col] {
+        let Value::Int(key) = values[0] else {
+            bail!("key of table {} must be INT, not {}", parent.name, values[0]);
+        };
+        return Ok(read(txn, &*db.table(&parent.name)?, key)?.is_some());
+    }
+    let unique = parent.constraints.iter().find_map(|c| match &c.kind {
+        ConstraintKind::Unique { columns: unique } if unique.len() == columns.len() => {
+            let order: Option<Vec<usize>> = unique.iter().map(|u| columns.iter().position(|c| c == u)).collect();
+            order.map(|order| (c, order))
+        }
+        _ => None,
+    });
+    let Some((c, order)) = unique else {
+        bail!("no unique constraint of table {} matches a foreign key referencing it", parent.name);
+    };
+    let values: Vec<Value> = order.iter().map(|i| values[*i].clone()).collect();
+    let index = db.table(&index_name(&parent.name, &c.name))?;
+    Ok(!find_entries(txn, &index, &values, read, false)?.is_empty())
+}
+
+/// Rows of `child` with `values` in `columns`.
+fn referencing(txn: &mut Transaction, child: &Table, columns: &[usize], values: &[Value]) -> Result<Vec<Row>> {
+    Ok(txn
+        .scan(child)?
+        .into_iter()
+        .filter(|row| columns.iter().zip(values).all(|(c, v)| row[*c] == *v))
+        .collect())
+}
+
+fn still_referenced(parent: &str, c: &Constraint, child: &str) -> anyhow::Error {
+    anyhow::anyhow!(
+        "update or delete on table {} violates foreign key constraint {} on table {}",
+        parent,
+        c.name,
+        child
+    )
+}
+
+/// Run `check`: a row of the table must not share the constrained values with another
+/// (UNIQUE), the row with the key must have a parent (FOREIGN KEY), or rows with the
+/// values, if there still are any, must still have a parent (the NO ACTION of a foreign
+/// key). Checks of constraints dropped since pass.
+pub fn verify(db: &Database, txn: &mut Transaction, check: &DeferredCheck) -> Result<()> {
+    let Some(def) = db.table_def(&check.table) else {
+        return Ok(());
+    };
+    let Some(c) = def.constraint(&check.constraint) else {
+        return Ok(());
+    };
+    match &c.kind {
+        ConstraintKind::Unique { .. } => {
+            let index = db.table(&index_name(&def.name, &c.name))?;
+            if find_entries(txn, &index, &check.values, Transaction::get, true)?.len() > 1 {
+                return Err(duplicate(c, &check.values));
+            }
+        }
+        ConstraintKind::ForeignKey {
+            columns,
+            parent,
+            parent_columns,
+            ..
+        } => {
+            let Some(parent_def) = db.table_def(parent) else {
+                bail!("table {} does not exist", parent);
+            };
+            let table = db.table(&def.name)?;
+            match check.key {
+                Some(key) => {
+                    // A row changed or deleted since was checked, if need be, for that.
+                    let Some(row) = txn.get(&table, key)? else {
+                        return Ok(());
+                    };
+                    if values(&row, columns).as_ref() != Some(&check.values) {
+                        return Ok(());
+                    }
+                    if !parent_has(db, txn, &parent_def, parent_columns, &check.values, true)? {
+                        bail!(
+                            "insert or update on table {} violates foreign key constraint {}: no row of {} has ({})",
+                            def.name,
+                            c.name,
+                            parent,
+                            check.values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ")
+                        );
+                    }
+                }
+                None => {
+                    if !parent_has(db, txn, &parent_def, parent_columns, &check.values, false)?
+                        && !referencing(txn, &table, columns, &check.values)?.is_empty()
+                    {
+                        return Err(still_referenced(parent, c, &def.name));
+                    }
+                }
+            }
+        }
+        ConstraintKind::Check { .. } => {}
+    }
+    Ok(())
+}
+
+/// Run the checks `txn` deferred: all of them (at commit), or those of constraints no
+/// longer deferred (after SET CONSTRAINTS ... IMMEDIATE), deferring the others again.
+pub fn check_deferred(db: &Database, txn: &mut Transaction, at_commit: bool) -> Result<()> {
+    let mut checks = txn.take_deferred().into_iter();
+    while let Some(check) = checks.next() {
+        if !at_commit {
+            let def = db.table_def(&check.table);
+            if def.and_then(|d| d.constraint(&check.constraint).cloned()).is_some_and(|c| deferred(txn, &c)) {
+                txn.defer(check);
+                continue;
+            }
+        }
+        if let Err(e) = verify(db, txn, &check) {
+            // The transaction may go on: it has to pass these at commit.
+            if !at_commit {
+                txn.defer(check);
+                checks.for_each(|c| txn.defer(c));
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// A foreign key referencing the table an `Enforcer` writes.
+struct Reference {
+    child: Arc<Table>,
+    constraint: Constraint,
+}
+
+/// Enforces the constraints of one table as a DML operator writes it, along with the
+/// foreign keys referencing it. The operator calls `check` before writing a row, then
+/// `inserted`, `updated` or `deleted`, and `finish` once it is done.
+pub struct Enforcer {
+    table: Arc<Table>,
+    def: TableDef,
+    checks: Vec<(String, Expr)>,
+    /// Unique constraints, with their columns and indexes.
+    indexes: Vec<(Constraint, Vec<usize>, Arc<Table>)>,
+    references: Vec<Reference>,
+    /// Checks to run as the statement ends.
+    pending: Vec<DeferredCheck>,
+    /// Tables ON DELETE actions changed, by name, with their incremental views and triggers.
+    cascades: HashMap<String, (Enforcer, Maintenance, Triggers)>,
+}
+
+impl Enforcer {
+    pub fn new(db: &Database, def: &TableDef) -> Result<Self> {
+        let indexes = def
+            .constraints
+            .iter()
+            .filter_map(|c| match &c.kind {
+                ConstraintKind::Unique { columns } => Some((c, columns)),
+                _ => None,
+            })
+            .map(|(c, columns)| Ok((c.clone(), columns.clone(), db.table(&index_name(&def.name, &c.name))?)))
+            .collect::<Result<_>>()?;
+        let references = db
+            .references_to(&def.name)
+            .into_iter()
+            .map(|(child, constraint)| Ok(Reference { child: db.table(&child)?, constraint }))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            table: db.table(&def.name)?,
+            def: def.clone(),
+            checks: checks(db, def)?,
+            indexes,
+            references,
+            pending: Vec::new(),
+            cascades: HashMap::new(),
+        })
+    }
+
+    /// Check the NOT NULL and CHECK constraints on `row`, about to be written.
+    pub fn check(&self, row: &[Value]) -> Result<()> {
+        for (col, v) in self.def.columns.iter().zip(row) {
+            if col.not_null && v.is_null() {
+                bail!("null value in column {} of relation {} violates not-null constraint", col.name, self.def.name);
+            }
+        }
+        for (name, e) in &self.checks {
+            if e.eval(row)? == Value::Bool(false) {
+                bail!("new row for relation {} violates check constraint {}", self.def.name, name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the index entries of the unique constraint `i` for `values`. A duplicate fails
+    /// unless the constraint is deferrable: then it is checked again later, as another row
+    /// may give up the values first.
+    fn add_entry(&mut self, txn: &mut Transaction, i: usize, values: Vec<Value>) -> Result<()> {
+        let (c, _, index) = &self.indexes[i];
+        let mut key = home_key(&values);
+        let mut duplicated = false;
+        while let Some(existing) = txn.insert_or_existing(index, index_entry(&values, key))? {
+            if existing[..values.len()] == *values {
+                if !c.deferrable {
+                    return Err(duplicate(c, &values));
+                }
+                duplicated = true;
+            }
+            key += 1;
+        }
+        if duplicated {
+            queue(&mut self.pending, txn, &self.def.name, c, None, values);
+        }
+        Ok(())
+    }
+
+    fn remove_entry(&self, txn: &mut Transaction, i: usize, values: &[Value]) -> Result<()> {
+        let index = &self.indexes[i].2;
+        let n = values.len();
+        if let Some(&key) = find_entries(txn, index, values, Transaction::get, false)?.first() {
+            txn.delete(index, key)?;
+            close_gap(txn, index, key, &|entry| home_key(&entry[..n]))?;
+        }
+        Ok(())
+    }
+
+    /// Check that the row `new` (which replaced `old`, if any) references existing parent
+    /// rows.
+    fn check_parents(&mut self, txn: &mut Transaction, old: Option<&[Value]>, new: &[Value]) -> Result<()> {
+        let key = self.table.key_of(new)?;
+        for c in &self.def.constraints {
+            let ConstraintKind::ForeignKey { columns, .. } = &c.kind else {
+                continue;
+            };
+            let Some(referenced) = values(new, columns) else {
+                continue;
+            };
+            if old.is_some_and(|old| values(old, columns).as_ref() == Some(&referenced)) {
+                continue;
+            }
+            queue(&mut self.pending, txn, &self.def.name, c, Some(key), referenced);
+        }
+        Ok(())
+    }
+
+    pub fn inserted(&mut self, ctx: &mut ExecContext<'_>, row: &[Value]) -> Result<()> {
+        for i in 0..self.indexes.len() {
+            if let Some(values) = values(row, &self.indexes[i].1) {
+                self.add_entry(ctx.txn, i, values)?;
+            }
+        }
+        self.check_parents(ctx.txn, None, row)
+    }
+
+    pub fn updated(&mut self, ctx: &mut ExecContext<'_>, old: &[Value], new: &[Value]) -> Result<()> {
+        for i in 0..self.indexes.len() {
+            let columns = &self.indexes[i].1;
+            let (before, after) = (values(old, columns), values(new, columns));
+            if before == after {
+                continue;
+            }
+            if let Some(before) = before {
+                self.remove_entry(ctx.txn, i, &before)?;
+            }
+            if let Some(after) = after {
+                self.add_entry(ctx.txn, i, after)?;
+            }
+        }
+        self.check_parents(ctx.txn, Some(old), new)?;
+        // Whatever the ON DELETE action, taking values away from referencing rows is NO ACTION.
+        for r in &self.references {
+            let ConstraintKind::ForeignKey { parent_columns, .. } = &r.constraint.kind else {
+                continue;
+            };
+            let Some(before) = values(old, parent_columns) else {
+                continue;
+            };
+            if values(new, parent_columns).as_ref() != Some(&before) {
+                queue(&mut self.pending, ctx.txn, r.child.name(), &r.constraint, None, before);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn deleted(&mut self, ctx: &mut ExecContext<'_>, old: &[Value]) -> Result<()> {
+        for i in 0..self.indexes.len() {
+            if let Some(values) = values(old, &self.indexes[i].1) {
+                self.remove_entry(ctx.txn, i, &values)?;
+            }
+        }
+        let Self {
+            def,
+            references,
+            pending,
+            cascades,
+            ..
+        } = self;
+        for r in references.iter() {
+            let ConstraintKind::ForeignKey {
+                columns,
+                parent_columns,
+                on_delete,
+                ..
+            } = &r.constraint.kind
+            else {
+                continue;
+            };
+            let Some(values) = values(old, parent_columns) else {
+                continue;
+            };
+            if *on_delete == ReferentialAction::NoAction {
+                queue(pending, ctx.txn, r.child.name(), &r.constraint, None, values);
+                continue;
+            }
+            let rows = referencing(ctx.txn, &r.child, columns, &values)?;
+            if rows.is_empty() {
+                continue;
+            }
+            if *on_delete == ReferentialAction::Restrict {
+                return Err(still_referenced(&def.name, &r.constraint, r.child.name()));
+            }
+            let (child, views, triggers) = match cascades.get_mut(r.child.name()) {
+                Some(cascade) => cascade,
+                None => {
+                    let Some(child_def) = ctx.db.table_def(r.child.name()) else {
+                        bail!("table {} does not exist", r.child.name());
+                    };
+                    let cascade = (
+                        Enforcer::new(ctx.db, &child_def)?,
+                        Maintenance::new(ctx.db, r.child.name())?,
+                        Triggers::new(ctx.db, &child_def, &[TriggerEvent::Delete, TriggerEvent::Update])?,
+                    );
+                    cascades.entry(child_def.name).or_insert(cascade)
+                }
+            };
+            let mut skipped = false;
+            for row in rows {
+                let key = r.child.key_of(&row)?;
+                if *on_delete == ReferentialAction::Cascade {
+                    if !triggers.before_delete(ctx, &row)? {
+                        skipped = true;
+                        continue;
+                    }
+                    if ctx.txn.delete(&r.child, key)? {
+                        child.deleted(ctx, &row)?;
+                        views.record(Some(&row), None)?;
+                        triggers.after_row(ctx, TriggerEvent::Delete, Some(&row), None)?;
+                    }
+                    continue;
+                }
+                let mut new = row.clone();
+                for c in columns {
+                    new[*c] = Value::Null;
+                }
+                let Some(new) = triggers.before_row(ctx, TriggerEvent::Update, Some(&row), new)? else {
+                    skipped = true;
+                    continue;
+                };
+                child.check(&new)?;
+                if ctx.txn.update(&r.child, key, new.clone())? {
+                    child.updated(ctx, &row, &new)?;
+                    views.record(Some(&row), Some(&new))?;
+                    triggers.after_row(ctx, TriggerEvent::Update, Some(&row), Some(&new))?;
+                }
+            }
+            if skipped {
+                queue(pending, ctx.txn, r.child.name(), &r.constraint, None, values);
+            }
+        }
+        Ok(())
+    }
+
+    /// Finish the tables ON DELETE actions changed, then run the checks left for the end
+    /// of the statement.
+    pub fn finish(&mut self, ctx: &mut ExecContext<'_>) -> Result<()> {
+        for (child, views, _) in self.cascades.values_mut() {
+            child.finish(ctx)?;
+            views.flush(ctx.txn)?;
+        }
+        for check in std::mem::take(&mut self.pending) {
+            ctx.limits.check()?;
+            verify(ctx.db, ctx.txn, &check)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::ColumnDef;
+
+    #[test]
+    fn index_schemas_and_null_values() {
+        let def = TableDef {
+            name: "t".into(),
+            columns: vec![
+                ColumnDef::new("id", ColumnType::Int),
+                ColumnDef::new("a", ColumnType::Text),
+                ColumnDef::new("b", ColumnType::Float),
+            ],
+            key_col: 0,
+            constraints: vec![
+                Constraint {
+                    name: "t_b_a_key".into(),
+                    kind: ConstraintKind::Unique { columns: vec![2, 1] },
+                    deferrable: false,
+                    initially_deferred: false,
+                },
+                Constraint {
+                    name: "t_check".into(),
+                    kind: ConstraintKind::Check { expr: "b > 0".into() },
+                    deferrable: false,
+                    initially_deferred: false,
+                },
+            ],
+            triggers: Vec::new(),
+        };
+        assert_eq!(
+            indexes(&def),
+            vec![("t.t_b_a_key".to_string(), vec![ColumnType::Float, ColumnType::Text, ColumnType::Int])]
+        );
+        let row = vec![Value::Int(1), Value::Text("x".into()), Value::Null];
+        assert_eq!(values(&row, &[1, 0]), Some(vec![Value::Text("x".into()), Value::Int(1)]));
+        assert_eq!(values(&row, &[1, 2]), None);
+        assert_eq!(index_entry(&[Value::Int(7)], 3), vec![Value::Int(7), Value::Int(3)]);
+    }
+}
@@ -0,0 +1,634 @@
+//! Translation of CREATE TABLE and CREATE SEQUENCE into catalog definitions.
+
+use anyhow::{bail, Result};
+use sqlparser::ast::{
+    self, ColumnOption, ConstraintCharacteristics, DataType, DeferrableInitial, GeneratedAs, Ident, ObjectName,
+    SequenceOptions, TableConstraint,
+};
+
+use crate::catalog::{ColumnDef, Constraint, ConstraintKind, Identity, ReferentialAction, SequenceDef, TableDef};
+use crate::sequence::DEFAULT_CACHE;
+use crate::storage::ColumnType;
+
+/// Column added to tables without an INT primary key to serve as their row key.
+pub const ROWID_COLUMN: &str = "rowid";
+
+/// Identifier as the catalog stores it: unquoted names fold to lower case.
+pub fn ident(id: &Ident) -> String {
+    match id.quote_style {
+        Some(_) => id.value.clone(),
+        None => id.value.to_ascii_lowercase(),
+    }
+}
+
+/// Name of a table; schema-qualified names are not supported.
+pub fn object_name(name: &ObjectName) -> Result<String> {
+    match name.0.as_slice() {
+        [id] => Ok(ident(id)),
+        _ => bail!("schema-qualified names are not supported: {}", name),
+    }
+}
+
+/// Storage type for a SQL data type.
+pub fn column_type(dt: &DataType) -> Result<ColumnType> {
+    Ok(match dt {
+        DataType::Int(_)
+        | DataType::Integer(_)
+        | DataType::BigInt(_)
+        | DataType::SmallInt(_)
+        | DataType::TinyInt(_)
+        | DataType::Int2(_)
+        | DataType::Int4(_)
+        | DataType::Int8(_)
+        | DataType::Int64 => ColumnType::Int,
+        DataType::Float(_)
+        | DataType::Real
+        | DataType::Float4
+        | DataType::Float8
+        | DataType::Float64
+        | DataType::Double
+        | DataType::DoublePrecision => ColumnType::Float,
+        DataType::Text
+        | DataType::String(_)
+        | DataType::Varchar(_)
+        | DataType::Char(_)
+        | DataType::Character(_)
+        | DataType::CharVarying(_)
+        | DataType::CharacterVarying(_) => ColumnType::Text,
+        DataType::Bool | DataType::Boolean => ColumnType::Bool,
+        other => bail!("unsupported data type: {}", other),
+    })
+}
+
+/// Whether `dt` is one of the SERIAL types: INT, filled from a sequence of its own.
+fn is_serial(dt: &DataType) -> bool {
+    match dt {
+        DataType::Custom(name, modifiers) if modifiers.is_empty() => object_name(name).is_ok_and(|n| {
+            matches!(n.as_str(), "serial" | "serial2" | "serial4" | "serial8" | "smallserial" | "bigserial")
+        }),
+        _ => false,
+    }
+}
+
+/// Table definition for `CREATE TABLE name (columns, constraints)`, with the sequences of
+/// its identity (and SERIAL) columns. The row key is the INT PRIMARY KEY column if there is
+/// one; otherwise a hidden `rowid` column is appended. `parent` looks up the tables foreign
+/// keys reference.
+pub fn create_table(
+    name: &ObjectName,
+    columns: &[ast::ColumnDef],
+    constraints: &[TableConstraint],
+    parent: &dyn Fn(&str) -> Option<TableDef>,
+) -> Result<(TableDef, Vec<SequenceDef>)> {
+    let name = object_name(name)?;
+    if columns.is_empty() {
+        bail!("table {} must have at least one column", name);
+    }
+    let mut defs: Vec<ColumnDef> = Vec::new();
+    let mut key = None;
+    let mut set_key = |col: usize, defs: &[ColumnDef], c: &Option<ConstraintCharacteristics>| -> Result<()> {
+        if key.replace(col).is_some() {
+            bail!("multiple primary keys for table {} are not allowed", name);
+        }
+        if defs[col].ty != ColumnType::Int {
+            bail!("primary key column {} must be INT", defs[col].name);
+        }
+        if c.is_some_and(|c| c.deferrable == Some(true) || c.initially.is_some()) {
+            bail!("deferrable primary keys are not supported");
+        }
+        Ok(())
+    };
+    // Constraints as written, named and resolved once all columns are known.
+    let mut pending = Vec::new();
+    let mut sequences = Vec::new();
+    for col in columns {
+        let serial = is_serial(&col.data_type);
+        let ty = if serial { ColumnType::Int } else { column_type(&col.data_type)? };
+        let def = ColumnDef::new(ident(&col.name), ty);
+        if defs.iter().any(|d| d.name == def.name) {
+            bail!("column {} specified more than once", def.name);
+        }
+        defs.push(def);
+        let i = defs.len() - 1;
+        let mut nullable = false;
+        let mut identity = serial.then_some((false, &[][..]));
+        for opt in &col.options {
+            let named = opt.name.as_ref().map(ident);
+            let column = || vec![col.name.clone()];
+            match &opt.option {
+                ColumnOption::Unique {
+                    is_primary: true,
+                    characteristics,
+                } => set_key(i, &defs, characteristics)?,
+                ColumnOption::NotNull => defs[i].not_null = true,
+                ColumnOption::Null => nullable = true,
+                ColumnOption::Unique {
+                    is_primary: false,
+                    characteristics,
+                } => pending.push(Pending::Unique(named, column(), *characteristics)),
+                ColumnOption::Check(expr) => pending.push(Pending::Check(named, Some(i), expr)),
+                ColumnOption::ForeignKey {
+                    foreign_table,
+                    referred_columns,
+                    on_delete,
+                    on_update,
+                    characteristics,
+                } => pending.push(Pending::ForeignKey {
+                    name: named,
+                    columns: column(),
+                    parent: foreign_table,
+                    parent_columns: referred_columns,
+                    on_delete: *on_delete,
+                    on_update: *on_update,
+                    characteristics: *characteristics,
+                }),
+                ColumnOption::Generated {
+                    generated_as: generated_as @ (GeneratedAs::Always | GeneratedAs::ByDefault),
+                    sequence_options,
+                    generation_expr: None,
+                    ..
+                } => {
+                    let options = sequence_options.as_deref().unwrap_or_default();
+                    if identity.replace((*generated_as == GeneratedAs::Always, options)).is_some() {
+                        bail!("multiple identity specifications for column {}", defs[i].name);
+                    }
+                }
+                ColumnOption::Default(expr) => {
+                    if defs[i].default.replace(expr.to_string()).is_some() {
+                        bail!("multiple default values specified for column {}", defs[i].name);
+                    }
+                }
+                other => bail!("column constraint {} is not supported", other),
+            }
+        }
+        if identity.is_some() && defs[i].default.is_some() {
+            bail!("both default and identity specified for column {}", defs[i].name);
+        }
+        if let Some((always, options)) = identity {
+            if defs[i].ty != ColumnType::Int {
+                bail!("identity column {} must be INT", defs[i].name);
+            }
+            let seq_name = format!("{}_{}_seq", name, defs[i].name);
+            let mut seq = sequence(seq_name, options)?;
+            seq.owned_by = Some(name.clone());
+            defs[i].not_null = true;
+            defs[i].identity = Some(Identity {
+                sequence: seq.name.clone(),
+                always,
+            });
+            sequences.push(seq);
+        }
+        if nullable && defs[i].not_null {
+            bail!("conflicting NULL/NOT NULL declarations for column {}", defs[i].name);
+        }
+    }
+    for c in constraints {
+        match c {
+            TableConstraint::PrimaryKey {
+                columns: cols,
+                characteristics,
+                ..
+            } => {
+                let [col] = cols.as_slice() else {
+                    bail!("composite primary keys are not supported");
+                };
+                let col_name = ident(col);
+                match defs.iter().position(|d| d.name == col_name) {
+                    Some(i) => set_key(i, &defs, characteristics)?,
+                    None => bail!("column {} named in key does not exist", col_name),
+                }
+            }
+            TableConstraint::Unique {
+                name: named,
+                columns,
+                characteristics,
+                ..
+            } => pending.push(Pending::Unique(named.as_ref().map(ident), columns.clone(), *characteristics)),
+            TableConstraint::Check { name: named, expr } => {
+                pending.push(Pending::Check(named.as_ref().map(ident), None, expr))
+            }
+            TableConstraint::ForeignKey {
+                name: named,
+                columns,
+                foreign_table,
+                referred_columns,
+                on_delete,
+                on_update,
+                characteristics,
+            } => pending.push(Pending::ForeignKey {
+                name: named.as_ref().map(ident),
+                columns: columns.clone(),
+                parent: foreign_table,
+                parent_columns: referred_columns,
+                on_delete: *on_delete,
+                on_update: *on_update,
+                characteristics: *characteristics,
+            }),
+            other => bail!("table constraint {} is not supported", other),
+        }
+    }
+    let key_col = match key {
+        Some(k) => k,
+        None => {
+            if defs.iter().any(|d| d.name == ROWID_COLUMN) {
+                bail!("table without a primary key cannot have a column named {}", ROWID_COLUMN);
+            }
+            defs.push(ColumnDef {
+                hidden: true,
+                ..ColumnDef::new(ROWID_COLUMN, ColumnType::Int)
+            });
+            defs.len() - 1
+        }
+    };
+    let mut def = TableDef {
+        name,
+        columns: defs,
+        key_col,
+        constraints: Vec::new(),
+        triggers: Vec::new(),
+    };
+    // Foreign keys last: one may reference a UNIQUE constraint of the table itself.
+    pending.sort_by_key(|p| matches!(p, Pending::ForeignKey { .. }));
+    for p in pending {
+        let c = p.resolve(&def, parent)?;
+        if def.constraint(&c.name).is_some() {
+            bail!("constraint {} for relation {} already exists", c.name, def.name);
+        }
+        def.constraints.push(c);
+    }
+    Ok((def, sequences))
+}
+
+/// Sequence definition for `CREATE SEQUENCE name [AS type] options [OWNED BY table.column]`.
+pub fn create_sequence(
+    name: &ObjectName,
+    data_type: Option<&DataType>,
+    options: &[SequenceOptions],
+    owned_by: Option<&ObjectName>,
+) -> Result<SequenceDef> {
+    if let Some(dt) = data_type.filter(|dt| !matches!(column_type(dt), Ok(ColumnType::Int))) {
+        bail!("sequence type must be INT, not {}", dt);
+    }
+    let mut def = sequence(object_name(name)?, options)?;
+    def.owned_by = match owned_by.map(|o| o.0.as_slice()) {
+        None => None,
+        Some([none]) if none.quote_style.is_none() && none.value.eq_ignore_ascii_case("none") => None,
+        Some([table, _column]) => Some(ident(table)),
+        Some(_) => bail!("invalid OWNED BY option: {}", owned_by.unwrap()),
+    };
+    Ok(def)
+}
+
+/// Sequence `name` with `options`, the others as PostgreSQL defaults them: ascending
+/// sequences count up from 1, descending ones down from -1.
+fn sequence(name: String, options: &[SequenceOptions]) -> Result<SequenceDef> {
+    let (mut increment, mut min_value, mut max_value, mut start, mut cache, mut cycle) =
+        (1, None, None, None, DEFAULT_CACHE, false);
+    for option in options {
+        match option {
+            SequenceOptions::IncrementBy(e, _) => increment = integer(e, "INCREMENT")?,
+            SequenceOptions::MinValue(e) => min_value = e.as_ref().map(|e| integer(e, "MINVALUE")).transpose()?,
+            SequenceOptions::MaxValue(e) => max_value = e.as_ref().map(|e| integer(e, "MAXVALUE")).transpose()?,
+            SequenceOptions::StartWith(e, _) => start = Some(integer(e, "START")?),
+            SequenceOptions::Cache(e) => cache = integer(e, "CACHE")?,
+            SequenceOptions::Cycle(no) => cycle = !no,
+        }
+    }
+    if increment == 0 {
+        bail!("INCREMENT must not be zero");
+    }
+    let min_value = min_value.unwrap_or(if increment > 0 { 1 } else { i64::MIN });
+    let max_value = max_value.unwrap_or(if increment > 0 { i64::MAX } else { -1 });
+    if min_value >= max_value {
+        bail!("MINVALUE ({}) must be less than MAXVALUE ({})", min_value, max_value);
+    }
+    let start = start.unwrap_or(if increment > 0 { min_value } else { max_value });
+    if start < min_value {
+        bail!("START value ({}) cannot be less than MINVALUE ({})", start, min_value);
+    }
+    if start > max_value {
+        bail!("START value ({}) cannot be greater than MAXVALUE ({})", start, max_value);
+    }
+    if cache < 1 {
+        bail!("CACHE ({}) must be greater than zero", cache);
+    }
+    Ok(SequenceDef {
+        name,
+        start,
+        increment,
+        min_value,
+        max_value,
+        cache,
+        cycle,
+        owned_by: None,
+    })
+}
+
+/// The value of the integer constant given for sequence option `what`.
+fn integer(e: &ast::Expr, what: &str) -> Result<i64> {
+    match e {
+        ast::Expr::Value(ast::Value::Number(s, _)) if s.parse::<i64>().is_ok() => Ok(s.parse()?),
+        _ => bail!("{} must be an integer constant, got {}", what, e),
+    }
+}
+
+/// A constraint of CREATE TABLE as written: its name, if it was given one, and what it
+/// constrains by column name.
+enum Pending<'a> {
+    Unique(Option<String>, Vec<Ident>, Option<ConstraintCharacteristics>),
+    /// A CHECK, and the column it was written on, if any.
+    Check(Option<String>, Option<usize>, &'a ast::Expr),
+    ForeignKey {
+        name: Option<String>,
+        columns: Vec<Ident>,
+        parent: &'a ObjectName,
+        parent_columns: &'a [Ident],
+        on_delete: Option<ast::ReferentialAction>,
+        on_update: Option<ast::ReferentialAction>,
+        characteristics: Option<ConstraintCharacteristics>,
+    },
+}
+
+impl Pending<'_> {
+    fn resolve(self, def: &TableDef, parent: &dyn Fn(&str) -> Option<TableDef>) -> Result<Constraint> {
+        let column_names = |cols: &[usize]| cols.iter().map(|c| def.columns[*c].name.as_str()).collect::<Vec<_>>();
+        let (name, kind, characteristics) = match self {
+            Pending::Unique(name, columns, characteristics) => {
+                let columns = constrained_columns(def, &columns)?;
+                let name = name.unwrap_or_else(|| unique_name(def, &column_names(&columns), "key"));
+                (name, ConstraintKind::Unique { columns }, characteristics)
+            }
+            Pending::Check(name, column, expr) => {
+                let name = name.unwrap_or_else(|| match column {
+                    Some(c) => unique_name(def, &[def.columns[c].name.as_str()], "check"),
+                    None => unique_name(def, &[], "check"),
+                });
+                let expr = expr.to_string();
+                (name, ConstraintKind::Check { expr }, None)
+            }
+            Pending::ForeignKey {
+                name,
+                columns,
+                parent: parent_name,
+                parent_columns,
+                on_delete,
+                on_update,
+                characteristics,
+            } => {
+                let columns = constrained_columns(def, &columns)?;
+                let parent_name = object_name(parent_name)?;
+                let parent_def = match parent_name == def.name {
+                    true => Some(def.clone()),
+                    false => parent(&parent_name),
+                };
+                let Some(parent_def) = parent_def else {
+                    bail!("referenced table {} does not exist", parent_name);
+                };
+                let parent_columns = referenced_columns(&parent_def, parent_columns)?;
+                if parent_columns.len() != columns.len() {
+                    bail!("number of referencing and referenced columns for foreign key disagree");
+                }
+                for (c, p) in columns.iter().zip(&parent_columns) {
+                    let (c, p) = (&def.columns[*c], &parent_def.columns[*p]);
+                    if c.ty != p.ty {
+                        let (cn, pn) = (&c.name, &p.name);
+                        bail!("foreign key column {} of type {} cannot reference {} of type {}", cn, c.ty, pn, p.ty);
+                    }
+                }
+                if let Some(action) = on_update.filter(|a| *a != ast::ReferentialAction::NoAction) {
+                    bail!("ON UPDATE {} is not supported", action);
+                }
+                let on_delete = match on_delete {
+                    None | Some(ast::ReferentialAction::NoAction) => ReferentialAction::NoAction,
+                    Some(ast::ReferentialAction::Restrict) => ReferentialAction::Restrict,
+                    Some(ast::ReferentialAction::Cascade) => ReferentialAction::Cascade,
+                    Some(ast::ReferentialAction::SetNull) => ReferentialAction::SetNull,
+                    Some(other) => bail!("ON DELETE {} is not supported", other),
+                };
+                let name = name.unwrap_or_else(|| unique_name(def, &column_names(&columns), "fkey"));
+                let kind = ConstraintKind::ForeignKey {
+                    columns,
+                    parent: parent_name,
+                    parent_columns,
+                    on_delete,
+                };
+                (name, kind, characteristics)
+            }
+        };
+        let (deferrable, initially, enforced) = match characteristics {
+            Some(c) => (c.deferrable, c.initially, c.enforced),
+            None => (None, None, None),
+        };
+        if enforced == Some(false) {
+            bail!("NOT ENFORCED constraints are not supported");
+        }
+        let initially_deferred = initially == Some(DeferrableInitial::Deferred);
+        if initially_deferred && deferrable == Some(false) {
+            bail!("constraint declared INITIALLY DEFERRED must be DEFERRABLE");
+        }
+        Ok(Constraint {
+            name,
+            kind,
+            deferrable: deferrable == Some(true) || initially_deferred,
+            initially_deferred,
+        })
+    }
+}
+
+fn constrained_columns(def: &TableDef, columns: &[Ident]) -> Result<Vec<usize>> {
+    let mut out = Vec::new();
+    for col in columns {
+        let name = ident(col);
+        match def.column_index(&name) {
+            Some(i) if !def.columns[i].hidden && !out.contains(&i) => out.push(i),
+            Some(i) if out.contains(&i) => bail!("column {} appears twice in constraint", name),
+            _ => bail!("column {} named in constraint does not exist", name),
+        }
+    }
+    Ok(out)
+}
+
+/// The columns of `parent` a foreign key references: its key column if `columns` is empty,
+/// else columns that are its key or, in some order, the columns of a UNIQUE constraint.
+fn referenced_columns(parent: &TableDef, columns: &[Ident]) -> Result<Vec<usize>> {
+    if columns.is_empty() {
+        if parent.columns[parent.key_col].hidden {
+            bail!("there is no primary key for referenced table {}", parent.name);
+        }
+        return Ok(vec![parent.key_col]);
+    }
+    let cols = constrained_columns(parent, columns)?;
+    let unique = cols == [parent.key_col]
+        || parent.constraints.iter().any(|c| match &c.kind {
+            ConstraintKind::Unique { columns } => {
+                columns.len() == cols.len() && columns.iter().all(|c| cols.contains(c))
+            }
+            _ => false,
+        });
+    if !unique {
+        bail!("there is no unique constraint matching given keys for referenced table {}", parent.name);
+    }
+    Ok(cols)
+}
+
+/// `table_columns_suffix`, as PostgreSQL names constraints, numbered if a constraint of
+/// `def` already has the name.
+fn unique_name(def: &TableDef, columns: &[&str], suffix: &str) -> String {
+    let base: Vec<&str> = std::iter::once(def.name.as_str()).chain(columns.iter().copied()).collect();
+    let base = format!("{}_{}", base.join("_"), suffix);
+    let mut name = base.clone();
+    let mut n = 0;
+    while def.constraint(&name).is_some() {
+        n += 1;
+        name = format!("{}{}", base, n);
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlparser::ast::Statement;
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+
+    fn with_sequences(sql: &str) -> Result<(TableDef, Vec<SequenceDef>)> {
+        match Parser::parse_sql(&GenericDialect {}, sql)?.remove(0) {
+            Statement::CreateTable { name, columns, constraints, .. } => {
+                create_table(&name, &columns, &constraints, &|name| {
+                    let parent = "CREATE TABLE p (id INT PRIMARY KEY, a INT, b TEXT, UNIQUE (b, a), c FLOAT UNIQUE)";
+                    (name == "p").then(|| def(parent).unwrap())
+                })
+            }
+            other => panic!("not a CREATE TABLE: {}", other),
+        }
+    }
+
+    fn def(sql: &str) -> Result<TableDef> {
+        with_sequences(sql).map(|(def, _)| def)
+    }
+
+    fn seq(sql: &str) -> Result<SequenceDef> {
+        match Parser::parse_sql(&GenericDialect {}, sql)?.remove(0) {
+            Statement::CreateSequence {
+                name,
+                data_type,
+                sequence_options,
+                owned_by,
+                ..
+            } => create_sequence(&name, data_type.as_ref(), &sequence_options, owned_by.as_ref()),
+            other => panic!("not a CREATE SEQUENCE: {}", other),
+        }
+    }
+
+    #[test]
+    fn primary_key_or_hidden_rowid() {
+        let t = def("CREATE TABLE Users (id INT PRIMARY KEY, name VARCHAR(20), score DOUBLE)").unwrap();
+        assert_eq!(t.name, "users");
+        assert_eq!(t.key_col, 0);
+        assert_eq!(t.schema(), vec![ColumnType::Int, ColumnType::Text, ColumnType::Float]);
+        let t = def("CREATE TABLE t (a TEXT, b INTEGER NOT NULL, PRIMARY KEY (b))").unwrap();
+        assert_eq!(t.key_col, 1);
+        let t = def("CREATE TABLE t (a TEXT, ok BOOLEAN)").unwrap();
+        assert_eq!(t.key_col, 2);
+        assert!(t.columns[2].hidden && t.columns[2].name == ROWID_COLUMN);
+
+        assert!(def("CREATE TABLE t (a TEXT PRIMARY KEY)").is_err());
+        assert!(def("CREATE TABLE t (a INT PRIMARY KEY, b INT PRIMARY KEY)").is_err());
+        assert!(def("CREATE TABLE t (a INT, a TEXT)").is_err());
+        assert!(def("CREATE TABLE t (a DATE)").is_err());
+        assert!(def("CREATE TABLE t (rowid INT)").is_err());
+    }
+
+    #[test]
+    fn constraints() {
+        let t = def(
+            "CREATE TABLE t (a INT NOT NULL UNIQUE CHECK (a > 0), b TEXT, c INT REFERENCES p ON DELETE CASCADE, \
+             CONSTRAINT two UNIQUE (a, b) DEFERRABLE INITIALLY DEFERRED, CHECK (a < c), \
+             FOREIGN KEY (c, b) REFERENCES p (a, b), FOREIGN KEY (a) REFERENCES t (a))",
+        )
+        .unwrap();
+        assert!(t.columns[0].not_null && !t.columns[1].not_null);
+        let names: Vec<&str> = t.constraints.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["t_a_key", "t_a_check", "two", "t_check", "t_c_fkey", "t_c_b_fkey", "t_a_fkey"]);
+        let two = t.constraint("two").unwrap();
+        assert!(two.deferrable && two.initially_deferred);
+        assert_eq!(two.kind, ConstraintKind::Unique { columns: vec![0, 1] });
+        assert_eq!(t.constraints[1].kind, ConstraintKind::Check { expr: "a > 0".into() });
+        assert_eq!(
+            t.constraints[4].kind,
+            ConstraintKind::ForeignKey {
+                columns: vec![2],
+                parent: "p".into(),
+                parent_columns: vec![0],
+                on_delete: ReferentialAction::Cascade,
+            }
+        );
+        let ConstraintKind::ForeignKey { parent_columns, .. } = &t.constraints[5].kind else {
+            panic!("not a foreign key: {:?}", t.constraints[5]);
+        };
+        assert_eq!(parent_columns, &[1, 2]);
+        assert!(!t.constraints[5].deferrable);
+
+        assert!(def("CREATE TABLE t (a INT NULL NOT NULL)").is_err());
+        assert!(def("CREATE TABLE t (a INT UNIQUE, CONSTRAINT t_a_key CHECK (a > 0))").is_err());
+        assert!(def("CREATE TABLE t (a INT UNIQUE (b))").is_err());
+        assert!(def("CREATE TABLE t (a INT, UNIQUE (a, a))").is_err());
+        assert!(def("CREATE TABLE t (a INT REFERENCES q)").is_err());
+        assert!(def("CREATE TABLE t (a INT REFERENCES p (a))").is_err());
+        assert!(def("CREATE TABLE t (a TEXT REFERENCES p)").is_err());
+        assert!(def("CREATE TABLE t (a INT REFERENCES p ON UPDATE CASCADE)").is_err());
+        assert!(def("CREATE TABLE t (a INT REFERENCES p NOT DEFERRABLE INITIALLY DEFERRED)").is_err());
+        assert!(def("CREATE TABLE t (a INT PRIMARY KEY DEFERRABLE)").is_err());
+        assert!(def("CREATE TABLE t (a INT REFERENCES t)").is_err());
+    }
+
+    #[test]
+    fn sequences_and_identity_columns() {
+        let s = seq("CREATE SEQUENCE s").unwrap();
+        assert_eq!((s.start, s.increment, s.min_value, s.max_value), (1, 1, 1, i64::MAX));
+        assert_eq!((s.cache, s.cycle, s.owned_by), (DEFAULT_CACHE, false, None));
+        let sql = "CREATE SEQUENCE s AS BIGINT INCREMENT BY 2 MINVALUE 5 MAXVALUE 10 CACHE 5 CYCLE OWNED BY t.id";
+        let s = seq(sql).unwrap();
+        assert_eq!((s.start, s.increment, s.min_value, s.max_value), (5, 2, 5, 10));
+        assert_eq!((s.cache, s.cycle, s.owned_by.as_deref()), (5, true, Some("t")));
+        assert_eq!(seq("CREATE SEQUENCE s NO MINVALUE START WITH 7").unwrap().start, 7);
+        assert!(seq("CREATE SEQUENCE s INCREMENT 0").is_err());
+        assert!(seq("CREATE SEQUENCE s MINVALUE 5 MAXVALUE 5").is_err());
+        assert!(seq("CREATE SEQUENCE s START 0").is_err());
+        assert!(seq("CREATE SEQUENCE s CACHE 0").is_err());
+        assert!(seq("CREATE SEQUENCE s AS TEXT").is_err());
+
+        let (t, seqs) = with_sequences(
+            "CREATE TABLE Orders (id INT GENERATED ALWAYS AS IDENTITY (START WITH 100) PRIMARY KEY, \
+             n SERIAL, note TEXT)",
+        )
+        .unwrap();
+        assert_eq!(t.key_col, 0);
+        let identity = |i: usize| t.columns[i].identity.as_ref().map(|id| (id.sequence.as_str(), id.always));
+        assert_eq!(identity(0), Some(("orders_id_seq", true)));
+        assert_eq!(identity(1), Some(("orders_n_seq", false)));
+        assert_eq!(identity(2), None);
+        assert!(t.columns[0].not_null && t.columns[1].not_null);
+        assert_eq!(seqs.iter().map(|s| (s.name.as_str(), s.start)).collect::<Vec<_>>(), [
+            ("orders_id_seq", 100),
+            ("orders_n_seq", 1)
+        ]);
+        assert!(seqs.iter().all(|s| s.owned_by.as_deref() == Some("orders")));
+        assert!(def("CREATE TABLE t (a INT GENERATED BY DEFAULT AS IDENTITY)").unwrap().columns[0].identity.is_some());
+        assert!(def("CREATE TABLE t (a TEXT GENERATED ALWAYS AS IDENTITY)").is_err());
+        assert!(def("CREATE TABLE t (a SERIAL GENERATED ALWAYS AS IDENTITY)").is_err());
+        assert!(def("CREATE TABLE t (a SERIAL NULL)").is_err());
+    }
+
+    #[test]
+    fn column_defaults() {
+        let t = def("CREATE TABLE t (a INT DEFAULT 7, b TEXT NOT NULL DEFAULT 'x' || 'y', c INT)").unwrap();
+        let defaults: Vec<_> = t.columns.iter().map(|c| c.default.as_deref()).collect();
+        assert_eq!(defaults, [Some("7"), Some("'x' || 'y'"), None, None]);
+        assert!(t.columns[1].not_null);
+        assert!(def("CREATE TABLE t (a INT DEFAULT 1 DEFAULT 2)").is_err());
+        assert!(def("CREATE TABLE t (a SERIAL DEFAULT 1)").is_err());
+        assert!(def("CREATE TABLE t (a INT GENERATED BY DEFAULT AS IDENTITY DEFAULT 1)").is_err());
+    }
+}
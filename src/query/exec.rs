@@ -0,0 +1,2184 @@
+//! Plan execution: one pull-based (Volcano) operator per plan node, producing rows one at
+//! a time inside the caller's transaction.
+
+use anyhow::{bail, Result};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::catalog::{TableDef, TriggerEvent, TriggerTiming};
+use crate::db::Database;
+use crate::query::aggregate::{Accumulator, AggCall, ACCUMULATOR_SIZE};
+use crate::query::constraint::Enforcer;
+use crate::query::expr::{coerce, BinaryOp, Expr};
+use crate::query::limits::{Limits, Reservation};
+use crate::query::optimizer::key_range;
+use crate::query::parallel::Gather;
+use crate::query::plan::{
+    AggStrategy, ApplyKind, IndexBounds, JoinType, Node, OnConflict, Plan, SetOpKind, SortKey,
+};
+use crate::query::spill::{self, SpillFile, SpillReader};
+use crate::query::table_fn::TableFunc;
+use crate::query::trigger::Triggers;
+use crate::query::vector;
+use crate::query::view::Maintenance;
+use crate::query::window::{Partition, WindowCall};
+use crate::storage::{ColumnType, Table, Value};
+use crate::txn::{LockMode, Transaction};
+
+pub type Row = Vec<Value>;
+
+/// What operators need while running: the database, the statement's transaction and the
+/// limits it runs under.
+pub struct ExecContext<'a> {
+    pub db: &'a Database,
+    pub txn: &'a mut Transaction,
+    pub limits: Arc<Limits>,
+    /// How many triggers the plan runs inside of (see `trigger`).
+    pub trigger_depth: usize,
+}
+
+pub trait Operator {
+    /// Next output row, or None when exhausted.
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>>;
+}
+
+/// Build the operator tree for `plan`: batch operators where the configuration asks for
+/// vectorized execution and the plan allows it (see `vector::build`), else row operators.
+pub fn build(plan: &Plan, db: &Database) -> Result<Box<dyn Operator>> {
+    if db.config().vectorized_execution {
+        return vector::build(plan, db);
+    }
+    build_with(plan, db, &mut |op| op)
+}
+
+/// Build the row operator tree for `plan`, passing each operator through `wrap` as soon as
+/// it is built (children before their parent).
+pub fn build_with(
+    plan: &Plan,
+    db: &Database,
+    wrap: &mut dyn FnMut(Box<dyn Operator>) -> Box<dyn Operator>,
+) -> Result<Box<dyn Operator>> {
+    let children = match plan.node {
+        // The workers of a Gather run its child themselves.
+        Node::Gather { .. } => Vec::new(),
+        _ => plan
+            .children
+            .iter()
+            .map(|c| build_with(c, db, wrap))
+            .collect::<Result<Vec<_>>>()?,
+    };
+    Ok(wrap(build_operator(plan, db, children)?))
+}
+
+/// The row operator for the root node of `plan`, reading from `children` (the operators
+/// for its children, in order).
+pub fn build_operator(plan: &Plan, db: &Database, children: Vec<Box<dyn Operator>>) -> Result<Box<dyn Operator>> {
+    let mut children = children.into_iter();
+    let mut child = || children.next().expect("plan node is missing a child");
+    let op: Box<dyn Operator> = match &plan.node {
+        Node::SeqScan { table, filter } => Box::new(SeqScan {
+            table: db.table(table)?,
+            filter: filter.clone(),
+            next_page: 0,
+            started: false,
+            buf: VecDeque::new(),
+        }),
+        Node::IndexScan { table, bounds, filter } => Box::new(IndexScan {
+            table: db.table(table)?,
+            bounds: bounds.clone(),
+            filter: filter.clone(),
+            buf: None,
+        }),
+        Node::Values { rows } => Box::new(Values {
+            rows: rows.clone().into(),
+        }),
+        Node::WorkTableScan { name } => bail!("work table {} read outside its recursive query", name),
+        Node::TableFunction { func, args } => Box::new(TableFunction {
+            func: func.clone(),
+            args: args.clone(),
+            buf: None,
+            memory: None,
+        }),
+        Node::Filter { predicate } => Box::new(Filter {
+            input: child(),
+            predicate: predicate.clone(),
+        }),
+        Node::Apply { kind, params, subplan } => Box::new(Apply {
+            input: child(),
+            kind: kind.clone(),
+            params: params.clone(),
+            subplan: (**subplan).clone(),
+            last: None,
+        }),
+        Node::Project { exprs } => Box::new(Project {
+            input: child(),
+            exprs: exprs.clone(),
+        }),
+        Node::NestedLoopJoin { join_type, on } => Box::new(NestedLoopJoin {
+            spec: JoinSpec::new(*join_type, on, plan, plan.children[1].columns.len()),
+            left: child(),
+            right: child(),
+            right_rows: None,
+            memory: None,
+            pending: VecDeque::new(),
+            done: false,
+        }),
+        Node::HashJoin {
+            join_type,
+            left_keys,
+            right_keys,
+            residual,
+        } => Box::new(HashJoin {
+            spec: JoinSpec::new(*join_type, residual, plan, plan.children[1].columns.len()),
+            left: child(),
+            right: child(),
+            left_keys: left_keys.clone(),
+            right_keys: right_keys.clone(),
+            work_mem: db.config().work_mem_kb * 1024,
+            temp_dir: db.temp_dir(),
+            batch: None,
+            memory: None,
+            partitions: VecDeque::new(),
+            started: false,
+            pending: VecDeque::new(),
+        }),
+        Node::IndexNestedLoopJoin {
+            join_type,
+            table,
+            key,
+            filter,
+        } => {
+            let table = db.table(table)?;
+            Box::new(IndexNestedLoopJoin {
+                spec: JoinSpec::new(*join_type, filter, plan, table.schema().len()),
+                outer: child(),
+                table,
+                key: key.clone(),
+                pending: VecDeque::new(),
+            })
+        }
+        Node::MergeJoin {
+            join_type,
+            left_keys,
+            right_keys,
+            residual,
+        } => Box::new(MergeJoin {
+            spec: JoinSpec::new(*join_type, residual, plan, plan.children[1].columns.len()),
+            left: child(),
+            right: child(),
+            left_keys: left_keys.clone(),
+            right_keys: right_keys.clone(),
+            group: Vec::new(),
+            group_key: None,
+            lookahead: None,
+            started: false,
+            done: false,
+            pending: VecDeque::new(),
+        }),
+        Node::Aggregate {
+            strategy,
+            group_by,
+            sets,
+            aggregates,
+        } => {
+            let spec = AggSpec {
+                group_by: group_by.clone(),
+                sets: sets.clone(),
+                aggregates: aggregates.clone(),
+            };
+            match strategy {
+                AggStrategy::Hash => Box::new(HashAggregate {
+                    input: child(),
+                    spec,
+                    work_mem: db.config().work_mem_kb * 1024,
+                    temp_dir: db.temp_dir(),
+                    output: None,
+                    partitions: VecDeque::new(),
+                }),
+                AggStrategy::Sorted => Box::new(SortedAggregate {
+                    input: child(),
+                    spec,
+                    group: None,
+                    done: false,
+                }),
+            }
+        }
+        Node::Window {
+            partition_by,
+            order_by,
+            functions,
+        } => Box::new(Window {
+            input: child(),
+            partition_by: partition_by.clone(),
+            order_by: order_by.clone(),
+            functions: functions.clone(),
+            next_partition: None,
+            output: VecDeque::new(),
+            memory: None,
+            done: false,
+        }),
+        Node::Sort { keys, limit } => Box::new(Sort {
+            input: child(),
+            keys: keys.as_slice().into(),
+            limit: limit.map(|n| usize::try_from(n).unwrap_or(usize::MAX)),
+            work_mem: db.config().work_mem_kb * 1024,
+            temp_dir: db.temp_dir(),
+            output: None,
+            memory: None,
+        }),
+        Node::Limit { limit, offset } => Box::new(Limit {
+            input: child(),
+            limit: *limit,
+            offset: *offset,
+            seen: 0,
+        }),
+        Node::RecursiveUnion {
+            name,
+            distinct,
+            recursive,
+        } => Box::new(RecursiveUnion {
+            input: child(),
+            name: name.clone(),
+            distinct: *distinct,
+            recursive: (**recursive).clone(),
+            max_depth: db.config().max_recursion_depth,
+            seen: HashSet::new(),
+            step: Vec::new(),
+            seen_memory: None,
+            step_memory: None,
+            current: None,
+            depth: 0,
+        }),
+        Node::Gather { workers } => Box::new(Gather::new(&plan.children[0], db, *workers)?),
+        Node::Append => Box::new(Append {
+            inputs: (0..plan.children.len()).map(|_| child()).collect(),
+            types: plan.columns.iter().map(|(_, t)| *t).collect(),
+            current: 0,
+        }),
+        Node::SetOp { op, all } => Box::new(SetOp {
+            input: child(),
+            op: *op,
+            all: *all,
+            pending: None,
+        }),
+        Node::Insert {
+            table,
+            columns,
+            on_conflict,
+        } => {
+            let def = table_def(db, table)?;
+            let events: &[TriggerEvent] = match on_conflict {
+                Some(OnConflict::DoUpdate { .. }) => &[TriggerEvent::Insert, TriggerEvent::Update],
+                _ => &[TriggerEvent::Insert],
+            };
+            Box::new(Insert {
+                input: child(),
+                table: db.table(table)?,
+                columns: columns.clone(),
+                on_conflict: on_conflict.clone(),
+                affected: HashSet::new(),
+                constraints: Enforcer::new(db, &def)?,
+                views: Maintenance::new(db, table)?,
+                triggers: Triggers::new(db, &def, events)?,
+                def,
+                rows: None,
+            })
+        }
+        Node::Update { table, assignments } => {
+            let def = table_def(db, table)?;
+            Box::new(Update {
+                input: child(),
+                table: db.table(table)?,
+                assignments: assignments.clone(),
+                constraints: Enforcer::new(db, &def)?,
+                views: Maintenance::new(db, table)?,
+                triggers: Triggers::new(db, &def, &[TriggerEvent::Update])?,
+                def,
+                rows: None,
+            })
+        }
+        Node::Delete { table } => {
+            let def = table_def(db, table)?;
+            Box::new(Delete {
+                input: child(),
+                table: db.table(table)?,
+                width: def.columns.len(),
+                constraints: Enforcer::new(db, &def)?,
+                views: Maintenance::new(db, table)?,
+                triggers: Triggers::new(db, &def, &[TriggerEvent::Delete])?,
+                rows: None,
+            })
+        }
+        Node::LockRows { table, mode } => {
+            let table = db.table(table)?;
+            Box::new(LockRows {
+                input: child(),
+                width: table.schema().len(),
+                table,
+                mode: *mode,
+            })
+        }
+    };
+    Ok(op)
+}
+
+fn table_def(db: &Database, name: &str) -> Result<TableDef> {
+    match db.table_def(name) {
+        Some(def) => Ok(def),
+        None => bail!("table {} does not exist", name),
+    }
+}
+
+/// Run `plan` to completion and collect its rows, which count against the statement's
+/// memory budget.
+pub fn execute(plan: &Plan, ctx: &mut ExecContext<'_>) -> Result<Vec<Row>> {
+    let mut op = build(plan, ctx.db)?;
+    let mut memory = ctx.limits.reservation();
+    let mut rows = Vec::new();
+    while let Some(row) = op.next(ctx)? {
+        ctx.limits.check()?;
+        memory.grow(spill::row_size(&row))?;
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+fn passes(filter: &Option<Expr>, row: &[Value]) -> Result<bool> {
+    match filter {
+        Some(f) => f.eval_predicate(row),
+        None => Ok(true),
+    }
+}
+
+fn drain(input: &mut dyn Operator, ctx: &mut ExecContext<'_>) -> Result<Vec<Row>> {
+    let mut rows = Vec::new();
+    while let Some(row) = input.next(ctx)? {
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// Like `drain`, counting the rows in `memory`.
+fn drain_reserved(input: &mut dyn Operator, ctx: &mut ExecContext<'_>, memory: &mut Reservation) -> Result<Vec<Row>> {
+    let mut rows = Vec::new();
+    while let Some(row) = input.next(ctx)? {
+        memory.grow(spill::row_size(&row))?;
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+fn concat(left: &[Value], right: &[Value]) -> Row {
+    let mut row = Vec::with_capacity(left.len() + right.len());
+    row.extend_from_slice(left);
+    row.extend_from_slice(right);
+    row
+}
+
+/// A value compared with an INT key column, as a key: None if it cannot equal any key.
+fn as_key(v: &Value) -> Result<Option<i64>> {
+    match v {
+        Value::Null => Ok(None),
+        Value::Int(n) => Ok(Some(*n)),
+        Value::Float(x) if x.fract() == 0.0 && *x >= i64::MIN as f64 && *x < i64::MAX as f64 => {
+            Ok(Some(*x as i64))
+        }
+        Value::Float(_) => Ok(None),
+        other => bail!("cannot compare INT key with {}", other),
+    }
+}
+
+struct SeqScan {
+    table: Arc<Table>,
+    filter: Option<Expr>,
+    next_page: u32,
+    started: bool,
+    buf: VecDeque<Row>,
+}
+
+impl Operator for SeqScan {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        if !self.started {
+            ctx.txn.note_table_scan(&self.table);
+            self.started = true;
+        }
+        loop {
+            while let Some(row) = self.buf.pop_front() {
+                if passes(&self.filter, &row)? {
+                    return Ok(Some(row));
+                }
+            }
+            if self.next_page >= self.table.num_pages() {
+                return Ok(None);
+            }
+            ctx.limits.check()?;
+            let rows = ctx.txn.scan_page(&self.table, self.next_page)?;
+            self.next_page += 1;
+            self.buf.extend(rows.into_iter().map(|(_, row)| row));
+        }
+    }
+}
+
+struct IndexScan {
+    table: Arc<Table>,
+    bounds: IndexBounds,
+    filter: Option<Expr>,
+    buf: Option<VecDeque<Row>>,
+}
+
+impl IndexScan {
+    fn fetch(&self, txn: &mut Transaction) -> Result<Vec<Row>> {
+        let mut rows = Vec::new();
+        match &self.bounds {
+            IndexBounds::Keys(exprs) => {
+                let mut keys = Vec::new();
+                for e in exprs {
+                    if let Some(k) = as_key(&e.eval(&[])?)? {
+                        keys.push(k);
+                    }
+                }
+                keys.sort_unstable();
+                keys.dedup();
+                for k in keys {
+                    rows.extend(txn.get(&self.table, k)?);
+                }
+            }
+            IndexBounds::Range { low, high } => {
+                let eval = |b: &Option<(Expr, bool)>| -> Result<Option<(Value, bool)>> {
+                    Ok(match b {
+                        Some((e, incl)) => Some((e.eval(&[])?, *incl)),
+                        None => None,
+                    })
+                };
+                let (low, high) = (eval(low)?, eval(high)?);
+                let range = key_range(
+                    low.as_ref().map(|(v, i)| (v, *i)),
+                    high.as_ref().map(|(v, i)| (v, *i)),
+                )?;
+                if let Some((start, end)) = range {
+                    match end.checked_add(1) {
+                        Some(end) => rows = txn.range(&self.table, start, end)?,
+                        None => {
+                            rows = txn.range(&self.table, start, i64::MAX)?;
+                            rows.extend(txn.get(&self.table, i64::MAX)?);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(rows)
+    }
+}
+
+impl Operator for IndexScan {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        if self.buf.is_none() {
+            ctx.limits.check()?;
+            self.buf = Some(self.fetch(ctx.txn)?.into());
+        }
+        let buf = self.buf.as_mut().unwrap();
+        while let Some(row) = buf.pop_front() {
+            if passes(&self.filter, &row)? {
+                return Ok(Some(row));
+            }
+        }
+        Ok(None)
+    }
+}
+
+struct Values {
+    rows: VecDeque<Vec<Expr>>,
+}
+
+impl Operator for Values {
+    fn next(&mut self, _ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        match self.rows.pop_front() {
+            Some(exprs) => Ok(Some(exprs.iter().map(|e| e.eval(&[])).collect::<Result<_>>()?)),
+            None => Ok(None),
+        }
+    }
+}
+
+struct TableFunction {
+    func: TableFunc,
+    args: Vec<Expr>,
+    buf: Option<VecDeque<Row>>,
+    memory: Option<Reservation>,
+}
+
+impl Operator for TableFunction {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        if self.buf.is_none() {
+            ctx.limits.check()?;
+            let args = self.args.iter().map(|e| e.eval(&[])).collect::<Result<Vec<_>>>()?;
+            let rows = self.func.call(ctx.db, &args)?.rows;
+            let mut memory = ctx.limits.reservation();
+            memory.grow(rows.iter().map(|r| spill::row_size(r)).sum())?;
+            self.memory = Some(memory);
+            self.buf = Some(rows.into());
+        }
+        Ok(self.buf.as_mut().unwrap().pop_front())
+    }
+}
+
+struct Filter {
+    input: Box<dyn Operator>,
+    predicate: Expr,
+}
+
+impl Operator for Filter {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        while let Some(row) = self.input.next(ctx)? {
+            if self.predicate.eval_predicate(&row)? {
+                return Ok(Some(row));
+            }
+        }
+        Ok(None)
+    }
+}
+
+struct Apply {
+    input: Box<dyn Operator>,
+    kind: ApplyKind,
+    params: Vec<Expr>,
+    subplan: Plan,
+    /// Parameter values and IN operand of the last run, and its result.
+    last: Option<(Vec<Value>, Option<Value>, Value)>,
+}
+
+impl Apply {
+    /// Run the subplan with `params` bound and reduce its rows to the appended value.
+    fn run(&self, ctx: &mut ExecContext<'_>, params: &[Value], operand: Option<&Value>) -> Result<Value> {
+        let mut op = build(&self.subplan.bind_outer(params), ctx.db)?;
+        match operand {
+            None if self.kind == ApplyKind::Exists => Ok(Value::Bool(op.next(ctx)?.is_some())),
+            None => {
+                let Some(row) = op.next(ctx)? else {
+                    return Ok(Value::Null);
+                };
+                if op.next(ctx)?.is_some() {
+                    bail!("more than one row returned by a subquery used as an expression");
+                }
+                Ok(row.into_iter().next().unwrap_or(Value::Null))
+            }
+            Some(operand) => {
+                let mut result = Value::Bool(false);
+                while let Some(row) = op.next(ctx)? {
+                    let value = row.into_iter().next().unwrap_or(Value::Null);
+                    let eq = Expr::binary(BinaryOp::Eq, Expr::Literal(operand.clone()), Expr::Literal(value));
+                    match eq.eval(&[])? {
+                        Value::Bool(true) => return Ok(Value::Bool(true)),
+                        Value::Null => result = Value::Null,
+                        _ => {}
+                    }
+                }
+                Ok(result)
+            }
+        }
+    }
+}
+
+impl Operator for Apply {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        let Some(mut row) = self.input.next(ctx)? else {
+            return Ok(None);
+        };
+        let params = self.params.iter().map(|p| p.eval(&row)).collect::<Result<Vec<_>>>()?;
+        let operand = match &self.kind {
+            ApplyKind::In(e) => Some(e.eval(&row)?),
+            _ => None,
+        };
+        let value = match &self.last {
+            Some((p, o, v)) if *p == params && *o == operand => v.clone(),
+            _ => {
+                let v = self.run(ctx, &params, operand.as_ref())?;
+                self.last = Some((params, operand, v.clone()));
+                v
+            }
+        };
+        row.push(value);
+        Ok(Some(row))
+    }
+}
+
+struct Project {
+    input: Box<dyn Operator>,
+    exprs: Vec<Expr>,
+}
+
+impl Operator for Project {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        match self.input.next(ctx)? {
+            Some(row) => Ok(Some(self.exprs.iter().map(|e| e.eval(&row)).collect::<Result<_>>()?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Output shaping shared by the join operators.
+struct JoinSpec {
+    join_type: JoinType,
+    /// Join condition beyond the keys the algorithm matches on.
+    residual: Option<Expr>,
+    left_width: usize,
+    right_width: usize,
+}
+
+impl JoinSpec {
+    fn new(join_type: JoinType, residual: &Option<Expr>, plan: &Plan, right_width: usize) -> Self {
+        Self {
+            join_type,
+            residual: residual.clone(),
+            left_width: plan.children[0].columns.len(),
+            right_width,
+        }
+    }
+
+    /// The joined row, if `left` and `right` satisfy the residual condition.
+    fn matches(&self, left: &[Value], right: &[Value]) -> Result<Option<Row>> {
+        let row = concat(left, right);
+        Ok(passes(&self.residual, &row)?.then_some(row))
+    }
+
+    /// Output for a left row once all its candidate matches have been tried; joined rows
+    /// themselves are emitted by the caller (unless this is a semi or anti join).
+    fn finish_left(&self, left: Row, matched: bool, out: &mut VecDeque<Row>) {
+        match self.join_type {
+            JoinType::Semi if matched => out.push_back(left),
+            JoinType::Anti if !matched => out.push_back(left),
+            JoinType::Left | JoinType::Full if !matched => {
+                out.push_back(concat(&left, &vec![Value::Null; self.right_width]))
+            }
+            _ => {}
+        }
+    }
+
+    /// Output for a right row that matched no left row.
+    fn unmatched_right(&self, right: &[Value], out: &mut VecDeque<Row>) {
+        if matches!(self.join_type, JoinType::Right | JoinType::Full) {
+            out.push_back(concat(&vec![Value::Null; self.left_width], right));
+        }
+    }
+}
+
+struct NestedLoopJoin {
+    left: Box<dyn Operator>,
+    right: Box<dyn Operator>,
+    spec: JoinSpec,
+    right_rows: Option<Vec<(Row, bool)>>,
+    /// Holds `right_rows`, which stay in memory.
+    memory: Option<Reservation>,
+    pending: VecDeque<Row>,
+    done: bool,
+}
+
+impl Operator for NestedLoopJoin {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        if self.right_rows.is_none() {
+            let mut memory = ctx.limits.reservation();
+            let rows = drain_reserved(&mut *self.right, ctx, &mut memory)?;
+            self.right_rows = Some(rows.into_iter().map(|r| (r, false)).collect());
+            self.memory = Some(memory);
+        }
+        loop {
+            if let Some(row) = self.pending.pop_front() {
+                return Ok(Some(row));
+            }
+            if self.done {
+                return Ok(None);
+            }
+            let rights = self.right_rows.as_mut().unwrap();
+            let Some(left) = self.left.next(ctx)? else {
+                for (right, matched) in rights.iter() {
+                    if !matched {
+                        self.spec.unmatched_right(right, &mut self.pending);
+                    }
+                }
+                self.done = true;
+                continue;
+            };
+            ctx.limits.check()?;
+            let mut any = false;
+            for (right, matched) in rights.iter_mut() {
+                if let Some(row) = self.spec.matches(&left, right)? {
+                    any = true;
+                    *matched = true;
+                    if !self.spec.join_type.emits_right() {
+                        break;
+                    }
+                    self.pending.push_back(row);
+                }
+            }
+            self.spec.finish_left(left, any, &mut self.pending);
+        }
+    }
+}
+
+/// Partitions a spilling hash join splits its inputs into.
+const HASH_PARTITIONS: u64 = 16;
+
+/// Join key of `row`, or None if any part is NULL (NULL never equals anything).
+fn hash_key(exprs: &[Expr], row: &[Value]) -> Result<Option<Vec<Value>>> {
+    let key = exprs.iter().map(|e| e.eval(row)).collect::<Result<Vec<_>>>()?;
+    Ok((!key.iter().any(Value::is_null)).then_some(key))
+}
+
+/// Partition of a join key; rows with a NULL key go to the first.
+fn partition(key: &Option<Vec<Value>>) -> usize {
+    use std::hash::{Hash, Hasher};
+    let Some(key) = key else {
+        return 0;
+    };
+    let mut h = std::collections::hash_map::DefaultHasher::new();
+    key.hash(&mut h);
+    (h.finish() % HASH_PARTITIONS) as usize
+}
+
+/// The build rows of one hash join batch, and where its probe rows come from.
+struct HashBatch {
+    rows: Vec<(Row, bool)>,
+    table: HashMap<Vec<Value>, Vec<usize>>,
+    /// None: probe with the left child itself.
+    probe: Option<SpillReader>,
+}
+
+impl HashBatch {
+    fn new(rows: Vec<Row>, keys: &[Expr], probe: Option<SpillReader>) -> Result<Self> {
+        let mut table: HashMap<Vec<Value>, Vec<usize>> = HashMap::new();
+        for (i, row) in rows.iter().enumerate() {
+            if let Some(key) = hash_key(keys, row)? {
+                table.entry(key).or_default().push(i);
+            }
+        }
+        Ok(Self {
+            rows: rows.into_iter().map(|r| (r, false)).collect(),
+            table,
+            probe,
+        })
+    }
+}
+
+struct HashJoin {
+    left: Box<dyn Operator>,
+    right: Box<dyn Operator>,
+    spec: JoinSpec,
+    left_keys: Vec<Expr>,
+    right_keys: Vec<Expr>,
+    work_mem: usize,
+    temp_dir: PathBuf,
+    batch: Option<HashBatch>,
+    /// Holds the build rows of `batch`.
+    memory: Option<Reservation>,
+    /// Spilled (build, probe) partitions not joined yet.
+    partitions: VecDeque<(SpillFile, SpillFile)>,
+    started: bool,
+    pending: VecDeque<Row>,
+}
+
+impl HashJoin {
+    /// Read the build side. If it fits in `work_mem` (and the statement's memory budget) it
+    /// becomes the only batch; otherwise both sides are partitioned by key hash into
+    /// temporary files, one batch each.
+    fn build(&mut self, ctx: &mut ExecContext<'_>) -> Result<()> {
+        let keep_nulls = matches!(self.spec.join_type, JoinType::Right | JoinType::Full);
+        let mut memory = ctx.limits.reservation();
+        let mut rows = Vec::new();
+        let mut size = 0;
+        let mut parts: Option<Vec<(SpillFile, SpillFile)>> = None;
+        while let Some(row) = self.right.next(ctx)? {
+            let key = hash_key(&self.right_keys, &row)?;
+            if key.is_none() && !keep_nulls {
+                continue;
+            }
+            match &mut parts {
+                Some(parts) => parts[partition(&key)].0.write(&row)?,
+                None => {
+                    size += spill::row_size(&row);
+                    rows.push(row);
+                    if size <= memory.budget(self.work_mem) {
+                        memory.resize(size)?;
+                        continue;
+                    }
+                    let mut p = (0..HASH_PARTITIONS)
+                        .map(|_| Ok((SpillFile::create(&self.temp_dir)?, SpillFile::create(&self.temp_dir)?)))
+                        .collect::<Result<Vec<_>>>()?;
+                    for row in rows.drain(..) {
+                        p[partition(&hash_key(&self.right_keys, &row)?)].0.write(&row)?;
+                    }
+                    memory.clear();
+                    parts = Some(p);
+                }
+            }
+        }
+        let Some(mut parts) = parts else {
+            self.batch = Some(HashBatch::new(rows, &self.right_keys, None)?);
+            self.memory = Some(memory);
+            return Ok(());
+        };
+        tracing::debug!(partitions = HASH_PARTITIONS, "hash join spilled to disk");
+        while let Some(row) = self.left.next(ctx)? {
+            let key = hash_key(&self.left_keys, &row)?;
+            parts[partition(&key)].1.write(&row)?;
+        }
+        self.partitions = parts.into_iter().collect();
+        Ok(())
+    }
+
+    /// Load the next spilled partition, if any.
+    fn next_batch(&mut self, ctx: &mut ExecContext<'_>) -> Result<bool> {
+        let Some((mut build, mut probe)) = self.partitions.pop_front() else {
+            return Ok(false);
+        };
+        // Release the last batch's rows before loading the next.
+        self.memory = None;
+        let mut memory = ctx.limits.reservation();
+        let mut rows = Vec::new();
+        let mut reader = build.reader()?;
+        while let Some(row) = reader.next_row()? {
+            memory.grow(spill::row_size(&row))?;
+            rows.push(row);
+        }
+        self.batch = Some(HashBatch::new(rows, &self.right_keys, Some(probe.reader()?))?);
+        self.memory = Some(memory);
+        Ok(true)
+    }
+}
+
+impl Operator for HashJoin {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        if !self.started {
+            self.started = true;
+            self.build(ctx)?;
+        }
+        loop {
+            if let Some(row) = self.pending.pop_front() {
+                return Ok(Some(row));
+            }
+            let Some(batch) = self.batch.as_mut() else {
+                if self.next_batch(ctx)? {
+                    continue;
+                }
+                return Ok(None);
+            };
+            let left = match &mut batch.probe {
+                Some(reader) => reader.next_row()?,
+                None => self.left.next(ctx)?,
+            };
+            let Some(left) = left else {
+                for (right, matched) in &batch.rows {
+                    if !matched {
+                        self.spec.unmatched_right(right, &mut self.pending);
+                    }
+                }
+                self.batch = None;
+                continue;
+            };
+            let mut any = false;
+            if let Some(ids) = hash_key(&self.left_keys, &left)?.and_then(|k| batch.table.get(&k)) {
+                for &i in ids {
+                    let (right, matched) = &mut batch.rows[i];
+                    if let Some(row) = self.spec.matches(&left, right)? {
+                        any = true;
+                        *matched = true;
+                        if !self.spec.join_type.emits_right() {
+                            break;
+                        }
+                        self.pending.push_back(row);
+                    }
+                }
+            }
+            self.spec.finish_left(left, any, &mut self.pending);
+        }
+    }
+}
+
+struct IndexNestedLoopJoin {
+    outer: Box<dyn Operator>,
+    spec: JoinSpec,
+    table: Arc<Table>,
+    key: Expr,
+    pending: VecDeque<Row>,
+}
+
+impl Operator for IndexNestedLoopJoin {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        loop {
+            if let Some(row) = self.pending.pop_front() {
+                return Ok(Some(row));
+            }
+            let Some(outer) = self.outer.next(ctx)? else {
+                return Ok(None);
+            };
+            let inner = match as_key(&self.key.eval(&outer)?)? {
+                Some(key) => ctx.txn.get(&self.table, key)?,
+                None => None,
+            };
+            let joined = match &inner {
+                Some(inner) => self.spec.matches(&outer, inner)?,
+                None => None,
+            };
+            let matched = joined.is_some();
+            if let Some(row) = joined.filter(|_| self.spec.join_type.emits_right()) {
+                self.pending.push_back(row);
+            }
+            self.spec.finish_left(outer, matched, &mut self.pending);
+        }
+    }
+}
+
+/// Lexicographic order of two NULL-free join keys.
+fn compare_join_keys(a: &[Value], b: &[Value]) -> Ordering {
+    a.iter()
+        .zip(b)
+        .map(|(x, y)| x.total_cmp(y))
+        .find(|o| o.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+/// Both inputs arrive sorted on their keys, so matching right rows form one group that
+/// advances with the left input.
+struct MergeJoin {
+    left: Box<dyn Operator>,
+    right: Box<dyn Operator>,
+    spec: JoinSpec,
+    left_keys: Vec<Expr>,
+    right_keys: Vec<Expr>,
+    /// Right rows sharing `group_key`, with whether they matched.
+    group: Vec<(Row, bool)>,
+    group_key: Option<Vec<Value>>,
+    /// The first right row after the group; None once the right input is exhausted.
+    lookahead: Option<(Option<Vec<Value>>, Row)>,
+    started: bool,
+    done: bool,
+    pending: VecDeque<Row>,
+}
+
+impl MergeJoin {
+    fn read_right(&mut self, ctx: &mut ExecContext<'_>) -> Result<()> {
+        self.lookahead = match self.right.next(ctx)? {
+            Some(row) => Some((hash_key(&self.right_keys, &row)?, row)),
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// Emit what is left of the current group and load the next one. Right rows with NULL
+    /// keys sort last and never form a group.
+    fn next_group(&mut self, ctx: &mut ExecContext<'_>) -> Result<()> {
+        for (right, matched) in std::mem::take(&mut self.group) {
+            if !matched {
+                self.spec.unmatched_right(&right, &mut self.pending);
+            }
+        }
+        self.group_key = None;
+        let Some((Some(key), _)) = &self.lookahead else {
+            return Ok(());
+        };
+        let key = key.clone();
+        let (_, row) = self.lookahead.take().unwrap();
+        self.group.push((row, false));
+        self.read_right(ctx)?;
+        while matches!(&self.lookahead, Some((Some(k), _)) if compare_join_keys(k, &key).is_eq()) {
+            let (_, row) = self.lookahead.take().unwrap();
+            self.group.push((row, false));
+            self.read_right(ctx)?;
+        }
+        self.group_key = Some(key);
+        Ok(())
+    }
+}
+
+impl Operator for MergeJoin {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        if !self.started {
+            self.started = true;
+            self.read_right(ctx)?;
+            self.next_group(ctx)?;
+        }
+        loop {
+            if let Some(row) = self.pending.pop_front() {
+                return Ok(Some(row));
+            }
+            if self.done {
+                return Ok(None);
+            }
+            let Some(left) = self.left.next(ctx)? else {
+                // Flush the remaining groups, then the right rows with NULL keys.
+                self.next_group(ctx)?;
+                while self.group_key.is_some() {
+                    self.next_group(ctx)?;
+                }
+                while let Some((_, row)) = self.lookahead.take() {
+                    self.spec.unmatched_right(&row, &mut self.pending);
+                    self.read_right(ctx)?;
+                }
+                self.done = true;
+                continue;
+            };
+            let Some(key) = hash_key(&self.left_keys, &left)? else {
+                self.spec.finish_left(left, false, &mut self.pending);
+                continue;
+            };
+            while self.group_key.as_ref().is_some_and(|g| compare_join_keys(g, &key).is_lt()) {
+                self.next_group(ctx)?;
+            }
+            let mut any = false;
+            if self.group_key.as_ref().is_some_and(|g| compare_join_keys(g, &key).is_eq()) {
+                for (right, matched) in self.group.iter_mut() {
+                    if let Some(row) = self.spec.matches(&left, right)? {
+                        any = true;
+                        *matched = true;
+                        if !self.spec.join_type.emits_right() {
+                            break;
+                        }
+                        self.pending.push_back(row);
+                    }
+                }
+            }
+            self.spec.finish_left(left, any, &mut self.pending);
+        }
+    }
+}
+
+/// Order two sort key values: NULLs by `nulls_first`, the rest ascending or descending.
+pub fn compare_keys(a: &[Value], b: &[Value], keys: &[SortKey]) -> Ordering {
+    for ((x, y), k) in a.iter().zip(b).zip(keys) {
+        let ord = match (x.is_null(), y.is_null()) {
+            (true, true) => Ordering::Equal,
+            (true, false) if k.nulls_first => Ordering::Less,
+            (true, false) => Ordering::Greater,
+            (false, true) if k.nulls_first => Ordering::Greater,
+            (false, true) => Ordering::Less,
+            (false, false) if k.desc => y.total_cmp(x),
+            (false, false) => x.total_cmp(y),
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+    Ordering::Equal
+}
+
+/// Runs merged at once. With more runs than this, groups of them are first merged into
+/// longer runs.
+const MERGE_FAN_IN: usize = 64;
+
+/// A row with its sort key. `seq` breaks ties so that rows with equal keys keep their
+/// input order: it is the row's input position while runs are built, and its run's
+/// position while they are merged.
+struct Keyed {
+    key: Vec<Value>,
+    row: Row,
+    seq: u64,
+    order: Rc<[SortKey]>,
+}
+
+impl Keyed {
+    fn size(&self) -> usize {
+        spill::row_size(&self.key) + spill::row_size(&self.row)
+    }
+}
+
+impl Ord for Keyed {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_keys(&self.key, &other.key, &self.order).then(self.seq.cmp(&other.seq))
+    }
+}
+
+impl PartialOrd for Keyed {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Keyed {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Keyed {}
+
+/// K-way merge of sorted runs, each row stored as its key followed by the row.
+struct Merger {
+    /// Kept so the files outlive their readers.
+    _runs: Vec<SpillFile>,
+    readers: Vec<SpillReader>,
+    heap: BinaryHeap<Reverse<Keyed>>,
+    order: Rc<[SortKey]>,
+}
+
+impl Merger {
+    fn new(mut runs: Vec<SpillFile>, order: Rc<[SortKey]>) -> Result<Self> {
+        let readers = runs.iter_mut().map(SpillFile::reader).collect::<Result<_>>()?;
+        let mut merger = Self {
+            _runs: runs,
+            readers,
+            heap: BinaryHeap::new(),
+            order,
+        };
+        for i in 0..merger.readers.len() {
+            merger.read(i)?;
+        }
+        Ok(merger)
+    }
+
+    fn read(&mut self, run: usize) -> Result<()> {
+        if let Some(mut key) = self.readers[run].next_row()? {
+            let row = key.split_off(self.order.len());
+            self.heap.push(Reverse(Keyed {
+                key,
+                row,
+                seq: run as u64,
+                order: Rc::clone(&self.order),
+            }));
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Option<Keyed>> {
+        let Some(Reverse(next)) = self.heap.pop() else {
+            return Ok(None);
+        };
+        self.read(next.seq as usize)?;
+        Ok(Some(next))
+    }
+}
+
+enum SortOutput {
+    Memory(std::vec::IntoIter<Keyed>),
+    /// Rows still to merge, up to the limit if there is one.
+    Merge(Merger, Option<usize>),
+}
+
+/// Sorts in memory up to `work_mem` (less if the statement's memory budget has less left);
+/// past it, writes sorted runs to temporary files and merges them. With a limit, only the
+/// first rows are kept, in a bounded heap.
+struct Sort {
+    input: Box<dyn Operator>,
+    keys: Rc<[SortKey]>,
+    limit: Option<usize>,
+    work_mem: usize,
+    temp_dir: PathBuf,
+    output: Option<SortOutput>,
+    /// Holds the rows of a sort done in memory.
+    memory: Option<Reservation>,
+}
+
+impl Sort {
+    /// Write `rows` out as one sorted run.
+    fn write_run(&self, mut rows: Vec<Keyed>) -> Result<SpillFile> {
+        rows.sort_unstable();
+        let mut run = SpillFile::create(&self.temp_dir)?;
+        for mut r in rows {
+            r.key.append(&mut r.row);
+            run.write(&r.key)?;
+        }
+        Ok(run)
+    }
+
+    /// Merge `runs` until at most `MERGE_FAN_IN` are left. Adjacent runs are merged so
+    /// that ties keep their input order; rows past the limit are dropped.
+    fn merge_runs(&self, mut runs: Vec<SpillFile>) -> Result<Vec<SpillFile>> {
+        while runs.len() > MERGE_FAN_IN {
+            tracing::debug!(runs = runs.len(), "sort merge pass");
+            let mut merged = Vec::new();
+            let mut rest = runs.into_iter();
+            loop {
+                let group: Vec<SpillFile> = rest.by_ref().take(MERGE_FAN_IN).collect();
+                if group.is_empty() {
+                    break;
+                }
+                let mut merger = Merger::new(group, Rc::clone(&self.keys))?;
+                let mut out = SpillFile::create(&self.temp_dir)?;
+                while self.limit.is_none_or(|l| (out.rows() as usize) < l) {
+                    let Some(mut r) = merger.next()? else {
+                        break;
+                    };
+                    r.key.append(&mut r.row);
+                    out.write(&r.key)?;
+                }
+                merged.push(out);
+            }
+            runs = merged;
+        }
+        Ok(runs)
+    }
+
+    fn fill(&mut self, ctx: &mut ExecContext<'_>) -> Result<SortOutput> {
+        let mut runs = Vec::new();
+        // Rows not written out yet; with a limit, a max-heap of the smallest so far.
+        let mut rows = Vec::new();
+        let mut top = BinaryHeap::new();
+        let mut memory = ctx.limits.reservation();
+        let mut size = 0;
+        let mut seq = 0;
+        while let Some(row) = self.input.next(ctx)? {
+            let key = self.keys.iter().map(|k| k.expr.eval(&row)).collect::<Result<Vec<_>>>()?;
+            let entry = Keyed {
+                key,
+                row,
+                seq,
+                order: Rc::clone(&self.keys),
+            };
+            seq += 1;
+            match self.limit {
+                None => {
+                    size += entry.size();
+                    rows.push(entry);
+                }
+                Some(n) if top.len() < n => {
+                    size += entry.size();
+                    top.push(entry);
+                }
+                Some(_) => {
+                    if top.peek().is_some_and(|largest| entry < *largest) {
+                        size -= top.pop().map_or(0, |e| e.size());
+                        size += entry.size();
+                        top.push(entry);
+                    }
+                }
+            }
+            if size <= memory.budget(self.work_mem) {
+                memory.resize(size)?;
+            } else {
+                rows.extend(top.drain());
+                runs.push(self.write_run(std::mem::take(&mut rows))?);
+                memory.clear();
+                size = 0;
+            }
+        }
+        rows.extend(top);
+        if runs.is_empty() {
+            rows.sort_unstable();
+            rows.truncate(self.limit.unwrap_or(usize::MAX));
+            self.memory = Some(memory);
+            return Ok(SortOutput::Memory(rows.into_iter()));
+        }
+        if !rows.is_empty() {
+            runs.push(self.write_run(rows)?);
+        }
+        tracing::debug!(runs = runs.len(), rows = seq, "sort spilled to disk");
+        let runs = self.merge_runs(runs)?;
+        Ok(SortOutput::Merge(Merger::new(runs, Rc::clone(&self.keys))?, self.limit))
+    }
+}
+
+impl Operator for Sort {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        if self.output.is_none() {
+            self.output = Some(self.fill(ctx)?);
+        }
+        let next = match self.output.as_mut().unwrap() {
+            SortOutput::Memory(rows) => rows.next(),
+            SortOutput::Merge(_, Some(0)) => None,
+            SortOutput::Merge(merger, remaining) => {
+                if let Some(n) = remaining {
+                    *n -= 1;
+                }
+                merger.next()?
+            }
+        };
+        Ok(next.map(|k| k.row))
+    }
+}
+
+/// What an aggregate node computes; see `Node::Aggregate`.
+struct AggSpec {
+    group_by: Vec<Expr>,
+    sets: Vec<Vec<usize>>,
+    aggregates: Vec<AggCall>,
+}
+
+impl AggSpec {
+    fn accumulators(&self) -> Vec<Accumulator> {
+        self.aggregates.iter().map(Accumulator::new).collect()
+    }
+
+    /// Argument values of every aggregate for `row` (None where it skips the row).
+    fn inputs(&self, row: &[Value]) -> Result<Vec<Option<Vec<Value>>>> {
+        self.aggregates.iter().map(|a| a.inputs(row)).collect()
+    }
+
+    fn update(accs: &mut [Accumulator], inputs: &[Option<Vec<Value>>]) -> Result<()> {
+        for (acc, args) in accs.iter_mut().zip(inputs) {
+            if let Some(args) = args {
+                acc.update(args.clone())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Output row of a group of `set` with key `key` (the values of the set's keys).
+    fn output(&self, set: usize, key: &[Value], accs: &[Accumulator]) -> Result<Row> {
+        let mut row = vec![Value::Null; self.group_by.len()];
+        for (&k, v) in self.sets[set].iter().zip(key) {
+            row[k] = v.clone();
+        }
+        for acc in accs {
+            row.push(acc.finish()?);
+        }
+        Ok(row)
+    }
+}
+
+/// Spilled partitions are split again at most this many times; past that a partition is
+/// aggregated in memory whatever its size.
+const MAX_AGG_SPILL_DEPTH: u32 = 4;
+
+/// Hash aggregation. When the groups would outgrow `work_mem` (or the statement's memory
+/// budget), rows of groups not yet in memory are written, tagged with their grouping set, to
+/// one of `HASH_PARTITIONS` temporary files by key hash; each file is aggregated once the
+/// groups in memory have been emitted.
+struct HashAggregate {
+    input: Box<dyn Operator>,
+    spec: AggSpec,
+    work_mem: usize,
+    temp_dir: PathBuf,
+    /// Rows of the pass being emitted; None before the first pass.
+    output: Option<std::vec::IntoIter<Row>>,
+    /// Spilled partitions not aggregated yet, with the pass depth that wrote them.
+    partitions: VecDeque<(SpillFile, u32)>,
+}
+
+impl HashAggregate {
+    /// Aggregate rows from the input (depth 0, every set) or a spilled partition (rows
+    /// carry their set as a leading column).
+    fn pass(&mut self, ctx: &mut ExecContext<'_>, mut source: Option<SpillReader>, depth: u32) -> Result<Vec<Row>> {
+        use std::hash::{Hash, Hasher};
+        let mut groups: HashMap<(usize, Vec<Value>), Vec<Accumulator>> = HashMap::new();
+        let mut memory = ctx.limits.reservation();
+        let mut size = 0;
+        let mut parts: Option<Vec<SpillFile>> = None;
+        let all_sets: Vec<usize> = (0..self.spec.sets.len()).collect();
+        loop {
+            let (sets, row) = match &mut source {
+                None => match self.input.next(ctx)? {
+                    Some(row) => (all_sets.clone(), row),
+                    None => break,
+                },
+                Some(reader) => match reader.next_row()? {
+                    Some(mut row) => {
+                        let Value::Int(set) = row.remove(0) else {
+                            bail!("corrupt aggregate spill file");
+                        };
+                        (vec![set as usize], row)
+                    }
+                    None => break,
+                },
+            };
+            let keys = self.spec.group_by.iter().map(|e| e.eval(&row)).collect::<Result<Vec<_>>>()?;
+            let inputs = self.spec.inputs(&row)?;
+            for set in sets {
+                let key: Vec<Value> = self.spec.sets[set].iter().map(|&k| keys[k].clone()).collect();
+                if let Some(accs) = groups.get_mut(&(set, key.clone())) {
+                    AggSpec::update(accs, &inputs)?;
+                    continue;
+                }
+                let group_size = spill::row_size(&key) + ACCUMULATOR_SIZE * self.spec.aggregates.len();
+                let full = size + group_size > memory.budget(self.work_mem);
+                if parts.is_none() && full && depth < MAX_AGG_SPILL_DEPTH {
+                    tracing::debug!(depth, groups = groups.len(), "hash aggregate spilled to disk");
+                    parts = Some(
+                        (0..HASH_PARTITIONS)
+                            .map(|_| SpillFile::create(&self.temp_dir))
+                            .collect::<Result<Vec<_>>>()?,
+                    );
+                }
+                if let Some(parts) = &mut parts {
+                    let mut h = std::collections::hash_map::DefaultHasher::new();
+                    (depth, set, &key).hash(&mut h);
+                    let mut tagged = vec![Value::Int(set as i64)];
+                    tagged.extend(row.iter().cloned());
+                    parts[(h.finish() % HASH_PARTITIONS) as usize].write(&tagged)?;
+                    continue;
+                }
+                memory.grow(group_size)?;
+                size += group_size;
+                let mut accs = self.spec.accumulators();
+                AggSpec::update(&mut accs, &inputs)?;
+                groups.insert((set, key), accs);
+            }
+        }
+        let mut rows: Vec<Row> = groups
+            .iter()
+            .map(|((set, key), accs)| self.spec.output(*set, key, accs))
+            .collect::<Result<_>>()?;
+        if depth == 0 {
+            // An empty grouping set has a row even without input.
+            for (set, keys) in self.spec.sets.iter().enumerate() {
+                if keys.is_empty() && !groups.contains_key(&(set, vec![])) {
+                    rows.push(self.spec.output(set, &[], &self.spec.accumulators())?);
+                }
+            }
+        }
+        for part in parts.into_iter().flatten() {
+            if part.rows() > 0 {
+                self.partitions.push_back((part, depth + 1));
+            }
+        }
+        Ok(rows)
+    }
+}
+
+impl Operator for HashAggregate {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        loop {
+            if let Some(row) = self.output.as_mut().and_then(Iterator::next) {
+                return Ok(Some(row));
+            }
+            let rows = match (&self.output, self.partitions.pop_front()) {
+                (None, _) => self.pass(ctx, None, 0)?,
+                (Some(_), Some((mut part, depth))) => {
+                    let reader = part.reader()?;
+                    self.pass(ctx, Some(reader), depth)?
+                }
+                (Some(_), None) => return Ok(None),
+            };
+            self.output = Some(rows.into_iter());
+        }
+    }
+}
+
+/// Aggregation of input sorted on the group keys (a single grouping set of all of them).
+struct SortedAggregate {
+    input: Box<dyn Operator>,
+    spec: AggSpec,
+    /// Key and accumulators of the group being read.
+    group: Option<(Vec<Value>, Vec<Accumulator>)>,
+    done: bool,
+}
+
+impl Operator for SortedAggregate {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        while !self.done {
+            let Some(row) = self.input.next(ctx)? else {
+                self.done = true;
+                return match self.group.take() {
+                    Some((key, accs)) => self.spec.output(0, &key, &accs).map(Some),
+                    // Without GROUP BY there is one group, even if empty.
+                    None if self.spec.group_by.is_empty() => {
+                        self.spec.output(0, &[], &self.spec.accumulators()).map(Some)
+                    }
+                    None => Ok(None),
+                };
+            };
+            let key = self.spec.group_by.iter().map(|e| e.eval(&row)).collect::<Result<Vec<_>>>()?;
+            let inputs = self.spec.inputs(&row)?;
+            let finished = match &mut self.group {
+                Some((k, accs)) if *k == key => {
+                    AggSpec::update(accs, &inputs)?;
+                    continue;
+                }
+                group => group.take(),
+            };
+            let mut accs = self.spec.accumulators();
+            AggSpec::update(&mut accs, &inputs)?;
+            self.group = Some((key, accs));
+            if let Some((key, accs)) = finished {
+                return self.spec.output(0, &key, &accs).map(Some);
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Window functions over input sorted on the partition keys, one partition in memory at a
+/// time.
+struct Window {
+    input: Box<dyn Operator>,
+    partition_by: Vec<Expr>,
+    order_by: Vec<SortKey>,
+    functions: Vec<WindowCall>,
+    /// First row of the next partition, with its partition key.
+    next_partition: Option<(Vec<Value>, Row)>,
+    output: VecDeque<Row>,
+    /// Holds the rows of the partition being output.
+    memory: Option<Reservation>,
+    done: bool,
+}
+
+impl Window {
+    /// Read the next partition and compute its rows, or return false at the end of input.
+    fn partition(&mut self, ctx: &mut ExecContext<'_>) -> Result<bool> {
+        let (key, first) = match self.next_partition.take() {
+            Some(next) => next,
+            None => match self.input.next(ctx)? {
+                Some(row) => (self.key(&row)?, row),
+                None => return Ok(false),
+            },
+        };
+        self.memory = None;
+        let mut memory = ctx.limits.reservation();
+        memory.grow(spill::row_size(&first))?;
+        let mut rows = vec![first];
+        while let Some(row) = self.input.next(ctx)? {
+            let k = self.key(&row)?;
+            if k != key {
+                self.next_partition = Some((k, row));
+                break;
+            }
+            memory.grow(spill::row_size(&row))?;
+            rows.push(row);
+        }
+        self.memory = Some(memory);
+        let partition = Partition::new(&rows, &self.order_by)?;
+        let values = self
+            .functions
+            .iter()
+            .map(|f| partition.evaluate(f))
+            .collect::<Result<Vec<_>>>()?;
+        for (i, mut row) in rows.into_iter().enumerate() {
+            row.extend(values.iter().map(|v| v[i].clone()));
+            self.output.push_back(row);
+        }
+        Ok(true)
+    }
+
+    fn key(&self, row: &[Value]) -> Result<Vec<Value>> {
+        self.partition_by.iter().map(|e| e.eval(row)).collect()
+    }
+}
+
+impl Operator for Window {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        while self.output.is_empty() && !self.done {
+            self.done = !self.partition(ctx)?;
+        }
+        Ok(self.output.pop_front())
+    }
+}
+
+struct Limit {
+    input: Box<dyn Operator>,
+    limit: Option<u64>,
+    offset: u64,
+    seen: u64,
+}
+
+impl Operator for Limit {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        loop {
+            if self.limit.is_some_and(|l| self.seen >= self.offset + l) {
+                return Ok(None);
+            }
+            let Some(row) = self.input.next(ctx)? else {
+                return Ok(None);
+            };
+            self.seen += 1;
+            if self.seen > self.offset {
+                return Ok(Some(row));
+            }
+        }
+    }
+}
+
+struct Append {
+    inputs: Vec<Box<dyn Operator>>,
+    types: Vec<ColumnType>,
+    current: usize,
+}
+
+impl Operator for Append {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        while let Some(input) = self.inputs.get_mut(self.current) {
+            match input.next(ctx)? {
+                Some(row) => {
+                    return row
+                        .into_iter()
+                        .zip(&self.types)
+                        .map(|(v, ty)| coerce(v, *ty))
+                        .collect::<Result<Row>>()
+                        .map(Some)
+                }
+                None => self.current += 1,
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// INTERSECT or EXCEPT over groups counted by the aggregate below.
+struct SetOp {
+    input: Box<dyn Operator>,
+    op: SetOpKind,
+    all: bool,
+    /// The row being output, and how many more times.
+    pending: Option<(Row, i64)>,
+}
+
+impl Operator for SetOp {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        loop {
+            if let Some((row, n)) = &mut self.pending {
+                if *n > 0 {
+                    *n -= 1;
+                    return Ok(Some(row.clone()));
+                }
+            }
+            let Some(mut row) = self.input.next(ctx)? else {
+                return Ok(None);
+            };
+            let (Some(Value::Int(right)), Some(Value::Int(left))) = (row.pop(), row.pop()) else {
+                bail!("set operation input is missing its counts");
+            };
+            self.pending = Some((row, self.op.count(self.all, left, right)));
+        }
+    }
+}
+
+/// Reject rows whose key column is NULL.
+fn check_key(def: &TableDef, row: &[Value]) -> Result<()> {
+    if row[def.key_col].is_null() {
+        bail!(
+            "null value in column {} of {} violates not-null constraint",
+            def.columns[def.key_col].name,
+            def.name
+        );
+    }
+    Ok(())
+}
+
+/// DML operators read their whole input before writing, so a scan of the target table
+/// never sees the statement's own changes.
+struct RecursiveUnion {
+    /// The non-recursive term.
+    input: Box<dyn Operator>,
+    name: String,
+    distinct: bool,
+    recursive: Plan,
+    max_depth: u64,
+    /// Every row produced so far, with `distinct`.
+    seen: HashSet<Row>,
+    /// Rows the running step has produced: the work table of the next one.
+    step: Vec<Row>,
+    /// Hold `seen` and `step`.
+    seen_memory: Option<Reservation>,
+    step_memory: Option<Reservation>,
+    /// The recursive term of the running step; None while the input runs.
+    current: Option<Box<dyn Operator>>,
+    depth: u64,
+}
+
+impl Operator for RecursiveUnion {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        loop {
+            let row = match &mut self.current {
+                None => self.input.next(ctx)?,
+                Some(op) => op.next(ctx)?,
+            };
+            if let Some(row) = row {
+                if self.distinct && !self.seen.insert(row.clone()) {
+                    continue;
+                }
+                let size = spill::row_size(&row);
+                if self.distinct {
+                    self.seen_memory.get_or_insert_with(|| ctx.limits.reservation()).grow(size)?;
+                }
+                self.step_memory.get_or_insert_with(|| ctx.limits.reservation()).grow(size)?;
+                self.step.push(row.clone());
+                return Ok(Some(row));
+            }
+            if self.step.is_empty() {
+                return Ok(None);
+            }
+            ctx.limits.check()?;
+            self.depth += 1;
+            if self.max_depth > 0 && self.depth > self.max_depth {
+                bail!(
+                    "recursive query {} exceeded max_recursion_depth ({} steps)",
+                    self.name,
+                    self.max_depth
+                );
+            }
+            let work_table = std::mem::take(&mut self.step);
+            self.step_memory = None;
+            self.current = Some(build(&self.recursive.bind_work_table(&self.name, &work_table), ctx.db)?);
+        }
+    }
+}
+
+struct Insert {
+    input: Box<dyn Operator>,
+    table: Arc<Table>,
+    def: TableDef,
+    columns: Vec<usize>,
+    on_conflict: Option<OnConflict>,
+    /// Keys inserted or updated so far, which a later DO UPDATE must not touch again.
+    affected: HashSet<i64>,
+    /// Constraints of the table, and of the tables referencing it.
+    constraints: Enforcer,
+    /// Incremental views over the table, brought up to date once all rows are written.
+    views: Maintenance,
+    triggers: Triggers,
+    rows: Option<std::vec::IntoIter<Row>>,
+}
+
+impl Operator for Insert {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        if self.rows.is_none() {
+            self.triggers.statement(ctx, TriggerTiming::Before)?;
+            self.rows = Some(drain(&mut *self.input, ctx)?.into_iter());
+        }
+        while let Some(values) = self.rows.as_mut().unwrap().next() {
+            let mut row = vec![Value::Null; self.def.columns.len()];
+            for (v, col) in values.into_iter().zip(&self.columns) {
+                row[*col] = coerce(v, self.def.columns[*col].ty)
+                    .map_err(|e| anyhow::anyhow!("column {}: {}", self.def.columns[*col].name, e))?;
+            }
+            let key = self.def.key_col;
+            if self.def.columns[key].hidden && !self.columns.contains(&key) {
+                row[key] = Value::Int(self.table.allocate_key());
+            }
+            let Some(row) = self.triggers.before_row(ctx, TriggerEvent::Insert, None, row)? else {
+                continue;
+            };
+            check_key(&self.def, &row)?;
+            self.constraints.check(&row)?;
+            let Some(on_conflict) = &self.on_conflict else {
+                ctx.txn.insert(&self.table, row.clone())?;
+                self.constraints.inserted(ctx, &row)?;
+                self.views.record(None, Some(&row))?;
+                self.triggers.after_row(ctx, TriggerEvent::Insert, None, Some(&row))?;
+                return Ok(Some(row));
+            };
+            let Some(existing) = ctx.txn.insert_or_existing(&self.table, row.clone())? else {
+                self.affected.insert(self.table.key_of(&row)?);
+                self.constraints.inserted(ctx, &row)?;
+                self.views.record(None, Some(&row))?;
+                self.triggers.after_row(ctx, TriggerEvent::Insert, None, Some(&row))?;
+                return Ok(Some(row));
+            };
+            let OnConflict::DoUpdate { assignments, filter } = on_conflict else {
+                continue;
+            };
+            let key = self.table.key_of(&existing)?;
+            if !self.affected.insert(key) {
+                bail!("ON CONFLICT DO UPDATE command cannot affect row a second time");
+            }
+            let both: Row = existing.iter().cloned().chain(row).collect();
+            if let Some(filter) = filter {
+                if !filter.eval_predicate(&both)? {
+                    continue;
+                }
+            }
+            let mut new = existing.clone();
+            for (col, e) in assignments {
+                new[*col] = coerce(e.eval(&both)?, self.def.columns[*col].ty)
+                    .map_err(|e| anyhow::anyhow!("column {}: {}", self.def.columns[*col].name, e))?;
+            }
+            let Some(new) = self.triggers.before_row(ctx, TriggerEvent::Update, Some(&existing), new)? else {
+                continue;
+            };
+            check_key(&self.def, &new)?;
+            self.constraints.check(&new)?;
+            ctx.txn.update(&self.table, key, new.clone())?;
+            self.constraints.updated(ctx, &existing, &new)?;
+            self.views.record(Some(&existing), Some(&new))?;
+            self.triggers.after_row(ctx, TriggerEvent::Update, Some(&existing), Some(&new))?;
+            return Ok(Some(new));
+        }
+        self.constraints.finish(ctx)?;
+        self.views.flush(ctx.txn)?;
+        self.triggers.statement(ctx, TriggerTiming::After)?;
+        Ok(None)
+    }
+}
+
+struct Update {
+    input: Box<dyn Operator>,
+    table: Arc<Table>,
+    def: TableDef,
+    assignments: Vec<(usize, Expr)>,
+    constraints: Enforcer,
+    views: Maintenance,
+    triggers: Triggers,
+    rows: Option<std::vec::IntoIter<Row>>,
+}
+
+impl Operator for Update {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        if self.rows.is_none() {
+            self.triggers.statement(ctx, TriggerTiming::Before)?;
+            self.rows = Some(drain(&mut *self.input, ctx)?.into_iter());
+        }
+        while let Some(old) = self.rows.as_mut().unwrap().next() {
+            let mut new = old.clone();
+            for (col, e) in &self.assignments {
+                new[*col] = coerce(e.eval(&old)?, self.def.columns[*col].ty)
+                    .map_err(|e| anyhow::anyhow!("column {}: {}", self.def.columns[*col].name, e))?;
+            }
+            new.truncate(self.def.columns.len());
+            let old = &old[..new.len()];
+            let Some(new) = self.triggers.before_row(ctx, TriggerEvent::Update, Some(old), new)? else {
+                continue;
+            };
+            check_key(&self.def, &new)?;
+            self.constraints.check(&new)?;
+            if ctx.txn.update(&self.table, self.table.key_of(old)?, new.clone())? {
+                self.constraints.updated(ctx, old, &new)?;
+                self.views.record(Some(old), Some(&new))?;
+                self.triggers.after_row(ctx, TriggerEvent::Update, Some(old), Some(&new))?;
+                return Ok(Some(new));
+            }
+        }
+        self.constraints.finish(ctx)?;
+        self.views.flush(ctx.txn)?;
+        self.triggers.statement(ctx, TriggerTiming::After)?;
+        Ok(None)
+    }
+}
+
+struct Delete {
+    input: Box<dyn Operator>,
+    table: Arc<Table>,
+    width: usize,
+    constraints: Enforcer,
+    views: Maintenance,
+    triggers: Triggers,
+    rows: Option<std::vec::IntoIter<Row>>,
+}
+
+impl Operator for Delete {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        if self.rows.is_none() {
+            self.triggers.statement(ctx, TriggerTiming::Before)?;
+            self.rows = Some(drain(&mut *self.input, ctx)?.into_iter());
+        }
+        while let Some(mut old) = self.rows.as_mut().unwrap().next() {
+            old.truncate(self.width);
+            if !self.triggers.before_delete(ctx, &old)? {
+                continue;
+            }
+            if ctx.txn.delete(&self.table, self.table.key_of(&old)?)? {
+                self.constraints.deleted(ctx, &old)?;
+                self.views.record(Some(&old), None)?;
+                self.triggers.after_row(ctx, TriggerEvent::Delete, Some(&old), None)?;
+                return Ok(Some(old));
+            }
+        }
+        self.constraints.finish(ctx)?;
+        self.views.flush(ctx.txn)?;
+        self.triggers.statement(ctx, TriggerTiming::After)?;
+        Ok(None)
+    }
+}
+
+struct LockRows {
+    input: Box<dyn Operator>,
+    table: Arc<Table>,
+    width: usize,
+    mode: LockMode,
+}
+
+impl Operator for LockRows {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        while let Some(mut row) = self.input.next(ctx)? {
+            let key = self.table.key_of(&row[..self.width])?;
+            if let Some(newest) = ctx.txn.get_locked(&self.table, key, self.mode)? {
+                row.splice(..self.width, newest);
+                return Ok(Some(row));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::query::expr::BinaryOp;
+    use crate::storage::ColumnType;
+    use crate::txn::IsolationLevel;
+
+    fn values(rows: &[Row]) -> Plan {
+        Plan {
+            node: Node::Values {
+                rows: rows.iter().map(|r| r.iter().cloned().map(Expr::Literal).collect()).collect(),
+            },
+            children: vec![],
+            columns: vec![("k".into(), ColumnType::Int), ("v".into(), ColumnType::Int)],
+            est_rows: rows.len() as f64,
+            est_cost: 0.0,
+        }
+    }
+
+    fn sorted_on_key(plan: Plan) -> Plan {
+        Plan {
+            node: Node::Sort {
+                keys: vec![SortKey {
+                    expr: Expr::Column(0),
+                    desc: false,
+                    nulls_first: false,
+                }],
+                limit: None,
+            },
+            columns: plan.columns.clone(),
+            est_rows: plan.est_rows,
+            est_cost: plan.est_cost,
+            children: vec![plan],
+        }
+    }
+
+    fn int(n: Option<i64>) -> Value {
+        n.map_or(Value::Null, Value::Int)
+    }
+
+    /// What every join algorithm must produce: a plain nested loop over the whole condition.
+    fn reference(join_type: JoinType, left: &[Row], right: &[Row], cond: &Expr) -> Vec<Row> {
+        let spec = JoinSpec {
+            join_type,
+            residual: Some(cond.clone()),
+            left_width: 2,
+            right_width: 2,
+        };
+        let mut out = VecDeque::new();
+        let mut right_matched = vec![false; right.len()];
+        for l in left {
+            let mut matched = false;
+            for (r, m) in right.iter().zip(&mut right_matched) {
+                if let Some(row) = spec.matches(l, r).unwrap() {
+                    matched = true;
+                    *m = true;
+                    if join_type.emits_right() {
+                        out.push_back(row);
+                    }
+                }
+            }
+            spec.finish_left(l.clone(), matched, &mut out);
+        }
+        for (r, m) in right.iter().zip(right_matched) {
+            if !m {
+                spec.unmatched_right(r, &mut out);
+            }
+        }
+        out.into()
+    }
+
+    fn run(db: &Database, plan: &Plan) -> Vec<Row> {
+        let mut txn = db.begin(IsolationLevel::ReadCommitted);
+        let mut ctx = ExecContext { db, txn: &mut txn, limits: Limits::none(), trigger_depth: 0 };
+        let mut rows = execute(plan, &mut ctx).unwrap();
+        rows.sort_by(|a, b| compare_join_keys(a, b));
+        rows
+    }
+
+    #[test]
+    fn join_algorithms_agree_for_every_join_type() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = |work_mem_kb| {
+            Database::open(Config {
+                data_dir: dir.path().to_string_lossy().into_owned(),
+                wal_sync: false,
+                work_mem_kb,
+                ..Config::default()
+            })
+            .unwrap()
+        };
+        // Keys repeat on both sides and some are NULL, which never matches.
+        let left: Vec<Row> = (0..300)
+            .map(|i| vec![int((i % 11 != 0).then_some(i % 37)), Value::Int(i)])
+            .collect();
+        let right: Vec<Row> = (0..200)
+            .map(|i| vec![int((i % 13 != 0).then_some(i % 50)), Value::Int(i)])
+            .collect();
+        let key = Expr::Binary {
+            op: BinaryOp::Eq,
+            left: Box::new(Expr::Column(0)),
+            right: Box::new(Expr::Column(2)),
+        };
+        let residual = Expr::Binary {
+            op: BinaryOp::Lt,
+            left: Box::new(Expr::Column(1)),
+            right: Box::new(Expr::Column(3)),
+        };
+        let joined = |node: Node, sorted: bool| Plan {
+            node,
+            children: if sorted {
+                vec![sorted_on_key(values(&left)), sorted_on_key(values(&right))]
+            } else {
+                vec![values(&left), values(&right)]
+            },
+            columns: vec![],
+            est_rows: 0.0,
+            est_cost: 0.0,
+        };
+        for residual in [None, Some(residual)] {
+            let cond = Expr::and_all([Some(key.clone()), residual.clone()].into_iter().flatten().collect()).unwrap();
+            for join_type in [
+                JoinType::Inner,
+                JoinType::Left,
+                JoinType::Right,
+                JoinType::Full,
+                JoinType::Semi,
+                JoinType::Anti,
+            ] {
+                let mut expected = reference(join_type, &left, &right, &cond);
+                expected.sort_by(|a, b| compare_join_keys(a, b));
+                let hash = joined(
+                    Node::HashJoin {
+                        join_type,
+                        left_keys: vec![Expr::Column(0)],
+                        right_keys: vec![Expr::Column(0)],
+                        residual: residual.clone(),
+                    },
+                    false,
+                );
+                let merge = joined(
+                    Node::MergeJoin {
+                        join_type,
+                        left_keys: vec![Expr::Column(0)],
+                        right_keys: vec![Expr::Column(0)],
+                        residual: residual.clone(),
+                    },
+                    true,
+                );
+                let nested = joined(
+                    Node::NestedLoopJoin {
+                        join_type,
+                        on: Some(cond.clone()),
+                    },
+                    false,
+                );
+                let db = open(4096);
+                assert_eq!(run(&db, &nested), expected, "nested loop {:?}", join_type);
+                assert_eq!(run(&db, &hash), expected, "hash {:?}", join_type);
+                assert_eq!(run(&db, &merge), expected, "merge {:?}", join_type);
+                drop(db);
+                // 1 KB of work_mem forces the hash join to partition both inputs to disk.
+                let db = open(1);
+                assert_eq!(run(&db, &hash), expected, "spilled hash {:?}", join_type);
+                assert!(std::fs::read_dir(db.temp_dir()).map_or(true, |mut d| d.next().is_none()));
+            }
+        }
+    }
+
+    #[test]
+    fn aggregation_strategies_agree() {
+        use crate::query::aggregate::AggFunc;
+
+        let dir = tempfile::tempdir().unwrap();
+        let open = |work_mem_kb| {
+            Database::open(Config {
+                data_dir: dir.path().to_string_lossy().into_owned(),
+                wal_sync: false,
+                work_mem_kb,
+                ..Config::default()
+            })
+            .unwrap()
+        };
+        let input: Vec<Row> = (0..2000)
+            .map(|i| vec![int((i % 17 != 0).then_some(i % 400)), int((i % 5 != 0).then_some(i % 9))])
+            .collect();
+        let call = |func, distinct| AggCall {
+            args: if func == AggFunc::CountStar { vec![] } else { vec![Expr::Column(1)] },
+            func,
+            distinct,
+            filter: None,
+        };
+        let aggregated = |strategy, sets: Vec<Vec<usize>>, child| Plan {
+            node: Node::Aggregate {
+                strategy,
+                group_by: vec![Expr::Column(0)],
+                sets,
+                aggregates: vec![
+                    call(AggFunc::CountStar, false),
+                    call(AggFunc::Sum, false),
+                    call(AggFunc::Count, true),
+                    call(AggFunc::Max, false),
+                ],
+            },
+            children: vec![child],
+            columns: vec![],
+            est_rows: 0.0,
+            est_cost: 0.0,
+        };
+
+        let db = open(4096);
+        let sorted = run(&db, &aggregated(AggStrategy::Sorted, vec![vec![0]], sorted_on_key(values(&input))));
+        // 400 keys plus the NULL group.
+        assert_eq!(sorted.len(), 401);
+        let total: i64 = sorted
+            .iter()
+            .map(|r| match r[1] {
+                Value::Int(n) => n,
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(total, 2000);
+        let hash = aggregated(AggStrategy::Hash, vec![vec![0]], values(&input));
+        assert_eq!(run(&db, &hash), sorted);
+        let rollup = aggregated(AggStrategy::Hash, vec![vec![0], vec![]], values(&input));
+        let expected = run(&db, &rollup);
+        assert_eq!(expected.len(), 402);
+        drop(db);
+        // 1 KB of work_mem holds only a few groups, so the rest are partitioned to disk.
+        let db = open(1);
+        assert_eq!(run(&db, &hash), sorted);
+        assert_eq!(run(&db, &rollup), expected);
+        assert!(std::fs::read_dir(db.temp_dir()).map_or(true, |mut d| d.next().is_none()));
+    }
+
+    #[test]
+    fn external_sort_matches_in_memory_sort() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = |work_mem_kb| {
+            Database::open(Config {
+                data_dir: dir.path().to_string_lossy().into_owned(),
+                wal_sync: false,
+                work_mem_kb,
+                ..Config::default()
+            })
+            .unwrap()
+        };
+        let input: Vec<Row> = (0..3000)
+            .map(|i| vec![int((i % 13 != 0).then_some(i * 7 % 50)), int((i % 7 != 0).then_some(i))])
+            .collect();
+        let key = |c, desc, nulls_first| SortKey {
+            expr: Expr::Column(c),
+            desc,
+            nulls_first,
+        };
+        // Ties on the first key set must keep their input order.
+        let orders = [
+            vec![key(0, true, true)],
+            vec![key(0, false, false), key(1, true, true)],
+            vec![key(1, false, true)],
+        ];
+        let sorted = |keys: &[SortKey], limit| Plan {
+            node: Node::Sort {
+                keys: keys.to_vec(),
+                limit,
+            },
+            children: vec![values(&input)],
+            columns: vec![],
+            est_rows: 0.0,
+            est_cost: 0.0,
+        };
+        let run = |db: &Database, plan: &Plan| {
+            let mut txn = db.begin(IsolationLevel::ReadCommitted);
+            execute(plan, &mut ExecContext { db, txn: &mut txn, limits: Limits::none(), trigger_depth: 0 }).unwrap()
+        };
+        for keys in &orders {
+            let mut expected = input.clone();
+            expected.sort_by(|a, b| {
+                let eval = |r: &Row| keys.iter().map(|k| k.expr.eval(r).unwrap()).collect::<Vec<_>>();
+                compare_keys(&eval(a), &eval(b), keys)
+            });
+            for work_mem_kb in [4096, 1] {
+                // At 1 KB every run holds a few rows, so runs are merged in several passes.
+                let db = open(work_mem_kb);
+                assert_eq!(run(&db, &sorted(keys, None)), expected, "{:?} {} KB", keys, work_mem_kb);
+                for limit in [0, 1, 10, 500, 5000] {
+                    let top = run(&db, &sorted(keys, Some(limit)));
+                    assert_eq!(top, expected[..expected.len().min(limit as usize)], "top {} {:?}", limit, keys);
+                }
+                assert!(std::fs::read_dir(db.temp_dir()).map_or(true, |mut d| d.next().is_none()));
+            }
+        }
+    }
+}
@@ -0,0 +1,496 @@
+//! EXPLAIN: describe the plan tree the planner chose and, for EXPLAIN ANALYZE, run it with
+//! every operator instrumented to count rows, loops, time and page accesses.
+
+use anyhow::Result;
+use serde_json::{json, Map, Value as Json};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::buffer::IoCounters;
+use crate::db::Database;
+use crate::query::exec::{self, ExecContext, Operator, Row};
+use crate::query::expr::Expr;
+use crate::query::plan::{
+    AggStrategy, ApplyKind, IndexBounds, JoinType, Node, OnConflict, Plan, SetOpKind, SortKey,
+};
+
+/// What one operator did while the plan ran. Time and page accesses include its children.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OpStats {
+    pub rows: u64,
+    /// Times the operator was started; 0 if it never ran.
+    pub loops: u64,
+    pub time: Duration,
+    pub io: IoCounters,
+}
+
+struct Instrumented {
+    inner: Box<dyn Operator>,
+    stats: Rc<RefCell<OpStats>>,
+}
+
+impl Operator for Instrumented {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        let (start, io) = (Instant::now(), IoCounters::current());
+        let row = self.inner.next(ctx);
+        let mut s = self.stats.borrow_mut();
+        s.loops = 1;
+        s.time += start.elapsed();
+        s.io.add(IoCounters::current().since(io));
+        if matches!(row, Ok(Some(_))) {
+            s.rows += 1;
+        }
+        row
+    }
+}
+
+/// Run `plan` to completion. Returns its rows and the stats of every node in post-order
+/// (children before their parent, left to right).
+pub fn analyze(plan: &Plan, ctx: &mut ExecContext<'_>) -> Result<(Vec<Row>, Vec<OpStats>)> {
+    let mut cells = Vec::new();
+    let mut op = exec::build_with(plan, ctx.db, &mut |inner| {
+        let stats = Rc::new(RefCell::new(OpStats::default()));
+        cells.push(Rc::clone(&stats));
+        Box::new(Instrumented { inner, stats })
+    })?;
+    let mut rows = Vec::new();
+    while let Some(row) = op.next(ctx)? {
+        rows.push(row);
+    }
+    drop(op);
+    Ok((rows, cells.iter().map(|c| *c.borrow()).collect()))
+}
+
+/// One plan node as EXPLAIN shows it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExplainNode {
+    /// "Seq Scan", "Hash Join", ...
+    pub node_type: String,
+    pub relation: Option<String>,
+    /// The B-tree the node reads, as its file name.
+    pub index: Option<String>,
+    /// Labelled details ("Filter", "Index Cond", ...) in display order.
+    pub details: Vec<(String, String)>,
+    pub est_rows: f64,
+    pub est_cost: f64,
+    pub actual: Option<OpStats>,
+    pub children: Vec<ExplainNode>,
+}
+
+/// The EXPLAIN output of one statement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Explain {
+    pub root: ExplainNode,
+    /// Only filled in by EXPLAIN ANALYZE.
+    pub planning_time: Option<Duration>,
+    pub execution_time: Option<Duration>,
+}
+
+fn ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+fn index_name(table: &str) -> String {
+    format!("{}.idx", table)
+}
+
+/// "Hash Join", "Hash Left Join", "Nested Loop", "Nested Loop Anti Join", ...
+fn join_name(algorithm: &str, join_type: JoinType) -> String {
+    let kind = match join_type {
+        JoinType::Inner if algorithm.ends_with("Loop") => return algorithm.to_string(),
+        JoinType::Inner => "",
+        JoinType::Left => " Left",
+        JoinType::Right => " Right",
+        JoinType::Full => " Full",
+        JoinType::Semi => " Semi",
+        JoinType::Anti => " Anti",
+    };
+    format!("{}{} Join", algorithm, kind)
+}
+
+fn list(exprs: &[Expr], name: &dyn Fn(usize) -> String) -> String {
+    exprs.iter().map(|e| e.display_with(name)).collect::<Vec<_>>().join(", ")
+}
+
+fn sort_key(k: &SortKey, name: &dyn Fn(usize) -> String) -> String {
+    let mut s = k.expr.display_with(name);
+    if k.desc {
+        s.push_str(" DESC");
+    }
+    if k.nulls_first != k.desc {
+        s.push_str(if k.nulls_first { " NULLS FIRST" } else { " NULLS LAST" });
+    }
+    s
+}
+
+/// Describe `plan`. `actual` holds the stats from `analyze`, in the order it returns them.
+/// Subplans that run once per outer row or step (of `Apply` and `RecursiveUnion`) follow
+/// the children, without stats, as do the nodes parallel workers run under a `Gather`.
+pub fn describe(plan: &Plan, db: &Database, actual: &mut Option<std::vec::IntoIter<OpStats>>) -> ExplainNode {
+    let mut children: Vec<ExplainNode> = match plan.node {
+        Node::Gather { .. } => plan.children.iter().map(|c| describe(c, db, &mut None)).collect(),
+        _ => plan.children.iter().map(|c| describe(c, db, actual)).collect(),
+    };
+    let stats = actual.as_mut().map(|a| a.next().unwrap_or_default());
+
+    let mut input: Vec<String> = match plan.children.as_slice() {
+        [] => plan.columns.iter().map(|(n, _)| n.clone()).collect(),
+        cs => cs.iter().flat_map(|c| c.columns.iter().map(|(n, _)| n.clone())).collect(),
+    };
+    if let Node::IndexNestedLoopJoin { table, .. } = &plan.node {
+        if let Some(def) = db.table_def(table) {
+            input.extend(def.columns.iter().map(|c| format!("{}.{}", table, c.name)));
+        }
+    }
+    let name = |i: usize| input.get(i).cloned().unwrap_or_else(|| format!("#{}", i));
+    let key_name = |table: &str, offset: usize| match db.table_def(table) {
+        Some(def) => name(offset + def.key_col),
+        None => "key".to_string(),
+    };
+
+    let mut details: Vec<(String, String)> = Vec::new();
+    let mut detail = |label: &str, value: String| details.push((label.to_string(), value));
+    let (node_type, relation, index) = match &plan.node {
+        Node::SeqScan { table, filter } => {
+            if let Some(f) = filter {
+                detail("Filter", f.display_with(&name));
+            }
+            ("Seq Scan".to_string(), Some(table.clone()), None)
+        }
+        Node::IndexScan { table, bounds, filter } => {
+            let key = key_name(table, 0);
+            let cond = match bounds {
+                IndexBounds::Keys(keys) if keys.len() == 1 => format!("{} = {}", key, keys[0].display_with(&name)),
+                IndexBounds::Keys(keys) => format!("{} IN ({})", key, list(keys, &name)),
+                IndexBounds::Range { low, high } => {
+                    let mut parts = Vec::new();
+                    if let Some((e, incl)) = low {
+                        parts.push(format!("{} {} {}", key, if *incl { ">=" } else { ">" }, e.display_with(&name)));
+                    }
+                    if let Some((e, incl)) = high {
+                        parts.push(format!("{} {} {}", key, if *incl { "<=" } else { "<" }, e.display_with(&name)));
+                    }
+                    if parts.is_empty() {
+                        "full range".to_string()
+                    } else {
+                        parts.join(" AND ")
+                    }
+                }
+            };
+            detail("Index Cond", cond);
+            if let Some(f) = filter {
+                detail("Filter", f.display_with(&name));
+            }
+            ("Index Scan".to_string(), Some(table.clone()), Some(index_name(table)))
+        }
+        Node::Values { rows } => {
+            detail("Rows", rows.len().to_string());
+            ("Values Scan".to_string(), None, None)
+        }
+        Node::WorkTableScan { name } => ("WorkTable Scan".to_string(), Some(name.clone()), None),
+        Node::TableFunction { func, args } => {
+            detail("Function Call", format!("{}({})", func.name(), list(args, &name)));
+            ("Function Scan".to_string(), Some(func.name().to_string()), None)
+        }
+        Node::Filter { predicate } => {
+            detail("Filter", predicate.display_with(&name));
+            ("Filter".to_string(), None, None)
+        }
+        Node::Apply { kind, params, subplan } => {
+            detail(
+                "Subquery",
+                match kind {
+                    ApplyKind::Scalar => "scalar".to_string(),
+                    ApplyKind::Exists => "EXISTS".to_string(),
+                    ApplyKind::In(e) => format!("{} IN", e.display_with(&name)),
+                },
+            );
+            if !params.is_empty() {
+                let params: Vec<String> = params
+                    .iter()
+                    .enumerate()
+                    .map(|(i, p)| format!("outer#{} = {}", i, p.display_with(&name)))
+                    .collect();
+                detail("Params", params.join(", "));
+            }
+            children.push(describe(subplan, db, &mut None));
+            ("Apply".to_string(), None, None)
+        }
+        Node::Project { exprs } => {
+            detail("Output", list(exprs, &name));
+            ("Project".to_string(), None, None)
+        }
+        Node::NestedLoopJoin { join_type, on } => {
+            if let Some(on) = on {
+                detail("Join Filter", on.display_with(&name));
+            }
+            (join_name("Nested Loop", *join_type), None, None)
+        }
+        Node::HashJoin {
+            join_type,
+            left_keys,
+            right_keys,
+            residual,
+        }
+        | Node::MergeJoin {
+            join_type,
+            left_keys,
+            right_keys,
+            residual,
+        } => {
+            let right = |i: usize| name(i + plan.children[0].columns.len());
+            let cond: Vec<String> = left_keys
+                .iter()
+                .zip(right_keys)
+                .map(|(l, r)| format!("{} = {}", l.display_with(&name), r.display_with(&right)))
+                .collect();
+            let (algo, label) = match plan.node {
+                Node::HashJoin { .. } => ("Hash", "Hash Cond"),
+                _ => ("Merge", "Merge Cond"),
+            };
+            detail(label, cond.join(" AND "));
+            if let Some(r) = residual {
+                detail("Join Filter", r.display_with(&name));
+            }
+            (join_name(algo, *join_type), None, None)
+        }
+        Node::IndexNestedLoopJoin {
+            join_type,
+            table,
+            key,
+            filter,
+        } => {
+            let offset = plan.children[0].columns.len();
+            detail("Index Cond", format!("{} = {}", key_name(table, offset), key.display_with(&name)));
+            if let Some(f) = filter {
+                detail("Filter", f.display_with(&name));
+            }
+            (join_name("Index Nested Loop", *join_type), Some(table.clone()), Some(index_name(table)))
+        }
+        Node::Aggregate {
+            strategy,
+            group_by,
+            sets,
+            ..
+        } => {
+            for set in sets.iter().filter(|s| !s.is_empty() || sets.len() > 1) {
+                let keys: Vec<String> = set.iter().map(|&k| group_by[k].display_with(&name)).collect();
+                detail("Group Key", if keys.is_empty() { "()".to_string() } else { keys.join(", ") });
+            }
+            let node_type = match strategy {
+                _ if group_by.is_empty() && sets.len() == 1 => "Aggregate",
+                AggStrategy::Hash => "HashAggregate",
+                AggStrategy::Sorted => "GroupAggregate",
+            };
+            (node_type.to_string(), None, None)
+        }
+        Node::Window {
+            partition_by,
+            order_by,
+            functions,
+        } => {
+            if !partition_by.is_empty() {
+                detail("Partition Key", list(partition_by, &name));
+            }
+            if !order_by.is_empty() {
+                let keys: Vec<String> = order_by.iter().map(|k| sort_key(k, &name)).collect();
+                detail("Order Key", keys.join(", "));
+            }
+            let calls: Vec<String> = functions.iter().map(|f| f.display_with(&name)).collect();
+            detail("Functions", calls.join(", "));
+            ("WindowAgg".to_string(), None, None)
+        }
+        Node::Sort { keys, limit } => {
+            let keys: Vec<String> = keys.iter().map(|k| sort_key(k, &name)).collect();
+            detail("Sort Key", keys.join(", "));
+            if limit.is_some() {
+                detail("Sort Method", "top-N heapsort".to_string());
+            }
+            ("Sort".to_string(), None, None)
+        }
+        Node::Limit { limit, offset } => {
+            if let Some(l) = limit {
+                detail("Limit", l.to_string());
+            }
+            if *offset > 0 {
+                detail("Offset", offset.to_string());
+            }
+            ("Limit".to_string(), None, None)
+        }
+        Node::RecursiveUnion {
+            name,
+            distinct,
+            recursive,
+        } => {
+            if *distinct {
+                detail("Distinct", "true".to_string());
+            }
+            children.push(describe(recursive, db, &mut None));
+            ("Recursive Union".to_string(), Some(name.clone()), None)
+        }
+        Node::Gather { workers } => {
+            detail("Workers Planned", workers.to_string());
+            // Each worker aggregates its own rows; the Gather merges the results.
+            if let Node::Aggregate { .. } = plan.children[0].node {
+                children[0].node_type = format!("Partial {}", children[0].node_type);
+            }
+            ("Gather".to_string(), None, None)
+        }
+        Node::Append => ("Append".to_string(), None, None),
+        Node::SetOp { op, all } => {
+            let command = match op {
+                SetOpKind::Intersect => "Intersect",
+                SetOpKind::Except => "Except",
+            };
+            detail("Command", format!("{}{}", command, if *all { " All" } else { "" }));
+            ("SetOp".to_string(), None, None)
+        }
+        Node::Insert { table, on_conflict, .. } => {
+            match on_conflict {
+                None => {}
+                Some(OnConflict::DoNothing) => detail("Conflict Resolution", "NOTHING".to_string()),
+                Some(OnConflict::DoUpdate { assignments, filter }) => {
+                    detail("Conflict Resolution", "UPDATE".to_string());
+                    // The existing row, then the one proposed for insertion.
+                    let columns: Vec<&String> = plan.columns.iter().map(|(n, _)| n).collect();
+                    let both = |i: usize| match columns.get(i) {
+                        Some(n) => n.to_string(),
+                        None => format!("excluded.{}", columns[i - columns.len()]),
+                    };
+                    let set: Vec<String> = assignments
+                        .iter()
+                        .map(|(c, e)| format!("{} = {}", columns[*c], e.display_with(&both)))
+                        .collect();
+                    detail("Conflict Set", set.join(", "));
+                    if let Some(f) = filter {
+                        detail("Conflict Filter", f.display_with(&both));
+                    }
+                }
+            }
+            ("Insert".to_string(), Some(table.clone()), None)
+        }
+        Node::Update { table, assignments } => {
+            let set: Vec<String> = assignments
+                .iter()
+                .map(|(c, e)| format!("{} = {}", name(*c), e.display_with(&name)))
+                .collect();
+            detail("Set", set.join(", "));
+            ("Update".to_string(), Some(table.clone()), None)
+        }
+        Node::Delete { table } => ("Delete".to_string(), Some(table.clone()), None),
+        Node::LockRows { table, mode } => {
+            detail("Lock Mode", format!("{:?}", mode));
+            ("LockRows".to_string(), Some(table.clone()), None)
+        }
+    };
+    ExplainNode {
+        node_type,
+        relation,
+        index,
+        details,
+        est_rows: plan.est_rows,
+        est_cost: plan.est_cost,
+        actual: stats,
+        children,
+    }
+}
+
+impl ExplainNode {
+    fn title(&self) -> String {
+        let mut s = self.node_type.clone();
+        if let Some(i) = &self.index {
+            s.push_str(&format!(" using {}", i));
+        }
+        if let Some(r) = &self.relation {
+            s.push_str(&format!(" on {}", r));
+        }
+        s
+    }
+
+    fn text(&self, indent: usize, arrow: bool, out: &mut Vec<String>) {
+        let mut line = format!(
+            "{}{}{}  (cost={:.2} rows={:.0})",
+            " ".repeat(indent),
+            if arrow { "->  " } else { "" },
+            self.title(),
+            self.est_cost,
+            self.est_rows.ceil()
+        );
+        match &self.actual {
+            Some(a) if a.loops == 0 => line.push_str(" (never executed)"),
+            Some(a) => line.push_str(&format!(
+                " (actual time={:.3} ms rows={} loops={})",
+                ms(a.time),
+                a.rows,
+                a.loops
+            )),
+            None => {}
+        }
+        out.push(line);
+        let inner = indent + if arrow { 6 } else { 2 };
+        for (label, value) in &self.details {
+            out.push(format!("{}{}: {}", " ".repeat(inner), label, value));
+        }
+        if let Some(a) = self.actual.filter(|a| a.io != IoCounters::default()) {
+            out.push(format!("{}Buffers: hit={} read={}", " ".repeat(inner), a.io.hits, a.io.reads));
+        }
+        for c in &self.children {
+            c.text(inner, true, out);
+        }
+    }
+
+    fn json(&self) -> Json {
+        let mut obj = Map::new();
+        obj.insert("node_type".into(), json!(self.node_type));
+        if let Some(r) = &self.relation {
+            obj.insert("relation".into(), json!(r));
+        }
+        if let Some(i) = &self.index {
+            obj.insert("index".into(), json!(i));
+        }
+        for (label, value) in &self.details {
+            obj.insert(label.to_lowercase().replace(' ', "_"), json!(value));
+        }
+        obj.insert("estimated_rows".into(), json!(self.est_rows));
+        obj.insert("estimated_cost".into(), json!(self.est_cost));
+        if let Some(a) = &self.actual {
+            obj.insert("actual_rows".into(), json!(a.rows));
+            obj.insert("actual_loops".into(), json!(a.loops));
+            obj.insert("actual_time_ms".into(), json!(ms(a.time)));
+            obj.insert("buffers_hit".into(), json!(a.io.hits));
+            obj.insert("buffers_read".into(), json!(a.io.reads));
+        }
+        if !self.children.is_empty() {
+            obj.insert("plans".into(), Json::Array(self.children.iter().map(|c| c.json()).collect()));
+        }
+        Json::Object(obj)
+    }
+}
+
+impl Explain {
+    /// Indented plan tree, one line per entry.
+    pub fn to_text(&self) -> Vec<String> {
+        let mut out = Vec::new();
+        self.root.text(0, false, &mut out);
+        if let Some(t) = self.planning_time {
+            out.push(format!("Planning Time: {:.3} ms", ms(t)));
+        }
+        if let Some(t) = self.execution_time {
+            out.push(format!("Execution Time: {:.3} ms", ms(t)));
+        }
+        out
+    }
+
+    pub fn to_json(&self) -> Json {
+        let mut obj = Map::new();
+        obj.insert("plan".into(), self.root.json());
+        if let Some(t) = self.planning_time {
+            obj.insert("planning_time_ms".into(), json!(ms(t)));
+        }
+        if let Some(t) = self.execution_time {
+            obj.insert("execution_time_ms".into(), json!(ms(t)));
+        }
+        Json::Object(obj)
+    }
+}
@@ -0,0 +1,701 @@
+//! Bound scalar expressions and their evaluation.
+//!
+//! `Column(i)` is a position in the input row. While planning, the same type is used with
+//! `i` naming a column of the query block (see `planner`); it is remapped to a row position
+//! once the operator producing the input is known.
+
+use anyhow::{bail, Result};
+use std::collections::BTreeSet;
+use std::fmt;
+
+use super::function::{self, ScalarFunc};
+use super::udf;
+use crate::sequence::SessionSequences;
+use crate::storage::{ColumnType, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinaryOp {
+    Plus,
+    Minus,
+    Multiply,
+    Divide,
+    Modulo,
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    And,
+    Or,
+    Concat,
+}
+
+impl BinaryOp {
+    pub fn is_comparison(self) -> bool {
+        matches!(
+            self,
+            BinaryOp::Eq | BinaryOp::NotEq | BinaryOp::Lt | BinaryOp::LtEq | BinaryOp::Gt | BinaryOp::GtEq
+        )
+    }
+
+    /// `a op b` == `b op.flip() a`.
+    pub fn flip(self) -> Self {
+        match self {
+            BinaryOp::Lt => BinaryOp::Gt,
+            BinaryOp::LtEq => BinaryOp::GtEq,
+            BinaryOp::Gt => BinaryOp::Lt,
+            BinaryOp::GtEq => BinaryOp::LtEq,
+            other => other,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Plus => "+",
+            BinaryOp::Minus => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Modulo => "%",
+            BinaryOp::Eq => "=",
+            BinaryOp::NotEq => "<>",
+            BinaryOp::Lt => "<",
+            BinaryOp::LtEq => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::GtEq => ">=",
+            BinaryOp::And => "AND",
+            BinaryOp::Or => "OR",
+            BinaryOp::Concat => "||",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnaryOp {
+    Not,
+    Neg,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Column(usize),
+    Literal(Value),
+    /// Value `index` a correlated subquery reads from the current row of its enclosing
+    /// query; replaced by a literal before the subquery runs (see `Node::Apply`).
+    Outer {
+        index: usize,
+        ty: ColumnType,
+    },
+    /// Parameter `$index+1` of a prepared statement; replaced by a literal before the plan
+    /// runs (see `Plan::bind_params`).
+    Param {
+        index: usize,
+        ty: ColumnType,
+    },
+    Unary {
+        op: UnaryOp,
+        expr: Box<Expr>,
+    },
+    Binary {
+        op: BinaryOp,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    IsNull {
+        expr: Box<Expr>,
+        negated: bool,
+    },
+    InList {
+        expr: Box<Expr>,
+        list: Vec<Expr>,
+        negated: bool,
+    },
+    Between {
+        expr: Box<Expr>,
+        low: Box<Expr>,
+        high: Box<Expr>,
+        negated: bool,
+    },
+    /// Call of a built-in scalar function, resolved to result type `ty` when planned.
+    Function {
+        func: ScalarFunc,
+        args: Vec<Expr>,
+        ty: ColumnType,
+    },
+    /// `CASE [operand] WHEN .. THEN .. [ELSE ..] END`. With an operand, each branch
+    /// condition is a value compared to it; otherwise a predicate.
+    Case {
+        operand: Option<Box<Expr>>,
+        branches: Vec<(Expr, Expr)>,
+        else_result: Option<Box<Expr>>,
+        ty: ColumnType,
+    },
+    Cast {
+        expr: Box<Expr>,
+        ty: ColumnType,
+    },
+}
+
+impl Expr {
+    pub fn binary(op: BinaryOp, left: Expr, right: Expr) -> Expr {
+        Expr::Binary {
+            op,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    /// AND of all `exprs`; `None` when empty.
+    pub fn and_all(exprs: Vec<Expr>) -> Option<Expr> {
+        exprs.into_iter().reduce(|a, b| Expr::binary(BinaryOp::And, a, b))
+    }
+
+    /// Split a predicate into its top-level AND terms.
+    pub fn conjuncts(self) -> Vec<Expr> {
+        match self {
+            Expr::Binary {
+                op: BinaryOp::And,
+                left,
+                right,
+            } => {
+                let mut out = left.conjuncts();
+                out.extend(right.conjuncts());
+                out
+            }
+            other => vec![other],
+        }
+    }
+
+    /// Child expressions, in evaluation order.
+    pub fn children(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Column(_) | Expr::Literal(_) | Expr::Outer { .. } | Expr::Param { .. } => vec![],
+            Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } | Expr::Cast { expr, .. } => vec![expr],
+            Expr::Binary { left, right, .. } => vec![left, right],
+            Expr::InList { expr, list, .. } => std::iter::once(&**expr).chain(list).collect(),
+            Expr::Between { expr, low, high, .. } => vec![expr, low, high],
+            Expr::Function { args, .. } => args.iter().collect(),
+            Expr::Case {
+                operand,
+                branches,
+                else_result,
+                ..
+            } => operand
+                .iter()
+                .map(|e| &**e)
+                .chain(branches.iter().flat_map(|(when, then)| [when, then]))
+                .chain(else_result.iter().map(|e| &**e))
+                .collect(),
+        }
+    }
+
+    /// Columns referenced anywhere in the expression.
+    pub fn columns(&self) -> BTreeSet<usize> {
+        let mut out = BTreeSet::new();
+        self.collect_columns(&mut out);
+        out
+    }
+
+    fn collect_columns(&self, out: &mut BTreeSet<usize>) {
+        if let Expr::Column(i) = self {
+            out.insert(*i);
+        }
+        for c in self.children() {
+            c.collect_columns(out);
+        }
+    }
+
+    /// Whether the expression reads a value of an enclosing query.
+    pub fn has_outer(&self) -> bool {
+        matches!(self, Expr::Outer { .. }) || self.children().into_iter().any(Expr::has_outer)
+    }
+
+    /// Whether the expression calls a sequence function, whose value changes from call to
+    /// call and which is bound to the session that planned it.
+    pub fn calls_sequence(&self) -> bool {
+        matches!(self, Expr::Function { func: ScalarFunc::Sequence(_), .. })
+            || self.children().into_iter().any(Expr::calls_sequence)
+    }
+
+    /// Make the sequence function calls in the expression those of `sequences`' session.
+    pub fn bind_sequences(&self, sequences: &SessionSequences) -> Expr {
+        self.transform(&|e| match e {
+            Expr::Function {
+                func: ScalarFunc::Sequence(f),
+                args,
+                ty,
+            } => Some(Expr::Function {
+                func: ScalarFunc::Sequence(sequences.func(f.op())),
+                args: args.iter().map(|a| a.bind_sequences(sequences)).collect(),
+                ty: *ty,
+            }),
+            _ => None,
+        })
+    }
+
+    /// Replace each outer reference `i` with the literal `values[i]`.
+    pub fn bind_outer(&self, values: &[Value]) -> Expr {
+        self.transform(&|e| match e {
+            Expr::Outer { index, .. } => Some(Expr::Literal(values[*index].clone())),
+            _ => None,
+        })
+    }
+
+    /// Replace each parameter `i` with the literal `values[i]`.
+    pub fn bind_params(&self, values: &[Value]) -> Expr {
+        self.transform(&|e| match e {
+            Expr::Param { index, .. } => Some(Expr::Literal(values[*index].clone())),
+            _ => None,
+        })
+    }
+
+    /// Rewrite every column reference through `f`.
+    pub fn map_columns(&self, f: &impl Fn(usize) -> usize) -> Expr {
+        self.transform(&|e| match e {
+            Expr::Column(i) => Some(Expr::Column(f(*i))),
+            _ => None,
+        })
+    }
+
+    /// Rebuild the tree top-down: where `f` returns a replacement it is used as is;
+    /// elsewhere the node is kept and its children are transformed.
+    pub fn transform(&self, f: &impl Fn(&Expr) -> Option<Expr>) -> Expr {
+        if let Some(e) = f(self) {
+            return e;
+        }
+        let t = |e: &Expr| Box::new(e.transform(f));
+        match self {
+            Expr::Column(_) | Expr::Literal(_) | Expr::Outer { .. } | Expr::Param { .. } => self.clone(),
+            Expr::Unary { op, expr } => Expr::Unary { op: *op, expr: t(expr) },
+            Expr::Binary { op, left, right } => Expr::Binary {
+                op: *op,
+                left: t(left),
+                right: t(right),
+            },
+            Expr::IsNull { expr, negated } => Expr::IsNull {
+                expr: t(expr),
+                negated: *negated,
+            },
+            Expr::InList { expr, list, negated } => Expr::InList {
+                expr: t(expr),
+                list: list.iter().map(|e| e.transform(f)).collect(),
+                negated: *negated,
+            },
+            Expr::Between {
+                expr,
+                low,
+                high,
+                negated,
+            } => Expr::Between {
+                expr: t(expr),
+                low: t(low),
+                high: t(high),
+                negated: *negated,
+            },
+            Expr::Function { func, args, ty } => Expr::Function {
+                func: func.clone(),
+                args: args.iter().map(|e| e.transform(f)).collect(),
+                ty: *ty,
+            },
+            Expr::Case {
+                operand,
+                branches,
+                else_result,
+                ty,
+            } => Expr::Case {
+                operand: operand.as_deref().map(t),
+                branches: branches
+                    .iter()
+                    .map(|(when, then)| (when.transform(f), then.transform(f)))
+                    .collect(),
+                else_result: else_result.as_deref().map(t),
+                ty: *ty,
+            },
+            Expr::Cast { expr, ty } => Expr::Cast { expr: t(expr), ty: *ty },
+        }
+    }
+
+    /// Whether the expression reads no columns and calls no sequence function (its value
+    /// is fixed for the statement, or for one run of a subquery).
+    pub fn is_constant(&self) -> bool {
+        self.columns().is_empty() && !self.calls_sequence()
+    }
+
+    /// Result type given the input column types. A bare NULL is typed TEXT.
+    pub fn data_type(&self, input: &[ColumnType]) -> ColumnType {
+        self.data_type_with(&|i| input.get(i).copied().unwrap_or(ColumnType::Text))
+    }
+
+    /// Result type given the type of each column.
+    pub fn data_type_with(&self, column: &dyn Fn(usize) -> ColumnType) -> ColumnType {
+        match self {
+            Expr::Column(i) => column(*i),
+            Expr::Literal(v) => v.column_type().unwrap_or(ColumnType::Text),
+            Expr::Outer { ty, .. }
+            | Expr::Param { ty, .. }
+            | Expr::Function { ty, .. }
+            | Expr::Case { ty, .. }
+            | Expr::Cast { ty, .. } => *ty,
+            Expr::Unary { op: UnaryOp::Neg, expr } => expr.data_type_with(column),
+            Expr::Binary { op, left, right } => match op {
+                BinaryOp::Plus | BinaryOp::Minus | BinaryOp::Multiply | BinaryOp::Divide | BinaryOp::Modulo => {
+                    match (left.data_type_with(column), right.data_type_with(column)) {
+                        (ColumnType::Int, ColumnType::Int) => ColumnType::Int,
+                        _ => ColumnType::Float,
+                    }
+                }
+                BinaryOp::Concat => ColumnType::Text,
+                _ => ColumnType::Bool,
+            },
+            Expr::Unary { op: UnaryOp::Not, .. }
+            | Expr::IsNull { .. }
+            | Expr::InList { .. }
+            | Expr::Between { .. } => ColumnType::Bool,
+        }
+    }
+
+    pub fn eval(&self, row: &[Value]) -> Result<Value> {
+        match self {
+            Expr::Column(i) => match row.get(*i) {
+                Some(v) => Ok(v.clone()),
+                None => bail!("column {} out of range for row of {}", i, row.len()),
+            },
+            Expr::Literal(v) => Ok(v.clone()),
+            Expr::Outer { index, .. } => bail!("outer reference {} evaluated outside its subquery", index),
+            Expr::Param { index, .. } => bail!("there is no value for parameter ${}", index + 1),
+            Expr::Unary { op, expr } => {
+                let v = expr.eval(row)?;
+                match (op, v) {
+                    (_, Value::Null) => Ok(Value::Null),
+                    (UnaryOp::Not, Value::Bool(b)) => Ok(Value::Bool(!b)),
+                    (UnaryOp::Neg, Value::Int(n)) => match n.checked_neg() {
+                        Some(n) => Ok(Value::Int(n)),
+                        None => bail!("integer out of range"),
+                    },
+                    (UnaryOp::Neg, Value::Float(x)) => Ok(Value::Float(-x)),
+                    (op, v) => bail!("cannot apply {:?} to {}", op, type_name(&v)),
+                }
+            }
+            Expr::Binary { op, left, right } => {
+                let l = left.eval(row)?;
+                match op {
+                    // Three-valued logic; the right side is skipped when it cannot matter.
+                    BinaryOp::And => {
+                        if l == Value::Bool(false) {
+                            return Ok(l);
+                        }
+                        logic(*op, l, right.eval(row)?)
+                    }
+                    BinaryOp::Or => {
+                        if l == Value::Bool(true) {
+                            return Ok(l);
+                        }
+                        logic(*op, l, right.eval(row)?)
+                    }
+                    _ => binary(*op, l, right.eval(row)?),
+                }
+            }
+            Expr::IsNull { expr, negated } => Ok(Value::Bool(expr.eval(row)?.is_null() != *negated)),
+            Expr::InList { expr, list, negated } => {
+                let v = expr.eval(row)?;
+                if v.is_null() {
+                    return Ok(Value::Null);
+                }
+                let mut saw_null = false;
+                for item in list {
+                    match binary(BinaryOp::Eq, v.clone(), item.eval(row)?)? {
+                        Value::Bool(true) => return Ok(Value::Bool(!negated)),
+                        Value::Null => saw_null = true,
+                        _ => {}
+                    }
+                }
+                Ok(if saw_null { Value::Null } else { Value::Bool(*negated) })
+            }
+            Expr::Between {
+                expr,
+                low,
+                high,
+                negated,
+            } => {
+                let v = expr.eval(row)?;
+                let ge = binary(BinaryOp::GtEq, v.clone(), low.eval(row)?)?;
+                let le = binary(BinaryOp::LtEq, v, high.eval(row)?)?;
+                let both = logic(BinaryOp::And, ge, le)?;
+                if *negated {
+                    Expr::Unary {
+                        op: UnaryOp::Not,
+                        expr: Box::new(Expr::Literal(both)),
+                    }
+                    .eval(row)
+                } else {
+                    Ok(both)
+                }
+            }
+            Expr::Function { func, args, ty } => {
+                let v = if let ScalarFunc::User(f) = func {
+                    let args = args.iter().map(|e| e.eval(row)).collect::<Result<Vec<_>>>()?;
+                    return udf::check_result(f.name(), func.call(&args)?, Some(*ty));
+                } else if *func == ScalarFunc::Coalesce {
+                    // Later arguments are only evaluated while the earlier ones are NULL.
+                    let mut out = Value::Null;
+                    for arg in args {
+                        out = arg.eval(row)?;
+                        if !out.is_null() {
+                            break;
+                        }
+                    }
+                    out
+                } else {
+                    let args = args.iter().map(|e| e.eval(row)).collect::<Result<Vec<_>>>()?;
+                    func.call(&args)?
+                };
+                widen(v, *ty)
+            }
+            Expr::Case {
+                operand,
+                branches,
+                else_result,
+                ty,
+            } => {
+                let operand = operand.as_ref().map(|e| e.eval(row)).transpose()?;
+                for (when, then) in branches {
+                    let hit = match &operand {
+                        Some(v) => binary(BinaryOp::Eq, v.clone(), when.eval(row)?)? == Value::Bool(true),
+                        None => when.eval_predicate(row)?,
+                    };
+                    if hit {
+                        return widen(then.eval(row)?, *ty);
+                    }
+                }
+                match else_result {
+                    Some(e) => widen(e.eval(row)?, *ty),
+                    None => Ok(Value::Null),
+                }
+            }
+            Expr::Cast { expr, ty } => function::cast(expr.eval(row)?, *ty),
+        }
+    }
+
+    /// Evaluate as a filter: only TRUE passes (NULL and FALSE do not).
+    pub fn eval_predicate(&self, row: &[Value]) -> Result<bool> {
+        match self.eval(row)? {
+            Value::Bool(b) => Ok(b),
+            Value::Null => Ok(false),
+            v => bail!("argument of WHERE must be BOOL, not {}", type_name(&v)),
+        }
+    }
+}
+
+fn type_name(v: &Value) -> String {
+    v.column_type().map_or("NULL".to_string(), |t| t.to_string())
+}
+
+/// `l op r` for AND and OR, in three-valued logic.
+pub fn logic(op: BinaryOp, l: Value, r: Value) -> Result<Value> {
+    let as_bool = |v: &Value| match v {
+        Value::Bool(b) => Ok(Some(*b)),
+        Value::Null => Ok(None),
+        v => bail!("argument of {} must be BOOL, not {}", op.symbol(), type_name(v)),
+    };
+    let (l, r) = (as_bool(&l)?, as_bool(&r)?);
+    Ok(match (op, l, r) {
+        (BinaryOp::And, Some(false), _) | (BinaryOp::And, _, Some(false)) => Value::Bool(false),
+        (BinaryOp::And, Some(true), Some(true)) => Value::Bool(true),
+        (BinaryOp::Or, Some(true), _) | (BinaryOp::Or, _, Some(true)) => Value::Bool(true),
+        (BinaryOp::Or, Some(false), Some(false)) => Value::Bool(false),
+        _ => Value::Null,
+    })
+}
+
+/// `l op r` for the arithmetic, comparison and concatenation operators.
+pub fn binary(op: BinaryOp, l: Value, r: Value) -> Result<Value> {
+    if l.is_null() || r.is_null() {
+        return Ok(Value::Null);
+    }
+    if op.is_comparison() {
+        let Some(ord) = l.sql_cmp(&r) else {
+            bail!("cannot compare {} with {}", type_name(&l), type_name(&r));
+        };
+        use std::cmp::Ordering::*;
+        let b = match op {
+            BinaryOp::Eq => ord == Equal,
+            BinaryOp::NotEq => ord != Equal,
+            BinaryOp::Lt => ord == Less,
+            BinaryOp::LtEq => ord != Greater,
+            BinaryOp::Gt => ord == Greater,
+            _ => ord != Less,
+        };
+        return Ok(Value::Bool(b));
+    }
+    match (op, &l, &r) {
+        (BinaryOp::Concat, _, _) => Ok(Value::Text(format!("{}{}", l, r))),
+        (_, Value::Int(a), Value::Int(b)) => {
+            let (a, b) = (*a, *b);
+            let out = match op {
+                BinaryOp::Plus => a.checked_add(b),
+                BinaryOp::Minus => a.checked_sub(b),
+                BinaryOp::Multiply => a.checked_mul(b),
+                BinaryOp::Divide | BinaryOp::Modulo if b == 0 => bail!("division by zero"),
+                BinaryOp::Divide => a.checked_div(b),
+                BinaryOp::Modulo => a.checked_rem(b),
+                _ => bail!("operator {} does not apply to INT", op.symbol()),
+            };
+            match out {
+                Some(n) => Ok(Value::Int(n)),
+                None => bail!("integer out of range"),
+            }
+        }
+        _ => match (l.as_f64(), r.as_f64()) {
+            (Some(a), Some(b)) => Ok(Value::Float(match op {
+                BinaryOp::Plus => a + b,
+                BinaryOp::Minus => a - b,
+                BinaryOp::Multiply => a * b,
+                BinaryOp::Divide | BinaryOp::Modulo if b == 0.0 => bail!("division by zero"),
+                BinaryOp::Divide => a / b,
+                BinaryOp::Modulo => a % b,
+                _ => bail!("operator {} does not apply to FLOAT", op.symbol()),
+            })),
+            _ => bail!(
+                "operator {} does not apply to {} and {}",
+                op.symbol(),
+                type_name(&l),
+                type_name(&r)
+            ),
+        },
+    }
+}
+
+/// An INT result of an expression typed FLOAT (say `coalesce(int, float)`) as FLOAT.
+fn widen(v: Value, ty: ColumnType) -> Result<Value> {
+    Ok(match (v, ty) {
+        (Value::Int(n), ColumnType::Float) => Value::Float(n as f64),
+        (v, _) => v,
+    })
+}
+
+/// Convert `v` for storage in a column of type `ty` (INT widens to FLOAT).
+pub fn coerce(v: Value, ty: ColumnType) -> Result<Value> {
+    match (v, ty) {
+        (Value::Null, _) => Ok(Value::Null),
+        (Value::Int(n), ColumnType::Float) => Ok(Value::Float(n as f64)),
+        (v, ty) if v.column_type() == Some(ty) => Ok(v),
+        (v, ty) => bail!("expected {} value, got {}", ty, type_name(&v)),
+    }
+}
+
+/// Renders with `#i` for columns; use `display_with` to show names.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.display_with(&|i| format!("#{}", i)))
+    }
+}
+
+impl Expr {
+    /// SQL-like rendering, naming column `i` by `name(i)`.
+    pub fn display_with(&self, name: &dyn Fn(usize) -> String) -> String {
+        let d = |e: &Expr| e.display_with(name);
+        match self {
+            Expr::Column(i) => name(*i),
+            Expr::Literal(Value::Text(s)) => format!("'{}'", s.replace('\'', "''")),
+            Expr::Literal(v) => v.to_string(),
+            Expr::Outer { index, .. } => format!("outer#{}", index),
+            Expr::Param { index, .. } => format!("${}", index + 1),
+            Expr::Unary { op: UnaryOp::Not, expr } => format!("NOT {}", d(expr)),
+            Expr::Unary { op: UnaryOp::Neg, expr } => format!("-{}", d(expr)),
+            Expr::Binary { op, left, right } => format!("({} {} {})", d(left), op.symbol(), d(right)),
+            Expr::IsNull { expr, negated } => {
+                format!("{} IS {}NULL", d(expr), if *negated { "NOT " } else { "" })
+            }
+            Expr::InList { expr, list, negated } => format!(
+                "{} {}IN ({})",
+                d(expr),
+                if *negated { "NOT " } else { "" },
+                list.iter().map(d).collect::<Vec<_>>().join(", ")
+            ),
+            Expr::Between {
+                expr,
+                low,
+                high,
+                negated,
+            } => format!(
+                "{} {}BETWEEN {} AND {}",
+                d(expr),
+                if *negated { "NOT " } else { "" },
+                d(low),
+                d(high)
+            ),
+            Expr::Function { func, args, .. } => match (func.operator(), args.as_slice()) {
+                (Some(op), [s, pattern]) => format!("({} {} {})", d(s), op, d(pattern)),
+                (Some(op), [s, pattern, escape]) => format!("({} {} {} ESCAPE {})", d(s), op, d(pattern), d(escape)),
+                _ => format!("{}({})", func.name(), args.iter().map(d).collect::<Vec<_>>().join(", ")),
+            },
+            Expr::Case {
+                operand,
+                branches,
+                else_result,
+                ..
+            } => {
+                let mut out = "CASE".to_string();
+                if let Some(e) = operand {
+                    out += &format!(" {}", d(e));
+                }
+                for (when, then) in branches {
+                    out += &format!(" WHEN {} THEN {}", d(when), d(then));
+                }
+                if let Some(e) = else_result {
+                    out += &format!(" ELSE {}", d(e));
+                }
+                out + " END"
+            }
+            Expr::Cast { expr, ty } => format!("CAST({} AS {})", d(expr), ty),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lit(v: Value) -> Expr {
+        Expr::Literal(v)
+    }
+
+    #[test]
+    fn three_valued_logic_and_comparisons() {
+        let row = vec![Value::Int(5), Value::Null, Value::Text("a".into())];
+        let gt = Expr::binary(BinaryOp::Gt, Expr::Column(0), lit(Value::Int(3)));
+        assert_eq!(gt.eval(&row).unwrap(), Value::Bool(true));
+        let null_cmp = Expr::binary(BinaryOp::Eq, Expr::Column(1), lit(Value::Int(3)));
+        assert_eq!(null_cmp.eval(&row).unwrap(), Value::Null);
+        assert!(!null_cmp.eval_predicate(&row).unwrap());
+        let or = Expr::binary(BinaryOp::Or, null_cmp.clone(), gt.clone());
+        assert_eq!(or.eval(&row).unwrap(), Value::Bool(true));
+        let and = Expr::binary(BinaryOp::And, null_cmp, gt);
+        assert_eq!(and.eval(&row).unwrap(), Value::Null);
+        let in_list = Expr::InList {
+            expr: Box::new(Expr::Column(0)),
+            list: vec![lit(Value::Int(1)), lit(Value::Null)],
+            negated: true,
+        };
+        assert_eq!(in_list.eval(&row).unwrap(), Value::Null);
+        assert!(Expr::binary(BinaryOp::Lt, Expr::Column(0), Expr::Column(2))
+            .eval(&row)
+            .is_err());
+    }
+
+    #[test]
+    fn arithmetic_and_coercion() {
+        let e = Expr::binary(BinaryOp::Divide, lit(Value::Int(7)), lit(Value::Float(2.0)));
+        assert_eq!(e.eval(&[]).unwrap(), Value::Float(3.5));
+        assert!(Expr::binary(BinaryOp::Divide, lit(Value::Int(1)), lit(Value::Int(0)))
+            .eval(&[])
+            .is_err());
+        assert!(Expr::binary(BinaryOp::Plus, lit(Value::Int(i64::MAX)), lit(Value::Int(1)))
+            .eval(&[])
+            .is_err());
+        assert_eq!(coerce(Value::Int(2), ColumnType::Float).unwrap(), Value::Float(2.0));
+        assert!(coerce(Value::Text("x".into()), ColumnType::Int).is_err());
+        let e = Expr::binary(BinaryOp::And, Expr::Column(3), Expr::Column(1)).map_columns(&|i| i + 10);
+        assert_eq!(e.columns().into_iter().collect::<Vec<_>>(), vec![11, 13]);
+    }
+}
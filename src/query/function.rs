@@ -0,0 +1,743 @@
+//! Built-in scalar functions: string functions (`lower`, `upper`, `substr`, `trim`,
+//! `replace`, `concat`, `length`, `position`, `split_part`), math functions (`abs`, `round`,
+//! `mod`, `power`), the conditionals `COALESCE`, `NULLIF`, `GREATEST` and `LEAST`, and the
+//! functions behind `LIKE`, `ILIKE` and regular-expression matching (`~`, `~*`).
+//!
+//! Each function has one or more typed signatures. A call is resolved against them when
+//! the query is planned (`ScalarFunc::result_type`); `CAST` conversions are checked the
+//! same way (`cast_type`). Functions registered from Rust (`udf::ScalarUdf`) declare
+//! their signatures in the same terms.
+
+use anyhow::{bail, Result};
+use regex_automata::meta::Regex;
+use regex_automata::util::syntax;
+use std::cell::RefCell;
+
+use crate::query::udf::{ScalarUdf, Udf};
+use crate::sequence::SequenceFunc;
+use crate::storage::{ColumnType, Value};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ScalarFunc {
+    Lower,
+    Upper,
+    /// `substr(text, start [, count])`, also written `SUBSTRING(text FROM start FOR count)`.
+    Substr,
+    /// `trim(text [, characters])`, also written `TRIM([BOTH | LEADING | TRAILING] ...)`.
+    Trim,
+    Ltrim,
+    Rtrim,
+    Replace,
+    Concat,
+    Length,
+    /// `position(substring, text)`, written `POSITION(substring IN text)`.
+    Position,
+    SplitPart,
+    Abs,
+    Round,
+    Mod,
+    Power,
+    Coalesce,
+    Nullif,
+    Greatest,
+    Least,
+    /// `text LIKE pattern [ESCAPE escape]`.
+    Like,
+    ILike,
+    /// `text ~ pattern`.
+    RegexMatch,
+    /// `text ~* pattern`.
+    RegexIMatch,
+    /// A function registered from Rust (see `udf`).
+    User(Udf<dyn ScalarUdf>),
+    /// `nextval`, `currval` or `setval`, bound to the session planning the call.
+    Sequence(SequenceFunc),
+}
+
+/// A parameter (or result) of a function signature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamType {
+    /// Exactly this type; an INT argument may widen to FLOAT.
+    Is(ColumnType),
+    /// Any type.
+    Any,
+    /// One type shared by every `Same` argument of the call, INT and FLOAT meeting as
+    /// FLOAT; a `Same` result has that type.
+    Same,
+}
+
+/// One form of a function: its parameter types and result type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signature {
+    pub params: &'static [ParamType],
+    /// The last parameter may be repeated.
+    pub variadic: bool,
+    pub result: ParamType,
+}
+
+impl Signature {
+    /// Result type of a call with arguments of types `args` (None if unknown), if this
+    /// form accepts them. Without `widen`, INT arguments do not match FLOAT parameters.
+    fn accepts(&self, args: &[Option<ColumnType>], widen: bool) -> Option<ColumnType> {
+        let n = self.params.len();
+        if args.len() < n || (args.len() > n && !self.variadic) {
+            return None;
+        }
+        let mut same: Option<ColumnType> = None;
+        for (i, arg) in args.iter().enumerate() {
+            let Some(ty) = *arg else {
+                continue;
+            };
+            match self.params[i.min(n - 1)] {
+                ParamType::Any => {}
+                ParamType::Is(p) if p == ty || (widen && p == ColumnType::Float && ty == ColumnType::Int) => {}
+                ParamType::Is(_) => return None,
+                ParamType::Same => {
+                    same = Some(match same {
+                        None => ty,
+                        Some(s) => meet(s, ty, widen)?,
+                    })
+                }
+            }
+        }
+        Some(match self.result {
+            ParamType::Is(t) => t,
+            ParamType::Same => same.unwrap_or(ColumnType::Text),
+            ParamType::Any => ColumnType::Text,
+        })
+    }
+}
+
+fn meet(a: ColumnType, b: ColumnType, widen: bool) -> Option<ColumnType> {
+    use ColumnType::*;
+    match (a, b) {
+        (a, b) if a == b => Some(a),
+        (Int, Float) | (Float, Int) if widen => Some(Float),
+        _ => None,
+    }
+}
+
+/// Result type of a call with arguments of types `args` under the first of `signatures`
+/// accepting them, preferring exact matches over ones that widen INT to FLOAT.
+pub fn resolve(signatures: &[Signature], args: &[Option<ColumnType>]) -> Option<ColumnType> {
+    [false, true]
+        .into_iter()
+        .find_map(|widen| signatures.iter().find_map(|s| s.accepts(args, widen)))
+}
+
+/// `args` as they are listed in an error about a call that does not exist.
+pub fn type_list(args: &[Option<ColumnType>]) -> String {
+    args.iter()
+        .map(|t| t.map_or("unknown".to_string(), |t| t.to_string()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The type values of types `types` (None where unknown) share as the results of `what`,
+/// such as CASE: INT and FLOAT meet as FLOAT. TEXT if none is known.
+pub fn common_type(what: &str, types: &[Option<ColumnType>]) -> Result<ColumnType> {
+    let mut out: Option<ColumnType> = None;
+    for ty in types.iter().flatten() {
+        out = Some(match out {
+            None => *ty,
+            Some(t) => match meet(t, *ty, true) {
+                Some(t) => t,
+                None => bail!("{} types {} and {} cannot be matched", what, t, ty),
+            },
+        });
+    }
+    Ok(out.unwrap_or(ColumnType::Text))
+}
+
+impl ScalarFunc {
+    /// The function called `name` (lower case), if it is one.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "lower" => ScalarFunc::Lower,
+            "upper" => ScalarFunc::Upper,
+            "substr" | "substring" => ScalarFunc::Substr,
+            "trim" | "btrim" => ScalarFunc::Trim,
+            "ltrim" => ScalarFunc::Ltrim,
+            "rtrim" => ScalarFunc::Rtrim,
+            "replace" => ScalarFunc::Replace,
+            "concat" => ScalarFunc::Concat,
+            "length" | "char_length" | "character_length" => ScalarFunc::Length,
+            "position" => ScalarFunc::Position,
+            "split_part" => ScalarFunc::SplitPart,
+            "abs" => ScalarFunc::Abs,
+            "round" => ScalarFunc::Round,
+            "mod" => ScalarFunc::Mod,
+            "power" | "pow" => ScalarFunc::Power,
+            "coalesce" => ScalarFunc::Coalesce,
+            "nullif" => ScalarFunc::Nullif,
+            "greatest" => ScalarFunc::Greatest,
+            "least" => ScalarFunc::Least,
+            _ => return None,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            ScalarFunc::Lower => "lower",
+            ScalarFunc::Upper => "upper",
+            ScalarFunc::Substr => "substr",
+            ScalarFunc::Trim => "trim",
+            ScalarFunc::Ltrim => "ltrim",
+            ScalarFunc::Rtrim => "rtrim",
+            ScalarFunc::Replace => "replace",
+            ScalarFunc::Concat => "concat",
+            ScalarFunc::Length => "length",
+            ScalarFunc::Position => "position",
+            ScalarFunc::SplitPart => "split_part",
+            ScalarFunc::Abs => "abs",
+            ScalarFunc::Round => "round",
+            ScalarFunc::Mod => "mod",
+            ScalarFunc::Power => "power",
+            ScalarFunc::Coalesce => "coalesce",
+            ScalarFunc::Nullif => "nullif",
+            ScalarFunc::Greatest => "greatest",
+            ScalarFunc::Least => "least",
+            ScalarFunc::Like => "like",
+            ScalarFunc::ILike => "ilike",
+            ScalarFunc::RegexMatch => "regexp_match",
+            ScalarFunc::RegexIMatch => "regexp_imatch",
+            ScalarFunc::User(f) => f.name(),
+            ScalarFunc::Sequence(f) => f.name(),
+        }
+    }
+
+    /// The operator a function is written as, if it is one.
+    pub fn operator(&self) -> Option<&'static str> {
+        match self {
+            ScalarFunc::Like => Some("LIKE"),
+            ScalarFunc::ILike => Some("ILIKE"),
+            ScalarFunc::RegexMatch => Some("~"),
+            ScalarFunc::RegexIMatch => Some("~*"),
+            _ => None,
+        }
+    }
+
+    pub fn signatures(&self) -> &[Signature] {
+        use ColumnType::*;
+        use ParamType::*;
+        match self {
+            ScalarFunc::Lower | ScalarFunc::Upper => &[Signature {
+                params: &[Is(Text)],
+                variadic: false,
+                result: Is(Text),
+            }],
+            ScalarFunc::Substr => &[
+                Signature {
+                    params: &[Is(Text), Is(Int)],
+                    variadic: false,
+                    result: Is(Text),
+                },
+                Signature {
+                    params: &[Is(Text), Is(Int), Is(Int)],
+                    variadic: false,
+                    result: Is(Text),
+                },
+            ],
+            ScalarFunc::Trim | ScalarFunc::Ltrim | ScalarFunc::Rtrim => &[
+                Signature {
+                    params: &[Is(Text)],
+                    variadic: false,
+                    result: Is(Text),
+                },
+                Signature {
+                    params: &[Is(Text), Is(Text)],
+                    variadic: false,
+                    result: Is(Text),
+                },
+            ],
+            ScalarFunc::Replace => &[Signature {
+                params: &[Is(Text), Is(Text), Is(Text)],
+                variadic: false,
+                result: Is(Text),
+            }],
+            ScalarFunc::Concat => &[Signature {
+                params: &[Any],
+                variadic: true,
+                result: Is(Text),
+            }],
+            ScalarFunc::Length => &[Signature {
+                params: &[Is(Text)],
+                variadic: false,
+                result: Is(Int),
+            }],
+            ScalarFunc::Position => &[Signature {
+                params: &[Is(Text), Is(Text)],
+                variadic: false,
+                result: Is(Int),
+            }],
+            ScalarFunc::SplitPart => &[Signature {
+                params: &[Is(Text), Is(Text), Is(Int)],
+                variadic: false,
+                result: Is(Text),
+            }],
+            ScalarFunc::Abs => &[
+                Signature {
+                    params: &[Is(Int)],
+                    variadic: false,
+                    result: Is(Int),
+                },
+                Signature {
+                    params: &[Is(Float)],
+                    variadic: false,
+                    result: Is(Float),
+                },
+            ],
+            ScalarFunc::Round => &[
+                Signature {
+                    params: &[Is(Int)],
+                    variadic: false,
+                    result: Is(Int),
+                },
+                Signature {
+                    params: &[Is(Float)],
+                    variadic: false,
+                    result: Is(Float),
+                },
+                Signature {
+                    params: &[Is(Float), Is(Int)],
+                    variadic: false,
+                    result: Is(Float),
+                },
+            ],
+            ScalarFunc::Mod => &[
+                Signature {
+                    params: &[Is(Int), Is(Int)],
+                    variadic: false,
+                    result: Is(Int),
+                },
+                Signature {
+                    params: &[Is(Float), Is(Float)],
+                    variadic: false,
+                    result: Is(Float),
+                },
+            ],
+            ScalarFunc::Power => &[Signature {
+                params: &[Is(Float), Is(Float)],
+                variadic: false,
+                result: Is(Float),
+            }],
+            ScalarFunc::Coalesce | ScalarFunc::Greatest | ScalarFunc::Least => &[Signature {
+                params: &[Same],
+                variadic: true,
+                result: Same,
+            }],
+            ScalarFunc::Nullif => &[Signature {
+                params: &[Same, Same],
+                variadic: false,
+                result: Same,
+            }],
+            ScalarFunc::Like | ScalarFunc::ILike => &[
+                Signature {
+                    params: &[Is(Text), Is(Text)],
+                    variadic: false,
+                    result: Is(Bool),
+                },
+                Signature {
+                    params: &[Is(Text), Is(Text), Is(Text)],
+                    variadic: false,
+                    result: Is(Bool),
+                },
+            ],
+            ScalarFunc::RegexMatch | ScalarFunc::RegexIMatch => &[Signature {
+                params: &[Is(Text), Is(Text)],
+                variadic: false,
+                result: Is(Bool),
+            }],
+            ScalarFunc::User(f) => f.func().signatures(),
+            ScalarFunc::Sequence(f) => f.signatures(),
+        }
+    }
+
+    /// Result type of a call with arguments of types `args` (None for an untyped NULL or a
+    /// value whose type is not known yet), or an error if no signature accepts them. Exact
+    /// matches win over ones that widen INT to FLOAT.
+    pub fn result_type(&self, args: &[Option<ColumnType>]) -> Result<ColumnType> {
+        if let Some(ty) = resolve(self.signatures(), args) {
+            return Ok(ty);
+        }
+        if self.signatures().iter().all(|s| s.result == ParamType::Same) {
+            common_type(&self.name().to_uppercase(), args)?;
+        }
+        bail!("function {}({}) does not exist", self.name(), type_list(args))
+    }
+
+    /// The parameter type of argument `i` of a call with `n` arguments, in the first form
+    /// taking that many.
+    pub fn param_type(&self, n: usize, i: usize) -> Option<ParamType> {
+        let s = self
+            .signatures()
+            .iter()
+            .find(|s| s.params.len() == n || (s.variadic && n >= s.params.len()))?;
+        s.params.get(i.min(s.params.len() - 1)).copied()
+    }
+
+    /// Whether a NULL argument makes the result NULL.
+    fn strict(&self) -> bool {
+        match self {
+            ScalarFunc::User(f) => f.func().strict(),
+            _ => !matches!(
+                self,
+                ScalarFunc::Concat | ScalarFunc::Coalesce | ScalarFunc::Nullif | ScalarFunc::Greatest | ScalarFunc::Least
+            ),
+        }
+    }
+
+    pub fn call(&self, args: &[Value]) -> Result<Value> {
+        if self.strict() && args.iter().any(Value::is_null) {
+            return Ok(Value::Null);
+        }
+        let text = |i: usize| match &args[i] {
+            Value::Text(s) => Ok(s.as_str()),
+            v => bail!("{}: expected TEXT argument, got {}", self.name(), v),
+        };
+        let int = |i: usize| match &args[i] {
+            Value::Int(n) => Ok(*n),
+            v => bail!("{}: expected INT argument, got {}", self.name(), v),
+        };
+        let float = |i: usize| match args[i].as_f64() {
+            Some(x) => Ok(x),
+            None => bail!("{}: expected FLOAT argument, got {}", self.name(), args[i]),
+        };
+        Ok(match self {
+            ScalarFunc::Lower => Value::Text(text(0)?.to_lowercase()),
+            ScalarFunc::Upper => Value::Text(text(0)?.to_uppercase()),
+            ScalarFunc::Substr => {
+                let count = match args.len() {
+                    3 => Some(int(2)?),
+                    _ => None,
+                };
+                Value::Text(substr(text(0)?, int(1)?, count)?)
+            }
+            ScalarFunc::Trim | ScalarFunc::Ltrim | ScalarFunc::Rtrim => {
+                let chars: Vec<char> = match args.len() {
+                    2 => text(1)?.chars().collect(),
+                    _ => vec![' '],
+                };
+                let s = text(0)?;
+                let s = match self {
+                    ScalarFunc::Ltrim => s.trim_start_matches(chars.as_slice()),
+                    ScalarFunc::Rtrim => s.trim_end_matches(chars.as_slice()),
+                    _ => s.trim_matches(chars.as_slice()),
+                };
+                Value::Text(s.to_string())
+            }
+            ScalarFunc::Replace => match text(1)? {
+                "" => args[0].clone(),
+                from => Value::Text(text(0)?.replace(from, text(2)?)),
+            },
+            ScalarFunc::Concat => Value::Text(args.iter().filter(|v| !v.is_null()).map(|v| v.to_string()).collect()),
+            ScalarFunc::Length => Value::Int(text(0)?.chars().count() as i64),
+            ScalarFunc::Position => {
+                let s = text(1)?;
+                Value::Int(s.find(text(0)?).map_or(0, |at| s[..at].chars().count() as i64 + 1))
+            }
+            ScalarFunc::SplitPart => Value::Text(split_part(text(0)?, text(1)?, int(2)?)?),
+            ScalarFunc::Abs => match &args[0] {
+                Value::Int(n) => match n.checked_abs() {
+                    Some(n) => Value::Int(n),
+                    None => bail!("integer out of range"),
+                },
+                _ => Value::Float(float(0)?.abs()),
+            },
+            ScalarFunc::Round => match (&args[0], args.len()) {
+                (Value::Int(n), 1) => Value::Int(*n),
+                (_, 1) => Value::Float(float(0)?.round()),
+                _ => {
+                    let scale = 10f64.powi(i32::try_from(int(1)?).unwrap_or(i32::MAX).clamp(-308, 308));
+                    Value::Float((float(0)? * scale).round() / scale)
+                }
+            },
+            ScalarFunc::Mod => match (&args[0], &args[1]) {
+                (Value::Int(_), Value::Int(0)) => bail!("division by zero"),
+                (Value::Int(a), Value::Int(b)) => Value::Int(a.checked_rem(*b).unwrap_or(0)),
+                _ if float(1)? == 0.0 => bail!("division by zero"),
+                _ => Value::Float(float(0)? % float(1)?),
+            },
+            ScalarFunc::Power => {
+                let (base, exp) = (float(0)?, float(1)?);
+                if base == 0.0 && exp < 0.0 {
+                    bail!("zero raised to a negative power is undefined");
+                }
+                if base < 0.0 && exp.fract() != 0.0 {
+                    bail!("a negative number raised to a non-integer power yields a complex result");
+                }
+                Value::Float(base.powf(exp))
+            }
+            ScalarFunc::Coalesce => args.iter().find(|v| !v.is_null()).cloned().unwrap_or(Value::Null),
+            ScalarFunc::Nullif => match args[0].sql_cmp(&args[1]) {
+                Some(std::cmp::Ordering::Equal) => Value::Null,
+                _ => args[0].clone(),
+            },
+            ScalarFunc::Greatest | ScalarFunc::Least => {
+                let mut best: Option<&Value> = None;
+                for v in args.iter().filter(|v| !v.is_null()) {
+                    let Some(b) = best else {
+                        best = Some(v);
+                        continue;
+                    };
+                    let Some(ord) = v.sql_cmp(b) else {
+                        bail!("{} types {} and {} cannot be matched", self.name(), v, b);
+                    };
+                    let better = match self {
+                        ScalarFunc::Greatest => std::cmp::Ordering::Greater,
+                        _ => std::cmp::Ordering::Less,
+                    };
+                    if ord == better {
+                        best = Some(v);
+                    }
+                }
+                best.cloned().unwrap_or(Value::Null)
+            }
+            ScalarFunc::Like | ScalarFunc::ILike => {
+                let escape = match args.len() {
+                    3 => {
+                        let mut chars = text(2)?.chars();
+                        match (chars.next(), chars.next()) {
+                            (c, None) => c,
+                            _ => bail!("invalid escape string: must be empty or one character"),
+                        }
+                    }
+                    _ => Some('\\'),
+                };
+                let (s, p) = (text(0)?, text(1)?);
+                let matched = if *self == ScalarFunc::ILike {
+                    like(&s.to_lowercase(), &p.to_lowercase(), escape)?
+                } else {
+                    like(s, p, escape)?
+                };
+                Value::Bool(matched)
+            }
+            ScalarFunc::RegexMatch | ScalarFunc::RegexIMatch => {
+                Value::Bool(regex_match(text(0)?, text(1)?, *self == ScalarFunc::RegexIMatch)?)
+            }
+            ScalarFunc::User(f) => f.func().call(args)?,
+            ScalarFunc::Sequence(f) => f.call(args)?,
+        })
+    }
+}
+
+/// Characters `start..start + count` of `s`, counting from 1; positions before the first
+/// character count towards `count` but produce nothing.
+fn substr(s: &str, start: i64, count: Option<i64>) -> Result<String> {
+    let end = match count {
+        Some(n) if n < 0 => bail!("negative substring length not allowed"),
+        Some(n) => start.saturating_add(n),
+        None => i64::MAX,
+    };
+    let first = start.max(1);
+    if end <= first {
+        return Ok(String::new());
+    }
+    Ok(s.chars().skip((first - 1) as usize).take((end - first) as usize).collect())
+}
+
+/// Field `n` of `s` split on `delimiter`, counting from 1, or from the end if negative;
+/// empty past the last field.
+fn split_part(s: &str, delimiter: &str, n: i64) -> Result<String> {
+    if n == 0 {
+        bail!("field position must not be zero");
+    }
+    let fields: Vec<&str> = if delimiter.is_empty() {
+        vec![s]
+    } else {
+        s.split(delimiter).collect()
+    };
+    let i = if n > 0 {
+        usize::try_from(n - 1).ok()
+    } else {
+        fields.len().checked_sub(n.unsigned_abs() as usize)
+    };
+    Ok(i.and_then(|i| fields.get(i)).map_or(String::new(), |f| f.to_string()))
+}
+
+/// SQL LIKE: `%` matches any run of characters, `_` any one character, and `escape`
+/// makes the character after it literal.
+fn like(s: &str, pattern: &str, escape: Option<char>) -> Result<bool> {
+    enum Token {
+        Literal(char),
+        One,
+        Many,
+    }
+    let mut tokens = Vec::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            c if Some(c) == escape => match chars.next() {
+                Some(c) => Token::Literal(c),
+                None => bail!("LIKE pattern must not end with escape character"),
+            },
+            '%' => Token::Many,
+            '_' => Token::One,
+            c => Token::Literal(c),
+        });
+    }
+    // Greedy matching that backtracks to the last `%`.
+    let s: Vec<char> = s.chars().collect();
+    let (mut si, mut ti) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while si < s.len() {
+        match tokens.get(ti) {
+            Some(Token::Many) => {
+                backtrack = Some((ti, si));
+                ti += 1;
+                continue;
+            }
+            Some(Token::One) => {
+                si += 1;
+                ti += 1;
+                continue;
+            }
+            Some(Token::Literal(c)) if *c == s[si] => {
+                si += 1;
+                ti += 1;
+                continue;
+            }
+            _ => {}
+        }
+        let Some((t, at)) = backtrack else {
+            return Ok(false);
+        };
+        backtrack = Some((t, at + 1));
+        ti = t + 1;
+        si = at + 1;
+    }
+    Ok(tokens[ti..].iter().all(|t| matches!(t, Token::Many)))
+}
+
+thread_local! {
+    /// The last pattern compiled on this thread, which is usually the next one needed.
+    static LAST_REGEX: RefCell<Option<(String, bool, Regex)>> = const { RefCell::new(None) };
+}
+
+/// Whether `pattern` (a regular expression) matches anywhere in `s`.
+fn regex_match(s: &str, pattern: &str, case_insensitive: bool) -> Result<bool> {
+    LAST_REGEX.with(|last| {
+        let mut last = last.borrow_mut();
+        match &*last {
+            Some((p, ci, re)) if p == pattern && *ci == case_insensitive => return Ok(re.is_match(s)),
+            _ => {}
+        }
+        let re = Regex::builder()
+            .syntax(syntax::Config::new().case_insensitive(case_insensitive))
+            .build(pattern)
+            .map_err(|e| anyhow::anyhow!("invalid regular expression: {}", e))?;
+        let matched = re.is_match(s);
+        *last = Some((pattern.to_string(), case_insensitive, re));
+        Ok(matched)
+    })
+}
+
+/// Check that a value of type `from` (None for an untyped NULL) can be cast to `to`.
+pub fn cast_type(from: Option<ColumnType>, to: ColumnType) -> Result<()> {
+    use ColumnType::*;
+    match (from, to) {
+        (None, _) | (Some(Text), _) | (Some(_), Text) => Ok(()),
+        (Some(Int | Float), Int | Float) | (Some(Int | Bool), Int | Bool) => Ok(()),
+        (Some(from), to) => bail!("cannot cast type {} to {}", from, to),
+    }
+}
+
+/// `CAST(v AS ty)`.
+pub fn cast(v: Value, ty: ColumnType) -> Result<Value> {
+    let invalid = |s: &str| anyhow::anyhow!("invalid input syntax for type {}: \"{}\"", ty, s);
+    Ok(match (v, ty) {
+        (Value::Null, _) => Value::Null,
+        (v, ty) if v.column_type() == Some(ty) => v,
+        (v, ColumnType::Text) => Value::Text(v.to_string()),
+        (Value::Int(n), ColumnType::Float) => Value::Float(n as f64),
+        (Value::Float(x), ColumnType::Int) => {
+            let r = x.round();
+            if !(i64::MIN as f64..i64::MAX as f64).contains(&r) {
+                bail!("integer out of range");
+            }
+            Value::Int(r as i64)
+        }
+        (Value::Int(n), ColumnType::Bool) => Value::Bool(n != 0),
+        (Value::Bool(b), ColumnType::Int) => Value::Int(b as i64),
+        (Value::Text(s), ColumnType::Int) => Value::Int(s.trim().parse().map_err(|_| invalid(&s))?),
+        (Value::Text(s), ColumnType::Float) => Value::Float(s.trim().parse().map_err(|_| invalid(&s))?),
+        (Value::Text(s), ColumnType::Bool) => match s.trim().to_ascii_lowercase().as_str() {
+            "t" | "true" | "yes" | "y" | "on" | "1" => Value::Bool(true),
+            "f" | "false" | "no" | "n" | "off" | "0" => Value::Bool(false),
+            _ => return Err(invalid(&s)),
+        },
+        (v, ty) => bail!("cannot cast {} to {}", v, ty),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.into())
+    }
+
+    #[test]
+    fn signatures_resolve_with_widening() {
+        use ColumnType::*;
+        assert_eq!(ScalarFunc::Abs.result_type(&[Some(Int)]).unwrap(), Int);
+        assert_eq!(ScalarFunc::Round.result_type(&[Some(Int), Some(Int)]).unwrap(), Float);
+        assert_eq!(ScalarFunc::Coalesce.result_type(&[None, Some(Int), Some(Float)]).unwrap(), Float);
+        assert_eq!(ScalarFunc::Concat.result_type(&[Some(Int), Some(Bool)]).unwrap(), Text);
+        assert_eq!(ScalarFunc::Power.result_type(&[Some(Int), Some(Int)]).unwrap(), Float);
+        let err = ScalarFunc::Lower.result_type(&[Some(Int)]).unwrap_err();
+        assert_eq!(err.to_string(), "function lower(INT) does not exist");
+        assert!(ScalarFunc::Greatest.result_type(&[Some(Int), Some(Text)]).is_err());
+        assert!(ScalarFunc::Substr.result_type(&[Some(Text)]).is_err());
+        assert_eq!(ScalarFunc::SplitPart.param_type(3, 2), Some(ParamType::Is(Int)));
+        assert!(cast_type(Some(Float), Bool).is_err());
+        assert_eq!(common_type("CASE", &[Some(Int), None, Some(Float)]).unwrap(), Float);
+        let err = common_type("CASE", &[Some(Int), Some(Text)]).unwrap_err();
+        assert_eq!(err.to_string(), "CASE types INT and TEXT cannot be matched");
+    }
+
+    #[test]
+    fn string_functions() {
+        let call = |f: ScalarFunc, args: &[Value]| f.call(args).unwrap();
+        assert_eq!(call(ScalarFunc::Substr, &[text("héllo"), Value::Int(2), Value::Int(3)]), text("éll"));
+        assert_eq!(call(ScalarFunc::Substr, &[text("hello"), Value::Int(0), Value::Int(2)]), text("h"));
+        assert_eq!(call(ScalarFunc::Trim, &[text("xxaxx"), text("x")]), text("a"));
+        assert_eq!(call(ScalarFunc::Position, &[text("l"), text("héllo")]), Value::Int(3));
+        assert_eq!(call(ScalarFunc::SplitPart, &[text("a,b,c"), text(","), Value::Int(-1)]), text("c"));
+        assert_eq!(call(ScalarFunc::SplitPart, &[text("a,b,c"), text(","), Value::Int(5)]), text(""));
+        assert_eq!(call(ScalarFunc::Concat, &[text("a"), Value::Null, Value::Int(1)]), text("a1"));
+        assert_eq!(call(ScalarFunc::Upper, &[Value::Null]), Value::Null);
+
+        let like = |s: &str, p: &str| call(ScalarFunc::Like, &[text(s), text(p)]);
+        assert_eq!(like("abcabc", "%bc"), Value::Bool(true));
+        assert_eq!(like("abc", "a_c"), Value::Bool(true));
+        assert_eq!(like("abc", "a%d"), Value::Bool(false));
+        assert_eq!(like("50%", "50\\%"), Value::Bool(true));
+        assert_eq!(like("500", "50\\%"), Value::Bool(false));
+        assert_eq!(call(ScalarFunc::ILike, &[text("ABC"), text("a%")]), Value::Bool(true));
+        assert_eq!(call(ScalarFunc::RegexMatch, &[text("abc123"), text("[0-9]+$")]), Value::Bool(true));
+        assert_eq!(call(ScalarFunc::RegexIMatch, &[text("ABC"), text("^abc$")]), Value::Bool(true));
+        assert!(ScalarFunc::RegexMatch.call(&[text("a"), text("(")]).is_err());
+    }
+
+    #[test]
+    fn math_conditionals_and_casts() {
+        let call = |f: ScalarFunc, args: &[Value]| f.call(args).unwrap();
+        assert_eq!(call(ScalarFunc::Round, &[Value::Float(2.346), Value::Int(2)]), Value::Float(2.35));
+        assert_eq!(call(ScalarFunc::Round, &[Value::Float(-2.5)]), Value::Float(-3.0));
+        assert_eq!(call(ScalarFunc::Mod, &[Value::Int(-7), Value::Int(3)]), Value::Int(-1));
+        assert!(ScalarFunc::Mod.call(&[Value::Int(1), Value::Int(0)]).is_err());
+        assert_eq!(call(ScalarFunc::Power, &[Value::Int(2), Value::Int(10)]), Value::Float(1024.0));
+        assert_eq!(call(ScalarFunc::Greatest, &[Value::Int(1), Value::Null, Value::Float(2.5)]), Value::Float(2.5));
+        assert_eq!(call(ScalarFunc::Nullif, &[Value::Int(1), Value::Int(1)]), Value::Null);
+        assert_eq!(call(ScalarFunc::Coalesce, &[Value::Null, text("x")]), text("x"));
+
+        assert_eq!(cast(text(" 42 "), ColumnType::Int).unwrap(), Value::Int(42));
+        assert_eq!(cast(Value::Float(2.5), ColumnType::Int).unwrap(), Value::Int(3));
+        assert_eq!(cast(Value::Bool(true), ColumnType::Text).unwrap(), text("true"));
+        assert_eq!(cast(text("off"), ColumnType::Bool).unwrap(), Value::Bool(false));
+        let err = cast(text("4x"), ColumnType::Int).unwrap_err();
+        assert_eq!(err.to_string(), "invalid input syntax for type INT: \"4x\"");
+    }
+}
@@ -0,0 +1,196 @@
+//! Limits on a running statement: cancellation, `statement_timeout` and a memory budget.
+//!
+//! Each statement runs under one `Limits`, shared by its operators (and parallel workers)
+//! through `ExecContext`. Operators call `check` where they may run for long without
+//! producing a row (per page read, per outer row of a nested loop, ...), and take a
+//! `Reservation` for the rows and groups they hold in memory. Either fails with a
+//! `StatementError`. Operators that can spill to disk keep within `Reservation::budget`
+//! instead, so only those that cannot run out of memory.
+
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Why a statement was stopped (match via `anyhow::Error::downcast_ref`).
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum StatementError {
+    #[error("canceling statement due to user request")]
+    Cancelled,
+    #[error("canceling statement due to statement timeout of {timeout_ms} ms")]
+    Timeout { timeout_ms: u64 },
+    #[error("out of memory: statement needs more than its limit of {limit_kb} KB")]
+    OutOfMemory { limit_kb: usize },
+}
+
+/// Cancels the running statement of a session, from any thread. A cancel that arrives
+/// while no statement runs is forgotten when the next one starts.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Forget an earlier cancel; called as a statement starts.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+/// What one statement is checked against while it runs.
+#[derive(Debug, Default)]
+pub struct Limits {
+    cancel: CancelToken,
+    deadline: Option<(Instant, Duration)>,
+    /// Bytes operators may hold in memory; 0 for no limit.
+    memory_limit: usize,
+    memory_used: AtomicUsize,
+}
+
+impl Limits {
+    /// Limits for a statement starting now: `timeout` (if any) from now and at most
+    /// `memory_limit` bytes (0 for no limit).
+    pub fn new(cancel: CancelToken, timeout: Option<Duration>, memory_limit: usize) -> Arc<Self> {
+        Arc::new(Self {
+            cancel,
+            deadline: timeout.map(|t| (Instant::now() + t, t)),
+            memory_limit,
+            memory_used: AtomicUsize::new(0),
+        })
+    }
+
+    /// No limits, for internal statements.
+    pub fn none() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Fail if the statement was cancelled or has run past its timeout.
+    pub fn check(&self) -> Result<()> {
+        if self.cancel.is_cancelled() {
+            return Err(StatementError::Cancelled.into());
+        }
+        if let Some((deadline, timeout)) = self.deadline {
+            if Instant::now() >= deadline {
+                let timeout_ms = timeout.as_millis() as u64;
+                return Err(StatementError::Timeout { timeout_ms }.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// An empty reservation against the memory budget.
+    pub fn reservation(self: &Arc<Self>) -> Reservation {
+        Reservation {
+            limits: Arc::clone(self),
+            bytes: 0,
+        }
+    }
+
+    /// Bytes reserved by the statement's operators right now.
+    pub fn memory_used(&self) -> usize {
+        self.memory_used.load(Ordering::Relaxed)
+    }
+}
+
+/// Memory an operator holds, counted against its statement's budget until dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    limits: Arc<Limits>,
+    bytes: usize,
+}
+
+impl Reservation {
+    /// Count `bytes` more, or fail if that would exceed the budget.
+    pub fn grow(&mut self, bytes: usize) -> Result<()> {
+        let used = self.limits.memory_used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        let limit = self.limits.memory_limit;
+        if limit > 0 && used > limit {
+            self.limits.memory_used.fetch_sub(bytes, Ordering::Relaxed);
+            return Err(StatementError::OutOfMemory { limit_kb: limit / 1024 }.into());
+        }
+        self.bytes += bytes;
+        Ok(())
+    }
+
+    /// Bytes a spilling operator may hold under this reservation before it spills:
+    /// `work_mem`, or less if that is more than the statement has left.
+    pub fn budget(&self, work_mem: usize) -> usize {
+        let limit = self.limits.memory_limit;
+        if limit == 0 {
+            return work_mem;
+        }
+        let free = limit.saturating_sub(self.limits.memory_used());
+        work_mem.min(self.bytes + free)
+    }
+
+    /// Count `bytes` in all, growing (or failing as `grow` does) or shrinking to fit.
+    pub fn resize(&mut self, bytes: usize) -> Result<()> {
+        if bytes > self.bytes {
+            return self.grow(bytes - self.bytes);
+        }
+        self.limits.memory_used.fetch_sub(self.bytes - bytes, Ordering::Relaxed);
+        self.bytes = bytes;
+        Ok(())
+    }
+
+    /// Give back everything reserved, say after spilling it to disk.
+    pub fn clear(&mut self) {
+        self.limits.memory_used.fetch_sub(self.bytes, Ordering::Relaxed);
+        self.bytes = 0;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_cancel_timeout_and_memory() {
+        let token = CancelToken::default();
+        let limits = Limits::new(token.clone(), None, 1000);
+        limits.check().unwrap();
+        token.cancel();
+        let err = limits.check().unwrap_err();
+        assert_eq!(err.downcast_ref::<StatementError>(), Some(&StatementError::Cancelled));
+        token.reset();
+        limits.check().unwrap();
+
+        let limits = Limits::new(token, Some(Duration::ZERO), 0);
+        let err = limits.check().unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&StatementError::Timeout { timeout_ms: 0 }));
+
+        let limits = Limits::new(CancelToken::default(), None, 2048);
+        let mut a = limits.reservation();
+        a.grow(1500).unwrap();
+        let mut b = limits.reservation();
+        let err = b.grow(1000).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&StatementError::OutOfMemory { limit_kb: 2 }));
+        drop(a);
+        b.grow(1000).unwrap();
+        assert_eq!(limits.memory_used(), 1000);
+        b.resize(400).unwrap();
+        assert_eq!(limits.memory_used(), 400);
+        assert!(b.resize(4000).is_err());
+        assert_eq!(b.budget(4096), 2048);
+        assert_eq!(b.budget(1024), 1024);
+        let mut c = limits.reservation();
+        c.grow(1000).unwrap();
+        assert_eq!(b.budget(4096), 1048);
+        assert_eq!(Limits::none().reservation().budget(4096), 4096);
+        b.clear();
+        drop(c);
+        assert_eq!(limits.memory_used(), 0);
+    }
+}
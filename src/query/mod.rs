@@ -1,2 +1,59 @@
 //! Query layer: parser, planner, executor.
-//! SQL → AST → logical plan → row-by-row execution.
+//! SQL → AST → logical plan → row-by-row (or batched, see `vector`) execution, parts of it
+//! in parallel worker threads (see `parallel`).
+
+pub mod aggregate;
+pub mod command;
+pub mod constraint;
+pub mod ddl;
+pub mod exec;
+pub mod explain;
+pub mod expr;
+pub mod function;
+pub mod limits;
+pub mod optimizer;
+pub mod parallel;
+pub mod plan;
+pub mod plan_cache;
+pub mod planner;
+pub mod spill;
+pub mod stats;
+pub mod system;
+pub mod table_fn;
+pub mod trigger;
+pub mod udf;
+pub mod vector;
+pub mod view;
+pub mod window;
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{ColumnType, Value};
+
+/// Outcome of one statement: a result set (possibly empty) and a command tag
+/// such as `SELECT 3` or `VACUUM`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueryResult {
+    pub columns: Vec<(String, ColumnType)>,
+    pub rows: Vec<Vec<Value>>,
+    pub tag: String,
+}
+
+impl QueryResult {
+    /// A statement that returns no rows.
+    pub fn command(tag: impl Into<String>) -> Self {
+        Self {
+            columns: Vec::new(),
+            rows: Vec::new(),
+            tag: tag.into(),
+        }
+    }
+}
+
+/// What a prepared statement takes and returns: its parameter types, in order, and its
+/// result columns (none for statements that return no rows).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatementDescription {
+    pub param_types: Vec<ColumnType>,
+    pub columns: Vec<(String, ColumnType)>,
+}
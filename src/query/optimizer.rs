@@ -0,0 +1,1032 @@
+//! Cost-based choice of access paths, join order and join algorithms.
+//!
+//! Costs are in units of one sequential page read, as in PostgreSQL: a random page read
+//! costs `RANDOM_PAGE_COST`, processing a row `CPU_TUPLE_COST`, and so on. Row estimates
+//! come from ANALYZE statistics (`stats`) when present, otherwise from the number of row
+//! versions in the table and fixed default selectivities.
+//!
+//! Join orders are enumerated bottom-up over subsets of the FROM relations (dynamic
+//! programming, bushy trees, cross products only where a subset has no connecting join
+//! predicate). Past `DP_MAX_RELATIONS` relations a greedy left-deep search is used instead.
+//! Only inner joins are reordered; outer, semi and anti joins are planned as written
+//! (`JoinPlanner::plan_join`) and enter the enumeration as single relations.
+
+use anyhow::{bail, Result};
+use std::collections::BTreeSet;
+
+use crate::catalog::{ColumnStats, TableStats};
+use crate::query::expr::{BinaryOp, Expr};
+use crate::query::plan::{IndexBounds, JoinType, Node, Plan, SortKey};
+use crate::query::stats::{num_groups, selectivity};
+use crate::storage::{ColumnType, Value, PAGE_SIZE};
+
+pub const SEQ_PAGE_COST: f64 = 1.0;
+pub const RANDOM_PAGE_COST: f64 = 4.0;
+pub const CPU_TUPLE_COST: f64 = 0.01;
+pub const CPU_INDEX_TUPLE_COST: f64 = 0.005;
+pub const CPU_OPERATOR_COST: f64 = 0.0025;
+
+/// Largest join the exhaustive search handles; it visits 3^n subset splits.
+pub const DP_MAX_RELATIONS: usize = 12;
+
+/// Bytes a row of `n` columns is assumed to take in memory, for sizing hash tables.
+pub fn row_width(columns: usize) -> f64 {
+    32.0 + 24.0 * columns as f64
+}
+
+/// Cost of sorting `rows` rows in memory.
+pub fn sort_cost(rows: f64) -> f64 {
+    let n = rows.max(2.0);
+    2.0 * CPU_OPERATOR_COST * n * n.log2()
+}
+
+/// Cost of keeping the first `limit` of `rows` rows in a bounded heap.
+pub fn top_n_cost(rows: f64, limit: f64) -> f64 {
+    2.0 * CPU_OPERATOR_COST * rows.max(2.0) * (2.0 * limit).max(2.0).log2()
+}
+
+/// Extra cost of a sort whose `rows` rows of `columns` columns outgrow `work_mem` bytes:
+/// every row is written to a run and read back once.
+pub fn sort_spill_cost(rows: f64, columns: usize, work_mem: f64) -> f64 {
+    let bytes = rows * row_width(columns);
+    if bytes > work_mem {
+        2.0 * SEQ_PAGE_COST * bytes / PAGE_SIZE as f64
+    } else {
+        0.0
+    }
+}
+
+/// Where a FROM relation's rows come from.
+#[derive(Debug, Clone)]
+pub enum Source {
+    Table {
+        table: String,
+        key_col: usize,
+        /// Current size estimates (see `planner` for how they are derived).
+        rows: f64,
+        pages: f64,
+        stats: Option<TableStats>,
+    },
+    /// Anything else, already planned: table functions, `VALUES`, joins that cannot be
+    /// reordered.
+    Plan(Box<Planned>),
+}
+
+/// One FROM relation. A table's columns have global ids `offset..offset + columns.len()`;
+/// a planned source has those of its layout.
+#[derive(Debug, Clone)]
+pub struct Relation {
+    pub source: Source,
+    pub columns: Vec<(String, ColumnType)>,
+    pub offset: usize,
+}
+
+impl Relation {
+    fn column_ids(&self) -> Vec<usize> {
+        match &self.source {
+            Source::Table { .. } => (self.offset..self.offset + self.columns.len()).collect(),
+            Source::Plan(p) => p.layout.clone(),
+        }
+    }
+
+    pub fn has_column(&self, gid: usize) -> bool {
+        match &self.source {
+            Source::Table { .. } => (self.offset..self.offset + self.columns.len()).contains(&gid),
+            Source::Plan(p) => p.layout.contains(&gid),
+        }
+    }
+
+    /// Statistics of column `gid`. Without ANALYZE data the key column is still known to
+    /// be unique.
+    fn column_stats(&self, gid: usize) -> Option<ColumnStats> {
+        match &self.source {
+            Source::Table { stats: Some(s), .. } => s.columns.get(gid - self.offset).cloned(),
+            Source::Table { key_col, rows, .. } if gid - self.offset == *key_col => Some(ColumnStats {
+                n_distinct: rows.max(1.0),
+                ..Default::default()
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// A plan together with the global column id of each of its output columns.
+#[derive(Debug, Clone)]
+pub struct Planned {
+    pub plan: Plan,
+    pub layout: Vec<usize>,
+    /// Global id of a column the output is known to be sorted on (ascending).
+    pub sorted_on: Option<usize>,
+}
+
+impl Planned {
+    /// Rewrite an expression over global column ids into one over this plan's output.
+    pub fn remap(&self, e: &Expr) -> Expr {
+        remap(e, &self.layout)
+    }
+}
+
+/// Rewrite an expression over global column ids into one over rows with `layout`.
+pub fn remap(e: &Expr, layout: &[usize]) -> Expr {
+    e.map_columns(&|g| {
+        layout
+            .iter()
+            .position(|l| *l == g)
+            .expect("column of a relation outside the plan")
+    })
+}
+
+fn popcount(mask: u64) -> u32 {
+    mask.count_ones()
+}
+
+/// The join planning problem for one query block.
+pub struct JoinPlanner<'a> {
+    rels: &'a [Relation],
+    /// Conjuncts of WHERE and ON, over global column ids, with the relations they touch.
+    conjuncts: Vec<(Expr, u64)>,
+    /// Estimated selectivity of each conjunct.
+    sel: Vec<f64>,
+    /// Memory a hash table may use before the join spills, in bytes.
+    work_mem: f64,
+}
+
+/// How two inputs are joined, before the plan is built.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Method {
+    NestedLoop,
+    Hash,
+    /// Right side is the single relation with this index, looked up by key.
+    IndexNestedLoop(usize),
+    Merge,
+}
+
+impl<'a> JoinPlanner<'a> {
+    pub fn new(rels: &'a [Relation], conjuncts: Vec<Expr>) -> Self {
+        let conjuncts = conjuncts
+            .into_iter()
+            .map(|c| {
+                let mut mask = 0u64;
+                for gid in c.columns() {
+                    if let Some(r) = rels.iter().position(|r| r.has_column(gid)) {
+                        mask |= 1 << r;
+                    }
+                }
+                (c, mask)
+            })
+            .collect();
+        let mut planner = Self {
+            rels,
+            conjuncts,
+            sel: Vec::new(),
+            work_mem: 4096.0 * 1024.0,
+        };
+        planner.sel = planner.conjuncts.iter().map(|(c, _)| planner.selectivity(c)).collect();
+        planner
+    }
+
+    /// Size hash joins for `bytes` of working memory.
+    pub fn with_work_mem(mut self, bytes: usize) -> Self {
+        self.work_mem = bytes as f64;
+        self
+    }
+
+    fn column_stats(&self, gid: usize) -> Option<ColumnStats> {
+        let r = self.rels.iter().find(|r| r.has_column(gid))?;
+        r.column_stats(gid)
+    }
+
+    fn selectivity(&self, e: &Expr) -> f64 {
+        selectivity(e, &|gid| self.column_stats(gid))
+    }
+
+    /// Estimated number of groups `keys` (over global ids) form among `rows` joined rows.
+    pub fn estimate_groups(&self, keys: &[Expr], rows: f64) -> f64 {
+        num_groups(keys, rows, &|gid| self.column_stats(gid))
+    }
+
+    /// Conjuncts evaluated at the scan of relation `r`. Constant conjuncts go to the first.
+    fn local_conjuncts(&self, r: usize) -> Vec<&Expr> {
+        self.conjuncts
+            .iter()
+            .filter(|(_, m)| *m == 1 << r || (*m == 0 && r == 0))
+            .map(|(c, _)| c)
+            .collect()
+    }
+
+    /// Conjuncts that first become evaluable when `left` and `right` are joined.
+    fn join_conjuncts(&self, left: u64, right: u64) -> Vec<&Expr> {
+        let both = left | right;
+        self.conjuncts
+            .iter()
+            .filter(|(_, m)| *m & !both == 0 && *m & left != 0 && *m & right != 0)
+            .map(|(c, _)| c)
+            .collect()
+    }
+
+    fn connected(&self, left: u64, right: u64) -> bool {
+        !self.join_conjuncts(left, right).is_empty()
+    }
+
+    fn base_rows(&self, r: usize) -> f64 {
+        match &self.rels[r].source {
+            Source::Table { rows, .. } => *rows,
+            Source::Plan(p) => p.plan.est_rows,
+        }
+    }
+
+    /// Estimated rows of joining the relations in `mask` with every applicable predicate;
+    /// independent of join order.
+    fn rows(&self, mask: u64) -> f64 {
+        let mut rows = 1.0;
+        for r in 0..self.rels.len() {
+            if mask & (1 << r) != 0 {
+                rows *= self.base_rows(r);
+            }
+        }
+        for ((_, m), sel) in self.conjuncts.iter().zip(&self.sel) {
+            if *m & !mask == 0 && (*m != 0 || mask & 1 != 0) {
+                rows *= sel;
+            }
+        }
+        rows.max(1.0)
+    }
+
+    /// Cheapest way to read relation `r`, with its local conjuncts applied.
+    pub fn access_path(&self, r: usize) -> Planned {
+        let rel = &self.rels[r];
+        let layout = rel.column_ids();
+        let local = self.local_conjuncts(r);
+        let est_rows = self.rows(1 << r);
+        let local_pos: Vec<Expr> = local.iter().map(|c| remap(c, &layout)).collect();
+        let plan = match &rel.source {
+            Source::Plan(p) => {
+                let sorted_on = p.sorted_on;
+                let p = p.plan.clone();
+                let plan = match Expr::and_all(local_pos) {
+                    None => p,
+                    Some(predicate) => Plan {
+                        columns: p.columns.clone(),
+                        est_cost: p.est_cost + p.est_rows * CPU_OPERATOR_COST * local.len() as f64,
+                        est_rows,
+                        node: Node::Filter { predicate },
+                        children: vec![p],
+                    },
+                };
+                return Planned { plan, layout, sorted_on };
+            }
+            Source::Table { table, rows, pages, .. } => {
+                let seq_cost = pages * SEQ_PAGE_COST
+                    + rows * CPU_TUPLE_COST
+                    + rows * CPU_OPERATOR_COST * local.len() as f64;
+                Plan {
+                    node: Node::SeqScan {
+                        table: table.clone(),
+                        filter: Expr::and_all(local_pos),
+                    },
+                    children: vec![],
+                    columns: rel.columns.clone(),
+                    est_rows,
+                    est_cost: seq_cost,
+                }
+            }
+        };
+        match self.index_path(r, false) {
+            Some(index) if index.plan.est_cost < plan.est_cost => index,
+            _ => Planned {
+                plan,
+                layout,
+                sorted_on: None,
+            },
+        }
+    }
+
+    /// Relation `r`, a table, read in key order through its primary-key B-tree with its
+    /// local conjuncts applied. Unless `full`, only if some conjunct bounds the key.
+    pub fn index_path(&self, r: usize, full: bool) -> Option<Planned> {
+        let rel = &self.rels[r];
+        let Source::Table {
+            table,
+            key_col,
+            rows,
+            pages,
+            ..
+        } = &rel.source
+        else {
+            return None;
+        };
+        let layout = rel.column_ids();
+        let local_pos: Vec<Expr> = self.local_conjuncts(r).iter().map(|c| remap(c, &layout)).collect();
+        let (bounds, used, matched) = match self.index_bounds(rel, *key_col, &local_pos) {
+            Some(b) => b,
+            None if full => (IndexBounds::Range { low: None, high: None }, BTreeSet::new(), *rows),
+            None => return None,
+        };
+        let rest: Vec<Expr> = local_pos
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !used.contains(i))
+            .map(|(_, c)| c.clone())
+            .collect();
+        let matched = matched.min(*rows);
+        let cost = RANDOM_PAGE_COST
+            + matched * (CPU_INDEX_TUPLE_COST + CPU_TUPLE_COST)
+            + matched.min(*pages) * RANDOM_PAGE_COST
+            + matched * CPU_OPERATOR_COST * rest.len() as f64;
+        let plan = Plan {
+            node: Node::IndexScan {
+                table: table.clone(),
+                bounds,
+                filter: Expr::and_all(rest),
+            },
+            children: vec![],
+            columns: rel.columns.clone(),
+            est_rows: self.rows(1 << r),
+            est_cost: cost,
+        };
+        Some(Planned {
+            plan,
+            layout,
+            sorted_on: Some(rel.offset + key_col),
+        })
+    }
+
+    /// Index bounds on the key column usable from `conjuncts` (over table positions): the
+    /// bounds, which conjuncts they replace, and how many index entries they match.
+    fn index_bounds(
+        &self,
+        rel: &Relation,
+        key_col: usize,
+        conjuncts: &[Expr],
+    ) -> Option<(IndexBounds, BTreeSet<usize>, f64)> {
+        let is_key = |e: &Expr| matches!(e, Expr::Column(c) if *c == key_col);
+        let key_stats = rel.column_stats(rel.offset + key_col);
+        let key_sel = |e: &Expr| selectivity(e, &|c| (c == key_col).then(|| key_stats.clone()).flatten());
+        let rows = match &rel.source {
+            Source::Table { rows, .. } => *rows,
+            Source::Plan(_) => return None,
+        };
+        // An equality or IN list pins the keys; take the first.
+        for (i, c) in conjuncts.iter().enumerate() {
+            match c {
+                Expr::Binary { op: BinaryOp::Eq, left, right } => {
+                    let v = if is_key(left) && right.is_constant() {
+                        right
+                    } else if is_key(right) && left.is_constant() {
+                        left
+                    } else {
+                        continue;
+                    };
+                    return Some((IndexBounds::Keys(vec![(**v).clone()]), BTreeSet::from([i]), 1.0));
+                }
+                Expr::InList { expr, list, negated: false }
+                    if is_key(expr) && list.iter().all(Expr::is_constant) =>
+                {
+                    let n = list.len() as f64;
+                    return Some((IndexBounds::Keys(list.clone()), BTreeSet::from([i]), n));
+                }
+                _ => {}
+            }
+        }
+        let mut low = None;
+        let mut high = None;
+        let mut used = BTreeSet::new();
+        for (i, c) in conjuncts.iter().enumerate() {
+            match c {
+                Expr::Binary { op, left, right } if op.is_comparison() && *op != BinaryOp::NotEq => {
+                    let (op, bound) = if is_key(left) && right.is_constant() {
+                        (*op, right)
+                    } else if is_key(right) && left.is_constant() {
+                        (op.flip(), left)
+                    } else {
+                        continue;
+                    };
+                    let slot = match op {
+                        BinaryOp::Gt | BinaryOp::GtEq => &mut low,
+                        _ => &mut high,
+                    };
+                    if slot.is_none() {
+                        *slot = Some(((**bound).clone(), matches!(op, BinaryOp::GtEq | BinaryOp::LtEq)));
+                        used.insert(i);
+                    }
+                }
+                Expr::Between {
+                    expr,
+                    low: lo,
+                    high: hi,
+                    negated: false,
+                } if is_key(expr) && lo.is_constant() && hi.is_constant() && low.is_none() && high.is_none() => {
+                    low = Some(((**lo).clone(), true));
+                    high = Some(((**hi).clone(), true));
+                    used.insert(i);
+                }
+                _ => {}
+            }
+        }
+        if used.is_empty() {
+            return None;
+        }
+        let sel = key_sel(&Expr::and_all(used.iter().map(|i| conjuncts[*i].clone()).collect())?);
+        Some((IndexBounds::Range { low, high }, used, rows * sel))
+    }
+
+    /// Cost of joining `left` and `right` with `method` on `conj`, producing `out` rows, or
+    /// None if the method does not apply.
+    fn join_cost(
+        &self,
+        method: Method,
+        left: &Planned,
+        right: &Planned,
+        conj: &[&Expr],
+        join_type: JoinType,
+        out: f64,
+    ) -> Option<f64> {
+        let (l, r) = (&left.plan, &right.plan);
+        let keys = self.hash_keys(conj, left, right).0;
+        let cost = match method {
+            Method::NestedLoop => {
+                l.est_cost
+                    + r.est_cost
+                    + r.est_rows * CPU_TUPLE_COST
+                    + l.est_rows * r.est_rows * CPU_OPERATOR_COST * conj.len().max(1) as f64
+            }
+            Method::Hash => {
+                if keys.is_empty() {
+                    return None;
+                }
+                let k = keys.len() as f64;
+                // A build side over work_mem is written out and read back with the probe side.
+                let build_bytes = r.est_rows * row_width(r.columns.len());
+                let spill = if build_bytes > self.work_mem {
+                    let bytes = build_bytes + l.est_rows * row_width(l.columns.len());
+                    2.0 * SEQ_PAGE_COST * bytes / PAGE_SIZE as f64
+                } else {
+                    0.0
+                };
+                l.est_cost
+                    + r.est_cost
+                    + r.est_rows * (CPU_TUPLE_COST + CPU_OPERATOR_COST * k)
+                    + l.est_rows * CPU_OPERATOR_COST * k
+                    + out * CPU_OPERATOR_COST * (conj.len() - keys.len()) as f64
+                    + spill
+            }
+            Method::IndexNestedLoop(r_idx) => {
+                if matches!(join_type, JoinType::Right | JoinType::Full) {
+                    return None;
+                }
+                self.inlj_key(conj, left, r_idx)?;
+                let filters = conj.len() - 1 + self.local_conjuncts(r_idx).len();
+                l.est_cost
+                    + l.est_rows * (RANDOM_PAGE_COST + CPU_INDEX_TUPLE_COST + CPU_TUPLE_COST)
+                    + l.est_rows * CPU_OPERATOR_COST * filters as f64
+            }
+            Method::Merge => {
+                if keys.is_empty() {
+                    return None;
+                }
+                let sort = |p: &Planned, key: &Expr| match (p.sorted_on, key, keys.len()) {
+                    (Some(s), Expr::Column(c), 1) if s == *c => 0.0,
+                    _ => sort_cost(p.plan.est_rows),
+                };
+                l.est_cost
+                    + r.est_cost
+                    + sort(left, &keys[0].0)
+                    + sort(right, &keys[0].1)
+                    + (l.est_rows + r.est_rows) * CPU_OPERATOR_COST * keys.len() as f64
+                    + out * CPU_OPERATOR_COST * (conj.len() - keys.len()) as f64
+            }
+        };
+        Some(cost + out * CPU_TUPLE_COST)
+    }
+
+    /// Equality conjuncts usable as hash keys: (left key, right key, conjunct) triples over
+    /// global ids, and the remaining conjuncts.
+    fn hash_keys<'c>(
+        &self,
+        conj: &[&'c Expr],
+        left: &Planned,
+        right: &Planned,
+    ) -> (Vec<(Expr, Expr)>, Vec<&'c Expr>) {
+        let within = |e: &Expr, p: &Planned| {
+            let cols = e.columns();
+            !cols.is_empty() && cols.iter().all(|c| p.layout.contains(c))
+        };
+        let mut keys = Vec::new();
+        let mut rest = Vec::new();
+        for c in conj {
+            match c {
+                Expr::Binary { op: BinaryOp::Eq, left: a, right: b } if within(a, left) && within(b, right) => {
+                    keys.push(((**a).clone(), (**b).clone()))
+                }
+                Expr::Binary { op: BinaryOp::Eq, left: a, right: b } if within(b, left) && within(a, right) => {
+                    keys.push(((**b).clone(), (**a).clone()))
+                }
+                _ => rest.push(*c),
+            }
+        }
+        (keys, rest)
+    }
+
+    /// For an index nested-loop join into relation `r`: the conjunct index and the outer
+    /// expression equated with `r`'s key.
+    fn inlj_key(&self, conj: &[&Expr], left: &Planned, r: usize) -> Option<(usize, Expr)> {
+        let rel = &self.rels[r];
+        let Source::Table { key_col, .. } = &rel.source else {
+            return None;
+        };
+        let key = rel.offset + key_col;
+        let outer = |e: &Expr| {
+            let cols = e.columns();
+            !cols.is_empty() && cols.iter().all(|c| left.layout.contains(c))
+        };
+        conj.iter().enumerate().find_map(|(i, c)| match c {
+            Expr::Binary { op: BinaryOp::Eq, left: a, right: b } => match (&**a, &**b) {
+                (Expr::Column(k), o) | (o, Expr::Column(k)) if *k == key && outer(o) => Some((i, o.clone())),
+                _ => None,
+            },
+            _ => None,
+        })
+    }
+
+    /// Sort `input` ascending on `keys` (over global ids) unless it already is.
+    fn sorted(input: &Planned, keys: &[&Expr]) -> Plan {
+        if let ([Expr::Column(c)], Some(s)) = (keys, input.sorted_on) {
+            if *c == s {
+                return input.plan.clone();
+            }
+        }
+        let keys = keys
+            .iter()
+            .map(|k| SortKey {
+                expr: input.remap(k),
+                desc: false,
+                nulls_first: false,
+            })
+            .collect();
+        Plan {
+            node: Node::Sort { keys, limit: None },
+            columns: input.plan.columns.clone(),
+            est_rows: input.plan.est_rows,
+            est_cost: input.plan.est_cost + sort_cost(input.plan.est_rows),
+            children: vec![input.plan.clone()],
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn build_join(
+        &self,
+        method: Method,
+        left: &Planned,
+        right: &Planned,
+        conj: &[&Expr],
+        join_type: JoinType,
+        est_rows: f64,
+        cost: f64,
+    ) -> Planned {
+        // Expressions are evaluated over the left row followed by the right row, even for
+        // semi and anti joins, which output only the left columns.
+        let mut columns = left.plan.columns.clone();
+        let mut layout = left.layout.clone();
+        let mut sorted_on = left.sorted_on;
+        let (node, children) = match method {
+            Method::IndexNestedLoop(r) => {
+                let rel = &self.rels[r];
+                columns.extend(rel.columns.iter().cloned());
+                layout.extend(rel.column_ids());
+                let (i, outer) = self.inlj_key(conj, left, r).expect("index join without key");
+                let mut filter: Vec<Expr> = conj
+                    .iter()
+                    .enumerate()
+                    .filter(|(j, _)| *j != i)
+                    .map(|(_, c)| remap(c, &layout))
+                    .collect();
+                filter.extend(self.local_conjuncts(r).into_iter().map(|c| remap(c, &layout)));
+                let Source::Table { table, .. } = &rel.source else {
+                    unreachable!("index join into a non-table relation");
+                };
+                (
+                    Node::IndexNestedLoopJoin {
+                        join_type,
+                        table: table.clone(),
+                        key: left.remap(&outer),
+                        filter: Expr::and_all(filter),
+                    },
+                    vec![left.plan.clone()],
+                )
+            }
+            Method::Hash | Method::NestedLoop | Method::Merge => {
+                columns.extend(right.plan.columns.iter().cloned());
+                layout.extend(right.layout.iter().copied());
+                let (keys, rest) = self.hash_keys(conj, left, right);
+                let residual = Expr::and_all(rest.iter().map(|c| remap(c, &layout)).collect());
+                let left_keys = keys.iter().map(|(l, _)| left.remap(l)).collect();
+                let right_keys = keys.iter().map(|(_, r)| right.remap(r)).collect();
+                match method {
+                    Method::Hash => {
+                        sorted_on = None;
+                        (
+                            Node::HashJoin {
+                                join_type,
+                                left_keys,
+                                right_keys,
+                                residual,
+                            },
+                            vec![left.plan.clone(), right.plan.clone()],
+                        )
+                    }
+                    Method::Merge => {
+                        let lk: Vec<&Expr> = keys.iter().map(|(l, _)| l).collect();
+                        let rk: Vec<&Expr> = keys.iter().map(|(_, r)| r).collect();
+                        sorted_on = match lk.as_slice() {
+                            [Expr::Column(c)] => Some(*c),
+                            _ => None,
+                        };
+                        (
+                            Node::MergeJoin {
+                                join_type,
+                                left_keys,
+                                right_keys,
+                                residual,
+                            },
+                            vec![Self::sorted(left, &lk), Self::sorted(right, &rk)],
+                        )
+                    }
+                    _ => (
+                        Node::NestedLoopJoin {
+                            join_type,
+                            on: Expr::and_all(conj.iter().map(|c| remap(c, &layout)).collect()),
+                        },
+                        vec![left.plan.clone(), right.plan.clone()],
+                    ),
+                }
+            }
+        };
+        if !join_type.emits_right() {
+            columns.truncate(left.plan.columns.len());
+            layout.truncate(left.layout.len());
+        }
+        if matches!(join_type, JoinType::Right | JoinType::Full) {
+            sorted_on = None;
+        }
+        Planned {
+            plan: Plan {
+                node,
+                children,
+                columns,
+                est_rows,
+                est_cost: cost,
+            },
+            layout,
+            sorted_on,
+        }
+    }
+
+    /// Cheapest method for joining two planned inputs.
+    fn best_method(
+        &self,
+        left: &Planned,
+        right: &Planned,
+        rmask: u64,
+        conj: &[&Expr],
+        join_type: JoinType,
+        out: f64,
+    ) -> (Method, f64) {
+        let mut methods = vec![Method::NestedLoop, Method::Hash, Method::Merge];
+        if popcount(rmask) == 1 {
+            methods.push(Method::IndexNestedLoop(rmask.trailing_zeros() as usize));
+        }
+        methods
+            .into_iter()
+            .filter_map(|m| Some((m, self.join_cost(m, left, right, conj, join_type, out)?)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .expect("nested loop always applies")
+    }
+
+    /// Cheapest inner join of two planned subsets.
+    fn best_join(&self, left: &Planned, right: &Planned, lmask: u64, rmask: u64) -> (Method, f64) {
+        let conj = self.join_conjuncts(lmask, rmask);
+        self.best_method(left, right, rmask, &conj, JoinType::Inner, self.rows(lmask | rmask))
+    }
+
+    fn build_inner(&self, method: Method, left: &Planned, right: &Planned, lmask: u64, rmask: u64, cost: f64) -> Planned {
+        let conj = self.join_conjuncts(lmask, rmask);
+        let rows = self.rows(lmask | rmask);
+        self.build_join(method, left, right, &conj, JoinType::Inner, rows, cost)
+    }
+
+    /// Plan `rels[0] <join_type> JOIN rels[1] ON on` for exactly two relations. The
+    /// conjuncts given to `new` filter their relation before the join (only sound for
+    /// conditions on the side whose rows are not preserved); `on` is evaluated by the join.
+    /// Outer joins may run with their inputs swapped.
+    pub fn plan_join(&self, join_type: JoinType, on: Vec<Expr>) -> Result<Planned> {
+        if self.rels.len() != 2 {
+            bail!("plan_join needs exactly two relations");
+        }
+        let conj: Vec<&Expr> = on.iter().collect();
+        let (l, r) = (self.rows(1), self.rows(2));
+        let inner = (self.rows(3) * on.iter().map(|c| self.selectivity(c)).product::<f64>()).max(1.0);
+        let semi = inner.min(l);
+        let out = match join_type {
+            JoinType::Inner => inner,
+            JoinType::Left => inner.max(l),
+            JoinType::Right => inner.max(r),
+            JoinType::Full => inner.max(l) + (r - inner).max(0.0),
+            JoinType::Semi => semi,
+            JoinType::Anti => (l - semi).max(1.0),
+        };
+        let paths = [self.access_path(0), self.access_path(1)];
+        let mut orientations = vec![(0, 1, join_type)];
+        if let Some(swapped) = join_type.swapped().filter(|_| join_type != JoinType::Inner) {
+            orientations.push((1, 0, swapped));
+        }
+        let (li, ri, jt, method, cost) = orientations
+            .into_iter()
+            .map(|(li, ri, jt)| {
+                let (m, cost) = self.best_method(&paths[li], &paths[ri], 1 << ri, &conj, jt, out);
+                (li, ri, jt, m, cost)
+            })
+            .min_by(|a, b| a.4.total_cmp(&b.4))
+            .unwrap();
+        Ok(self.build_join(method, &paths[li], &paths[ri], &conj, jt, out, cost))
+    }
+
+    /// Plan the whole FROM list with every conjunct applied.
+    pub fn plan(&self) -> Result<Planned> {
+        let n = self.rels.len();
+        if n == 0 {
+            bail!("no relations to join");
+        }
+        if n > 63 {
+            bail!("too many relations in FROM ({})", n);
+        }
+        if n > DP_MAX_RELATIONS {
+            return Ok(self.plan_greedy());
+        }
+        let full = (1u64 << n) - 1;
+        let mut best: Vec<Option<Planned>> = vec![None; 1 << n];
+        for r in 0..n {
+            best[1 << r] = Some(self.access_path(r));
+        }
+        let mut masks: Vec<u64> = (1..=full).filter(|m| popcount(*m) > 1).collect();
+        masks.sort_by_key(|m| popcount(*m));
+        for mask in masks {
+            let splits: Vec<(u64, u64)> = {
+                let mut out = Vec::new();
+                let mut sub = (mask - 1) & mask;
+                while sub != 0 {
+                    out.push((sub, mask ^ sub));
+                    sub = (sub - 1) & mask;
+                }
+                out
+            };
+            let any_connected = splits.iter().any(|(l, r)| self.connected(*l, *r));
+            let mut choice: Option<(u64, u64, Method, f64)> = None;
+            for (l, r) in splits {
+                if any_connected && !self.connected(l, r) {
+                    continue;
+                }
+                let (Some(lp), Some(rp)) = (&best[l as usize], &best[r as usize]) else {
+                    continue;
+                };
+                let (m, cost) = self.best_join(lp, rp, l, r);
+                if choice.is_none_or(|c| cost < c.3) {
+                    choice = Some((l, r, m, cost));
+                }
+            }
+            if let Some((l, r, m, cost)) = choice {
+                let (lp, rp) = (best[l as usize].as_ref().unwrap(), best[r as usize].as_ref().unwrap());
+                best[mask as usize] = Some(self.build_inner(m, lp, rp, l, r, cost));
+            }
+        }
+        Ok(best[full as usize].take().expect("every subset is joinable"))
+    }
+
+    /// Left-deep greedy order: start from the smallest input, then repeatedly add the
+    /// relation that is cheapest to join next, preferring connected ones.
+    fn plan_greedy(&self) -> Planned {
+        let n = self.rels.len();
+        let paths: Vec<Planned> = (0..n).map(|r| self.access_path(r)).collect();
+        let start = (0..n)
+            .min_by(|a, b| paths[*a].plan.est_rows.total_cmp(&paths[*b].plan.est_rows))
+            .unwrap();
+        let mut mask = 1u64 << start;
+        let mut cur = paths[start].clone();
+        while popcount(mask) < n as u32 {
+            let candidates: Vec<usize> = (0..n).filter(|r| mask & (1 << r) == 0).collect();
+            let connected: Vec<usize> = candidates
+                .iter()
+                .copied()
+                .filter(|r| self.connected(mask, 1 << r))
+                .collect();
+            let pool = if connected.is_empty() { candidates } else { connected };
+            let (r, m, cost) = pool
+                .into_iter()
+                .map(|r| {
+                    let (m, cost) = self.best_join(&cur, &paths[r], mask, 1 << r);
+                    (r, m, cost)
+                })
+                .min_by(|a, b| a.2.total_cmp(&b.2))
+                .unwrap();
+            cur = self.build_inner(m, &cur, &paths[r], mask, 1 << r, cost);
+            mask |= 1 << r;
+        }
+        cur
+    }
+}
+
+/// Inclusive key range `[start, end]` selected by index scan bounds, or None if it is
+/// empty. Non-integer bounds are rounded inwards; a NULL bound matches nothing.
+pub fn key_range(low: Option<(&Value, bool)>, high: Option<(&Value, bool)>) -> Result<Option<(i64, i64)>> {
+    let start = match low {
+        None => i64::MIN,
+        Some((v, incl)) => match v {
+            Value::Null => return Ok(None),
+            Value::Int(n) if incl => *n,
+            Value::Int(n) => match n.checked_add(1) {
+                Some(n) => n,
+                None => return Ok(None),
+            },
+            Value::Float(x) => {
+                let c = x.ceil();
+                let c = if !incl && c == *x { c + 1.0 } else { c };
+                if c > i64::MAX as f64 {
+                    return Ok(None);
+                }
+                c.max(i64::MIN as f64) as i64
+            }
+            other => bail!("cannot compare INT key with {}", other),
+        },
+    };
+    let end = match high {
+        None => i64::MAX,
+        Some((v, incl)) => match v {
+            Value::Null => return Ok(None),
+            Value::Int(n) if incl => *n,
+            Value::Int(n) => match n.checked_sub(1) {
+                Some(n) => n,
+                None => return Ok(None),
+            },
+            Value::Float(x) => {
+                let f = x.floor();
+                let f = if !incl && f == *x { f - 1.0 } else { f };
+                if f < i64::MIN as f64 {
+                    return Ok(None);
+                }
+                f.min(i64::MAX as f64) as i64
+            }
+            other => bail!("cannot compare INT key with {}", other),
+        },
+    };
+    Ok((start <= end).then_some((start, end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(name: &str, offset: usize, rows: f64, stats: Option<TableStats>) -> Relation {
+        Relation {
+            source: Source::Table {
+                table: name.to_string(),
+                key_col: 0,
+                rows,
+                pages: (rows / 100.0).ceil(),
+                stats,
+            },
+            columns: vec![
+                (format!("{}.id", name), ColumnType::Int),
+                (format!("{}.v", name), ColumnType::Int),
+            ],
+            offset,
+        }
+    }
+
+    fn eq(a: Expr, b: Expr) -> Expr {
+        Expr::binary(BinaryOp::Eq, a, b)
+    }
+
+    fn lit(n: i64) -> Expr {
+        Expr::Literal(Value::Int(n))
+    }
+
+    #[test]
+    fn access_path_prefers_index_for_selective_key_predicates() {
+        let rels = vec![table("t", 0, 100_000.0, None)];
+        let key_eq = JoinPlanner::new(&rels, vec![eq(Expr::Column(0), lit(5))]).access_path(0);
+        assert!(matches!(key_eq.plan.node, Node::IndexScan { bounds: IndexBounds::Keys(_), .. }));
+        let narrow = Expr::Between {
+            expr: Box::new(Expr::Column(0)),
+            low: Box::new(lit(10)),
+            high: Box::new(lit(20)),
+            negated: false,
+        };
+        let range = JoinPlanner::new(&rels, vec![narrow]).access_path(0);
+        assert!(matches!(range.plan.node, Node::IndexScan { bounds: IndexBounds::Range { .. }, .. }));
+        // Without statistics a one-sided range is assumed to match a third of the table.
+        let wide = Expr::binary(BinaryOp::Gt, Expr::Column(0), lit(10));
+        let p = JoinPlanner::new(&rels, vec![wide]).access_path(0);
+        assert!(matches!(p.plan.node, Node::SeqScan { .. }));
+        let other = JoinPlanner::new(&rels, vec![eq(Expr::Column(1), lit(5))]).access_path(0);
+        assert!(matches!(other.plan.node, Node::SeqScan { filter: Some(_), .. }));
+        assert!((other.plan.est_rows - 500.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn outer_joins_use_ordered_scans_and_may_swap_inputs() {
+        let rels = vec![table("a", 0, 100_000.0, None), table("b", 2, 100_000.0, None)];
+        let range = |col| Expr::Between {
+            expr: Box::new(Expr::Column(col)),
+            low: Box::new(lit(10)),
+            high: Box::new(lit(20)),
+            negated: false,
+        };
+        let key = || vec![eq(Expr::Column(0), Expr::Column(2))];
+        // Both index scans return rows in key order; a full join cannot use the index.
+        let p = JoinPlanner::new(&rels, vec![range(0), range(2)])
+            .plan_join(JoinType::Full, key())
+            .unwrap();
+        assert!(
+            matches!(p.plan.node, Node::MergeJoin { join_type: JoinType::Full, .. }),
+            "{:?}",
+            p.plan.node
+        );
+        assert!(p.plan.children.iter().all(|c| matches!(c.node, Node::IndexScan { .. })));
+        assert_eq!(p.sorted_on, None);
+
+        // A small preserved side on the right probes the big left table's index.
+        let p = JoinPlanner::new(&rels, vec![range(2)]).plan_join(JoinType::Right, key()).unwrap();
+        assert!(
+            matches!(p.plan.node, Node::IndexNestedLoopJoin { join_type: JoinType::Left, .. }),
+            "{:?}",
+            p.plan.node
+        );
+        assert_eq!(p.layout, vec![2, 3, 0, 1]);
+
+        // Semi and anti joins keep only the left columns.
+        for jt in [JoinType::Semi, JoinType::Anti] {
+            let p = JoinPlanner::new(&rels, vec![]).plan_join(jt, key()).unwrap();
+            assert_eq!(p.layout, vec![0, 1]);
+            assert_eq!(p.plan.columns.len(), 2);
+        }
+    }
+
+    #[test]
+    fn join_algorithm_follows_input_sizes() {
+        // Small outer, big inner joined on the inner's key: index nested loop.
+        let rels = vec![table("a", 0, 100_000.0, None), table("b", 2, 1_000_000.0, None)];
+        let conj = vec![eq(Expr::Column(1), Expr::Column(2)), eq(Expr::Column(0), lit(7))];
+        let p = JoinPlanner::new(&rels, conj).plan().unwrap();
+        assert!(matches!(p.plan.node, Node::IndexNestedLoopJoin { .. }), "{:?}", p.plan.node);
+        assert_eq!(p.layout, vec![0, 1, 2, 3]);
+
+        // Two big inputs on a non-key column: hash join, building on the smaller side.
+        let rels = vec![table("a", 0, 200_000.0, None), table("b", 2, 10_000.0, None)];
+        let conj = vec![eq(Expr::Column(1), Expr::Column(3))];
+        let p = JoinPlanner::new(&rels, conj).plan().unwrap();
+        match &p.plan.node {
+            Node::HashJoin { left_keys, right_keys, .. } => {
+                assert_eq!(left_keys, &vec![Expr::Column(1)]);
+                assert_eq!(right_keys, &vec![Expr::Column(1)]);
+            }
+            other => panic!("expected hash join, got {:?}", other),
+        }
+        assert_eq!(p.layout, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn dp_avoids_cross_products_and_greedy_handles_many_relations() {
+        // a - b - c chain where a and c are large: never join a with c directly.
+        let rels = vec![
+            table("a", 0, 50_000.0, None),
+            table("b", 2, 10.0, None),
+            table("c", 4, 50_000.0, None),
+        ];
+        let conj = vec![eq(Expr::Column(1), Expr::Column(3)), eq(Expr::Column(3), Expr::Column(5))];
+        let p = JoinPlanner::new(&rels, conj.clone()).plan().unwrap();
+        for node in p.plan.walk() {
+            if node.children.len() == 2 {
+                let names: Vec<&str> = node.columns.iter().map(|(n, _)| n.as_str()).collect();
+                assert!(names.iter().any(|n| n.starts_with("b.")), "{:?}", names);
+            }
+        }
+        assert_eq!(p.layout.len(), 6);
+
+        let rels: Vec<Relation> = (0..14).map(|i| table(&format!("t{}", i), i * 2, 100.0, None)).collect();
+        let conj = (1..14).map(|i| eq(Expr::Column(i * 2 - 1), Expr::Column(i * 2))).collect();
+        let p = JoinPlanner::new(&rels, conj).plan().unwrap();
+        assert_eq!(p.layout.len(), 28);
+        assert!(p.plan.walk().iter().all(|n| !matches!(n.node, Node::NestedLoopJoin { on: None, .. })));
+    }
+
+    #[test]
+    fn key_ranges() {
+        let int = |n| Value::Int(n);
+        assert_eq!(key_range(Some((&int(3), false)), Some((&int(5), true))).unwrap(), Some((4, 5)));
+        assert_eq!(key_range(Some((&Value::Float(2.5), true)), None).unwrap(), Some((3, i64::MAX)));
+        assert_eq!(key_range(None, Some((&Value::Float(2.0), false))).unwrap(), Some((i64::MIN, 1)));
+        assert_eq!(key_range(Some((&int(5), true)), Some((&int(5), false))).unwrap(), None);
+        assert_eq!(key_range(None, Some((&int(i64::MIN), false))).unwrap(), None);
+        assert_eq!(key_range(Some((&Value::Null, true)), None).unwrap(), None);
+        assert!(key_range(Some((&Value::Text("x".into()), true)), None).is_err());
+    }
+}
@@ -0,0 +1,351 @@
+//! Intra-query parallelism.
+//!
+//! `parallelize` puts a `Gather` over each scan pipeline of a query that reads enough
+//! pages: a `SeqScan` with the `Filter`s and `Project`s above it and, when each of its
+//! aggregates can be merged, the `Aggregate` on top. The `Gather` operator starts up to
+//! `workers` threads that claim `CHUNK_PAGES` pages at a time from a shared counter and
+//! run the pipeline over them on batches (see `vector`), reading through a copy of the
+//! transaction's `ReadView`. Without an aggregate, workers send the rows they produce
+//! through a bounded channel; with one, each aggregates its share of the rows and the
+//! `Gather` merges the partial groups once every worker is done. A hash join whose build
+//! side is such a pipeline builds its table from the parallel scan.
+//!
+//! Workers are threads of their own rather than tasks of a shared pool: a worker waits
+//! whenever the consumer of its rows falls behind, which in a pool of fixed size would
+//! hold up the workers of other queries.
+
+use anyhow::{bail, Result};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::db::Database;
+use crate::query::aggregate::ACCUMULATOR_SIZE;
+use crate::query::exec::{ExecContext, Operator, Row};
+use crate::query::expr::Expr;
+use crate::query::limits::Limits;
+use crate::query::optimizer::{row_width, CPU_TUPLE_COST};
+use crate::query::plan::{AggStrategy, Node, Plan};
+use crate::query::vector::{self, Batch, Groups, Needed};
+use crate::storage::Table;
+use crate::txn::ReadView;
+
+/// Pages a worker claims at a time.
+pub const CHUNK_PAGES: u32 = 8;
+
+/// Batches of rows each worker may have waiting in the channel to its `Gather`.
+const QUEUED_BATCHES: usize = 4;
+
+/// `plan` with a `Gather` over each scan pipeline of a table of at least
+/// `parallel_min_pages` pages, run by up to `workers` threads. Plans that change data are
+/// left alone, as are the subplans of `Apply` and `RecursiveUnion` nodes, which run once
+/// per outer row or step.
+pub fn parallelize(plan: Plan, db: &Database, workers: usize) -> Plan {
+    if workers <= 1 || plan.modification().is_some() {
+        return plan;
+    }
+    rewrite(plan, db, workers)
+}
+
+fn rewrite(mut plan: Plan, db: &Database, workers: usize) -> Plan {
+    if let Some(n) = planned_workers(&plan, db, workers) {
+        return Plan {
+            node: Node::Gather { workers: n },
+            columns: plan.columns.clone(),
+            est_rows: plan.est_rows,
+            est_cost: plan.est_cost / n as f64 + CPU_TUPLE_COST * plan.est_rows,
+            children: vec![plan],
+        };
+    }
+    let before: f64 = plan.children.iter().map(|c| c.est_cost).sum();
+    plan.children = plan.children.into_iter().map(|c| rewrite(c, db, workers)).collect();
+    let after: f64 = plan.children.iter().map(|c| c.est_cost).sum();
+    plan.est_cost += after - before;
+    plan
+}
+
+/// How many workers a `Gather` over `plan` would use, if `plan` is a pipeline worth
+/// running in parallel: a table with enough pages for two chunks or more, and partial
+/// groups that fit in `work_mem`.
+fn planned_workers(plan: &Plan, db: &Database, workers: usize) -> Option<usize> {
+    let mut scan = plan;
+    if let Node::Aggregate {
+        strategy,
+        group_by,
+        sets,
+        aggregates,
+    } = &plan.node
+    {
+        let work_mem = (db.config().work_mem_kb * 1024) as f64;
+        let width = row_width(group_by.len()) + (ACCUMULATOR_SIZE * aggregates.len()) as f64;
+        if !(*strategy == AggStrategy::Hash || group_by.is_empty())
+            || sets.len() != 1
+            || !aggregates.iter().all(|a| a.func.mergeable())
+            || plan.est_rows * width > work_mem
+        {
+            return None;
+        }
+        scan = &plan.children[0];
+    }
+    while let Node::Filter { .. } | Node::Project { .. } = scan.node {
+        scan = &scan.children[0];
+    }
+    let Node::SeqScan { table, .. } = &scan.node else {
+        return None;
+    };
+    let pages = db.table(table).ok()?.num_pages();
+    if pages < db.config().parallel_min_pages || plan.has_outer() {
+        return None;
+    }
+    let n = workers.min(pages.div_ceil(CHUNK_PAGES) as usize);
+    (n > 1).then_some(n)
+}
+
+/// A step of a pipeline above its scan.
+enum Step {
+    Filter(Expr),
+    Project(Vec<Expr>),
+}
+
+/// What each worker of a `Gather` runs over its pages.
+struct Pipeline {
+    table: Arc<Table>,
+    /// Columns of `table` read above the scan (see `vector::decode_mask`).
+    decode: Vec<bool>,
+    filter: Option<Expr>,
+    /// Applied to each batch of the scan, bottom up.
+    steps: Vec<Step>,
+    /// Groups to aggregate the rows into, if the pipeline ends in an aggregate.
+    aggregate: Option<Groups>,
+}
+
+impl Pipeline {
+    fn new(plan: &Plan, db: &Database) -> Result<Self> {
+        let mut node = plan;
+        let mut needed: Needed = None;
+        let mut aggregate = None;
+        if let Node::Aggregate {
+            group_by,
+            sets,
+            aggregates,
+            ..
+        } = &plan.node
+        {
+            let [set] = sets.as_slice() else {
+                bail!("parallel aggregation of more than one grouping set");
+            };
+            aggregate = Some(Groups::new(group_by, set, aggregates));
+            needed = vector::child_needs(plan, &needed).pop().flatten();
+            node = &plan.children[0];
+        }
+        let mut steps = Vec::new();
+        loop {
+            match &node.node {
+                Node::Filter { predicate } => steps.push(Step::Filter(predicate.clone())),
+                Node::Project { exprs } => steps.push(Step::Project(exprs.clone())),
+                Node::SeqScan { table, filter } => {
+                    let table = db.table(table)?;
+                    steps.reverse();
+                    return Ok(Self {
+                        decode: vector::decode_mask(&table, filter.as_ref(), &needed),
+                        table,
+                        filter: filter.clone(),
+                        steps,
+                        aggregate,
+                    });
+                }
+                _ => bail!("Gather over a plan its workers cannot run"),
+            }
+            needed = vector::child_needs(node, &needed).pop().flatten();
+            node = &node.children[0];
+        }
+    }
+
+    /// `batch` after the steps above the scan; None if no row is left.
+    fn run_steps(&self, mut batch: Batch) -> Result<Option<Batch>> {
+        for step in &self.steps {
+            batch = match step {
+                Step::Filter(predicate) => match vector::filter_batch(predicate, batch)? {
+                    Some(b) => b,
+                    None => return Ok(None),
+                },
+                Step::Project(exprs) => vector::project_batch(exprs, &batch)?,
+            };
+        }
+        Ok(Some(batch))
+    }
+}
+
+/// What a worker sends its `Gather`.
+enum Output {
+    Rows(Vec<Row>),
+    /// Its partial groups, sent once it has no pages left.
+    Groups(Groups),
+}
+
+/// Run `pipeline` over chunks of pages claimed from `next` until `end` (or `stop`), sending
+/// the results on `tx`. Partial groups count against the statement's memory budget.
+fn work(
+    pipeline: &Pipeline,
+    view: &ReadView,
+    limits: &Arc<Limits>,
+    next: &AtomicU64,
+    end: u32,
+    stop: &AtomicBool,
+    tx: &SyncSender<Result<Output>>,
+) -> Result<()> {
+    let mut groups = pipeline.aggregate.clone();
+    let mut memory = limits.reservation();
+    while !stop.load(Ordering::Relaxed) {
+        limits.check()?;
+        let start = next.fetch_add(CHUNK_PAGES as u64, Ordering::Relaxed);
+        if start >= end as u64 {
+            break;
+        }
+        let mut pages = start as u32..(start + CHUNK_PAGES as u64).min(end as u64) as u32;
+        let (table, filter) = (&pipeline.table, pipeline.filter.as_ref());
+        while let Some(batch) = vector::scan_batch(view, table, &pipeline.decode, filter, &mut pages)? {
+            let Some(batch) = pipeline.run_steps(batch)? else {
+                continue;
+            };
+            match &mut groups {
+                Some(groups) => {
+                    groups.add(&batch)?;
+                    memory.resize(groups.size())?;
+                }
+                // The Gather is gone.
+                None if tx.send(Ok(Output::Rows(batch.rows()))).is_err() => return Ok(()),
+                None => {}
+            }
+        }
+    }
+    if let Some(groups) = groups {
+        let _ = tx.send(Ok(Output::Groups(groups)));
+    }
+    Ok(())
+}
+
+/// Workers of a `Gather` that has started.
+struct Workers {
+    /// Dropped to wake workers waiting to send when the `Gather` stops early.
+    rx: Option<Receiver<Result<Output>>>,
+    stop: Arc<AtomicBool>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Workers {
+    /// Wait for every worker to exit.
+    fn join(&mut self) -> Result<()> {
+        let mut panicked = false;
+        for h in self.handles.drain(..) {
+            panicked |= h.join().is_err();
+        }
+        if panicked {
+            bail!("parallel worker panicked");
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.rx = None;
+        let _ = self.join();
+    }
+}
+
+/// Rows of a pipeline run by worker threads (see the module docs). The workers start on
+/// the first call to `next`.
+pub struct Gather {
+    pipeline: Arc<Pipeline>,
+    workers: usize,
+    running: Option<Workers>,
+    /// The partial groups received so far, merged.
+    merged: Option<Groups>,
+    buf: VecDeque<Row>,
+    done: bool,
+}
+
+impl Gather {
+    /// A `Gather` running `plan` (its child) in up to `workers` threads.
+    pub fn new(plan: &Plan, db: &Database, workers: usize) -> Result<Self> {
+        Ok(Self {
+            pipeline: Arc::new(Pipeline::new(plan, db)?),
+            workers,
+            running: None,
+            merged: None,
+            buf: VecDeque::new(),
+            done: false,
+        })
+    }
+
+    fn start(&mut self, ctx: &mut ExecContext<'_>) -> Result<Workers> {
+        let table = &self.pipeline.table;
+        ctx.txn.note_table_scan(table);
+        let end = table.num_pages();
+        let n = self.workers.min(end.div_ceil(CHUNK_PAGES) as usize).max(1);
+        let (tx, rx) = mpsc::sync_channel(n * QUEUED_BATCHES);
+        let next = Arc::new(AtomicU64::new(0));
+        let mut workers = Workers {
+            rx: Some(rx),
+            stop: Arc::new(AtomicBool::new(false)),
+            handles: Vec::with_capacity(n),
+        };
+        for i in 0..n {
+            let (pipeline, view, limits, next, stop, tx) = (
+                Arc::clone(&self.pipeline),
+                ctx.txn.read_view().clone(),
+                Arc::clone(&ctx.limits),
+                Arc::clone(&next),
+                Arc::clone(&workers.stop),
+                tx.clone(),
+            );
+            let handle = thread::Builder::new()
+                .name(format!("parallel-worker-{}", i))
+                .spawn(move || {
+                    if let Err(e) = work(&pipeline, &view, &limits, &next, end, &stop, &tx) {
+                        let _ = tx.send(Err(e));
+                    }
+                })?;
+            workers.handles.push(handle);
+        }
+        Ok(workers)
+    }
+}
+
+impl Operator for Gather {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        loop {
+            if let Some(row) = self.buf.pop_front() {
+                return Ok(Some(row));
+            }
+            if self.done {
+                return Ok(None);
+            }
+            if self.running.is_none() {
+                self.running = Some(self.start(ctx)?);
+            }
+            let workers = self.running.as_mut().expect("workers started");
+            let received = workers.rx.as_ref().expect("receiver open").recv();
+            match received {
+                Ok(Ok(Output::Rows(rows))) => self.buf.extend(rows),
+                Ok(Ok(Output::Groups(groups))) => match &mut self.merged {
+                    Some(merged) => merged.merge(groups)?,
+                    None => self.merged = Some(groups),
+                },
+                Ok(Err(e)) => return Err(e),
+                // Every worker has finished.
+                Err(_) => {
+                    workers.join()?;
+                    if let Some(merged) = self.merged.take() {
+                        self.buf.extend(merged.finish()?);
+                    }
+                    self.done = true;
+                }
+            }
+        }
+    }
+}
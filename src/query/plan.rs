@@ -0,0 +1,522 @@
+//! Physical plan tree produced by the planner and run by `exec`.
+//!
+//! Expressions inside a node refer to columns by position in the node's input: the child's
+//! output, or for joins the left child's columns followed by the right child's.
+
+use crate::query::aggregate::AggCall;
+use crate::query::expr::Expr;
+use crate::query::table_fn::TableFunc;
+use crate::query::window::WindowCall;
+use crate::sequence::SessionSequences;
+use crate::storage::{ColumnType, Value};
+use crate::txn::LockMode;
+
+/// Which keys an index scan visits. Bounds are evaluated when the scan starts.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IndexBounds {
+    /// Exactly these keys (`key = e` or `key IN (...)`).
+    Keys(Vec<Expr>),
+    /// Keys between the bounds; the flag says whether the bound itself is included.
+    Range {
+        low: Option<(Expr, bool)>,
+        high: Option<(Expr, bool)>,
+    },
+}
+
+/// Which rows a join produces. Outer joins pad the unmatched rows of the preserved side
+/// with NULLs; semi and anti joins output only left rows, those with (without) a match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinType {
+    Inner,
+    Left,
+    Right,
+    Full,
+    Semi,
+    Anti,
+}
+
+impl JoinType {
+    /// Whether the output has the right input's columns.
+    pub fn emits_right(self) -> bool {
+        !matches!(self, JoinType::Semi | JoinType::Anti)
+    }
+
+    /// The same join with its inputs swapped, if there is one.
+    pub fn swapped(self) -> Option<JoinType> {
+        match self {
+            JoinType::Inner | JoinType::Full => Some(self),
+            JoinType::Left => Some(JoinType::Right),
+            JoinType::Right => Some(JoinType::Left),
+            JoinType::Semi | JoinType::Anti => None,
+        }
+    }
+}
+
+/// How an `Aggregate` node finds the rows of a group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggStrategy {
+    /// A hash table of groups, partitioned to temporary files past `work_mem`.
+    Hash,
+    /// Input sorted on the group keys, so each group's rows are adjacent.
+    Sorted,
+}
+
+/// The set operation of a `SetOp` node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetOpKind {
+    Intersect,
+    Except,
+}
+
+impl SetOpKind {
+    /// How many copies of a row occurring `left` times on the left and `right` times on
+    /// the right the operation outputs.
+    pub fn count(self, all: bool, left: i64, right: i64) -> i64 {
+        match (self, all) {
+            (SetOpKind::Intersect, true) => left.min(right),
+            (SetOpKind::Intersect, false) => (left > 0 && right > 0) as i64,
+            (SetOpKind::Except, true) => (left - right).max(0),
+            (SetOpKind::Except, false) => (left > 0 && right == 0) as i64,
+        }
+    }
+}
+
+/// What an `Apply` node appends to each row from its subquery's rows.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ApplyKind {
+    /// The only row's only value; NULL without rows, an error with more than one.
+    Scalar,
+    /// Whether there is a row.
+    Exists,
+    /// Whether `expr`, over the outer row, equals a row's value: NULL rather than false
+    /// if it is NULL or a value is.
+    In(Expr),
+}
+
+/// What INSERT does with a row whose key a live row already has.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OnConflict {
+    DoNothing,
+    /// Assign to the existing row. The expressions read the existing row followed by the
+    /// row proposed for insertion (`excluded`); rows failing `filter` are left alone.
+    DoUpdate {
+        assignments: Vec<(usize, Expr)>,
+        filter: Option<Expr>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortKey {
+    pub expr: Expr,
+    pub desc: bool,
+    pub nulls_first: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    /// Every visible row of `table`, in heap order.
+    SeqScan { table: String, filter: Option<Expr> },
+    /// Rows of `table` found through its primary-key B-tree, in key order.
+    IndexScan {
+        table: String,
+        bounds: IndexBounds,
+        filter: Option<Expr>,
+    },
+    /// Rows computed from constant expressions (`VALUES`, `SELECT` without `FROM`).
+    Values { rows: Vec<Vec<Expr>> },
+    /// The rows the last iteration of the enclosing `RecursiveUnion` named `name` produced.
+    WorkTableScan { name: String },
+    /// A table function, built-in or registered from Rust (see `table_fn`).
+    TableFunction { func: TableFunc, args: Vec<Expr> },
+    Filter { predicate: Expr },
+    /// Each child row followed by a value computed from the rows of `subplan` (see
+    /// `ApplyKind`). `params` are evaluated over the child row and bound to the subplan's
+    /// outer references before it runs; it runs once per distinct set of values in a row,
+    /// so only once when there are none.
+    Apply {
+        kind: ApplyKind,
+        params: Vec<Expr>,
+        subplan: Box<Plan>,
+    },
+    Project { exprs: Vec<Expr> },
+    /// For each left row, every right row (materialized once) passing `on`.
+    NestedLoopJoin { join_type: JoinType, on: Option<Expr> },
+    /// Build a hash table on the right child keyed by `right_keys`, probe it with the left.
+    /// Both sides are partitioned to temporary files if the table outgrows `work_mem`.
+    HashJoin {
+        join_type: JoinType,
+        left_keys: Vec<Expr>,
+        right_keys: Vec<Expr>,
+        residual: Option<Expr>,
+    },
+    /// For each row of the only child, look up `table` by primary key `key` (evaluated on
+    /// the outer row); `filter` sees the outer row followed by the table row. Inner, left,
+    /// semi and anti joins only.
+    IndexNestedLoopJoin {
+        join_type: JoinType,
+        table: String,
+        key: Expr,
+        filter: Option<Expr>,
+    },
+    /// Merge two inputs sorted ascending on their keys (NULLs last).
+    MergeJoin {
+        join_type: JoinType,
+        left_keys: Vec<Expr>,
+        right_keys: Vec<Expr>,
+        residual: Option<Expr>,
+    },
+    /// One output row per group of each grouping set: the values of `group_by` (NULL for
+    /// keys not in the set) followed by the value of each aggregate. `sets` index into
+    /// `group_by`; a plain `GROUP BY` has one set with every key, no `GROUP BY` one empty
+    /// set. `Sorted` handles a single set only.
+    Aggregate {
+        strategy: AggStrategy,
+        group_by: Vec<Expr>,
+        sets: Vec<Vec<usize>>,
+        aggregates: Vec<AggCall>,
+    },
+    /// The child's rows, sorted on `partition_by` and then `order_by`, each followed by the
+    /// value of every function in `functions` over its partition.
+    Window {
+        partition_by: Vec<Expr>,
+        order_by: Vec<SortKey>,
+        functions: Vec<WindowCall>,
+    },
+    /// Order the child's rows by `keys`, spilling sorted runs to temporary files past
+    /// `work_mem`. With a limit, only that many leading rows are produced (top-N).
+    Sort { keys: Vec<SortKey>, limit: Option<u64> },
+    Limit { limit: Option<u64>, offset: u64 },
+    /// `WITH RECURSIVE`: the child's rows, then those of `recursive` run over the rows of
+    /// the previous step (its `WorkTableScan`s of `name`) until a step produces none. With
+    /// `distinct`, rows already produced are dropped, which also ends cycles.
+    RecursiveUnion {
+        name: String,
+        distinct: bool,
+        recursive: Box<Plan>,
+    },
+    /// The rows of the child, a scan pipeline (a `SeqScan` under `Filter`s and `Project`s,
+    /// possibly topped by a single-set `Aggregate`), run by up to `workers` threads that
+    /// each read a share of the table's pages (see `parallel`). Rows come in no particular
+    /// order.
+    Gather { workers: usize },
+    /// The rows of each child in turn (`UNION ALL`), converted to the output column types.
+    Append,
+    /// `INTERSECT` or `EXCEPT` of the groups of the child: rows of the operation's columns
+    /// followed by how many times each occurs on the left and on the right. Each row is
+    /// output as many times as `op` keeps it (see `SetOpKind::count`).
+    SetOp { op: SetOpKind, all: bool },
+    /// Insert the child's rows into `table`; child column i goes to table column
+    /// `columns[i]`. Omitted columns are NULL, or a fresh key for a generated `rowid`.
+    /// Returns the rows inserted, and those updated by `on_conflict`.
+    Insert {
+        table: String,
+        columns: Vec<usize>,
+        on_conflict: Option<OnConflict>,
+    },
+    /// Replace each child row (a full row of `table`, possibly followed by values the
+    /// assignments read) with the assigned values.
+    Update {
+        table: String,
+        assignments: Vec<(usize, Expr)>,
+    },
+    /// Delete each child row (a full row of `table`, possibly followed by other values).
+    Delete { table: String },
+    /// `SELECT ... FOR UPDATE/SHARE`: lock each child row (a full row of `table`, possibly
+    /// followed by other values) in `mode` until the transaction ends, and pass on its
+    /// newest version instead. Rows deleted meanwhile are dropped.
+    LockRows { table: String, mode: LockMode },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    pub node: Node,
+    pub children: Vec<Plan>,
+    /// Output columns.
+    pub columns: Vec<(String, ColumnType)>,
+    /// Estimated output rows and total cost (see `optimizer` for the units).
+    pub est_rows: f64,
+    pub est_cost: f64,
+}
+
+impl Plan {
+    pub fn column_types(&self) -> Vec<ColumnType> {
+        self.columns.iter().map(|(_, t)| *t).collect()
+    }
+
+    /// This plan with `values` bound to its outer references (see `Expr::Outer`). The
+    /// subplans of its `Apply` nodes have outer references of their own and are left alone.
+    pub fn bind_outer(&self, values: &[Value]) -> Plan {
+        let mut node = self.node.map_exprs(&mut |e| e.bind_outer(values));
+        if let Node::RecursiveUnion { recursive, .. } = &mut node {
+            **recursive = recursive.bind_outer(values);
+        }
+        Plan {
+            node,
+            children: self.children.iter().map(|c| c.bind_outer(values)).collect(),
+            ..self.clone()
+        }
+    }
+
+    /// This plan with `values` bound to the parameters of its prepared statement (see
+    /// `Expr::Param`), subplans included.
+    pub fn bind_params(&self, values: &[Value]) -> Plan {
+        let mut node = self.node.map_exprs(&mut |e| e.bind_params(values));
+        match &mut node {
+            Node::Apply { subplan, .. } => **subplan = subplan.bind_params(values),
+            Node::RecursiveUnion { recursive, .. } => **recursive = recursive.bind_params(values),
+            _ => {}
+        }
+        Plan {
+            node,
+            children: self.children.iter().map(|c| c.bind_params(values)).collect(),
+            ..self.clone()
+        }
+    }
+
+    /// This plan with its sequence function calls made by `sequences`' session.
+    pub fn bind_sequences(&self, sequences: &SessionSequences) -> Plan {
+        let mut node = self.node.map_exprs(&mut |e| e.bind_sequences(sequences));
+        match &mut node {
+            Node::Apply { subplan, .. } => **subplan = subplan.bind_sequences(sequences),
+            Node::RecursiveUnion { recursive, .. } => **recursive = recursive.bind_sequences(sequences),
+            _ => {}
+        }
+        Plan {
+            node,
+            children: self.children.iter().map(|c| c.bind_sequences(sequences)).collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Whether the plan reads values of an enclosing query.
+    pub fn has_outer(&self) -> bool {
+        let mut found = false;
+        self.node.map_exprs(&mut |e| {
+            found |= e.has_outer();
+            e.clone()
+        });
+        let recursive = match &self.node {
+            Node::RecursiveUnion { recursive, .. } => Some(&**recursive),
+            _ => None,
+        };
+        found || self.children.iter().chain(recursive).any(Plan::has_outer)
+    }
+
+    /// Whether the plan calls a sequence function (see `Expr::calls_sequence`).
+    pub fn calls_sequence(&self) -> bool {
+        let mut found = false;
+        self.node.map_exprs(&mut |e| {
+            found |= e.calls_sequence();
+            e.clone()
+        });
+        let nested = match &self.node {
+            Node::Apply { subplan, .. } => Some(&**subplan),
+            Node::RecursiveUnion { recursive, .. } => Some(&**recursive),
+            _ => None,
+        };
+        found || self.children.iter().chain(nested).any(Plan::calls_sequence)
+    }
+
+    /// This plan with its `WorkTableScan`s of `name` reading `rows` instead.
+    pub fn bind_work_table(&self, name: &str, rows: &[Vec<Value>]) -> Plan {
+        let node = match &self.node {
+            Node::WorkTableScan { name: n } if n == name => Node::Values {
+                rows: rows.iter().map(|r| r.iter().cloned().map(Expr::Literal).collect()).collect(),
+            },
+            // An inner recursive query of the same name has its own work table.
+            Node::RecursiveUnion { name: n, .. } if n == name => return self.clone(),
+            Node::RecursiveUnion {
+                name: n,
+                distinct,
+                recursive,
+            } => Node::RecursiveUnion {
+                name: n.clone(),
+                distinct: *distinct,
+                recursive: Box::new(recursive.bind_work_table(name, rows)),
+            },
+            Node::Apply { kind, params, subplan } => Node::Apply {
+                kind: kind.clone(),
+                params: params.clone(),
+                subplan: Box::new(subplan.bind_work_table(name, rows)),
+            },
+            other => other.clone(),
+        };
+        Plan {
+            node,
+            children: self.children.iter().map(|c| c.bind_work_table(name, rows)).collect(),
+            ..self.clone()
+        }
+    }
+
+    /// The INSERT, UPDATE or DELETE this plan runs, under its RETURNING list if it has one.
+    pub fn modification(&self) -> Option<&Node> {
+        let node = match (&self.node, self.children.as_slice()) {
+            (Node::Project { .. }, [child]) => &child.node,
+            (node, _) => node,
+        };
+        matches!(node, Node::Insert { .. } | Node::Update { .. } | Node::Delete { .. }).then_some(node)
+    }
+
+    /// Nodes of the tree in pre-order.
+    pub fn walk(&self) -> Vec<&Plan> {
+        let mut out = vec![self];
+        for c in &self.children {
+            out.extend(c.walk());
+        }
+        out
+    }
+}
+
+impl Node {
+    /// The same node with every expression it holds rewritten by `f`; subplans are kept.
+    pub fn map_exprs(&self, f: &mut dyn FnMut(&Expr) -> Expr) -> Node {
+        match self {
+            Node::SeqScan { table, filter } => Node::SeqScan {
+                table: table.clone(),
+                filter: filter.as_ref().map(&mut *f),
+            },
+            Node::IndexScan { table, bounds, filter } => Node::IndexScan {
+                table: table.clone(),
+                bounds: match bounds {
+                    IndexBounds::Keys(keys) => IndexBounds::Keys(keys.iter().map(&mut *f).collect()),
+                    IndexBounds::Range { low, high } => IndexBounds::Range {
+                        low: low.as_ref().map(|(e, incl)| (f(e), *incl)),
+                        high: high.as_ref().map(|(e, incl)| (f(e), *incl)),
+                    },
+                },
+                filter: filter.as_ref().map(&mut *f),
+            },
+            Node::Values { rows } => Node::Values {
+                rows: rows.iter().map(|r| r.iter().map(&mut *f).collect()).collect(),
+            },
+            Node::TableFunction { func, args } => Node::TableFunction {
+                func: func.clone(),
+                args: args.iter().map(&mut *f).collect(),
+            },
+            Node::Filter { predicate } => Node::Filter { predicate: f(predicate) },
+            Node::Apply { kind, params, subplan } => Node::Apply {
+                kind: match kind {
+                    ApplyKind::In(e) => ApplyKind::In(f(e)),
+                    other => other.clone(),
+                },
+                params: params.iter().map(&mut *f).collect(),
+                subplan: subplan.clone(),
+            },
+            Node::Project { exprs } => Node::Project {
+                exprs: exprs.iter().map(&mut *f).collect(),
+            },
+            Node::NestedLoopJoin { join_type, on } => Node::NestedLoopJoin {
+                join_type: *join_type,
+                on: on.as_ref().map(&mut *f),
+            },
+            Node::HashJoin {
+                join_type,
+                left_keys,
+                right_keys,
+                residual,
+            } => Node::HashJoin {
+                join_type: *join_type,
+                left_keys: left_keys.iter().map(&mut *f).collect(),
+                right_keys: right_keys.iter().map(&mut *f).collect(),
+                residual: residual.as_ref().map(&mut *f),
+            },
+            Node::IndexNestedLoopJoin {
+                join_type,
+                table,
+                key,
+                filter,
+            } => Node::IndexNestedLoopJoin {
+                join_type: *join_type,
+                table: table.clone(),
+                key: f(key),
+                filter: filter.as_ref().map(&mut *f),
+            },
+            Node::MergeJoin {
+                join_type,
+                left_keys,
+                right_keys,
+                residual,
+            } => Node::MergeJoin {
+                join_type: *join_type,
+                left_keys: left_keys.iter().map(&mut *f).collect(),
+                right_keys: right_keys.iter().map(&mut *f).collect(),
+                residual: residual.as_ref().map(&mut *f),
+            },
+            Node::Aggregate {
+                strategy,
+                group_by,
+                sets,
+                aggregates,
+            } => Node::Aggregate {
+                strategy: *strategy,
+                group_by: group_by.iter().map(&mut *f).collect(),
+                sets: sets.clone(),
+                aggregates: aggregates
+                    .iter()
+                    .map(|a| AggCall {
+                        args: a.args.iter().map(&mut *f).collect(),
+                        filter: a.filter.as_ref().map(&mut *f),
+                        ..a.clone()
+                    })
+                    .collect(),
+            },
+            Node::Window {
+                partition_by,
+                order_by,
+                functions,
+            } => Node::Window {
+                partition_by: partition_by.iter().map(&mut *f).collect(),
+                order_by: order_by
+                    .iter()
+                    .map(|k| SortKey {
+                        expr: f(&k.expr),
+                        ..k.clone()
+                    })
+                    .collect(),
+                functions: functions
+                    .iter()
+                    .map(|w| WindowCall {
+                        args: w.args.iter().map(&mut *f).collect(),
+                        filter: w.filter.as_ref().map(&mut *f),
+                        ..w.clone()
+                    })
+                    .collect(),
+            },
+            Node::Sort { keys, limit } => Node::Sort {
+                keys: keys
+                    .iter()
+                    .map(|k| SortKey {
+                        expr: f(&k.expr),
+                        ..k.clone()
+                    })
+                    .collect(),
+                limit: *limit,
+            },
+            Node::Update { table, assignments } => Node::Update {
+                table: table.clone(),
+                assignments: assignments.iter().map(|(c, e)| (*c, f(e))).collect(),
+            },
+            Node::Insert {
+                table,
+                columns,
+                on_conflict: Some(OnConflict::DoUpdate { assignments, filter }),
+            } => Node::Insert {
+                table: table.clone(),
+                columns: columns.clone(),
+                on_conflict: Some(OnConflict::DoUpdate {
+                    assignments: assignments.iter().map(|(c, e)| (*c, f(e))).collect(),
+                    filter: filter.as_ref().map(&mut *f),
+                }),
+            },
+            Node::WorkTableScan { .. }
+            | Node::Limit { .. }
+            | Node::RecursiveUnion { .. }
+            | Node::Append
+            | Node::Gather { .. }
+            | Node::SetOp { .. }
+            | Node::Insert { .. }
+            | Node::Delete { .. }
+            | Node::LockRows { .. } => self.clone(),
+        }
+    }
+}
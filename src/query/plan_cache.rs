@@ -0,0 +1,428 @@
+//! Server-wide plan cache.
+//!
+//! Statements sent as SQL text are looked up by their normalized form (see `normalize`):
+//! the literal operands of comparisons, arithmetic, IN lists and BETWEEN, the values of
+//! VALUES lists and of UPDATE's SET become parameters, so statements that differ only in
+//! those literals share one generic plan, built the first time and bound to each
+//! statement's values afterwards. Literals that shape a plan rather than feed it (LIMIT,
+//! ORDER BY and GROUP BY positions, function arguments, ...) stay part of the text.
+//!
+//! An entry is valid for the catalog version it was planned at (see
+//! `Database::catalog_version`): DDL and statistics that moved significantly make every
+//! plan stale, and the next lookup plans again. When the cache is full the entry used
+//! least recently makes room. Hits and misses are kept per entry and in total, and read
+//! through the `rustdb_plan_cache` and `rustdb_plan_cache_stats` system views.
+
+use sqlparser::ast::{self, Statement, VisitMut, VisitorMut};
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::ops::ControlFlow;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::hash::StableHasher;
+use crate::query::plan::Plan;
+use crate::query::planner::number;
+use crate::storage::{ColumnType, Value};
+
+/// A statement with its literals taken out as parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Normalized {
+    pub statement: Statement,
+    /// Values of the parameters, in order: `$1` first.
+    pub params: Vec<Value>,
+}
+
+impl Normalized {
+    pub fn param_types(&self) -> Vec<ColumnType> {
+        self.params.iter().map(|v| v.column_type().unwrap_or(ColumnType::Text)).collect()
+    }
+}
+
+/// `stmt`, a query or DML statement, with its literals replaced by parameters. None for
+/// other statements and for those that have parameters already.
+pub fn normalize(stmt: &Statement) -> Option<Normalized> {
+    if !matches!(
+        stmt,
+        Statement::Query(_) | Statement::Insert { .. } | Statement::Update { .. } | Statement::Delete { .. }
+    ) {
+        return None;
+    }
+    let has_params = ast::visit_expressions(stmt, |e| match e {
+        ast::Expr::Value(ast::Value::Placeholder(_)) => ControlFlow::Break(()),
+        _ => ControlFlow::Continue(()),
+    });
+    if has_params.is_break() {
+        return None;
+    }
+    let mut statement = stmt.clone();
+    let mut normalizer = Normalizer { params: Vec::new() };
+    let _ = statement.visit(&mut normalizer);
+    Some(Normalized {
+        statement,
+        params: normalizer.params,
+    })
+}
+
+/// Replaces literals in value positions with parameters.
+struct Normalizer {
+    params: Vec<Value>,
+}
+
+impl Normalizer {
+    /// Make `e` a parameter if it is a number or string literal.
+    fn parameterize(&mut self, e: &mut ast::Expr) {
+        let value = match e {
+            ast::Expr::Value(ast::Value::Number(s, _)) => number(s).ok(),
+            ast::Expr::Value(ast::Value::SingleQuotedString(s)) => Some(Value::Text(s.clone())),
+            ast::Expr::UnaryOp {
+                op: ast::UnaryOperator::Minus,
+                expr,
+            } => match &**expr {
+                ast::Expr::Value(ast::Value::Number(s, _)) => number(&format!("-{}", s)).ok(),
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(value) = value {
+            self.params.push(value);
+            *e = ast::Expr::Value(ast::Value::Placeholder(format!("${}", self.params.len())));
+        }
+    }
+}
+
+impl VisitorMut for Normalizer {
+    type Break = ();
+
+    fn pre_visit_statement(&mut self, stmt: &mut Statement) -> ControlFlow<()> {
+        if let Statement::Update { assignments, .. } = stmt {
+            for a in assignments {
+                self.parameterize(&mut a.value);
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_query(&mut self, query: &mut ast::Query) -> ControlFlow<()> {
+        if let ast::SetExpr::Values(values) = &mut *query.body {
+            for e in values.rows.iter_mut().flatten() {
+                self.parameterize(e);
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_expr(&mut self, e: &mut ast::Expr) -> ControlFlow<()> {
+        use ast::BinaryOperator as B;
+        match e {
+            ast::Expr::BinaryOp { left, op, right } => {
+                if matches!(
+                    op,
+                    B::Plus
+                        | B::Minus
+                        | B::Multiply
+                        | B::Divide
+                        | B::Modulo
+                        | B::Eq
+                        | B::NotEq
+                        | B::Lt
+                        | B::LtEq
+                        | B::Gt
+                        | B::GtEq
+                        | B::StringConcat
+                ) {
+                    self.parameterize(left);
+                    self.parameterize(right);
+                }
+            }
+            ast::Expr::InList { list, .. } => list.iter_mut().for_each(|e| self.parameterize(e)),
+            ast::Expr::Between { low, high, .. } => {
+                self.parameterize(low);
+                self.parameterize(high);
+            }
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// What a cached plan was built for: the normalized statement text, the types of its
+/// parameters and the session settings plans depend on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub query: String,
+    pub param_types: Vec<ColumnType>,
+    pub parallel_workers: usize,
+}
+
+impl CacheKey {
+    /// A hash of the whole key, shown as its fingerprint; the same in every build.
+    pub fn fingerprint(&self) -> u64 {
+        let mut h = StableHasher::new();
+        self.hash(&mut h);
+        h.finish()
+    }
+}
+
+struct Entry {
+    /// None once the catalog changed under it.
+    plan: Option<Arc<Plan>>,
+    /// Catalog version `plan` was built at.
+    version: u64,
+    hits: u64,
+    misses: u64,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<CacheKey, Entry>,
+    /// Bumped by every lookup; orders entries by last use.
+    clock: u64,
+    hits: u64,
+    misses: u64,
+    invalidations: u64,
+    evictions: u64,
+}
+
+/// One cached statement, as the `rustdb_plan_cache` view shows it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntryStats {
+    pub fingerprint: u64,
+    pub query: String,
+    pub param_types: Vec<ColumnType>,
+    pub hits: u64,
+    /// Lookups that found no valid plan, including the first.
+    pub misses: u64,
+    /// Whether a plan valid for the current catalog version is held.
+    pub valid: bool,
+}
+
+/// Totals over the life of the cache, as the `rustdb_plan_cache_stats` view shows them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub invalidations: u64,
+    pub evictions: u64,
+}
+
+/// Generic plans by `CacheKey`, for up to `capacity` statements.
+pub struct PlanCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+impl PlanCache {
+    /// A cache of `capacity` plans; 0 turns caching off.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::default(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    fn inner(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The plan cached for `key` if it was built at catalog version `version`; counts a
+    /// hit or a miss.
+    pub fn get(&self, key: &CacheKey, version: u64) -> Option<Arc<Plan>> {
+        let mut inner = self.inner();
+        let Inner {
+            entries,
+            clock,
+            hits,
+            misses,
+            invalidations,
+            ..
+        } = &mut *inner;
+        *clock += 1;
+        let Some(entry) = entries.get_mut(key) else {
+            *misses += 1;
+            return None;
+        };
+        entry.last_used = *clock;
+        if entry.plan.is_some() && entry.version != version {
+            entry.plan = None;
+            *invalidations += 1;
+        }
+        match &entry.plan {
+            Some(plan) => {
+                entry.hits += 1;
+                *hits += 1;
+                Some(Arc::clone(plan))
+            }
+            None => {
+                entry.misses += 1;
+                *misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Cache `plan`, built for `key` at catalog version `version` after `get` missed.
+    pub fn insert(&self, key: CacheKey, plan: Arc<Plan>, version: u64) {
+        if !self.enabled() {
+            return;
+        }
+        let mut inner = self.inner();
+        let clock = inner.clock;
+        if let Some(entry) = inner.entries.get_mut(&key) {
+            entry.plan = Some(plan);
+            entry.version = version;
+            return;
+        }
+        if inner.entries.len() >= self.capacity {
+            let oldest = inner.entries.iter().min_by_key(|(_, e)| e.last_used).map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                inner.entries.remove(&oldest);
+                inner.evictions += 1;
+            }
+        }
+        inner.entries.insert(
+            key,
+            Entry {
+                plan: Some(plan),
+                version,
+                hits: 0,
+                misses: 1,
+                last_used: clock,
+            },
+        );
+    }
+
+    /// Every cached statement, most used first, with its validity at catalog version
+    /// `version`.
+    pub fn entries(&self, version: u64) -> Vec<EntryStats> {
+        let inner = self.inner();
+        let mut entries: Vec<EntryStats> = inner
+            .entries
+            .iter()
+            .map(|(key, e)| EntryStats {
+                fingerprint: key.fingerprint(),
+                query: key.query.clone(),
+                param_types: key.param_types.clone(),
+                hits: e.hits,
+                misses: e.misses,
+                valid: e.plan.is_some() && e.version == version,
+            })
+            .collect();
+        entries.sort_by(|a, b| (b.hits, &a.query).cmp(&(a.hits, &b.query)));
+        entries
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner();
+        CacheStats {
+            entries: inner.entries.len(),
+            capacity: self.capacity,
+            hits: inner.hits,
+            misses: inner.misses,
+            invalidations: inner.invalidations,
+            evictions: inner.evictions,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::plan::Node;
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+
+    fn normalized(sql: &str) -> Option<(String, Vec<Value>)> {
+        let stmt = Parser::parse_sql(&GenericDialect {}, sql).unwrap().pop().unwrap();
+        normalize(&stmt).map(|n| (n.statement.to_string(), n.params))
+    }
+
+    #[test]
+    fn normalizes_literals_in_value_positions() {
+        let sql = "select a, 'x' from t where b = 5 and c in (1, -2.5) order by 1 limit 10";
+        let (text, params) = normalized(sql).unwrap();
+        assert_eq!(text, "SELECT a, 'x' FROM t WHERE b = $1 AND c IN ($2, $3) ORDER BY 1 LIMIT 10");
+        assert_eq!(params, vec![Value::Int(5), Value::Int(1), Value::Float(-2.5)]);
+        let (other, _) = normalized("SELECT a, 'x' FROM t WHERE b = 7 AND c IN (3, 4) ORDER BY 1 LIMIT 10").unwrap();
+        assert_eq!(other, text);
+
+        let (text, params) = normalized("INSERT INTO t VALUES (1, 'a', NULL), (2, 'b', 3 + 4)").unwrap();
+        assert_eq!(text, "INSERT INTO t VALUES ($1, $2, NULL), ($3, $4, $5 + $6)");
+        assert_eq!(params.len(), 6);
+        let (text, _) = normalized("UPDATE t SET v = 3, w = w * 2 WHERE k BETWEEN 1 AND 9").unwrap();
+        assert_eq!(text, "UPDATE t SET v = $1, w = w * $2 WHERE k BETWEEN $3 AND $4");
+        let (text, _) = normalized("SELECT upper(name), count(*) FROM t GROUP BY 1").unwrap();
+        assert_eq!(text, "SELECT upper(name), count(*) FROM t GROUP BY 1");
+
+        assert_eq!(normalized("SELECT a FROM t WHERE b = $1"), None);
+        assert_eq!(normalized("CREATE TABLE t (a INT)"), None);
+    }
+
+    fn plan(rows: f64) -> Arc<Plan> {
+        Arc::new(Plan {
+            node: Node::Values { rows: vec![] },
+            children: vec![],
+            columns: vec![],
+            est_rows: rows,
+            est_cost: 0.0,
+        })
+    }
+
+    fn key(query: &str) -> CacheKey {
+        CacheKey {
+            query: query.to_string(),
+            param_types: vec![ColumnType::Int],
+            parallel_workers: 1,
+        }
+    }
+
+    #[test]
+    fn hits_misses_invalidation_and_eviction() {
+        let cache = PlanCache::new(2);
+        assert!(cache.get(&key("a"), 0).is_none());
+        cache.insert(key("a"), plan(1.0), 0);
+        assert_eq!(cache.get(&key("a"), 0).unwrap().est_rows, 1.0);
+        assert_eq!(cache.get(&key("a"), 0).unwrap().est_rows, 1.0);
+        // The catalog changed: planned again, counts kept.
+        assert!(cache.get(&key("a"), 1).is_none());
+        cache.insert(key("a"), plan(2.0), 1);
+        assert_eq!(cache.get(&key("a"), 1).unwrap().est_rows, 2.0);
+        let entries = cache.entries(1);
+        assert_eq!((entries[0].hits, entries[0].misses, entries[0].valid), (3, 2, true));
+        assert!(!cache.entries(2)[0].valid);
+
+        // "a" was used last, so "b" makes room for "c".
+        assert!(cache.get(&key("b"), 1).is_none());
+        cache.insert(key("b"), plan(3.0), 1);
+        assert!(cache.get(&key("a"), 1).is_some());
+        assert!(cache.get(&key("c"), 1).is_none());
+        cache.insert(key("c"), plan(4.0), 1);
+        assert!(cache.get(&key("b"), 1).is_none());
+        assert!(cache.get(&key("a"), 1).is_some());
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                entries: 2,
+                capacity: 2,
+                hits: 5,
+                misses: 5,
+                invalidations: 1,
+                evictions: 1,
+            }
+        );
+        assert_ne!(key("a").fingerprint(), key("b").fingerprint());
+        let parallel = CacheKey { parallel_workers: 4, ..key("a") };
+        assert_ne!(key("a").fingerprint(), parallel.fingerprint());
+        assert_eq!(key("a").fingerprint(), key("a").fingerprint());
+
+        let off = PlanCache::new(0);
+        off.insert(key("a"), plan(1.0), 0);
+        assert!(!off.enabled() && off.entries(0).is_empty());
+    }
+}
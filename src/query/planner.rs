@@ -0,0 +1,3197 @@
+//! Planner: binds sqlparser ASTs against the catalog and builds physical plans.
+//!
+//! Columns of a query block get global ids: each FROM relation owns a contiguous range,
+//! in FROM order. Expressions are bound to global ids first; `optimizer` picks the join
+//! tree and each expression is then rewritten to positions in the rows of the operator
+//! that evaluates it.
+//!
+//! Subqueries in WHERE of the form `EXISTS`, `NOT EXISTS` and `IN` over a plain SELECT
+//! become semi and anti joins, their correlated conjuncts the join condition. Any other
+//! subquery is planned on its own and run by an `Apply` node per row of its enclosing
+//! block, with the outer values it reads bound first (see `Outer`). WITH queries and views
+//! are planned wherever they are referenced, like subqueries in FROM.
+
+use anyhow::{bail, Result};
+use sqlparser::ast::{
+    self, FromTable, JoinConstraint, JoinOperator, LockClause, LockType, Offset, OnInsert, OrderByExpr, Query,
+    Select, SelectItem, SetExpr, SetOperator, SetQuantifier, Statement, TableFactor, TableWithJoins,
+};
+use sqlparser::dialect::GenericDialect;
+use sqlparser::parser::Parser;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::catalog::{ColumnDef, TableDef, ViewDef};
+use crate::db::Database;
+use crate::query::aggregate::{AggCall, AggFunc};
+use crate::query::ddl::{column_type, ident, object_name};
+use crate::query::expr::{BinaryOp, Expr, UnaryOp};
+use crate::query::function::{self, ParamType, ScalarFunc};
+use crate::query::optimizer::{
+    remap, row_width, sort_cost, sort_spill_cost, top_n_cost, JoinPlanner, Planned, Relation, Source,
+    CPU_OPERATOR_COST, CPU_TUPLE_COST, RANDOM_PAGE_COST, SEQ_PAGE_COST,
+};
+use crate::query::parallel;
+use crate::query::plan::{AggStrategy, ApplyKind, JoinType, Node, OnConflict, Plan, SetOpKind, SortKey};
+use crate::query::stats::selectivity;
+use crate::query::system;
+use crate::query::table_fn::TableFunc;
+use crate::query::udf::FunctionRegistry;
+use crate::query::view;
+use crate::query::window::{Frame, FrameBound, FrameUnits, WindowCall, WindowFunc};
+use crate::sequence::{SequenceOp, SessionSequences};
+use crate::storage::{ColumnType, Value, PAGE_SIZE};
+use crate::txn::LockMode;
+
+/// Rows assumed for a table function, which has no statistics.
+const TABLE_FUNCTION_ROWS: f64 = 1000.0;
+
+/// Steps a recursive query is assumed to take, each producing as many rows as its
+/// recursive term is estimated to.
+const RECURSIVE_STEPS: f64 = 10.0;
+
+/// A relation in scope: its name for qualified references and its columns.
+#[derive(Debug, Clone)]
+struct ScopeRel {
+    name: String,
+    columns: Vec<(String, ColumnType)>,
+    hidden: Vec<bool>,
+    offset: usize,
+    /// False for the right side of a semi or anti join once its ON condition is bound.
+    visible: bool,
+}
+
+/// Names visible to expressions of one query block.
+#[derive(Debug, Clone, Default)]
+struct Scope {
+    rels: Vec<ScopeRel>,
+    /// Global id of the first column. The columns of a subquery turned into a join follow
+    /// those of its enclosing block.
+    base: usize,
+    /// The block this one is a subquery of, whose columns its expressions may also name.
+    outer: Option<Rc<Outer>>,
+    /// Parameter types of the statement, shared by all its blocks.
+    params: Rc<Params>,
+    /// User-defined functions callable by the statement.
+    functions: Arc<FunctionRegistry>,
+    /// The sequences `nextval` and friends are called on, for the session planning the
+    /// statement. None where those functions are not allowed.
+    sequences: Option<SessionSequences>,
+    /// Types of the values bound as columns past those of `rels`: subquery values (see
+    /// `SUBLINK_COLUMNS`) and aggregate outputs (see `GROUPED_COLUMNS`).
+    late_types: RefCell<HashMap<usize, ColumnType>>,
+}
+
+/// Types of the parameters `$1`, `$2`, ... of a statement: declared, or taken from the
+/// first expression a parameter is compared with, assigned to or combined with. One used
+/// on its own is TEXT.
+#[derive(Debug, Default)]
+struct Params {
+    types: RefCell<Vec<Option<ColumnType>>>,
+}
+
+impl Params {
+    /// The type of parameter `index`, fixed to `ty` if it has none yet.
+    fn resolve(&self, index: usize, ty: ColumnType) -> ColumnType {
+        let mut types = self.types.borrow_mut();
+        if types.len() <= index {
+            types.resize(index + 1, None);
+        }
+        *types[index].get_or_insert(ty)
+    }
+}
+
+/// Index of a `$n` placeholder. `?` placeholders are numbered before parsing.
+fn param_index(p: &str) -> Result<usize> {
+    match p.strip_prefix('$').map(str::parse::<usize>) {
+        Some(Ok(n)) if n > 0 => Ok(n - 1),
+        _ => bail!("unsupported parameter placeholder {}", p),
+    }
+}
+
+/// The query block a subquery is nested in, and the values the subquery reads from its
+/// current row: `Expr::Outer { index: i }` stands for `params[i]`, an expression of that
+/// block.
+#[derive(Debug)]
+struct Outer {
+    scope: Scope,
+    params: RefCell<Vec<Expr>>,
+}
+
+impl Outer {
+    fn new(scope: &Scope) -> Rc<Outer> {
+        Rc::new(Outer {
+            scope: scope.clone(),
+            params: RefCell::default(),
+        })
+    }
+
+    /// The outer reference standing for `e`.
+    fn param(&self, e: Expr) -> Expr {
+        let ty = e.data_type(&self.scope.types());
+        let mut params = self.params.borrow_mut();
+        let index = match params.iter().position(|p| *p == e) {
+            Some(i) => i,
+            None => {
+                params.push(e);
+                params.len() - 1
+            }
+        };
+        Expr::Outer { index, ty }
+    }
+}
+
+impl Scope {
+    fn width(&self) -> usize {
+        self.rels.last().map_or(self.base, |r| r.offset + r.columns.len())
+    }
+
+    /// Column types, by global id.
+    fn types(&self) -> Vec<ColumnType> {
+        let mut out = vec![ColumnType::Text; self.width()];
+        for r in &self.rels {
+            for (i, (_, t)) in r.columns.iter().enumerate() {
+                out[r.offset + i] = *t;
+            }
+        }
+        out
+    }
+
+    /// Type of the column with global id `id`, if known yet.
+    fn column_type(&self, id: usize) -> Option<ColumnType> {
+        for r in &self.rels {
+            if let Some((_, t)) = id.checked_sub(r.offset).and_then(|i| r.columns.get(i)) {
+                return Some(*t);
+            }
+        }
+        self.late_types.borrow().get(&id).copied()
+    }
+
+    /// Type `e` as `ty` if it is a parameter without a type yet.
+    fn hint(&self, e: &ast::Expr, ty: ColumnType) {
+        if let ast::Expr::Value(ast::Value::Placeholder(p)) = e {
+            if let Ok(index) = param_index(p) {
+                self.params.resolve(index, ty);
+            }
+        }
+    }
+
+    fn add(&mut self, name: String, columns: Vec<(String, ColumnType)>, hidden: Vec<bool>) -> Result<usize> {
+        if self.rels.iter().any(|r| r.name == name) {
+            bail!("table name {} specified more than once", name);
+        }
+        let offset = self.width();
+        self.rels.push(ScopeRel {
+            name,
+            columns,
+            hidden,
+            offset,
+            visible: true,
+        });
+        Ok(offset)
+    }
+
+    /// Global id of a (possibly qualified) column reference.
+    fn resolve(&self, parts: &[ast::Ident]) -> Result<usize> {
+        let names: Vec<String> = parts.iter().map(ident).collect();
+        let (rel, col) = match names.as_slice() {
+            [col] => (None, col),
+            [rel, col] => (Some(rel), col),
+            _ => bail!("unsupported column reference {}", names.join(".")),
+        };
+        match (self.find(rel, col)?, rel) {
+            (Some(id), _) => Ok(id),
+            (None, Some(rel)) if !self.rels.iter().any(|r| r.visible && r.name == *rel) => {
+                bail!("missing FROM-clause entry for table {}", rel)
+            }
+            (None, Some(rel)) => bail!("column {}.{} does not exist", rel, col),
+            (None, None) => bail!("column {} does not exist", col),
+        }
+    }
+
+    /// A column reference: a column of this block or, if it has none of that name, an
+    /// outer reference to one of an enclosing block.
+    fn column(&self, parts: &[ast::Ident]) -> Result<Expr> {
+        let local = self.resolve(parts);
+        match (local, &self.outer) {
+            (Ok(id), _) => Ok(Expr::Column(id)),
+            (Err(e), Some(outer)) if !self.is_ambiguous(parts) => match outer.scope.column(parts) {
+                Ok(e) => Ok(outer.param(e)),
+                Err(_) => Err(e),
+            },
+            (Err(e), _) => Err(e),
+        }
+    }
+
+    fn is_ambiguous(&self, parts: &[ast::Ident]) -> bool {
+        let names: Vec<String> = parts.iter().map(ident).collect();
+        match names.as_slice() {
+            [col] => self.find(None, col).is_err(),
+            [rel, col] => self.find(Some(rel), col).is_err(),
+            _ => false,
+        }
+    }
+
+    /// Global id of column `col` of relation `rel` (or any visible relation), if there is
+    /// exactly one.
+    fn find(&self, rel: Option<&String>, col: &str) -> Result<Option<usize>> {
+        let mut found = None;
+        for r in self.rels.iter().filter(|r| r.visible) {
+            if rel.is_some_and(|n| *n != r.name) {
+                continue;
+            }
+            if let Some(i) = r.columns.iter().position(|(n, _)| n == col) {
+                if found.is_some() {
+                    bail!("column reference {} is ambiguous", col);
+                }
+                found = Some(r.offset + i);
+            }
+        }
+        Ok(found)
+    }
+}
+
+fn binary_op(op: &ast::BinaryOperator) -> Result<BinaryOp> {
+    use ast::BinaryOperator as B;
+    Ok(match op {
+        B::Plus => BinaryOp::Plus,
+        B::Minus => BinaryOp::Minus,
+        B::Multiply => BinaryOp::Multiply,
+        B::Divide => BinaryOp::Divide,
+        B::Modulo => BinaryOp::Modulo,
+        B::Eq => BinaryOp::Eq,
+        B::NotEq => BinaryOp::NotEq,
+        B::Lt => BinaryOp::Lt,
+        B::LtEq => BinaryOp::LtEq,
+        B::Gt => BinaryOp::Gt,
+        B::GtEq => BinaryOp::GtEq,
+        B::And => BinaryOp::And,
+        B::Or => BinaryOp::Or,
+        B::StringConcat => BinaryOp::Concat,
+        other => bail!("unsupported operator {}", other),
+    })
+}
+
+/// Literal value of a SQL number, INT if it fits.
+pub fn number(s: &str) -> Result<Value> {
+    if let Ok(n) = s.parse::<i64>() {
+        return Ok(Value::Int(n));
+    }
+    match s.parse::<f64>() {
+        Ok(x) => Ok(Value::Float(x)),
+        Err(_) => bail!("invalid number {}", s),
+    }
+}
+
+fn literal(v: &ast::Value) -> Result<Value> {
+    Ok(match v {
+        ast::Value::Number(s, _) => number(s)?,
+        ast::Value::SingleQuotedString(s) => Value::Text(s.clone()),
+        ast::Value::Boolean(b) => Value::Bool(*b),
+        ast::Value::Null => Value::Null,
+        other => bail!("unsupported literal {}", other),
+    })
+}
+
+/// Whether `e` is the DEFAULT keyword, as it appears in the VALUES of an INSERT.
+fn is_default(e: &ast::Expr) -> bool {
+    matches!(e, ast::Expr::Identifier(i) if i.quote_style.is_none() && i.value.eq_ignore_ascii_case("default"))
+}
+
+/// Bind a scalar expression in `scope`.
+fn bind(scope: &Scope, e: &ast::Expr) -> Result<Expr> {
+    bind_with(scope, e, &mut |_| Ok(None))
+}
+
+/// Consulted on every sub-expression before it is bound; `Some` is used in its place.
+type BindHook<'h> = dyn FnMut(&ast::Expr) -> Result<Option<Expr>> + 'h;
+
+/// Bind a scalar expression in `scope`, letting `hook` bind parts of it.
+fn bind_with(scope: &Scope, e: &ast::Expr, hook: &mut BindHook) -> Result<Expr> {
+    use ast::Expr as E;
+    if let Some(bound) = hook(e)? {
+        return Ok(bound);
+    }
+    let mut b = |e: &ast::Expr| bind_with(scope, e, hook).map(Box::new);
+    Ok(match e {
+        E::Identifier(id) => scope.column(std::slice::from_ref(id))?,
+        E::CompoundIdentifier(parts) => scope.column(parts)?,
+        E::Value(ast::Value::Placeholder(p)) => {
+            let index = param_index(p)?;
+            Expr::Param {
+                index,
+                ty: scope.params.resolve(index, ColumnType::Text),
+            }
+        }
+        E::Value(v) => Expr::Literal(literal(v)?),
+        E::Nested(e) => *b(e)?,
+        E::BinaryOp { left, op, right } if regex_op(op).is_some() => {
+            let Some((func, negated)) = regex_op(op) else {
+                unreachable!()
+            };
+            let call = scalar_call(scope, func, &[left, right], &mut |e| b(e).map(|e| *e))?;
+            not_if(negated, call)
+        }
+        E::BinaryOp { left, op, right } => {
+            let op = binary_op(op)?;
+            let hint = |e: &Expr| match op {
+                BinaryOp::And | BinaryOp::Or => ColumnType::Bool,
+                _ => e.data_type(&scope.types()),
+            };
+            // A parameter takes the type of the other operand.
+            let (left, right) = if matches!(&**left, E::Value(ast::Value::Placeholder(_))) {
+                let right = b(right)?;
+                scope.hint(left, hint(&right));
+                (b(left)?, right)
+            } else {
+                let left = b(left)?;
+                scope.hint(right, hint(&left));
+                (left, b(right)?)
+            };
+            Expr::Binary { op, left, right }
+        }
+        E::UnaryOp { op, expr } => match (op, &**expr) {
+            // Fold the sign into numeric literals so i64::MIN can be written.
+            (ast::UnaryOperator::Minus, E::Value(ast::Value::Number(s, _))) => {
+                Expr::Literal(number(&format!("-{}", s))?)
+            }
+            (ast::UnaryOperator::Minus, _) => Expr::Unary {
+                op: UnaryOp::Neg,
+                expr: b(expr)?,
+            },
+            (ast::UnaryOperator::Plus, _) => *b(expr)?,
+            (ast::UnaryOperator::Not, _) => Expr::Unary {
+                op: UnaryOp::Not,
+                expr: b(expr)?,
+            },
+            (op, _) => bail!("unsupported operator {}", op),
+        },
+        E::IsNull(e) => Expr::IsNull {
+            expr: b(e)?,
+            negated: false,
+        },
+        E::IsNotNull(e) => Expr::IsNull {
+            expr: b(e)?,
+            negated: true,
+        },
+        E::InList { expr, list, negated } => {
+            let expr = b(expr)?;
+            let ty = expr.data_type(&scope.types());
+            list.iter().for_each(|e| scope.hint(e, ty));
+            Expr::InList {
+                expr,
+                list: list.iter().map(|e| b(e).map(|e| *e)).collect::<Result<_>>()?,
+                negated: *negated,
+            }
+        }
+        E::Between {
+            expr,
+            negated,
+            low,
+            high,
+        } => {
+            let expr = b(expr)?;
+            let ty = expr.data_type(&scope.types());
+            scope.hint(low, ty);
+            scope.hint(high, ty);
+            Expr::Between {
+                expr,
+                low: b(low)?,
+                high: b(high)?,
+                negated: *negated,
+            }
+        }
+        E::Like {
+            negated,
+            expr,
+            pattern,
+            escape_char,
+        }
+        | E::ILike {
+            negated,
+            expr,
+            pattern,
+            escape_char,
+        } => {
+            let func = if matches!(e, E::Like { .. }) { ScalarFunc::Like } else { ScalarFunc::ILike };
+            let mut call = scalar_call(scope, func, &[expr, pattern], &mut |e| b(e).map(|e| *e))?;
+            if let (Some(c), Expr::Function { args, .. }) = (escape_char, &mut call) {
+                args.push(Expr::Literal(Value::Text(c.to_string())));
+            }
+            not_if(*negated, call)
+        }
+        E::Cast {
+            expr,
+            data_type,
+            format: None,
+        } => {
+            let ty = column_type(data_type)?;
+            scope.hint(expr, ty);
+            let expr = b(expr)?;
+            function::cast_type(arg_type(scope, &expr), ty)?;
+            Expr::Cast { expr, ty }
+        }
+        E::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => {
+            let operand = operand.as_deref().map(&mut b).transpose()?;
+            let mut branches = Vec::new();
+            for when in conditions {
+                match &operand {
+                    Some(o) => scope.hint(when, arg_type(scope, o).unwrap_or(ColumnType::Text)),
+                    None => scope.hint(when, ColumnType::Bool),
+                }
+                let when = *b(when)?;
+                match (&operand, arg_type(scope, &when)) {
+                    (None, Some(ty)) if ty != ColumnType::Bool => {
+                        bail!("argument of CASE/WHEN must be type BOOL, not type {}", ty)
+                    }
+                    _ => {}
+                }
+                branches.push(when);
+            }
+            let outcomes: Vec<&ast::Expr> = results.iter().chain(else_result.as_deref()).collect();
+            let (mut outcomes, ty) = bind_same(scope, "CASE", &outcomes, &mut |e| b(e).map(|e| *e))?;
+            let else_result = match else_result {
+                Some(_) => outcomes.pop().map(Box::new),
+                None => None,
+            };
+            Expr::Case {
+                operand,
+                branches: branches.into_iter().zip(outcomes).collect(),
+                else_result,
+                ty,
+            }
+        }
+        E::Substring {
+            expr,
+            substring_from,
+            substring_for,
+            ..
+        } => {
+            let one = E::Value(ast::Value::Number("1".into(), false));
+            let mut args = vec![&**expr, substring_from.as_deref().unwrap_or(&one)];
+            args.extend(substring_for.as_deref());
+            scalar_call(scope, ScalarFunc::Substr, &args, &mut |e| b(e).map(|e| *e))?
+        }
+        E::Trim {
+            expr,
+            trim_where,
+            trim_what,
+            trim_characters,
+        } => {
+            let func = match trim_where {
+                None | Some(ast::TrimWhereField::Both) => ScalarFunc::Trim,
+                Some(ast::TrimWhereField::Leading) => ScalarFunc::Ltrim,
+                Some(ast::TrimWhereField::Trailing) => ScalarFunc::Rtrim,
+            };
+            let mut args = vec![&**expr];
+            match (trim_what, trim_characters.as_deref()) {
+                (Some(what), None) => args.push(what),
+                (None, Some([what])) => args.push(what),
+                (None, None) => {}
+                _ => bail!("unsupported expression: {}", e),
+            }
+            scalar_call(scope, func, &args, &mut |e| b(e).map(|e| *e))?
+        }
+        E::Position { expr, r#in } => {
+            scalar_call(scope, ScalarFunc::Position, &[expr, r#in], &mut |e| b(e).map(|e| *e))?
+        }
+        E::Function(f) if f.over.is_some() => bail!("window functions are not allowed here: {}", f),
+        E::Function(f) if scope.functions.aggregate(&object_name(&f.name)?).is_some() => {
+            bail!("aggregate function calls are not allowed here: {}", f)
+        }
+        E::Function(f) if scope.functions.window(&object_name(&f.name)?).is_some() => {
+            bail!("window function {} requires an OVER clause", f.name)
+        }
+        E::Function(f) => {
+            let name = object_name(&f.name)?;
+            let mut args = Vec::new();
+            for a in &f.args {
+                match a {
+                    ast::FunctionArg::Unnamed(ast::FunctionArgExpr::Expr(e)) => args.push(e),
+                    other => bail!("unsupported argument {} to {}", other, name),
+                }
+            }
+            let func = match (SequenceOp::from_name(&name), &scope.sequences) {
+                (Some(op), Some(sequences)) => Some(ScalarFunc::Sequence(sequences.func(op))),
+                (Some(_), None) => bail!("function {} is not allowed here", name),
+                (None, _) => scope.functions.scalar(&name),
+            };
+            let Some(func) = func else {
+                let mut types = Vec::new();
+                for e in args {
+                    types.push(arg_type(scope, &*b(e)?).map_or("unknown".to_string(), |t| t.to_string()));
+                }
+                bail!("function {}({}) does not exist", name, types.join(", "));
+            };
+            if f.distinct || f.filter.is_some() || !f.order_by.is_empty() || f.null_treatment.is_some() {
+                bail!("{} is not an aggregate function: {}", name, f);
+            }
+            scalar_call(scope, func, &args, &mut |e| b(e).map(|e| *e))?
+        }
+        E::Subquery(_) | E::Exists { .. } | E::InSubquery { .. } => bail!("subqueries are not supported here: {}", e),
+        other => bail!("unsupported expression: {}", other),
+    })
+}
+
+/// The function a regular-expression match operator calls, and whether it is negated.
+fn regex_op(op: &ast::BinaryOperator) -> Option<(ScalarFunc, bool)> {
+    use ast::BinaryOperator as B;
+    match op {
+        B::PGRegexMatch => Some((ScalarFunc::RegexMatch, false)),
+        B::PGRegexIMatch => Some((ScalarFunc::RegexIMatch, false)),
+        B::PGRegexNotMatch => Some((ScalarFunc::RegexMatch, true)),
+        B::PGRegexNotIMatch => Some((ScalarFunc::RegexIMatch, true)),
+        _ => None,
+    }
+}
+
+fn not_if(negated: bool, e: Expr) -> Expr {
+    if negated {
+        Expr::Unary {
+            op: UnaryOp::Not,
+            expr: Box::new(e),
+        }
+    } else {
+        e
+    }
+}
+
+/// Type of bound expression `e`, or None for a bare NULL and for one reading a value whose
+/// type is not known yet (a window function's), which any function parameter accepts.
+fn arg_type(scope: &Scope, e: &Expr) -> Option<ColumnType> {
+    if matches!(e, Expr::Literal(Value::Null)) || e.columns().iter().any(|c| scope.column_type(*c).is_none()) {
+        return None;
+    }
+    Some(e.data_type_with(&|i| scope.column_type(i).unwrap_or(ColumnType::Text)))
+}
+
+/// Bind `exprs`, whose values share a type as the results of `what`: parameters among them
+/// take the type of the others. Returns the bound expressions and their common type.
+fn bind_same(
+    scope: &Scope,
+    what: &str,
+    exprs: &[&ast::Expr],
+    bind: &mut dyn FnMut(&ast::Expr) -> Result<Expr>,
+) -> Result<(Vec<Expr>, ColumnType)> {
+    let is_param = |e: &ast::Expr| matches!(e, ast::Expr::Value(ast::Value::Placeholder(_)));
+    let mut bound = Vec::new();
+    for e in exprs {
+        bound.push(if is_param(e) { None } else { Some(bind(e)?) });
+    }
+    let known: Vec<_> = bound.iter().flatten().map(|e| arg_type(scope, e)).collect();
+    let ty = function::common_type(what, &known)?;
+    for (e, b) in exprs.iter().zip(&mut bound) {
+        if b.is_none() {
+            scope.hint(e, ty);
+            *b = Some(bind(e)?);
+        }
+    }
+    let bound: Vec<Expr> = bound.into_iter().flatten().collect();
+    let types: Vec<_> = bound.iter().map(|e| arg_type(scope, e)).collect();
+    let ty = function::common_type(what, &types)?;
+    Ok((bound, ty))
+}
+
+/// Bind a call of `func` with arguments `args`, checked against its signatures. Parameters
+/// among the arguments are typed by the signature, or after the other arguments.
+fn scalar_call(
+    scope: &Scope,
+    func: ScalarFunc,
+    args: &[&ast::Expr],
+    bind: &mut dyn FnMut(&ast::Expr) -> Result<Expr>,
+) -> Result<Expr> {
+    let is_param = |e: &ast::Expr| matches!(e, ast::Expr::Value(ast::Value::Placeholder(_)));
+    let mut bound = Vec::new();
+    for e in args {
+        bound.push(if is_param(e) { None } else { Some(bind(e)?) });
+    }
+    let same: Vec<_> = bound
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| func.param_type(args.len(), *i) == Some(ParamType::Same))
+        .filter_map(|(_, e)| e.as_ref().map(|e| arg_type(scope, e)))
+        .collect();
+    let same = function::common_type(func.name(), &same).ok();
+    for (i, e) in args.iter().enumerate() {
+        if bound[i].is_none() {
+            match func.param_type(args.len(), i) {
+                Some(ParamType::Is(ty)) => scope.hint(e, ty),
+                Some(ParamType::Same) => scope.hint(e, same.unwrap_or(ColumnType::Text)),
+                _ => {}
+            }
+            bound[i] = Some(bind(e)?);
+        }
+    }
+    let args: Vec<Expr> = bound.into_iter().flatten().collect();
+    let types: Vec<_> = args.iter().map(|e| arg_type(scope, e)).collect();
+    let ty = func.result_type(&types)?;
+    Ok(Expr::Function { func, args, ty })
+}
+
+/// A constant non-negative integer (LIMIT, OFFSET).
+fn constant_count(e: &ast::Expr, what: &str) -> Result<u64> {
+    let v = bind(&Scope::default(), e)?.eval(&[])?;
+    match v {
+        Value::Int(n) if n >= 0 => Ok(n as u64),
+        v => bail!("{} must be a non-negative integer, got {}", what, v),
+    }
+}
+
+/// Output column name of an unaliased select item, as PostgreSQL names it.
+fn output_name(e: &ast::Expr) -> String {
+    match e {
+        ast::Expr::Identifier(id) => ident(id),
+        ast::Expr::CompoundIdentifier(parts) => parts.last().map_or("?column?".into(), ident),
+        ast::Expr::Nested(e) => output_name(e),
+        ast::Expr::Function(f) => f.name.0.last().map_or("?column?".into(), ident),
+        ast::Expr::Cast { expr, .. } => output_name(expr),
+        ast::Expr::Case { .. } => "case".to_string(),
+        ast::Expr::Substring { .. } => "substring".to_string(),
+        ast::Expr::Position { .. } => "position".to_string(),
+        ast::Expr::Trim { trim_where, .. } => match trim_where {
+            Some(ast::TrimWhereField::Leading) => "ltrim",
+            Some(ast::TrimWhereField::Trailing) => "rtrim",
+            _ => "btrim",
+        }
+        .to_string(),
+        // A scalar subquery is named after its only column.
+        ast::Expr::Subquery(q) => match &*q.body {
+            SetExpr::Select(s) => match s.projection.as_slice() {
+                [SelectItem::UnnamedExpr(e)] => output_name(e),
+                [SelectItem::ExprWithAlias { alias, .. }] => ident(alias),
+                _ => "?column?".to_string(),
+            },
+            _ => "?column?".to_string(),
+        },
+        ast::Expr::Exists { .. } => "exists".to_string(),
+        _ => "?column?".to_string(),
+    }
+}
+
+/// Most elements a CUBE may have; it expands to 2^n grouping sets.
+const MAX_CUBE_ELEMENTS: usize = 12;
+
+/// A select list entry before binding.
+enum SelectExpr<'q> {
+    Ast(&'q ast::Expr),
+    /// A column of `*`, by global id.
+    Column(usize),
+}
+
+impl SelectExpr<'_> {
+    fn bind(&self, scope: &Scope) -> Result<Expr> {
+        match self {
+            SelectExpr::Ast(e) => bind(scope, e),
+            SelectExpr::Column(gid) => Ok(Expr::Column(*gid)),
+        }
+    }
+}
+
+/// The select item an ORDER BY expression names by position or alias, if any.
+fn output_column<T>(e: &ast::Expr, items: &[(String, T)]) -> Result<Option<usize>> {
+    Ok(match e {
+        ast::Expr::Value(ast::Value::Number(n, _)) => match n.parse::<usize>() {
+            Ok(i) if (1..=items.len()).contains(&i) => Some(i - 1),
+            _ => bail!("ORDER BY position {} is not in select list", n),
+        },
+        ast::Expr::Identifier(id) if items.iter().filter(|(n, _)| *n == ident(id)).count() == 1 => {
+            items.iter().position(|(n, _)| *n == ident(id))
+        }
+        _ => None,
+    })
+}
+
+/// Whether column `i` of `plan` is a bare NULL, which takes its type from the other side of
+/// a set operation.
+fn untyped_null(plan: &Plan, i: usize) -> bool {
+    let null = |e: &Expr| matches!(e, Expr::Literal(Value::Null));
+    match &plan.node {
+        Node::Project { exprs } => exprs.get(i).is_some_and(null),
+        Node::Values { rows } => rows.iter().all(|r| r.get(i).is_some_and(null)),
+        _ => false,
+    }
+}
+
+/// The aggregate a function call makes, if it is one; arguments are bound in `scope`.
+fn aggregate_call(scope: &Scope, f: &ast::Function) -> Result<Option<AggCall>> {
+    let Some(mut func) = scope.functions.aggregate(&object_name(&f.name)?) else {
+        return Ok(None);
+    };
+    if f.over.is_some() {
+        return Ok(None);
+    }
+    if !f.order_by.is_empty() || f.null_treatment.is_some() {
+        bail!("unsupported aggregate syntax: {}", f);
+    }
+    let mut args = Vec::new();
+    for a in &f.args {
+        match a {
+            ast::FunctionArg::Unnamed(ast::FunctionArgExpr::Expr(e)) => args.push(bind(scope, e)?),
+            ast::FunctionArg::Unnamed(ast::FunctionArgExpr::Wildcard)
+                if func == AggFunc::Count && f.args.len() == 1 && !f.distinct =>
+            {
+                func = AggFunc::CountStar
+            }
+            other => bail!("unsupported argument {} to {}", other, func.name()),
+        }
+    }
+    let types = scope.types();
+    func.result_type(&args.iter().map(|a| a.data_type(&types)).collect::<Vec<_>>())?;
+    let filter = match &f.filter {
+        Some(e) => Some(bind(scope, e)?),
+        None => None,
+    };
+    Ok(Some(AggCall {
+        func,
+        args,
+        distinct: f.distinct,
+        filter,
+    }))
+}
+
+/// Whether `e` calls an aggregate function (outside any that fail to bind). An aggregate
+/// with OVER is a window function, though its arguments may still hold aggregates; those
+/// of a subquery belong to the subquery.
+fn has_aggregate(scope: &Scope, e: &ast::Expr) -> bool {
+    let mut found = false;
+    let _ = bind_with(scope, e, &mut |node| {
+        match node {
+            ast::Expr::Subquery(_) | ast::Expr::Exists { .. } => return Ok(Some(Expr::Literal(Value::Null))),
+            ast::Expr::InSubquery { expr, .. } => {
+                found |= has_aggregate(scope, expr);
+                return Ok(Some(Expr::Literal(Value::Null)));
+            }
+            _ => {}
+        }
+        if let ast::Expr::Function(f) = node {
+            if let Some(ast::WindowType::WindowSpec(spec)) = &f.over {
+                let args = f.args.iter().filter_map(|a| match a {
+                    ast::FunctionArg::Unnamed(ast::FunctionArgExpr::Expr(e)) => Some(e),
+                    _ => None,
+                });
+                found |= args
+                    .chain(f.filter.as_deref())
+                    .chain(&spec.partition_by)
+                    .chain(spec.order_by.iter().map(|o| &o.expr))
+                    .any(|e| has_aggregate(scope, e));
+                return Ok(Some(Expr::Literal(Value::Null)));
+            }
+            if object_name(&f.name).is_ok_and(|n| scope.functions.aggregate(&n).is_some()) {
+                found = true;
+                return Ok(Some(Expr::Literal(Value::Null)));
+            }
+        }
+        Ok(None)
+    });
+    found
+}
+
+/// PARTITION BY and ORDER BY of a window, over the rows its `Window` node reads.
+#[derive(Debug, Clone, PartialEq)]
+struct WindowDef {
+    partition_by: Vec<Expr>,
+    order_by: Vec<SortKey>,
+}
+
+/// Binds an expression over the rows a `Window` node reads.
+type BaseBinder<'b> = dyn FnMut(&ast::Expr) -> Result<Expr> + 'b;
+
+/// A constant frame offset.
+fn frame_bound(b: &ast::WindowFrameBound) -> Result<FrameBound> {
+    let offset = |e: &ast::Expr| -> Result<Value> {
+        match bind(&Scope::default(), e)?.eval(&[])? {
+            Value::Null => bail!("frame offset must not be null"),
+            v => Ok(v),
+        }
+    };
+    Ok(match b {
+        ast::WindowFrameBound::CurrentRow => FrameBound::CurrentRow,
+        ast::WindowFrameBound::Preceding(None) => FrameBound::UnboundedPreceding,
+        ast::WindowFrameBound::Preceding(Some(e)) => FrameBound::Preceding(offset(e)?),
+        ast::WindowFrameBound::Following(None) => FrameBound::UnboundedFollowing,
+        ast::WindowFrameBound::Following(Some(e)) => FrameBound::Following(offset(e)?),
+    })
+}
+
+/// The window function call `f` makes, if it has OVER; its arguments, PARTITION BY and
+/// ORDER BY are bound by `base`. Types and the frame are checked once the window's input
+/// is planned.
+fn window_call(
+    functions: &FunctionRegistry,
+    f: &ast::Function,
+    base: &mut BaseBinder,
+) -> Result<Option<(WindowDef, WindowCall)>> {
+    let Some(over) = &f.over else {
+        return Ok(None);
+    };
+    let name = object_name(&f.name)?;
+    let Some(mut func) = functions.window(&name) else {
+        bail!("function {} is not a window function", name);
+    };
+    let ast::WindowType::WindowSpec(spec) = over else {
+        bail!("named windows are not supported: {}", f);
+    };
+    if spec.window_name.is_some() {
+        bail!("named windows are not supported: {}", f);
+    }
+    if f.distinct {
+        bail!("DISTINCT is not implemented for window functions");
+    }
+    if !f.order_by.is_empty() || f.null_treatment.is_some() {
+        bail!("unsupported window function syntax: {}", f);
+    }
+    let mut args = Vec::new();
+    for a in &f.args {
+        match a {
+            ast::FunctionArg::Unnamed(ast::FunctionArgExpr::Expr(e)) => args.push(base(e)?),
+            ast::FunctionArg::Unnamed(ast::FunctionArgExpr::Wildcard)
+                if func == WindowFunc::Aggregate(AggFunc::Count) && f.args.len() == 1 =>
+            {
+                func = WindowFunc::Aggregate(AggFunc::CountStar)
+            }
+            other => bail!("unsupported argument {} to {}", other, name),
+        }
+    }
+    let filter = match (&f.filter, &func) {
+        (None, _) => None,
+        (Some(e), WindowFunc::Aggregate(_)) => Some(base(e)?),
+        (Some(_), _) => bail!("FILTER is not implemented for non-aggregate window functions"),
+    };
+    let def = WindowDef {
+        partition_by: spec.partition_by.iter().map(&mut *base).collect::<Result<_>>()?,
+        order_by: spec
+            .order_by
+            .iter()
+            .map(|o| Ok(Planner::sort_key(base(&o.expr)?, o)))
+            .collect::<Result<_>>()?,
+    };
+    let frame = match &spec.window_frame {
+        None => Frame::default(),
+        Some(w) => Frame {
+            units: match w.units {
+                ast::WindowFrameUnits::Rows => FrameUnits::Rows,
+                ast::WindowFrameUnits::Range => FrameUnits::Range,
+                ast::WindowFrameUnits::Groups => bail!("GROUPS frames are not supported"),
+            },
+            start: frame_bound(&w.start_bound)?,
+            end: match &w.end_bound {
+                Some(b) => frame_bound(b)?,
+                None => FrameBound::CurrentRow,
+            },
+        },
+    };
+    Ok(Some((def, WindowCall { func, args, filter, frame })))
+}
+
+/// Add a window call to `windows` unless an equal one is there; returns its index.
+fn add_window(windows: &mut Vec<(WindowDef, WindowCall)>, w: (WindowDef, WindowCall)) -> usize {
+    match windows.iter().position(|x| *x == w) {
+        Some(i) => i,
+        None => {
+            windows.push(w);
+            windows.len() - 1
+        }
+    }
+}
+
+/// Bind `e` in `scope` with its window function calls added to `windows` (their parts
+/// bound by `base`) and replaced by `Column(first + i)` for the i-th call, and its
+/// subqueries bound by `sublink`.
+fn bind_windowed(
+    scope: &Scope,
+    e: &ast::Expr,
+    first: usize,
+    base: &mut BaseBinder,
+    windows: &mut Vec<(WindowDef, WindowCall)>,
+    sublink: &mut SublinkBinder,
+) -> Result<Expr> {
+    bind_with(scope, e, &mut |node| match node {
+        ast::Expr::Function(f) => {
+            let call = window_call(&scope.functions, f, base)?;
+            Ok(call.map(|w| Expr::Column(first + add_window(windows, w))))
+        }
+        ast::Expr::InSubquery { expr, .. } => {
+            let operand = bind_windowed(scope, expr, first, base, windows, sublink)?;
+            sublink(node, Some(operand))
+        }
+        _ => sublink(node, None),
+    })
+}
+
+/// Grouped window calls are bound as `Column(WINDOW_COLUMNS + i)` until the number of
+/// aggregates, after which their results are appended, is known.
+const WINDOW_COLUMNS: usize = usize::MAX / 2;
+
+/// The value of a subquery run per row is bound as `Column(SUBLINK_COLUMNS + i)` until
+/// the `Apply` node computing the i-th subquery of the block is planned.
+const SUBLINK_COLUMNS: usize = usize::MAX / 4;
+
+/// A subquery in an expression, planned on its own. `params` are the expressions of the
+/// enclosing block its outer references read; the left operand of IN is in `kind`.
+struct Sublink {
+    kind: ApplyKind,
+    plan: Plan,
+    params: Vec<Expr>,
+}
+
+/// The subqueries of one query block that run per row, in the order they were bound.
+#[derive(Default)]
+struct Sublinks {
+    list: Vec<Sublink>,
+    /// How many already have their `Apply` node.
+    applied: usize,
+}
+
+/// Plans the subquery an expression is, if it is one, given the bound left operand of
+/// IN; returns what stands for its value (see `Planner::sublink`).
+type SublinkBinder<'b> = dyn FnMut(&ast::Expr, Option<Expr>) -> Result<Option<Expr>> + 'b;
+
+/// A WHERE subquery turned into a semi or anti join of its block's FROM list with
+/// `right`; `filters` apply to `right` alone.
+struct SemiJoin {
+    join_type: JoinType,
+    right: Relation,
+    filters: Vec<Expr>,
+    on: Vec<Expr>,
+}
+
+/// What WHERE applies on top of a block's joined FROM list: its decorrelated subqueries,
+/// then the conjuncts that read the values of other subqueries.
+#[derive(Default)]
+struct Residual {
+    semi_joins: Vec<SemiJoin>,
+    filters: Vec<Expr>,
+}
+
+/// A WITH query in scope.
+#[derive(Debug)]
+struct Cte {
+    name: String,
+    columns: Vec<String>,
+    query: Query,
+    recursive: bool,
+    /// While its recursive term is planned: the scan of the work table that term reads
+    /// in its place.
+    work_table: Option<Plan>,
+}
+
+/// The top-level AND terms of a WHERE clause.
+fn ast_conjuncts(e: &ast::Expr) -> Vec<&ast::Expr> {
+    match e {
+        ast::Expr::BinaryOp {
+            left,
+            op: ast::BinaryOperator::And,
+            right,
+        } => {
+            let mut out = ast_conjuncts(left);
+            out.extend(ast_conjuncts(right));
+            out
+        }
+        ast::Expr::Nested(e) => ast_conjuncts(e),
+        e => vec![e],
+    }
+}
+
+/// `plan` with its first columns named `names`.
+fn rename_columns(mut plan: Plan, names: &[String], what: &str) -> Result<Plan> {
+    if names.len() > plan.columns.len() {
+        bail!(
+            "{} has {} columns available but {} columns specified",
+            what,
+            plan.columns.len(),
+            names.len()
+        );
+    }
+    for (c, n) in plan.columns.iter_mut().zip(names) {
+        c.0 = n.clone();
+    }
+    Ok(plan)
+}
+
+/// Column types of an aggregate's output: `keys`, then `aggs`.
+fn grouped_types(scope: &Scope, keys: &[Expr], aggs: &[AggCall]) -> Result<Vec<ColumnType>> {
+    let types = scope.types();
+    let mut out: Vec<ColumnType> = keys.iter().map(|k| k.data_type(&types)).collect();
+    for a in aggs {
+        let args: Vec<ColumnType> = a.args.iter().map(|e| e.data_type(&types)).collect();
+        out.push(a.func.result_type(&args)?);
+    }
+    Ok(out)
+}
+
+/// Bind an expression evaluated after aggregation, over the aggregate's output: the
+/// group `keys` (over global ids) followed by `aggs`, to which its aggregate calls are
+/// added. Other columns must be inside an aggregate or a key. Window calls go to
+/// `windows` (see `WINDOW_COLUMNS`) and subqueries to `sublink`; without them they are
+/// an error.
+fn bind_grouped(
+    scope: &Scope,
+    e: &ast::Expr,
+    keys: &[Expr],
+    aggs: &mut Vec<AggCall>,
+    mut windows: Option<&mut Vec<(WindowDef, WindowCall)>>,
+    mut sublink: Option<&mut SublinkBinder>,
+) -> Result<Expr> {
+    let bound = bind_with(scope, e, &mut |node| {
+        // A parameter compared with an aggregate takes its type from the aggregate's output.
+        if let ast::Expr::BinaryOp { left, right, .. } = node {
+            for (param, other) in [(left, right), (right, left)] {
+                if matches!(&**param, ast::Expr::Value(ast::Value::Placeholder(_))) {
+                    if let Ok(bound) = bind_grouped(scope, other, keys, aggs, None, None) {
+                        scope.hint(param, bound.data_type(&grouped_types(scope, keys, aggs)?));
+                    }
+                }
+            }
+        }
+        if let Some(sublink) = sublink.as_deref_mut() {
+            let operand = match node {
+                ast::Expr::InSubquery { expr, .. } => Some(bind_grouped(
+                    scope,
+                    expr,
+                    keys,
+                    aggs,
+                    windows.as_deref_mut(),
+                    Some(&mut *sublink),
+                )?),
+                _ => None,
+            };
+            if let Some(e) = sublink(node, operand)? {
+                return Ok(Some(e));
+            }
+        }
+        if let ast::Expr::Function(f) = node {
+            if let Some(windows) = windows.as_deref_mut() {
+                let base = &mut |e: &ast::Expr| bind_grouped(scope, e, keys, aggs, None, None);
+                if let Some(w) = window_call(&scope.functions, f, base)? {
+                    return Ok(Some(Expr::Column(WINDOW_COLUMNS + add_window(windows, w))));
+                }
+            }
+            if let Some(call) = aggregate_call(scope, f)? {
+                let i = match aggs.iter().position(|a| *a == call) {
+                    Some(i) => i,
+                    None => {
+                        aggs.push(call);
+                        aggs.len() - 1
+                    }
+                };
+                return Ok(Some(grouped_column(scope, keys, aggs, keys.len() + i)?));
+            }
+        }
+        // A part without aggregates may be a key as a whole.
+        if let Ok(bound) = bind(scope, node) {
+            if let Some(i) = keys.iter().position(|k| *k == bound) {
+                return Ok(Some(grouped_column(scope, keys, aggs, i)?));
+            }
+            if bound.is_constant() {
+                return Ok(Some(bound));
+            }
+            if let ast::Expr::Identifier(_) | ast::Expr::CompoundIdentifier(_) = node {
+                bail!(
+                    "column {} must appear in the GROUP BY clause or be used in an aggregate function",
+                    node
+                );
+            }
+        }
+        Ok(None)
+    })?;
+    Ok(bound.map_columns(&|c| match c.checked_sub(GROUPED_COLUMNS) {
+        Some(i) if c < SUBLINK_COLUMNS => i,
+        _ => c,
+    }))
+}
+
+/// Aggregate outputs are bound as `Column(GROUPED_COLUMNS + i)` while an expression over
+/// them is bound, so that their types are known apart from those of the FROM columns.
+const GROUPED_COLUMNS: usize = usize::MAX / 8;
+
+/// The column standing for output `i` of an aggregate over `keys` and `aggs` while it is
+/// bound (see `GROUPED_COLUMNS`).
+fn grouped_column(scope: &Scope, keys: &[Expr], aggs: &[AggCall], i: usize) -> Result<Expr> {
+    let ty = grouped_types(scope, keys, aggs)?[i];
+    scope.late_types.borrow_mut().insert(GROUPED_COLUMNS + i, ty);
+    Ok(Expr::Column(GROUPED_COLUMNS + i))
+}
+
+/// Turns statements into plans for one database.
+pub struct Planner<'a> {
+    db: &'a Database,
+    /// The blocks enclosing the subquery being planned, innermost last.
+    outer: RefCell<Vec<Rc<Outer>>>,
+    /// WITH queries in scope, innermost last.
+    ctes: RefCell<Vec<Rc<Cte>>>,
+    /// Views being expanded, innermost last.
+    views: RefCell<Vec<String>>,
+    params: Rc<Params>,
+    /// The database's user-defined functions when planning started.
+    functions: Arc<FunctionRegistry>,
+    /// The sequences, as the session the plans are for calls them.
+    sequences: SessionSequences,
+    /// Most worker threads a part of a query may run in (see `parallel`).
+    parallel_workers: usize,
+}
+
+impl<'a> Planner<'a> {
+    pub fn new(db: &'a Database) -> Self {
+        Self {
+            db,
+            outer: RefCell::default(),
+            ctes: RefCell::default(),
+            views: RefCell::default(),
+            params: Rc::default(),
+            functions: db.functions(),
+            sequences: SessionSequences::new(Arc::clone(db.sequences()), 0),
+            parallel_workers: db.config().parallel_workers,
+        }
+    }
+
+    /// Plan sequence function calls for session `session` (see `sequence`).
+    pub fn with_session(mut self, session: u64) -> Self {
+        self.sequences = SessionSequences::new(Arc::clone(self.db.sequences()), session);
+        self
+    }
+
+    /// Plan queries for up to `workers` threads instead of the configured number.
+    pub fn with_parallel_workers(mut self, workers: usize) -> Self {
+        self.parallel_workers = workers;
+        self
+    }
+
+    /// Declare the types of the first parameters; the others are inferred while planning.
+    pub fn with_param_types(self, types: &[ColumnType]) -> Self {
+        *self.params.types.borrow_mut() = types.iter().copied().map(Some).collect();
+        self
+    }
+
+    /// Types of the parameters of the statements planned so far.
+    pub fn param_types(&self) -> Vec<ColumnType> {
+        let types = self.params.types.borrow();
+        types.iter().map(|t| t.unwrap_or(ColumnType::Text)).collect()
+    }
+
+    /// The value of a constant expression, such as an argument of EXECUTE.
+    pub fn constant(&self, e: &ast::Expr) -> Result<Value> {
+        bind(&self.scope(), e)?.eval(&[])
+    }
+
+    /// An expression over the columns of a row of `def`, such as a CHECK constraint.
+    pub fn row_expr(&self, def: &TableDef, e: &ast::Expr) -> Result<Expr> {
+        let mut scope = self.scope();
+        let columns = def.columns.iter().map(|c| (c.name.clone(), c.ty)).collect();
+        scope.add(def.name.clone(), columns, def.columns.iter().map(|c| c.hidden).collect())?;
+        bind(&scope, e)
+    }
+
+    /// The DEFAULT expression of column `c`, if it has one. It cannot name columns.
+    pub fn column_default(&self, c: &ColumnDef) -> Result<Option<Expr>> {
+        let Some(sql) = &c.default else {
+            return Ok(None);
+        };
+        let e = Parser::new(&GenericDialect {}).try_with_sql(sql)?.parse_expr()?;
+        let scope = Scope {
+            outer: None,
+            ..self.scope()
+        };
+        bind(&scope, &e).map(Some)
+    }
+
+    /// An empty scope for a query block, nested in the block being planned if any.
+    fn scope(&self) -> Scope {
+        Scope {
+            outer: self.outer.borrow().last().cloned(),
+            params: Rc::clone(&self.params),
+            functions: Arc::clone(&self.functions),
+            sequences: Some(self.sequences.clone()),
+            ..Scope::default()
+        }
+    }
+
+    /// Run `f` with `outer` as the innermost enclosing block.
+    fn with_outer<T>(&self, outer: &Rc<Outer>, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        self.outer.borrow_mut().push(Rc::clone(outer));
+        let result = f();
+        self.outer.borrow_mut().pop();
+        result
+    }
+
+    /// Plan a query or DML statement.
+    pub fn plan(&self, stmt: &Statement) -> Result<Plan> {
+        match stmt {
+            Statement::Query(q) => Ok(parallel::parallelize(self.plan_query(q)?, self.db, self.parallel_workers)),
+            Statement::Insert {
+                table_name,
+                columns,
+                source,
+                on,
+                returning,
+                or,
+                ignore,
+                overwrite,
+                partitioned,
+                after_columns,
+                table,
+                replace_into,
+                priority,
+                insert_alias,
+                table_alias,
+                into: _,
+            } => {
+                if or.is_some()
+                    || *ignore
+                    || *overwrite
+                    || partitioned.is_some()
+                    || !after_columns.is_empty()
+                    || *table
+                    || *replace_into
+                    || priority.is_some()
+                    || insert_alias.is_some()
+                {
+                    bail!("unsupported INSERT syntax: {}", stmt);
+                }
+                let Some(source) = source else {
+                    bail!("INSERT without a source is not supported");
+                };
+                let on_conflict = match on {
+                    None => None,
+                    Some(OnInsert::OnConflict(c)) => Some(c),
+                    Some(other) => bail!("unsupported INSERT syntax: {}", other),
+                };
+                let table = object_name(table_name)?;
+                let plan = self.plan_insert(&table, table_alias.as_ref(), columns, source, on_conflict)?;
+                match returning {
+                    Some(items) => {
+                        let name = table_alias.as_ref().map_or(table.clone(), ident);
+                        self.plan_returning(plan, name, items)
+                    }
+                    None => Ok(plan),
+                }
+            }
+            Statement::Update {
+                table,
+                assignments,
+                from,
+                selection,
+                returning,
+            } => {
+                if from.is_some() {
+                    bail!("unsupported UPDATE syntax: {}", stmt);
+                }
+                let (plan, name) = self.plan_update(table, assignments, selection.as_ref())?;
+                match returning {
+                    Some(items) => self.plan_returning(plan, name, items),
+                    None => Ok(plan),
+                }
+            }
+            Statement::Delete {
+                tables,
+                from,
+                using,
+                selection,
+                returning,
+                order_by,
+                limit,
+            } => {
+                let from = match from {
+                    FromTable::WithFromKeyword(f) | FromTable::WithoutKeyword(f) => f,
+                };
+                if !tables.is_empty() || using.is_some() || !order_by.is_empty() || limit.is_some() {
+                    bail!("unsupported DELETE syntax: {}", stmt);
+                }
+                let [target] = from.as_slice() else {
+                    bail!("DELETE from more than one table is not supported");
+                };
+                let (plan, name) = self.plan_delete(target, selection.as_ref())?;
+                match returning {
+                    Some(items) => self.plan_returning(plan, name, items),
+                    None => Ok(plan),
+                }
+            }
+            other => bail!("unsupported statement: {}", other),
+        }
+    }
+
+    pub fn plan_query(&self, q: &Query) -> Result<Plan> {
+        let Some(with) = &q.with else {
+            return self.plan_query_body(q);
+        };
+        let depth = self.ctes.borrow().len();
+        for (i, cte) in with.cte_tables.iter().enumerate() {
+            let name = ident(&cte.alias.name);
+            if with.cte_tables[..i].iter().any(|c| ident(&c.alias.name) == name) {
+                bail!("WITH query name {} specified more than once", name);
+            }
+            if cte.from.is_some() {
+                bail!("unsupported WITH syntax: {}", cte);
+            }
+            // MATERIALIZED and NOT MATERIALIZED are hints; every reference is planned anew.
+            self.ctes.borrow_mut().push(Rc::new(Cte {
+                name,
+                columns: cte.alias.columns.iter().map(ident).collect(),
+                query: (*cte.query).clone(),
+                recursive: with.recursive,
+                work_table: None,
+            }));
+        }
+        let plan = self.plan_query_body(q);
+        self.ctes.borrow_mut().truncate(depth);
+        plan
+    }
+
+    fn plan_query_body(&self, q: &Query) -> Result<Plan> {
+        if q.fetch.is_some() || !q.limit_by.is_empty() || q.for_clause.is_some() {
+            bail!("unsupported query syntax: {}", q);
+        }
+        let limit = q.limit.as_ref().map(|e| constant_count(e, "LIMIT")).transpose()?;
+        let offset = match &q.offset {
+            Some(Offset { value, .. }) => constant_count(value, "OFFSET")?,
+            None => 0,
+        };
+        // ORDER BY only has to produce the rows the limit lets through.
+        let bound = limit.map(|l| l.saturating_add(offset));
+        let plan = match &*q.body {
+            SetExpr::Select(s) => self.plan_select(s, &q.order_by, bound, &q.locks)?,
+            body => {
+                if let Some(lock) = q.locks.first() {
+                    bail!("FOR {} is only supported on a SELECT from a single table", lock.lock_type);
+                }
+                let plan = self.plan_set_expr(body)?;
+                self.order_output(plan, &q.order_by, bound)?
+            }
+        };
+        Ok(Self::limit(plan, limit, offset))
+    }
+
+    /// A query body without ORDER BY or LIMIT of its own.
+    fn plan_set_expr(&self, body: &SetExpr) -> Result<Plan> {
+        match body {
+            SetExpr::Select(s) => self.plan_select(s, &[], None, &[]),
+            SetExpr::Values(v) => self.plan_values(v),
+            SetExpr::Query(inner) => self.plan_query(inner),
+            SetExpr::SetOperation {
+                op,
+                set_quantifier,
+                left,
+                right,
+            } => self.plan_set_operation(op, set_quantifier, left, right),
+            other => bail!("unsupported query: {}", other),
+        }
+    }
+
+    /// UNION, INTERSECT or EXCEPT. Each column takes the type both sides convert to. Duplicates
+    /// are removed by aggregating the rows of both sides on every column; for INTERSECT and
+    /// EXCEPT the aggregate also counts each row's occurrences on either side.
+    fn plan_set_operation(
+        &self,
+        op: &SetOperator,
+        quantifier: &SetQuantifier,
+        left: &SetExpr,
+        right: &SetExpr,
+    ) -> Result<Plan> {
+        let all = match quantifier {
+            SetQuantifier::None | SetQuantifier::Distinct => false,
+            SetQuantifier::All => true,
+            other => bail!("{} {} is not supported", op, other),
+        };
+        let left = self.plan_set_expr(left)?;
+        let right = self.plan_set_expr(right)?;
+        if left.columns.len() != right.columns.len() {
+            bail!("each {} query must have the same number of columns", op);
+        }
+        let mut columns = Vec::new();
+        for (i, ((name, l), (_, r))) in left.columns.iter().zip(&right.columns).enumerate() {
+            let ty = match (*l, *r) {
+                (l, r) if l == r => l,
+                (l, _) if untyped_null(&right, i) => l,
+                (_, r) if untyped_null(&left, i) => r,
+                (ColumnType::Int, ColumnType::Float) | (ColumnType::Float, ColumnType::Int) => ColumnType::Float,
+                (l, r) => bail!("{} types {} and {} cannot be matched", op, l, r),
+            };
+            columns.push((name.clone(), ty));
+        }
+        let width = columns.len();
+        let counted = match op {
+            SetOperator::Union => None,
+            SetOperator::Intersect => Some(SetOpKind::Intersect),
+            SetOperator::Except => Some(SetOpKind::Except),
+        };
+        let Some(kind) = counted else {
+            let append = Self::append(vec![left, right], columns);
+            return if all { Ok(append) } else { self.distinct(append, width, vec![]) };
+        };
+        // Each side's rows followed by a 1 in its own count column and a 0 in the other.
+        let tag = |plan: Plan, side: usize| {
+            let mut exprs: Vec<Expr> = (0..width).map(Expr::Column).collect();
+            exprs.extend((0..2).map(|s| Expr::Literal(Value::Int((s == side) as i64))));
+            let mut columns = plan.columns.clone();
+            columns.extend([("left".to_string(), ColumnType::Int), ("right".to_string(), ColumnType::Int)]);
+            Self::project(plan, exprs, columns)
+        };
+        let (left_rows, right_rows) = (left.est_rows, right.est_rows);
+        let mut tagged = columns.clone();
+        tagged.extend([("left".to_string(), ColumnType::Int), ("right".to_string(), ColumnType::Int)]);
+        let append = Self::append(vec![tag(left, 0), tag(right, 1)], tagged);
+        let counts = (width..width + 2)
+            .map(|c| AggCall {
+                func: AggFunc::Sum,
+                args: vec![Expr::Column(c)],
+                distinct: false,
+                filter: None,
+            })
+            .collect();
+        let counted = self.distinct(append, width, counts)?;
+        Ok(Plan {
+            est_rows: match kind {
+                SetOpKind::Intersect => left_rows.min(right_rows),
+                SetOpKind::Except => left_rows,
+            },
+            est_cost: counted.est_cost + counted.est_rows * CPU_TUPLE_COST,
+            node: Node::SetOp { op: kind, all },
+            columns,
+            children: vec![counted],
+        })
+    }
+
+    /// UNION ALL of `inputs`, whose columns convert to `columns`. Nested appends of the same
+    /// column types are merged into one.
+    fn append(inputs: Vec<Plan>, columns: Vec<(String, ColumnType)>) -> Plan {
+        let mut children = Vec::new();
+        for plan in inputs {
+            let same_types = plan.columns.iter().zip(&columns).all(|((_, a), (_, b))| a == b);
+            match plan.node {
+                Node::Append if same_types => children.extend(plan.children),
+                _ => children.push(plan),
+            }
+        }
+        Plan {
+            est_rows: children.iter().map(|c| c.est_rows).sum(),
+            est_cost: children.iter().map(|c| c.est_cost + c.est_rows * CPU_TUPLE_COST).sum(),
+            node: Node::Append,
+            columns,
+            children,
+        }
+    }
+
+    /// One row per distinct value of the first `width` columns of `input`, followed by the
+    /// values of `aggregates`. Without statistics, every row is taken to be distinct.
+    fn distinct(&self, input: Plan, width: usize, aggregates: Vec<AggCall>) -> Result<Plan> {
+        let mut columns = input.columns[..width].to_vec();
+        for a in &aggregates {
+            let arg_types: Vec<ColumnType> = a.args.iter().map(|e| e.data_type(&input.column_types())).collect();
+            columns.push((a.display_with(&|i| input.columns[i].0.clone()), a.func.result_type(&arg_types)?));
+        }
+        let keys: Vec<Expr> = (0..width).map(Expr::Column).collect();
+        let rows = input.est_rows;
+        let work = input.est_cost + rows * CPU_OPERATOR_COST * columns.len() as f64 + rows * CPU_TUPLE_COST;
+        let (strategy, child, cost) = self.aggregate_strategy(input, &keys, Some(false), rows, columns.len(), work);
+        Ok(Plan {
+            node: Node::Aggregate {
+                strategy,
+                group_by: keys,
+                sets: vec![(0..width).collect()],
+                aggregates,
+            },
+            columns,
+            est_rows: rows,
+            est_cost: cost,
+            children: vec![child],
+        })
+    }
+
+    /// The plan of the WITH query `name` names, if one is in scope.
+    fn cte_plan(&self, name: &str) -> Result<Option<Plan>> {
+        let Some(i) = self.ctes.borrow().iter().rposition(|c| c.name == name) else {
+            return Ok(None);
+        };
+        let cte = Rc::clone(&self.ctes.borrow()[i]);
+        if let Some(scan) = &cte.work_table {
+            return Ok(Some(scan.clone()));
+        }
+        // Its body sees only the WITH queries before it.
+        let hidden = self.ctes.borrow_mut().split_off(i);
+        let plan = self.plan_cte(&cte);
+        self.ctes.borrow_mut().extend(hidden);
+        plan.map(Some)
+    }
+
+    /// A WITH query. Under WITH RECURSIVE, a UNION whose right term reads the query itself
+    /// is a recursive union; that term reads the rows of the previous step.
+    fn plan_cte(&self, cte: &Cte) -> Result<Plan> {
+        let what = format!("WITH query {}", cte.name);
+        let (quantifier, left, right) = match &*cte.query.body {
+            SetExpr::SetOperation {
+                op: SetOperator::Union,
+                set_quantifier,
+                left,
+                right,
+            } if cte.recursive => (set_quantifier, left, right),
+            _ => return rename_columns(self.plan_query(&cte.query)?, &cte.columns, &what),
+        };
+        let q = &cte.query;
+        if !q.order_by.is_empty() || q.limit.is_some() || q.offset.is_some() || q.fetch.is_some() {
+            bail!("ORDER BY, LIMIT and OFFSET are not supported in a recursive query");
+        }
+        let distinct = match quantifier {
+            SetQuantifier::None | SetQuantifier::Distinct => true,
+            SetQuantifier::All => false,
+            other => bail!("UNION {} is not supported", other),
+        };
+        let base = rename_columns(self.plan_set_expr(left)?, &cte.columns, &what)?;
+        let scan = Plan {
+            node: Node::WorkTableScan { name: cte.name.clone() },
+            children: vec![],
+            columns: base.columns.clone(),
+            est_rows: base.est_rows,
+            est_cost: base.est_rows * CPU_TUPLE_COST,
+        };
+        self.ctes.borrow_mut().push(Rc::new(Cte {
+            name: cte.name.clone(),
+            columns: vec![],
+            query: cte.query.clone(),
+            recursive: true,
+            work_table: Some(scan),
+        }));
+        let recursive = self.plan_set_expr(right);
+        self.ctes.borrow_mut().pop();
+        let recursive = recursive?;
+        if recursive.columns.len() != base.columns.len() {
+            bail!("each UNION query must have the same number of columns");
+        }
+        let reads_itself = recursive
+            .walk()
+            .iter()
+            .any(|p| matches!(&p.node, Node::WorkTableScan { name } if *name == cte.name));
+        if !reads_itself {
+            bail!("recursive query {} must refer to itself in the right term of its UNION", cte.name);
+        }
+        Ok(Plan {
+            columns: base.columns.clone(),
+            est_rows: base.est_rows + RECURSIVE_STEPS * recursive.est_rows,
+            est_cost: base.est_cost + RECURSIVE_STEPS * recursive.est_cost,
+            node: Node::RecursiveUnion {
+                name: cte.name.clone(),
+                distinct,
+                recursive: Box::new(recursive),
+            },
+            children: vec![base],
+        })
+    }
+
+    /// The query of a view, which sees neither the WITH queries nor the columns of the
+    /// statement reading the view.
+    fn plan_view(&self, view: &ViewDef) -> Result<Plan> {
+        if self.views.borrow().contains(&view.name) {
+            bail!("infinite recursion detected in view {}", view.name);
+        }
+        let query = view::parse_query(&view.query)?;
+        let ctes = self.ctes.take();
+        let outer = self.outer.take();
+        self.views.borrow_mut().push(view.name.clone());
+        let plan = self.plan_query(&query);
+        self.views.borrow_mut().pop();
+        *self.ctes.borrow_mut() = ctes;
+        *self.outer.borrow_mut() = outer;
+        rename_columns(plan?, &view.columns, &format!("view {}", view.name))
+    }
+
+    /// Fail unless `name` is a table DML may change.
+    fn check_writable(&self, name: &str) -> Result<()> {
+        match self.db.view_def(name) {
+            Some(v) if v.materialized => bail!("cannot change materialized view {}", name),
+            Some(_) => bail!("cannot change view {}", name),
+            None => Ok(()),
+        }
+    }
+
+    fn limit(plan: Plan, limit: Option<u64>, offset: u64) -> Plan {
+        if limit.is_none() && offset == 0 {
+            return plan;
+        }
+        let available = (plan.est_rows - offset as f64).max(0.0);
+        Plan {
+            node: Node::Limit { limit, offset },
+            columns: plan.columns.clone(),
+            est_rows: limit.map_or(available, |l| available.min(l as f64)),
+            est_cost: plan.est_cost,
+            children: vec![plan],
+        }
+    }
+
+    /// Sort `plan` on `keys`. With a bound only the first `bound` rows are kept, which a
+    /// bounded heap does more cheaply.
+    fn sort(&self, plan: Plan, keys: Vec<SortKey>, bound: Option<u64>) -> Plan {
+        if keys.is_empty() {
+            return plan;
+        }
+        let rows = plan.est_rows;
+        let work_mem = (self.db.config().work_mem_kb * 1024) as f64;
+        let (est_rows, cost) = match bound {
+            Some(n) => {
+                let kept = rows.min(n as f64);
+                (kept, top_n_cost(rows, n as f64) + sort_spill_cost(kept, plan.columns.len(), work_mem))
+            }
+            None => (rows, sort_cost(rows) + sort_spill_cost(rows, plan.columns.len(), work_mem)),
+        };
+        Plan {
+            node: Node::Sort { keys, limit: bound },
+            columns: plan.columns.clone(),
+            est_rows,
+            est_cost: plan.est_cost + cost,
+            children: vec![plan],
+        }
+    }
+
+    /// Whether `input` already comes out in the order of `keys`: a single ascending key,
+    /// NULLs last, on the column the input is sorted on.
+    fn provides_order(input: &Planned, keys: &[SortKey]) -> bool {
+        match keys {
+            [SortKey {
+                expr: Expr::Column(c),
+                desc: false,
+                nulls_first: false,
+            }] => input.sorted_on.is_some_and(|s| input.layout.get(*c) == Some(&s)),
+            _ => false,
+        }
+    }
+
+    fn sort_key(expr: Expr, o: &OrderByExpr) -> SortKey {
+        let desc = o.asc == Some(false);
+        SortKey {
+            expr,
+            desc,
+            // PostgreSQL: NULLs sort as larger than any value.
+            nulls_first: o.nulls_first.unwrap_or(desc),
+        }
+    }
+
+    /// ORDER BY over the output columns of `plan` (by position or name).
+    fn order_output(&self, plan: Plan, order_by: &[OrderByExpr], bound: Option<u64>) -> Result<Plan> {
+        let mut keys = Vec::new();
+        for o in order_by {
+            let idx = match &o.expr {
+                ast::Expr::Value(ast::Value::Number(s, _)) => match s.parse::<usize>() {
+                    Ok(n) if (1..=plan.columns.len()).contains(&n) => n - 1,
+                    _ => bail!("ORDER BY position {} is not in select list", s),
+                },
+                ast::Expr::Identifier(id) => match plan.columns.iter().position(|(n, _)| *n == ident(id)) {
+                    Some(i) => i,
+                    None => bail!("column {} does not exist", ident(id)),
+                },
+                other => bail!("ORDER BY expression {} must name an output column", other),
+            };
+            keys.push(Self::sort_key(Expr::Column(idx), o));
+        }
+        Ok(self.sort(plan, keys, bound))
+    }
+
+    fn plan_values(&self, v: &ast::Values) -> Result<Plan> {
+        let scope = self.scope();
+        let rows: Vec<Vec<Expr>> = v
+            .rows
+            .iter()
+            .map(|r| r.iter().map(|e| bind(&scope, e)).collect::<Result<Vec<_>>>())
+            .collect::<Result<_>>()?;
+        let width = rows.first().map_or(0, |r| r.len());
+        if rows.iter().any(|r| r.len() != width) {
+            bail!("VALUES lists must all be the same length");
+        }
+        // A column's type is that of its first non-NULL entry.
+        let columns = (0..width)
+            .map(|i| {
+                let ty = rows
+                    .iter()
+                    .find(|r| !matches!(r[i], Expr::Literal(Value::Null)))
+                    .map_or(ColumnType::Text, |r| r[i].data_type(&[]));
+                (format!("column{}", i + 1), ty)
+            })
+            .collect();
+        let n = rows.len() as f64;
+        Ok(Plan {
+            node: Node::Values { rows },
+            children: vec![],
+            columns,
+            est_rows: n,
+            est_cost: n * CPU_TUPLE_COST,
+        })
+    }
+
+    /// A FROM item computed by `plan`, added to `scope` as `name` with its columns
+    /// renamed by `aliases`.
+    fn planned_relation(
+        &self,
+        scope: &mut Scope,
+        name: String,
+        aliases: &[ast::Ident],
+        plan: Plan,
+    ) -> Result<Relation> {
+        let aliases: Vec<String> = aliases.iter().map(ident).collect();
+        let plan = rename_columns(plan, &aliases, &format!("table {}", name))?;
+        let columns = plan.columns.clone();
+        let offset = scope.add(name, columns.clone(), vec![false; columns.len()])?;
+        Ok(Relation {
+            source: Source::Plan(Box::new(Planned {
+                layout: (offset..offset + columns.len()).collect(),
+                plan,
+                sorted_on: None,
+            })),
+            columns,
+            offset,
+        })
+    }
+
+    /// A table, WITH query, subquery or table function of FROM, added to `scope`.
+    fn relation(&self, scope: &mut Scope, factor: &TableFactor) -> Result<Relation> {
+        let (name, alias, args) = match factor {
+            TableFactor::Table { name, alias, args, .. } => (name, alias, args),
+            TableFactor::Derived {
+                lateral,
+                subquery,
+                alias,
+            } => {
+                if *lateral {
+                    bail!("LATERAL subqueries are not supported");
+                }
+                let Some(alias) = alias else {
+                    bail!("subquery in FROM must have an alias");
+                };
+                let plan = self.plan_query(subquery)?;
+                return self.planned_relation(scope, ident(&alias.name), &alias.columns, plan);
+            }
+            other => bail!("unsupported FROM item: {}", other),
+        };
+        let name = object_name(name)?;
+        let (alias_name, aliases) = match alias {
+            Some(a) => (ident(&a.name), a.columns.as_slice()),
+            None => (name.clone(), &[][..]),
+        };
+        if let Some(args) = args {
+            let Some(func) = self.functions.table(&name) else {
+                bail!("unknown table function: {}", name);
+            };
+            let empty = self.scope();
+            let args = args
+                .iter()
+                .map(|a| match a {
+                    ast::FunctionArg::Unnamed(ast::FunctionArgExpr::Expr(e)) => bind(&empty, e),
+                    other => bail!("unsupported table function argument {}", other),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            func.check_args(&args.iter().map(|e| arg_type(&empty, e)).collect::<Vec<_>>())?;
+            let plan = Plan {
+                columns: func.columns()?,
+                node: Node::TableFunction { func, args },
+                children: vec![],
+                est_rows: TABLE_FUNCTION_ROWS,
+                est_cost: TABLE_FUNCTION_ROWS * CPU_TUPLE_COST,
+            };
+            return self.planned_relation(scope, alias_name, aliases, plan);
+        }
+        if let Some(plan) = self.cte_plan(&name)? {
+            return self.planned_relation(scope, alias_name, aliases, plan);
+        }
+        if let Some(view) = self.db.view_def(&name).filter(|v| !v.materialized) {
+            let plan = self.plan_view(&view)?;
+            return self.planned_relation(scope, alias_name, aliases, plan);
+        }
+        let def = self.db.table_def(&name);
+        if def.is_none() && system::VIEWS.contains(&name.as_str()) {
+            let func = TableFunc::System(name);
+            let plan = Plan {
+                columns: func.columns()?,
+                node: Node::TableFunction { func, args: vec![] },
+                children: vec![],
+                est_rows: TABLE_FUNCTION_ROWS,
+                est_cost: TABLE_FUNCTION_ROWS * CPU_TUPLE_COST,
+            };
+            return self.planned_relation(scope, alias_name, aliases, plan);
+        }
+        if !aliases.is_empty() {
+            bail!("column aliases for tables are not supported");
+        }
+        let Some(def) = def else {
+            bail!("table {} does not exist", name);
+        };
+        let table = self.db.table(&name)?;
+        let pages = table.num_pages() as f64;
+        let stats = self.db.table_stats(&name);
+        let rows = match &stats {
+            // Scale the analyzed density to the table's current size, as PostgreSQL does.
+            Some(s) if s.pages > 0 => s.row_count as f64 * pages / s.pages as f64,
+            _ => table.version_counts().0 as f64,
+        };
+        let columns: Vec<(String, ColumnType)> = def.columns.iter().map(|c| (c.name.clone(), c.ty)).collect();
+        let hidden = def.columns.iter().map(|c| c.hidden).collect();
+        let offset = scope.add(alias_name.clone(), columns.clone(), hidden)?;
+        Ok(Relation {
+            source: Source::Table {
+                table: name,
+                key_col: def.key_col,
+                rows,
+                pages,
+                stats,
+            },
+            columns: columns
+                .into_iter()
+                .map(|(n, t)| (format!("{}.{}", alias_name, n), t))
+                .collect(),
+            offset,
+        })
+    }
+
+    fn join_planner<'r>(&self, rels: &'r [Relation], conjuncts: Vec<Expr>) -> JoinPlanner<'r> {
+        JoinPlanner::new(rels, conjuncts).with_work_mem(self.db.config().work_mem_kb * 1024)
+    }
+
+    /// FROM list and WHERE: relations whose inner joins may be reordered, with the
+    /// conjuncts of WHERE and of the ON conditions of those joins. Each outer, semi or anti
+    /// join is planned on the spot, together with everything to its left in the same FROM
+    /// item, and becomes a single relation. WHERE subqueries go to the residual, as semi
+    /// joins or into `links`.
+    fn plan_from(
+        &self,
+        scope: &mut Scope,
+        from: &[TableWithJoins],
+        selection: Option<&ast::Expr>,
+        links: &mut Sublinks,
+    ) -> Result<(Vec<Relation>, Vec<Expr>, Residual)> {
+        // Bind everything first: WHERE sees every FROM item.
+        let mut items = Vec::new();
+        for twj in from {
+            // ON conditions may only refer to this FROM item.
+            let first = scope.rels.len();
+            let base = self.relation(scope, &twj.relation)?;
+            let mut steps = Vec::new();
+            for join in &twj.joins {
+                let (join_type, constraint) = match &join.join_operator {
+                    JoinOperator::Inner(c) => (JoinType::Inner, c),
+                    JoinOperator::CrossJoin => (JoinType::Inner, &JoinConstraint::None),
+                    JoinOperator::LeftOuter(c) => (JoinType::Left, c),
+                    JoinOperator::RightOuter(c) => (JoinType::Right, c),
+                    JoinOperator::FullOuter(c) => (JoinType::Full, c),
+                    JoinOperator::LeftSemi(c) | JoinOperator::RightSemi(c) => (JoinType::Semi, c),
+                    JoinOperator::LeftAnti(c) | JoinOperator::RightAnti(c) => (JoinType::Anti, c),
+                    other => bail!("{:?} is not supported yet", other),
+                };
+                let relation = self.relation(scope, &join.relation)?;
+                let on = match constraint {
+                    JoinConstraint::On(e) => {
+                        let item = Scope {
+                            rels: scope.rels[first..].to_vec(),
+                            base: scope.base,
+                            outer: scope.outer.clone(),
+                            params: Rc::clone(&scope.params),
+                            functions: Arc::clone(&scope.functions),
+                            sequences: scope.sequences.clone(),
+                            late_types: RefCell::default(),
+                        };
+                        bind(&item, e)?.conjuncts()
+                    }
+                    JoinConstraint::None if join_type == JoinType::Inner => vec![],
+                    JoinConstraint::None => bail!("{:?} requires an ON condition", join.join_operator),
+                    c => bail!("join constraint {:?} is not supported yet", c),
+                };
+                // RIGHT SEMI and RIGHT ANTI keep the rows of the new relation instead.
+                let flipped = matches!(join.join_operator, JoinOperator::RightSemi(_) | JoinOperator::RightAnti(_));
+                if matches!(join_type, JoinType::Semi | JoinType::Anti) {
+                    let n = scope.rels.len();
+                    let dropped = if flipped { first..n - 1 } else { n - 1..n };
+                    for r in &mut scope.rels[dropped] {
+                        r.visible = false;
+                    }
+                }
+                steps.push((join_type, flipped, relation, on));
+            }
+            items.push((base, steps));
+        }
+        let mut filters = Vec::new();
+        let mut residual = Residual::default();
+        for c in selection.map_or(vec![], ast_conjuncts) {
+            if let Some(semi) = self.semi_join(scope, c)? {
+                residual.semi_joins.push(semi);
+                continue;
+            }
+            for c in self.bind_sublinks(scope, c, links)?.conjuncts() {
+                if c.columns().iter().any(|g| *g >= SUBLINK_COLUMNS) {
+                    residual.filters.push(c);
+                } else {
+                    filters.push(c);
+                }
+            }
+        }
+
+        let mut rels = Vec::new();
+        let mut conjuncts = Vec::new();
+        for (base, steps) in items {
+            let mut group = vec![base];
+            let mut group_on = Vec::new();
+            for (join_type, flipped, relation, on) in steps {
+                if join_type == JoinType::Inner {
+                    group.push(relation);
+                    group_on.extend(on);
+                    continue;
+                }
+                let left = self.collapse(group, group_on)?;
+                let pair = if flipped { [relation, left] } else { [left, relation] };
+                // ON conditions on the side whose rows are not preserved filter it before the
+                // join, and so do WHERE conditions on the preserved side; the remaining ON
+                // conditions are the join condition.
+                let (preserved, filtered) = match join_type {
+                    JoinType::Right => (Some(1), Some(0)),
+                    JoinType::Full => (None, None),
+                    _ => (Some(0), Some(1)),
+                };
+                let within = |c: &Expr, side: Option<usize>| {
+                    let cols = c.columns();
+                    side.is_some_and(|r| !cols.is_empty() && cols.iter().all(|g| pair[r].has_column(*g)))
+                };
+                let (mut pushed, on): (Vec<Expr>, Vec<Expr>) = on.into_iter().partition(|c| within(c, filtered));
+                let (applied, rest): (Vec<Expr>, Vec<Expr>) = filters.into_iter().partition(|c| within(c, preserved));
+                pushed.extend(applied);
+                filters = rest;
+                let planned = self.join_planner(&pair, pushed).plan_join(join_type, on)?;
+                group = vec![Relation {
+                    columns: planned.plan.columns.clone(),
+                    offset: pair[0].offset.min(pair[1].offset),
+                    source: Source::Plan(Box::new(planned)),
+                }];
+                group_on = Vec::new();
+            }
+            rels.extend(group);
+            conjuncts.extend(group_on);
+        }
+        conjuncts.extend(filters);
+        Ok((rels, conjuncts, residual))
+    }
+
+    /// Bind `e` in `scope`, planning its subqueries into `links`.
+    fn bind_sublinks(&self, scope: &Scope, e: &ast::Expr, links: &mut Sublinks) -> Result<Expr> {
+        bind_with(scope, e, &mut |node| {
+            let operand = match node {
+                ast::Expr::InSubquery { expr, .. } => Some(self.bind_sublinks(scope, expr, links)?),
+                _ => None,
+            };
+            self.sublink(scope, node, operand, links)
+        })
+    }
+
+    /// Plan the subquery `node` is, if it is one, into `links`, given the bound left
+    /// operand of IN. Returns the column its value is appended as (see `SUBLINK_COLUMNS`),
+    /// negated for NOT IN and NOT EXISTS.
+    fn sublink(
+        &self,
+        scope: &Scope,
+        node: &ast::Expr,
+        operand: Option<Expr>,
+        links: &mut Sublinks,
+    ) -> Result<Option<Expr>> {
+        let (query, negated) = match node {
+            ast::Expr::Subquery(q) => (q, false),
+            ast::Expr::Exists { subquery, negated } | ast::Expr::InSubquery { subquery, negated, .. } => {
+                (subquery, *negated)
+            }
+            _ => return Ok(None),
+        };
+        let outer = Outer::new(scope);
+        let plan = self.with_outer(&outer, || self.plan_query(query))?;
+        let kind = match (node, operand) {
+            (ast::Expr::Exists { .. }, _) => ApplyKind::Exists,
+            _ if plan.columns.len() != 1 => bail!("subquery must return only one column"),
+            (_, Some(operand)) => ApplyKind::In(operand),
+            (_, None) => ApplyKind::Scalar,
+        };
+        links.list.push(Sublink {
+            kind,
+            plan,
+            params: outer.params.take(),
+        });
+        let value = Expr::Column(SUBLINK_COLUMNS + links.list.len() - 1);
+        let ty = match &links.list[links.list.len() - 1].kind {
+            ApplyKind::Scalar => links.list[links.list.len() - 1].plan.columns[0].1,
+            _ => ColumnType::Bool,
+        };
+        scope.late_types.borrow_mut().insert(SUBLINK_COLUMNS + links.list.len() - 1, ty);
+        Ok(Some(if negated {
+            Expr::Unary {
+                op: UnaryOp::Not,
+                expr: Box::new(value),
+            }
+        } else {
+            value
+        }))
+    }
+
+    /// The semi or anti join a WHERE conjunct makes if it is `EXISTS`, `NOT EXISTS` or
+    /// `IN` over a plain SELECT, its columns added to `scope` out of sight. None for any
+    /// other conjunct, and for subqueries that do not bind this way; those run by `Apply`.
+    fn semi_join(&self, scope: &mut Scope, e: &ast::Expr) -> Result<Option<SemiJoin>> {
+        let mut e = e;
+        let mut negated = false;
+        loop {
+            match e {
+                ast::Expr::Nested(inner) => e = inner,
+                ast::Expr::UnaryOp {
+                    op: ast::UnaryOperator::Not,
+                    expr,
+                } => {
+                    negated = !negated;
+                    e = expr;
+                }
+                _ => break,
+            }
+        }
+        let (query, operand) = match e {
+            ast::Expr::Exists { subquery, negated: n } => {
+                negated ^= *n;
+                (subquery, None)
+            }
+            ast::Expr::InSubquery {
+                expr,
+                subquery,
+                negated: false,
+            } if !negated => (subquery, Some(expr)),
+            _ => return Ok(None),
+        };
+        let SetExpr::Select(s) = &*query.body else {
+            return Ok(None);
+        };
+        let plain = query.with.is_none()
+            && query.order_by.is_empty()
+            && query.limit.is_none()
+            && query.offset.is_none()
+            && query.fetch.is_none()
+            && s.distinct.is_none()
+            && !s.from.is_empty()
+            && matches!(&s.group_by, ast::GroupByExpr::Expressions(e) if e.is_empty())
+            && s.having.is_none()
+            && s.named_window.is_empty()
+            && s.qualify.is_none();
+        if !plain {
+            return Ok(None);
+        }
+        let outer = Outer::new(scope);
+        let mut inner = Scope {
+            base: scope.width(),
+            outer: Some(Rc::clone(&outer)),
+            params: Rc::clone(&scope.params),
+            functions: Arc::clone(&scope.functions),
+            sequences: scope.sequences.clone(),
+            ..Scope::default()
+        };
+        let Ok((right, filters, mut on)) =
+            self.with_outer(&outer, || self.semi_join_inner(&mut inner, s, operand.is_some()))
+        else {
+            return Ok(None);
+        };
+        let params = outer.params.take();
+        on = on
+            .iter()
+            .map(|c| {
+                c.transform(&|e: &Expr| match e {
+                    Expr::Outer { index, .. } => Some(params[*index].clone()),
+                    _ => None,
+                })
+            })
+            .collect();
+        if let Some(operand) = operand {
+            let Ok(lhs) = bind(scope, operand) else {
+                return Ok(None);
+            };
+            let (SelectItem::UnnamedExpr(item) | SelectItem::ExprWithAlias { expr: item, .. }) = &s.projection[0]
+            else {
+                unreachable!("checked by semi_join_inner");
+            };
+            let rhs = bind(&inner, item)?;
+            on.push(Expr::binary(BinaryOp::Eq, lhs, rhs));
+        }
+        for mut r in inner.rels {
+            r.visible = false;
+            scope.rels.push(r);
+        }
+        Ok(Some(SemiJoin {
+            join_type: if negated { JoinType::Anti } else { JoinType::Semi },
+            right,
+            filters,
+            on,
+        }))
+    }
+
+    /// FROM and WHERE of a subquery turned into a semi join, bound in `inner`: the right
+    /// relation, the filters on it alone, and the conjuncts reading the outer query. Fails
+    /// if anything reads the outer query other than WHERE conjuncts, or if `single` and
+    /// the select list is not a single expression.
+    fn semi_join_inner(&self, inner: &mut Scope, s: &Select, single: bool) -> Result<(Relation, Vec<Expr>, Vec<Expr>)> {
+        let mut none = Sublinks::default();
+        let (mut rels, conjuncts, residual) = self.plan_from(inner, &s.from, None, &mut none)?;
+        let reads_outer = |r: &Relation| matches!(&r.source, Source::Plan(p) if p.plan.has_outer());
+        if !residual.filters.is_empty() || rels.iter().any(reads_outer) || conjuncts.iter().any(Expr::has_outer) {
+            bail!("subquery does not reduce to a join");
+        }
+        for item in &s.projection {
+            let reduces = match item {
+                SelectItem::UnnamedExpr(e) | SelectItem::ExprWithAlias { expr: e, .. } => {
+                    !has_aggregate(inner, e) && !bind(inner, e)?.has_outer()
+                }
+                _ => !single,
+            };
+            if !reduces {
+                bail!("subquery does not reduce to a join");
+            }
+        }
+        if single && s.projection.len() != 1 {
+            bail!("subquery does not reduce to a join");
+        }
+        let (mut on, mut filters) = (Vec::new(), conjuncts);
+        for c in s.selection.as_ref().map_or(vec![], ast_conjuncts) {
+            for c in bind(inner, c)?.conjuncts() {
+                if c.has_outer() {
+                    on.push(c);
+                } else {
+                    filters.push(c);
+                }
+            }
+        }
+        if rels.len() == 1 {
+            return Ok((rels.pop().unwrap(), filters, on));
+        }
+        let planned = self.join_planner(&rels, filters).plan()?;
+        let right = Relation {
+            columns: planned.plan.columns.clone(),
+            offset: inner.base,
+            source: Source::Plan(Box::new(planned)),
+        };
+        Ok((right, vec![], on))
+    }
+
+    /// `input`, whose rows have `layout`, with the value of each subquery of `links` not yet
+    /// applied appended by an `Apply` node; `param` rewrites their parameters over its rows.
+    fn apply_sublinks(
+        mut input: Plan,
+        layout: &mut Vec<usize>,
+        links: &mut Sublinks,
+        param: &dyn Fn(&Expr) -> Result<Expr>,
+    ) -> Result<Plan> {
+        for i in links.applied..links.list.len() {
+            let link = &links.list[i];
+            let (kind, ty) = match &link.kind {
+                ApplyKind::Scalar => (ApplyKind::Scalar, link.plan.columns[0].1),
+                ApplyKind::Exists => (ApplyKind::Exists, ColumnType::Bool),
+                ApplyKind::In(e) => (ApplyKind::In(remap(e, layout)), ColumnType::Bool),
+            };
+            let params = link.params.iter().map(param).collect::<Result<Vec<_>>>()?;
+            let runs = if params.is_empty() { 1.0 } else { input.est_rows };
+            let mut columns = input.columns.clone();
+            columns.push((format!("(SubPlan {})", i + 1), ty));
+            input = Plan {
+                columns,
+                est_rows: input.est_rows,
+                est_cost: input.est_cost + runs * link.plan.est_cost,
+                node: Node::Apply {
+                    kind,
+                    params,
+                    subplan: Box::new(link.plan.clone()),
+                },
+                children: vec![input],
+            };
+            layout.push(SUBLINK_COLUMNS + i);
+        }
+        links.applied = links.list.len();
+        Ok(input)
+    }
+
+    /// The subqueries of `links` not yet applied, appended to `input`.
+    fn apply_planned(input: Planned, links: &mut Sublinks) -> Result<Planned> {
+        let Planned {
+            plan,
+            mut layout,
+            sorted_on,
+        } = input;
+        let outer = layout.clone();
+        let plan = Self::apply_sublinks(plan, &mut layout, links, &|p| Ok(remap(p, &outer)))?;
+        Ok(Planned {
+            plan,
+            layout,
+            sorted_on,
+        })
+    }
+
+    /// `input` with the residual of WHERE on top: its semi and anti joins, then its
+    /// subqueries and the filters reading them.
+    fn plan_residual(&self, mut input: Planned, residual: Residual, links: &mut Sublinks) -> Result<Planned> {
+        for semi in residual.semi_joins {
+            let left = Relation {
+                columns: input.plan.columns.clone(),
+                offset: 0,
+                source: Source::Plan(Box::new(input)),
+            };
+            let rels = [left, semi.right];
+            input = self.join_planner(&rels, semi.filters).plan_join(semi.join_type, semi.on)?;
+        }
+        let mut input = Self::apply_planned(input, links)?;
+        if let Some(predicate) = Expr::and_all(residual.filters) {
+            let predicate = input.remap(&predicate);
+            let plan = input.plan;
+            input.plan = Plan {
+                columns: plan.columns.clone(),
+                est_rows: (plan.est_rows * selectivity(&predicate, &|_| None)).max(1.0),
+                est_cost: plan.est_cost + plan.est_rows * CPU_OPERATOR_COST,
+                node: Node::Filter { predicate },
+                children: vec![plan],
+            };
+        }
+        Ok(input)
+    }
+
+    /// Join the relations of `group` into one, unless it is one already.
+    fn collapse(&self, mut group: Vec<Relation>, on: Vec<Expr>) -> Result<Relation> {
+        if group.len() == 1 && on.is_empty() {
+            return Ok(group.pop().unwrap());
+        }
+        let planned = self.join_planner(&group, on).plan()?;
+        Ok(Relation {
+            columns: planned.plan.columns.clone(),
+            offset: group[0].offset,
+            source: Source::Plan(Box::new(planned)),
+        })
+    }
+
+    fn plan_select(
+        &self,
+        s: &Select,
+        order_by: &[OrderByExpr],
+        bound: Option<u64>,
+        locks: &[LockClause],
+    ) -> Result<Plan> {
+        if s.distinct.is_some() {
+            bail!("SELECT DISTINCT is not supported yet");
+        }
+        if s.top.is_some()
+            || s.into.is_some()
+            || !s.lateral_views.is_empty()
+            || !s.cluster_by.is_empty()
+            || !s.distribute_by.is_empty()
+            || !s.sort_by.is_empty()
+            || !s.named_window.is_empty()
+            || s.qualify.is_some()
+            || s.value_table_mode.is_some()
+        {
+            bail!("unsupported SELECT syntax: {}", s);
+        }
+        let mut scope = self.scope();
+        let mut links = Sublinks::default();
+        let (rels, conjuncts, residual) = self.plan_from(&mut scope, &s.from, s.selection.as_ref(), &mut links)?;
+        let semi_joined = !residual.semi_joins.is_empty();
+
+        let (planner, input) = if rels.is_empty() {
+            let values = Plan {
+                node: Node::Values { rows: vec![vec![]] },
+                children: vec![],
+                columns: vec![],
+                est_rows: 1.0,
+                est_cost: CPU_TUPLE_COST,
+            };
+            let plan = match Expr::and_all(conjuncts) {
+                None => values,
+                Some(predicate) => Plan {
+                    node: Node::Filter { predicate },
+                    columns: vec![],
+                    est_rows: 1.0,
+                    est_cost: values.est_cost + CPU_OPERATOR_COST,
+                    children: vec![values],
+                },
+            };
+            let input = Planned {
+                plan,
+                layout: vec![],
+                sorted_on: None,
+            };
+            (None, input)
+        } else {
+            let planner = self.join_planner(&rels, conjuncts);
+            let input = planner.plan()?;
+            (Some(planner), input)
+        };
+        let input = self.plan_residual(input, residual, &mut links)?;
+        let locked = Self::row_lock(&scope, &rels, locks)?;
+
+        // Select list, unbound: `*` expands to columns right away.
+        let mut items: Vec<(String, SelectExpr)> = Vec::new();
+        for item in &s.projection {
+            match item {
+                SelectItem::UnnamedExpr(e) => items.push((output_name(e), SelectExpr::Ast(e))),
+                SelectItem::ExprWithAlias { expr, alias } => items.push((ident(alias), SelectExpr::Ast(expr))),
+                SelectItem::Wildcard(_) => {
+                    if scope.rels.is_empty() {
+                        bail!("SELECT * with no tables specified is not valid");
+                    }
+                    for r in scope.rels.iter().filter(|r| r.visible) {
+                        items.extend(Self::star(r));
+                    }
+                }
+                SelectItem::QualifiedWildcard(name, _) => {
+                    let name = object_name(name)?;
+                    match scope.rels.iter().find(|r| r.visible && r.name == name) {
+                        Some(r) => items.extend(Self::star(r)),
+                        None => bail!("missing FROM-clause entry for table {}", name),
+                    }
+                }
+            }
+        }
+
+        let grouped = !matches!(&s.group_by, ast::GroupByExpr::Expressions(e) if e.is_empty())
+            || s.having.is_some()
+            || items
+                .iter()
+                .any(|(_, i)| matches!(i, SelectExpr::Ast(e) if has_aggregate(&scope, e)))
+            || order_by.iter().any(|o| has_aggregate(&scope, &o.expr));
+        if grouped {
+            if let Some(lock) = locks.first() {
+                bail!("FOR {} is not allowed with aggregate functions", lock.lock_type);
+            }
+            return self.plan_grouped(s, order_by, bound, &scope, &items, planner.as_ref(), input, links);
+        }
+
+        // Window calls get global ids after the FROM columns; their parts are bound over
+        // the input's rows.
+        let mut windows = Vec::new();
+        let width = scope.width();
+        let (items, order) = {
+            let base = &mut |e: &ast::Expr| Ok(input.remap(&bind(&scope, e)?));
+            let sublink = &mut |node: &ast::Expr, operand| self.sublink(&scope, node, operand, &mut links);
+            let mut bind_item = |e: &ast::Expr| bind_windowed(&scope, e, width, base, &mut windows, sublink);
+            let items: Vec<(String, Expr)> = items
+                .into_iter()
+                .map(|(n, i)| match i {
+                    SelectExpr::Ast(e) => Ok((n, bind_item(e)?)),
+                    SelectExpr::Column(gid) => Ok((n, Expr::Column(gid))),
+                })
+                .collect::<Result<_>>()?;
+            // ORDER BY may name output columns (by alias or position) or input expressions.
+            let mut order = Vec::new();
+            for o in order_by {
+                order.push(match output_column(&o.expr, &items)? {
+                    Some(i) => items[i].1.clone(),
+                    None => bind_item(&o.expr)?,
+                });
+            }
+            (items, order)
+        };
+        // Subqueries of the select list and ORDER BY run before the windows.
+        let input = Self::apply_planned(input, &mut links)?;
+        let windowed = !windows.is_empty();
+        if let (true, Some(lock)) = (windowed, locks.first()) {
+            bail!("FOR {} is not allowed with window functions", lock.lock_type);
+        }
+        let mut sorted_by = match input.sorted_on {
+            Some(gid) => vec![SortKey {
+                expr: input.remap(&Expr::Column(gid)),
+                desc: false,
+                nulls_first: false,
+            }],
+            None => vec![],
+        };
+        let input = if !windowed {
+            input
+        } else {
+            let (plan, appended, windows_sorted_by) = self.plan_windows(input.plan, windows, sorted_by)?;
+            sorted_by = windows_sorted_by;
+            let layout = input.layout.into_iter().chain(appended.into_iter().map(|i| width + i)).collect();
+            Planned {
+                plan,
+                layout,
+                sorted_on: None,
+            }
+        };
+        let keys: Vec<SortKey> = order
+            .iter()
+            .zip(order_by)
+            .map(|(e, o)| Self::sort_key(input.remap(e), o))
+            .collect();
+        // No sort if the input is already in order. A single table may also be read in
+        // order through its B-tree, which pays off when it saves a large sort or only the
+        // first rows are wanted.
+        let input = match &planner {
+            Some(p)
+                if rels.len() == 1
+                    && !windowed
+                    && !semi_joined
+                    && links.list.is_empty()
+                    && !keys.is_empty()
+                    && !Self::provides_order(&input, &keys) =>
+            {
+                match p.index_path(0, true) {
+                    Some(ordered) if Self::provides_order(&ordered, &keys) => {
+                        let sort_cost = self.sort(input.plan.clone(), keys.clone(), bound).est_cost;
+                        let fraction = bound.map_or(1.0, |n| (n as f64 / ordered.plan.est_rows).min(1.0));
+                        if ordered.plan.est_cost * fraction < sort_cost {
+                            ordered
+                        } else {
+                            input
+                        }
+                    }
+                    _ => input,
+                }
+            }
+            _ => input,
+        };
+        let sorted = if Self::provides_order(&input, &keys) || sorted_by.starts_with(&keys) {
+            input.plan.clone()
+        } else {
+            self.sort(input.plan.clone(), keys, bound)
+        };
+        // Locking comes last so that a LIMIT above it stops it early.
+        let sorted = match locked {
+            Some((table, mode)) => Self::lock_rows(sorted, table, mode),
+            None => sorted,
+        };
+
+        let types: Vec<ColumnType> = sorted.columns.iter().map(|(_, t)| *t).collect();
+        let exprs: Vec<Expr> = items.iter().map(|(_, e)| input.remap(e)).collect();
+        let columns = items
+            .iter()
+            .zip(&exprs)
+            .map(|((n, _), e)| (n.clone(), e.data_type(&types)))
+            .collect();
+        Ok(Self::project(sorted, exprs, columns))
+    }
+
+    /// `Window` nodes computing `windows` over `input`, one per distinct window, each over
+    /// its input sorted on PARTITION BY then ORDER BY unless `sorted_by` (the order `input`
+    /// already has) starts with those keys. Returns the plan, the window calls in the order
+    /// their results were appended to `input`'s columns, and the order of the output.
+    fn plan_windows(
+        &self,
+        input: Plan,
+        windows: Vec<(WindowDef, WindowCall)>,
+        mut sorted_by: Vec<SortKey>,
+    ) -> Result<(Plan, Vec<usize>, Vec<SortKey>)> {
+        let mut plan = input;
+        let mut appended: Vec<usize> = Vec::new();
+        for (i, (def, _)) in windows.iter().enumerate() {
+            if appended.contains(&i) {
+                continue;
+            }
+            let calls: Vec<usize> = (i..windows.len())
+                .filter(|j| !appended.contains(j) && windows[*j].0 == *def)
+                .collect();
+            let types: Vec<ColumnType> = plan.columns.iter().map(|(_, t)| *t).collect();
+            let order_types: Vec<ColumnType> = def.order_by.iter().map(|k| k.expr.data_type(&types)).collect();
+            let keys: Vec<SortKey> = def
+                .partition_by
+                .iter()
+                .map(|e| SortKey {
+                    expr: e.clone(),
+                    desc: false,
+                    nulls_first: false,
+                })
+                .chain(def.order_by.iter().cloned())
+                .collect();
+            if !sorted_by.starts_with(&keys) {
+                plan = self.sort(plan, keys.clone(), None);
+                sorted_by = keys;
+            }
+            let names: Vec<String> = plan.columns.iter().map(|(n, _)| n.clone()).collect();
+            let name = |c: usize| names[c].clone();
+            let mut columns = plan.columns.clone();
+            let mut functions = Vec::new();
+            for &j in &calls {
+                let call = &windows[j].1;
+                call.frame.validate(&order_types)?;
+                let arg_types: Vec<ColumnType> = call.args.iter().map(|a| a.data_type(&types)).collect();
+                columns.push((call.display_with(&name), call.func.result_type(&arg_types)?));
+                functions.push(call.clone());
+            }
+            plan = Plan {
+                columns,
+                est_rows: plan.est_rows,
+                est_cost: plan.est_cost + plan.est_rows * CPU_OPERATOR_COST * calls.len() as f64,
+                node: Node::Window {
+                    partition_by: def.partition_by.clone(),
+                    order_by: def.order_by.clone(),
+                    functions,
+                },
+                children: vec![plan],
+            };
+            appended.extend(calls);
+        }
+        Ok((plan, appended, sorted_by))
+    }
+
+    /// The table a FOR UPDATE / FOR SHARE clause locks rows of, and in which mode; None
+    /// without one. Only a SELECT from a single table can lock rows. With several clauses
+    /// the strongest mode wins.
+    fn row_lock(scope: &Scope, rels: &[Relation], locks: &[LockClause]) -> Result<Option<(String, LockMode)>> {
+        let Some(first) = locks.first() else {
+            return Ok(None);
+        };
+        let table = match rels {
+            [Relation {
+                source: Source::Table { table, .. },
+                ..
+            }] => table.clone(),
+            _ => bail!("FOR {} is only supported on a SELECT from a single table", first.lock_type),
+        };
+        let mut mode = LockMode::Shared;
+        for lock in locks {
+            if let Some(nonblock) = &lock.nonblock {
+                bail!("FOR {} {} is not supported", lock.lock_type, nonblock);
+            }
+            if let Some(of) = &lock.of {
+                let name = object_name(of)?;
+                if name != scope.rels[0].name {
+                    bail!("relation {} in FOR {} clause not found in FROM clause", name, lock.lock_type);
+                }
+            }
+            if lock.lock_type == LockType::Update {
+                mode = LockMode::Exclusive;
+            }
+        }
+        Ok(Some((table, mode)))
+    }
+
+    /// `input`, a table's full rows possibly followed by other values, with each row locked
+    /// (see `Node::LockRows`).
+    fn lock_rows(input: Plan, table: String, mode: LockMode) -> Plan {
+        Plan {
+            columns: input.columns.clone(),
+            est_rows: input.est_rows,
+            est_cost: input.est_cost + input.est_rows * (RANDOM_PAGE_COST + CPU_TUPLE_COST),
+            node: Node::LockRows { table, mode },
+            children: vec![input],
+        }
+    }
+
+    fn project(input: Plan, exprs: Vec<Expr>, columns: Vec<(String, ColumnType)>) -> Plan {
+        Plan {
+            columns,
+            est_rows: input.est_rows,
+            est_cost: input.est_cost + input.est_rows * CPU_OPERATOR_COST * exprs.len() as f64,
+            node: Node::Project { exprs },
+            children: vec![input],
+        }
+    }
+
+    /// Hash or sorted aggregation of `input` on `group_by`, whichever costs less. `work` is
+    /// the cost of reading the input and computing `groups` groups of `width` values;
+    /// `sortable` is None if sorted aggregation cannot be used, else whether the input is
+    /// already sorted. Returns the strategy, the aggregate's child and the total cost.
+    fn aggregate_strategy(
+        &self,
+        input: Plan,
+        group_by: &[Expr],
+        sortable: Option<bool>,
+        groups: f64,
+        width: usize,
+        work: f64,
+    ) -> (AggStrategy, Plan, f64) {
+        let rows = input.est_rows;
+        let work_mem = (self.db.config().work_mem_kb * 1024) as f64;
+        let hash_cost = if groups * row_width(width) > work_mem {
+            work + 2.0 * SEQ_PAGE_COST * rows * row_width(input.columns.len()) / PAGE_SIZE as f64
+        } else {
+            work
+        };
+        let Some(presorted) = sortable else {
+            return (AggStrategy::Hash, input, hash_cost);
+        };
+        let sorted_cost = work
+            + if presorted {
+                0.0
+            } else {
+                sort_cost(rows) + sort_spill_cost(rows, input.columns.len(), work_mem)
+            };
+        if hash_cost < sorted_cost {
+            return (AggStrategy::Hash, input, hash_cost);
+        }
+        let child = if presorted {
+            input
+        } else {
+            let keys = group_by
+                .iter()
+                .map(|k| SortKey {
+                    expr: k.clone(),
+                    desc: false,
+                    nulls_first: false,
+                })
+                .collect();
+            self.sort(input, keys, None)
+        };
+        (AggStrategy::Sorted, child, sorted_cost)
+    }
+
+    /// GROUP BY: the distinct key expressions, over global ids, and the grouping sets as
+    /// indexes into them. Keys may also name select items by position or alias.
+    fn group_keys(
+        &self,
+        scope: &Scope,
+        group_by: &ast::GroupByExpr,
+        items: &[(String, SelectExpr)],
+    ) -> Result<(Vec<Expr>, Vec<Vec<usize>>)> {
+        let ast::GroupByExpr::Expressions(exprs) = group_by else {
+            bail!("GROUP BY ALL is not supported");
+        };
+        let mut keys: Vec<Expr> = Vec::new();
+        let mut key = |e: &ast::Expr| -> Result<usize> {
+            let bound = match e {
+                ast::Expr::Value(ast::Value::Number(n, _)) => match n.parse::<usize>() {
+                    Ok(i) if (1..=items.len()).contains(&i) => items[i - 1].1.bind(scope)?,
+                    _ => bail!("GROUP BY position {} is not in select list", n),
+                },
+                ast::Expr::Identifier(id) if scope.resolve(std::slice::from_ref(id)).is_err() => {
+                    match items.iter().find(|(n, _)| *n == ident(id)) {
+                        Some((_, item)) => item.bind(scope)?,
+                        None => bind(scope, e)?,
+                    }
+                }
+                e => bind(scope, e)?,
+            };
+            Ok(match keys.iter().position(|k| *k == bound) {
+                Some(i) => i,
+                None => {
+                    keys.push(bound);
+                    keys.len() - 1
+                }
+            })
+        };
+        // Each GROUP BY item offers alternatives; the grouping sets are their cross product.
+        let mut sets: Vec<Vec<usize>> = vec![vec![]];
+        for item in exprs {
+            let mut elements = |list: &[Vec<ast::Expr>]| -> Result<Vec<Vec<usize>>> {
+                list.iter().map(|l| l.iter().map(&mut key).collect()).collect()
+            };
+            let alternatives: Vec<Vec<usize>> = match item {
+                ast::Expr::GroupingSets(list) => elements(list)?,
+                ast::Expr::Rollup(list) => {
+                    let elems = elements(list)?;
+                    (0..=elems.len()).rev().map(|n| elems[..n].concat()).collect()
+                }
+                ast::Expr::Cube(list) => {
+                    let elems = elements(list)?;
+                    let n = elems.len();
+                    if n > MAX_CUBE_ELEMENTS {
+                        bail!("CUBE is limited to {} elements", MAX_CUBE_ELEMENTS);
+                    }
+                    // Largest sets first, earlier elements before later ones.
+                    (0..1usize << n)
+                        .rev()
+                        .map(|mask| {
+                            (0..n)
+                                .filter(|i| mask & (1 << (n - 1 - i)) != 0)
+                                .flat_map(|i| elems[i].clone())
+                                .collect()
+                        })
+                        .collect()
+                }
+                e => vec![vec![key(e)?]],
+            };
+            sets = sets
+                .iter()
+                .flat_map(|set| {
+                    alternatives.iter().map(move |alt| {
+                        let mut set = set.clone();
+                        for k in alt {
+                            if !set.contains(k) {
+                                set.push(*k);
+                            }
+                        }
+                        set
+                    })
+                })
+                .collect();
+        }
+        Ok((keys, sets))
+    }
+
+    /// A query block with GROUP BY, HAVING or aggregates: input, aggregation, subqueries,
+    /// HAVING filter, ORDER BY and the select list, the last four over the aggregate's
+    /// output.
+    #[allow(clippy::too_many_arguments)]
+    fn plan_grouped(
+        &self,
+        s: &Select,
+        order_by: &[OrderByExpr],
+        bound: Option<u64>,
+        scope: &Scope,
+        items: &[(String, SelectExpr)],
+        planner: Option<&JoinPlanner>,
+        input: Planned,
+        mut links: Sublinks,
+    ) -> Result<Plan> {
+        let (keys, sets) = self.group_keys(scope, &s.group_by, items)?;
+        let mut aggs: Vec<AggCall> = Vec::new();
+        let mut windows = Vec::new();
+        let first = links.applied;
+        let sublink = &mut |node: &ast::Expr, operand| self.sublink(scope, node, operand, &mut links);
+        let mut exprs: Vec<(String, Expr)> = items
+            .iter()
+            .map(|(n, item)| {
+                let e = match item {
+                    SelectExpr::Ast(e) => {
+                        bind_grouped(scope, e, &keys, &mut aggs, Some(&mut windows), Some(&mut *sublink))?
+                    }
+                    SelectExpr::Column(gid) => match keys.iter().position(|k| *k == Expr::Column(*gid)) {
+                        Some(i) => Expr::Column(i),
+                        None => bail!("column {} must appear in the GROUP BY clause or be used in an aggregate function", n),
+                    },
+                };
+                Ok((n.clone(), e))
+            })
+            .collect::<Result<_>>()?;
+        let mut having = match &s.having {
+            Some(h) => Some(bind_grouped(scope, h, &keys, &mut aggs, None, Some(&mut *sublink))?),
+            None => None,
+        };
+        let mut sort_keys = Vec::new();
+        for o in order_by {
+            let expr = match output_column(&o.expr, &exprs)? {
+                Some(i) => exprs[i].1.clone(),
+                None => bind_grouped(scope, &o.expr, &keys, &mut aggs, Some(&mut windows), Some(&mut *sublink))?,
+            };
+            sort_keys.push(Self::sort_key(expr, o));
+        }
+
+        // Aggregate output columns: the keys, then the aggregates.
+        let types = scope.types();
+        let group_by: Vec<Expr> = keys.iter().map(|k| input.remap(k)).collect();
+        let aggregates: Vec<AggCall> = aggs
+            .iter()
+            .map(|a| AggCall {
+                func: a.func.clone(),
+                args: a.args.iter().map(|e| input.remap(e)).collect(),
+                distinct: a.distinct,
+                filter: a.filter.as_ref().map(|f| input.remap(f)),
+            })
+            .collect();
+        let input_name = |i: usize| input.plan.columns[i].0.clone();
+        let mut columns: Vec<(String, ColumnType)> = group_by
+            .iter()
+            .zip(&keys)
+            .map(|(k, global)| (k.display_with(&input_name), global.data_type(&types)))
+            .collect();
+        for (a, global) in aggregates.iter().zip(&aggs) {
+            let arg_types: Vec<ColumnType> = global.args.iter().map(|e| e.data_type(&types)).collect();
+            columns.push((a.display_with(&input_name), a.func.result_type(&arg_types)?));
+        }
+
+        // Hash aggregation unless the input is (or is worth) sorting on a single set of keys.
+        let rows = input.plan.est_rows;
+        let groups: f64 = sets
+            .iter()
+            .map(|set| {
+                let set_keys: Vec<Expr> = set.iter().map(|&k| keys[k].clone()).collect();
+                planner.map_or(1.0, |p| p.estimate_groups(&set_keys, rows))
+            })
+            .sum();
+        let work = input.plan.est_cost
+            + rows * CPU_OPERATOR_COST * (keys.len() + aggs.len()) as f64 * sets.len() as f64
+            + groups * CPU_TUPLE_COST;
+        let presorted = match keys.as_slice() {
+            [] => true,
+            [Expr::Column(c)] => input.sorted_on == Some(*c),
+            _ => false,
+        };
+        let sortable = (sets.len() == 1).then_some(presorted);
+        let (strategy, child, cost) =
+            self.aggregate_strategy(input.plan, &group_by, sortable, groups, keys.len() + aggs.len(), work);
+        let mut plan = Plan {
+            node: Node::Aggregate {
+                strategy,
+                group_by,
+                sets,
+                aggregates,
+            },
+            columns,
+            est_rows: groups,
+            est_cost: cost,
+            children: vec![child],
+        };
+        if links.applied < links.list.len() {
+            // Subqueries read the group keys of the outer query.
+            let base = plan.columns.len();
+            let param = |p: &Expr| match keys.iter().position(|k| k == p) {
+                Some(i) => Ok(Expr::Column(i)),
+                None if p.is_constant() => Ok(p.clone()),
+                None => bail!("subquery uses an ungrouped column of the outer query"),
+            };
+            plan = Self::apply_sublinks(plan, &mut (0..base).collect(), &mut links, &param)?;
+            let position = |c: usize| match c.checked_sub(SUBLINK_COLUMNS) {
+                Some(i) if c < WINDOW_COLUMNS => base + i - first,
+                _ => c,
+            };
+            for (_, e) in &mut exprs {
+                *e = e.map_columns(&position);
+            }
+            for k in &mut sort_keys {
+                k.expr = k.expr.map_columns(&position);
+            }
+            having = having.map(|h| h.map_columns(&position));
+        }
+        if let Some(predicate) = having {
+            plan = Plan {
+                columns: plan.columns.clone(),
+                est_rows: (plan.est_rows * selectivity(&predicate, &|_| None)).max(1.0),
+                est_cost: plan.est_cost + plan.est_rows * CPU_OPERATOR_COST,
+                node: Node::Filter { predicate },
+                children: vec![plan],
+            };
+        }
+        let mut sorted_by = Vec::new();
+        if !windows.is_empty() {
+            let base = plan.columns.len();
+            let (windowed, appended, windows_sorted_by) = self.plan_windows(plan, windows, vec![])?;
+            let position = |c: usize| match c.checked_sub(WINDOW_COLUMNS) {
+                Some(i) => base + appended.iter().position(|a| *a == i).expect("window call planned"),
+                None => c,
+            };
+            for (_, e) in &mut exprs {
+                *e = e.map_columns(&position);
+            }
+            for k in &mut sort_keys {
+                k.expr = k.expr.map_columns(&position);
+            }
+            plan = windowed;
+            sorted_by = windows_sorted_by;
+        }
+        let sorted = if sorted_by.starts_with(&sort_keys) {
+            plan
+        } else {
+            self.sort(plan, sort_keys, bound)
+        };
+        let types: Vec<ColumnType> = sorted.columns.iter().map(|(_, t)| *t).collect();
+        let columns = exprs.iter().map(|(n, e)| (n.clone(), e.data_type(&types))).collect();
+        Ok(Self::project(sorted, exprs.into_iter().map(|(_, e)| e).collect(), columns))
+    }
+
+    /// `rel.*`: its visible columns.
+    fn star(r: &ScopeRel) -> Vec<(String, SelectExpr<'static>)> {
+        r.columns
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !r.hidden[*i])
+            .map(|(i, (n, _))| (n.clone(), SelectExpr::Column(r.offset + i)))
+            .collect()
+    }
+
+    fn plan_insert(
+        &self,
+        table: &str,
+        alias: Option<&ast::Ident>,
+        columns: &[ast::Ident],
+        source: &Query,
+        on_conflict: Option<&ast::OnConflict>,
+    ) -> Result<Plan> {
+        self.check_writable(table)?;
+        let Some(def) = self.db.table_def(table) else {
+            bail!("table {} does not exist", table);
+        };
+        let targets: Vec<usize> = if columns.is_empty() {
+            (0..def.columns.len()).filter(|i| !def.columns[*i].hidden).collect()
+        } else {
+            let mut out = Vec::new();
+            for c in columns {
+                let name = ident(c);
+                let Some(i) = def.column_index(&name) else {
+                    bail!("column {} of relation {} does not exist", name, table);
+                };
+                if out.contains(&i) {
+                    bail!("column {} specified more than once", name);
+                }
+                out.push(i);
+            }
+            out
+        };
+        let values = match &*source.body {
+            SetExpr::Values(v) => Some(v),
+            _ => None,
+        };
+        // A GENERATED ALWAYS column can only be written as DEFAULT.
+        let defaulted = |k: usize| values.is_some_and(|v| v.rows.iter().all(|r| r.get(k).is_some_and(is_default)));
+        let always = |(_, i): &(usize, &usize)| def.columns[**i].identity.as_ref().is_some_and(|i| i.always);
+        if let Some((_, i)) = targets.iter().enumerate().filter(always).find(|(k, _)| !defaulted(*k)) {
+            bail!(
+                "cannot insert a non-DEFAULT value into column {}: it is GENERATED ALWAYS AS IDENTITY",
+                def.columns[*i].name
+            );
+        }
+        let source = match values {
+            Some(v) if v.rows.iter().flatten().any(is_default) => {
+                Cow::Owned(Self::replace_defaults(&def, &targets, source)?)
+            }
+            _ => Cow::Borrowed(source),
+        };
+        // Parameters in VALUES take the types of their target columns.
+        if let SetExpr::Values(v) = &*source.body {
+            let scope = self.scope();
+            for row in &v.rows {
+                for (e, &col) in row.iter().zip(&targets) {
+                    scope.hint(e, def.columns[col].ty);
+                }
+            }
+        }
+        let source = self.plan_query(&source)?;
+        if source.columns.len() > targets.len() {
+            bail!("INSERT has more expressions than target columns");
+        }
+        if source.columns.len() < targets.len() {
+            bail!("INSERT has more target columns than expressions");
+        }
+        let (source, targets) = self.fill_defaults(&def, source, targets)?;
+        let on_conflict = match on_conflict {
+            Some(c) => Some(self.on_conflict(&def, alias, c)?),
+            None => None,
+        };
+        Ok(Plan {
+            node: Node::Insert {
+                table: table.to_string(),
+                columns: targets,
+                on_conflict,
+            },
+            columns: def.columns.iter().map(|c| (c.name.clone(), c.ty)).collect(),
+            est_rows: source.est_rows,
+            est_cost: source.est_cost + source.est_rows * CPU_TUPLE_COST,
+            children: vec![source],
+        })
+    }
+
+    /// `source`, the rows of an INSERT into `def` for columns `targets`, with a value added
+    /// for each column they leave out that has one: the next value of its sequence for an
+    /// identity column, else its DEFAULT expression.
+    fn fill_defaults(&self, def: &TableDef, source: Plan, mut targets: Vec<usize>) -> Result<(Plan, Vec<usize>)> {
+        let mut exprs: Vec<Expr> = (0..source.columns.len()).map(Expr::Column).collect();
+        let mut columns = source.columns.clone();
+        for (i, c) in def.columns.iter().enumerate() {
+            if targets.contains(&i) {
+                continue;
+            }
+            let expr = match &c.identity {
+                Some(identity) => Expr::Function {
+                    func: ScalarFunc::Sequence(self.sequences.func(SequenceOp::Next)),
+                    args: vec![Expr::Literal(Value::Text(format!("\"{}\"", identity.sequence)))],
+                    ty: ColumnType::Int,
+                },
+                None => match self.column_default(c)? {
+                    Some(e) => e,
+                    None => continue,
+                },
+            };
+            exprs.push(expr);
+            columns.push((c.name.clone(), c.ty));
+            targets.push(i);
+        }
+        if columns.len() == source.columns.len() {
+            return Ok((source, targets));
+        }
+        let plan = Plan {
+            node: Node::Project { exprs },
+            columns,
+            est_rows: source.est_rows,
+            est_cost: source.est_cost + source.est_rows * CPU_OPERATOR_COST,
+            children: vec![source],
+        };
+        Ok((plan, targets))
+    }
+
+    /// `source`, the VALUES of an INSERT into `def` for columns `targets`, with each DEFAULT
+    /// in it replaced by its column's default, NULL if the column has none.
+    fn replace_defaults(def: &TableDef, targets: &[usize], source: &Query) -> Result<Query> {
+        let mut source = source.clone();
+        if let SetExpr::Values(v) = &mut *source.body {
+            for row in &mut v.rows {
+                for (e, &i) in row.iter_mut().zip(targets).filter(|(e, _)| is_default(e)) {
+                    let c = &def.columns[i];
+                    let sql = match (&c.identity, &c.default) {
+                        (Some(identity), _) => format!("nextval('\"{}\"')", identity.sequence.replace('\'', "''")),
+                        (None, Some(default)) => default.clone(),
+                        (None, None) => "NULL".to_string(),
+                    };
+                    *e = Parser::new(&GenericDialect {}).try_with_sql(&sql)?.parse_expr()?;
+                }
+            }
+        }
+        Ok(source)
+    }
+
+    /// ON CONFLICT of an INSERT into `def`. Only the key can conflict, so the conflict
+    /// target must name the key column. DO UPDATE expressions see the existing row under the
+    /// table's name (or alias) and the row proposed for insertion as `excluded`.
+    fn on_conflict(&self, def: &TableDef, alias: Option<&ast::Ident>, c: &ast::OnConflict) -> Result<OnConflict> {
+        match &c.conflict_target {
+            Some(ast::ConflictTarget::Columns(cols)) => {
+                let key = &def.columns[def.key_col];
+                if key.hidden || !matches!(cols.as_slice(), [c] if ident(c) == key.name) {
+                    bail!("there is no unique or exclusion constraint matching the ON CONFLICT specification");
+                }
+            }
+            Some(ast::ConflictTarget::OnConstraint(name)) => {
+                bail!("ON CONFLICT ON CONSTRAINT is not supported: {}", name)
+            }
+            None => {}
+        }
+        let ast::OnConflictAction::DoUpdate(update) = &c.action else {
+            return Ok(OnConflict::DoNothing);
+        };
+        if c.conflict_target.is_none() {
+            bail!("ON CONFLICT DO UPDATE requires inference specification or constraint name");
+        }
+        let columns: Vec<(String, ColumnType)> = def.columns.iter().map(|c| (c.name.clone(), c.ty)).collect();
+        let hidden: Vec<bool> = def.columns.iter().map(|c| c.hidden).collect();
+        let mut scope = self.scope();
+        let name = alias.map_or(def.name.clone(), ident);
+        scope.add(name, columns.clone(), hidden.clone())?;
+        scope.add("excluded".to_string(), columns, hidden)?;
+        let mut assignments: Vec<(usize, Expr)> = Vec::new();
+        for a in &update.assignments {
+            let i = Self::assignment_column(&scope.rels[0], &def.name, a, &assignments)?;
+            Self::check_identity_update(def, i)?;
+            scope.hint(&a.value, scope.rels[0].columns[i].1);
+            assignments.push((i, bind(&scope, &a.value)?));
+        }
+        let filter = match &update.selection {
+            Some(e) => Some(bind(&scope, e)?),
+            None => None,
+        };
+        Ok(OnConflict::DoUpdate { assignments, filter })
+    }
+
+    /// Fail if column `i` of `def` is GENERATED ALWAYS AS IDENTITY, which UPDATE may not set.
+    fn check_identity_update(def: &TableDef, i: usize) -> Result<()> {
+        let c = &def.columns[i];
+        if c.identity.as_ref().is_some_and(|i| i.always) {
+            bail!("column {} can only be updated to DEFAULT: it is GENERATED ALWAYS AS IDENTITY", c.name);
+        }
+        Ok(())
+    }
+
+    /// The column of `rel` an UPDATE assignment sets, given those already set.
+    fn assignment_column(rel: &ScopeRel, table: &str, a: &ast::Assignment, sets: &[(usize, Expr)]) -> Result<usize> {
+        let Some(col) = a.id.last() else {
+            bail!("empty assignment target");
+        };
+        let name = ident(col);
+        let Some(i) = rel.columns.iter().position(|(n, _)| *n == name) else {
+            bail!("column {} of relation {} does not exist", name, table);
+        };
+        if rel.hidden[i] {
+            bail!("column {} cannot be updated", name);
+        }
+        if sets.iter().any(|(c, _)| *c == i) {
+            bail!("multiple assignments to same column {}", name);
+        }
+        Ok(i)
+    }
+
+    /// RETURNING: a projection of the rows an INSERT, UPDATE or DELETE wrote, with the
+    /// target table visible as `name`.
+    fn plan_returning(&self, plan: Plan, name: String, items: &[SelectItem]) -> Result<Plan> {
+        let Some(Node::Insert { table, .. } | Node::Update { table, .. } | Node::Delete { table }) = plan.modification()
+        else {
+            bail!("RETURNING is only valid on INSERT, UPDATE and DELETE");
+        };
+        let Some(def) = self.db.table_def(table) else {
+            bail!("table {} does not exist", table);
+        };
+        let mut scope = self.scope();
+        scope.add(name, plan.columns.clone(), def.columns.iter().map(|c| c.hidden).collect())?;
+        let rel = &scope.rels[0];
+        let mut returned: Vec<(String, SelectExpr)> = Vec::new();
+        for item in items {
+            match item {
+                SelectItem::UnnamedExpr(e) => returned.push((output_name(e), SelectExpr::Ast(e))),
+                SelectItem::ExprWithAlias { expr, alias } => returned.push((ident(alias), SelectExpr::Ast(expr))),
+                SelectItem::Wildcard(_) => returned.extend(Self::star(rel)),
+                SelectItem::QualifiedWildcard(name, _) => {
+                    let name = object_name(name)?;
+                    if name != rel.name {
+                        bail!("missing FROM-clause entry for table {}", name);
+                    }
+                    returned.extend(Self::star(rel));
+                }
+            }
+        }
+        let bound: Vec<(String, Expr)> = returned
+            .into_iter()
+            .map(|(n, e)| Ok((n, e.bind(&scope)?)))
+            .collect::<Result<_>>()?;
+        let types = scope.types();
+        let columns = bound.iter().map(|(n, e)| (n.clone(), e.data_type(&types))).collect();
+        Ok(Self::project(plan, bound.into_iter().map(|(_, e)| e).collect(), columns))
+    }
+
+    /// Rows of the single DML target table matching `selection`, as full table rows
+    /// followed by the values of the subqueries of `links` applied so far.
+    fn target_rows(
+        &self,
+        target: &TableWithJoins,
+        selection: Option<&ast::Expr>,
+        links: &mut Sublinks,
+    ) -> Result<(String, Scope, Planned)> {
+        if !target.joins.is_empty() {
+            bail!("joins in UPDATE or DELETE targets are not supported");
+        }
+        if let TableFactor::Table { name, .. } = &target.relation {
+            self.check_writable(&object_name(name)?)?;
+        }
+        let mut scope = self.scope();
+        let (rels, conjuncts, residual) = self.plan_from(&mut scope, std::slice::from_ref(target), selection, links)?;
+        let Source::Table { table, .. } = &rels[0].source else {
+            bail!("{} is not a table", target.relation);
+        };
+        let table = table.clone();
+        let planned = self.join_planner(&rels, conjuncts).plan()?;
+        let planned = self.plan_residual(planned, residual, links)?;
+        Ok((table, scope, planned))
+    }
+
+    /// An UPDATE, and the name its target is visible under.
+    fn plan_update(
+        &self,
+        target: &TableWithJoins,
+        assignments: &[ast::Assignment],
+        selection: Option<&ast::Expr>,
+    ) -> Result<(Plan, String)> {
+        let mut links = Sublinks::default();
+        let (table, scope, input) = self.target_rows(target, selection, &mut links)?;
+        let Some(def) = self.db.table_def(&table) else {
+            bail!("table {} does not exist", table);
+        };
+        let rel = &scope.rels[0];
+        let mut sets: Vec<(usize, Expr)> = Vec::new();
+        for a in assignments {
+            let i = Self::assignment_column(rel, &table, a, &sets)?;
+            Self::check_identity_update(&def, i)?;
+            scope.hint(&a.value, rel.columns[i].1);
+            sets.push((i, self.bind_sublinks(&scope, &a.value, &mut links)?));
+        }
+        let input = Self::apply_planned(input, &mut links)?;
+        let sets = sets.into_iter().map(|(i, e)| (i, input.remap(&e))).collect();
+        let rows = input.plan.est_rows;
+        let plan = Plan {
+            node: Node::Update { table, assignments: sets },
+            columns: rel.columns.clone(),
+            est_rows: rows,
+            est_cost: input.plan.est_cost + rows * (RANDOM_PAGE_COST + CPU_TUPLE_COST),
+            children: vec![input.plan],
+        };
+        Ok((plan, rel.name.clone()))
+    }
+
+    /// A DELETE, and the name its target is visible under.
+    fn plan_delete(&self, target: &TableWithJoins, selection: Option<&ast::Expr>) -> Result<(Plan, String)> {
+        let (table, scope, input) = self.target_rows(target, selection, &mut Sublinks::default())?;
+        let rows = input.plan.est_rows;
+        let rel = &scope.rels[0];
+        let plan = Plan {
+            node: Node::Delete { table },
+            columns: rel.columns.clone(),
+            est_rows: rows,
+            est_cost: input.plan.est_cost + rows * (RANDOM_PAGE_COST + CPU_TUPLE_COST),
+            children: vec![input.plan],
+        };
+        Ok((plan, rel.name.clone()))
+    }
+}
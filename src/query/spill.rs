@@ -0,0 +1,174 @@
+//! Temporary files for operators that outgrow `work_mem`: rows are appended and read back
+//! in the same order. Files live in the database's temp directory and are deleted when the
+//! `SpillFile` is dropped (and left-overs when the database is opened).
+
+use anyhow::{bail, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::storage::Value;
+
+/// Directory under the data directory holding spill files.
+pub const TEMP_DIR: &str = "tmp";
+
+static NEXT_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// Approximate in-memory size of a row, for `work_mem` accounting.
+pub fn row_size(row: &[Value]) -> usize {
+    let heap: usize = row
+        .iter()
+        .map(|v| match v {
+            Value::Text(s) => s.capacity(),
+            _ => 0,
+        })
+        .sum();
+    std::mem::size_of::<Vec<Value>>() + std::mem::size_of_val(row) + heap
+}
+
+/// An append-only temporary file of rows.
+pub struct SpillFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    rows: u64,
+    bytes: u64,
+}
+
+impl SpillFile {
+    pub fn create(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let n = NEXT_FILE.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("spill-{}-{}", std::process::id(), n));
+        let file = OpenOptions::new().write(true).create_new(true).open(&path)?;
+        Ok(Self {
+            path,
+            writer: BufWriter::new(file),
+            rows: 0,
+            bytes: 0,
+        })
+    }
+
+    pub fn write(&mut self, row: &[Value]) -> Result<()> {
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(&(row.len() as u32).to_le_bytes());
+        for v in row {
+            match v {
+                Value::Null => buf.push(0),
+                Value::Int(n) => {
+                    buf.push(1);
+                    buf.extend_from_slice(&n.to_le_bytes());
+                }
+                Value::Float(x) => {
+                    buf.push(2);
+                    buf.extend_from_slice(&x.to_le_bytes());
+                }
+                Value::Text(s) => {
+                    buf.push(3);
+                    buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+                    buf.extend_from_slice(s.as_bytes());
+                }
+                Value::Bool(b) => buf.extend_from_slice(&[4, *b as u8]),
+            }
+        }
+        self.writer.write_all(&buf)?;
+        self.rows += 1;
+        self.bytes += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Rows written so far.
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// Bytes written so far.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Read back every row written so far, from the start.
+    pub fn reader(&mut self) -> Result<SpillReader> {
+        self.writer.flush()?;
+        Ok(SpillReader {
+            reader: BufReader::new(File::open(&self.path)?),
+            remaining: self.rows,
+        })
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            tracing::warn!(path = %self.path.display(), error = %e, "could not remove spill file");
+        }
+    }
+}
+
+pub struct SpillReader {
+    reader: BufReader<File>,
+    remaining: u64,
+}
+
+impl SpillReader {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut b = [0u8; N];
+        self.reader.read_exact(&mut b)?;
+        Ok(b)
+    }
+
+    pub fn next_row(&mut self) -> Result<Option<Vec<Value>>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        let n = u32::from_le_bytes(self.bytes()?) as usize;
+        let mut row = Vec::with_capacity(n);
+        for _ in 0..n {
+            let [tag] = self.bytes()?;
+            row.push(match tag {
+                0 => Value::Null,
+                1 => Value::Int(i64::from_le_bytes(self.bytes()?)),
+                2 => Value::Float(f64::from_le_bytes(self.bytes()?)),
+                3 => {
+                    let len = u32::from_le_bytes(self.bytes()?) as usize;
+                    let mut s = vec![0u8; len];
+                    self.reader.read_exact(&mut s)?;
+                    Value::Text(String::from_utf8(s)?)
+                }
+                4 => Value::Bool(self.bytes::<1>()?[0] != 0),
+                other => bail!("corrupt spill file: value tag {}", other),
+            });
+        }
+        Ok(Some(row))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_roundtrip_and_file_is_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut f = SpillFile::create(&dir.path().join(TEMP_DIR)).unwrap();
+        let rows = vec![
+            vec![Value::Int(-3), Value::Null, Value::Text("héllo".into())],
+            vec![Value::Float(2.5), Value::Bool(true), Value::Text(String::new())],
+            vec![],
+        ];
+        for r in &rows {
+            f.write(r).unwrap();
+        }
+        let mut r = f.reader().unwrap();
+        let mut back = Vec::new();
+        while let Some(row) = r.next_row().unwrap() {
+            back.push(row);
+        }
+        assert_eq!(back, rows);
+        assert_eq!(f.rows(), 3);
+        let path = f.path.clone();
+        drop(f);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,431 @@
+//! ANALYZE: per-column statistics from a row sample, and the selectivity estimates the
+//! optimizer derives from them.
+//!
+//! Rows are sampled with a reservoir of `SAMPLE_ROWS` over a full visible scan, so the row
+//! count is exact while the per-column figures come from the sample. The number of distinct
+//! values is scaled up from the sample with the Haas–Stokes "Duj1" estimator.
+
+use anyhow::Result;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use crate::catalog::{ColumnStats, TableStats};
+use crate::query::expr::{BinaryOp, Expr, UnaryOp};
+use crate::storage::{Table, Value};
+use crate::txn::Transaction;
+
+/// Rows kept in the ANALYZE sample.
+pub const SAMPLE_ROWS: usize = 30_000;
+/// Most common values kept per column.
+pub const MCV_TARGET: usize = 10;
+/// Histogram buckets per column.
+pub const HISTOGRAM_BUCKETS: usize = 100;
+
+/// Selectivity of `col = const` when nothing is known about the column.
+pub const DEFAULT_EQ_SEL: f64 = 0.005;
+/// Selectivity of `col < const` (and friends) when nothing is known about the column.
+pub const DEFAULT_INEQ_SEL: f64 = 1.0 / 3.0;
+/// Selectivity of `lo < col < hi` when nothing is known about the column.
+pub const DEFAULT_RANGE_SEL: f64 = 0.005;
+/// Selectivity of a predicate we cannot analyse at all.
+pub const DEFAULT_SEL: f64 = 0.5;
+/// Distinct values of a grouping expression we know nothing about (as in PostgreSQL).
+pub const DEFAULT_NUM_DISTINCT: f64 = 200.0;
+
+/// Small deterministic generator for reservoir sampling (xorshift64*).
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) % n
+    }
+}
+
+/// Scan `table` as `txn` sees it and compute its statistics.
+pub fn analyze(txn: &mut Transaction, table: &Table) -> Result<TableStats> {
+    txn.note_table_scan(table);
+    let pages = table.num_pages();
+    let mut sample: Vec<Vec<Value>> = Vec::new();
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let mut seen = 0u64;
+    for page_id in 0..pages {
+        for (_, row) in txn.scan_page(table, page_id)? {
+            seen += 1;
+            if sample.len() < SAMPLE_ROWS {
+                sample.push(row);
+            } else {
+                let j = rng.below(seen) as usize;
+                if j < SAMPLE_ROWS {
+                    sample[j] = row;
+                }
+            }
+        }
+    }
+    let columns = (0..table.schema().len())
+        .map(|c| column_stats(sample.iter().map(|r| &r[c]), sample.len(), seen))
+        .collect();
+    Ok(TableStats {
+        row_count: seen,
+        pages,
+        columns,
+    })
+}
+
+fn encoded_width(v: &Value) -> usize {
+    match v {
+        Value::Null => 0,
+        Value::Int(_) | Value::Float(_) => 8,
+        Value::Bool(_) => 1,
+        Value::Text(s) => 4 + s.len(),
+    }
+}
+
+/// Statistics of one column from `n` sampled values out of `total` rows.
+fn column_stats<'a>(values: impl Iterator<Item = &'a Value>, n: usize, total: u64) -> ColumnStats {
+    let mut counts: HashMap<&Value, usize> = HashMap::new();
+    let mut nulls = 0;
+    let mut width = 0;
+    for v in values {
+        if v.is_null() {
+            nulls += 1;
+        } else {
+            width += encoded_width(v);
+            *counts.entry(v).or_default() += 1;
+        }
+    }
+    if n == 0 {
+        return ColumnStats::default();
+    }
+    let non_null = n - nulls;
+    let null_frac = nulls as f64 / n as f64;
+    let d = counts.len() as f64;
+    let n_distinct = if n as u64 >= total {
+        d
+    } else {
+        // Duj1: n*d / (n - f1 + f1*n/N), over the non-NULL rows.
+        let f1 = counts.values().filter(|c| **c == 1).count() as f64;
+        let (nn, big_n) = (non_null as f64, total as f64 * (1.0 - null_frac));
+        let est = nn * d / (nn - f1 + f1 * nn / big_n.max(1.0));
+        est.clamp(d, big_n.max(d))
+    };
+
+    let mut by_count: Vec<(&Value, usize)> = counts.iter().map(|(v, c)| (*v, *c)).collect();
+    by_count.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.total_cmp(b.0)));
+    let mcv_len = if counts.len() <= MCV_TARGET && n as u64 >= total {
+        // Every value fits in the list: it describes the column exactly.
+        counts.len()
+    } else {
+        let avg = non_null as f64 / d.max(1.0);
+        by_count
+            .iter()
+            .take(MCV_TARGET)
+            .take_while(|(_, c)| *c >= 2 && *c as f64 > 1.25 * avg)
+            .count()
+    };
+    let mcv: Vec<(Value, f64)> = by_count[..mcv_len]
+        .iter()
+        .map(|(v, c)| ((*v).clone(), *c as f64 / n as f64))
+        .collect();
+
+    let mut rest: Vec<&Value> = by_count[mcv_len..]
+        .iter()
+        .flat_map(|(v, c)| std::iter::repeat_n(*v, *c))
+        .collect();
+    rest.sort_by(|a, b| a.total_cmp(b));
+    let histogram = if rest.len() < 2 {
+        Vec::new()
+    } else {
+        let buckets = HISTOGRAM_BUCKETS.min(rest.len() - 1);
+        (0..=buckets)
+            .map(|i| rest[i * (rest.len() - 1) / buckets].clone())
+            .collect()
+    };
+    ColumnStats {
+        null_frac,
+        n_distinct,
+        avg_width: if non_null == 0 { 0.0 } else { width as f64 / non_null as f64 },
+        mcv,
+        histogram,
+    }
+}
+
+impl ColumnStats {
+    fn mcv_total(&self) -> f64 {
+        self.mcv.iter().map(|(_, f)| f).sum()
+    }
+
+    /// Fraction of rows that are non-NULL and not among the MCVs (what the histogram covers).
+    fn histogram_mass(&self) -> f64 {
+        (1.0 - self.null_frac - self.mcv_total()).max(0.0)
+    }
+
+    /// Selectivity of `col = v`.
+    pub fn eq_selectivity(&self, v: &Value) -> f64 {
+        if v.is_null() {
+            return 0.0;
+        }
+        if let Some((_, f)) = self.mcv.iter().find(|(m, _)| m == v) {
+            return *f;
+        }
+        let others = self.n_distinct - self.mcv.len() as f64;
+        if others < 1.0 {
+            return 0.0;
+        }
+        (self.histogram_mass() / others).clamp(0.0, 1.0)
+    }
+
+    /// Fraction of histogram rows below `v`, interpolating inside numeric buckets.
+    fn histogram_fraction_below(&self, v: &Value) -> Option<f64> {
+        let h = &self.histogram;
+        if h.len() < 2 {
+            return None;
+        }
+        if v.total_cmp(&h[0]) != Ordering::Greater {
+            return Some(0.0);
+        }
+        if v.total_cmp(&h[h.len() - 1]) != Ordering::Less {
+            return Some(1.0);
+        }
+        // h[i] < v <= h[i + 1]
+        let i = h.partition_point(|b| b.total_cmp(v) == Ordering::Less) - 1;
+        let within = match (h[i].as_f64(), h[i + 1].as_f64(), v.as_f64()) {
+            (Some(lo), Some(hi), Some(x)) if hi > lo => (x - lo) / (hi - lo),
+            _ => 0.5,
+        };
+        Some((i as f64 + within) / (h.len() - 1) as f64)
+    }
+
+    /// Selectivity of `lo <(=) col <(=) hi`; a missing bound is unbounded.
+    pub fn range_selectivity(&self, lo: Option<(&Value, bool)>, hi: Option<(&Value, bool)>) -> f64 {
+        let in_range = |v: &Value| {
+            let above = lo.is_none_or(|(b, incl)| match v.sql_cmp(b) {
+                Some(Ordering::Greater) => true,
+                Some(Ordering::Equal) => incl,
+                _ => false,
+            });
+            let below = hi.is_none_or(|(b, incl)| match v.sql_cmp(b) {
+                Some(Ordering::Less) => true,
+                Some(Ordering::Equal) => incl,
+                _ => false,
+            });
+            above && below
+        };
+        let mcv: f64 = self.mcv.iter().filter(|(v, _)| in_range(v)).map(|(_, f)| f).sum();
+        let below = |b: Option<(&Value, bool)>, default: f64| match b {
+            Some((v, _)) => self.histogram_fraction_below(v),
+            None => Some(default),
+        };
+        let hist = match (below(lo, 0.0), below(hi, 1.0)) {
+            (Some(l), Some(h)) => (h - l).max(0.0),
+            _ if self.histogram.is_empty() && !self.mcv.is_empty() => 0.0,
+            _ if lo.is_some() && hi.is_some() => DEFAULT_RANGE_SEL,
+            _ => DEFAULT_INEQ_SEL,
+        };
+        (mcv + hist * self.histogram_mass()).clamp(0.0, 1.0)
+    }
+}
+
+/// Estimated number of groups `keys` split `rows` input rows into: the product of the
+/// keys' distinct values (NULL counting as one), at most `rows`.
+pub fn num_groups(keys: &[Expr], rows: f64, column: &dyn Fn(usize) -> Option<ColumnStats>) -> f64 {
+    let groups: f64 = keys
+        .iter()
+        .map(|k| match k {
+            Expr::Column(c) => column(*c).map_or(DEFAULT_NUM_DISTINCT, |st| {
+                st.n_distinct + if st.null_frac > 0.0 { 1.0 } else { 0.0 }
+            }),
+            k if k.is_constant() => 1.0,
+            _ => DEFAULT_NUM_DISTINCT,
+        })
+        .product();
+    groups.min(rows).max(1.0)
+}
+
+/// Estimated fraction of input rows for which `pred` is true. `column` gives the statistics
+/// of the base-table column behind a column reference, when known.
+pub fn selectivity(pred: &Expr, column: &dyn Fn(usize) -> Option<ColumnStats>) -> f64 {
+    let constant = |e: &Expr| if e.is_constant() { e.eval(&[]).ok() } else { None };
+    let col_const = |l: &Expr, r: &Expr| match (l, constant(r)) {
+        (Expr::Column(c), Some(v)) => Some((*c, v)),
+        _ => None,
+    };
+    let sel = match pred {
+        Expr::Literal(Value::Bool(true)) => 1.0,
+        Expr::Literal(_) => 0.0,
+        Expr::Binary { op: BinaryOp::And, left, right } => {
+            selectivity(left, column) * selectivity(right, column)
+        }
+        Expr::Binary { op: BinaryOp::Or, left, right } => {
+            let (a, b) = (selectivity(left, column), selectivity(right, column));
+            a + b - a * b
+        }
+        Expr::Unary { op: UnaryOp::Not, expr } => 1.0 - selectivity(expr, column),
+        Expr::Binary { op, left, right } if op.is_comparison() => {
+            let (c, v, op) = match (col_const(left, right), col_const(right, left)) {
+                (Some((c, v)), _) => (c, v, *op),
+                (None, Some((c, v))) => (c, v, op.flip()),
+                (None, None) => {
+                    return match (&**left, &**right, op) {
+                        (Expr::Column(a), Expr::Column(b), BinaryOp::Eq) => join_eq_selectivity(*a, *b, column),
+                        (_, _, BinaryOp::Eq) => DEFAULT_EQ_SEL,
+                        (_, _, BinaryOp::NotEq) => 1.0 - DEFAULT_EQ_SEL,
+                        _ => DEFAULT_INEQ_SEL,
+                    };
+                }
+            };
+            compare_selectivity(column(c).as_ref(), op, &v)
+        }
+        Expr::Between { expr, low, high, negated } => match (&**expr, constant(low), constant(high)) {
+            (Expr::Column(c), Some(lo), Some(hi)) => {
+                let s = match column(*c) {
+                    Some(st) => st.range_selectivity(Some((&lo, true)), Some((&hi, true))),
+                    None => DEFAULT_RANGE_SEL,
+                };
+                if *negated {
+                    1.0 - s - column(*c).map_or(0.0, |st| st.null_frac)
+                } else {
+                    s
+                }
+            }
+            _ => DEFAULT_INEQ_SEL,
+        },
+        Expr::InList { expr, list, negated } => {
+            let s: f64 = list
+                .iter()
+                .map(|item| match col_const(expr, item) {
+                    Some((c, v)) => compare_selectivity(column(c).as_ref(), BinaryOp::Eq, &v),
+                    None => DEFAULT_EQ_SEL,
+                })
+                .sum::<f64>()
+                .min(1.0);
+            if *negated {
+                1.0 - s
+            } else {
+                s
+            }
+        }
+        Expr::IsNull { expr, negated } => {
+            let f = match &**expr {
+                Expr::Column(c) => column(*c).map_or(DEFAULT_EQ_SEL, |st| st.null_frac),
+                _ => DEFAULT_EQ_SEL,
+            };
+            if *negated {
+                1.0 - f
+            } else {
+                f
+            }
+        }
+        _ => DEFAULT_SEL,
+    };
+    sel.clamp(0.0, 1.0)
+}
+
+/// Selectivity of `col op v` for a comparison operator.
+fn compare_selectivity(stats: Option<&ColumnStats>, op: BinaryOp, v: &Value) -> f64 {
+    if v.is_null() {
+        return 0.0;
+    }
+    let Some(st) = stats else {
+        return match op {
+            BinaryOp::Eq => DEFAULT_EQ_SEL,
+            BinaryOp::NotEq => 1.0 - DEFAULT_EQ_SEL,
+            _ => DEFAULT_INEQ_SEL,
+        };
+    };
+    match op {
+        BinaryOp::Eq => st.eq_selectivity(v),
+        BinaryOp::NotEq => 1.0 - st.eq_selectivity(v) - st.null_frac,
+        BinaryOp::Lt => st.range_selectivity(None, Some((v, false))),
+        BinaryOp::LtEq => st.range_selectivity(None, Some((v, true))),
+        BinaryOp::Gt => st.range_selectivity(Some((v, false)), None),
+        _ => st.range_selectivity(Some((v, true)), None),
+    }
+}
+
+/// Selectivity of the join clause `a = b`: one over the larger number of distinct values,
+/// as each value of the smaller side is assumed to find its match.
+fn join_eq_selectivity(a: usize, b: usize, column: &dyn Fn(usize) -> Option<ColumnStats>) -> f64 {
+    match (column(a), column(b)) {
+        (Some(x), Some(y)) => {
+            let nd = x.n_distinct.max(y.n_distinct).max(1.0);
+            (1.0 - x.null_frac) * (1.0 - y.null_frac) / nd
+        }
+        (Some(x), None) | (None, Some(x)) => (1.0 - x.null_frac) / x.n_distinct.max(1.0),
+        (None, None) => DEFAULT_EQ_SEL,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ints(vals: impl IntoIterator<Item = i64>) -> Vec<Value> {
+        vals.into_iter().map(Value::Int).collect()
+    }
+
+    #[test]
+    fn column_stats_mcv_histogram_and_nulls() {
+        // 0..1000 once each, 7 repeated 500 times, and 500 NULLs.
+        let mut vals = ints(0..1000);
+        vals.extend(ints(std::iter::repeat_n(7, 500)));
+        vals.extend(std::iter::repeat_n(Value::Null, 500));
+        let n = vals.len();
+        let st = column_stats(vals.iter(), n, n as u64);
+        assert_eq!(st.null_frac, 0.25);
+        assert_eq!(st.n_distinct, 1000.0);
+        assert_eq!(st.avg_width, 8.0);
+        assert_eq!(st.mcv, vec![(Value::Int(7), 501.0 / 2000.0)]);
+        assert_eq!(st.histogram.len(), HISTOGRAM_BUCKETS + 1);
+        assert_eq!(st.histogram[0], Value::Int(0));
+        assert_eq!(st.histogram[HISTOGRAM_BUCKETS], Value::Int(999));
+
+        assert!((st.eq_selectivity(&Value::Int(7)) - 0.2505).abs() < 1e-9);
+        let other = st.eq_selectivity(&Value::Int(3));
+        assert!((other - 0.4995 / 999.0).abs() < 1e-9, "{}", other);
+        // About a tenth of the histogram rows, plus nothing from the MCV list.
+        let s = st.range_selectivity(Some((&Value::Int(100), true)), Some((&Value::Int(200), false)));
+        assert!((s - 0.1 * 0.4995).abs() < 0.005, "{}", s);
+        let s = st.range_selectivity(None, Some((&Value::Int(10), false)));
+        assert!(s > 0.2505 && s < 0.27, "{}", s);
+    }
+
+    #[test]
+    fn sampled_distinct_count_is_scaled_up() {
+        // A sample of 1000 unique values out of 100000 rows: Duj1 extrapolates.
+        let vals = ints(0..1000);
+        let st = column_stats(vals.iter(), 1000, 100_000);
+        assert!(st.n_distinct > 10_000.0, "{}", st.n_distinct);
+        assert!(st.mcv.is_empty());
+        // A sample with few repeated values: estimate stays close to what was seen.
+        let vals = ints((0..1000).map(|i| i % 20));
+        let st = column_stats(vals.iter(), 1000, 100_000);
+        assert_eq!(st.n_distinct, 20.0);
+    }
+
+    #[test]
+    fn predicate_selectivity() {
+        let st = column_stats(ints(0..100).iter(), 100, 100);
+        let column = |c: usize| (c == 0).then(|| st.clone());
+        let lit = |n| Expr::Literal(Value::Int(n));
+        let eq = Expr::binary(BinaryOp::Eq, Expr::Column(0), lit(5));
+        assert!((selectivity(&eq, &column) - 0.01).abs() < 1e-9);
+        let flipped = Expr::binary(BinaryOp::Gt, lit(50), Expr::Column(0));
+        let s = selectivity(&flipped, &column);
+        assert!((s - 0.5).abs() < 0.02, "{}", s);
+        let unknown = Expr::binary(BinaryOp::Eq, Expr::Column(1), lit(5));
+        assert_eq!(selectivity(&unknown, &column), DEFAULT_EQ_SEL);
+        let both = Expr::binary(BinaryOp::And, eq.clone(), unknown);
+        assert!((selectivity(&both, &column) - 0.01 * DEFAULT_EQ_SEL).abs() < 1e-12);
+        let join = Expr::binary(BinaryOp::Eq, Expr::Column(0), Expr::Column(1));
+        assert!((selectivity(&join, &column) - 0.01).abs() < 1e-9);
+        let in_list = Expr::InList {
+            expr: Box::new(Expr::Column(0)),
+            list: vec![lit(1), lit(2), lit(3)],
+            negated: false,
+        };
+        assert!((selectivity(&in_list, &column) - 0.03).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,65 @@
+//! System views: read-only tables of server state, read as `SELECT ... FROM name`. They
+//! are planned as calls of table functions without arguments (`TableFunc::System`) and
+//! computed each time they are scanned.
+
+use anyhow::{bail, Result};
+
+use crate::db::Database;
+use crate::storage::{ColumnType, Value};
+
+/// Names of the system views, which user tables of the same name hide.
+pub const VIEWS: &[&str] = &["rustdb_plan_cache", "rustdb_plan_cache_stats"];
+
+/// Output columns of system view `name`.
+pub fn columns(name: &str) -> Result<Vec<(String, ColumnType)>> {
+    let columns: &[(&str, ColumnType)] = match name {
+        "rustdb_plan_cache" => &[
+            ("fingerprint", ColumnType::Text),
+            ("query", ColumnType::Text),
+            ("param_types", ColumnType::Text),
+            ("hits", ColumnType::Int),
+            ("misses", ColumnType::Int),
+            ("valid", ColumnType::Bool),
+        ],
+        "rustdb_plan_cache_stats" => &[
+            ("entries", ColumnType::Int),
+            ("capacity", ColumnType::Int),
+            ("hits", ColumnType::Int),
+            ("misses", ColumnType::Int),
+            ("invalidations", ColumnType::Int),
+            ("evictions", ColumnType::Int),
+        ],
+        other => bail!("unknown system view: {}", other),
+    };
+    Ok(columns.iter().map(|(n, t)| (n.to_string(), *t)).collect())
+}
+
+/// Current rows of system view `name`.
+pub fn rows(name: &str, db: &Database) -> Result<Vec<Vec<Value>>> {
+    let cache = db.plan_cache();
+    Ok(match name {
+        "rustdb_plan_cache" => cache
+            .entries(db.catalog_version())
+            .into_iter()
+            .map(|e| {
+                let types: Vec<String> = e.param_types.iter().map(|t| t.to_string()).collect();
+                vec![
+                    Value::Text(format!("{:016x}", e.fingerprint)),
+                    Value::Text(e.query),
+                    Value::Text(types.join(", ")),
+                    Value::Int(e.hits as i64),
+                    Value::Int(e.misses as i64),
+                    Value::Bool(e.valid),
+                ]
+            })
+            .collect(),
+        "rustdb_plan_cache_stats" => {
+            let s = cache.stats();
+            vec![[s.entries as u64, s.capacity as u64, s.hits, s.misses, s.invalidations, s.evictions]
+                .into_iter()
+                .map(|n| Value::Int(n as i64))
+                .collect()]
+        }
+        other => bail!("unknown system view: {}", other),
+    })
+}
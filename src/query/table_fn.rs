@@ -0,0 +1,251 @@
+//! Built-in table functions, usable as `SELECT * FROM name(args...)`, and the calls of
+//! those registered from Rust (`udf::TableUdf`) and of system views (`system`).
+
+use anyhow::{bail, Context, Result};
+use std::path::PathBuf;
+
+use crate::db::Database;
+use crate::query::expr::coerce;
+use crate::query::{function, system};
+use crate::query::udf::{TableUdf, Udf};
+use crate::storage::{inspect_file, parse_schema, ColumnType, Table, Value};
+
+/// Result set of a table function: column names/types and rows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableFnOutput {
+    pub columns: Vec<(String, ColumnType)>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+/// The table function a plan calls.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableFunc {
+    /// A built-in, by lower-case name.
+    Builtin(String),
+    User(Udf<dyn TableUdf>),
+    /// A system view, by name; takes no arguments.
+    System(String),
+}
+
+/// The built-in table function called `name` (lower case), if there is one.
+pub fn builtin(name: &str) -> Option<TableFunc> {
+    match name {
+        "pageinspect" => Some(TableFunc::Builtin(name.to_string())),
+        _ => None,
+    }
+}
+
+impl TableFunc {
+    pub fn name(&self) -> &str {
+        match self {
+            TableFunc::Builtin(name) | TableFunc::System(name) => name,
+            TableFunc::User(f) => f.name(),
+        }
+    }
+
+    pub fn columns(&self) -> Result<Vec<(String, ColumnType)>> {
+        match self {
+            TableFunc::Builtin(name) => columns(name),
+            TableFunc::User(f) => Ok(f.func().columns()),
+            TableFunc::System(name) => system::columns(name),
+        }
+    }
+
+    /// Check arguments of types `args` (None where unknown) against the parameters of a
+    /// user-defined function; built-ins check theirs when called.
+    pub fn check_args(&self, args: &[Option<ColumnType>]) -> Result<()> {
+        let TableFunc::User(f) = self else {
+            return Ok(());
+        };
+        let params = f.func().params();
+        let accepted = params.len() == args.len()
+            && params.iter().zip(args).all(|(p, a)| match a {
+                None => true,
+                Some(a) => a == p || (*a, *p) == (ColumnType::Int, ColumnType::Float),
+            });
+        if !accepted {
+            bail!("function {}({}) does not exist", f.name(), function::type_list(args));
+        }
+        Ok(())
+    }
+
+    pub fn call(&self, db: &Database, args: &[Value]) -> Result<TableFnOutput> {
+        let f = match self {
+            TableFunc::Builtin(name) => return call(name, db, args),
+            TableFunc::User(f) => f,
+            TableFunc::System(name) => {
+                return Ok(TableFnOutput {
+                    columns: system::columns(name)?,
+                    rows: system::rows(name, db)?,
+                })
+            }
+        };
+        let args = args
+            .iter()
+            .zip(f.func().params())
+            .map(|(v, ty)| coerce(v.clone(), *ty))
+            .collect::<Result<Vec<_>>>()?;
+        let columns = f.func().columns();
+        let mut rows = f.func().call(&args)?;
+        for row in &mut rows {
+            if row.len() != columns.len() {
+                bail!(
+                    "function {} returned a row of {} values for {} columns",
+                    f.name(),
+                    row.len(),
+                    columns.len()
+                );
+            }
+            for (v, (name, ty)) in row.iter_mut().zip(&columns) {
+                *v = coerce(std::mem::replace(v, Value::Null), *ty).map_err(|e| {
+                    anyhow::anyhow!("function {} returned a wrong value for column {}: {}", f.name(), name, e)
+                })?;
+            }
+        }
+        Ok(TableFnOutput { columns, rows })
+    }
+}
+
+/// Call a built-in table function by (case-insensitive) name.
+pub fn call(name: &str, db: &Database, args: &[Value]) -> Result<TableFnOutput> {
+    match name.to_ascii_lowercase().as_str() {
+        "pageinspect" => pageinspect(db, args),
+        other => bail!("unknown table function: {}", other),
+    }
+}
+
+/// Output columns of a built-in table function, without calling it.
+pub fn columns(name: &str) -> Result<Vec<(String, ColumnType)>> {
+    match name.to_ascii_lowercase().as_str() {
+        "pageinspect" => Ok(vec![
+            ("section".to_string(), ColumnType::Text),
+            ("item".to_string(), ColumnType::Int),
+            ("offset".to_string(), ColumnType::Int),
+            ("length".to_string(), ColumnType::Int),
+            ("detail".to_string(), ColumnType::Text),
+        ]),
+        other => bail!("unknown table function: {}", other),
+    }
+}
+
+/// The file `relation` names for `pageinspect`, with the schema of its rows if known: the
+/// heap of a table (or unique index) of that name, else a file of the data directory, such
+/// as `t.idx`. Files outside the data directory cannot be read from SQL; the
+/// `rustdb-pageinspect` CLI reads any file.
+fn relation_file(db: &Database, relation: &str) -> Result<(PathBuf, Option<Vec<ColumnType>>)> {
+    if let Ok(table) = db.table(relation) {
+        return Ok((Table::heap_path(db.data_dir(), relation), Some(table.schema().to_vec())));
+    }
+    let dir = db.data_dir().canonicalize()?;
+    let path = dir
+        .join(relation)
+        .canonicalize()
+        .with_context(|| format!("pageinspect: relation or file {} does not exist", relation))?;
+    if !path.starts_with(&dir) || path == dir {
+        bail!("pageinspect: {} is outside the data directory", relation);
+    }
+    Ok((path, None))
+}
+
+/// `pageinspect(relation TEXT, page_id INT [, schema TEXT])`, for a table or a file of the
+/// data directory (see `relation_file`). Schema is a comma-separated type list (e.g.
+/// `'int,text'`) used to decode heap rows; a table's own schema is the default.
+/// Returns one row per header, slot and B-tree entry: (section, item, offset, length, detail).
+fn pageinspect(db: &Database, args: &[Value]) -> Result<TableFnOutput> {
+    let (relation, page_id, schema) = match args {
+        [Value::Text(relation), Value::Int(page_id)] => (relation, *page_id, None),
+        [Value::Text(relation), Value::Int(page_id), Value::Text(schema)] => {
+            (relation, *page_id, Some(parse_schema(schema)?))
+        }
+        _ => bail!("usage: pageinspect(relation TEXT, page_id INT [, schema TEXT])"),
+    };
+    let page_id = u32::try_from(page_id).map_err(|_| anyhow::anyhow!("invalid page id {}", page_id))?;
+    let (path, own_schema) = relation_file(db, relation)?;
+    let report = inspect_file(path, page_id, schema.or(own_schema).as_deref())?;
+    let rows = report
+        .to_rows()
+        .into_iter()
+        .map(|(section, item, offset, length, detail)| {
+            vec![
+                Value::Text(section),
+                Value::Int(item as i64),
+                Value::Int(offset as i64),
+                Value::Int(length as i64),
+                Value::Text(detail),
+            ]
+        })
+        .collect();
+    Ok(TableFnOutput {
+        columns: columns("pageinspect")?,
+        rows,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::storage::{row_encode, HeapFile, Page, PageFlags};
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn pageinspect_heap_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::open(Config {
+            data_dir: dir.path().to_string_lossy().into_owned(),
+            wal_sync: false,
+            ..Config::default()
+        })
+        .unwrap();
+        let schema = vec![ColumnType::Int, ColumnType::Bool];
+        let mut page = Page::new(0, PageFlags::Heap);
+        page.insert(&row_encode(&schema, &[Value::Int(1), Value::Bool(true)], 9, 0).unwrap())
+            .unwrap();
+        HeapFile::create(dir.path().join("dump.heap")).unwrap().append_page(&page).unwrap();
+
+        let file = Value::Text("dump.heap".into());
+        let out = call(
+            "PAGEINSPECT",
+            &db,
+            &[file.clone(), Value::Int(0), Value::Text("int,bool".into())],
+        )
+        .unwrap();
+        assert_eq!(out.columns.len(), 5);
+        assert_eq!(out.rows.len(), 2);
+        assert_eq!(out.rows[1][0], Value::Text("slot".into()));
+        match &out.rows[1][4] {
+            Value::Text(d) => assert!(d.contains("txn_id=9") && d.contains("Int(1)"), "{}", d),
+            v => panic!("unexpected detail {:?}", v),
+        }
+        assert!(call("pageinspect", &db, &[file]).is_err());
+
+        // Tables are found by name and decoded with their own schema.
+        let mut s = db.session();
+        s.execute("CREATE TABLE t (id INT PRIMARY KEY, name TEXT)").unwrap();
+        s.execute("INSERT INTO t VALUES (7, 'x')").unwrap();
+        let out = call("pageinspect", &db, &[Value::Text("t".into()), Value::Int(0)]).unwrap();
+        assert!(out.rows.iter().any(|r| matches!(&r[4], Value::Text(d) if d.contains("Text(\"x\")"))));
+    }
+
+    #[test]
+    fn pageinspect_stays_inside_the_data_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::open(Config {
+            data_dir: dir.path().join("data").to_string_lossy().into_owned(),
+            wal_sync: false,
+            ..Config::default()
+        })
+        .unwrap();
+        let outside = NamedTempFile::new_in(dir.path()).unwrap();
+        let mut page = Page::new(0, PageFlags::Heap);
+        page.insert(b"secret").unwrap();
+        HeapFile::create(outside.path()).unwrap().append_page(&page).unwrap();
+        let name = outside.path().file_name().unwrap().to_string_lossy().into_owned();
+        for relation in [outside.path().to_string_lossy().into_owned(), format!("../{}", name)] {
+            let err = call("pageinspect", &db, &[Value::Text(relation.clone()), Value::Int(0)]).unwrap_err();
+            assert!(err.to_string().contains("outside the data directory"), "{}: {}", relation, err);
+        }
+        let err = call("pageinspect", &db, &[Value::Text("nope.heap".into()), Value::Int(0)]).unwrap_err();
+        assert!(err.to_string().contains("does not exist"), "{}", err);
+    }
+}
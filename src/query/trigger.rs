@@ -0,0 +1,405 @@
+//! Triggers: statements or Rust functions run as rows of a table are inserted, updated or
+//! deleted, inside the transaction (and under the limits) of the statement changing them.
+//!
+//! Row-level triggers fire for each row the statement writes: BEFORE ones just before it
+//! is written, AFTER ones right after. Statement-level triggers fire once per statement,
+//! even one that changes no row: BEFORE ones before it reads its input, AFTER ones once
+//! its rows are written and its constraints checked. Triggers of the same timing and level
+//! fire in name order. An upsert (`INSERT ... ON CONFLICT DO UPDATE`) fires the UPDATE
+//! triggers for the rows it updates. Rows a foreign key's ON DELETE CASCADE or SET NULL
+//! deletes or updates fire the row-level DELETE or UPDATE triggers of their table, but not
+//! its statement-level ones.
+//!
+//! A SQL body reads the row through `OLD.column` and `NEW.column`, which become parameters
+//! of its statements: `$1..` the columns of OLD, then those of NEW (NULL where there is no
+//! such row). Only a trigger function can change the row a BEFORE ROW trigger is about to
+//! write, or skip it (see `TriggerCall`).
+
+use anyhow::{anyhow, bail, Result};
+use sqlparser::ast::{self, Statement};
+use sqlparser::dialect::GenericDialect;
+use sqlparser::parser::Parser;
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+use std::sync::{Arc, Mutex};
+
+use crate::catalog::{TableDef, TriggerBody, TriggerDef, TriggerEvent, TriggerTiming};
+use crate::db::Database;
+use crate::query::exec::{self, ExecContext, Row};
+use crate::query::expr::coerce;
+use crate::query::plan::Plan;
+use crate::query::planner::Planner;
+use crate::query::udf::{TriggerUdf, Udf};
+use crate::storage::{ColumnType, Value};
+
+/// Most triggers a statement may be running inside of, counting those fired by the
+/// statements of other triggers.
+pub const MAX_DEPTH: usize = 16;
+
+/// What a trigger function is called with.
+pub struct TriggerCall<'c> {
+    pub trigger: &'c TriggerDef,
+    pub table: &'c TableDef,
+    pub event: TriggerEvent,
+    /// The row before the change, for row-level UPDATE and DELETE triggers.
+    pub old: Option<&'c [Value]>,
+    /// The row after the change, for row-level INSERT and UPDATE triggers. What a BEFORE
+    /// trigger leaves here is what gets written.
+    pub new: Option<Row>,
+    skipped: bool,
+    ctx: ExecContext<'c>,
+}
+
+impl TriggerCall<'_> {
+    /// Leave the row alone: from a BEFORE ROW trigger, the statement goes on without
+    /// writing it and the remaining triggers do not fire for it.
+    pub fn skip(&mut self) {
+        self.skipped = true;
+    }
+
+    /// Run one query or data-changing statement inside the changing statement's
+    /// transaction, with `params` bound to `$1..`, and return its rows.
+    pub fn execute(&mut self, sql: &str, params: &[Value]) -> Result<Vec<Row>> {
+        let mut statements = Parser::parse_sql(&GenericDialect {}, sql)?;
+        let (Some(statement), true) = (statements.pop(), statements.is_empty()) else {
+            bail!("trigger {} can only execute one statement at a time: {}", self.trigger.name, sql);
+        };
+        let types: Vec<ColumnType> = params.iter().map(|v| v.column_type().unwrap_or(ColumnType::Text)).collect();
+        let planner = Planner::new(self.ctx.db).with_param_types(&types);
+        let plan = planner.plan(&statement)?;
+        if planner.param_types().len() > params.len() {
+            bail!("statement expects {} parameters but got {}: {}", planner.param_types().len(), params.len(), sql);
+        }
+        exec::execute(&plan.bind_params(params), &mut self.ctx)
+    }
+}
+
+/// The statements of SQL body `sql` of `trigger`, on table `def`, planned with `OLD` and
+/// `NEW` columns as parameters.
+fn plan_body(db: &Database, def: &TableDef, trigger: &TriggerDef, sql: &str) -> Result<Vec<Plan>> {
+    let statements = Parser::parse_sql(&GenericDialect {}, sql)?;
+    if statements.is_empty() {
+        bail!("trigger {} has an empty body", trigger.name);
+    }
+    let types: Vec<ColumnType> = def.schema().into_iter().chain(def.schema()).collect();
+    statements
+        .into_iter()
+        .map(|mut statement| {
+            if !matches!(
+                statement,
+                Statement::Query(_) | Statement::Insert { .. } | Statement::Update { .. } | Statement::Delete { .. }
+            ) {
+                bail!("trigger {} can only run queries, INSERT, UPDATE and DELETE: {}", trigger.name, statement);
+            }
+            bind_row(&mut statement, def, trigger)?;
+            Planner::new(db).with_param_types(&types).plan(&statement)
+        })
+        .collect()
+}
+
+/// Replace `OLD.column` and `NEW.column` in `statement` with the parameters standing for
+/// them.
+fn bind_row(statement: &mut Statement, def: &TableDef, trigger: &TriggerDef) -> Result<()> {
+    let found = ast::visit_expressions_mut(statement, |e| {
+        let ast::Expr::CompoundIdentifier(parts) = e else {
+            return ControlFlow::Continue(());
+        };
+        let [record, column] = parts.as_slice() else {
+            return ControlFlow::Continue(());
+        };
+        let record = record.value.to_ascii_lowercase();
+        let base = match record.as_str() {
+            "old" => 0,
+            "new" => def.columns.len(),
+            _ => return ControlFlow::Continue(()),
+        };
+        if !trigger.for_each_row {
+            return ControlFlow::Break(anyhow!(
+                "{} is not available in statement-level trigger {}",
+                record.to_ascii_uppercase(),
+                trigger.name
+            ));
+        }
+        let name = match column.quote_style {
+            Some(_) => column.value.clone(),
+            None => column.value.to_ascii_lowercase(),
+        };
+        let Some(i) = def.column_index(&name).filter(|i| !def.columns[*i].hidden) else {
+            return ControlFlow::Break(anyhow!("record {} has no field {}", record, name));
+        };
+        *e = ast::Expr::Value(ast::Value::Placeholder(format!("${}", base + i + 1)));
+        ControlFlow::Continue(())
+    });
+    match found {
+        ControlFlow::Break(e) => Err(e),
+        ControlFlow::Continue(()) => Ok(()),
+    }
+}
+
+/// Check that `trigger` can run on table `def`: that its function is registered, or that
+/// its statements can be planned.
+pub fn validate(db: &Database, def: &TableDef, trigger: &TriggerDef) -> Result<()> {
+    action(db, def, trigger).map(|_| ())
+}
+
+/// What firing a trigger runs.
+enum Action {
+    Sql(Vec<Plan>),
+    Function(Udf<dyn TriggerUdf>),
+}
+
+fn action(db: &Database, def: &TableDef, trigger: &TriggerDef) -> Result<Action> {
+    match &trigger.body {
+        TriggerBody::Sql(sql) => Ok(Action::Sql(plan_body(db, def, trigger, sql)?)),
+        TriggerBody::Function(name) => match db.functions().trigger(name) {
+            Some(f) => Ok(Action::Function(f)),
+            None => bail!("function {} does not exist", name),
+        },
+    }
+}
+
+/// What the triggers of every table run, by table and trigger name, planned once for each
+/// `Database::catalog_version` rather than by every statement firing them.
+#[derive(Default)]
+pub struct TriggerCache(Mutex<Planned>);
+
+#[derive(Default)]
+struct Planned {
+    version: u64,
+    actions: HashMap<(String, String), Arc<Action>>,
+}
+
+impl TriggerCache {
+    fn action(&self, db: &Database, def: &TableDef, trigger: &TriggerDef) -> Result<Arc<Action>> {
+        let version = db.catalog_version();
+        let key = (def.name.clone(), trigger.name.clone());
+        {
+            let mut cache = self.0.lock().unwrap_or_else(|e| e.into_inner());
+            if cache.version != version {
+                *cache = Planned {
+                    version,
+                    actions: HashMap::new(),
+                };
+            }
+            if let Some(action) = cache.actions.get(&key) {
+                return Ok(Arc::clone(action));
+            }
+        }
+        let planned = Arc::new(action(db, def, trigger)?);
+        let mut cache = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if cache.version == version {
+            cache.actions.insert(key, Arc::clone(&planned));
+        }
+        Ok(planned)
+    }
+}
+
+/// The triggers of a table that a data-changing operator fires, for the events its
+/// statement may cause. The operator calls `statement` with BEFORE before reading its
+/// input, `before_row` (or `before_delete`) and `after_row` around each row it writes,
+/// and `statement` with AFTER once it is done.
+pub struct Triggers {
+    def: TableDef,
+    events: Vec<TriggerEvent>,
+    triggers: Vec<(TriggerDef, Arc<Action>)>,
+    finished: bool,
+}
+
+impl Triggers {
+    pub fn new(db: &Database, def: &TableDef, events: &[TriggerEvent]) -> Result<Self> {
+        let triggers = def
+            .triggers
+            .iter()
+            .filter(|t| events.iter().any(|e| t.fires_on(*e)))
+            .map(|t| Ok((t.clone(), db.trigger_cache().action(db, def, t)?)))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            def: def.clone(),
+            events: events.to_vec(),
+            triggers,
+            finished: false,
+        })
+    }
+
+    fn any(&self, timing: TriggerTiming, row: bool, event: TriggerEvent) -> bool {
+        self.triggers
+            .iter()
+            .any(|(t, _)| t.timing == timing && t.for_each_row == row && t.fires_on(event))
+    }
+
+    /// Fire the triggers of `timing` and level `row` on `event` in turn. Returns the row
+    /// a BEFORE ROW INSERT or UPDATE is to write, and false if one of them skipped it.
+    fn fire(
+        &self,
+        ctx: &mut ExecContext<'_>,
+        timing: TriggerTiming,
+        row: bool,
+        event: TriggerEvent,
+        old: Option<&[Value]>,
+        mut new: Option<Row>,
+    ) -> Result<(bool, Option<Row>)> {
+        let width = self.def.columns.len();
+        for (trigger, action) in &self.triggers {
+            if trigger.timing != timing || trigger.for_each_row != row || !trigger.fires_on(event) {
+                continue;
+            }
+            if ctx.trigger_depth >= MAX_DEPTH {
+                bail!("trigger {} nested too deeply: more than {} levels", trigger.name, MAX_DEPTH);
+            }
+            ctx.limits.check()?;
+            let mut inner = ExecContext {
+                db: ctx.db,
+                txn: &mut *ctx.txn,
+                limits: Arc::clone(&ctx.limits),
+                trigger_depth: ctx.trigger_depth + 1,
+            };
+            match &**action {
+                Action::Sql(plans) => {
+                    let nulls = vec![Value::Null; width];
+                    let values: Vec<Value> =
+                        old.unwrap_or(&nulls).iter().chain(new.as_deref().unwrap_or(&nulls)).cloned().collect();
+                    for plan in plans {
+                        exec::execute(&plan.bind_params(&values), &mut inner)?;
+                    }
+                }
+                Action::Function(f) => {
+                    let mut call = TriggerCall {
+                        trigger,
+                        table: &self.def,
+                        event,
+                        old,
+                        new: new.take(),
+                        skipped: false,
+                        ctx: inner,
+                    };
+                    f.func().call(&mut call)?;
+                    let (skipped, changed) = (call.skipped, call.new);
+                    if timing == TriggerTiming::Before && row {
+                        if skipped {
+                            return Ok((false, None));
+                        }
+                        new = changed.map(|r| self.conform(trigger, r)).transpose()?;
+                    } else {
+                        new = changed;
+                    }
+                }
+            }
+        }
+        Ok((true, new))
+    }
+
+    /// `row`, as trigger function `trigger` left it, with values of the table's types.
+    fn conform(&self, trigger: &TriggerDef, row: Row) -> Result<Row> {
+        if row.len() != self.def.columns.len() {
+            bail!(
+                "trigger {} returned a row of {} values for table {} of {} columns",
+                trigger.name,
+                row.len(),
+                self.def.name,
+                self.def.columns.len()
+            );
+        }
+        row.into_iter()
+            .zip(&self.def.columns)
+            .map(|(v, c)| coerce(v, c.ty).map_err(|e| anyhow!("trigger {}: column {}: {}", trigger.name, c.name, e)))
+            .collect()
+    }
+
+    /// Fire the statement-level triggers of `timing` for each event of the statement; the
+    /// AFTER ones only once, however often the operator is asked for more rows.
+    pub fn statement(&mut self, ctx: &mut ExecContext<'_>, timing: TriggerTiming) -> Result<()> {
+        if timing == TriggerTiming::After {
+            if self.finished {
+                return Ok(());
+            }
+            self.finished = true;
+        }
+        for &event in &self.events {
+            if self.any(timing, false, event) {
+                self.fire(ctx, timing, false, event, None, None)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fire the BEFORE ROW triggers of an INSERT or UPDATE (of `old`) writing `new`: the
+    /// row to write as they left it, or None if one of them skipped it.
+    pub fn before_row(
+        &self,
+        ctx: &mut ExecContext<'_>,
+        event: TriggerEvent,
+        old: Option<&[Value]>,
+        new: Row,
+    ) -> Result<Option<Row>> {
+        if !self.any(TriggerTiming::Before, true, event) {
+            return Ok(Some(new));
+        }
+        let (go_ahead, new) = self.fire(ctx, TriggerTiming::Before, true, event, old, Some(new))?;
+        match (go_ahead, new) {
+            (true, Some(new)) => Ok(Some(new)),
+            (true, None) => bail!("BEFORE ROW trigger on {} left no row to write", self.def.name),
+            (false, _) => Ok(None),
+        }
+    }
+
+    /// Fire the BEFORE ROW triggers of a DELETE of `old`: false if one of them skipped it.
+    pub fn before_delete(&self, ctx: &mut ExecContext<'_>, old: &[Value]) -> Result<bool> {
+        if !self.any(TriggerTiming::Before, true, TriggerEvent::Delete) {
+            return Ok(true);
+        }
+        Ok(self.fire(ctx, TriggerTiming::Before, true, TriggerEvent::Delete, Some(old), None)?.0)
+    }
+
+    /// Fire the AFTER ROW triggers of `event`, which changed `old` into `new`.
+    pub fn after_row(
+        &self,
+        ctx: &mut ExecContext<'_>,
+        event: TriggerEvent,
+        old: Option<&[Value]>,
+        new: Option<&[Value]>,
+    ) -> Result<()> {
+        if self.any(TriggerTiming::After, true, event) {
+            self.fire(ctx, TriggerTiming::After, true, event, old, new.map(<[Value]>::to_vec))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::ColumnDef;
+
+    #[test]
+    fn old_and_new_become_parameters() {
+        let def = TableDef {
+            name: "t".into(),
+            columns: vec![ColumnDef::new("id", ColumnType::Int), ColumnDef::new("Name", ColumnType::Text)],
+            key_col: 0,
+            constraints: Vec::new(),
+            triggers: Vec::new(),
+        };
+        let mut trigger = TriggerDef {
+            name: "audit".into(),
+            timing: TriggerTiming::After,
+            events: vec![TriggerEvent::Update],
+            for_each_row: true,
+            body: TriggerBody::Sql(String::new()),
+        };
+        let bound = |sql: &str, trigger: &TriggerDef| -> Result<String> {
+            let mut statement = Parser::parse_sql(&GenericDialect {}, sql)?.remove(0);
+            bind_row(&mut statement, &def, trigger)?;
+            Ok(statement.to_string())
+        };
+        assert_eq!(
+            bound("INSERT INTO log VALUES (OLD.id, new.\"Name\", x.id)", &trigger).unwrap(),
+            "INSERT INTO log VALUES ($1, $4, x.id)"
+        );
+        let err = bound("SELECT new.name", &trigger).unwrap_err();
+        assert_eq!(err.to_string(), "record new has no field name");
+        trigger.for_each_row = false;
+        let err = bound("SELECT old.id", &trigger).unwrap_err();
+        assert_eq!(err.to_string(), "OLD is not available in statement-level trigger audit");
+        assert_eq!(bound("SELECT t.id FROM t", &trigger).unwrap(), "SELECT t.id FROM t");
+    }
+}
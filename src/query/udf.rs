@@ -0,0 +1,299 @@
+//! User-defined functions registered from Rust: scalar functions, aggregates and table
+//! functions, callable from SQL like the built-ins once added to a `Database` (see
+//! `Database::register_scalar_function` and friends), and trigger functions, which
+//! triggers run.
+//!
+//! Calls are type-checked when planned, against the same kind of `Signature` the
+//! built-ins declare; values a function returns are checked against its declared types
+//! when it runs.
+
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+use crate::query::aggregate::AggFunc;
+use crate::query::expr::coerce;
+use crate::query::function::{ParamType, ScalarFunc, Signature};
+use crate::query::table_fn::{self, TableFunc};
+use crate::query::trigger::TriggerCall;
+use crate::query::window::WindowFunc;
+use crate::sequence::SequenceOp;
+use crate::storage::{ColumnType, Value};
+
+/// A scalar function: one value per call.
+pub trait ScalarUdf: Send + Sync {
+    /// The forms the function can be called in, tried in order.
+    fn signatures(&self) -> &[Signature];
+
+    /// The result for `args`, which match one of the signatures (INT arguments may have
+    /// been passed for FLOAT parameters). It must be NULL or of the result type of the
+    /// signature the call was resolved to; an `Any` result is TEXT.
+    fn call(&self, args: &[Value]) -> Result<Value>;
+
+    /// Whether a NULL argument makes the result NULL without calling the function.
+    fn strict(&self) -> bool {
+        true
+    }
+}
+
+/// An aggregate, computed from a state kept per group. Rows whose first argument is NULL
+/// are skipped, as they are by the built-in aggregates.
+pub trait AggregateUdf: Send + Sync {
+    fn signatures(&self) -> &[Signature];
+
+    /// The state of a group no row has been added to.
+    fn init(&self) -> Vec<Value>;
+
+    /// Add one row's argument values to `state`.
+    fn accumulate(&self, state: &mut Vec<Value>, args: &[Value]) -> Result<()>;
+
+    /// Add `other`, the state of another part of the same group, to `state`.
+    fn merge(&self, state: &mut Vec<Value>, other: Vec<Value>) -> Result<()>;
+
+    /// The aggregate's value for a group with state `state`.
+    fn finalize(&self, state: Vec<Value>) -> Result<Value>;
+}
+
+/// A table function, usable as `SELECT * FROM name(args...)`. Its arguments must be
+/// constant.
+pub trait TableUdf: Send + Sync {
+    /// Parameter types; an INT argument may be passed for a FLOAT parameter.
+    fn params(&self) -> &[ColumnType];
+
+    /// Names and types of the columns of the rows `call` returns.
+    fn columns(&self) -> Vec<(String, ColumnType)>;
+
+    fn call(&self, args: &[Value]) -> Result<Vec<Vec<Value>>>;
+}
+
+/// A trigger function, run by the triggers created with `EXECUTE FUNCTION name()`. It
+/// sees the rows of `call` and may run statements in the changing statement's
+/// transaction; a BEFORE ROW one may also change or skip the row.
+pub trait TriggerUdf: Send + Sync {
+    fn call(&self, call: &mut TriggerCall<'_>) -> Result<()>;
+}
+
+/// A registered function, shared by the plans calling it. Handles are equal when they
+/// refer to the same registration.
+pub struct Udf<T: ?Sized> {
+    name: String,
+    func: Arc<T>,
+}
+
+impl<T: ?Sized> Udf<T> {
+    pub fn new(name: &str, func: Arc<T>) -> Self {
+        Self {
+            name: name.to_ascii_lowercase(),
+            func,
+        }
+    }
+
+    /// The name SQL calls it by (lower case).
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn func(&self) -> &T {
+        &self.func
+    }
+}
+
+impl<T: ?Sized> Clone for Udf<T> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            func: Arc::clone(&self.func),
+        }
+    }
+}
+
+impl<T: ?Sized> PartialEq for Udf<T> {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::addr_eq(Arc::as_ptr(&self.func), Arc::as_ptr(&other.func))
+    }
+}
+
+impl<T: ?Sized> Eq for Udf<T> {}
+
+impl<T: ?Sized> Hash for Udf<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (Arc::as_ptr(&self.func) as *const ()).hash(state)
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for Udf<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Udf({})", self.name)
+    }
+}
+
+/// Check a value returned by function `name` against its result type `ty` (None if the
+/// signature leaves it open); INT widens to FLOAT.
+pub fn check_result(name: &str, v: Value, ty: Option<ColumnType>) -> Result<Value> {
+    match ty {
+        Some(ty) => coerce(v, ty).map_err(|e| anyhow::anyhow!("function {} returned a wrong value: {}", name, e)),
+        None => Ok(v),
+    }
+}
+
+/// The result type every form of `signatures` shares, if they share one.
+pub fn fixed_result(signatures: &[Signature]) -> Option<ColumnType> {
+    let mut out = None;
+    for s in signatures {
+        match (s.result, out) {
+            (ParamType::Is(t), None) => out = Some(t),
+            (ParamType::Is(t), Some(o)) if t == o => {}
+            _ => return None,
+        }
+    }
+    out
+}
+
+/// The user-defined functions of a database, by lower-case name.
+#[derive(Debug, Clone, Default)]
+pub struct FunctionRegistry {
+    scalars: HashMap<String, Udf<dyn ScalarUdf>>,
+    aggregates: HashMap<String, Udf<dyn AggregateUdf>>,
+    tables: HashMap<String, Udf<dyn TableUdf>>,
+    triggers: HashMap<String, Udf<dyn TriggerUdf>>,
+}
+
+impl FunctionRegistry {
+    /// Whether a function (built-in or user-defined) of any kind is called `name`.
+    pub fn exists(&self, name: &str) -> bool {
+        self.scalar(name).is_some()
+            || self.window(name).is_some()
+            || self.table(name).is_some()
+            || self.trigger(name).is_some()
+            || SequenceOp::from_name(name).is_some()
+    }
+
+    fn check_new(&self, name: &str) -> Result<()> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            bail!("invalid function name \"{}\"", name);
+        }
+        if self.exists(name) {
+            bail!("function {} already exists", name);
+        }
+        Ok(())
+    }
+
+    pub fn add_scalar(&mut self, name: &str, func: Arc<dyn ScalarUdf>) -> Result<()> {
+        let udf = Udf::new(name, func);
+        self.check_new(udf.name())?;
+        if udf.func().signatures().is_empty() {
+            bail!("function {} has no signatures", udf.name());
+        }
+        self.scalars.insert(udf.name().to_string(), udf);
+        Ok(())
+    }
+
+    pub fn add_aggregate(&mut self, name: &str, func: Arc<dyn AggregateUdf>) -> Result<()> {
+        let udf = Udf::new(name, func);
+        self.check_new(udf.name())?;
+        if udf.func().signatures().is_empty() {
+            bail!("function {} has no signatures", udf.name());
+        }
+        self.aggregates.insert(udf.name().to_string(), udf);
+        Ok(())
+    }
+
+    pub fn add_table(&mut self, name: &str, func: Arc<dyn TableUdf>) -> Result<()> {
+        let udf = Udf::new(name, func);
+        self.check_new(udf.name())?;
+        self.tables.insert(udf.name().to_string(), udf);
+        Ok(())
+    }
+
+    pub fn add_trigger(&mut self, name: &str, func: Arc<dyn TriggerUdf>) -> Result<()> {
+        let udf = Udf::new(name, func);
+        self.check_new(udf.name())?;
+        self.triggers.insert(udf.name().to_string(), udf);
+        Ok(())
+    }
+
+    /// The scalar function called `name` (lower case): a built-in, else a user-defined one.
+    pub fn scalar(&self, name: &str) -> Option<ScalarFunc> {
+        ScalarFunc::from_name(name).or_else(|| self.scalars.get(name).cloned().map(ScalarFunc::User))
+    }
+
+    pub fn aggregate(&self, name: &str) -> Option<AggFunc> {
+        AggFunc::from_name(name).or_else(|| self.aggregates.get(name).cloned().map(AggFunc::User))
+    }
+
+    /// The window function called `name`; every aggregate is one.
+    pub fn window(&self, name: &str) -> Option<WindowFunc> {
+        WindowFunc::from_name(name).or_else(|| self.aggregate(name).map(WindowFunc::Aggregate))
+    }
+
+    /// The table function called `name` (any case).
+    pub fn table(&self, name: &str) -> Option<TableFunc> {
+        let name = name.to_ascii_lowercase();
+        table_fn::builtin(&name).or_else(|| self.tables.get(&name).cloned().map(TableFunc::User))
+    }
+
+    /// The trigger function called `name` (lower case).
+    pub fn trigger(&self, name: &str) -> Option<Udf<dyn TriggerUdf>> {
+        self.triggers.get(name).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Double;
+
+    impl ScalarUdf for Double {
+        fn signatures(&self) -> &[Signature] {
+            &[Signature {
+                params: &[ParamType::Is(ColumnType::Float)],
+                variadic: false,
+                result: ParamType::Is(ColumnType::Float),
+            }]
+        }
+
+        fn call(&self, args: &[Value]) -> Result<Value> {
+            Ok(Value::Float(args[0].as_f64().unwrap_or(0.0) * 2.0))
+        }
+    }
+
+    struct Noop;
+
+    impl TriggerUdf for Noop {
+        fn call(&self, _: &mut TriggerCall<'_>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn registry_resolves_builtins_first_and_rejects_duplicates() {
+        let mut reg = FunctionRegistry::default();
+        reg.add_scalar("Twice", Arc::new(Double)).unwrap();
+        let Some(ScalarFunc::User(f)) = reg.scalar("twice") else {
+            panic!("twice not registered");
+        };
+        assert_eq!(f.name(), "twice");
+        assert_eq!(reg.scalar("twice"), Some(ScalarFunc::User(f.clone())));
+        assert_ne!(Udf::new("twice", Arc::new(Double) as Arc<dyn ScalarUdf>), f);
+        assert_eq!(reg.scalar("lower"), Some(ScalarFunc::Lower));
+
+        let err = reg.add_scalar("twice", Arc::new(Double)).unwrap_err();
+        assert_eq!(err.to_string(), "function twice already exists");
+        assert!(reg.add_scalar("upper", Arc::new(Double)).is_err());
+        assert!(reg.add_scalar("sum", Arc::new(Double)).is_err());
+        assert!(reg.add_scalar("pageinspect", Arc::new(Double)).is_err());
+        assert!(reg.add_scalar("nextval", Arc::new(Double)).is_err());
+        assert!(reg.add_scalar("a b", Arc::new(Double)).is_err());
+        reg.add_trigger("Audit", Arc::new(Noop)).unwrap();
+        assert_eq!(reg.trigger("audit").map(|f| f.name().to_string()), Some("audit".to_string()));
+        assert!(reg.add_scalar("audit", Arc::new(Double)).is_err());
+        assert!(reg.add_trigger("twice", Arc::new(Noop)).is_err());
+
+        assert_eq!(check_result("f", Value::Int(1), Some(ColumnType::Float)).unwrap(), Value::Float(1.0));
+        let err = check_result("f", Value::Text("x".into()), Some(ColumnType::Int)).unwrap_err();
+        assert_eq!(err.to_string(), "function f returned a wrong value: expected INT value, got TEXT");
+    }
+}
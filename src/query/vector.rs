@@ -0,0 +1,1291 @@
+//! Vectorized execution: operators that pass batches of up to `BATCH_SIZE` rows stored
+//! column by column, with a selection vector marking the rows still in the batch, and
+//! evaluate expressions a column at a time.
+//!
+//! Used when `vectorized_execution` is on. `build` makes batch operators for the scans,
+//! filters, projections, hash joins and hash aggregates of a plan and `exec` row operators
+//! for everything else, converting between the two where they meet. Unlike their row
+//! counterparts, batch hash joins and aggregates do not spill, so they are only used when
+//! the planner expects their hash table to fit in `work_mem`.
+
+use anyhow::{anyhow, bail, Result};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::db::Database;
+use crate::query::aggregate::{Accumulator, AggCall, ACCUMULATOR_SIZE};
+use crate::query::exec::{self, ExecContext, Operator, Row};
+use crate::query::expr::{self, BinaryOp, Expr, UnaryOp};
+use crate::query::limits::Reservation;
+use crate::query::optimizer::row_width;
+use crate::query::plan::{AggStrategy, JoinType, Node, Plan};
+use crate::storage::{cmp_floats, row_decode_each, ColumnType, Table, Value, ValueRef};
+use crate::txn::ReadView;
+
+/// Rows a batch operator puts in a batch (scans may exceed it by up to a page's rows).
+pub const BATCH_SIZE: usize = 1024;
+
+/// The values of one column of a batch.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Data {
+    Int(Vec<i64>),
+    Float(Vec<f64>),
+    Bool(Vec<bool>),
+    Text(Vec<String>),
+    /// Values of more than one type (say INT results of an expression typed FLOAT).
+    Any(Vec<Value>),
+}
+
+/// One column of a batch: its values, and which of them are NULL (a NULL's slot in
+/// `data` holds a placeholder).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vector {
+    pub data: Data,
+    pub nulls: Vec<bool>,
+}
+
+impl Vector {
+    pub fn with_capacity(ty: ColumnType, n: usize) -> Self {
+        let data = match ty {
+            ColumnType::Int => Data::Int(Vec::with_capacity(n)),
+            ColumnType::Float => Data::Float(Vec::with_capacity(n)),
+            ColumnType::Bool => Data::Bool(Vec::with_capacity(n)),
+            ColumnType::Text => Data::Text(Vec::with_capacity(n)),
+        };
+        Self {
+            data,
+            nulls: Vec::with_capacity(n),
+        }
+    }
+
+    /// A column of `n` NULLs.
+    pub fn nulls(ty: ColumnType, n: usize) -> Self {
+        let mut v = Self::with_capacity(ty, n);
+        for _ in 0..n {
+            v.push_null();
+        }
+        v
+    }
+
+    /// A column of `values`, typed after the first non-NULL one.
+    pub fn from_values(values: Vec<Value>) -> Self {
+        let ty = values.iter().find_map(Value::column_type).unwrap_or(ColumnType::Int);
+        let mut v = Self::with_capacity(ty, values.len());
+        for value in values {
+            v.push(value);
+        }
+        v
+    }
+
+    pub fn len(&self) -> usize {
+        self.nulls.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nulls.is_empty()
+    }
+
+    pub fn get(&self, i: usize) -> Value {
+        if self.nulls[i] {
+            return Value::Null;
+        }
+        match &self.data {
+            Data::Int(d) => Value::Int(d[i]),
+            Data::Float(d) => Value::Float(d[i]),
+            Data::Bool(d) => Value::Bool(d[i]),
+            Data::Text(d) => Value::Text(d[i].clone()),
+            Data::Any(d) => d[i].clone(),
+        }
+    }
+
+    pub fn push_null(&mut self) {
+        match &mut self.data {
+            Data::Int(d) => d.push(0),
+            Data::Float(d) => d.push(0.0),
+            Data::Bool(d) => d.push(false),
+            Data::Text(d) => d.push(String::new()),
+            Data::Any(d) => d.push(Value::Null),
+        }
+        self.nulls.push(true);
+    }
+
+    /// Append `v`; a value of another type than the column's turns it into `Data::Any`.
+    pub fn push(&mut self, v: Value) {
+        match (&mut self.data, v) {
+            (_, Value::Null) => return self.push_null(),
+            (Data::Int(d), Value::Int(n)) => d.push(n),
+            (Data::Float(d), Value::Float(x)) => d.push(x),
+            (Data::Bool(d), Value::Bool(b)) => d.push(b),
+            (Data::Text(d), Value::Text(s)) => d.push(s),
+            (Data::Any(d), v) => d.push(v),
+            (_, v) => {
+                let mut values: Vec<Value> = (0..self.len()).map(|i| self.get(i)).collect();
+                values.push(v);
+                self.data = Data::Any(values);
+            }
+        }
+        self.nulls.push(false);
+    }
+
+    fn push_ref(&mut self, v: ValueRef<'_>) {
+        match (&mut self.data, v) {
+            (_, ValueRef::Null) => return self.push_null(),
+            (Data::Int(d), ValueRef::Int(n)) => d.push(n),
+            (Data::Float(d), ValueRef::Float(x)) => d.push(x),
+            (Data::Bool(d), ValueRef::Bool(b)) => d.push(b),
+            (Data::Text(d), ValueRef::Text(s)) => d.push(s.to_string()),
+            _ => return self.push(v.to_value()),
+        }
+        self.nulls.push(false);
+    }
+
+    /// The values at positions `idx`, in that order.
+    pub fn gather(&self, idx: &[u32]) -> Vector {
+        let data = match &self.data {
+            Data::Int(_) => Data::Int(Vec::with_capacity(idx.len())),
+            Data::Float(_) => Data::Float(Vec::with_capacity(idx.len())),
+            Data::Bool(_) => Data::Bool(Vec::with_capacity(idx.len())),
+            Data::Text(_) => Data::Text(Vec::with_capacity(idx.len())),
+            Data::Any(_) => Data::Any(Vec::with_capacity(idx.len())),
+        };
+        let mut out = Vector {
+            data,
+            nulls: Vec::with_capacity(idx.len()),
+        };
+        out.extend(self, idx);
+        out
+    }
+
+    /// Append the values of `other` at positions `idx`.
+    pub fn extend(&mut self, other: &Vector, idx: &[u32]) {
+        let at = |i: &u32| *i as usize;
+        match (&mut self.data, &other.data) {
+            (Data::Int(d), Data::Int(s)) => d.extend(idx.iter().map(|i| s[at(i)])),
+            (Data::Float(d), Data::Float(s)) => d.extend(idx.iter().map(|i| s[at(i)])),
+            (Data::Bool(d), Data::Bool(s)) => d.extend(idx.iter().map(|i| s[at(i)])),
+            (Data::Text(d), Data::Text(s)) => d.extend(idx.iter().map(|i| s[at(i)].clone())),
+            (Data::Any(d), Data::Any(s)) => d.extend(idx.iter().map(|i| s[at(i)].clone())),
+            _ => {
+                for i in idx {
+                    self.push(other.get(at(i)));
+                }
+                return;
+            }
+        }
+        self.nulls.extend(idx.iter().map(|i| other.nulls[at(i)]));
+    }
+}
+
+/// Rows stored column by column. Operators drop rows by narrowing the selection instead
+/// of copying the columns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Batch {
+    pub columns: Vec<Vector>,
+    /// Rows in each column, selected or not.
+    pub len: usize,
+    /// Positions of the rows in the batch, ascending; None when all of them are.
+    pub selection: Option<Vec<u32>>,
+}
+
+impl Batch {
+    pub fn new(columns: Vec<Vector>, len: usize) -> Self {
+        Self {
+            columns,
+            len,
+            selection: None,
+        }
+    }
+
+    /// Positions of the rows in the batch.
+    pub fn selected(&self) -> Cow<'_, [u32]> {
+        match &self.selection {
+            Some(sel) => Cow::Borrowed(sel),
+            None => Cow::Owned((0..self.len as u32).collect()),
+        }
+    }
+
+    /// Keep only the rows at positions `sel` (a subset of the selected ones).
+    fn select(&mut self, sel: Vec<u32>) {
+        self.selection = (sel.len() < self.len).then_some(sel);
+    }
+
+    /// The row at position `i`.
+    pub fn row(&self, i: usize) -> Row {
+        self.columns.iter().map(|c| c.get(i)).collect()
+    }
+
+    /// The selected rows.
+    pub fn rows(&self) -> Vec<Row> {
+        self.selected().iter().map(|&i| self.row(i as usize)).collect()
+    }
+}
+
+pub trait BatchOperator {
+    /// Next batch, with at least one row selected, or None when exhausted.
+    fn next_batch(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Batch>>;
+}
+
+/// An operator of either kind, while the tree is being built.
+enum Built {
+    Rows(Box<dyn Operator>),
+    Batches(Box<dyn BatchOperator>),
+}
+
+impl Built {
+    fn into_rows(self) -> Box<dyn Operator> {
+        match self {
+            Built::Rows(op) => op,
+            Built::Batches(op) => Box::new(BatchRows {
+                input: op,
+                batch: None,
+                positions: Vec::new(),
+                next: 0,
+            }),
+        }
+    }
+
+    /// As a batch operator; `plan` is the node it runs.
+    fn into_batches(self, plan: &Plan) -> Box<dyn BatchOperator> {
+        match self {
+            Built::Batches(op) => op,
+            Built::Rows(op) => Box::new(RowSource {
+                input: op,
+                types: types(plan),
+            }),
+        }
+    }
+}
+
+fn types(plan: &Plan) -> Vec<ColumnType> {
+    plan.columns.iter().map(|(_, t)| *t).collect()
+}
+
+/// Build the operator tree for `plan`, with batch operators where they apply.
+pub fn build(plan: &Plan, db: &Database) -> Result<Box<dyn Operator>> {
+    Ok(build_node(plan, db, None)?.into_rows())
+}
+
+/// Output columns of a node that the operators above it read; None for all of them.
+pub type Needed = Option<BTreeSet<usize>>;
+
+/// The columns each child of `plan` must produce when `needed` of its own are read.
+/// Only nodes whose output is computed from their input by expressions can narrow it.
+pub fn child_needs(plan: &Plan, needed: &Needed) -> Vec<Needed> {
+    let all = vec![None; plan.children.len()];
+    let cols = |exprs: &mut dyn Iterator<Item = &Expr>| exprs.flat_map(Expr::columns).collect::<BTreeSet<_>>();
+    match &plan.node {
+        Node::Filter { predicate } => vec![needed.as_ref().map(|n| n | &predicate.columns())],
+        Node::Project { exprs } => {
+            let read = exprs
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| needed.as_ref().is_none_or(|n| n.contains(i)))
+                .map(|(_, e)| e);
+            vec![Some(cols(&mut read.into_iter()))]
+        }
+        Node::Aggregate {
+            group_by, aggregates, ..
+        } => {
+            let args = aggregates.iter().flat_map(|a| a.args.iter().chain(&a.filter));
+            vec![Some(cols(&mut group_by.iter().chain(args)))]
+        }
+        Node::HashJoin {
+            join_type,
+            left_keys,
+            right_keys,
+            residual,
+        } => {
+            let Some(needed) = needed else {
+                return all;
+            };
+            let width = plan.children[0].columns.len();
+            let residual = cols(&mut residual.iter());
+            let mut left = needed | &cols(&mut left_keys.iter());
+            let mut right = cols(&mut right_keys.iter());
+            let output = if join_type.emits_right() { needed | &residual } else { residual };
+            for i in output {
+                if i < width {
+                    left.insert(i);
+                } else {
+                    right.insert(i - width);
+                }
+            }
+            left.retain(|&i| i < width);
+            vec![Some(left), Some(right)]
+        }
+        _ => all,
+    }
+}
+
+fn build_node(plan: &Plan, db: &Database, needed: Needed) -> Result<Built> {
+    if let Node::Gather { .. } = plan.node {
+        // Its workers run its child on batches of their own.
+        return Ok(Built::Rows(exec::build_operator(plan, db, Vec::new())?));
+    }
+    let children = plan
+        .children
+        .iter()
+        .zip(child_needs(plan, &needed))
+        .map(|(c, n)| build_node(c, db, n))
+        .collect::<Result<Vec<_>>>()?;
+    // Nodes above batch operators run on batches too; above row operators, converting
+    // rows to batches would cost more than it saves.
+    let batched = children.iter().any(|c| matches!(c, Built::Batches(_)));
+    let work_mem = (db.config().work_mem_kb * 1024) as f64;
+    let mut inputs = children.into_iter().zip(&plan.children);
+    let mut input = || {
+        let (op, plan) = inputs.next().expect("plan node is missing a child");
+        op.into_batches(plan)
+    };
+    let op: Box<dyn BatchOperator> = match &plan.node {
+        Node::SeqScan { table, filter } => {
+            let table = db.table(table)?;
+            Box::new(Scan {
+                decode: decode_mask(&table, filter.as_ref(), &needed),
+                table,
+                filter: filter.clone(),
+                next_page: 0,
+                started: false,
+            })
+        }
+        Node::Filter { predicate } if batched => Box::new(Filter {
+            input: input(),
+            predicate: predicate.clone(),
+        }),
+        Node::Project { exprs } if batched => Box::new(Project {
+            input: input(),
+            exprs: exprs.clone(),
+        }),
+        // Sorted aggregation without keys has one group, so its output order is moot.
+        Node::Aggregate {
+            strategy,
+            group_by,
+            sets,
+            aggregates,
+        } if batched
+            && (*strategy == AggStrategy::Hash || group_by.is_empty())
+            && sets.len() == 1
+            && plan.est_rows * (row_width(sets[0].len()) + (ACCUMULATOR_SIZE * aggregates.len()) as f64)
+                <= work_mem =>
+        {
+            Box::new(HashAggregate {
+                input: input(),
+                groups: Some(Groups::new(group_by, &sets[0], aggregates)),
+                types: types(plan),
+                output: None,
+            })
+        }
+        Node::HashJoin {
+            join_type,
+            left_keys,
+            right_keys,
+            residual,
+        } if batched && plan.children[1].est_rows * row_width(plan.children[1].columns.len()) <= work_mem => {
+            Box::new(HashJoin {
+                left: input(),
+                right: input(),
+                join_type: *join_type,
+                left_keys: left_keys.clone(),
+                right_keys: right_keys.clone(),
+                residual: residual.clone(),
+                left_types: types(&plan.children[0]),
+                right_types: types(&plan.children[1]),
+                build: None,
+                pending: VecDeque::new(),
+                done: false,
+            })
+        }
+        _ => {
+            let children = inputs.map(|(op, _)| op.into_rows()).collect();
+            return Ok(Built::Rows(exec::build_operator(plan, db, children)?));
+        }
+    };
+    Ok(Built::Batches(op))
+}
+
+/// Value of an expression over a batch: the same for every row, or a column with values
+/// at the positions it was evaluated for.
+enum Datum<'a> {
+    Const(Value),
+    Column(Cow<'a, Vector>),
+}
+
+impl Datum<'_> {
+    fn get(&self, i: usize) -> Value {
+        match self {
+            Datum::Const(v) => v.clone(),
+            Datum::Column(c) => c.get(i),
+        }
+    }
+
+    fn is_null(&self, i: usize) -> bool {
+        match self {
+            Datum::Const(v) => v.is_null(),
+            Datum::Column(c) => c.nulls[i],
+        }
+    }
+
+    /// The values at positions `sel`, as a column.
+    fn gather(&self, sel: &[u32]) -> Vector {
+        match self {
+            Datum::Const(v) => Vector::from_values(vec![v.clone(); sel.len()]),
+            Datum::Column(c) => c.gather(sel),
+        }
+    }
+}
+
+fn column(data: Data, nulls: Vec<bool>) -> Datum<'static> {
+    Datum::Column(Cow::Owned(Vector { data, nulls }))
+}
+
+/// Evaluate `f` at each position of `sel`, into a column of `len` rows.
+fn per_row(len: usize, sel: &[u32], mut f: impl FnMut(usize) -> Result<Value>) -> Result<Datum<'static>> {
+    let mut values = vec![Value::Null; len];
+    for &i in sel {
+        values[i as usize] = f(i as usize)?;
+    }
+    Ok(Datum::Column(Cow::Owned(Vector::from_values(values))))
+}
+
+/// Evaluate `e` for the rows of `batch` at positions `sel`, with the same results and
+/// errors as `Expr::eval` on each of them. Arithmetic, comparisons and the logical
+/// operators run on whole columns; other expressions are evaluated row by row.
+fn eval<'a>(e: &Expr, batch: &'a Batch, sel: &[u32]) -> Result<Datum<'a>> {
+    if sel.is_empty() {
+        return Ok(Datum::Const(Value::Null));
+    }
+    let len = batch.len;
+    match e {
+        Expr::Column(i) => match batch.columns.get(*i) {
+            Some(c) => Ok(Datum::Column(Cow::Borrowed(c))),
+            None => bail!("column {} out of range for row of {}", i, batch.columns.len()),
+        },
+        Expr::Literal(v) => Ok(Datum::Const(v.clone())),
+        Expr::Binary { op, left, right } if matches!(op, BinaryOp::And | BinaryOp::Or) => {
+            // The right side is only evaluated where the left one does not decide.
+            let l = eval(left, batch, sel)?;
+            let decided = Value::Bool(*op == BinaryOp::Or);
+            let rest: Vec<u32> = sel.iter().copied().filter(|&i| l.get(i as usize) != decided).collect();
+            let r = eval(right, batch, &rest)?;
+            bools(len, sel, |i| {
+                let lv = l.get(i);
+                if lv == decided {
+                    return Ok(lv);
+                }
+                expr::logic(*op, lv, r.get(i))
+            })
+        }
+        Expr::Binary { op, left, right } => {
+            let l = eval(left, batch, sel)?;
+            let r = eval(right, batch, sel)?;
+            binary(*op, &l, &r, len, sel)
+        }
+        Expr::Unary { op, expr: inner } => {
+            let v = eval(inner, batch, sel)?;
+            match (op, &v) {
+                (UnaryOp::Not, Datum::Column(c)) if matches!(c.data, Data::Bool(_)) => {
+                    let Data::Bool(d) = &c.data else { unreachable!() };
+                    Ok(column(Data::Bool(d.iter().map(|b| !b).collect()), c.nulls.clone()))
+                }
+                (UnaryOp::Neg, Datum::Column(c)) if matches!(c.data, Data::Float(_)) => {
+                    let Data::Float(d) = &c.data else { unreachable!() };
+                    Ok(column(Data::Float(d.iter().map(|x| -x).collect()), c.nulls.clone()))
+                }
+                _ => per_row(len, sel, |i| {
+                    Expr::Unary {
+                        op: *op,
+                        expr: Box::new(Expr::Literal(v.get(i))),
+                    }
+                    .eval(&[])
+                }),
+            }
+        }
+        Expr::IsNull { expr: inner, negated } => {
+            let v = eval(inner, batch, sel)?;
+            bools(len, sel, |i| Ok(Value::Bool(v.is_null(i) != *negated)))
+        }
+        Expr::Between {
+            expr: inner,
+            low,
+            high,
+            negated,
+        } => {
+            let v = eval(inner, batch, sel)?;
+            let ge = binary(BinaryOp::GtEq, &v, &eval(low, batch, sel)?, len, sel)?;
+            let le = binary(BinaryOp::LtEq, &v, &eval(high, batch, sel)?, len, sel)?;
+            bools(len, sel, |i| {
+                Ok(match expr::logic(BinaryOp::And, ge.get(i), le.get(i))? {
+                    Value::Bool(b) => Value::Bool(b != *negated),
+                    v => v,
+                })
+            })
+        }
+        _ => {
+            let columns = e.columns();
+            let mut row = vec![Value::Null; batch.columns.len()];
+            per_row(len, sel, |i| {
+                for &c in &columns {
+                    if let Some(col) = batch.columns.get(c) {
+                        row[c] = col.get(i);
+                    }
+                }
+                e.eval(&row)
+            })
+        }
+    }
+}
+
+/// A BOOL column from `f`, which returns BOOL or NULL for each position of `sel`.
+fn bools(len: usize, sel: &[u32], mut f: impl FnMut(usize) -> Result<Value>) -> Result<Datum<'static>> {
+    let mut out = vec![false; len];
+    let mut nulls = vec![true; len];
+    for &i in sel {
+        let i = i as usize;
+        if let Value::Bool(b) = f(i)? {
+            out[i] = b;
+            nulls[i] = false;
+        }
+    }
+    Ok(column(Data::Bool(out), nulls))
+}
+
+/// One operand of a typed kernel: a column of values, or a constant.
+enum Side<'a, T> {
+    Column(&'a [T], &'a [bool]),
+    Const(T),
+}
+
+impl<T: Copy> Side<'_, T> {
+    /// Value at position `i`, None if NULL.
+    #[inline]
+    fn at(&self, i: usize) -> Option<T> {
+        match self {
+            Side::Column(d, nulls) => (!nulls[i]).then(|| d[i]),
+            Side::Const(v) => Some(*v),
+        }
+    }
+}
+
+fn ints<'a>(d: &'a Datum) -> Option<Side<'a, i64>> {
+    match d {
+        Datum::Const(Value::Int(n)) => Some(Side::Const(*n)),
+        Datum::Column(c) => match &c.data {
+            Data::Int(v) => Some(Side::Column(v, &c.nulls)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// A numeric operand as FLOAT; INT columns are converted into `buf`.
+fn floats<'a>(d: &'a Datum, buf: &'a mut Vec<f64>) -> Option<Side<'a, f64>> {
+    match d {
+        Datum::Const(v) => v.as_f64().map(Side::Const),
+        Datum::Column(c) => match &c.data {
+            Data::Float(v) => Some(Side::Column(v, &c.nulls)),
+            Data::Int(v) => {
+                *buf = v.iter().map(|&n| n as f64).collect();
+                Some(Side::Column(buf, &c.nulls))
+            }
+            _ => None,
+        },
+    }
+}
+
+/// Apply `f` to the operands at each position of `sel` where neither is NULL.
+fn kernel<A: Copy, B: Copy, T: Clone + Default>(
+    l: &Side<A>,
+    r: &Side<B>,
+    len: usize,
+    sel: &[u32],
+    mut f: impl FnMut(A, B) -> Result<T>,
+) -> Result<(Vec<T>, Vec<bool>)> {
+    let mut out = vec![T::default(); len];
+    let mut nulls = vec![true; len];
+    for &i in sel {
+        let i = i as usize;
+        if let (Some(a), Some(b)) = (l.at(i), r.at(i)) {
+            out[i] = f(a, b)?;
+            nulls[i] = false;
+        }
+    }
+    Ok((out, nulls))
+}
+
+fn compare(op: BinaryOp, ord: Ordering) -> bool {
+    match op {
+        BinaryOp::Eq => ord == Ordering::Equal,
+        BinaryOp::NotEq => ord != Ordering::Equal,
+        BinaryOp::Lt => ord == Ordering::Less,
+        BinaryOp::LtEq => ord != Ordering::Greater,
+        BinaryOp::Gt => ord == Ordering::Greater,
+        _ => ord != Ordering::Less,
+    }
+}
+
+/// `l op r` for an operator other than AND and OR: on typed columns for arithmetic and
+/// comparisons of numbers and comparisons of TEXT, else through `expr::binary`.
+fn binary(op: BinaryOp, l: &Datum, r: &Datum, len: usize, sel: &[u32]) -> Result<Datum<'static>> {
+    if matches!(l, Datum::Const(Value::Null)) || matches!(r, Datum::Const(Value::Null)) {
+        return Ok(Datum::Const(Value::Null));
+    }
+    let arithmetic = matches!(
+        op,
+        BinaryOp::Plus | BinaryOp::Minus | BinaryOp::Multiply | BinaryOp::Divide | BinaryOp::Modulo
+    );
+    if let (Some(a), Some(b)) = (ints(l), ints(r)) {
+        if op.is_comparison() {
+            let (out, nulls) = kernel(&a, &b, len, sel, |x, y| Ok(compare(op, x.cmp(&y))))?;
+            return Ok(column(Data::Bool(out), nulls));
+        }
+        if arithmetic {
+            let (out, nulls) = kernel(&a, &b, len, sel, |x: i64, y: i64| {
+                let out = match op {
+                    BinaryOp::Plus => x.checked_add(y),
+                    BinaryOp::Minus => x.checked_sub(y),
+                    BinaryOp::Multiply => x.checked_mul(y),
+                    BinaryOp::Divide | BinaryOp::Modulo if y == 0 => bail!("division by zero"),
+                    BinaryOp::Divide => x.checked_div(y),
+                    _ => x.checked_rem(y),
+                };
+                out.ok_or_else(|| anyhow!("integer out of range"))
+            })?;
+            return Ok(column(Data::Int(out), nulls));
+        }
+    }
+    let (mut lbuf, mut rbuf) = (Vec::new(), Vec::new());
+    if let (Some(a), Some(b)) = (floats(l, &mut lbuf), floats(r, &mut rbuf)) {
+        // An INT converted to FLOAT may round, so mixed comparisons go row by row.
+        let mixed = ints(l).is_some() || ints(r).is_some();
+        if op.is_comparison() && !mixed {
+            let (out, nulls) = kernel(&a, &b, len, sel, |x: f64, y: f64| Ok(compare(op, cmp_floats(x, y))))?;
+            return Ok(column(Data::Bool(out), nulls));
+        }
+        if arithmetic {
+            let (out, nulls) = kernel(&a, &b, len, sel, |x: f64, y: f64| {
+                Ok(match op {
+                    BinaryOp::Plus => x + y,
+                    BinaryOp::Minus => x - y,
+                    BinaryOp::Multiply => x * y,
+                    BinaryOp::Divide | BinaryOp::Modulo if y == 0.0 => bail!("division by zero"),
+                    BinaryOp::Divide => x / y,
+                    _ => x % y,
+                })
+            })?;
+            return Ok(column(Data::Float(out), nulls));
+        }
+    }
+    if op.is_comparison() {
+        if let (Some(a), Some(b)) = (texts(l), texts(r)) {
+            return bools(len, sel, |i| {
+                Ok(match (a(i), b(i)) {
+                    (Some(x), Some(y)) => Value::Bool(compare(op, x.cmp(y))),
+                    _ => Value::Null,
+                })
+            });
+        }
+    }
+    per_row(len, sel, |i| expr::binary(op, l.get(i), r.get(i)))
+}
+
+/// A TEXT operand, as a function of the position (None where NULL).
+fn texts<'a>(d: &'a Datum) -> Option<Box<dyn Fn(usize) -> Option<&'a str> + 'a>> {
+    match d {
+        Datum::Const(Value::Text(s)) => Some(Box::new(move |_| Some(s.as_str()))),
+        Datum::Column(c) => match &c.data {
+            Data::Text(v) => Some(Box::new(move |i| (!c.nulls[i]).then(|| v[i].as_str()))),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The positions of `sel` at which `predicate` is TRUE (NULL and FALSE do not pass).
+fn select(predicate: &Expr, batch: &Batch, sel: &[u32]) -> Result<Vec<u32>> {
+    let d = eval(predicate, batch, sel)?;
+    if let Datum::Column(c) = &d {
+        if let Data::Bool(b) = &c.data {
+            return Ok(sel.iter().copied().filter(|&i| !c.nulls[i as usize] && b[i as usize]).collect());
+        }
+    }
+    let mut out = Vec::new();
+    for &i in sel {
+        // Fails, as `eval_predicate` does, on a value that is not BOOL.
+        if Expr::Literal(d.get(i as usize)).eval_predicate(&[])? {
+            out.push(i);
+        }
+    }
+    Ok(out)
+}
+
+/// Key of the row at position `i`, or None if any part is NULL (NULL never equals
+/// anything).
+fn key_at(keys: &[Datum], i: usize) -> Option<Vec<Value>> {
+    keys.iter().map(|k| Some(k.get(i)).filter(|v| !v.is_null())).collect()
+}
+
+/// Batches holding `rows`, of columns of types `types`.
+fn batches_of(rows: Vec<Row>, types: &[ColumnType]) -> VecDeque<Batch> {
+    let mut out = VecDeque::new();
+    let mut rows = rows.into_iter().peekable();
+    while rows.peek().is_some() {
+        let mut columns: Vec<Vector> = types.iter().map(|&t| Vector::with_capacity(t, BATCH_SIZE)).collect();
+        let mut len = 0;
+        for row in rows.by_ref().take(BATCH_SIZE) {
+            for (c, v) in columns.iter_mut().zip(row) {
+                c.push(v);
+            }
+            len += 1;
+        }
+        out.push_back(Batch::new(columns, len));
+    }
+    out
+}
+
+/// The rows of a batch operator, one at a time.
+struct BatchRows {
+    input: Box<dyn BatchOperator>,
+    batch: Option<Batch>,
+    /// Selected positions of `batch`, and the next of them to return.
+    positions: Vec<u32>,
+    next: usize,
+}
+
+impl Operator for BatchRows {
+    fn next(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Row>> {
+        loop {
+            if let Some(batch) = &self.batch {
+                if let Some(&i) = self.positions.get(self.next) {
+                    self.next += 1;
+                    return Ok(Some(batch.row(i as usize)));
+                }
+            }
+            let Some(batch) = self.input.next_batch(ctx)? else {
+                return Ok(None);
+            };
+            self.positions = batch.selected().into_owned();
+            self.next = 0;
+            self.batch = Some(batch);
+        }
+    }
+}
+
+/// Batches of the rows of a row operator.
+struct RowSource {
+    input: Box<dyn Operator>,
+    types: Vec<ColumnType>,
+}
+
+impl BatchOperator for RowSource {
+    fn next_batch(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Batch>> {
+        let mut rows = Vec::new();
+        while rows.len() < BATCH_SIZE {
+            match self.input.next(ctx)? {
+                Some(row) => rows.push(row),
+                None => break,
+            }
+        }
+        Ok(batches_of(rows, &self.types).pop_front())
+    }
+}
+
+/// Sequential scan decoding each visible row straight into the batch's columns.
+struct Scan {
+    table: Arc<Table>,
+    /// Whether each column is read above the scan; the others are left NULL.
+    decode: Vec<bool>,
+    filter: Option<Expr>,
+    next_page: u32,
+    started: bool,
+}
+
+impl BatchOperator for Scan {
+    fn next_batch(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Batch>> {
+        if !self.started {
+            ctx.txn.note_table_scan(&self.table);
+            self.started = true;
+        }
+        ctx.limits.check()?;
+        let mut pages = self.next_page..self.table.num_pages();
+        let batch = scan_batch(ctx.txn.read_view(), &self.table, &self.decode, self.filter.as_ref(), &mut pages)?;
+        self.next_page = pages.start;
+        Ok(batch)
+    }
+}
+
+/// Which columns of `table` a scan with `filter` has to decode when `needed` of them are
+/// read above it.
+pub fn decode_mask(table: &Table, filter: Option<&Expr>, needed: &Needed) -> Vec<bool> {
+    let read = needed.as_ref().map(|n| n | &filter.iter().flat_map(|f| f.columns()).collect());
+    (0..table.schema().len()).map(|i| read.as_ref().is_none_or(|r| r.contains(&i))).collect()
+}
+
+/// The next batch of rows of `table` visible to `view` that pass `filter`, read from the
+/// start of `pages` on; the pages read are taken off the range. Columns not in `decode`
+/// are left NULL. None once the range is used up.
+pub fn scan_batch(
+    view: &ReadView,
+    table: &Table,
+    decode: &[bool],
+    filter: Option<&Expr>,
+    pages: &mut Range<u32>,
+) -> Result<Option<Batch>> {
+    let schema = table.schema();
+    while pages.start < pages.end {
+        let mut columns: Vec<Vector> = schema.iter().map(|&t| Vector::with_capacity(t, BATCH_SIZE)).collect();
+        let mut len = 0;
+        while len < BATCH_SIZE && pages.start < pages.end {
+            view.visit_page(table, pages.start, |_, bytes| {
+                len += 1;
+                row_decode_each(schema, bytes, |i, v| {
+                    if decode[i] {
+                        columns[i].push_ref(v)
+                    }
+                })
+            })?;
+            pages.start += 1;
+        }
+        if len == 0 {
+            continue;
+        }
+        for (i, &t) in schema.iter().enumerate() {
+            if !decode[i] {
+                columns[i] = Vector::nulls(t, len);
+            }
+        }
+        let batch = Batch::new(columns, len);
+        match filter {
+            Some(f) => {
+                if let Some(batch) = filter_batch(f, batch)? {
+                    return Ok(Some(batch));
+                }
+            }
+            None => return Ok(Some(batch)),
+        }
+    }
+    Ok(None)
+}
+
+/// `batch` with only the rows passing `predicate` selected; None if none do.
+pub fn filter_batch(predicate: &Expr, mut batch: Batch) -> Result<Option<Batch>> {
+    let sel = select(predicate, &batch, &batch.selected())?;
+    if sel.is_empty() {
+        return Ok(None);
+    }
+    batch.select(sel);
+    Ok(Some(batch))
+}
+
+/// The values of `exprs` for the selected rows of `batch`.
+pub fn project_batch(exprs: &[Expr], batch: &Batch) -> Result<Batch> {
+    let sel = batch.selected();
+    let columns = exprs.iter().map(|e| Ok(eval(e, batch, &sel)?.gather(&sel))).collect::<Result<_>>()?;
+    Ok(Batch::new(columns, sel.len()))
+}
+
+struct Filter {
+    input: Box<dyn BatchOperator>,
+    predicate: Expr,
+}
+
+impl BatchOperator for Filter {
+    fn next_batch(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Batch>> {
+        while let Some(batch) = self.input.next_batch(ctx)? {
+            if let Some(batch) = filter_batch(&self.predicate, batch)? {
+                return Ok(Some(batch));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Projection; its batches hold only the selected rows of its input's.
+struct Project {
+    input: Box<dyn BatchOperator>,
+    exprs: Vec<Expr>,
+}
+
+impl BatchOperator for Project {
+    fn next_batch(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Batch>> {
+        match self.input.next_batch(ctx)? {
+            Some(batch) => Ok(Some(project_batch(&self.exprs, &batch)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Hash aggregation of a single grouping set, held in memory.
+struct HashAggregate {
+    input: Box<dyn BatchOperator>,
+    /// Taken once the input has been aggregated.
+    groups: Option<Groups>,
+    types: Vec<ColumnType>,
+    output: Option<VecDeque<Batch>>,
+}
+
+impl BatchOperator for HashAggregate {
+    fn next_batch(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Batch>> {
+        if let Some(mut groups) = self.groups.take() {
+            let mut memory = ctx.limits.reservation();
+            while let Some(batch) = self.input.next_batch(ctx)? {
+                groups.add(&batch)?;
+                memory.resize(groups.size())?;
+            }
+            self.output = Some(batches_of(groups.finish()?, &self.types));
+        }
+        Ok(self.output.as_mut().and_then(VecDeque::pop_front))
+    }
+}
+
+/// The groups of one grouping set and their accumulators. Keys and aggregate arguments
+/// are evaluated a batch at a time; groups built over different rows of the same input
+/// can be merged (see `parallel`).
+#[derive(Debug, Clone)]
+pub struct Groups {
+    group_by: Vec<Expr>,
+    /// The grouping set: positions in `group_by`.
+    keys: Vec<usize>,
+    aggregates: Vec<AggCall>,
+    index: HashMap<Vec<Value>, usize>,
+    /// Key values and accumulators of each group, in order of first appearance.
+    groups: Vec<(Vec<Value>, Vec<Accumulator>)>,
+}
+
+impl Groups {
+    pub fn new(group_by: &[Expr], keys: &[usize], aggregates: &[AggCall]) -> Self {
+        let mut groups = Self {
+            group_by: group_by.to_vec(),
+            keys: keys.to_vec(),
+            aggregates: aggregates.to_vec(),
+            index: HashMap::new(),
+            groups: Vec::new(),
+        };
+        if keys.is_empty() {
+            // The one group, which has a row even without input.
+            groups.group(Vec::new());
+        }
+        groups
+    }
+
+    /// Position of the group with `key`, added if new.
+    fn group(&mut self, key: Vec<Value>) -> usize {
+        if let Some(&g) = self.index.get(&key) {
+            return g;
+        }
+        self.index.insert(key.clone(), self.groups.len());
+        self.groups.push((key, self.aggregates.iter().map(Accumulator::new).collect()));
+        self.groups.len() - 1
+    }
+
+    /// Estimated bytes the groups take in memory.
+    pub fn size(&self) -> usize {
+        self.groups.len() * (row_width(self.keys.len()) as usize + ACCUMULATOR_SIZE * self.aggregates.len())
+    }
+
+    /// Add the selected rows of `batch`.
+    pub fn add(&mut self, batch: &Batch) -> Result<()> {
+        let sel = batch.selected();
+        let mut group = vec![0; batch.len];
+        if !self.keys.is_empty() {
+            let key_columns = self
+                .keys
+                .iter()
+                .map(|&k| eval(&self.group_by[k], batch, &sel))
+                .collect::<Result<Vec<_>>>()?;
+            for &i in sel.iter() {
+                let key: Vec<Value> = key_columns.iter().map(|k| k.get(i as usize)).collect();
+                group[i as usize] = self.group(key);
+            }
+        }
+        for (j, call) in self.aggregates.iter().enumerate() {
+            let rows = match &call.filter {
+                Some(f) => Cow::Owned(select(f, batch, &sel)?),
+                None => Cow::Borrowed(&sel[..]),
+            };
+            let args = call.args.iter().map(|a| eval(a, batch, &rows)).collect::<Result<Vec<_>>>()?;
+            for &i in rows.iter() {
+                let i = i as usize;
+                // Every aggregate but COUNT(*) skips a NULL first argument.
+                if args.first().is_some_and(|a| a.is_null(i)) {
+                    continue;
+                }
+                self.groups[group[i]].1[j].update(args.iter().map(|a| a.get(i)).collect())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the groups of `other`, built over other rows by the same aggregation.
+    pub fn merge(&mut self, other: Groups) -> Result<()> {
+        for (key, accs) in other.groups {
+            let g = self.group(key);
+            for (acc, other) in self.groups[g].1.iter_mut().zip(accs) {
+                acc.merge(other)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// One row per group: the grouping keys (NULL for those not in the set) followed by
+    /// the value of each aggregate.
+    pub fn finish(self) -> Result<Vec<Row>> {
+        self.groups
+            .into_iter()
+            .map(|(key, accs)| {
+                let mut row = vec![Value::Null; self.group_by.len()];
+                for (&k, v) in self.keys.iter().zip(key) {
+                    row[k] = v;
+                }
+                for acc in &accs {
+                    row.push(acc.finish()?);
+                }
+                Ok(row)
+            })
+            .collect()
+    }
+}
+
+/// The build (right) side of a hash join, held in memory.
+struct HashTable {
+    columns: Vec<Vector>,
+    /// Positions in `columns` of the rows with each key.
+    index: HashMap<Vec<Value>, Vec<u32>>,
+    /// Whether each row has matched a probe row.
+    matched: Vec<bool>,
+    /// Kept so the rows count against the memory budget while the table lives.
+    _memory: Reservation,
+}
+
+/// Hash join. The build side is read into columns; each probe batch is matched against
+/// it, and the candidate pairs are gathered into batches on which the residual condition
+/// is evaluated.
+struct HashJoin {
+    left: Box<dyn BatchOperator>,
+    right: Box<dyn BatchOperator>,
+    join_type: JoinType,
+    left_keys: Vec<Expr>,
+    right_keys: Vec<Expr>,
+    residual: Option<Expr>,
+    left_types: Vec<ColumnType>,
+    right_types: Vec<ColumnType>,
+    build: Option<HashTable>,
+    pending: VecDeque<Batch>,
+    done: bool,
+}
+
+impl HashJoin {
+    fn build(&mut self, ctx: &mut ExecContext<'_>) -> Result<HashTable> {
+        let keep_nulls = matches!(self.join_type, JoinType::Right | JoinType::Full);
+        let mut columns: Vec<Vector> = self.right_types.iter().map(|&t| Vector::with_capacity(t, 0)).collect();
+        let mut index: HashMap<Vec<Value>, Vec<u32>> = HashMap::new();
+        let mut memory = ctx.limits.reservation();
+        let width = row_width(self.right_types.len()) as usize;
+        let mut len = 0;
+        while let Some(batch) = self.right.next_batch(ctx)? {
+            let sel = batch.selected();
+            let keys = self.right_keys.iter().map(|e| eval(e, &batch, &sel)).collect::<Result<Vec<_>>>()?;
+            let mut keep = Vec::with_capacity(sel.len());
+            for &i in sel.iter() {
+                let key = key_at(&keys, i as usize);
+                if key.is_none() && !keep_nulls {
+                    continue;
+                }
+                if let Some(key) = key {
+                    index.entry(key).or_default().push((len + keep.len()) as u32);
+                }
+                keep.push(i);
+            }
+            for (c, col) in columns.iter_mut().zip(&batch.columns) {
+                c.extend(col, &keep);
+            }
+            len += keep.len();
+            memory.grow(keep.len() * width)?;
+        }
+        Ok(HashTable {
+            columns,
+            index,
+            matched: vec![false; len],
+            _memory: memory,
+        })
+    }
+
+    /// Join one probe batch, queueing the output batches.
+    fn probe(&mut self, batch: Batch) -> Result<()> {
+        let table = self.build.as_mut().expect("hash join probed before it was built");
+        let sel = batch.selected().into_owned();
+        let keys = self.left_keys.iter().map(|e| eval(e, &batch, &sel)).collect::<Result<Vec<_>>>()?;
+        let (mut lefts, mut rights) = (Vec::new(), Vec::new());
+        for &i in &sel {
+            if let Some(ids) = key_at(&keys, i as usize).and_then(|k| table.index.get(&k)) {
+                for &r in ids {
+                    lefts.push(i);
+                    rights.push(r);
+                }
+            }
+        }
+        let mut matched = vec![false; batch.len];
+        if !self.join_type.emits_right() && self.residual.is_none() {
+            for &i in &lefts {
+                matched[i as usize] = true;
+            }
+        } else {
+            for (l, r) in lefts.chunks(BATCH_SIZE).zip(rights.chunks(BATCH_SIZE)) {
+                let mut columns: Vec<Vector> = batch.columns.iter().map(|c| c.gather(l)).collect();
+                columns.extend(table.columns.iter().map(|c| c.gather(r)));
+                let mut pairs = Batch::new(columns, l.len());
+                let pass = match &self.residual {
+                    Some(residual) => select(residual, &pairs, &pairs.selected())?,
+                    None => (0..l.len() as u32).collect(),
+                };
+                for &p in &pass {
+                    matched[l[p as usize] as usize] = true;
+                    table.matched[r[p as usize] as usize] = true;
+                }
+                if self.join_type.emits_right() && !pass.is_empty() {
+                    pairs.select(pass);
+                    self.pending.push_back(pairs);
+                }
+            }
+        }
+        let unmatched: Vec<u32> = sel.iter().copied().filter(|&i| !matched[i as usize]).collect();
+        match self.join_type {
+            JoinType::Semi | JoinType::Anti => {
+                let keep: Vec<u32> = if self.join_type == JoinType::Semi {
+                    sel.into_iter().filter(|&i| matched[i as usize]).collect()
+                } else {
+                    unmatched
+                };
+                if !keep.is_empty() {
+                    let mut batch = batch;
+                    batch.select(keep);
+                    self.pending.push_back(batch);
+                }
+            }
+            JoinType::Left | JoinType::Full if !unmatched.is_empty() => {
+                let mut columns: Vec<Vector> = batch.columns.iter().map(|c| c.gather(&unmatched)).collect();
+                columns.extend(self.right_types.iter().map(|&t| Vector::nulls(t, unmatched.len())));
+                self.pending.push_back(Batch::new(columns, unmatched.len()));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Queue the build rows no probe row matched, for right and full joins.
+    fn finish(&mut self) {
+        let Some(table) = &self.build else {
+            return;
+        };
+        if !matches!(self.join_type, JoinType::Right | JoinType::Full) {
+            return;
+        }
+        let unmatched: Vec<u32> = (0..table.matched.len() as u32).filter(|&i| !table.matched[i as usize]).collect();
+        for chunk in unmatched.chunks(BATCH_SIZE) {
+            let mut columns: Vec<Vector> = self.left_types.iter().map(|&t| Vector::nulls(t, chunk.len())).collect();
+            columns.extend(table.columns.iter().map(|c| c.gather(chunk)));
+            self.pending.push_back(Batch::new(columns, chunk.len()));
+        }
+    }
+}
+
+impl BatchOperator for HashJoin {
+    fn next_batch(&mut self, ctx: &mut ExecContext<'_>) -> Result<Option<Batch>> {
+        if self.build.is_none() {
+            self.build = Some(self.build(ctx)?);
+        }
+        loop {
+            if let Some(batch) = self.pending.pop_front() {
+                return Ok(Some(batch));
+            }
+            if self.done {
+                return Ok(None);
+            }
+            match self.left.next_batch(ctx)? {
+                Some(batch) => self.probe(batch)?,
+                None => {
+                    self.finish();
+                    self.done = true;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ints(values: &[Option<i64>]) -> Vector {
+        Vector::from_values(values.iter().map(|v| v.map_or(Value::Null, Value::Int)).collect())
+    }
+
+    #[test]
+    fn expressions_over_batches_match_row_evaluation() {
+        let a = ints(&[Some(1), Some(0), None, Some(4), Some(-2)]);
+        let b = ints(&[Some(2), Some(0), Some(3), None, Some(5)]);
+        let batch = Batch::new(vec![a, b], 5);
+        let col = |i| Box::new(Expr::Column(i));
+        let lit = |n| Box::new(Expr::Literal(Value::Int(n)));
+        let exprs = [
+            Expr::binary(BinaryOp::Plus, Expr::Column(0), Expr::Column(1)),
+            Expr::binary(BinaryOp::Lt, Expr::Column(0), Expr::Literal(Value::Float(1.5))),
+            // The division only runs where b <> 0.
+            Expr::binary(
+                BinaryOp::And,
+                Expr::binary(BinaryOp::NotEq, Expr::Column(1), Expr::Literal(Value::Int(0))),
+                Expr::binary(BinaryOp::Gt, Expr::binary(BinaryOp::Divide, Expr::Column(0), Expr::Column(1)), *lit(0)),
+            ),
+            Expr::Between {
+                expr: col(0),
+                low: lit(0),
+                high: lit(3),
+                negated: true,
+            },
+            Expr::IsNull {
+                expr: col(1),
+                negated: false,
+            },
+            Expr::Unary {
+                op: UnaryOp::Neg,
+                expr: col(0),
+            },
+            Expr::Cast {
+                expr: col(0),
+                ty: ColumnType::Text,
+            },
+        ];
+        let sel = batch.selected();
+        for e in &exprs {
+            let d = eval(e, &batch, &sel).unwrap();
+            for i in 0..batch.len {
+                assert_eq!(d.get(i), e.eval(&batch.row(i)).unwrap(), "{} at row {}", e, i);
+            }
+        }
+
+        let div = Expr::binary(BinaryOp::Divide, Expr::Column(0), Expr::Column(1));
+        assert_eq!(eval(&div, &batch, &sel).err().unwrap().to_string(), "division by zero");
+        // Rows outside the selection are not evaluated.
+        assert!(eval(&div, &batch, &[0, 2, 3, 4]).is_ok());
+
+        let gt = Expr::binary(BinaryOp::Gt, Expr::Column(0), Expr::Literal(Value::Int(0)));
+        assert_eq!(select(&gt, &batch, &sel).unwrap(), vec![0, 3]);
+        let err = select(&Expr::Column(0), &batch, &sel).unwrap_err();
+        assert_eq!(err.to_string(), "argument of WHERE must be BOOL, not INT");
+    }
+
+    #[test]
+    fn vectors_gather_and_widen_to_any() {
+        let mut v = Vector::with_capacity(ColumnType::Int, 4);
+        v.push(Value::Int(1));
+        v.push_null();
+        v.push_ref(ValueRef::Int(3));
+        assert_eq!(v.gather(&[2, 1, 0]), ints(&[Some(3), None, Some(1)]));
+        v.push(Value::Text("x".into()));
+        assert!(matches!(v.data, Data::Any(_)));
+        let values: Vec<Value> = (0..v.len()).map(|i| v.get(i)).collect();
+        assert_eq!(values, vec![Value::Int(1), Value::Null, Value::Int(3), Value::Text("x".into())]);
+    }
+}
@@ -0,0 +1,539 @@
+//! Window functions: `row_number`, `rank`, `dense_rank`, `ntile`, `lag`, `lead`,
+//! `first_value`, `last_value` and every aggregate, computed for each row over its
+//! partition. The `Window` plan node hands each partition, sorted on the window's ORDER
+//! BY, to `Partition::evaluate`.
+
+use anyhow::{bail, Result};
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::query::aggregate::{Accumulator, AggCall, AggFunc};
+use crate::query::exec::compare_keys;
+use crate::query::expr::{BinaryOp, Expr};
+use crate::query::plan::SortKey;
+use crate::storage::{ColumnType, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameUnits {
+    /// Offsets count rows.
+    Rows,
+    /// Offsets are distances in the (single) ORDER BY value; rows with equal keys are
+    /// always in or out of the frame together.
+    Range,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameBound {
+    UnboundedPreceding,
+    /// A constant non-negative offset: INT for `ROWS`, of the ORDER BY key's type for `RANGE`.
+    Preceding(Value),
+    CurrentRow,
+    Following(Value),
+    UnboundedFollowing,
+}
+
+impl FrameBound {
+    /// Position in the order bounds can appear in; a frame may not start after it ends.
+    fn rank(&self) -> u8 {
+        match self {
+            FrameBound::UnboundedPreceding => 0,
+            FrameBound::Preceding(_) => 1,
+            FrameBound::CurrentRow => 2,
+            FrameBound::Following(_) => 3,
+            FrameBound::UnboundedFollowing => 4,
+        }
+    }
+}
+
+impl fmt::Display for FrameBound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameBound::UnboundedPreceding => write!(f, "UNBOUNDED PRECEDING"),
+            FrameBound::Preceding(v) => write!(f, "{} PRECEDING", v),
+            FrameBound::CurrentRow => write!(f, "CURRENT ROW"),
+            FrameBound::Following(v) => write!(f, "{} FOLLOWING", v),
+            FrameBound::UnboundedFollowing => write!(f, "UNBOUNDED FOLLOWING"),
+        }
+    }
+}
+
+/// The rows of its partition a function sees for one row.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub units: FrameUnits,
+    pub start: FrameBound,
+    pub end: FrameBound,
+}
+
+impl Default for Frame {
+    /// `RANGE BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW`: the partition up to the current
+    /// row's last peer, or all of it without ORDER BY.
+    fn default() -> Self {
+        Self {
+            units: FrameUnits::Range,
+            start: FrameBound::UnboundedPreceding,
+            end: FrameBound::CurrentRow,
+        }
+    }
+}
+
+impl Frame {
+    /// Check the bounds against each other and against the window's ORDER BY keys (of
+    /// types `order_types`).
+    pub fn validate(&self, order_types: &[ColumnType]) -> Result<()> {
+        if self.start == FrameBound::UnboundedFollowing {
+            bail!("frame start cannot be UNBOUNDED FOLLOWING");
+        }
+        if self.end == FrameBound::UnboundedPreceding {
+            bail!("frame end cannot be UNBOUNDED PRECEDING");
+        }
+        if self.start.rank() > self.end.rank() {
+            bail!("frame starting from {} cannot end with {}", self.start, self.end);
+        }
+        for bound in [&self.start, &self.end] {
+            let (FrameBound::Preceding(v) | FrameBound::Following(v)) = bound else {
+                continue;
+            };
+            let negative = match v {
+                Value::Int(n) => *n < 0,
+                Value::Float(x) => x.is_nan() || *x < 0.0,
+                _ if self.units == FrameUnits::Rows => bail!("frame offset must be an integer, got {}", v),
+                _ => bail!("RANGE offset must be a number, got {}", v),
+            };
+            if negative {
+                bail!("frame offset must not be negative, got {}", v);
+            }
+            match (self.units, order_types) {
+                (FrameUnits::Rows, _) if !matches!(v, Value::Int(_)) => {
+                    bail!("frame offset must be an integer, got {}", v)
+                }
+                (FrameUnits::Range, [ColumnType::Int | ColumnType::Float]) => {}
+                (FrameUnits::Range, [_]) => {
+                    bail!("RANGE with offset PRECEDING/FOLLOWING needs a numeric ORDER BY column")
+                }
+                (FrameUnits::Range, _) => {
+                    bail!("RANGE with offset PRECEDING/FOLLOWING requires exactly one ORDER BY column")
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let units = match self.units {
+            FrameUnits::Rows => "ROWS",
+            FrameUnits::Range => "RANGE",
+        };
+        write!(f, "{} BETWEEN {} AND {}", units, self.start, self.end)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WindowFunc {
+    RowNumber,
+    /// Position of the row's first peer, so ties share a rank and leave gaps.
+    Rank,
+    /// Number of distinct peer groups up to the row: ranks without gaps.
+    DenseRank,
+    /// `ntile(n)`: bucket 1..=n of the partition divided as evenly as possible.
+    Ntile,
+    /// `lag(value [, offset [, default]])`: `value` at the row `offset` (1) rows earlier,
+    /// or `default` (NULL) past the partition's edge.
+    Lag,
+    /// Like `Lag`, `offset` rows later.
+    Lead,
+    FirstValue,
+    LastValue,
+    /// An aggregate over the frame.
+    Aggregate(AggFunc),
+}
+
+impl WindowFunc {
+    /// The window function called `name` (lower case), if it is one. Aggregates are
+    /// window functions too.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "row_number" => WindowFunc::RowNumber,
+            "rank" => WindowFunc::Rank,
+            "dense_rank" => WindowFunc::DenseRank,
+            "ntile" => WindowFunc::Ntile,
+            "lag" => WindowFunc::Lag,
+            "lead" => WindowFunc::Lead,
+            "first_value" => WindowFunc::FirstValue,
+            "last_value" => WindowFunc::LastValue,
+            _ => WindowFunc::Aggregate(AggFunc::from_name(name)?),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            WindowFunc::RowNumber => "row_number",
+            WindowFunc::Rank => "rank",
+            WindowFunc::DenseRank => "dense_rank",
+            WindowFunc::Ntile => "ntile",
+            WindowFunc::Lag => "lag",
+            WindowFunc::Lead => "lead",
+            WindowFunc::FirstValue => "first_value",
+            WindowFunc::LastValue => "last_value",
+            WindowFunc::Aggregate(f) => f.name(),
+        }
+    }
+
+    /// Result type for arguments of types `args`, or an error if the call does not exist.
+    pub fn result_type(&self, args: &[ColumnType]) -> Result<ColumnType> {
+        use ColumnType::*;
+        Ok(match (self, args) {
+            (WindowFunc::Aggregate(f), args) => return f.result_type(args),
+            (WindowFunc::RowNumber | WindowFunc::Rank | WindowFunc::DenseRank, []) => Int,
+            (WindowFunc::Ntile, [Int]) => Int,
+            (WindowFunc::Lag | WindowFunc::Lead, [t] | [t, Int]) => *t,
+            (WindowFunc::Lag | WindowFunc::Lead, [t, Int, d]) if d == t => *t,
+            (WindowFunc::FirstValue | WindowFunc::LastValue, [t]) => *t,
+            _ => bail!(
+                "function {}({}) does not exist",
+                self.name(),
+                args.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(", ")
+            ),
+        })
+    }
+}
+
+/// One window function call, over the columns of the rows the `Window` node reads.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowCall {
+    pub func: WindowFunc,
+    pub args: Vec<Expr>,
+    /// `FILTER (WHERE ...)` of an aggregate.
+    pub filter: Option<Expr>,
+    /// Only used by aggregates, `first_value` and `last_value`.
+    pub frame: Frame,
+}
+
+impl WindowCall {
+    /// SQL-like rendering, naming column `i` by `name(i)`.
+    pub fn display_with(&self, name: &dyn Fn(usize) -> String) -> String {
+        let args = match &self.func {
+            WindowFunc::Aggregate(AggFunc::CountStar) => "*".to_string(),
+            _ => self.args.iter().map(|a| a.display_with(name)).collect::<Vec<_>>().join(", "),
+        };
+        let mut s = format!("{}({})", self.func.name(), args);
+        if let Some(f) = &self.filter {
+            s.push_str(&format!(" FILTER (WHERE {})", f.display_with(name)));
+        }
+        s
+    }
+}
+
+/// The rows of one partition, sorted on the window's ORDER BY.
+pub struct Partition<'a> {
+    rows: &'a [Vec<Value>],
+    order_by: &'a [SortKey],
+    /// ORDER BY values of each row.
+    keys: Vec<Vec<Value>>,
+    /// For each row, the range of its peers (rows with equal ORDER BY values).
+    peers: Vec<(usize, usize)>,
+}
+
+impl<'a> Partition<'a> {
+    pub fn new(rows: &'a [Vec<Value>], order_by: &'a [SortKey]) -> Result<Self> {
+        let keys = rows
+            .iter()
+            .map(|r| order_by.iter().map(|k| k.expr.eval(r)).collect::<Result<Vec<_>>>())
+            .collect::<Result<Vec<_>>>()?;
+        let mut peers = Vec::with_capacity(rows.len());
+        let mut start = 0;
+        while start < rows.len() {
+            let mut end = start + 1;
+            while end < rows.len() && compare_keys(&keys[start], &keys[end], order_by) == Ordering::Equal {
+                end += 1;
+            }
+            peers.extend(std::iter::repeat_n((start, end), end - start));
+            start = end;
+        }
+        Ok(Self {
+            rows,
+            order_by,
+            keys,
+            peers,
+        })
+    }
+
+    /// The value of `call` for every row of the partition.
+    pub fn evaluate(&self, call: &WindowCall) -> Result<Vec<Value>> {
+        let n = self.rows.len();
+        let arg = |row: usize, i: usize| call.args[i].eval(&self.rows[row]);
+        let mut out = Vec::with_capacity(n);
+        match &call.func {
+            WindowFunc::RowNumber => out.extend((1..=n as i64).map(Value::Int)),
+            WindowFunc::Rank => out.extend(self.peers.iter().map(|(start, _)| Value::Int(*start as i64 + 1))),
+            WindowFunc::DenseRank => {
+                let mut rank = 0;
+                for (i, (start, _)) in self.peers.iter().enumerate() {
+                    if *start == i {
+                        rank += 1;
+                    }
+                    out.push(Value::Int(rank));
+                }
+            }
+            WindowFunc::Ntile => {
+                for i in 0..n {
+                    let buckets = match arg(i, 0)? {
+                        Value::Int(b) if b > 0 => b as usize,
+                        Value::Null => {
+                            out.push(Value::Null);
+                            continue;
+                        }
+                        v => bail!("argument of ntile must be greater than zero, got {}", v),
+                    };
+                    // The first n % buckets buckets get one row more than the rest.
+                    let (size, extra) = (n / buckets, n % buckets);
+                    let bucket = if i < extra * (size + 1) {
+                        i / (size + 1)
+                    } else {
+                        extra + (i - extra * (size + 1)) / size
+                    };
+                    out.push(Value::Int(bucket as i64 + 1));
+                }
+            }
+            WindowFunc::Lag | WindowFunc::Lead => {
+                for i in 0..n {
+                    let offset = match call.args.len() {
+                        1 => 1,
+                        _ => match arg(i, 1)? {
+                            Value::Int(k) => k,
+                            Value::Null => {
+                                out.push(Value::Null);
+                                continue;
+                            }
+                            v => bail!("offset of {} must be an integer, got {}", call.func.name(), v),
+                        },
+                    };
+                    let target = match &call.func {
+                        WindowFunc::Lag => (i as i64).checked_sub(offset),
+                        _ => (i as i64).checked_add(offset),
+                    };
+                    out.push(match target {
+                        Some(j) if (0..n as i64).contains(&j) => arg(j as usize, 0)?,
+                        _ if call.args.len() == 3 => arg(i, 2)?,
+                        _ => Value::Null,
+                    });
+                }
+            }
+            WindowFunc::FirstValue | WindowFunc::LastValue => {
+                for i in 0..n {
+                    let (lo, hi) = self.frame(&call.frame, i)?;
+                    out.push(match &call.func {
+                        _ if lo == hi => Value::Null,
+                        WindowFunc::FirstValue => arg(lo, 0)?,
+                        _ => arg(hi - 1, 0)?,
+                    });
+                }
+            }
+            WindowFunc::Aggregate(func) => {
+                let agg = AggCall {
+                    func: func.clone(),
+                    args: call.args.clone(),
+                    distinct: false,
+                    filter: call.filter.clone(),
+                };
+                let inputs = self.rows.iter().map(|r| agg.inputs(r)).collect::<Result<Vec<_>>>()?;
+                if call.frame.start == FrameBound::UnboundedPreceding {
+                    // Frame ends never move backwards: keep one running accumulator.
+                    let mut acc = Accumulator::new(&agg);
+                    let mut added = 0;
+                    for i in 0..n {
+                        let (_, hi) = self.frame(&call.frame, i)?;
+                        for input in inputs[added.min(hi)..hi].iter().flatten() {
+                            acc.update(input.clone())?;
+                        }
+                        added = added.max(hi);
+                        out.push(acc.finish()?);
+                    }
+                } else {
+                    let mut last: Option<((usize, usize), Value)> = None;
+                    for i in 0..n {
+                        let frame = self.frame(&call.frame, i)?;
+                        let value = match &last {
+                            Some((f, v)) if *f == frame => v.clone(),
+                            _ => {
+                                let mut acc = Accumulator::new(&agg);
+                                for input in inputs[frame.0..frame.1].iter().flatten() {
+                                    acc.update(input.clone())?;
+                                }
+                                acc.finish()?
+                            }
+                        };
+                        last = Some((frame, value.clone()));
+                        out.push(value);
+                    }
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Rows `lo..hi` in the frame of row `i`.
+    fn frame(&self, frame: &Frame, i: usize) -> Result<(usize, usize)> {
+        let n = self.rows.len();
+        let rows = |v: &Value| match v {
+            Value::Int(k) => usize::try_from(*k).unwrap_or(usize::MAX),
+            _ => usize::MAX,
+        };
+        let lo = match (&frame.start, frame.units) {
+            (FrameBound::UnboundedPreceding, _) => 0,
+            (FrameBound::CurrentRow, FrameUnits::Rows) => i,
+            (FrameBound::CurrentRow, FrameUnits::Range) => self.peers[i].0,
+            (FrameBound::Preceding(k), FrameUnits::Rows) => i.saturating_sub(rows(k)),
+            (FrameBound::Following(k), FrameUnits::Rows) => i.saturating_add(rows(k)).min(n),
+            (FrameBound::Preceding(k), FrameUnits::Range) => self.range_bound(i, k, true, false)?,
+            (FrameBound::Following(k), FrameUnits::Range) => self.range_bound(i, k, false, false)?,
+            (FrameBound::UnboundedFollowing, _) => n,
+        };
+        let hi = match (&frame.end, frame.units) {
+            (FrameBound::UnboundedPreceding, _) => 0,
+            (FrameBound::CurrentRow, FrameUnits::Rows) => i + 1,
+            (FrameBound::CurrentRow, FrameUnits::Range) => self.peers[i].1,
+            (FrameBound::Preceding(k), FrameUnits::Rows) => (i + 1).saturating_sub(rows(k)),
+            (FrameBound::Following(k), FrameUnits::Rows) => i.saturating_add(rows(k)).saturating_add(1).min(n),
+            (FrameBound::Preceding(k), FrameUnits::Range) => self.range_bound(i, k, true, true)?,
+            (FrameBound::Following(k), FrameUnits::Range) => self.range_bound(i, k, false, true)?,
+            (FrameBound::UnboundedFollowing, _) => n,
+        };
+        Ok((lo, hi.max(lo)))
+    }
+
+    /// First row (or, for an `end` bound, one past the last row) whose ORDER BY value is
+    /// within `offset` before or after row `i`'s. A NULL value has only its peers in range.
+    fn range_bound(&self, i: usize, offset: &Value, preceding: bool, end: bool) -> Result<usize> {
+        let current = &self.keys[i][0];
+        if current.is_null() {
+            return Ok(if end { self.peers[i].1 } else { self.peers[i].0 });
+        }
+        // Earlier rows have smaller values, or larger ones in descending order.
+        let op = if preceding != self.order_by[0].desc {
+            BinaryOp::Minus
+        } else {
+            BinaryOp::Plus
+        };
+        let target = Expr::Binary {
+            op,
+            left: Box::new(Expr::Literal(current.clone())),
+            right: Box::new(Expr::Literal(offset.clone())),
+        }
+        .eval(&[])?;
+        let target = std::slice::from_ref(&target);
+        let key = &self.order_by[..1];
+        Ok(if end {
+            self.keys.partition_point(|k| compare_keys(&k[..1], target, key) != Ordering::Greater)
+        } else {
+            self.keys.partition_point(|k| compare_keys(&k[..1], target, key) == Ordering::Less)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(n: Option<i64>) -> Value {
+        n.map_or(Value::Null, Value::Int)
+    }
+
+    fn call(func: WindowFunc, args: Vec<Expr>, frame: Frame) -> WindowCall {
+        WindowCall {
+            func,
+            args,
+            filter: None,
+            frame,
+        }
+    }
+
+    #[test]
+    fn ranking_offsets_and_frames() {
+        // ORDER BY column 0; column 1 is the value.
+        let rows: Vec<Vec<Value>> = [(1, 10), (2, 20), (2, 30), (4, 40), (7, 50)]
+            .iter()
+            .map(|&(k, v)| vec![Value::Int(k), Value::Int(v)])
+            .collect();
+        let order_by = [SortKey {
+            expr: Expr::Column(0),
+            desc: false,
+            nulls_first: false,
+        }];
+        let p = Partition::new(&rows, &order_by).unwrap();
+        let ints = |v: &[i64]| v.iter().map(|&n| Value::Int(n)).collect::<Vec<_>>();
+        let run = |func, args, frame| p.evaluate(&call(func, args, frame)).unwrap();
+        let value = || vec![Expr::Column(1)];
+
+        assert_eq!(run(WindowFunc::RowNumber, vec![], Frame::default()), ints(&[1, 2, 3, 4, 5]));
+        assert_eq!(run(WindowFunc::Rank, vec![], Frame::default()), ints(&[1, 2, 2, 4, 5]));
+        assert_eq!(run(WindowFunc::DenseRank, vec![], Frame::default()), ints(&[1, 2, 2, 3, 4]));
+        let three = Expr::Literal(Value::Int(3));
+        assert_eq!(run(WindowFunc::Ntile, vec![three], Frame::default()), ints(&[1, 1, 2, 2, 3]));
+        assert_eq!(
+            run(WindowFunc::Lag, value(), Frame::default()),
+            vec![int(None), int(Some(10)), int(Some(20)), int(Some(30)), int(Some(40))]
+        );
+        let lead2 = vec![Expr::Column(1), Expr::Literal(Value::Int(2)), Expr::Literal(Value::Int(0))];
+        assert_eq!(run(WindowFunc::Lead, lead2, Frame::default()), ints(&[30, 40, 50, 0, 0]));
+
+        // The default frame ends at the last peer, so tied rows share a running sum.
+        let sum = WindowFunc::Aggregate(AggFunc::Sum);
+        assert_eq!(run(sum.clone(), value(), Frame::default()), ints(&[10, 60, 60, 100, 150]));
+        let rows_frame = |start, end| Frame {
+            units: FrameUnits::Rows,
+            start,
+            end,
+        };
+        let one = || Value::Int(1);
+        assert_eq!(
+            run(sum.clone(), value(), rows_frame(FrameBound::Preceding(one()), FrameBound::Following(one()))),
+            ints(&[30, 60, 90, 120, 90])
+        );
+        assert_eq!(
+            run(
+                WindowFunc::LastValue,
+                value(),
+                rows_frame(FrameBound::CurrentRow, FrameBound::UnboundedFollowing)
+            ),
+            ints(&[50, 50, 50, 50, 50])
+        );
+        let empty = rows_frame(FrameBound::Following(one()), FrameBound::Following(one()));
+        assert_eq!(run(WindowFunc::FirstValue, value(), empty)[4], Value::Null);
+
+        // RANGE 2 PRECEDING: keys within 2 below the current one.
+        let range = Frame {
+            units: FrameUnits::Range,
+            start: FrameBound::Preceding(Value::Int(2)),
+            end: FrameBound::CurrentRow,
+        };
+        assert_eq!(run(sum, value(), range.clone()), ints(&[10, 60, 60, 90, 50]));
+        let count = WindowFunc::Aggregate(AggFunc::CountStar);
+        assert_eq!(run(count, vec![], range), ints(&[1, 3, 3, 3, 1]));
+    }
+
+    #[test]
+    fn frames_are_validated() {
+        let frame = |units, start, end| Frame { units, start, end };
+        let int = [ColumnType::Int];
+        assert!(Frame::default().validate(&[]).is_ok());
+        assert!(frame(FrameUnits::Rows, FrameBound::UnboundedFollowing, FrameBound::UnboundedFollowing)
+            .validate(&int)
+            .is_err());
+        assert!(frame(FrameUnits::Rows, FrameBound::Following(Value::Int(1)), FrameBound::CurrentRow)
+            .validate(&int)
+            .is_err());
+        assert!(frame(FrameUnits::Rows, FrameBound::Preceding(Value::Int(-1)), FrameBound::CurrentRow)
+            .validate(&int)
+            .is_err());
+        let range = |types: &[ColumnType]| {
+            frame(FrameUnits::Range, FrameBound::Preceding(Value::Float(1.5)), FrameBound::CurrentRow).validate(types)
+        };
+        assert!(range(&int).is_ok());
+        assert!(range(&[ColumnType::Text]).is_err());
+        assert!(range(&[ColumnType::Int, ColumnType::Int]).is_err());
+    }
+}
@@ -0,0 +1,454 @@
+//! Sequences: counters handing out INT values for `nextval` and identity columns.
+//!
+//! A value once handed out is never handed out again, whether or not the transaction that
+//! asked for it commits, so sequences live outside of transactions: `nextval` takes no row
+//! locks and is not undone by a rollback. What makes values durable is a WAL record (see
+//! `WalRecord::Sequence`), written and flushed before any of them is handed out. Writing
+//! one per value would make every insert into a table with an identity column wait for a
+//! flush, so a sequence logs the end of a block of `SequenceDef::cache` values at a time
+//! and hands out the rest of the block from memory. After a crash it resumes past the
+//! logged end: the values of the block nobody got are skipped, never repeated.
+//!
+//! `currval` returns the value `nextval` (or `setval`) last returned in the same session,
+//! so calls are made through a `SessionSequences`, which plans bind when they are built.
+
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::catalog::SequenceDef;
+use crate::query::function::{ParamType, Signature};
+use crate::storage::{ColumnType, Value};
+use crate::txn::TxnManager;
+use crate::wal::WalRecord;
+
+/// Values a sequence allocates at a time unless CREATE SEQUENCE says otherwise.
+pub const DEFAULT_CACHE: i64 = 32;
+
+/// The state of one sequence.
+#[derive(Debug)]
+struct Counter {
+    def: SequenceDef,
+    /// The value handed out last, or, unless `called`, the one handed out next.
+    last: i64,
+    called: bool,
+    /// Values after `last` covered by the last WAL record, which may be handed out without
+    /// logging another.
+    cached: i64,
+}
+
+impl Counter {
+    fn new(def: SequenceDef) -> Self {
+        Self {
+            last: def.start,
+            called: false,
+            cached: 0,
+            def,
+        }
+    }
+
+    /// The value `nextval` returns next.
+    fn peek(&self) -> Result<i64> {
+        if !self.called {
+            return Ok(self.last);
+        }
+        let def = &self.def;
+        let next = self.last as i128 + def.increment as i128;
+        if next > def.max_value as i128 {
+            if !def.cycle {
+                bail!("nextval: reached maximum value of sequence {} ({})", def.name, def.max_value);
+            }
+            return Ok(def.min_value);
+        }
+        if next < def.min_value as i128 {
+            if !def.cycle {
+                bail!("nextval: reached minimum value of sequence {} ({})", def.name, def.min_value);
+            }
+            return Ok(def.max_value);
+        }
+        Ok(next as i64)
+    }
+
+    /// The last value of the block starting at `first`: `cache` values on, or the bound
+    /// the sequence reaches before that.
+    fn block_end(&self, first: i64) -> i64 {
+        let def = &self.def;
+        let end = first as i128 + (def.cache as i128 - 1) * def.increment as i128;
+        end.clamp(def.min_value as i128, def.max_value as i128) as i64
+    }
+}
+
+/// The sequences of a database and where each of them got to.
+pub struct Sequences {
+    counters: Mutex<HashMap<String, Counter>>,
+    /// Logs how far the sequences got.
+    txns: Arc<TxnManager>,
+    /// Per session, the value each sequence last returned to it.
+    sessions: Mutex<HashMap<u64, HashMap<String, i64>>>,
+}
+
+impl Sequences {
+    /// Sequences `defs` as the last of `records` for each of them left it, logging new
+    /// values to `txns`' WAL.
+    pub fn recover(txns: Arc<TxnManager>, defs: impl IntoIterator<Item = SequenceDef>, records: &[WalRecord]) -> Self {
+        let mut logged = HashMap::new();
+        for record in records {
+            match record {
+                WalRecord::Sequence { name, last, called } => {
+                    logged.insert(name.as_str(), (*last, *called));
+                }
+                WalRecord::Checkpoint { sequences, .. } => {
+                    for (name, last, called) in sequences {
+                        logged.insert(name.as_str(), (*last, *called));
+                    }
+                }
+                WalRecord::Commit { .. } => {}
+            }
+        }
+        let counters = defs
+            .into_iter()
+            .map(|def| {
+                let mut counter = Counter::new(def);
+                if let Some((last, called)) = logged.get(counter.def.name.as_str()) {
+                    counter.last = *last;
+                    counter.called = *called;
+                }
+                (counter.def.name.clone(), counter)
+            })
+            .collect();
+        Self {
+            counters: Mutex::new(counters),
+            txns,
+            sessions: Mutex::default(),
+        }
+    }
+
+    fn counters(&self) -> MutexGuard<'_, HashMap<String, Counter>> {
+        self.counters.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn sessions(&self) -> MutexGuard<'_, HashMap<u64, HashMap<String, i64>>> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn log(&self, name: &str, last: i64, called: bool) -> Result<()> {
+        self.txns.log(&WalRecord::Sequence {
+            name: name.to_string(),
+            last,
+            called,
+        })
+    }
+
+    /// Start counting for new sequence `def`, replacing whatever the log says about an
+    /// earlier sequence of that name.
+    pub fn create(&self, def: SequenceDef) -> Result<()> {
+        let mut counters = self.counters();
+        if counters.contains_key(&def.name) {
+            bail!("sequence {} already exists", def.name);
+        }
+        self.log(&def.name, def.start, false)?;
+        counters.insert(def.name.clone(), Counter::new(def));
+        Ok(())
+    }
+
+    /// Forget sequence `name`, which was dropped.
+    pub fn remove(&self, name: &str) {
+        self.counters().remove(name);
+        for last in self.sessions().values_mut() {
+            last.remove(name);
+        }
+    }
+
+    /// Advance sequence `name` and return its new value, for `session`.
+    pub fn next(&self, session: u64, name: &str) -> Result<i64> {
+        let mut counters = self.counters();
+        let Some(counter) = counters.get_mut(name) else {
+            bail!("sequence {} does not exist", name);
+        };
+        let value = counter.peek()?;
+        if counter.cached == 0 {
+            let end = counter.block_end(value);
+            self.log(name, end, true)?;
+            counter.cached = (end - value) / counter.def.increment + 1;
+        }
+        counter.last = value;
+        counter.called = true;
+        counter.cached -= 1;
+        drop(counters);
+        self.sessions().entry(session).or_default().insert(name.to_string(), value);
+        Ok(value)
+    }
+
+    /// The value `next` (or `set`) last returned to `session` for sequence `name`.
+    pub fn current(&self, session: u64, name: &str) -> Result<i64> {
+        if !self.counters().contains_key(name) {
+            bail!("sequence {} does not exist", name);
+        }
+        match self.sessions().get(&session).and_then(|last| last.get(name)) {
+            Some(v) => Ok(*v),
+            None => bail!("currval of sequence {} is not yet defined in this session", name),
+        }
+    }
+
+    /// Make `value` the last value of sequence `name`, or, unless `called`, the next one.
+    pub fn set(&self, session: u64, name: &str, value: i64, called: bool) -> Result<i64> {
+        let mut counters = self.counters();
+        let Some(counter) = counters.get_mut(name) else {
+            bail!("sequence {} does not exist", name);
+        };
+        let def = &counter.def;
+        if value < def.min_value || value > def.max_value {
+            bail!(
+                "setval: value {} is out of bounds for sequence {} ({}..{})",
+                value,
+                name,
+                def.min_value,
+                def.max_value
+            );
+        }
+        self.log(name, value, called)?;
+        counter.last = value;
+        counter.called = called;
+        counter.cached = 0;
+        drop(counters);
+        if called {
+            self.sessions().entry(session).or_default().insert(name.to_string(), value);
+        }
+        Ok(value)
+    }
+
+    /// Forget what the sequences returned to `session`, which has ended.
+    pub fn end_session(&self, session: u64) {
+        self.sessions().remove(&session);
+    }
+}
+
+/// The sequences of a database as one session calls them.
+#[derive(Clone)]
+pub struct SessionSequences {
+    sequences: Arc<Sequences>,
+    session: u64,
+}
+
+impl fmt::Debug for SessionSequences {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SessionSequences(session {})", self.session)
+    }
+}
+
+impl SessionSequences {
+    pub fn new(sequences: Arc<Sequences>, session: u64) -> Self {
+        Self { sequences, session }
+    }
+
+    pub fn func(&self, op: SequenceOp) -> SequenceFunc {
+        SequenceFunc {
+            op,
+            sequences: self.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SequenceOp {
+    /// `nextval(name)`.
+    Next,
+    /// `currval(name)`.
+    Current,
+    /// `setval(name, value [, is_called])`.
+    Set,
+}
+
+impl SequenceOp {
+    /// The sequence function called `name` (lower case), if it is one.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nextval" => Some(SequenceOp::Next),
+            "currval" => Some(SequenceOp::Current),
+            "setval" => Some(SequenceOp::Set),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SequenceOp::Next => "nextval",
+            SequenceOp::Current => "currval",
+            SequenceOp::Set => "setval",
+        }
+    }
+}
+
+/// A call of a sequence function by one session. Calls are equal when they are of the same
+/// function by the same session of the same database.
+#[derive(Clone)]
+pub struct SequenceFunc {
+    op: SequenceOp,
+    sequences: SessionSequences,
+}
+
+impl SequenceFunc {
+    pub fn op(&self) -> SequenceOp {
+        self.op
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.op.name()
+    }
+
+    pub fn signatures(&self) -> &'static [Signature] {
+        use ColumnType::*;
+        use ParamType::*;
+        match self.op {
+            SequenceOp::Next | SequenceOp::Current => &[Signature {
+                params: &[Is(Text)],
+                variadic: false,
+                result: Is(Int),
+            }],
+            SequenceOp::Set => &[
+                Signature {
+                    params: &[Is(Text), Is(Int)],
+                    variadic: false,
+                    result: Is(Int),
+                },
+                Signature {
+                    params: &[Is(Text), Is(Int), Is(Bool)],
+                    variadic: false,
+                    result: Is(Int),
+                },
+            ],
+        }
+    }
+
+    /// The result for `args`, none of them NULL, which match one of the signatures.
+    pub fn call(&self, args: &[Value]) -> Result<Value> {
+        let Some(Value::Text(name)) = args.first() else {
+            bail!("{}: expected a sequence name", self.name());
+        };
+        let name = sequence_name(name);
+        let SessionSequences { sequences, session } = &self.sequences;
+        Ok(Value::Int(match (self.op, &args[1..]) {
+            (SequenceOp::Next, _) => sequences.next(*session, &name)?,
+            (SequenceOp::Current, _) => sequences.current(*session, &name)?,
+            (SequenceOp::Set, [Value::Int(v)]) => sequences.set(*session, &name, *v, true)?,
+            (SequenceOp::Set, [Value::Int(v), Value::Bool(called)]) => sequences.set(*session, &name, *v, *called)?,
+            (SequenceOp::Set, other) => bail!("setval: unexpected arguments {:?}", other),
+        }))
+    }
+}
+
+impl PartialEq for SequenceFunc {
+    fn eq(&self, other: &Self) -> bool {
+        self.op == other.op
+            && self.sequences.session == other.sequences.session
+            && Arc::ptr_eq(&self.sequences.sequences, &other.sequences.sequences)
+    }
+}
+
+impl Eq for SequenceFunc {}
+
+impl Hash for SequenceFunc {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.op.hash(state);
+        self.sequences.session.hash(state);
+        Arc::as_ptr(&self.sequences.sequences).hash(state)
+    }
+}
+
+impl fmt::Debug for SequenceFunc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SequenceFunc({}, session {})", self.name(), self.sequences.session)
+    }
+}
+
+/// The sequence a name passed to a sequence function refers to: folded to lower case
+/// unless double-quoted, as identifiers are.
+pub fn sequence_name(s: &str) -> String {
+    match s.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        Some(quoted) => quoted.to_string(),
+        None => s.to_ascii_lowercase(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::wal::Wal;
+
+    fn def(name: &str, increment: i64, min_value: i64, max_value: i64, cache: i64, cycle: bool) -> SequenceDef {
+        SequenceDef {
+            name: name.into(),
+            start: if increment > 0 { min_value } else { max_value },
+            increment,
+            min_value,
+            max_value,
+            cache,
+            cycle,
+            owned_by: None,
+        }
+    }
+
+    fn open(dir: &std::path::Path, defs: &[SequenceDef]) -> (Sequences, usize) {
+        let (wal, records) = Wal::open(dir.join("test.wal"), false).unwrap();
+        let n = records.len();
+        let txns = Arc::new(TxnManager::recover(&Config::default(), wal, &records, 1));
+        (Sequences::recover(txns, defs.to_vec(), &records), n)
+    }
+
+    #[test]
+    fn values_are_logged_a_block_at_a_time_and_skipped_after_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let s = def("s", 1, 1, i64::MAX, 4, false);
+        let (seqs, _) = open(dir.path(), &[]);
+        seqs.create(s.clone()).unwrap();
+        assert!(seqs.create(s.clone()).is_err());
+        assert!(seqs.current(1, "s").unwrap_err().to_string().contains("not yet defined"));
+        let values: Vec<i64> = (0..5).map(|_| seqs.next(1, "s").unwrap()).collect();
+        assert_eq!(values, [1, 2, 3, 4, 5]);
+        assert_eq!(seqs.current(1, "s").unwrap(), 5);
+        assert!(seqs.current(2, "s").is_err());
+        drop(seqs);
+        // The create record, then one per block of 4.
+        let (seqs, records) = open(dir.path(), std::slice::from_ref(&s));
+        assert_eq!(records, 3);
+        assert_eq!(seqs.next(1, "s").unwrap(), 9);
+        assert_eq!(seqs.set(1, "s", 100, false).unwrap(), 100);
+        assert_eq!(seqs.current(1, "s").unwrap(), 9);
+        drop(seqs);
+        let (seqs, _) = open(dir.path(), std::slice::from_ref(&s));
+        assert_eq!(seqs.next(1, "s").unwrap(), 100);
+        // A checkpoint keeps only the last position.
+        seqs.txns.checkpoint().unwrap();
+        drop(seqs);
+        let (seqs, records) = open(dir.path(), &[s]);
+        assert_eq!(records, 1);
+        assert_eq!(seqs.next(1, "s").unwrap(), 104);
+        assert!(seqs.set(1, "s", 0, true).is_err());
+        assert!(seqs.next(1, "nope").is_err());
+    }
+
+    #[test]
+    fn bounds_cycle_or_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        let (seqs, _) = open(dir.path(), &[]);
+        seqs.create(def("down", -2, -5, -1, 32, true)).unwrap();
+        let values: Vec<i64> = (0..4).map(|_| seqs.next(1, "down").unwrap()).collect();
+        assert_eq!(values, [-1, -3, -5, -1]);
+        seqs.create(def("up", 1, 1, 2, 32, false)).unwrap();
+        assert_eq!(seqs.next(1, "up").unwrap(), 1);
+        assert_eq!(seqs.next(1, "up").unwrap(), 2);
+        let err = seqs.next(1, "up").unwrap_err();
+        assert_eq!(err.to_string(), "nextval: reached maximum value of sequence up (2)");
+        seqs.create(def("max", 1, i64::MAX - 1, i64::MAX, 32, false)).unwrap();
+        assert_eq!(seqs.next(1, "max").unwrap(), i64::MAX - 1);
+        assert_eq!(seqs.next(1, "max").unwrap(), i64::MAX);
+        assert!(seqs.next(1, "max").is_err());
+        seqs.remove("up");
+        assert!(seqs.current(1, "up").is_err());
+        assert_eq!(sequence_name("Orders_Seq"), "orders_seq");
+        assert_eq!(sequence_name("\"Orders\""), "Orders");
+    }
+}
@@ -1 +1,155 @@
 //! TCP server: accept connections, dispatch QUERY / META.
+//!
+//! Each connection gets its own `Session` and speaks the `protocol` messages: the server
+//! reads a request, answers it with `protocol::dispatch` and waits for the next, until the
+//! client closes the connection or sends something unreadable. Sessions block on I/O and
+//! lock waits, so a connection is served under `spawn_blocking`; the accept loop alone runs
+//! on the runtime. Past `max_connections` open connections, a new one is told so and closed.
+
+use anyhow::Result;
+use std::io::{BufReader, BufWriter};
+use std::net::TcpStream;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+
+use crate::db::Database;
+use crate::protocol::{self, Request, Response};
+
+/// Bind `config.listen_addr` and serve `db` until the task is dropped or accepting fails.
+pub async fn run(db: Arc<Database>) -> Result<()> {
+    let listener = TcpListener::bind(&db.config().listen_addr).await?;
+    tracing::info!(addr = %listener.local_addr()?, "listening");
+    serve(db, listener).await
+}
+
+/// Serve `db` to the connections `listener` accepts.
+pub async fn serve(db: Arc<Database>, listener: TcpListener) -> Result<()> {
+    let slots = Arc::new(Semaphore::new(db.config().max_connections));
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+        let Ok(slot) = Arc::clone(&slots).try_acquire_owned() else {
+            tracing::warn!(%peer, "too many connections");
+            tokio::task::spawn_blocking(move || refuse(stream));
+            continue;
+        };
+        let db = Arc::clone(&db);
+        tokio::task::spawn_blocking(move || {
+            tracing::debug!(%peer, "connection opened");
+            if let Err(e) = connection(&db, stream) {
+                tracing::warn!(%peer, error = %e, "connection failed");
+            }
+            tracing::debug!(%peer, "connection closed");
+            drop(slot);
+        });
+    }
+}
+
+/// Answer the requests of one client on a session of its own.
+fn connection(db: &Arc<Database>, stream: TcpStream) -> Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut session = db.session();
+    loop {
+        let request = match protocol::read_message::<Request>(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                let message = format!("invalid request: {:#}", e);
+                return protocol::write_message(&mut writer, &Response::Error { message });
+            }
+        };
+        let response = protocol::dispatch(&mut session, request);
+        protocol::write_message(&mut writer, &response)?;
+    }
+}
+
+fn refuse(stream: TcpStream) {
+    let message = "too many connections".to_string();
+    let _ = protocol::write_message(&mut BufWriter::new(stream), &Response::Error { message });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::QueryResult;
+    use crate::storage::Value;
+    use crate::Config;
+
+    fn request(stream: &mut TcpStream, request: &Request) -> Response {
+        protocol::write_message(stream, request).unwrap();
+        protocol::read_message(stream).unwrap().expect("a response")
+    }
+
+    fn rows(response: Response) -> Vec<Vec<Value>> {
+        match response {
+            Response::Result(QueryResult { rows, .. }) => rows,
+            r => panic!("not a result: {:?}", r),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn serves_sessions_over_tcp() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::open(Config {
+            data_dir: dir.path().to_string_lossy().into_owned(),
+            wal_sync: false,
+            max_connections: 2,
+            ..Config::default()
+        })
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(db, listener));
+
+        tokio::task::spawn_blocking(move || {
+            let mut a = TcpStream::connect(addr).unwrap();
+            let mut b = TcpStream::connect(addr).unwrap();
+            let create = Request::Query {
+                sql: "CREATE TABLE t (id INT PRIMARY KEY); INSERT INTO t VALUES (1), (2)".into(),
+            };
+            assert!(matches!(request(&mut a, &create), Response::Result(_)));
+
+            // Each connection has its own session: b does not see a's open transaction.
+            request(&mut a, &Request::Query { sql: "BEGIN".into() });
+            request(&mut a, &Request::Query { sql: "DELETE FROM t WHERE id = 1".into() });
+            let count = Request::Query { sql: "SELECT count(*) FROM t".into() };
+            assert_eq!(rows(request(&mut b, &count)), vec![vec![Value::Int(2)]]);
+            assert_eq!(rows(request(&mut a, &count)), vec![vec![Value::Int(1)]]);
+
+            // A statement waiting for a lock holds up only its own connection.
+            let update = Request::Query { sql: "UPDATE t SET id = 3 WHERE id = 1".into() };
+            protocol::write_message(&mut b, &update).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            assert_eq!(rows(request(&mut a, &count)), vec![vec![Value::Int(1)]]);
+            b.set_read_timeout(Some(std::time::Duration::from_millis(30))).unwrap();
+            assert!(protocol::read_message::<Response>(&mut b).is_err(), "not blocked");
+            b.set_read_timeout(None).unwrap();
+            request(&mut a, &Request::Query { sql: "COMMIT".into() });
+            let response = protocol::read_message::<Response>(&mut b).unwrap();
+            let serialization_failure = |m: &str| m.contains("could not serialize");
+            assert!(matches!(&response, Some(Response::Error { message }) if serialization_failure(message)));
+            let Response::CancelKey(key) = request(&mut b, &Request::CancelKey) else {
+                panic!("no cancel key");
+            };
+            assert_eq!(request(&mut a, &Request::Cancel { key }), Response::CancelComplete { cancelled: true });
+            assert_eq!(rows(request(&mut b, &count)), vec![vec![Value::Int(1)]]);
+
+            let mut c = TcpStream::connect(addr).unwrap();
+            let Some(Response::Error { message }) = protocol::read_message::<Response>(&mut c).unwrap() else {
+                panic!("third connection accepted");
+            };
+            assert_eq!(message, "too many connections");
+            drop(a);
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            let mut c = TcpStream::connect(addr).unwrap();
+            assert_eq!(rows(request(&mut c, &count)), vec![vec![Value::Int(1)]]);
+        })
+        .await
+        .unwrap();
+        server.abort();
+    }
+}
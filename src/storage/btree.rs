@@ -1,12 +1,13 @@
 //! B-tree index for primary key. Keys are i64; values point to heap (page_id, slot).
 
 use anyhow::Result;
+use serde::Serialize;
 
 use super::heap::{HeapFile, PageId};
 use super::page::{Page, PageFlags, PAGE_SIZE};
 
 /// Pointer to a row in the heap: page id + slot index.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
 pub struct RowRef {
     pub page_id: PageId,
     pub slot: u16,
@@ -22,14 +23,14 @@ impl RowRef {
 // Leaf: next_leaf_page_id (4) | num_entries (2) | [key:8][page_id:4][slot:2]*
 // Internal: num_keys (2) | [child0:4][key1:8][child1:4]...[child_n:4]
 const BTREE_BODY_START: usize = 32;
-const LEAF_ENTRY_SIZE: usize = 8 + 4 + 2; // key + page_id + slot
-const INTERNAL_KEY_SIZE: usize = 4 + 8;   // child + key (last child stored separately)
+pub(super) const LEAF_ENTRY_SIZE: usize = 8 + 4 + 2; // key + page_id + slot
+pub(super) const INTERNAL_KEY_SIZE: usize = 4 + 8;   // child + key (last child stored separately)
 
-fn leaf_max_entries() -> usize {
+pub(super) fn leaf_max_entries() -> usize {
     (PAGE_SIZE - BTREE_BODY_START - 4 - 2) / LEAF_ENTRY_SIZE // -4 next, -2 num
 }
 
-fn internal_max_keys() -> usize {
+pub(super) fn internal_max_keys() -> usize {
     (PAGE_SIZE - BTREE_BODY_START - 2 - 4) / INTERNAL_KEY_SIZE // -2 num, -4 first child
 }
 
@@ -40,7 +41,7 @@ pub struct BTree {
 
 impl BTree {
     /// Create new B-tree with empty root leaf. Overwrites index file.
-    pub fn create<P: std::path::AsRef<std::path::Path>>(path: P) -> Result<Self> {
+    pub fn create<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
         let mut index_heap = HeapFile::create(path)?;
         let root = Self::alloc_empty_leaf(&mut index_heap)?;
         assert_eq!(root, 0);
@@ -48,11 +49,16 @@ impl BTree {
     }
 
     /// Open existing B-tree. Root must be page 0.
-    pub fn open<P: std::path::AsRef<std::path::Path>>(path: P) -> Result<Self> {
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
         let index_heap = HeapFile::open(path)?;
         Ok(Self { index_heap })
     }
 
+    /// Cache the index pages in `pool` (see `HeapFile::attach_buffer_pool`).
+    pub fn attach_buffer_pool(&mut self, pool: &std::sync::Arc<crate::buffer::BufferPool>) {
+        self.index_heap.attach_buffer_pool(pool);
+    }
+
     fn alloc_empty_leaf(heap: &mut HeapFile) -> Result<PageId> {
         let mut page = Page::new(0, PageFlags::Leaf);
         Self::leaf_set_next(&mut page, 0);
@@ -64,7 +70,7 @@ impl BTree {
         let off = BTREE_BODY_START;
         page.as_bytes_mut()[off..off + 4].copy_from_slice(&next.to_le_bytes());
     }
-    fn leaf_next(page: &Page) -> PageId {
+    pub(super) fn leaf_next(page: &Page) -> PageId {
         let off = BTREE_BODY_START;
         u32::from_le_bytes(page.as_bytes()[off..off + 4].try_into().unwrap())
     }
@@ -72,18 +78,18 @@ impl BTree {
         let off = BTREE_BODY_START + 4;
         page.as_bytes_mut()[off..off + 2].copy_from_slice(&n.to_le_bytes());
     }
-    fn leaf_num_entries(page: &Page) -> u16 {
+    pub(super) fn leaf_num_entries(page: &Page) -> u16 {
         let off = BTREE_BODY_START + 4;
         u16::from_le_bytes(page.as_bytes()[off..off + 2].try_into().unwrap())
     }
-    fn leaf_entry_offset(idx: usize) -> usize {
+    pub(super) fn leaf_entry_offset(idx: usize) -> usize {
         BTREE_BODY_START + 6 + idx * LEAF_ENTRY_SIZE
     }
-    fn leaf_get_key(page: &Page, idx: usize) -> i64 {
+    pub(super) fn leaf_get_key(page: &Page, idx: usize) -> i64 {
         let off = Self::leaf_entry_offset(idx);
         i64::from_le_bytes(page.as_bytes()[off..off + 8].try_into().unwrap())
     }
-    fn leaf_get_ref(page: &Page, idx: usize) -> RowRef {
+    pub(super) fn leaf_get_ref(page: &Page, idx: usize) -> RowRef {
         let off = Self::leaf_entry_offset(idx) + 8;
         let page_id = u32::from_le_bytes(page.as_bytes()[off..off + 4].try_into().unwrap());
         let slot = u16::from_le_bytes(page.as_bytes()[off + 4..off + 6].try_into().unwrap());
@@ -113,18 +119,18 @@ impl BTree {
         let off = BTREE_BODY_START;
         page.as_bytes_mut()[off..off + 2].copy_from_slice(&n.to_le_bytes());
     }
-    fn internal_num_keys(page: &Page) -> u16 {
+    pub(super) fn internal_num_keys(page: &Page) -> u16 {
         let off = BTREE_BODY_START;
         u16::from_le_bytes(page.as_bytes()[off..off + 2].try_into().unwrap())
     }
-    fn internal_child_offset(idx: usize) -> usize {
+    pub(super) fn internal_child_offset(idx: usize) -> usize {
         BTREE_BODY_START + 2 + idx * (4 + 8) // first child at 0, then (child, key) pairs
     }
-    fn internal_get_child(page: &Page, idx: usize) -> PageId {
+    pub(super) fn internal_get_child(page: &Page, idx: usize) -> PageId {
         let off = Self::internal_child_offset(idx);
         u32::from_le_bytes(page.as_bytes()[off..off + 4].try_into().unwrap())
     }
-    fn internal_get_key(page: &Page, idx: usize) -> i64 {
+    pub(super) fn internal_get_key(page: &Page, idx: usize) -> i64 {
         let off = Self::internal_child_offset(idx) + 4;
         i64::from_le_bytes(page.as_bytes()[off..off + 8].try_into().unwrap())
     }
@@ -172,7 +178,58 @@ impl BTree {
         }
     }
 
-    fn flags(page: &Page) -> u16 {
+    /// Descend from the root to the leaf that would hold `key`.
+    fn find_leaf(&mut self, key: i64) -> Result<(PageId, Page)> {
+        let mut page_id = 0;
+        loop {
+            let page = self.index_heap.read_page(page_id)?;
+            if Self::flags(&page) == PageFlags::Leaf as u16 {
+                return Ok((page_id, page));
+            }
+            let n = Self::internal_num_keys(&page) as usize;
+            let mut child_idx = 0;
+            for i in 0..n {
+                if key < Self::internal_get_key(&page, i) {
+                    break;
+                }
+                child_idx = i + 1;
+            }
+            page_id = Self::internal_get_child(&page, child_idx);
+        }
+    }
+
+    /// Point an existing key at a new RowRef. Returns false if the key is absent.
+    pub fn update(&mut self, key: i64, value: RowRef) -> Result<bool> {
+        let (leaf_id, mut page) = self.find_leaf(key)?;
+        let n = Self::leaf_num_entries(&page) as usize;
+        for i in 0..n {
+            if Self::leaf_get_key(&page, i) == key {
+                Self::leaf_set_entry(&mut page, i, key, value);
+                self.index_heap.write_page(leaf_id, &page)?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Remove `key`. Returns false if it was absent. Leaves are not merged; an emptied leaf
+    /// stays in the chain and is refilled by later inserts into its key range.
+    pub fn delete(&mut self, key: i64) -> Result<bool> {
+        let (leaf_id, mut page) = self.find_leaf(key)?;
+        let n = Self::leaf_num_entries(&page) as usize;
+        let Some(idx) = (0..n).find(|&i| Self::leaf_get_key(&page, i) == key) else {
+            return Ok(false);
+        };
+        for i in idx..n - 1 {
+            let (k, r) = (Self::leaf_get_key(&page, i + 1), Self::leaf_get_ref(&page, i + 1));
+            Self::leaf_set_entry(&mut page, i, k, r);
+        }
+        Self::leaf_set_num_entries(&mut page, (n - 1) as u16);
+        self.index_heap.write_page(leaf_id, &page)?;
+        Ok(true)
+    }
+
+    pub(super) fn flags(page: &Page) -> u16 {
         u16::from_le_bytes(page.as_bytes()[8..10].try_into().unwrap())
     }
 
@@ -210,8 +267,7 @@ impl BTree {
             }
             Self::leaf_insert_at(&mut page, idx, key, value);
             self.index_heap.write_page(page_id, &page)?;
-            let max = leaf_max_entries();
-            if n + 1 > max {
+            if n + 1 >= leaf_max_entries() {
                 return Ok(Some(self.split_leaf(page_id, &mut page)?));
             }
             Ok(None)
@@ -229,8 +285,7 @@ impl BTree {
             if let Some((split_key, split_page_id)) = split {
                 self.insert_internal_child(&mut page, child_idx, split_key, split_page_id);
                 let n = Self::internal_num_keys(&page) as usize;
-                let max_internal = internal_max_keys();
-                if n > max_internal {
+                if n >= internal_max_keys() {
                     return Ok(Some(self.split_internal(page_id, &mut page)?));
                 }
                 self.index_heap.write_page(page_id, &page)?;
@@ -248,13 +303,21 @@ impl BTree {
     ) {
         let n = Self::internal_num_keys(page) as usize;
         let last_child = Self::internal_get_child(page, n);
-        for i in (after_child_idx + 1..n).rev() {
-            let c = Self::internal_get_child(page, i);
-            let k = Self::internal_get_key(page, i);
-            Self::internal_set_child_key(page, i + 1, c, k);
+        if after_child_idx == n {
+            Self::internal_set_child_key(page, n, last_child, key);
+            Self::internal_set_last_child(page, n + 1, right_page_id);
+        } else {
+            let displaced_key = Self::internal_get_key(page, after_child_idx);
+            for i in (after_child_idx + 1..n).rev() {
+                let c = Self::internal_get_child(page, i);
+                let k = Self::internal_get_key(page, i);
+                Self::internal_set_child_key(page, i + 1, c, k);
+            }
+            let left_child = Self::internal_get_child(page, after_child_idx);
+            Self::internal_set_child_key(page, after_child_idx, left_child, key);
+            Self::internal_set_child_key(page, after_child_idx + 1, right_page_id, displaced_key);
+            Self::internal_set_last_child(page, n + 1, last_child);
         }
-        Self::internal_set_child_key(page, after_child_idx + 1, right_page_id, key);
-        Self::internal_set_last_child(page, n + 1, last_child);
         Self::internal_set_num_keys(page, (n + 1) as u16);
     }
 
@@ -284,14 +347,16 @@ impl BTree {
         let n = Self::internal_num_keys(page) as usize;
         let mid = n / 2;
         let promote_key = Self::internal_get_key(page, mid);
+        // Left keeps child0..=child_mid and keys before mid; right takes everything after mid.
+        let right_keys = n - mid - 1;
         let mut new_page = Page::new(0, PageFlags::Internal);
-        Self::internal_set_num_keys(&mut new_page, (n - mid - 1) as u16);
-        Self::internal_set_last_child(&mut new_page, 0, Self::internal_get_child(page, mid + 1));
-        for i in 0..(n - mid - 1) {
-            let c = Self::internal_get_child(page, mid + 2 + i);
+        Self::internal_set_num_keys(&mut new_page, right_keys as u16);
+        for i in 0..right_keys {
+            let c = Self::internal_get_child(page, mid + 1 + i);
             let k = Self::internal_get_key(page, mid + 1 + i);
-            Self::internal_set_child_key(&mut new_page, i + 1, c, k);
+            Self::internal_set_child_key(&mut new_page, i, c, k);
         }
+        Self::internal_set_last_child(&mut new_page, right_keys, Self::internal_get_child(page, n));
         let right_id = self.index_heap.append_page(&new_page)?;
         Self::internal_set_num_keys(page, mid as u16);
         self.index_heap.write_page(page_id, page)?;
@@ -303,8 +368,8 @@ impl BTree {
         let left_id = self.index_heap.append_page(&left_page)?;
         let mut new_root = Page::new(0, PageFlags::Internal);
         Self::internal_set_num_keys(&mut new_root, 1);
-        Self::internal_set_last_child(&mut new_root, 0, left_id);
-        Self::internal_set_child_key(&mut new_root, 1, right_page_id, promote_key);
+        Self::internal_set_child_key(&mut new_root, 0, left_id, promote_key);
+        Self::internal_set_last_child(&mut new_root, 1, right_page_id);
         self.index_heap.write_page(0, &new_root)?;
         Ok(())
     }
@@ -323,45 +388,59 @@ impl BTree {
         end: i64,
         out: &mut Vec<(i64, RowRef)>,
     ) -> Result<()> {
-        let page = self.index_heap.read_page(page_id)?;
+        let mut page = self.index_heap.read_page(page_id)?;
         let flags = Self::flags(&page);
         if flags == PageFlags::Leaf as u16 {
-            let n = Self::leaf_num_entries(&page);
-            for i in 0..n as usize {
-                let k = Self::leaf_get_key(&page, i);
-                if k >= end {
-                    return Ok(());
+            loop {
+                let n = Self::leaf_num_entries(&page);
+                for i in 0..n as usize {
+                    let k = Self::leaf_get_key(&page, i);
+                    if k >= end {
+                        return Ok(());
+                    }
+                    if k >= start {
+                        out.push((k, Self::leaf_get_ref(&page, i)));
+                    }
                 }
-                if k >= start {
-                    out.push((k, Self::leaf_get_ref(&page, i)));
+                let next = Self::leaf_next(&page);
+                if next == 0 {
+                    return Ok(());
                 }
+                page = self.index_heap.read_page(next)?;
             }
-            let next = Self::leaf_next(&page);
-            if next != 0 {
-                self.range_scan_from(next, start, end, out)?;
-            }
-            Ok(())
         } else {
+            // Descend only towards `start`; the leaf chain carries the scan to `end`.
             let n = Self::internal_num_keys(&page);
-            let mut i = 0usize;
-            while i < n as usize {
-                let k = Self::internal_get_key(&page, i);
-                if end <= k {
-                    let child = Self::internal_get_child(&page, i);
-                    self.range_scan_from(child, start, end, out)?;
-                    return Ok(());
-                }
-                if start <= k {
-                    let child = Self::internal_get_child(&page, i);
-                    self.range_scan_from(child, start, end, out)?;
+            let mut child_idx = 0;
+            for i in 0..n as usize {
+                if start < Self::internal_get_key(&page, i) {
+                    break;
                 }
-                i += 1;
+                child_idx = i + 1;
             }
-            let child = Self::internal_get_child(&page, n as usize);
+            let child = Self::internal_get_child(&page, child_idx);
             self.range_scan_from(child, start, end, out)
         }
     }
 
+    /// Largest key in the tree, if any.
+    pub fn max_key(&mut self) -> Result<Option<i64>> {
+        let mut page = self.index_heap.read_page(0)?;
+        while Self::flags(&page) != PageFlags::Leaf as u16 {
+            let n = Self::internal_num_keys(&page) as usize;
+            page = self.index_heap.read_page(Self::internal_get_child(&page, n))?;
+        }
+        match Self::leaf_num_entries(&page) {
+            0 => {
+                // Deletes do not merge leaves, so the rightmost one may have emptied out.
+                let all = self.range_scan(i64::MIN, i64::MAX)?;
+                let max = all.last().map(|(k, _)| *k);
+                Ok(if self.get(i64::MAX)?.is_some() { Some(i64::MAX) } else { max })
+            }
+            n => Ok(Some(Self::leaf_get_key(&page, n as usize - 1))),
+        }
+    }
+
     pub fn num_pages(&self) -> PageId {
         self.index_heap.num_pages()
     }
@@ -389,8 +468,8 @@ mod tests {
     fn btree_range_scan() {
         let tmp = NamedTempFile::new().unwrap();
         let mut bt = BTree::create(tmp.path()).unwrap();
-        for i in 0..10 {
-            bt.insert(i * 10, RowRef::new(i, 0)).unwrap();
+        for i in 0..10u32 {
+            bt.insert(i as i64 * 10, RowRef::new(i, 0)).unwrap();
         }
         let r = bt.range_scan(25, 55).unwrap();
         assert_eq!(r.len(), 3);
@@ -403,7 +482,7 @@ mod tests {
     fn btree_split_under_load() {
         let tmp = NamedTempFile::new().unwrap();
         let mut bt = BTree::create(tmp.path()).unwrap();
-        let n = 500;
+        let n = 2000;
         for i in 0..n {
             bt.insert(i as i64, RowRef::new((i % 100) as u32, (i % 10) as u16)).unwrap();
         }
@@ -415,6 +494,44 @@ mod tests {
         }
     }
 
+    #[test]
+    fn btree_update_retargets() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut bt = BTree::create(tmp.path()).unwrap();
+        for i in 0..1000 {
+            bt.insert(i, RowRef::new(0, 0)).unwrap();
+        }
+        assert!(bt.update(777, RowRef::new(9, 3)).unwrap());
+        assert!(!bt.update(5000, RowRef::new(9, 3)).unwrap());
+        assert_eq!(bt.get(777).unwrap(), Some(RowRef::new(9, 3)));
+        assert_eq!(bt.get(776).unwrap(), Some(RowRef::new(0, 0)));
+    }
+
+    #[test]
+    fn btree_delete_keeps_neighbours() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut bt = BTree::create(tmp.path()).unwrap();
+        for i in 0..2000 {
+            bt.insert(i, RowRef::new(i as u32, 0)).unwrap();
+        }
+        for i in (0..2000).step_by(2) {
+            assert!(bt.delete(i).unwrap());
+        }
+        assert!(!bt.delete(0).unwrap());
+        assert_eq!(bt.get(0).unwrap(), None);
+        assert_eq!(bt.get(1001).unwrap(), Some(RowRef::new(1001, 0)));
+        let r = bt.range_scan(0, 2000).unwrap();
+        assert_eq!(r.len(), 1000);
+        assert!(r.iter().all(|(k, _)| k % 2 == 1));
+        bt.insert(10, RowRef::new(5, 5)).unwrap();
+        assert_eq!(bt.get(10).unwrap(), Some(RowRef::new(5, 5)));
+        assert_eq!(bt.max_key().unwrap(), Some(1999));
+        for i in (1000..2000).rev() {
+            bt.delete(i).unwrap();
+        }
+        assert_eq!(bt.max_key().unwrap(), Some(999));
+    }
+
     #[test]
     fn btree_reopen_persists() {
         let tmp = NamedTempFile::new().unwrap();
@@ -426,4 +543,87 @@ mod tests {
         let mut bt = BTree::open(path).unwrap();
         assert_eq!(bt.get(42).unwrap(), Some(RowRef::new(7, 3)));
     }
+
+    #[test]
+    fn btree_range_scan_crosses_leaves_once() {
+        // Descending into every overlapping child and then following the leaf chain from each
+        // used to return keys more than once.
+        let tmp = NamedTempFile::new().unwrap();
+        let mut bt = BTree::create(tmp.path()).unwrap();
+        for i in 0..2000u32 {
+            bt.insert(i as i64, RowRef::new(i, 0)).unwrap();
+        }
+        let all = bt.range_scan(0, 2000).unwrap();
+        assert_eq!(all.iter().map(|(k, _)| *k).collect::<Vec<_>>(), (0..2000).collect::<Vec<_>>());
+        let mid = bt.range_scan(500, 1500).unwrap();
+        assert_eq!(mid.len(), 1000);
+        assert_eq!((mid[0].0, mid[999].0), (500, 1499));
+        assert!(bt.range_scan(2000, 3000).unwrap().is_empty());
+    }
+
+    #[test]
+    fn btree_out_of_order_inserts_keep_every_key() {
+        // Out-of-order keys insert separators into the middle of the root, not just at its end.
+        let tmp = NamedTempFile::new().unwrap();
+        let mut bt = BTree::create(tmp.path()).unwrap();
+        let n = 20_000i64;
+        for i in 0..n {
+            let key = i * 7919 % n;
+            bt.insert(key, RowRef::new(key as u32, 0)).unwrap();
+        }
+        for key in 0..n {
+            assert_eq!(bt.get(key).unwrap(), Some(RowRef::new(key as u32, 0)), "key {}", key);
+        }
+        let all = bt.range_scan(i64::MIN, i64::MAX).unwrap();
+        assert_eq!(all.len(), n as usize);
+        assert!(all.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    fn internal_page(children: &[PageId], keys: &[i64]) -> Page {
+        let mut page = Page::new(0, PageFlags::Internal);
+        BTree::internal_set_num_keys(&mut page, keys.len() as u16);
+        for (i, key) in keys.iter().enumerate() {
+            BTree::internal_set_child_key(&mut page, i, children[i], *key);
+        }
+        BTree::internal_set_last_child(&mut page, keys.len(), children[keys.len()]);
+        page
+    }
+
+    fn internal_entries(page: &Page) -> (Vec<PageId>, Vec<i64>) {
+        let n = BTree::internal_num_keys(page) as usize;
+        let children = (0..=n).map(|i| BTree::internal_get_child(page, i)).collect();
+        let keys = (0..n).map(|i| BTree::internal_get_key(page, i)).collect();
+        (children, keys)
+    }
+
+    #[test]
+    fn internal_separator_lands_right_of_split_child() {
+        // Child 1 (keys 10..20) split at 15 into page 9: the separator must sit between them.
+        let mut page = internal_page(&[1, 2, 3], &[10, 20]);
+        let tmp = NamedTempFile::new().unwrap();
+        let mut bt = BTree::create(tmp.path()).unwrap();
+        bt.insert_internal_child(&mut page, 1, 15, 9);
+        assert_eq!(internal_entries(&page), (vec![1, 2, 9, 3], vec![10, 15, 20]));
+        bt.insert_internal_child(&mut page, 3, 25, 7);
+        assert_eq!(internal_entries(&page), (vec![1, 2, 9, 3, 7], vec![10, 15, 20, 25]));
+    }
+
+    #[test]
+    fn internal_split_promotes_middle_key() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut bt = BTree::create(tmp.path()).unwrap();
+        let mut page = internal_page(&[10, 11, 12, 13, 14], &[100, 200, 300, 400]);
+        let (promoted, right_id) = bt.split_internal(0, &mut page).unwrap();
+        assert_eq!(promoted, 300);
+        assert_eq!(internal_entries(&page), (vec![10, 11, 12], vec![100, 200]));
+        let right = bt.index_heap.read_page(right_id).unwrap();
+        assert_eq!(internal_entries(&right), (vec![13, 14], vec![400]));
+
+        bt.split_root(promoted, right_id).unwrap();
+        let root = bt.index_heap.read_page(0).unwrap();
+        let (children, keys) = internal_entries(&root);
+        assert_eq!((children[1], keys), (right_id, vec![300]));
+        let left = bt.index_heap.read_page(children[0]).unwrap();
+        assert_eq!(internal_entries(&left), (vec![10, 11, 12], vec![100, 200]));
+    }
 }
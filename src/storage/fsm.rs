@@ -0,0 +1,66 @@
+//! Free space map: how many bytes each heap page can still take, so inserts reuse space
+//! vacuum reclaimed instead of always growing the file. Kept in memory and rebuilt from the
+//! pages when a table is opened.
+
+use super::heap::PageId;
+
+#[derive(Debug, Default, Clone)]
+pub struct FreeSpaceMap {
+    free: Vec<u16>,
+}
+
+impl FreeSpaceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the free space of `page_id`, growing the map if needed.
+    pub fn set(&mut self, page_id: PageId, free: usize) {
+        let i = page_id as usize;
+        if i >= self.free.len() {
+            self.free.resize(i + 1, 0);
+        }
+        self.free[i] = free.min(u16::MAX as usize) as u16;
+    }
+
+    pub fn get(&self, page_id: PageId) -> usize {
+        self.free.get(page_id as usize).copied().unwrap_or(0) as usize
+    }
+
+    /// Lowest page with at least `need` bytes free.
+    pub fn find(&self, need: usize) -> Option<PageId> {
+        self.free
+            .iter()
+            .position(|&f| f as usize >= need)
+            .map(|i| i as PageId)
+    }
+
+    /// Forget pages from `num_pages` on (the heap was truncated).
+    pub fn truncate(&mut self, num_pages: PageId) {
+        self.free.truncate(num_pages as usize);
+    }
+
+    pub fn num_pages(&self) -> PageId {
+        self.free.len() as PageId
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_lowest_page_with_room() {
+        let mut fsm = FreeSpaceMap::new();
+        fsm.set(0, 10);
+        fsm.set(2, 500);
+        fsm.set(3, 4000);
+        assert_eq!(fsm.get(1), 0);
+        assert_eq!(fsm.find(100), Some(2));
+        assert_eq!(fsm.find(1000), Some(3));
+        assert_eq!(fsm.find(5000), None);
+        fsm.truncate(3);
+        assert_eq!(fsm.find(1000), None);
+        assert_eq!(fsm.num_pages(), 3);
+    }
+}
@@ -2,8 +2,11 @@
 
 use anyhow::{ensure, Result};
 use std::fs::{File, OpenOptions};
-use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::io::{BufWriter, Write};
 use std::path::Path;
+use std::sync::Arc;
+
+use crate::buffer::{BufferPool, FileId, IoCounters};
 
 #[allow(unused_imports)]
 use super::page::{Page, PageFlags, PAGE_SIZE};
@@ -16,6 +19,8 @@ pub struct HeapFile {
     path: std::path::PathBuf,
     file: File,
     num_pages: PageId,
+    /// Cache this file's pages go through, once attached.
+    pool: Option<(Arc<BufferPool>, FileId)>,
 }
 
 impl HeapFile {
@@ -32,6 +37,7 @@ impl HeapFile {
             path,
             file,
             num_pages: 0,
+            pool: None,
         })
     }
 
@@ -49,6 +55,7 @@ impl HeapFile {
             path,
             file,
             num_pages,
+            pool: None,
         })
     }
 
@@ -61,6 +68,8 @@ impl HeapFile {
         let mut w = BufWriter::new(&mut self.file);
         p.write_at(&mut w, id)?;
         w.flush()?;
+        drop(w);
+        self.cache(id, &p);
         self.num_pages += 1;
         Ok(id)
     }
@@ -68,7 +77,16 @@ impl HeapFile {
     /// Read a page by id. Returns error if page_id >= num_pages.
     pub fn read_page(&mut self, page_id: PageId) -> Result<Page> {
         ensure!(page_id < self.num_pages, "page id {} out of range", page_id);
-        Page::read_at(&mut self.file, page_id)
+        if let Some((pool, file)) = &self.pool {
+            if let Some(page) = pool.get(*file, page_id) {
+                IoCounters::count(true);
+                return Ok(page);
+            }
+        }
+        let page = Page::read_at(&mut self.file, page_id)?;
+        IoCounters::count(false);
+        self.cache(page_id, &page);
+        Ok(page)
     }
 
     /// Write a page at an existing id. Used when updating in-place (e.g. B-tree nodes).
@@ -78,9 +96,32 @@ impl HeapFile {
         p.set_page_id(page_id);
         p.write_at(&mut self.file, page_id)?;
         self.file.flush()?;
+        self.cache(page_id, &p);
         Ok(())
     }
 
+    /// Drop every page from `num_pages` on. Used by vacuum to return empty trailing pages.
+    pub fn truncate(&mut self, num_pages: PageId) -> Result<()> {
+        ensure!(num_pages <= self.num_pages, "cannot truncate heap to a larger size");
+        self.file.set_len(num_pages as u64 * PAGE_SIZE as u64)?;
+        if let Some((pool, file)) = &self.pool {
+            pool.forget(*file, num_pages);
+        }
+        self.num_pages = num_pages;
+        Ok(())
+    }
+
+    /// Route page reads through `pool`. Writes go to disk and update the cached copy.
+    pub fn attach_buffer_pool(&mut self, pool: &Arc<BufferPool>) {
+        self.pool = Some((Arc::clone(pool), pool.register_file()));
+    }
+
+    fn cache(&self, page_id: PageId, page: &Page) {
+        if let Some((pool, file)) = &self.pool {
+            pool.put(*file, page_id, page);
+        }
+    }
+
     /// Number of pages in the file.
     pub fn num_pages(&self) -> PageId {
         self.num_pages
@@ -92,6 +133,14 @@ impl HeapFile {
     }
 }
 
+impl Drop for HeapFile {
+    fn drop(&mut self) {
+        if let Some((pool, file)) = &self.pool {
+            pool.forget(*file, 0);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -122,6 +171,45 @@ mod tests {
         assert_eq!(r1.get_slot(0).unwrap(), b"row1");
     }
 
+    #[test]
+    fn truncate_drops_trailing_pages() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut heap = HeapFile::create(tmp.path()).unwrap();
+        for _ in 0..3 {
+            heap.append_page(&Page::new(0, PageFlags::Heap)).unwrap();
+        }
+        heap.truncate(1).unwrap();
+        assert_eq!(heap.num_pages(), 1);
+        assert!(heap.read_page(1).is_err());
+        assert!(heap.truncate(2).is_err());
+        assert_eq!(heap.append_page(&Page::new(0, PageFlags::Heap)).unwrap(), 1);
+        drop(heap);
+        assert_eq!(HeapFile::open(tmp.path()).unwrap().num_pages(), 2);
+    }
+
+    #[test]
+    fn buffer_pool_serves_repeated_reads() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut heap = HeapFile::create(tmp.path()).unwrap();
+        for _ in 0..2 {
+            heap.append_page(&Page::new(0, PageFlags::Heap)).unwrap();
+        }
+        let pool = Arc::new(BufferPool::new(8));
+        heap.attach_buffer_pool(&pool);
+        let before = IoCounters::current();
+        heap.read_page(0).unwrap();
+        heap.read_page(0).unwrap();
+        let mut p = heap.read_page(1).unwrap();
+        p.insert(b"x").unwrap();
+        heap.write_page(1, &p).unwrap();
+        assert_eq!(heap.read_page(1).unwrap().get_slot(0).unwrap(), b"x");
+        assert_eq!(IoCounters::current().since(before), IoCounters { hits: 2, reads: 2 });
+        heap.truncate(1).unwrap();
+        assert_eq!(pool.len(), 1);
+        drop(heap);
+        assert!(pool.is_empty());
+    }
+
     #[test]
     fn open_existing() {
         let tmp = NamedTempFile::new().unwrap();
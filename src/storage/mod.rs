@@ -4,8 +4,21 @@ mod row;
 mod page;
 mod heap;
 mod btree;
+mod fsm;
+mod inspect;
+mod table;
 
-pub use row::{Value, ColumnType, encode as row_encode, decode as row_decode, ROW_HEADER_LEN};
+pub use row::{
+    Value, ColumnType, RowHeader, encode as row_encode, decode as row_decode,
+    encode_with_header as row_encode_with_header, decode_values as row_decode_values,
+    decode_each as row_decode_each, cmp_floats, ValueRef, ROW_FORMAT, ROW_HEADER_LEN,
+};
 pub use page::{Page, PageFlags, PAGE_SIZE, HEADER_LEN};
 pub use heap::{HeapFile, PageId};
 pub use btree::{BTree, RowRef};
+pub use fsm::FreeSpaceMap;
+pub use table::{Table, VacuumStats};
+pub use inspect::{
+    inspect_file, inspect_page, parse_schema, BTreeNodeInfo, HeaderInfo, InternalEntryInfo, LeafEntryInfo,
+    PageReport, SlotInfo,
+};
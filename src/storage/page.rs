@@ -1,5 +1,7 @@
 //! Page format v1: 8 KB slotted page. Header + slot directory + row area.
 //! Row area grows downward from end of page; slot directory grows upward from header.
+//! A slot entry with length 0 is unused: vacuum frees slots without renumbering the others,
+//! so `RowRef`s to surviving rows stay valid, and inserts reuse the lowest unused slot.
 
 use anyhow::{bail, ensure, Result};
 use std::io::{Read, Seek, SeekFrom, Write};
@@ -19,12 +21,24 @@ const SLOT_SIZE: usize = 4; // offset u16, length u16
 const SLOT_DIR_START: usize = HEADER_LEN;
 
 #[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PageFlags {
     Heap = 0,
     Leaf = 1,
     Internal = 2,
 }
 
+impl PageFlags {
+    pub fn from_u16(v: u16) -> Option<Self> {
+        match v {
+            0 => Some(Self::Heap),
+            1 => Some(Self::Leaf),
+            2 => Some(Self::Internal),
+            _ => None,
+        }
+    }
+}
+
 /// Slotted page. Slot directory at [HEADER_LEN..); row area [free_end..PAGE_SIZE).
 /// Rows grow downward from PAGE_SIZE; free_end is the low end of the free region.
 #[derive(Clone)]
@@ -48,7 +62,7 @@ impl Page {
     fn set_magic(&mut self, v: u32) {
         self.data[OFFSET_MAGIC..OFFSET_MAGIC + 4].copy_from_slice(&v.to_le_bytes());
     }
-    fn magic(&self) -> u32 {
+    pub fn magic(&self) -> u32 {
         u32::from_le_bytes(self.data[OFFSET_MAGIC..OFFSET_MAGIC + 4].try_into().unwrap())
     }
     pub fn page_id(&self) -> u32 {
@@ -58,6 +72,9 @@ impl Page {
     pub fn set_page_id(&mut self, v: u32) {
         self.data[OFFSET_PAGE_ID..OFFSET_PAGE_ID + 4].copy_from_slice(&v.to_le_bytes());
     }
+    pub fn flags(&self) -> u16 {
+        u16::from_le_bytes(self.data[OFFSET_FLAGS..OFFSET_FLAGS + 2].try_into().unwrap())
+    }
     fn set_flags(&mut self, v: u16) {
         self.data[OFFSET_FLAGS..OFFSET_FLAGS + 2].copy_from_slice(&v.to_le_bytes());
     }
@@ -67,7 +84,7 @@ impl Page {
     fn set_n_slots(&mut self, v: u16) {
         self.data[OFFSET_N_SLOTS..OFFSET_N_SLOTS + 2].copy_from_slice(&v.to_le_bytes());
     }
-    fn free_end(&self) -> u16 {
+    pub fn free_end(&self) -> u16 {
         u16::from_le_bytes(self.data[OFFSET_FREE_END..OFFSET_FREE_END + 2].try_into().unwrap())
     }
     fn set_free_end(&mut self, v: u16) {
@@ -82,30 +99,40 @@ impl Page {
     pub fn free_space(&self) -> usize {
         let end = self.slot_dir_end();
         let start = self.free_end() as usize;
-        if start <= end { 0 } else { start - end - SLOT_SIZE }
+        start.saturating_sub(end + SLOT_SIZE)
     }
 
     /// Insert row bytes. Returns `Some(slot_index)` on success, `None` if no space.
+    /// Empty rows are rejected: a zero length marks an unused slot.
     pub fn insert(&mut self, row: &[u8]) -> Option<usize> {
-        let need = row.len() + SLOT_SIZE;
-        if self.free_space() < need {
+        if row.is_empty() {
             return None;
         }
         let n = self.raw_n_slots();
+        let reuse = (0..n as usize).find(|&i| self.slot_entry(i).is_some_and(|(_, len)| len == 0));
+        let need = row.len() + if reuse.is_some() { 0 } else { SLOT_SIZE };
+        let room = (self.free_end() as usize).saturating_sub(self.slot_dir_end());
+        if room < need {
+            return None;
+        }
         let new_free = self.free_end() as usize - row.len();
-        let slot_offset = new_free as u16;
-        let slot_len = row.len() as u16;
-
         self.data[new_free..new_free + row.len()].copy_from_slice(row);
         self.set_free_end(new_free as u16);
-        let slot_pos = SLOT_DIR_START + n as usize * SLOT_SIZE;
-        self.data[slot_pos..slot_pos + 2].copy_from_slice(&slot_offset.to_le_bytes());
-        self.data[slot_pos + 2..slot_pos + 4].copy_from_slice(&slot_len.to_le_bytes());
-        self.set_n_slots(n + 1);
-        Some(n as usize)
+        let slot = reuse.unwrap_or(n as usize);
+        self.set_slot_entry(slot, new_free as u16, row.len() as u16);
+        if reuse.is_none() {
+            self.set_n_slots(n + 1);
+        }
+        Some(slot)
     }
 
-    /// Get row bytes at slot. Returns `None` if slot invalid.
+    fn set_slot_entry(&mut self, slot_id: usize, offset: u16, len: u16) {
+        let pos = SLOT_DIR_START + slot_id * SLOT_SIZE;
+        self.data[pos..pos + 2].copy_from_slice(&offset.to_le_bytes());
+        self.data[pos + 2..pos + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    /// Get row bytes at slot. Returns `None` if the slot is invalid or unused.
     pub fn get_slot(&self, slot_id: usize) -> Option<&[u8]> {
         if slot_id >= self.raw_n_slots() as usize {
             return None;
@@ -113,12 +140,72 @@ impl Page {
         let pos = SLOT_DIR_START + slot_id * SLOT_SIZE;
         let offset = u16::from_le_bytes(self.data[pos..pos + 2].try_into().unwrap()) as usize;
         let len = u16::from_le_bytes(self.data[pos + 2..pos + 4].try_into().unwrap()) as usize;
-        if offset + len > PAGE_SIZE {
+        if len == 0 || offset + len > PAGE_SIZE {
             return None;
         }
         Some(&self.data[offset..offset + len])
     }
 
+    /// Release a slot. Its bytes become garbage until `compact`; trailing unused slots are
+    /// dropped from the directory.
+    pub fn free_slot(&mut self, slot_id: usize) -> Result<()> {
+        ensure!(slot_id < self.raw_n_slots() as usize, "invalid slot {}", slot_id);
+        self.set_slot_entry(slot_id, 0, 0);
+        let mut n = self.raw_n_slots() as usize;
+        while n > 0 && self.slot_entry(n - 1).is_some_and(|(_, len)| len == 0) {
+            n -= 1;
+        }
+        self.set_n_slots(n as u16);
+        Ok(())
+    }
+
+    /// Move all rows to the end of the page so freed row space becomes one contiguous
+    /// free region. Slot numbers are preserved.
+    pub fn compact(&mut self) {
+        let rows: Vec<(usize, Vec<u8>)> = self
+            .iter_slots()
+            .map(|(slot, bytes)| (slot, bytes.to_vec()))
+            .collect();
+        let mut free_end = PAGE_SIZE;
+        for (slot, bytes) in rows {
+            free_end -= bytes.len();
+            self.data[free_end..free_end + bytes.len()].copy_from_slice(&bytes);
+            self.set_slot_entry(slot, free_end as u16, bytes.len() as u16);
+        }
+        let dir_end = self.slot_dir_end();
+        self.data[dir_end..free_end].fill(0);
+        self.set_free_end(free_end as u16);
+    }
+
+    /// Number of slots holding a row.
+    pub fn live_slots(&self) -> usize {
+        self.iter_slots().count()
+    }
+
+    /// Mutable row bytes at slot, for in-place header updates. Length is fixed.
+    pub fn get_slot_mut(&mut self, slot_id: usize) -> Option<&mut [u8]> {
+        let (offset, len) = self.slot_entry(slot_id)?;
+        let (offset, len) = (offset as usize, len as usize);
+        if offset + len > PAGE_SIZE {
+            return None;
+        }
+        Some(&mut self.data[offset..offset + len])
+    }
+
+    /// Raw slot directory entry (offset, length), without bounds-checking the row area.
+    pub fn slot_entry(&self, slot_id: usize) -> Option<(u16, u16)> {
+        if slot_id >= self.raw_n_slots() as usize {
+            return None;
+        }
+        let pos = SLOT_DIR_START + slot_id * SLOT_SIZE;
+        if pos + SLOT_SIZE > PAGE_SIZE {
+            return None;
+        }
+        let offset = u16::from_le_bytes(self.data[pos..pos + 2].try_into().unwrap());
+        let len = u16::from_le_bytes(self.data[pos + 2..pos + 4].try_into().unwrap());
+        Some((offset, len))
+    }
+
     /// Mark row at slot as deleted (tombstone = 1). Row must have at least ROW_HEADER_LEN bytes.
     pub fn delete_slot(&mut self, slot_id: usize) -> Result<()> {
         if slot_id >= self.raw_n_slots() as usize {
@@ -152,6 +239,11 @@ impl Page {
         Ok(p)
     }
 
+    /// Wrap raw bytes without validating the magic. For inspection of possibly corrupt pages.
+    pub fn from_bytes(data: [u8; PAGE_SIZE]) -> Self {
+        Self { data }
+    }
+
     /// Read page at offset `page_id * PAGE_SIZE` in file.
     pub fn read_at<R: Read + Seek>(r: &mut R, page_id: u32) -> Result<Self> {
         r.seek(SeekFrom::Start((page_id as u64) * (PAGE_SIZE as u64)))?;
@@ -183,6 +275,7 @@ impl Page {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::row::{encode_with_header, ColumnType, RowHeader, Value};
     use std::io::Cursor;
 
     #[test]
@@ -205,7 +298,7 @@ mod tests {
     #[test]
     fn insert_get_delete() {
         let mut p = Page::new(0, PageFlags::Heap);
-        let mut row = vec![0u8; 20];
+        let mut row = vec![0u8; 32];
         row[0..8].copy_from_slice(&1u64.to_le_bytes());
         row[8] = 0;
         let idx = p.insert(&row).unwrap();
@@ -214,6 +307,25 @@ mod tests {
         assert_eq!(s[8], 1);
     }
 
+    #[test]
+    fn delete_needs_full_row_header() {
+        // Tombstoning only touches the header, but a row shorter than it is not a row.
+        let mut p = Page::new(0, PageFlags::Heap);
+        let short = p.insert(&[0u8; ROW_HEADER_LEN - 1]).unwrap();
+        assert!(p.delete_slot(short).is_err());
+        let header = RowHeader {
+            txn_id: 4,
+            tombstone: 0,
+            xmax: 9,
+            prev: Some((2, 5)),
+        };
+        let row = encode_with_header(&[ColumnType::Int], &[Value::Int(1)], &header).unwrap();
+        let idx = p.insert(&row).unwrap();
+        p.delete_slot(idx).unwrap();
+        let deleted = RowHeader::read(p.get_slot(idx).unwrap()).unwrap();
+        assert_eq!(deleted, RowHeader { tombstone: 1, ..header });
+    }
+
     #[test]
     fn insert_fill_then_no_space() {
         let mut p = Page::new(0, PageFlags::Heap);
@@ -225,6 +337,43 @@ mod tests {
         assert!(p.insert(&[0u8; 64]).is_none());
     }
 
+    #[test]
+    fn free_space_is_zero_when_less_than_a_slot_is_left() {
+        let mut p = Page::new(0, PageFlags::Heap);
+        let room = p.free_space();
+        p.insert(&vec![1u8; room - 2]).unwrap();
+        // Two bytes remain: not enough for another slot entry.
+        assert_eq!(p.free_space(), 0);
+        assert!(p.insert(&[1u8]).is_none());
+    }
+
+    #[test]
+    fn free_compact_and_reuse_slots() {
+        let mut p = Page::new(0, PageFlags::Heap);
+        for i in 0..4u8 {
+            p.insert(&[i; 100]).unwrap();
+        }
+        let before = p.free_space();
+        p.free_slot(1).unwrap();
+        assert_eq!(p.get_slot(1), None);
+        assert_eq!(p.n_slots(), 4);
+        assert_eq!(p.free_space(), before);
+        p.compact();
+        assert_eq!(p.free_space(), before + 100);
+        assert_eq!(p.get_slot(2).unwrap(), &[2u8; 100]);
+        assert_eq!(p.insert(&[9; 50]).unwrap(), 1);
+        assert_eq!(p.live_slots(), 4);
+
+        p.free_slot(3).unwrap();
+        p.free_slot(2).unwrap();
+        assert_eq!(p.n_slots(), 2);
+        p.free_slot(0).unwrap();
+        p.free_slot(1).unwrap();
+        p.compact();
+        assert_eq!(p.n_slots(), 0);
+        assert_eq!(p.free_space(), Page::new(0, PageFlags::Heap).free_space());
+    }
+
     #[test]
     fn read_write_roundtrip() {
         let mut p = Page::new(1, PageFlags::Heap);
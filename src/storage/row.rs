@@ -1,25 +1,249 @@
-//! Row format v1: header (txn_id, tombstone) + binary-encoded columns.
-//! Types: INT (8 bytes LE), TEXT (4-byte length + UTF-8), BOOL (1 byte).
+//! Row format v3: header (txn_id, tombstone, xmax, prev version) + null bitmap + columns.
+//! The bitmap has one bit per column (bit set = NULL); NULL columns take no further bytes.
+//! Types: INT (8 bytes LE), FLOAT (f64, 8 bytes LE), TEXT (4-byte length + UTF-8), BOOL (1 byte).
+//! txn_id is the creating transaction (xmin); xmax the deleting/superseding one (0 = none);
+//! prev points at the version this one replaced, so older snapshots can walk back to it.
 
 use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::io::{Cursor, Read, Write};
+use std::str::FromStr;
 
-pub const ROW_HEADER_LEN: usize = 9; // txn_id (8) + tombstone (1)
+/// The row format this build writes and reads, recorded in the catalog of each database
+/// (see `Catalog::load`). Bump it with every change to the layout above.
+pub const ROW_FORMAT: u32 = 3;
 
-#[derive(Debug, Clone, PartialEq)]
+pub const ROW_HEADER_LEN: usize = 23; // txn_id (8) + tombstone (1) + xmax (8) + prev (4 + 2)
+const OFFSET_TOMBSTONE: usize = 8;
+const OFFSET_XMAX: usize = 9;
+const OFFSET_PREV_PAGE: usize = 17;
+const OFFSET_PREV_SLOT: usize = 21;
+const NO_PREV: u32 = u32::MAX;
+
+/// Decoded row header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RowHeader {
+    pub txn_id: u64,
+    pub tombstone: u8,
+    pub xmax: u64,
+    /// Previous version as (page_id, slot).
+    pub prev: Option<(u32, u16)>,
+}
+
+impl RowHeader {
+    pub fn new(txn_id: u64) -> Self {
+        Self {
+            txn_id,
+            ..Self::default()
+        }
+    }
+
+    fn write<W: Write>(&self, w: &mut W) -> Result<()> {
+        w.write_all(&self.txn_id.to_le_bytes())?;
+        w.write_all(&[self.tombstone])?;
+        w.write_all(&self.xmax.to_le_bytes())?;
+        let (page, slot) = self.prev.unwrap_or((NO_PREV, 0));
+        w.write_all(&page.to_le_bytes())?;
+        w.write_all(&slot.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Read the header from the start of an encoded row.
+    pub fn read(bytes: &[u8]) -> Result<Self> {
+        ensure!(bytes.len() >= ROW_HEADER_LEN, "row too short");
+        let page = u32::from_le_bytes(bytes[OFFSET_PREV_PAGE..OFFSET_PREV_PAGE + 4].try_into()?);
+        let slot = u16::from_le_bytes(bytes[OFFSET_PREV_SLOT..OFFSET_PREV_SLOT + 2].try_into()?);
+        Ok(Self {
+            txn_id: u64::from_le_bytes(bytes[0..8].try_into()?),
+            tombstone: bytes[OFFSET_TOMBSTONE],
+            xmax: u64::from_le_bytes(bytes[OFFSET_XMAX..OFFSET_XMAX + 8].try_into()?),
+            prev: (page != NO_PREV).then_some((page, slot)),
+        })
+    }
+}
+
+/// Stamp xmax into an encoded row in place.
+pub fn set_xmax(bytes: &mut [u8], xmax: u64) -> Result<()> {
+    ensure!(bytes.len() >= ROW_HEADER_LEN, "row too short");
+    bytes[OFFSET_XMAX..OFFSET_XMAX + 8].copy_from_slice(&xmax.to_le_bytes());
+    Ok(())
+}
+
+/// Unlink an encoded row from the older version it points at (vacuum removed it).
+pub fn clear_prev(bytes: &mut [u8]) -> Result<()> {
+    ensure!(bytes.len() >= ROW_HEADER_LEN, "row too short");
+    bytes[OFFSET_PREV_PAGE..OFFSET_PREV_PAGE + 4].copy_from_slice(&NO_PREV.to_le_bytes());
+    bytes[OFFSET_PREV_SLOT..OFFSET_PREV_SLOT + 2].copy_from_slice(&0u16.to_le_bytes());
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
 pub enum Value {
+    Null,
     Int(i64),
+    Float(f64),
     Text(String),
     Bool(bool),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+impl Value {
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    /// Type of a non-NULL value.
+    pub fn column_type(&self) -> Option<ColumnType> {
+        match self {
+            Value::Null => None,
+            Value::Int(_) => Some(ColumnType::Int),
+            Value::Float(_) => Some(ColumnType::Float),
+            Value::Text(_) => Some(ColumnType::Text),
+            Value::Bool(_) => Some(ColumnType::Bool),
+        }
+    }
+
+    /// SQL comparison: `None` if either side is NULL or the types are not comparable.
+    /// INT and FLOAT compare exactly by numeric value, -0.0 equal to 0.0; NaN sorts above
+    /// (a negative NaN below) every number.
+    pub fn sql_cmp(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+            (Value::Float(a), Value::Float(b)) => Some(cmp_floats(*a, *b)),
+            (Value::Int(a), Value::Float(b)) => Some(cmp_int_float(*a, *b)),
+            (Value::Float(a), Value::Int(b)) => Some(cmp_int_float(*b, *a).reverse()),
+            (Value::Text(a), Value::Text(b)) => Some(a.cmp(b)),
+            (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+            _ => None,
+        }
+    }
+
+    /// Total order for sorting and grouping: NULLs first, then by type, then by value.
+    pub fn total_cmp(&self, other: &Value) -> Ordering {
+        fn rank(v: &Value) -> u8 {
+            match v {
+                Value::Null => 0,
+                Value::Bool(_) => 1,
+                Value::Int(_) | Value::Float(_) => 2,
+                Value::Text(_) => 3,
+            }
+        }
+        self.sql_cmp(other)
+            .unwrap_or_else(|| rank(self).cmp(&rank(other)))
+    }
+
+    /// Numeric view of INT and FLOAT values.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(n) => Some(*n as f64),
+            Value::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+}
+
+/// FLOAT comparison as `Value::sql_cmp` does it: -0.0 equals 0.0, and NaN sorts above
+/// (a negative NaN below) every number.
+pub fn cmp_floats(a: f64, b: f64) -> Ordering {
+    a.partial_cmp(&b).unwrap_or_else(|| a.total_cmp(&b))
+}
+
+/// Compare an INT with a FLOAT without rounding the INT to the nearest FLOAT, which above
+/// 2^53 would make distinct INTs equal to the same FLOAT.
+fn cmp_int_float(a: i64, b: f64) -> Ordering {
+    if b.is_nan() {
+        return if b.is_sign_negative() { Ordering::Greater } else { Ordering::Less };
+    }
+    if b >= i64::MAX as f64 {
+        return Ordering::Less;
+    }
+    if b < i64::MIN as f64 {
+        return Ordering::Greater;
+    }
+    // Now `whole` is an exact i64; the fraction breaks ties.
+    let whole = b.trunc();
+    a.cmp(&(whole as i64)).then_with(|| 0f64.total_cmp(&(b - whole)))
+}
+
+/// Equality as used for grouping and hashing: NULL equals NULL, 1 equals 1.0.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.total_cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // An INT equal to a FLOAT converts to it exactly, so hashing INTs as FLOATs keeps
+        // equal values hashing alike; INTs above 2^53 that round together merely collide.
+        // Materialized views store these hashes, so the scheme must not change.
+        fn hash_number<H: Hasher>(x: f64, state: &mut H) {
+            // -0.0 and 0.0 compare equal, so they must hash alike.
+            let x = if x == 0.0 { 0.0 } else { x };
+            (2u8, x.to_bits()).hash(state);
+        }
+        match self {
+            Value::Null => 0u8.hash(state),
+            Value::Bool(b) => (1u8, b).hash(state),
+            Value::Int(n) => hash_number(*n as f64, state),
+            Value::Float(x) => hash_number(*x, state),
+            Value::Text(s) => (3u8, s).hash(state),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Text(s) => write!(f, "{}", s),
+            Value::Bool(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum ColumnType {
     Int,
+    Float,
     Text,
     Bool,
 }
 
+impl fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ColumnType::Int => "INT",
+            ColumnType::Float => "FLOAT",
+            ColumnType::Text => "TEXT",
+            ColumnType::Bool => "BOOL",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for ColumnType {
+    type Err = anyhow::Error;
+
+    /// Parse a SQL-ish type name, e.g. `int`, `TEXT`, `boolean`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "int" | "integer" | "bigint" => Ok(ColumnType::Int),
+            "float" | "double" | "real" | "double precision" => Ok(ColumnType::Float),
+            "text" | "varchar" | "string" => Ok(ColumnType::Text),
+            "bool" | "boolean" => Ok(ColumnType::Bool),
+            other => anyhow::bail!("unknown column type: {}", other),
+        }
+    }
+}
+
 /// Encode a row: header (txn_id, tombstone) then column values per schema.
 /// Tombstone 0 = live, 1 = deleted.
 pub fn encode(
@@ -27,37 +251,123 @@ pub fn encode(
     values: &[Value],
     txn_id: u64,
     tombstone: u8,
+) -> Result<Vec<u8>> {
+    let header = RowHeader {
+        tombstone,
+        ..RowHeader::new(txn_id)
+    };
+    encode_with_header(schema, values, &header)
+}
+
+/// Encode a row with a full header (MVCC fields included).
+pub fn encode_with_header(
+    schema: &[ColumnType],
+    values: &[Value],
+    header: &RowHeader,
 ) -> Result<Vec<u8>> {
     ensure!(schema.len() == values.len(), "schema len != values len");
     let mut buf = Vec::with_capacity(ROW_HEADER_LEN + 64);
-    buf.write_all(&txn_id.to_le_bytes())?;
-    buf.write_all(&[tombstone])?;
+    header.write(&mut buf)?;
+    let mut nulls = vec![0u8; null_bitmap_len(schema.len())];
+    for (i, v) in values.iter().enumerate() {
+        if v.is_null() {
+            nulls[i / 8] |= 1 << (i % 8);
+        }
+    }
+    buf.extend_from_slice(&nulls);
     for (ty, v) in schema.iter().zip(values.iter()) {
-        encode_value(&mut buf, ty, v)?;
+        if !v.is_null() {
+            encode_value(&mut buf, ty, v)?;
+        }
     }
     Ok(buf)
 }
 
 /// Decode a row. Returns (txn_id, tombstone, values).
 pub fn decode(schema: &[ColumnType], bytes: &[u8]) -> Result<(u64, u8, Vec<Value>)> {
-    ensure!(bytes.len() >= ROW_HEADER_LEN, "row too short");
-    let mut c = Cursor::new(bytes);
-    let mut txn_buf = [0u8; 8];
-    c.read_exact(&mut txn_buf)?;
-    let txn_id = u64::from_le_bytes(txn_buf);
-    let mut tombstone_buf = [0u8; 1];
-    c.read_exact(&mut tombstone_buf)?;
-    let tombstone = tombstone_buf[0];
+    let header = RowHeader::read(bytes)?;
+    Ok((header.txn_id, header.tombstone, decode_values(schema, bytes)?))
+}
+
+/// Decode only the column values of a row, skipping the header.
+pub fn decode_values(schema: &[ColumnType], bytes: &[u8]) -> Result<Vec<Value>> {
+    let body = ROW_HEADER_LEN + null_bitmap_len(schema.len());
+    ensure!(bytes.len() >= body, "row too short");
+    let nulls = &bytes[ROW_HEADER_LEN..body];
+    let mut c = Cursor::new(&bytes[body..]);
     let mut values = Vec::with_capacity(schema.len());
-    for ty in schema {
-        values.push(decode_value(&mut c, ty)?);
+    for (i, ty) in schema.iter().enumerate() {
+        if nulls[i / 8] & (1 << (i % 8)) != 0 {
+            values.push(Value::Null);
+        } else {
+            values.push(decode_value(&mut c, ty)?);
+        }
     }
-    Ok((txn_id, tombstone, values))
+    Ok(values)
+}
+
+/// A column value borrowed from an encoded row.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueRef<'a> {
+    Null,
+    Int(i64),
+    Float(f64),
+    Text(&'a str),
+    Bool(bool),
+}
+
+impl ValueRef<'_> {
+    pub fn to_value(self) -> Value {
+        match self {
+            ValueRef::Null => Value::Null,
+            ValueRef::Int(n) => Value::Int(n),
+            ValueRef::Float(x) => Value::Float(x),
+            ValueRef::Text(s) => Value::Text(s.to_string()),
+            ValueRef::Bool(b) => Value::Bool(b),
+        }
+    }
+}
+
+/// Decode the column values of a row without copying them, passing each to `f` with its
+/// position. Used by scans that store values column by column.
+pub fn decode_each<'a>(schema: &[ColumnType], bytes: &'a [u8], mut f: impl FnMut(usize, ValueRef<'a>)) -> Result<()> {
+    let mut pos = ROW_HEADER_LEN + null_bitmap_len(schema.len());
+    ensure!(bytes.len() >= pos, "row too short");
+    let nulls = &bytes[ROW_HEADER_LEN..pos];
+    let mut take = |n: usize| -> Result<&'a [u8]> {
+        ensure!(bytes.len() >= pos + n, "row too short");
+        pos += n;
+        Ok(&bytes[pos - n..pos])
+    };
+    for (i, ty) in schema.iter().enumerate() {
+        let v = if nulls[i / 8] & (1 << (i % 8)) != 0 {
+            ValueRef::Null
+        } else {
+            match ty {
+                ColumnType::Int => ValueRef::Int(i64::from_le_bytes(take(8)?.try_into()?)),
+                ColumnType::Float => ValueRef::Float(f64::from_le_bytes(take(8)?.try_into()?)),
+                ColumnType::Text => {
+                    let len = u32::from_le_bytes(take(4)?.try_into()?) as usize;
+                    let s = std::str::from_utf8(take(len)?).map_err(|e| anyhow::anyhow!("invalid utf8: {}", e))?;
+                    ValueRef::Text(s)
+                }
+                ColumnType::Bool => ValueRef::Bool(take(1)?[0] != 0),
+            }
+        };
+        f(i, v);
+    }
+    Ok(())
+}
+
+fn null_bitmap_len(columns: usize) -> usize {
+    columns.div_ceil(8)
 }
 
 fn encode_value<W: Write>(w: &mut W, ty: &ColumnType, v: &Value) -> Result<()> {
     match (ty, v) {
         (ColumnType::Int, Value::Int(n)) => w.write_all(&n.to_le_bytes())?,
+        (ColumnType::Float, Value::Float(x)) => w.write_all(&x.to_le_bytes())?,
+        (ColumnType::Float, Value::Int(n)) => w.write_all(&(*n as f64).to_le_bytes())?,
         (ColumnType::Text, Value::Text(s)) => {
             let b = s.as_bytes();
             w.write_all(&(b.len() as u32).to_le_bytes())?;
@@ -76,6 +386,11 @@ fn decode_value<R: Read>(r: &mut R, ty: &ColumnType) -> Result<Value> {
             r.read_exact(&mut b)?;
             Ok(Value::Int(i64::from_le_bytes(b)))
         }
+        ColumnType::Float => {
+            let mut b = [0u8; 8];
+            r.read_exact(&mut b)?;
+            Ok(Value::Float(f64::from_le_bytes(b)))
+        }
         ColumnType::Text => {
             let mut len_b = [0u8; 4];
             r.read_exact(&mut len_b)?;
@@ -126,6 +441,62 @@ mod tests {
         assert_eq!(tomb, 1);
     }
 
+    #[test]
+    fn nulls_and_floats_roundtrip() {
+        let schema: Vec<ColumnType> = (0..10)
+            .map(|i| if i % 2 == 0 { ColumnType::Float } else { ColumnType::Text })
+            .collect();
+        let values: Vec<Value> = (0..10)
+            .map(|i| match i {
+                3 | 8 => Value::Null,
+                i if i % 2 == 0 => Value::Float(i as f64 / 4.0),
+                i => Value::Text(format!("t{}", i)),
+            })
+            .collect();
+        let encoded = encode(&schema, &values, 1, 0).unwrap();
+        assert_eq!(decode_values(&schema, &encoded).unwrap(), values);
+        assert_eq!(Value::Int(1), Value::Float(1.0));
+        assert_eq!(Value::Null.sql_cmp(&Value::Null), None);
+        assert_eq!(Value::Null.total_cmp(&Value::Int(0)), Ordering::Less);
+    }
+
+    #[test]
+    fn mvcc_header_roundtrip() {
+        let schema = vec![ColumnType::Int];
+        let header = RowHeader {
+            txn_id: 3,
+            tombstone: 0,
+            xmax: 0,
+            prev: Some((12, 4)),
+        };
+        let mut encoded = encode_with_header(&schema, &[Value::Int(1)], &header).unwrap();
+        assert_eq!(RowHeader::read(&encoded).unwrap(), header);
+        set_xmax(&mut encoded, 8).unwrap();
+        let read = RowHeader::read(&encoded).unwrap();
+        assert_eq!(read.xmax, 8);
+        assert_eq!(read.prev, Some((12, 4)));
+        assert_eq!(decode_values(&schema, &encoded).unwrap(), vec![Value::Int(1)]);
+        assert_eq!(RowHeader::read(&encode(&schema, &[Value::Int(1)], 1, 0).unwrap()).unwrap().prev, None);
+    }
+
+    #[test]
+    fn header_fields_do_not_overlap() {
+        // Extreme values in every field catch an offset that reads into its neighbour.
+        let header = RowHeader {
+            txn_id: u64::MAX,
+            tombstone: 1,
+            xmax: u64::MAX - 1,
+            prev: Some((u32::MAX - 1, u16::MAX)),
+        };
+        let schema = vec![ColumnType::Text];
+        let encoded = encode_with_header(&schema, &[Value::Text("v".into())], &header).unwrap();
+        assert_eq!(RowHeader::read(&encoded).unwrap(), header);
+        let (txn_id, tombstone, values) = decode(&schema, &encoded).unwrap();
+        assert_eq!((txn_id, tombstone, values), (u64::MAX, 1, vec![Value::Text("v".into())]));
+        // A v1 row (9-byte header) is too short to carry the v2 header.
+        assert!(RowHeader::read(&encoded[..9]).is_err());
+    }
+
     #[test]
     fn empty_text() {
         let schema = vec![ColumnType::Text];
@@ -134,4 +505,36 @@ mod tests {
         let (_, _, decoded) = decode(&schema, &encoded).unwrap();
         assert_eq!(decoded, values);
     }
+
+    #[test]
+    fn int_float_comparison_is_exact() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::collections::HashSet;
+
+        // 2^53 + 1 has no FLOAT; as f64 it would round to 2^53.
+        let big = 1i64 << 53;
+        let float = Value::Float(big as f64);
+        assert_eq!(Value::Int(big), float);
+        assert_ne!(Value::Int(big + 1), float);
+        assert_eq!(Value::Int(big + 1).total_cmp(&float), Ordering::Greater);
+        assert_eq!(float.total_cmp(&Value::Int(big + 1)), Ordering::Less);
+        assert_eq!(Value::Int(i64::MAX).total_cmp(&Value::Float(i64::MAX as f64)), Ordering::Less);
+        assert_eq!(Value::Int(i64::MIN), Value::Float(i64::MIN as f64));
+        assert_eq!(Value::Int(1).total_cmp(&Value::Float(1.5)), Ordering::Less);
+        assert_eq!(Value::Int(-1).total_cmp(&Value::Float(-1.5)), Ordering::Greater);
+        assert_eq!(Value::Int(i64::MAX).total_cmp(&Value::Float(f64::NAN)), Ordering::Less);
+        assert_eq!(Value::Int(0), Value::Float(-0.0));
+        assert_eq!(Value::Float(0.0), Value::Float(-0.0));
+
+        let hash = |v: &Value| {
+            let mut h = DefaultHasher::new();
+            v.hash(&mut h);
+            h.finish()
+        };
+        assert_eq!(hash(&Value::Int(big)), hash(&float));
+        assert_eq!(hash(&Value::Int(0)), hash(&Value::Float(-0.0)));
+        let values = [Value::Int(big), Value::Int(big + 1), float, Value::Float(0.5)];
+        let set: HashSet<Value> = values.into_iter().collect();
+        assert_eq!(set.len(), 3);
+    }
 }
@@ -0,0 +1,466 @@
+//! Table storage: a heap file of row versions plus a B-tree on the i64 row key.
+//! Versions are appended and stamped here; which of them a transaction sees is decided in `txn`.
+//! The index always points at the newest version of a key; older versions hang off `prev`.
+//!
+//! Vacuum removes versions nobody can see any more. It frees their slots (slot numbers of
+//! survivors do not change), retargets or deletes index entries that pointed at them, unlinks
+//! `prev` pointers into them, compacts the pages and truncates empty pages off the end.
+
+use anyhow::{bail, ensure, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use super::btree::{BTree, RowRef};
+use super::fsm::FreeSpaceMap;
+use super::heap::{HeapFile, PageId};
+use super::page::{Page, PageFlags};
+use super::row::{clear_prev, set_xmax, ColumnType, RowHeader, Value};
+
+struct TableFiles {
+    heap: HeapFile,
+    index: BTree,
+    fsm: FreeSpaceMap,
+}
+
+/// What one vacuum pass over a table did.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct VacuumStats {
+    pub table: String,
+    pub pages_scanned: u32,
+    pub versions_removed: u64,
+    pub versions_kept: u64,
+    pub index_entries_removed: u64,
+    pub pages_truncated: u32,
+}
+
+/// One table's heap and primary-key index. Safe to share between sessions.
+pub struct Table {
+    name: String,
+    schema: Vec<ColumnType>,
+    key_col: usize,
+    files: Mutex<TableFiles>,
+    /// Held shared across multi-step version reads and writes, exclusively by vacuum.
+    access: RwLock<()>,
+    /// Versions stored, and versions superseded or deleted since the last vacuum
+    /// (autovacuum's trigger).
+    versions: AtomicU64,
+    dead_since_vacuum: AtomicU64,
+    max_txn_id_at_open: u64,
+    /// Next key `allocate_key` hands out: one past the largest key in the index.
+    next_key: AtomicI64,
+}
+
+impl Table {
+    /// Create empty heap and index files for `name` under `dir`. Overwrites existing files.
+    pub fn create<P: AsRef<Path>>(
+        dir: P,
+        name: &str,
+        schema: Vec<ColumnType>,
+        key_col: usize,
+    ) -> Result<Self> {
+        Self::check_key(&schema, key_col)?;
+        let dir = dir.as_ref();
+        let heap = HeapFile::create(Self::heap_path(dir, name))?;
+        let index = BTree::create(Self::index_path(dir, name))?;
+        Ok(Self::from_files(name, schema, key_col, heap, index, FreeSpaceMap::new(), 0, 1))
+    }
+
+    /// Open the heap and index files of an existing table.
+    pub fn open<P: AsRef<Path>>(
+        dir: P,
+        name: &str,
+        schema: Vec<ColumnType>,
+        key_col: usize,
+    ) -> Result<Self> {
+        Self::check_key(&schema, key_col)?;
+        let dir = dir.as_ref();
+        let mut heap = HeapFile::open(Self::heap_path(dir, name))?;
+        let mut index = BTree::open(Self::index_path(dir, name))?;
+        let next_key = index.max_key()?.map_or(1, |k| k.saturating_add(1));
+        let mut fsm = FreeSpaceMap::new();
+        let mut versions = 0;
+        let mut max_txn_id = 0;
+        for page_id in 0..heap.num_pages() {
+            let page = heap.read_page(page_id)?;
+            fsm.set(page_id, page.free_space());
+            for (_, bytes) in page.iter_slots() {
+                let h = RowHeader::read(bytes)?;
+                max_txn_id = max_txn_id.max(h.txn_id).max(h.xmax);
+                versions += 1;
+            }
+        }
+        let mut t = Self::from_files(name, schema, key_col, heap, index, fsm, versions, next_key);
+        t.max_txn_id_at_open = max_txn_id;
+        Ok(t)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn from_files(
+        name: &str,
+        schema: Vec<ColumnType>,
+        key_col: usize,
+        heap: HeapFile,
+        index: BTree,
+        fsm: FreeSpaceMap,
+        versions: u64,
+        next_key: i64,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            schema,
+            key_col,
+            files: Mutex::new(TableFiles { heap, index, fsm }),
+            access: RwLock::new(()),
+            versions: AtomicU64::new(versions),
+            dead_since_vacuum: AtomicU64::new(0),
+            max_txn_id_at_open: 0,
+            next_key: AtomicI64::new(next_key),
+        }
+    }
+
+    fn check_key(schema: &[ColumnType], key_col: usize) -> Result<()> {
+        ensure!(
+            schema.get(key_col) == Some(&ColumnType::Int),
+            "row key column {} must be INT",
+            key_col
+        );
+        Ok(())
+    }
+
+    pub fn heap_path(dir: &Path, name: &str) -> PathBuf {
+        dir.join(format!("{}.heap", name))
+    }
+
+    pub fn index_path(dir: &Path, name: &str) -> PathBuf {
+        dir.join(format!("{}.idx", name))
+    }
+
+    fn files(&self) -> MutexGuard<'_, TableFiles> {
+        self.files.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Shared access for a sequence of calls that follows `RowRef`s (index lookup, chain
+    /// walk, append + index update). Vacuum waits for it, so refs stay valid meanwhile.
+    /// Do not block on row locks while holding it.
+    pub fn access(&self) -> RwLockReadGuard<'_, ()> {
+        self.access.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn exclusive(&self) -> RwLockWriteGuard<'_, ()> {
+        self.access.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn schema(&self) -> &[ColumnType] {
+        &self.schema
+    }
+
+    /// Position of the i64 row key in the schema.
+    pub fn key_col(&self) -> usize {
+        self.key_col
+    }
+
+    /// Extract the row key from a full row.
+    pub fn key_of(&self, values: &[Value]) -> Result<i64> {
+        match values.get(self.key_col) {
+            Some(Value::Int(k)) => Ok(*k),
+            other => bail!("row key of {} must be an INT, got {:?}", self.name, other),
+        }
+    }
+
+    /// A key above every key stored so far, for tables keyed by a generated `rowid`.
+    pub fn allocate_key(&self) -> i64 {
+        self.next_key.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Store an encoded row version in the first page the free space map says has room,
+    /// or in a new page.
+    pub fn append_version(&self, bytes: &[u8]) -> Result<RowRef> {
+        let mut f = self.files();
+        self.versions.fetch_add(1, Ordering::Relaxed);
+        while let Some(page_id) = f.fsm.find(bytes.len()) {
+            let mut page = f.heap.read_page(page_id)?;
+            let slot = page.insert(bytes);
+            f.fsm.set(page_id, page.free_space());
+            if let Some(slot) = slot {
+                f.heap.write_page(page_id, &page)?;
+                return Ok(RowRef::new(page_id, slot as u16));
+            }
+        }
+        let mut page = Page::new(0, PageFlags::Heap);
+        let Some(slot) = page.insert(bytes) else {
+            bail!("row of {} bytes does not fit in a page", bytes.len());
+        };
+        let page_id = f.heap.append_page(&page)?;
+        f.fsm.set(page_id, page.free_space());
+        Ok(RowRef::new(page_id, slot as u16))
+    }
+
+    /// Encoded bytes of the version at `r`.
+    pub fn read_version(&self, r: RowRef) -> Result<Vec<u8>> {
+        let page = self.files().heap.read_page(r.page_id)?;
+        match page.get_slot(r.slot as usize) {
+            Some(b) => Ok(b.to_vec()),
+            None => bail!("no row version at ({}, {})", r.page_id, r.slot),
+        }
+    }
+
+    /// Mark the version at `r` as deleted/superseded by `xmax`.
+    pub fn set_xmax(&self, r: RowRef, xmax: u64) -> Result<()> {
+        let mut f = self.files();
+        let mut page = f.heap.read_page(r.page_id)?;
+        match page.get_slot_mut(r.slot as usize) {
+            Some(bytes) => set_xmax(bytes, xmax)?,
+            None => bail!("no row version at ({}, {})", r.page_id, r.slot),
+        }
+        f.heap.write_page(r.page_id, &page)?;
+        self.dead_since_vacuum.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Largest transaction id stamped on any version when the table was opened. Recovery
+    /// allocates new ids above it.
+    pub fn max_txn_id_at_open(&self) -> u64 {
+        self.max_txn_id_at_open
+    }
+
+    /// (versions stored, versions superseded or deleted since the last vacuum).
+    pub fn version_counts(&self) -> (u64, u64) {
+        (
+            self.versions.load(Ordering::Relaxed),
+            self.dead_since_vacuum.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Cache the heap and index pages in `pool`.
+    pub fn attach_buffer_pool(&self, pool: &std::sync::Arc<crate::buffer::BufferPool>) {
+        let mut f = self.files();
+        f.heap.attach_buffer_pool(pool);
+        f.index.attach_buffer_pool(pool);
+    }
+
+    /// Number of heap pages.
+    pub fn num_pages(&self) -> PageId {
+        self.files().heap.num_pages()
+    }
+
+    /// All versions stored on one heap page, dead or alive.
+    pub fn page_versions(&self, page_id: PageId) -> Result<Vec<(RowRef, Vec<u8>)>> {
+        let page = self.files().heap.read_page(page_id)?;
+        Ok(page
+            .iter_slots()
+            .map(|(slot, bytes)| (RowRef::new(page_id, slot as u16), bytes.to_vec()))
+            .collect())
+    }
+
+    /// Newest version of `key`, if the key was ever inserted.
+    pub fn index_get(&self, key: i64) -> Result<Option<RowRef>> {
+        self.files().index.get(key)
+    }
+
+    /// Point `key` at a new newest version, inserting the key if needed.
+    pub fn index_put(&self, key: i64, r: RowRef) -> Result<()> {
+        let mut f = self.files();
+        if !f.index.update(key, r)? {
+            f.index.insert(key, r)?;
+            self.next_key.fetch_max(key.saturating_add(1), Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Keys in [start, end) with their newest versions.
+    pub fn index_range(&self, start: i64, end: i64) -> Result<Vec<(i64, RowRef)>> {
+        self.files().index.range_scan(start, end)
+    }
+
+    /// Remove every version `is_dead` says no current or future snapshot can see. Versions
+    /// older than a dead one in the same chain must be dead too (or `is_dead` must hold for
+    /// them); survivors that pointed at a removed version lose their `prev` link.
+    pub fn vacuum(&self, is_dead: impl Fn(&RowHeader) -> bool) -> Result<VacuumStats> {
+        let _excl = self.exclusive();
+        let mut f = self.files();
+        let n = f.heap.num_pages();
+        let mut stats = VacuumStats {
+            table: self.name.clone(),
+            pages_scanned: n,
+            ..VacuumStats::default()
+        };
+
+        // Pass 1: find dead versions, remembering where their chains continue.
+        let mut dead: HashMap<RowRef, Option<RowRef>> = HashMap::new();
+        let mut unlink = Vec::new();
+        for page_id in 0..n {
+            let page = f.heap.read_page(page_id)?;
+            for (slot, bytes) in page.iter_slots() {
+                let h = RowHeader::read(bytes)?;
+                let prev = h.prev.map(|(p, s)| RowRef::new(p, s));
+                let r = RowRef::new(page_id, slot as u16);
+                if is_dead(&h) {
+                    dead.insert(r, prev);
+                } else if prev.is_some() {
+                    unlink.push((r, prev.unwrap()));
+                }
+            }
+        }
+
+        // Pass 2: index entries must point at the newest surviving version, if any.
+        let mut entries = f.index.range_scan(i64::MIN, i64::MAX)?;
+        if let Some(r) = f.index.get(i64::MAX)? {
+            entries.push((i64::MAX, r));
+        }
+        for (key, r) in entries {
+            let mut cur = Some(r);
+            while let Some(prev) = cur.and_then(|c| dead.get(&c)) {
+                cur = *prev;
+            }
+            match cur {
+                Some(s) if s == r => {}
+                Some(s) => {
+                    f.index.update(key, s)?;
+                }
+                None => {
+                    f.index.delete(key)?;
+                    stats.index_entries_removed += 1;
+                }
+            }
+        }
+
+        // Pass 3: free dead slots, cut links to them and compact the touched pages.
+        let mut touched: HashMap<PageId, Vec<(u16, bool)>> = HashMap::new();
+        for r in dead.keys() {
+            touched.entry(r.page_id).or_default().push((r.slot, true));
+        }
+        for (r, prev) in unlink {
+            if dead.contains_key(&prev) {
+                touched.entry(r.page_id).or_default().push((r.slot, false));
+            }
+        }
+        for (page_id, slots) in touched {
+            let mut page = f.heap.read_page(page_id)?;
+            for (slot, free) in slots {
+                if free {
+                    page.free_slot(slot as usize)?;
+                } else if let Some(bytes) = page.get_slot_mut(slot as usize) {
+                    clear_prev(bytes)?;
+                }
+            }
+            page.compact();
+            f.heap.write_page(page_id, &page)?;
+            f.fsm.set(page_id, page.free_space());
+        }
+        stats.versions_removed = dead.len() as u64;
+
+        // Pass 4: give empty trailing pages back to the file system.
+        let mut keep = n;
+        while keep > 0 && f.heap.read_page(keep - 1)?.live_slots() == 0 {
+            keep -= 1;
+        }
+        if keep < n {
+            f.heap.truncate(keep)?;
+            f.fsm.truncate(keep);
+            stats.pages_truncated = n - keep;
+        }
+
+        let kept = self.versions.load(Ordering::Relaxed).saturating_sub(stats.versions_removed);
+        stats.versions_kept = kept;
+        self.versions.store(kept, Ordering::Relaxed);
+        self.dead_since_vacuum.store(0, Ordering::Relaxed);
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::row::{encode, encode_with_header as row_encode_with_header};
+
+    #[test]
+    fn append_read_and_stamp_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let schema = vec![ColumnType::Int, ColumnType::Text];
+        let t = Table::create(dir.path(), "users", schema.clone(), 0).unwrap();
+        let mut refs = Vec::new();
+        for i in 0..600 {
+            let row = encode(&schema, &[Value::Int(i), Value::Text("x".repeat(20))], 1, 0).unwrap();
+            let r = t.append_version(&row).unwrap();
+            t.index_put(i, r).unwrap();
+            refs.push(r);
+        }
+        assert!(t.num_pages() > 1);
+        t.set_xmax(refs[10], 7).unwrap();
+        let h = RowHeader::read(&t.read_version(refs[10]).unwrap()).unwrap();
+        assert_eq!(h.xmax, 7);
+        assert_eq!(t.index_get(599).unwrap(), Some(refs[599]));
+        t.index_put(599, refs[0]).unwrap();
+        assert_eq!(t.index_get(599).unwrap(), Some(refs[0]));
+        assert_eq!(t.index_range(100, 110).unwrap().len(), 10);
+        let total: usize = (0..t.num_pages())
+            .map(|p| t.page_versions(p).unwrap().len())
+            .sum();
+        assert_eq!(total, 600);
+
+        drop(t);
+        let t = Table::open(dir.path(), "users", schema, 0).unwrap();
+        assert_eq!(t.index_get(10).unwrap(), Some(refs[10]));
+    }
+
+    #[test]
+    fn vacuum_removes_dead_versions_and_reuses_space() {
+        let dir = tempfile::tempdir().unwrap();
+        let schema = vec![ColumnType::Int, ColumnType::Text];
+        let t = Table::create(dir.path(), "t", schema.clone(), 0).unwrap();
+        let mut refs = Vec::new();
+        let mut newer = None;
+        for i in 0..600 {
+            let row = encode(&schema, &[Value::Int(i), Value::Text("x".repeat(20))], 1, 0).unwrap();
+            let r = t.append_version(&row).unwrap();
+            t.index_put(i, r).unwrap();
+            refs.push(r);
+            if i == 0 {
+                // Key 0 gets a newer version early on, so it lives on the first page.
+                let header = RowHeader {
+                    prev: Some((r.page_id, r.slot)),
+                    ..RowHeader::new(2)
+                };
+                let bytes = row_encode_with_header(&schema, &[Value::Int(0), Value::Text("y".into())], &header)
+                    .unwrap();
+                newer = Some(t.append_version(&bytes).unwrap());
+            }
+        }
+        let newer = newer.unwrap();
+        t.index_put(0, newer).unwrap();
+        // Keys 300.. are deleted.
+        t.set_xmax(refs[0], 2).unwrap();
+        for r in &refs[300..] {
+            t.set_xmax(*r, 3).unwrap();
+        }
+        let pages_before = t.num_pages();
+
+        let stats = t.vacuum(|h| h.xmax != 0).unwrap();
+        assert_eq!(stats.versions_removed, 301);
+        assert_eq!(stats.index_entries_removed, 300);
+        assert!(stats.pages_truncated > 0);
+        assert!(t.num_pages() < pages_before);
+        assert_eq!(t.index_get(450).unwrap(), None);
+        let h = RowHeader::read(&t.read_version(newer).unwrap()).unwrap();
+        assert_eq!(h.prev, None);
+        assert_eq!(t.version_counts(), (300, 0));
+
+        // The slot freed on page 0 is reused before the file grows.
+        let row = encode(&schema, &[Value::Int(1000), Value::Text("z".into())], 4, 0).unwrap();
+        assert_eq!(t.append_version(&row).unwrap(), refs[0]);
+        assert_eq!(t.read_version(refs[150]).unwrap()[..8], 1u64.to_le_bytes());
+    }
+
+    #[test]
+    fn key_must_be_int() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(Table::create(dir.path(), "t", vec![ColumnType::Text], 0).is_err());
+    }
+}
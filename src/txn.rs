@@ -1 +0,0 @@
-//! Transaction manager: BEGIN, COMMIT, ROLLBACK.
@@ -0,0 +1,508 @@
+//! Two-phase lock manager: table and row locks with intention modes, FIFO wait queues,
+//! lock wait timeouts and deadlock detection over the waits-for graph.
+//!
+//! Locks are held until `release_all` (strict 2PL). Waiters re-run cycle detection every
+//! `deadlock_check` interval; the youngest transaction in a cycle is chosen as victim and
+//! its pending `lock` call returns `TxnError::Deadlock`. The caller must then abort it.
+//!
+//! A waiting `lock` call blocks its thread on a condition variable, and with it the whole
+//! session, which is synchronous throughout. On a tokio runtime, sessions must therefore
+//! run under `spawn_blocking`, as the server's connections and autovacuum do: a lock wait
+//! on a worker thread would stall every task scheduled there, the holder's included.
+
+use anyhow::Result;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use super::{TxnError, TxnId};
+use crate::config::Config;
+
+/// Lock modes, weakest first. Intention modes are taken on a table before locking its rows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LockMode {
+    IntentionShared,
+    IntentionExclusive,
+    Shared,
+    SharedIntentionExclusive,
+    Exclusive,
+}
+
+impl LockMode {
+    /// Standard multi-granularity compatibility matrix.
+    pub fn compatible(self, other: LockMode) -> bool {
+        use LockMode::*;
+        matches!(
+            (self, other),
+            (IntentionShared, IntentionShared)
+                | (IntentionShared, IntentionExclusive)
+                | (IntentionShared, Shared)
+                | (IntentionShared, SharedIntentionExclusive)
+                | (IntentionExclusive, IntentionShared)
+                | (IntentionExclusive, IntentionExclusive)
+                | (Shared, IntentionShared)
+                | (Shared, Shared)
+                | (SharedIntentionExclusive, IntentionShared)
+        )
+    }
+
+    /// Whether holding `self` already grants everything `other` would.
+    pub fn covers(self, other: LockMode) -> bool {
+        use LockMode::*;
+        self == other
+            || match self {
+                Exclusive => true,
+                SharedIntentionExclusive => other != Exclusive,
+                Shared => other == IntentionShared,
+                IntentionExclusive => other == IntentionShared,
+                IntentionShared => false,
+            }
+    }
+
+    /// Weakest mode covering both (lock upgrade target).
+    pub fn join(self, other: LockMode) -> LockMode {
+        if self.covers(other) {
+            self
+        } else if other.covers(self) {
+            other
+        } else {
+            // Only IX + S (in either order) lack a common member of the pair.
+            LockMode::SharedIntentionExclusive
+        }
+    }
+
+    /// Intention mode to take on the table before locking one of its rows in `self`.
+    fn intention(self) -> LockMode {
+        match self {
+            LockMode::IntentionShared | LockMode::Shared => LockMode::IntentionShared,
+            _ => LockMode::IntentionExclusive,
+        }
+    }
+}
+
+/// Lockable resource. Rows are identified by their table and i64 row key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LockTarget {
+    Table(String),
+    Row { table: String, key: i64 },
+}
+
+impl fmt::Display for LockTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockTarget::Table(t) => write!(f, "table {}", t),
+            LockTarget::Row { table, key } => write!(f, "row {}[{}]", table, key),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Waiter {
+    txn: TxnId,
+    mode: LockMode,
+}
+
+#[derive(Debug, Default)]
+struct LockQueue {
+    granted: HashMap<TxnId, LockMode>,
+    waiting: VecDeque<Waiter>,
+}
+
+impl LockQueue {
+    fn compatible_with_granted(&self, txn: TxnId, mode: LockMode) -> bool {
+        self.granted
+            .iter()
+            .all(|(&holder, &held)| holder == txn || held.compatible(mode))
+    }
+
+    /// A waiter is granted when it is compatible with all holders and with every waiter
+    /// queued ahead of it (FIFO, so exclusive requests are not starved).
+    fn grantable(&self, txn: TxnId, mode: LockMode) -> bool {
+        if !self.compatible_with_granted(txn, mode) {
+            return false;
+        }
+        for w in &self.waiting {
+            if w.txn == txn {
+                return true;
+            }
+            if !w.mode.compatible(mode) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[derive(Debug, Default)]
+struct LockTable {
+    queues: HashMap<LockTarget, LockQueue>,
+    /// Targets each transaction holds a lock on.
+    held: HashMap<TxnId, HashSet<LockTarget>>,
+    /// Victims chosen by deadlock detection, with the cycle that doomed them.
+    victims: HashMap<TxnId, Vec<TxnId>>,
+}
+
+impl LockTable {
+    /// Edges txn -> txns it is waiting behind.
+    fn waits_for(&self) -> HashMap<TxnId, HashSet<TxnId>> {
+        let mut graph: HashMap<TxnId, HashSet<TxnId>> = HashMap::new();
+        for q in self.queues.values() {
+            for (i, w) in q.waiting.iter().enumerate() {
+                let edges = graph.entry(w.txn).or_default();
+                for (&holder, &held) in &q.granted {
+                    if holder != w.txn && !held.compatible(w.mode) {
+                        edges.insert(holder);
+                    }
+                }
+                for ahead in q.waiting.iter().take(i) {
+                    if ahead.txn != w.txn && !ahead.mode.compatible(w.mode) {
+                        edges.insert(ahead.txn);
+                    }
+                }
+            }
+        }
+        graph
+    }
+
+    /// Find a waits-for cycle through `start`, if any.
+    fn cycle_through(&self, start: TxnId) -> Option<Vec<TxnId>> {
+        let graph = self.waits_for();
+        let mut path = vec![start];
+        let mut visited = HashSet::new();
+        if Self::dfs(&graph, start, start, &mut path, &mut visited) {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    fn dfs(
+        graph: &HashMap<TxnId, HashSet<TxnId>>,
+        start: TxnId,
+        node: TxnId,
+        path: &mut Vec<TxnId>,
+        visited: &mut HashSet<TxnId>,
+    ) -> bool {
+        let Some(edges) = graph.get(&node) else {
+            return false;
+        };
+        let mut next: Vec<TxnId> = edges.iter().copied().collect();
+        next.sort_unstable();
+        for n in next {
+            if n == start {
+                return true;
+            }
+            if visited.insert(n) {
+                path.push(n);
+                if Self::dfs(graph, start, n, path, visited) {
+                    return true;
+                }
+                path.pop();
+            }
+        }
+        false
+    }
+
+    fn remove_waiter(&mut self, txn: TxnId, target: &LockTarget) {
+        if let Some(q) = self.queues.get_mut(target) {
+            q.waiting.retain(|w| w.txn != txn);
+            if q.granted.is_empty() && q.waiting.is_empty() {
+                self.queues.remove(target);
+            }
+        }
+    }
+}
+
+/// Lock manager shared by all sessions.
+pub struct LockManager {
+    table: Mutex<LockTable>,
+    cond: Condvar,
+    lock_timeout: Duration,
+    deadlock_check: Duration,
+}
+
+impl LockManager {
+    pub fn new(lock_timeout: Duration, deadlock_check: Duration) -> Self {
+        Self {
+            table: Mutex::new(LockTable::default()),
+            cond: Condvar::new(),
+            lock_timeout,
+            deadlock_check,
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(
+            Duration::from_millis(config.lock_timeout_ms),
+            Duration::from_millis(config.deadlock_check_ms),
+        )
+    }
+
+    fn state(&self) -> MutexGuard<'_, LockTable> {
+        self.table.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Lock a table.
+    pub fn lock_table(&self, txn: TxnId, table: &str, mode: LockMode) -> Result<()> {
+        self.lock(txn, LockTarget::Table(table.to_string()), mode)
+    }
+
+    /// Lock a row, first taking the matching intention lock on its table.
+    pub fn lock_row(&self, txn: TxnId, table: &str, key: i64, mode: LockMode) -> Result<()> {
+        self.lock_table(txn, table, mode.intention())?;
+        self.lock(
+            txn,
+            LockTarget::Row {
+                table: table.to_string(),
+                key,
+            },
+            mode,
+        )
+    }
+
+    /// Acquire (or upgrade to) `mode` on `target`, blocking the thread until granted,
+    /// timed out, or chosen as a deadlock victim.
+    pub fn lock(&self, txn: TxnId, target: LockTarget, mode: LockMode) -> Result<()> {
+        let mut st = self.state();
+        let q = st.queues.entry(target.clone()).or_default();
+        let wanted = match q.granted.get(&txn) {
+            Some(&held) if held.covers(mode) => return Ok(()),
+            Some(&held) => held.join(mode),
+            None => mode,
+        };
+        let upgrade = q.granted.contains_key(&txn);
+        if q.waiting.is_empty() && q.compatible_with_granted(txn, wanted) {
+            q.granted.insert(txn, wanted);
+            st.held.entry(txn).or_default().insert(target);
+            return Ok(());
+        }
+        // Upgraders already hold the resource; queueing them last would deadlock against
+        // everyone behind them, so they jump the queue.
+        let waiter = Waiter { txn, mode: wanted };
+        if upgrade {
+            q.waiting.push_front(waiter);
+        } else {
+            q.waiting.push_back(waiter);
+        }
+
+        let started = Instant::now();
+        loop {
+            if let Some(cycle) = st.victims.remove(&txn) {
+                st.remove_waiter(txn, &target);
+                self.cond.notify_all();
+                return Err(TxnError::Deadlock { victim: txn, cycle }.into());
+            }
+            let q = st.queues.get_mut(&target).expect("queue of a waiting lock");
+            if q.grantable(txn, wanted) {
+                q.waiting.retain(|w| w.txn != txn);
+                q.granted.insert(txn, wanted);
+                st.held.entry(txn).or_default().insert(target);
+                // Compatible waiters queued behind us may now proceed too.
+                self.cond.notify_all();
+                return Ok(());
+            }
+            let waited = started.elapsed();
+            if waited >= self.lock_timeout {
+                st.remove_waiter(txn, &target);
+                self.cond.notify_all();
+                return Err(TxnError::LockTimeout {
+                    txn,
+                    target,
+                    mode: wanted,
+                    waited_ms: waited.as_millis() as u64,
+                }
+                .into());
+            }
+            let wait = self.deadlock_check.min(self.lock_timeout - waited);
+            let (guard, res) = self
+                .cond
+                .wait_timeout(st, wait)
+                .unwrap_or_else(|e| e.into_inner());
+            st = guard;
+            if res.timed_out() {
+                self.detect_deadlock(&mut st, txn);
+            }
+        }
+    }
+
+    /// Look for a cycle through `txn`; if found, doom its youngest member.
+    fn detect_deadlock(&self, st: &mut LockTable, txn: TxnId) {
+        if let Some(cycle) = st.cycle_through(txn) {
+            let victim = *cycle.iter().max().expect("non-empty cycle");
+            tracing::warn!(victim, ?cycle, "deadlock detected");
+            st.victims.insert(victim, cycle);
+            self.cond.notify_all();
+        }
+    }
+
+    /// Release every lock held by `txn` (commit or abort) and wake waiters.
+    pub fn release_all(&self, txn: TxnId) {
+        let mut st = self.state();
+        let targets = st.held.remove(&txn).unwrap_or_default();
+        for target in targets {
+            if let Some(q) = st.queues.get_mut(&target) {
+                q.granted.remove(&txn);
+                if q.granted.is_empty() && q.waiting.is_empty() {
+                    st.queues.remove(&target);
+                }
+            }
+        }
+        st.victims.remove(&txn);
+        self.cond.notify_all();
+    }
+
+    /// Mode `txn` currently holds on `target`, if any.
+    pub fn held_mode(&self, txn: TxnId, target: &LockTarget) -> Option<LockMode> {
+        self.state()
+            .queues
+            .get(target)
+            .and_then(|q| q.granted.get(&txn).copied())
+    }
+
+    /// Number of locks held by `txn`.
+    pub fn num_held(&self, txn: TxnId) -> usize {
+        self.state().held.get(&txn).map_or(0, |h| h.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn manager(timeout_ms: u64) -> Arc<LockManager> {
+        Arc::new(LockManager::new(
+            Duration::from_millis(timeout_ms),
+            Duration::from_millis(10),
+        ))
+    }
+
+    fn txn_error(e: anyhow::Error) -> TxnError {
+        e.downcast::<TxnError>().expect("TxnError")
+    }
+
+    #[test]
+    fn mode_lattice() {
+        use LockMode::*;
+        assert!(Shared.compatible(IntentionShared));
+        assert!(!Shared.compatible(IntentionExclusive));
+        assert!(!Exclusive.compatible(IntentionShared));
+        assert_eq!(IntentionExclusive.join(Shared), SharedIntentionExclusive);
+        assert_eq!(Shared.join(Exclusive), Exclusive);
+        assert!(SharedIntentionExclusive.covers(Shared));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn shared_locks_coexist_exclusive_waits() {
+        let lm = manager(5_000);
+        lm.lock_row(1, "t", 7, LockMode::Shared).unwrap();
+        lm.lock_row(2, "t", 7, LockMode::Shared).unwrap();
+
+        let lm2 = lm.clone();
+        let writer = tokio::task::spawn_blocking(move || {
+            lm2.lock_row(3, "t", 7, LockMode::Exclusive).unwrap();
+            Instant::now()
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!writer.is_finished());
+        let released = Instant::now();
+        lm.release_all(1);
+        lm.release_all(2);
+        let acquired = writer.await.unwrap();
+        assert!(acquired >= released);
+        let row = LockTarget::Row {
+            table: "t".into(),
+            key: 7,
+        };
+        assert_eq!(lm.held_mode(3, &row), Some(LockMode::Exclusive));
+        assert_eq!(
+            lm.held_mode(3, &LockTarget::Table("t".into())),
+            Some(LockMode::IntentionExclusive)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn table_lock_conflicts_with_row_intention() {
+        let lm = manager(100);
+        lm.lock_row(1, "t", 1, LockMode::Exclusive).unwrap();
+        // A shared table lock (e.g. for DDL or a full scan) conflicts with the row writer's IX.
+        let lm2 = lm.clone();
+        let err = tokio::task::spawn_blocking(move || lm2.lock_table(2, "t", LockMode::Shared))
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert!(matches!(txn_error(err), TxnError::LockTimeout { txn: 2, .. }));
+        // Other rows remain available to other writers.
+        lm.lock_row(3, "t", 2, LockMode::Exclusive).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn deadlock_aborts_youngest() {
+        let lm = manager(5_000);
+        lm.lock_row(1, "acct", 1, LockMode::Exclusive).unwrap();
+        lm.lock_row(2, "acct", 2, LockMode::Exclusive).unwrap();
+
+        let lm1 = lm.clone();
+        let t1 = tokio::task::spawn_blocking(move || lm1.lock_row(1, "acct", 2, LockMode::Exclusive));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let lm2 = lm.clone();
+        let t2 = tokio::task::spawn_blocking(move || {
+            let r = lm2.lock_row(2, "acct", 1, LockMode::Exclusive);
+            // The victim aborts, releasing its locks so the survivor can continue.
+            lm2.release_all(2);
+            r
+        });
+
+        let err = t2.await.unwrap().unwrap_err();
+        match txn_error(err) {
+            TxnError::Deadlock { victim, cycle } => {
+                assert_eq!(victim, 2);
+                assert!(cycle.contains(&1) && cycle.contains(&2));
+            }
+            other => panic!("expected deadlock, got {:?}", other),
+        }
+        t1.await.unwrap().unwrap();
+        assert_eq!(lm.num_held(2), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn upgrade_waits_for_other_readers() {
+        let lm = manager(5_000);
+        lm.lock_table(1, "t", LockMode::Shared).unwrap();
+        lm.lock_table(2, "t", LockMode::Shared).unwrap();
+        let lm1 = lm.clone();
+        let up = tokio::task::spawn_blocking(move || {
+            lm1.lock_table(1, "t", LockMode::IntentionExclusive)
+        });
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(!up.is_finished());
+        lm.release_all(2);
+        up.await.unwrap().unwrap();
+        assert_eq!(
+            lm.held_mode(1, &LockTarget::Table("t".into())),
+            Some(LockMode::SharedIntentionExclusive)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn many_writers_serialize() {
+        let lm = manager(10_000);
+        let counter = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = Vec::new();
+        for txn in 1..=8u64 {
+            let lm = lm.clone();
+            let counter = counter.clone();
+            tasks.push(tokio::task::spawn_blocking(move || {
+                lm.lock_row(txn, "t", 0, LockMode::Exclusive).unwrap();
+                counter.lock().unwrap().push(txn);
+                std::thread::sleep(Duration::from_millis(2));
+                lm.release_all(txn);
+            }));
+        }
+        for t in tasks {
+            t.await.unwrap();
+        }
+        assert_eq!(counter.lock().unwrap().len(), 8);
+    }
+}
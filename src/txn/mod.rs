@@ -0,0 +1,1260 @@
+//! Transaction manager: BEGIN, COMMIT, ROLLBACK.
+//!
+//! Multi-version concurrency control over `storage::Table`: every write appends a new row
+//! version stamped with the writer's id, and each transaction reads through a `Snapshot`.
+//! Writers take exclusive row locks (first updater wins); under REPEATABLE READ and
+//! SERIALIZABLE a write to a row changed since the snapshot fails with a serialization error.
+//! SERIALIZABLE additionally runs SSI (see `ssi`) to rule out write skew.
+//!
+//! Savepoints run as subtransactions with their own ids: versions written after a savepoint
+//! are stamped with its subtransaction id, so ROLLBACK TO SAVEPOINT only has to mark those
+//! ids aborted for the heap and index changes made since to become invisible again.
+//!
+//! With a `Wal`, commits are logged (and flushed) before they become visible, and
+//! `TxnManager::recover` rebuilds commit status from the log after a restart. Ids that
+//! never reached a commit record count as aborted. `TxnManager::checkpoint` folds the
+//! outcomes of finished transactions older than every running one into ranges of committed
+//! ids, in the log and in memory.
+
+mod lock;
+mod ssi;
+
+pub use lock::{LockManager, LockMode, LockTarget};
+
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::config::Config;
+use crate::storage::{row_decode_values, row_encode_with_header, RowHeader, RowRef, Table, Value};
+use crate::wal::{Wal, WalRecord};
+use ssi::SsiState;
+
+/// Transaction id. Allocated monotonically; a larger id is a younger transaction.
+pub type TxnId = u64;
+
+/// Versions stamped with this id are visible to everyone (bootstrap / frozen rows).
+pub const FROZEN_TXN_ID: TxnId = 0;
+
+/// Transaction-level failures callers may want to match on (via `anyhow::Error::downcast_ref`).
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum TxnError {
+    #[error("deadlock detected: transaction {victim} aborted to break waits-for cycle {cycle:?}")]
+    Deadlock { victim: TxnId, cycle: Vec<TxnId> },
+    #[error("transaction {txn} timed out after {waited_ms} ms waiting for {mode:?} lock on {target}")]
+    LockTimeout {
+        txn: TxnId,
+        target: LockTarget,
+        mode: LockMode,
+        waited_ms: u64,
+    },
+    #[error("could not serialize access (transaction {txn}): {reason}")]
+    SerializationFailure { txn: TxnId, reason: String },
+}
+
+impl TxnError {
+    /// Whether retrying the whole transaction may succeed.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            TxnError::Deadlock { .. }
+                | TxnError::LockTimeout { .. }
+                | TxnError::SerializationFailure { .. }
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IsolationLevel {
+    /// New snapshot for every statement.
+    ReadCommitted,
+    /// One snapshot for the whole transaction (snapshot isolation).
+    #[default]
+    RepeatableRead,
+    /// Snapshot isolation plus SSI conflict tracking.
+    Serializable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxnStatus {
+    InProgress,
+    Committed,
+    Aborted,
+}
+
+/// Which transactions' effects are visible: those committed before the snapshot was taken.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// First id not yet allocated when the snapshot was taken.
+    pub xmax: TxnId,
+    /// Transactions in progress when the snapshot was taken.
+    pub active: HashSet<TxnId>,
+}
+
+impl Snapshot {
+    /// Whether `xid` had finished before this snapshot (it may have aborted).
+    pub fn precedes(&self, xid: TxnId) -> bool {
+        xid < self.xmax && !self.active.contains(&xid)
+    }
+}
+
+#[derive(Debug)]
+struct TxnTable {
+    next_id: TxnId,
+    status: HashMap<TxnId, TxnStatus>,
+    /// Ids below this finished before the last checkpoint and are not in `status`: those in
+    /// the `settled` ranges (start inclusive, end exclusive, in order) committed.
+    settled_below: TxnId,
+    settled: Vec<(TxnId, TxnId)>,
+    /// In-progress transactions and subtransactions.
+    active: BTreeSet<TxnId>,
+    /// Subtransaction ids of each top-level transaction, and the reverse mapping.
+    children: HashMap<TxnId, Vec<TxnId>>,
+    parent: HashMap<TxnId, TxnId>,
+    /// Per running top-level transaction, the oldest id its current snapshot may not see.
+    snapshot_xmin: HashMap<TxnId, TxnId>,
+}
+
+/// Allocates transaction ids, tracks their status and owns the lock manager and SSI state.
+pub struct TxnManager {
+    table: Mutex<TxnTable>,
+    locks: LockManager,
+    ssi: Mutex<SsiState>,
+    wal: Option<Mutex<Wal>>,
+}
+
+impl TxnManager {
+    /// In-memory manager: transaction outcomes are not logged.
+    pub fn new(config: &Config) -> Self {
+        Self::with_state(config, FROZEN_TXN_ID + 1, HashMap::new(), None)
+    }
+
+    /// Manager that logs commits to `wal`. `records` are the records recovered when the log
+    /// was opened; new ids start above them and above `min_next_id`.
+    pub fn recover(config: &Config, wal: Wal, records: &[WalRecord], min_next_id: TxnId) -> Self {
+        let mut status = HashMap::new();
+        let mut next_id = min_next_id.max(FROZEN_TXN_ID + 1);
+        let mut settled = (FROZEN_TXN_ID, Vec::new());
+        for record in records {
+            match record {
+                WalRecord::Commit { txn, subxacts } => {
+                    for &id in std::iter::once(txn).chain(subxacts) {
+                        status.insert(id, TxnStatus::Committed);
+                        next_id = next_id.max(id + 1);
+                    }
+                }
+                WalRecord::Sequence { .. } => {}
+                WalRecord::Checkpoint { horizon, committed, .. } => {
+                    settled = (*horizon, committed.clone());
+                    next_id = next_id.max(*horizon);
+                }
+            }
+        }
+        let mgr = Self::with_state(config, next_id, status, Some(wal));
+        let mut t = mgr.txns();
+        (t.settled_below, t.settled) = settled;
+        drop(t);
+        mgr
+    }
+
+    fn with_state(
+        config: &Config,
+        next_id: TxnId,
+        status: HashMap<TxnId, TxnStatus>,
+        wal: Option<Wal>,
+    ) -> Self {
+        Self {
+            table: Mutex::new(TxnTable {
+                next_id,
+                status,
+                settled_below: FROZEN_TXN_ID,
+                settled: Vec::new(),
+                active: BTreeSet::new(),
+                children: HashMap::new(),
+                parent: HashMap::new(),
+                snapshot_xmin: HashMap::new(),
+            }),
+            locks: LockManager::from_config(config),
+            ssi: Mutex::new(SsiState::default()),
+            wal: wal.map(Mutex::new),
+        }
+    }
+
+    fn txns(&self) -> MutexGuard<'_, TxnTable> {
+        self.table.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn ssi(&self) -> MutexGuard<'_, SsiState> {
+        self.ssi.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn locks(&self) -> &LockManager {
+        &self.locks
+    }
+
+    /// Start a transaction.
+    pub fn begin(self: &Arc<Self>, isolation: IsolationLevel) -> Transaction {
+        // Lock order is always ssi -> txns.
+        let mut ssi = self.ssi();
+        let mut t = self.txns();
+        let id = t.next_id;
+        t.next_id += 1;
+        t.status.insert(id, TxnStatus::InProgress);
+        let snapshot = Self::snapshot_locked(&t);
+        t.snapshot_xmin.insert(id, Self::xmin_of(&snapshot));
+        t.active.insert(id);
+        if isolation == IsolationLevel::Serializable {
+            ssi.register(id);
+        }
+        Transaction {
+            id,
+            mgr: Arc::clone(self),
+            view: ReadView {
+                id,
+                isolation,
+                snapshot,
+                own: HashSet::from([id]),
+                mgr: Arc::clone(self),
+            },
+            finished: false,
+            savepoints: Vec::new(),
+            deferred: Vec::new(),
+            all_constraints: None,
+            constraint_modes: HashMap::new(),
+        }
+    }
+
+    fn snapshot_locked(t: &TxnTable) -> Snapshot {
+        Snapshot {
+            xmax: t.next_id,
+            active: t.active.iter().copied().collect(),
+        }
+    }
+
+    fn xmin_of(snapshot: &Snapshot) -> TxnId {
+        snapshot.active.iter().copied().min().unwrap_or(snapshot.xmax)
+    }
+
+    /// A fresh snapshot of the current state.
+    pub fn snapshot(&self) -> Snapshot {
+        Self::snapshot_locked(&self.txns())
+    }
+
+    /// A fresh snapshot for running transaction `id` (READ COMMITTED statements).
+    fn refresh_snapshot(&self, id: TxnId) -> Snapshot {
+        let mut t = self.txns();
+        let snapshot = Self::snapshot_locked(&t);
+        t.snapshot_xmin.insert(id, Self::xmin_of(&snapshot));
+        snapshot
+    }
+
+    /// Every transaction below this id has finished before any current snapshot was taken,
+    /// so running and future transactions agree on whether it committed.
+    pub fn vacuum_horizon(&self) -> TxnId {
+        let t = self.txns();
+        t.snapshot_xmin.values().copied().min().unwrap_or(t.next_id)
+    }
+
+    /// Whether no current or future snapshot can see the version: its writer aborted, or
+    /// its deleter committed below `horizon` (see `vacuum_horizon`).
+    pub fn is_dead(&self, h: &RowHeader, horizon: TxnId) -> bool {
+        h.tombstone != 0
+            || self.status(h.txn_id) == TxnStatus::Aborted
+            || (h.xmax != 0 && h.xmax < horizon && self.status(h.xmax) == TxnStatus::Committed)
+    }
+
+    /// Write and flush the commit record of `id` before its effects become visible.
+    fn log_commit(&self, id: TxnId) -> Result<()> {
+        let Some(wal) = &self.wal else {
+            return Ok(());
+        };
+        let subxacts = {
+            let t = self.txns();
+            t.children
+                .get(&id)
+                .into_iter()
+                .flatten()
+                .copied()
+                .filter(|s| t.status.get(s) == Some(&TxnStatus::InProgress))
+                .collect()
+        };
+        let mut wal = wal.lock().unwrap_or_else(|e| e.into_inner());
+        wal.append(&WalRecord::Commit { txn: id, subxacts })?;
+        wal.flush()
+    }
+
+    /// Write and flush `record`, which no transaction's outcome depends on (see `sequence`).
+    pub fn log(&self, record: &WalRecord) -> Result<()> {
+        let Some(wal) = &self.wal else {
+            return Ok(());
+        };
+        let mut wal = wal.lock().unwrap_or_else(|e| e.into_inner());
+        wal.append(record)?;
+        wal.flush()
+    }
+
+    /// Replace the log with a checkpoint: the outcomes of the transactions below the oldest
+    /// running one (the horizon) as ranges of committed ids, where each sequence got, and the
+    /// commits at or above the horizon. The status map drops the entries the ranges now
+    /// answer for. Returns how many records the log shrank by.
+    pub fn checkpoint(&self) -> Result<usize> {
+        let Some(wal) = &self.wal else {
+            return Ok(0);
+        };
+        // Holding the log keeps commits out until the status map matches the new log.
+        let mut wal = wal.lock().unwrap_or_else(|e| e.into_inner());
+        let mut horizon = {
+            let t = self.txns();
+            t.active.first().copied().unwrap_or(t.next_id)
+        };
+        let records = wal.records()?;
+        let mut committed = Vec::new();
+        let mut sequences = BTreeMap::new();
+        let mut kept = Vec::new();
+        for record in &records {
+            match record {
+                WalRecord::Commit { txn, subxacts } => {
+                    let mut above = Vec::new();
+                    for &id in std::iter::once(txn).chain(subxacts) {
+                        if id < horizon {
+                            committed.push((id, id + 1));
+                        } else {
+                            above.push(id);
+                        }
+                    }
+                    if let Some((&txn, subxacts)) = above.split_first() {
+                        let subxacts = subxacts.to_vec();
+                        kept.push(WalRecord::Commit { txn, subxacts });
+                    }
+                }
+                WalRecord::Sequence { name, last, called } => {
+                    sequences.insert(name.clone(), (*last, *called));
+                }
+                WalRecord::Checkpoint {
+                    horizon: previous,
+                    committed: ranges,
+                    sequences: positions,
+                } => {
+                    horizon = horizon.max(*previous);
+                    committed.extend_from_slice(ranges);
+                    for (name, last, called) in positions {
+                        sequences.insert(name.clone(), (*last, *called));
+                    }
+                }
+            }
+        }
+        committed.sort_unstable();
+        let mut merged: Vec<(TxnId, TxnId)> = Vec::new();
+        for (start, end) in committed {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        let checkpoint = WalRecord::Checkpoint {
+            horizon,
+            committed: merged.clone(),
+            sequences: sequences.into_iter().map(|(name, (last, called))| (name, last, called)).collect(),
+        };
+        kept.insert(0, checkpoint);
+        wal.rewrite(&kept)?;
+        let mut t = self.txns();
+        t.status.retain(|&id, _| id >= horizon);
+        t.settled_below = horizon;
+        t.settled = merged;
+        tracing::debug!(horizon, before = records.len(), after = kept.len(), "checkpoint");
+        Ok(records.len().saturating_sub(kept.len()))
+    }
+
+    /// Commit status of `xid`. Unknown ids below the high-water mark are treated as aborted.
+    pub fn status(&self, xid: TxnId) -> TxnStatus {
+        if xid == FROZEN_TXN_ID {
+            return TxnStatus::Committed;
+        }
+        let t = self.txns();
+        if let Some(&status) = t.status.get(&xid) {
+            return status;
+        }
+        let i = t.settled.partition_point(|&(start, _)| start <= xid);
+        if xid < t.settled_below && i > 0 && xid < t.settled[i - 1].1 {
+            TxnStatus::Committed
+        } else {
+            TxnStatus::Aborted
+        }
+    }
+
+    /// Oldest transaction still running, if any.
+    pub fn oldest_active(&self) -> Option<TxnId> {
+        self.txns().active.iter().next().copied()
+    }
+
+    /// Top-level transaction a (sub)transaction id belongs to.
+    pub fn top_level(&self, xid: TxnId) -> TxnId {
+        self.txns().parent.get(&xid).copied().unwrap_or(xid)
+    }
+
+    fn begin_subxact(&self, top: TxnId) -> TxnId {
+        let mut t = self.txns();
+        let id = t.next_id;
+        t.next_id += 1;
+        t.status.insert(id, TxnStatus::InProgress);
+        t.active.insert(id);
+        t.children.entry(top).or_default().push(id);
+        t.parent.insert(id, top);
+        id
+    }
+
+    fn abort_subxacts(&self, subs: &[TxnId]) {
+        let mut t = self.txns();
+        for sub in subs {
+            t.status.insert(*sub, TxnStatus::Aborted);
+            t.active.remove(sub);
+        }
+    }
+
+    /// End a top-level transaction. Subtransactions not already rolled back share its fate.
+    fn finish(&self, id: TxnId, status: TxnStatus) {
+        let mut t = self.txns();
+        t.status.insert(id, status);
+        t.active.remove(&id);
+        t.snapshot_xmin.remove(&id);
+        for sub in t.children.remove(&id).unwrap_or_default() {
+            if t.status.get(&sub) == Some(&TxnStatus::InProgress) {
+                t.status.insert(sub, status);
+            }
+            t.active.remove(&sub);
+        }
+    }
+}
+
+/// An open savepoint and the subtransaction running under it.
+#[derive(Debug)]
+struct Savepoint {
+    name: String,
+    xid: TxnId,
+    /// Subtransactions of savepoints released into this one.
+    released: Vec<TxnId>,
+    /// Checks deferred before the savepoint was set.
+    deferred: usize,
+}
+
+/// A constraint check put off until commit: that constraint `constraint` of `table` holds
+/// for the row with key `key`, or (None) for the rows with values `values` in the columns
+/// it constrains.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeferredCheck {
+    pub table: String,
+    pub constraint: String,
+    pub key: Option<i64>,
+    pub values: Vec<Value>,
+}
+
+/// A running transaction. Dropping it without committing rolls it back.
+pub struct Transaction {
+    id: TxnId,
+    mgr: Arc<TxnManager>,
+    view: ReadView,
+    finished: bool,
+    savepoints: Vec<Savepoint>,
+    deferred: Vec<DeferredCheck>,
+    /// Constraint modes set by SET CONSTRAINTS: of all deferrable constraints, then by name.
+    all_constraints: Option<bool>,
+    constraint_modes: HashMap<String, bool>,
+}
+
+/// What deciding row visibility takes from a transaction. Parallel workers read through a
+/// clone of it on the transaction's behalf (SSI reads included).
+#[derive(Clone)]
+pub struct ReadView {
+    id: TxnId,
+    isolation: IsolationLevel,
+    snapshot: Snapshot,
+    /// Our top-level id plus every subtransaction id not rolled back.
+    own: HashSet<TxnId>,
+    mgr: Arc<TxnManager>,
+}
+
+impl ReadView {
+    fn serializable(&self) -> bool {
+        self.isolation == IsolationLevel::Serializable
+    }
+
+    /// Whether the effects of `xid` are visible to this transaction.
+    fn sees(&self, xid: TxnId) -> bool {
+        xid == FROZEN_TXN_ID
+            || self.own.contains(&xid)
+            || (self.snapshot.precedes(xid) && self.mgr.status(xid) == TxnStatus::Committed)
+    }
+
+    /// Written by a transaction that is neither us, visible to us, nor aborted.
+    fn concurrent(&self, xid: TxnId) -> bool {
+        xid != FROZEN_TXN_ID
+            && !self.own.contains(&xid)
+            && !self.sees(xid)
+            && self.mgr.status(xid) != TxnStatus::Aborted
+    }
+
+    /// MVCC visibility of a row version.
+    pub fn visible(&self, h: &RowHeader) -> bool {
+        h.tombstone == 0 && self.sees(h.txn_id) && (h.xmax == 0 || !self.sees(h.xmax))
+    }
+
+    /// SSI read bookkeeping for a version we looked at. `read` is true if we returned it.
+    fn note_read(&self, table: &Table, r: RowRef, h: &RowHeader, read: bool) -> Result<()> {
+        if !self.serializable() {
+            return Ok(());
+        }
+        // A newer version exists that we cannot see: we read "before" its writer.
+        let writer = if read {
+            (h.xmax != 0 && self.concurrent(h.xmax)).then_some(h.xmax)
+        } else {
+            self.concurrent(h.txn_id).then_some(h.txn_id)
+        };
+        let mut ssi = self.mgr.ssi();
+        if read {
+            ssi.siread_tuple(self.id, table.name(), r);
+        }
+        if let Some(w) = writer {
+            ssi.add_conflict(self.id, self.mgr.top_level(w), self.id)?;
+        }
+        Ok(())
+    }
+
+    fn note_range(&self, table: &Table, start: i64, end: i64) {
+        if self.serializable() {
+            self.mgr.ssi().siread_range(self.id, table.name(), start, end);
+        }
+    }
+
+    /// Record that the caller is about to read every row of `table` page by page with
+    /// `visit_page`, so SERIALIZABLE conflict tracking also covers rows inserted later.
+    pub fn note_table_scan(&self, table: &Table) {
+        self.note_range(table, i64::MIN, i64::MAX);
+    }
+
+    /// Pass each visible row on one heap page to `f`, encoded, with its location; lets a
+    /// caller decode only what it needs.
+    pub fn visit_page(
+        &self,
+        table: &Table,
+        page_id: u32,
+        mut f: impl FnMut(RowRef, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        let _access = table.access();
+        if page_id >= table.num_pages() {
+            // Truncated by vacuum: it held nothing we could see.
+            return Ok(());
+        }
+        for (r, bytes) in table.page_versions(page_id)? {
+            let h = RowHeader::read(&bytes)?;
+            let visible = self.visible(&h);
+            self.note_read(table, r, &h, visible)?;
+            if visible {
+                f(r, &bytes)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Transaction {
+    pub fn id(&self) -> TxnId {
+        self.id
+    }
+
+    pub fn isolation(&self) -> IsolationLevel {
+        self.view.isolation
+    }
+
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.view.snapshot
+    }
+
+    /// What this transaction sees, for reading on its behalf.
+    pub fn read_view(&self) -> &ReadView {
+        &self.view
+    }
+
+    /// Call at the start of each statement; READ COMMITTED takes a new snapshot.
+    pub fn begin_statement(&mut self) {
+        if self.view.isolation == IsolationLevel::ReadCommitted {
+            self.view.snapshot = self.mgr.refresh_snapshot(self.id);
+        }
+    }
+
+    /// Id stamped on versions this transaction writes: the innermost savepoint's.
+    fn xid(&self) -> TxnId {
+        self.savepoints.last().map_or(self.id, |sp| sp.xid)
+    }
+
+    fn is_own(&self, xid: TxnId) -> bool {
+        self.view.own.contains(&xid)
+    }
+
+    /// SAVEPOINT name. Reusing a name shadows the older savepoint, as in SQL.
+    pub fn savepoint(&mut self, name: &str) {
+        let xid = self.mgr.begin_subxact(self.id);
+        self.view.own.insert(xid);
+        self.savepoints.push(Savepoint {
+            name: name.to_string(),
+            xid,
+            released: Vec::new(),
+            deferred: self.deferred.len(),
+        });
+    }
+
+    fn find_savepoint(&self, name: &str) -> Result<usize> {
+        match self.savepoints.iter().rposition(|sp| sp.name == name) {
+            Some(i) => Ok(i),
+            None => bail!("savepoint {} does not exist", name),
+        }
+    }
+
+    /// ROLLBACK TO SAVEPOINT name: undo everything written since the savepoint was set.
+    /// The savepoint itself stays open; locks taken since are kept until the transaction ends.
+    pub fn rollback_to_savepoint(&mut self, name: &str) -> Result<()> {
+        let i = self.find_savepoint(name)?;
+        self.deferred.truncate(self.savepoints[i].deferred);
+        let undone: Vec<TxnId> = self
+            .savepoints
+            .drain(i..)
+            .flat_map(|sp| std::iter::once(sp.xid).chain(sp.released))
+            .collect();
+        self.mgr.abort_subxacts(&undone);
+        for xid in &undone {
+            self.view.own.remove(xid);
+        }
+        self.savepoint(name);
+        Ok(())
+    }
+
+    /// RELEASE SAVEPOINT name: keep its changes, merging them into the enclosing level.
+    /// Savepoints set after it are released as well.
+    pub fn release_savepoint(&mut self, name: &str) -> Result<()> {
+        let i = self.find_savepoint(name)?;
+        let merged: Vec<TxnId> = self
+            .savepoints
+            .drain(i..)
+            .flat_map(|sp| std::iter::once(sp.xid).chain(sp.released))
+            .collect();
+        if let Some(parent) = self.savepoints.last_mut() {
+            parent.released.extend(merged);
+        }
+        Ok(())
+    }
+
+    /// Check `check` at commit.
+    pub fn defer(&mut self, check: DeferredCheck) {
+        self.deferred.push(check);
+    }
+
+    /// The checks deferred so far, which the caller now runs or defers again.
+    pub fn take_deferred(&mut self) -> Vec<DeferredCheck> {
+        std::mem::take(&mut self.deferred)
+    }
+
+    /// SET CONSTRAINTS: check the deferrable constraints called `names`, or all of them
+    /// (None), at commit (`deferred`) or as each statement ends.
+    pub fn set_constraints(&mut self, names: Option<Vec<String>>, deferred: bool) {
+        match names {
+            Some(names) => self.constraint_modes.extend(names.into_iter().map(|n| (n, deferred))),
+            None => {
+                self.constraint_modes.clear();
+                self.all_constraints = Some(deferred);
+            }
+        }
+    }
+
+    /// Whether SET CONSTRAINTS deferred deferrable constraint `name` (None if it did not
+    /// mention it).
+    pub fn constraint_deferred(&self, name: &str) -> Option<bool> {
+        self.constraint_modes.get(name).copied().or(self.all_constraints)
+    }
+
+    fn sees(&self, xid: TxnId) -> bool {
+        self.view.sees(xid)
+    }
+
+    /// MVCC visibility of a row version.
+    pub fn visible(&self, h: &RowHeader) -> bool {
+        self.view.visible(h)
+    }
+
+    fn note_read(&self, table: &Table, r: RowRef, h: &RowHeader, read: bool) -> Result<()> {
+        self.view.note_read(table, r, h, read)
+    }
+
+    fn note_range(&self, table: &Table, start: i64, end: i64) {
+        self.view.note_range(table, start, end)
+    }
+
+    /// SSI write bookkeeping: concurrent readers of this key/version now precede us.
+    fn note_write(&self, table: &Table, key: i64, version: Option<RowRef>) -> Result<()> {
+        let mut ssi = self.mgr.ssi();
+        if !ssi.is_tracked(self.id) {
+            return Ok(());
+        }
+        for reader in ssi.readers_of(self.id, table.name(), key, version) {
+            ssi.add_conflict(reader, self.id, self.id)?;
+        }
+        Ok(())
+    }
+
+    /// Follow the version chain of `key` to the version this transaction sees.
+    fn find_visible(&self, table: &Table, key: i64) -> Result<Option<(RowRef, Vec<u8>)>> {
+        let mut cur = table.index_get(key)?;
+        while let Some(r) = cur {
+            let bytes = table.read_version(r)?;
+            let h = RowHeader::read(&bytes)?;
+            if self.visible(&h) {
+                self.note_read(table, r, &h, true)?;
+                return Ok(Some((r, bytes)));
+            }
+            self.note_read(table, r, &h, false)?;
+            cur = h.prev.map(|(page_id, slot)| RowRef::new(page_id, slot));
+        }
+        Ok(None)
+    }
+
+    /// Newest version of `key` not written by an aborted transaction.
+    fn newest_version(&self, table: &Table, key: i64) -> Result<Option<(RowRef, RowHeader)>> {
+        let mut cur = table.index_get(key)?;
+        while let Some(r) = cur {
+            let h = RowHeader::read(&table.read_version(r)?)?;
+            if h.tombstone == 0
+                && (self.is_own(h.txn_id) || self.mgr.status(h.txn_id) != TxnStatus::Aborted)
+            {
+                return Ok(Some((r, h)));
+            }
+            cur = h.prev.map(|(page_id, slot)| RowRef::new(page_id, slot));
+        }
+        Ok(None)
+    }
+
+    /// Whether the version was deleted by a transaction that did not abort.
+    fn deleted(&self, h: &RowHeader) -> bool {
+        h.xmax != 0 && (self.is_own(h.xmax) || self.mgr.status(h.xmax) != TxnStatus::Aborted)
+    }
+
+    fn serialization_failure(&self, reason: &str) -> anyhow::Error {
+        TxnError::SerializationFailure {
+            txn: self.id,
+            reason: reason.to_string(),
+        }
+        .into()
+    }
+
+    /// Take the exclusive row lock on `key`. Must not be called while holding
+    /// `Table::access`, so a blocked writer never holds up vacuum.
+    fn lock_key(&self, table: &Table, key: i64) -> Result<()> {
+        self.mgr
+            .locks
+            .lock_row(self.id, table.name(), key, LockMode::Exclusive)
+    }
+
+    /// Newest live version of the locked `key`, checking for concurrent updates our
+    /// snapshot cannot see.
+    fn check_for_write(&self, table: &Table, key: i64) -> Result<Option<(RowRef, RowHeader)>> {
+        let Some((r, h)) = self.newest_version(table, key)? else {
+            return Ok(None);
+        };
+        let snapshot_bound = self.view.isolation != IsolationLevel::ReadCommitted;
+        if self.deleted(&h) {
+            if snapshot_bound && !self.sees(h.xmax) {
+                return Err(self.serialization_failure("row was deleted by a concurrent transaction"));
+            }
+            return Ok(None);
+        }
+        if snapshot_bound && !self.sees(h.txn_id) {
+            return Err(self.serialization_failure("row was updated by a concurrent transaction"));
+        }
+        Ok(Some((r, h)))
+    }
+
+    /// Read the row with key `key`.
+    pub fn get(&mut self, table: &Table, key: i64) -> Result<Option<Vec<Value>>> {
+        let _access = table.access();
+        self.note_range(table, key, key.saturating_add(1));
+        match self.find_visible(table, key)? {
+            Some((_, bytes)) => Ok(Some(row_decode_values(table.schema(), &bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Read the newest live version of `key`, locking it shared until the transaction ends
+    /// so no one else can change or delete it meanwhile (as foreign key checks need).
+    pub fn get_for_share(&mut self, table: &Table, key: i64) -> Result<Option<Vec<Value>>> {
+        self.get_locked(table, key, LockMode::Shared)
+    }
+
+    /// Read the newest live version of `key`, locking it in `mode` until the transaction
+    /// ends (`SELECT ... FOR SHARE` takes Shared, `FOR UPDATE` Exclusive).
+    pub fn get_locked(&mut self, table: &Table, key: i64, mode: LockMode) -> Result<Option<Vec<Value>>> {
+        self.mgr.locks.lock_row(self.id, table.name(), key, mode)?;
+        let _access = table.access();
+        match self.check_for_write(table, key)? {
+            Some((r, h)) => {
+                self.note_read(table, r, &h, true)?;
+                Ok(Some(row_decode_values(table.schema(), &table.read_version(r)?)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Visible rows on one heap page, with their versions' locations.
+    pub fn scan_page(
+        &mut self,
+        table: &Table,
+        page_id: u32,
+    ) -> Result<Vec<(RowRef, Vec<Value>)>> {
+        let mut out = Vec::new();
+        self.view.visit_page(table, page_id, |r, bytes| {
+            out.push((r, row_decode_values(table.schema(), bytes)?));
+            Ok(())
+        })?;
+        Ok(out)
+    }
+
+    /// Record that the caller is about to read every row of `table` page by page with
+    /// `scan_page`, so SERIALIZABLE conflict tracking also covers rows inserted later.
+    pub fn note_table_scan(&self, table: &Table) {
+        self.view.note_table_scan(table)
+    }
+
+    /// Lock all of `table` in `mode` until the transaction ends.
+    pub fn lock_table(&self, table: &Table, mode: LockMode) -> Result<()> {
+        self.mgr.locks.lock_table(self.id, table.name(), mode)
+    }
+
+    /// Full scan of visible rows, in heap order.
+    pub fn scan(&mut self, table: &Table) -> Result<Vec<Vec<Value>>> {
+        self.note_table_scan(table);
+        let mut rows = Vec::new();
+        for page_id in 0..table.num_pages() {
+            rows.extend(self.scan_page(table, page_id)?.into_iter().map(|(_, v)| v));
+        }
+        Ok(rows)
+    }
+
+    /// Visible rows with keys in [start, end), in key order.
+    pub fn range(&mut self, table: &Table, start: i64, end: i64) -> Result<Vec<Vec<Value>>> {
+        let _access = table.access();
+        self.note_range(table, start, end);
+        let mut rows = Vec::new();
+        for (key, _) in table.index_range(start, end)? {
+            if let Some((_, bytes)) = self.find_visible(table, key)? {
+                rows.push(row_decode_values(table.schema(), &bytes)?);
+            }
+        }
+        Ok(rows)
+    }
+
+    /// Insert a new row. Fails if a live row with the same key exists.
+    pub fn insert(&mut self, table: &Table, values: Vec<Value>) -> Result<()> {
+        let key = table.key_of(&values)?;
+        if self.insert_or_existing(table, values)?.is_some() {
+            bail!("duplicate key {} in {}", key, table.name());
+        }
+        Ok(())
+    }
+
+    /// Insert a new row unless a live row with the same key exists, in which case that row
+    /// is returned instead, still locked, so the caller may update it (INSERT ... ON CONFLICT).
+    pub fn insert_or_existing(&mut self, table: &Table, values: Vec<Value>) -> Result<Option<Vec<Value>>> {
+        let key = table.key_of(&values)?;
+        self.lock_key(table, key)?;
+        let _access = table.access();
+        let prev = match self.newest_version(table, key)? {
+            None => None,
+            // Deleted, even by a transaction we cannot see: the key is free regardless.
+            Some((r, h)) if self.deleted(&h) => Some(r),
+            Some((r, h)) => {
+                // Inserted (or updated) by a transaction we cannot see: its row would be live
+                // next to ours.
+                if self.view.isolation != IsolationLevel::ReadCommitted && !self.sees(h.txn_id) {
+                    return Err(self.serialization_failure("row was inserted by a concurrent transaction"));
+                }
+                self.note_read(table, r, &h, true)?;
+                let bytes = table.read_version(r)?;
+                return Ok(Some(row_decode_values(table.schema(), &bytes)?));
+            }
+        };
+        self.note_write(table, key, None)?;
+        let header = RowHeader {
+            prev: prev.map(|r| (r.page_id, r.slot)),
+            ..RowHeader::new(self.xid())
+        };
+        let bytes = row_encode_with_header(table.schema(), &values, &header)?;
+        let r = table.append_version(&bytes)?;
+        table.index_put(key, r)?;
+        Ok(None)
+    }
+
+    /// Replace the row with key `key`. Returns false if there is no such row.
+    pub fn update(&mut self, table: &Table, key: i64, values: Vec<Value>) -> Result<bool> {
+        let new_key = table.key_of(&values)?;
+        if new_key != key {
+            if !self.delete(table, key)? {
+                return Ok(false);
+            }
+            self.insert(table, values)?;
+            return Ok(true);
+        }
+        self.lock_key(table, key)?;
+        let _access = table.access();
+        let Some((old, _)) = self.check_for_write(table, key)? else {
+            return Ok(false);
+        };
+        self.note_write(table, key, Some(old))?;
+        let header = RowHeader {
+            prev: Some((old.page_id, old.slot)),
+            ..RowHeader::new(self.xid())
+        };
+        let bytes = row_encode_with_header(table.schema(), &values, &header)?;
+        let r = table.append_version(&bytes)?;
+        table.set_xmax(old, self.xid())?;
+        table.index_put(key, r)?;
+        Ok(true)
+    }
+
+    /// Delete the row with key `key`. Returns false if there is no such row.
+    pub fn delete(&mut self, table: &Table, key: i64) -> Result<bool> {
+        self.lock_key(table, key)?;
+        let _access = table.access();
+        let Some((old, _)) = self.check_for_write(table, key)? else {
+            return Ok(false);
+        };
+        self.note_write(table, key, Some(old))?;
+        table.set_xmax(old, self.xid())?;
+        Ok(true)
+    }
+
+    /// Commit. Serializable transactions may fail here with a serialization error,
+    /// in which case the transaction has been rolled back.
+    pub fn commit(mut self) -> Result<()> {
+        {
+            let mut ssi = self.mgr.ssi();
+            if ssi.is_tracked(self.id) {
+                if let Err(e) = ssi.pre_commit(self.id) {
+                    drop(ssi);
+                    self.abort();
+                    return Err(e.into());
+                }
+            }
+            if let Err(e) = self.mgr.log_commit(self.id) {
+                drop(ssi);
+                self.abort();
+                return Err(e);
+            }
+            if ssi.is_tracked(self.id) {
+                ssi.committed(self.id);
+            }
+            self.mgr.finish(self.id, TxnStatus::Committed);
+        }
+        self.finished = true;
+        self.mgr.locks.release_all(self.id);
+        Ok(())
+    }
+
+    /// Roll back all changes.
+    pub fn rollback(mut self) {
+        self.abort();
+    }
+
+    fn abort(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.mgr.ssi().aborted(self.id);
+        self.mgr.finish(self.id, TxnStatus::Aborted);
+        self.mgr.locks.release_all(self.id);
+        self.finished = true;
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::ColumnType;
+    use crate::wal::Wal;
+
+    fn setup() -> (tempfile::TempDir, Arc<TxnManager>, Table) {
+        let dir = tempfile::tempdir().unwrap();
+        let table = Table::create(
+            dir.path(),
+            "accounts",
+            vec![ColumnType::Int, ColumnType::Int],
+            0,
+        )
+        .unwrap();
+        let config = Config {
+            lock_timeout_ms: 200,
+            ..Config::default()
+        };
+        (dir, Arc::new(TxnManager::new(&config)), table)
+    }
+
+    fn row(k: i64, v: i64) -> Vec<Value> {
+        vec![Value::Int(k), Value::Int(v)]
+    }
+
+    #[test]
+    fn uncommitted_writes_are_private() {
+        let (_dir, mgr, t) = setup();
+        let mut a = mgr.begin(IsolationLevel::RepeatableRead);
+        a.insert(&t, row(1, 100)).unwrap();
+        assert_eq!(a.get(&t, 1).unwrap(), Some(row(1, 100)));
+        let mut b = mgr.begin(IsolationLevel::RepeatableRead);
+        assert_eq!(b.get(&t, 1).unwrap(), None);
+        a.commit().unwrap();
+        // b's snapshot predates a's commit.
+        assert_eq!(b.get(&t, 1).unwrap(), None);
+        b.commit().unwrap();
+        let mut c = mgr.begin(IsolationLevel::RepeatableRead);
+        assert_eq!(c.scan(&t).unwrap(), vec![row(1, 100)]);
+    }
+
+    #[test]
+    fn old_snapshot_reads_old_version() {
+        let (_dir, mgr, t) = setup();
+        let mut a = mgr.begin(IsolationLevel::RepeatableRead);
+        a.insert(&t, row(1, 100)).unwrap();
+        a.commit().unwrap();
+
+        let mut reader = mgr.begin(IsolationLevel::RepeatableRead);
+        let mut rc = mgr.begin(IsolationLevel::ReadCommitted);
+        let mut w = mgr.begin(IsolationLevel::RepeatableRead);
+        assert!(w.update(&t, 1, row(1, 50)).unwrap());
+        w.commit().unwrap();
+        assert_eq!(reader.get(&t, 1).unwrap(), Some(row(1, 100)));
+        assert_eq!(reader.scan(&t).unwrap(), vec![row(1, 100)]);
+        rc.begin_statement();
+        assert_eq!(rc.get(&t, 1).unwrap(), Some(row(1, 50)));
+    }
+
+    #[test]
+    fn rollback_discards_versions() {
+        let (_dir, mgr, t) = setup();
+        let mut a = mgr.begin(IsolationLevel::RepeatableRead);
+        a.insert(&t, row(1, 1)).unwrap();
+        a.commit().unwrap();
+        let mut b = mgr.begin(IsolationLevel::RepeatableRead);
+        b.update(&t, 1, row(1, 2)).unwrap();
+        assert!(b.delete(&t, 1).unwrap());
+        b.insert(&t, row(2, 2)).unwrap();
+        b.rollback();
+        let mut c = mgr.begin(IsolationLevel::RepeatableRead);
+        assert_eq!(c.scan(&t).unwrap(), vec![row(1, 1)]);
+        // The key freed by the aborted insert can be reused.
+        c.insert(&t, row(2, 3)).unwrap();
+        assert!(c.insert(&t, row(2, 4)).is_err());
+    }
+
+    #[test]
+    fn first_updater_wins_under_snapshot_isolation() {
+        let (_dir, mgr, t) = setup();
+        let mut a = mgr.begin(IsolationLevel::RepeatableRead);
+        a.insert(&t, row(1, 1)).unwrap();
+        a.commit().unwrap();
+
+        let mut b = mgr.begin(IsolationLevel::RepeatableRead);
+        let mut c = mgr.begin(IsolationLevel::RepeatableRead);
+        b.update(&t, 1, row(1, 2)).unwrap();
+        // c blocks on b's row lock and times out.
+        let err = c.update(&t, 1, row(1, 3)).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(TxnError::LockTimeout { .. })));
+        b.commit().unwrap();
+        let err = c.update(&t, 1, row(1, 3)).unwrap_err();
+        let e = err.downcast_ref::<TxnError>().unwrap();
+        assert!(matches!(e, TxnError::SerializationFailure { .. }) && e.is_retryable());
+        c.rollback();
+
+        // READ COMMITTED just applies its change to the newest version.
+        let mut d = mgr.begin(IsolationLevel::ReadCommitted);
+        assert!(d.update(&t, 1, row(1, 4)).unwrap());
+        d.commit().unwrap();
+    }
+
+    #[test]
+    fn insert_of_key_a_concurrent_transaction_inserted_fails() {
+        for isolation in [IsolationLevel::RepeatableRead, IsolationLevel::Serializable] {
+            let (_dir, mgr, t) = setup();
+            let mut a = mgr.begin(isolation);
+            assert!(a.scan(&t).unwrap().is_empty());
+            let mut b = mgr.begin(isolation);
+            b.insert(&t, row(5, 1)).unwrap();
+            b.commit().unwrap();
+            let err = a.insert(&t, row(5, 2)).unwrap_err();
+            let e = err.downcast_ref::<TxnError>().unwrap();
+            assert!(matches!(e, TxnError::SerializationFailure { .. }) && e.is_retryable());
+            let err = a.insert_or_existing(&t, row(5, 2)).unwrap_err();
+            assert!(matches!(err.downcast_ref(), Some(TxnError::SerializationFailure { .. })));
+            a.rollback();
+            let mut c = mgr.begin(isolation);
+            assert_eq!(c.scan(&t).unwrap(), vec![row(5, 1)]);
+        }
+
+        // READ COMMITTED sees the committed row and conflicts with it.
+        let (_dir, mgr, t) = setup();
+        let mut a = mgr.begin(IsolationLevel::ReadCommitted);
+        let mut b = mgr.begin(IsolationLevel::ReadCommitted);
+        b.insert(&t, row(5, 1)).unwrap();
+        b.commit().unwrap();
+        a.begin_statement();
+        assert_eq!(a.insert_or_existing(&t, row(5, 2)).unwrap(), Some(row(5, 1)));
+        assert!(a.insert(&t, row(5, 2)).unwrap_err().to_string().contains("duplicate key"));
+    }
+
+    #[test]
+    fn rollback_to_savepoint_undoes_only_later_changes() {
+        let (_dir, mgr, t) = setup();
+        let mut a = mgr.begin(IsolationLevel::RepeatableRead);
+        a.insert(&t, row(1, 10)).unwrap();
+        a.insert(&t, row(2, 20)).unwrap();
+        a.commit().unwrap();
+
+        let mut b = mgr.begin(IsolationLevel::RepeatableRead);
+        b.update(&t, 1, row(1, 11)).unwrap();
+        b.savepoint("step");
+        b.update(&t, 1, row(1, 12)).unwrap();
+        b.delete(&t, 2).unwrap();
+        b.insert(&t, row(3, 30)).unwrap();
+        assert_eq!(b.scan(&t).unwrap(), vec![row(1, 12), row(3, 30)]);
+
+        b.rollback_to_savepoint("step").unwrap();
+        assert_eq!(b.get(&t, 1).unwrap(), Some(row(1, 11)));
+        assert_eq!(b.get(&t, 2).unwrap(), Some(row(2, 20)));
+        assert_eq!(b.get(&t, 3).unwrap(), None);
+        // The savepoint survives and the step can be retried.
+        b.insert(&t, row(3, 31)).unwrap();
+        b.rollback_to_savepoint("step").unwrap();
+        b.insert(&t, row(3, 32)).unwrap();
+        b.commit().unwrap();
+
+        let mut c = mgr.begin(IsolationLevel::RepeatableRead);
+        assert_eq!(c.range(&t, 0, 10).unwrap(), vec![row(1, 11), row(2, 20), row(3, 32)]);
+    }
+
+    #[test]
+    fn released_savepoint_is_undone_by_outer_rollback() {
+        let (_dir, mgr, t) = setup();
+        let mut a = mgr.begin(IsolationLevel::RepeatableRead);
+        a.savepoint("outer");
+        a.insert(&t, row(1, 1)).unwrap();
+        a.savepoint("inner");
+        a.insert(&t, row(2, 2)).unwrap();
+        a.release_savepoint("inner").unwrap();
+        assert!(a.rollback_to_savepoint("inner").is_err());
+        a.insert(&t, row(3, 3)).unwrap();
+        a.rollback_to_savepoint("outer").unwrap();
+        assert!(a.scan(&t).unwrap().is_empty());
+        a.insert(&t, row(4, 4)).unwrap();
+        a.release_savepoint("outer").unwrap();
+        a.commit().unwrap();
+
+        let mut b = mgr.begin(IsolationLevel::RepeatableRead);
+        assert_eq!(b.scan(&t).unwrap(), vec![row(4, 4)]);
+    }
+
+    #[test]
+    fn shared_reads_hold_off_writers_and_deferred_checks_follow_savepoints() {
+        let (_dir, mgr, t) = setup();
+        let mut a = mgr.begin(IsolationLevel::ReadCommitted);
+        a.insert(&t, row(1, 1)).unwrap();
+        a.commit().unwrap();
+
+        let mut reader = mgr.begin(IsolationLevel::ReadCommitted);
+        assert_eq!(reader.get_for_share(&t, 1).unwrap(), Some(row(1, 1)));
+        assert_eq!(reader.get_for_share(&t, 2).unwrap(), None);
+        let mut writer = mgr.begin(IsolationLevel::ReadCommitted);
+        let err = writer.delete(&t, 1).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(TxnError::LockTimeout { .. })));
+
+        let check = |key| DeferredCheck {
+            table: "accounts".into(),
+            constraint: "c".into(),
+            key: Some(key),
+            values: Vec::new(),
+        };
+        reader.defer(check(1));
+        reader.savepoint("s");
+        reader.defer(check(2));
+        reader.rollback_to_savepoint("s").unwrap();
+        assert_eq!(reader.take_deferred(), vec![check(1)]);
+        assert!(reader.take_deferred().is_empty());
+
+        assert_eq!(reader.constraint_deferred("c"), None);
+        reader.set_constraints(None, true);
+        reader.set_constraints(Some(vec!["c".into()]), false);
+        assert_eq!(reader.constraint_deferred("c"), Some(false));
+        assert_eq!(reader.constraint_deferred("d"), Some(true));
+    }
+
+    #[test]
+    fn subtransaction_writes_are_hidden_until_commit() {
+        let (_dir, mgr, t) = setup();
+        let mut a = mgr.begin(IsolationLevel::RepeatableRead);
+        a.savepoint("s");
+        a.insert(&t, row(1, 1)).unwrap();
+        let mut other = mgr.begin(IsolationLevel::ReadCommitted);
+        assert_eq!(other.get(&t, 1).unwrap(), None);
+        a.commit().unwrap();
+        other.begin_statement();
+        assert_eq!(other.get(&t, 1).unwrap(), Some(row(1, 1)));
+    }
+
+    #[test]
+    fn checkpoint_keeps_every_outcome_and_truncates_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.wal");
+        let open = || {
+            let (wal, records) = Wal::open(&path, false).unwrap();
+            (Arc::new(TxnManager::recover(&Config::default(), wal, &records, 1)), records.len())
+        };
+        let (mgr, _) = open();
+        let run = |n: u64| {
+            for i in 0..n {
+                let mut t = mgr.begin(IsolationLevel::ReadCommitted);
+                // Subtransactions: one rolled back, one committing with its parent.
+                t.savepoint("a");
+                t.rollback_to_savepoint("a").unwrap();
+                if i % 3 == 0 {
+                    t.rollback();
+                } else {
+                    t.commit().unwrap();
+                }
+            }
+        };
+        run(6);
+        let old = mgr.begin(IsolationLevel::RepeatableRead);
+        run(6);
+        let last = mgr.begin(IsolationLevel::ReadCommitted).id();
+        let outcomes = |mgr: &TxnManager| (1..=last).map(|id| mgr.status(id)).collect::<Vec<_>>();
+        let before = outcomes(&mgr);
+        assert_eq!(before.iter().filter(|s| **s == TxnStatus::Committed).count(), 16);
+
+        // The four commits older than `old` fold into the checkpoint record.
+        assert_eq!(mgr.checkpoint().unwrap(), 3);
+        assert_eq!(outcomes(&mgr), before);
+        assert!(mgr.txns().status.keys().all(|&id| id >= old.id()));
+        old.commit().unwrap();
+        let before = outcomes(&mgr);
+        drop(mgr);
+        let (mgr, records) = open();
+        assert_eq!(records, 1 + 4 + 1);
+        assert_eq!(outcomes(&mgr), before);
+
+        mgr.checkpoint().unwrap();
+        drop(mgr);
+        let (mgr, records) = open();
+        assert_eq!(records, 1);
+        assert_eq!(outcomes(&mgr), before);
+        assert!(mgr.begin(IsolationLevel::ReadCommitted).id() >= last);
+    }
+}
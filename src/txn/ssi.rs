@@ -0,0 +1,257 @@
+//! Serializable Snapshot Isolation bookkeeping (Cahill et al.).
+//!
+//! Serializable transactions leave SIREAD locks on the row versions (`RowRef`s) and key
+//! ranges they read. A write that touches something a concurrent serializable transaction
+//! read, or a read that skips a version written by one, records an rw-antidependency
+//! `reader -> writer`. A transaction with both an incoming and an outgoing edge is a pivot;
+//! when the pivot's out-neighbour committed first the structure may be non-serializable and
+//! the transaction that observes it is aborted with `TxnError::SerializationFailure`.
+//!
+//! Locks and edges of a committed transaction are kept until every transaction that
+//! overlapped it has finished.
+
+use std::collections::{HashMap, HashSet};
+
+use super::{TxnError, TxnId};
+use crate::storage::RowRef;
+
+#[derive(Debug, Default)]
+struct SsiTxn {
+    start_seq: u64,
+    commit_seq: Option<u64>,
+    /// Transactions that read something this one wrote (`reader -> self`).
+    in_conflicts: HashSet<TxnId>,
+    /// Transactions that wrote something this one read (`self -> writer`).
+    out_conflicts: HashSet<TxnId>,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct SsiState {
+    seq: u64,
+    txns: HashMap<TxnId, SsiTxn>,
+    tuple_locks: HashMap<(String, RowRef), HashSet<TxnId>>,
+    /// Per table: [start, end) key ranges read, with the reader.
+    range_locks: HashMap<String, Vec<(i64, i64, TxnId)>>,
+}
+
+impl SsiState {
+    pub(super) fn register(&mut self, txn: TxnId) {
+        self.seq += 1;
+        self.txns.insert(
+            txn,
+            SsiTxn {
+                start_seq: self.seq,
+                ..SsiTxn::default()
+            },
+        );
+    }
+
+    pub(super) fn is_tracked(&self, txn: TxnId) -> bool {
+        self.txns.contains_key(&txn)
+    }
+
+    pub(super) fn siread_tuple(&mut self, txn: TxnId, table: &str, r: RowRef) {
+        self.tuple_locks
+            .entry((table.to_string(), r))
+            .or_default()
+            .insert(txn);
+    }
+
+    pub(super) fn siread_range(&mut self, txn: TxnId, table: &str, start: i64, end: i64) {
+        let ranges = self.range_locks.entry(table.to_string()).or_default();
+        if !ranges
+            .iter()
+            .any(|&(s, e, t)| t == txn && s <= start && end <= e)
+        {
+            ranges.push((start, end, txn));
+        }
+    }
+
+    /// Serializable transactions other than `writer` that overlap it and read `key` of
+    /// `table` (through a range) or the version `version`.
+    pub(super) fn readers_of(
+        &self,
+        writer: TxnId,
+        table: &str,
+        key: i64,
+        version: Option<RowRef>,
+    ) -> Vec<TxnId> {
+        let Some(w) = self.txns.get(&writer) else {
+            return Vec::new();
+        };
+        let mut readers = HashSet::new();
+        if let Some(ranges) = self.range_locks.get(table) {
+            readers.extend(
+                ranges
+                    .iter()
+                    .filter(|&&(s, e, _)| s <= key && key < e)
+                    .map(|&(_, _, t)| t),
+            );
+        }
+        if let Some(r) = version {
+            if let Some(holders) = self.tuple_locks.get(&(table.to_string(), r)) {
+                readers.extend(holders.iter().copied());
+            }
+        }
+        let mut out: Vec<TxnId> = readers
+            .into_iter()
+            .filter(|&t| t != writer)
+            .filter(|t| match self.txns.get(t).and_then(|r| r.commit_seq) {
+                Some(committed) => committed > w.start_seq,
+                None => self.txns.contains_key(t),
+            })
+            .collect();
+        out.sort_unstable();
+        out
+    }
+
+    /// Record `reader -rw-> writer`. Fails if this completes a dangerous structure; `me`
+    /// (one of the two, and still active) is the transaction reported as the victim.
+    pub(super) fn add_conflict(
+        &mut self,
+        reader: TxnId,
+        writer: TxnId,
+        me: TxnId,
+    ) -> Result<(), TxnError> {
+        if reader == writer || !self.is_tracked(reader) || !self.is_tracked(writer) {
+            return Ok(());
+        }
+        self.txns.get_mut(&reader).unwrap().out_conflicts.insert(writer);
+        self.txns.get_mut(&writer).unwrap().in_conflicts.insert(reader);
+        if self.dangerous(reader) || self.dangerous(writer) {
+            return Err(TxnError::SerializationFailure {
+                txn: me,
+                reason: format!(
+                    "rw-conflict {} -> {} completes a dangerous structure",
+                    reader, writer
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    /// `pivot` has `T1 -rw-> pivot -rw-> T3` where T3 committed before both the pivot and T1.
+    fn dangerous(&self, pivot: TxnId) -> bool {
+        let Some(p) = self.txns.get(&pivot) else {
+            return false;
+        };
+        p.out_conflicts.iter().any(|t3| {
+            let Some(t3_commit) = self.txns.get(t3).and_then(|t| t.commit_seq) else {
+                return false;
+            };
+            if p.commit_seq.is_some_and(|c| c < t3_commit) {
+                return false;
+            }
+            p.in_conflicts.iter().any(|t1| {
+                self.txns
+                    .get(t1)
+                    .is_some_and(|t| t.commit_seq.is_none_or(|c| c >= t3_commit))
+            })
+        })
+    }
+
+    /// Final check before `txn` commits.
+    pub(super) fn pre_commit(&self, txn: TxnId) -> Result<(), TxnError> {
+        if self.dangerous(txn) {
+            return Err(TxnError::SerializationFailure {
+                txn,
+                reason: "pivot of rw-conflicts with a committed out-neighbour".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    pub(super) fn committed(&mut self, txn: TxnId) {
+        self.seq += 1;
+        let seq = self.seq;
+        if let Some(t) = self.txns.get_mut(&txn) {
+            t.commit_seq = Some(seq);
+        }
+        self.gc();
+    }
+
+    pub(super) fn aborted(&mut self, txn: TxnId) {
+        self.forget(&HashSet::from([txn]));
+        self.gc();
+    }
+
+    /// Drop committed transactions that no active transaction overlaps.
+    fn gc(&mut self) {
+        let oldest_active = self
+            .txns
+            .values()
+            .filter(|t| t.commit_seq.is_none())
+            .map(|t| t.start_seq)
+            .min()
+            .unwrap_or(u64::MAX);
+        let done: HashSet<TxnId> = self
+            .txns
+            .iter()
+            .filter(|(_, t)| t.commit_seq.is_some_and(|c| c < oldest_active))
+            .map(|(&id, _)| id)
+            .collect();
+        if !done.is_empty() {
+            self.forget(&done);
+        }
+    }
+
+    fn forget(&mut self, gone: &HashSet<TxnId>) {
+        self.txns.retain(|id, _| !gone.contains(id));
+        for t in self.txns.values_mut() {
+            t.in_conflicts.retain(|id| !gone.contains(id));
+            t.out_conflicts.retain(|id| !gone.contains(id));
+        }
+        self.tuple_locks.retain(|_, holders| {
+            holders.retain(|id| !gone.contains(id));
+            !holders.is_empty()
+        });
+        self.range_locks.retain(|_, ranges| {
+            ranges.retain(|(_, _, id)| !gone.contains(id));
+            !ranges.is_empty()
+        });
+    }
+
+    #[cfg(test)]
+    fn num_tracked(&self) -> usize {
+        self.txns.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_cycle_aborts_second_committer() {
+        let mut s = SsiState::default();
+        s.register(1);
+        s.register(2);
+        s.siread_range(1, "doctors", i64::MIN, i64::MAX);
+        s.siread_range(2, "doctors", i64::MIN, i64::MAX);
+        // Each writes a row the other read.
+        for r in s.readers_of(1, "doctors", 10, None) {
+            s.add_conflict(r, 1, 1).unwrap();
+        }
+        for r in s.readers_of(2, "doctors", 20, None) {
+            s.add_conflict(r, 2, 2).unwrap();
+        }
+        s.pre_commit(1).unwrap();
+        s.committed(1);
+        assert!(matches!(
+            s.pre_commit(2),
+            Err(TxnError::SerializationFailure { txn: 2, .. })
+        ));
+        s.aborted(2);
+        assert_eq!(s.num_tracked(), 0);
+    }
+
+    #[test]
+    fn non_overlapping_reader_is_not_a_conflict() {
+        let mut s = SsiState::default();
+        s.register(1);
+        s.siread_tuple(1, "t", RowRef::new(0, 0));
+        s.committed(1);
+        s.register(2);
+        assert!(s.readers_of(2, "t", 0, Some(RowRef::new(0, 0))).is_empty());
+    }
+}
@@ -0,0 +1,149 @@
+//! Aggregate functions, GROUP BY and grouping sets, and the aggregate operators.
+
+mod common;
+
+use common::{config, open, plan_text, query};
+use rustdb::storage::Value;
+use rustdb::{Config, Database, Session};
+use tempfile::TempDir;
+
+fn text(v: &str) -> Value {
+    Value::Text(v.into())
+}
+
+/// Five sales in three regions, with a NULL quantity and a NULL `paid`.
+fn sales() -> (TempDir, Session) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut s = open(dir.path()).session();
+    s.execute("CREATE TABLE sales (id INT PRIMARY KEY, region TEXT, item TEXT, qty INT, paid BOOL)")
+        .unwrap();
+    s.execute(
+        "INSERT INTO sales VALUES (1, 'east', 'pen', 3, true), (2, 'east', 'ink', 5, false), \
+         (3, 'west', 'pen', 2, true), (4, 'west', 'pen', NULL, true), (5, 'north', 'ink', 7, NULL)",
+    )
+    .unwrap();
+    (dir, s)
+}
+
+#[test]
+fn aggregates_without_group_by() {
+    let (_dir, mut s) = sales();
+    let rows = query(
+        &mut s,
+        "SELECT count(*), count(qty), count(DISTINCT item), sum(qty), min(qty), max(item), \
+         bool_and(paid), bool_or(paid) FROM sales",
+    );
+    assert_eq!(
+        rows,
+        vec![vec![
+            Value::Int(5),
+            Value::Int(4),
+            Value::Int(2),
+            Value::Int(17),
+            Value::Int(2),
+            text("pen"),
+            Value::Bool(false),
+            Value::Bool(true),
+        ]]
+    );
+    // One row even over no input.
+    let rows = query(&mut s, "SELECT count(*), sum(qty), avg(qty) FROM sales WHERE id > 100");
+    assert_eq!(rows, vec![vec![Value::Int(0), Value::Null, Value::Null]]);
+    let text_plan = plan_text(&mut s, "EXPLAIN SELECT count(*) FROM sales");
+    assert!(text_plan.iter().any(|l| l.trim_start().starts_with("->  Aggregate")), "{:?}", text_plan);
+}
+
+#[test]
+fn group_by_and_having() {
+    let (_dir, mut s) = sales();
+    let rows = query(
+        &mut s,
+        "SELECT region, string_agg(item, ',') AS items, avg(qty) FROM sales \
+         GROUP BY region HAVING count(*) > 1 ORDER BY items, 1",
+    );
+    assert_eq!(
+        rows,
+        vec![
+            vec![text("east"), text("pen,ink"), Value::Float(4.0)],
+            vec![text("west"), text("pen,pen"), Value::Float(2.0)],
+        ]
+    );
+}
+
+#[test]
+fn misplaced_columns_and_aggregates_are_rejected() {
+    let (_dir, mut s) = sales();
+    let err = s.execute("SELECT region, qty FROM sales GROUP BY region").unwrap_err();
+    assert!(err.to_string().contains("must appear in the GROUP BY clause"), "{}", err);
+    assert!(s.execute("SELECT sum(item) FROM sales").is_err());
+    assert!(s.execute("SELECT id FROM sales WHERE count(*) > 1").is_err());
+}
+
+#[test]
+fn rollup_cube_and_grouping_sets() {
+    let (_dir, mut s) = sales();
+    // ROLLUP(a, b) is (a, b), (a) and (); CUBE adds (b); keys outside a set are NULL.
+    let rows = query(&mut s, "SELECT region, item, sum(qty) FROM sales GROUP BY ROLLUP (region, item)");
+    assert_eq!(rows.len(), 4 + 3 + 1);
+    assert!(rows.contains(&vec![Value::Null, Value::Null, Value::Int(17)]));
+    assert!(rows.contains(&vec![text("west"), Value::Null, Value::Int(2)]));
+    let rows = query(&mut s, "SELECT region, item, count(*) FROM sales GROUP BY CUBE (region, item)");
+    assert_eq!(rows.len(), 4 + 3 + 2 + 1);
+    assert!(rows.contains(&vec![Value::Null, text("pen"), Value::Int(3)]));
+    let rows = query(
+        &mut s,
+        "SELECT region, item, count(*) FROM sales GROUP BY GROUPING SETS ((region), (item), ())",
+    );
+    assert_eq!(rows.len(), 3 + 2 + 1);
+    let rows = query(
+        &mut s,
+        "SELECT region, count(*) FROM sales WHERE id > 100 GROUP BY GROUPING SETS ((region), ())",
+    );
+    assert_eq!(rows, vec![vec![Value::Null, Value::Int(0)]]);
+    let text_plan = plan_text(&mut s, "EXPLAIN SELECT region, count(*) FROM sales GROUP BY CUBE (region, item)");
+    assert!(text_plan.iter().any(|l| l.contains("HashAggregate")), "{:?}", text_plan);
+}
+
+/// 6000 rows in 2000 groups of `g`, in a database with 16 KB of work memory.
+fn many_groups() -> (TempDir, Session) {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(Config { work_mem_kb: 16, ..config(dir.path()) }).unwrap();
+    let mut s = db.session();
+    s.execute("CREATE TABLE t (id INT PRIMARY KEY, g INT, v INT)").unwrap();
+    let values: Vec<String> = (0..6000).map(|i| format!("({}, {}, {})", i, (i * 7) % 2000, i % 3)).collect();
+    s.execute(&format!("INSERT INTO t VALUES {}", values.join(", "))).unwrap();
+    s.execute("ANALYZE").unwrap();
+    (dir, s)
+}
+
+#[test]
+fn hash_aggregate_spills_high_cardinality_groups() {
+    let (_dir, mut s) = many_groups();
+    let sql = "SELECT g, count(*), sum(v), count(DISTINCT v) FROM t GROUP BY g";
+    let text = plan_text(&mut s, &format!("EXPLAIN {}", sql));
+    assert!(text.iter().any(|l| l.contains("HashAggregate")), "{:?}", text);
+    let rows = query(&mut s, sql);
+    assert_eq!(rows.len(), 2000);
+    for r in &rows {
+        let [Value::Int(g), Value::Int(n), Value::Int(sum), Value::Int(d)] = r[..] else {
+            panic!("{:?}", r);
+        };
+        let members: Vec<i64> = (0..6000).filter(|i| (i * 7) % 2000 == g).collect();
+        assert_eq!(n, members.len() as i64);
+        assert_eq!(sum, members.iter().map(|i| i % 3).sum::<i64>());
+        assert_eq!(d, 3);
+    }
+    let rows = query(&mut s, "SELECT g, count(*) FROM t GROUP BY ROLLUP (g)");
+    assert_eq!(rows.len(), 2001);
+    assert!(rows.contains(&vec![Value::Null, Value::Int(6000)]));
+}
+
+#[test]
+fn groups_in_index_order_stream_without_a_sort() {
+    let (_dir, mut s) = many_groups();
+    let sql = "SELECT id, count(*) FROM t WHERE id < 10 GROUP BY id";
+    let text = plan_text(&mut s, &format!("EXPLAIN {}", sql));
+    assert!(text.iter().any(|l| l.contains("GroupAggregate")), "{:?}", text);
+    assert!(!text.iter().any(|l| l.contains("Sort")), "{:?}", text);
+    assert_eq!(query(&mut s, sql).len(), 10);
+}
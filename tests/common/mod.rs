@@ -0,0 +1,46 @@
+//! Helpers shared by the SQL feature tests.
+#![allow(dead_code)]
+
+use std::path::Path;
+use std::sync::Arc;
+
+use rustdb::storage::Value;
+use rustdb::{Config, Database, Session};
+
+/// Configuration for a test database in `dir`: defaults, without syncing the WAL.
+pub fn config(dir: &Path) -> Config {
+    Config {
+        data_dir: dir.to_string_lossy().into_owned(),
+        wal_sync: false,
+        ..Config::default()
+    }
+}
+
+pub fn open(dir: &Path) -> Arc<Database> {
+    Database::open(config(dir)).unwrap()
+}
+
+pub fn query(s: &mut Session, sql: &str) -> Vec<Vec<Value>> {
+    s.execute(sql).unwrap().rows
+}
+
+/// Rows of `sql`, each rendered as its values joined by spaces.
+pub fn lines(s: &mut Session, sql: &str) -> Vec<String> {
+    query(s, sql)
+        .into_iter()
+        .map(|r| r.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(" "))
+        .collect()
+}
+
+/// Lines of an EXPLAIN result.
+pub fn plan_text(s: &mut Session, sql: &str) -> Vec<String> {
+    s.execute(sql)
+        .unwrap()
+        .rows
+        .into_iter()
+        .map(|r| match &r[0] {
+            Value::Text(l) => l.clone(),
+            other => panic!("not a plan line: {}", other),
+        })
+        .collect()
+}
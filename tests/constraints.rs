@@ -0,0 +1,138 @@
+//! NOT NULL, CHECK, UNIQUE and FOREIGN KEY constraints, immediate and deferred.
+
+mod common;
+
+use common::{open, query};
+use rustdb::storage::Value;
+use rustdb::Session;
+use tempfile::TempDir;
+
+fn fails(s: &mut Session, sql: &str, message: &str) {
+    let err = s.execute(sql).unwrap_err();
+    assert!(err.to_string().contains(message), "{}: {}", sql, err);
+}
+
+fn ints(rows: &[&[i64]]) -> Vec<Vec<Value>> {
+    rows.iter().map(|r| r.iter().map(|v| Value::Int(*v)).collect()).collect()
+}
+
+/// `items` with a NOT NULL, a CHECK and two UNIQUE constraints, and three rows in lot 1.
+fn items() -> (TempDir, Session) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut s = open(dir.path()).session();
+    s.execute(
+        "CREATE TABLE items (id INT PRIMARY KEY, code TEXT NOT NULL UNIQUE, qty INT CHECK (qty >= 0), \
+         lot INT, CONSTRAINT lot_qty UNIQUE (lot, qty))",
+    )
+    .unwrap();
+    s.execute("INSERT INTO items VALUES (1, 'a', 5, 1), (2, 'b', NULL, 1), (3, 'c', NULL, 1)").unwrap();
+    (dir, s)
+}
+
+#[test]
+fn not_null_check_and_unique() {
+    let (_dir, mut s) = items();
+    // NULLs never violate UNIQUE: rows 2 and 3 share lot 1 with no qty.
+    fails(&mut s, "INSERT INTO items VALUES (4, NULL, 1, 2)", "not-null constraint");
+    fails(&mut s, "INSERT INTO items VALUES (4, 'd', -1, 2)", "check constraint items_qty_check");
+    fails(&mut s, "INSERT INTO items VALUES (4, 'a', 1, 2)", "unique constraint items_code_key");
+    fails(&mut s, "UPDATE items SET qty = 5 WHERE id = 2", "unique constraint lot_qty");
+    fails(&mut s, "UPDATE items SET qty = qty - 10", "check constraint");
+    s.execute("UPDATE items SET code = 'z' WHERE id = 1").unwrap();
+    s.execute("INSERT INTO items VALUES (4, 'a', 1, 2)").unwrap();
+    s.execute("DELETE FROM items WHERE id = 4").unwrap();
+    s.execute("INSERT INTO items VALUES (5, 'a', 1, 2) ON CONFLICT (id) DO NOTHING").unwrap();
+    let upsert = "INSERT INTO items VALUES (5, 'x', 1, 2) ON CONFLICT (id) DO UPDATE SET code = 'b'";
+    fails(&mut s, upsert, "items_code_key");
+}
+
+#[test]
+fn check_constraints_must_be_boolean_over_the_table() {
+    let (_dir, mut s) = items();
+    assert!(s.execute("CREATE TABLE bad (a INT CHECK (a + 1))").is_err());
+    assert!(s.execute("CREATE TABLE bad (a INT CHECK (b > 0))").is_err());
+}
+
+#[test]
+fn a_failed_statement_leaves_the_unique_indexes_as_they_were() {
+    let (_dir, mut s) = items();
+    // The transaction stays usable too.
+    s.execute("BEGIN").unwrap();
+    s.execute("INSERT INTO items VALUES (6, 'f', 0, 3)").unwrap();
+    fails(&mut s, "INSERT INTO items VALUES (7, 'g', 0, 4), (8, 'f', 0, 5)", "items_code_key");
+    s.execute("INSERT INTO items VALUES (7, 'g', 0, 4)").unwrap();
+    s.execute("COMMIT").unwrap();
+    assert_eq!(query(&mut s, "SELECT id FROM items ORDER BY id"), ints(&[&[1], &[2], &[3], &[6], &[7]]));
+}
+
+#[test]
+fn foreign_keys_with_on_delete_actions() {
+    let (_dir, mut s) = items();
+    // Checked as each statement ends.
+    s.execute("INSERT INTO items VALUES (5, 'e', 1, 2)").unwrap();
+    s.execute("CREATE TABLE orders (id INT PRIMARY KEY, item INT REFERENCES items ON DELETE CASCADE)").unwrap();
+    s.execute(
+        "CREATE TABLE notes (id INT PRIMARY KEY, item INT REFERENCES items ON DELETE SET NULL, \
+         code TEXT REFERENCES items (code) ON DELETE RESTRICT)",
+    )
+    .unwrap();
+    s.execute("CREATE TABLE tags (item INT REFERENCES items, parent INT REFERENCES tags (rowid))").unwrap_err();
+    s.execute("CREATE TABLE tags (id INT PRIMARY KEY, item INT REFERENCES items, parent INT REFERENCES tags)")
+        .unwrap();
+    fails(&mut s, "INSERT INTO orders VALUES (1, 9)", "violates foreign key constraint orders_item_fkey");
+    s.execute("INSERT INTO orders VALUES (1, 1), (2, 1), (3, 2), (4, NULL)").unwrap();
+    s.execute("INSERT INTO notes VALUES (1, 1, NULL), (2, 2, 'b'), (3, NULL, 'c')").unwrap();
+    s.execute("INSERT INTO tags VALUES (2, 3, 1), (1, 3, NULL), (3, 5, 2)").unwrap();
+    fails(&mut s, "UPDATE notes SET code = 'q' WHERE id = 2", "notes_code_fkey");
+    fails(&mut s, "DELETE FROM items WHERE id = 3", "update or delete on table items");
+    fails(&mut s, "DELETE FROM items WHERE id = 2", "notes_code_fkey on table notes");
+    fails(&mut s, "UPDATE items SET code = 'q' WHERE id = 2", "notes_code_fkey on table notes");
+    s.execute("DELETE FROM items WHERE id = 1").unwrap();
+    assert_eq!(query(&mut s, "SELECT id FROM orders ORDER BY id"), ints(&[&[3], &[4]]));
+    assert_eq!(query(&mut s, "SELECT id FROM notes WHERE item IS NULL ORDER BY id"), ints(&[&[1], &[3]]));
+    // Rows that still reference a deleted row by the end of the statement fail NO ACTION.
+    s.execute("DELETE FROM tags WHERE id < 3").unwrap_err();
+    s.execute("DELETE FROM tags").unwrap();
+    fails(&mut s, "DROP TABLE items", "constraint notes_item_fkey on table notes depends on it");
+}
+
+#[test]
+fn deferred_constraints_are_checked_at_commit() {
+    let (_dir, mut s) = items();
+    // SET CONSTRAINTS moves the check.
+    s.execute(
+        "CREATE TABLE lines (id INT PRIMARY KEY, item INT, pos INT, \
+         FOREIGN KEY (item) REFERENCES items (id) DEFERRABLE INITIALLY DEFERRED, \
+         CONSTRAINT line_pos UNIQUE (item, pos) DEFERRABLE)",
+    )
+    .unwrap();
+    s.execute("BEGIN").unwrap();
+    s.execute("INSERT INTO lines VALUES (1, 10, 1), (2, 10, 2)").unwrap();
+    s.execute("INSERT INTO items VALUES (10, 'j', 0, 10)").unwrap();
+    s.execute("UPDATE lines SET pos = 3 - pos").unwrap();
+    s.execute("COMMIT").unwrap();
+    fails(&mut s, "UPDATE lines SET pos = 1", "unique constraint line_pos");
+    s.execute("BEGIN").unwrap();
+    s.execute("INSERT INTO lines VALUES (3, 11, 1)").unwrap();
+    fails(&mut s, "SET CONSTRAINTS lines_item_fkey IMMEDIATE", "lines_item_fkey");
+    fails(&mut s, "SET CONSTRAINTS items_code_key DEFERRED", "not deferrable");
+    s.execute("SET CONSTRAINTS ALL DEFERRED").unwrap();
+    s.execute("UPDATE lines SET pos = 1").unwrap();
+    fails(&mut s, "COMMIT", "violates foreign key constraint lines_item_fkey");
+    assert!(!s.in_transaction());
+    assert_eq!(query(&mut s, "SELECT id, pos FROM lines ORDER BY id"), ints(&[&[1, 2], &[2, 1]]));
+    fails(&mut s, "SET CONSTRAINTS ALL DEFERRED", "transaction block");
+}
+
+#[test]
+fn unique_indexes_are_vacuumed_with_their_table_and_kept_on_reopen() {
+    let (dir, mut s) = items();
+    // They are tables of their own.
+    let vacuumed: Vec<Value> = query(&mut s, "VACUUM items").into_iter().map(|r| r[0].clone()).collect();
+    assert_eq!(vacuumed.len(), 3);
+    drop(s);
+    let mut s = open(dir.path()).session();
+    fails(&mut s, "INSERT INTO items VALUES (20, 'a', 0, 20)", "items_code_key");
+    s.execute("DROP TABLE items").unwrap();
+    assert!(!dir.path().join("items.items_code_key.heap").exists());
+}
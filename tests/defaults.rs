@@ -0,0 +1,79 @@
+//! Column defaults.
+
+mod common;
+
+use common::{open, query};
+use rustdb::sequence::DEFAULT_CACHE;
+use rustdb::storage::Value;
+use rustdb::Session;
+use tempfile::TempDir;
+
+fn fails(s: &mut Session, sql: &str, message: &str) {
+    let err = s.execute(sql).unwrap_err();
+    assert!(err.to_string().contains(message), "{}: {}", sql, err);
+}
+
+/// `t` with a default on every column, the key drawn from the sequence `ticket`.
+fn tickets() -> (TempDir, Session) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut s = open(dir.path()).session();
+    s.execute("CREATE SEQUENCE ticket START 100").unwrap();
+    s.execute(
+        "CREATE TABLE t (id INT PRIMARY KEY DEFAULT nextval('ticket'), n INT DEFAULT 7, \
+         label TEXT NOT NULL DEFAULT 'none', score FLOAT DEFAULT 1 + 1)",
+    )
+    .unwrap();
+    (dir, s)
+}
+
+#[test]
+fn defaults_fill_in_columns_insert_leaves_out() {
+    let (_dir, mut s) = tickets();
+    // Or writes as DEFAULT; an explicit NULL stays NULL.
+    s.execute("INSERT INTO t (label) VALUES ('a')").unwrap();
+    s.execute("INSERT INTO t VALUES (DEFAULT, NULL, DEFAULT, 0.5), (1, default, 'b', DEFAULT)").unwrap();
+    s.execute("INSERT INTO t (id, n) SELECT 2, 3").unwrap();
+    let rows = query(&mut s, "SELECT id, n, label, score FROM t ORDER BY id");
+    let row = |id: i64, n: Value, label: &str, score: f64| {
+        vec![Value::Int(id), n, Value::Text(label.into()), Value::Float(score)]
+    };
+    assert_eq!(rows, vec![
+        row(1, Value::Int(7), "b", 2.0),
+        row(2, Value::Int(3), "none", 2.0),
+        row(100, Value::Int(7), "a", 2.0),
+        row(101, Value::Null, "none", 0.5),
+    ]);
+    fails(&mut s, "INSERT INTO t (id, label) VALUES (3, NULL)", "not-null");
+}
+
+#[test]
+fn default_stands_for_the_next_identity_value() {
+    let (_dir, mut s) = tickets();
+    // Even for GENERATED ALWAYS.
+    s.execute("CREATE TABLE e (id INT GENERATED ALWAYS AS IDENTITY, name TEXT DEFAULT 'x')").unwrap();
+    s.execute("INSERT INTO e VALUES (DEFAULT, DEFAULT), (DEFAULT, 'y')").unwrap();
+    let named = |id: i64, name: &str| vec![Value::Int(id), Value::Text(name.into())];
+    assert_eq!(query(&mut s, "SELECT id, name FROM e ORDER BY id"), vec![named(1, "x"), named(2, "y")]);
+    fails(&mut s, "INSERT INTO e VALUES (DEFAULT, 'z'), (5, 'w')", "GENERATED ALWAYS");
+}
+
+#[test]
+fn defaults_may_not_read_columns_or_call_unknown_functions() {
+    let (_dir, mut s) = tickets();
+    fails(&mut s, "CREATE TABLE bad (a INT, b INT DEFAULT a + 1)", "a");
+    fails(&mut s, "CREATE TABLE bad (a INT DEFAULT no_such_function())", "no_such_function");
+    assert!(s.database().table_def("bad").is_none());
+}
+
+#[test]
+fn defaults_are_kept_in_the_catalog() {
+    let (dir, mut s) = tickets();
+    s.execute("INSERT INTO t (label) VALUES ('a')").unwrap();
+    drop(s);
+    // The sequence resumes past its logged block.
+    let mut s = open(dir.path()).session();
+    assert_eq!(query(&mut s, "INSERT INTO t (label) VALUES ('c') RETURNING id, n"), vec![vec![
+        Value::Int(100 + DEFAULT_CACHE),
+        Value::Int(7)
+    ]]);
+}
@@ -0,0 +1,142 @@
+//! INSERT, UPDATE and DELETE: upserts and RETURNING.
+
+mod common;
+
+use common::{open, query};
+use rustdb::storage::{ColumnType, Value};
+use rustdb::Session;
+use tempfile::TempDir;
+
+fn text(v: &str) -> Value {
+    Value::Text(v.into())
+}
+
+#[test]
+fn insert_select_update_delete() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut s = open(dir.path()).session();
+    s.execute("CREATE TABLE users (id INT PRIMARY KEY, name TEXT, score DOUBLE)").unwrap();
+    let r = s
+        .execute("INSERT INTO users VALUES (1, 'ann', 1.5), (2, 'bob', NULL), (3, 'cy', 3)")
+        .unwrap();
+    assert_eq!(r.tag, "INSERT 0 3");
+    assert!(s.execute("INSERT INTO users VALUES (1, 'dup', 0)").is_err());
+    assert_eq!(
+        query(&mut s, "SELECT name, score * 2 FROM users WHERE score IS NOT NULL ORDER BY id DESC"),
+        vec![vec![text("cy"), Value::Float(6.0)], vec![text("ann"), Value::Float(3.0)]]
+    );
+    assert_eq!(s.execute("UPDATE users SET score = 0 WHERE id >= 2").unwrap().tag, "UPDATE 2");
+    assert_eq!(s.execute("DELETE FROM users WHERE name = 'ann'").unwrap().tag, "DELETE 1");
+    assert_eq!(
+        query(&mut s, "SELECT * FROM users"),
+        vec![
+            vec![Value::Int(2), text("bob"), Value::Float(0.0)],
+            vec![Value::Int(3), text("cy"), Value::Float(0.0)],
+        ]
+    );
+}
+
+#[test]
+fn tables_without_a_primary_key_get_a_hidden_rowid() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut s = open(dir.path()).session();
+    s.execute("CREATE TABLE log (msg TEXT)").unwrap();
+    s.execute("INSERT INTO log VALUES ('a'), ('b')").unwrap();
+    let r = s.execute("SELECT * FROM log ORDER BY 1 DESC LIMIT 1").unwrap();
+    assert_eq!(r.columns, vec![("msg".to_string(), ColumnType::Text)]);
+    assert_eq!(r.rows, vec![vec![text("b")]]);
+    assert_eq!(query(&mut s, "SELECT rowid FROM log WHERE msg = 'a'"), vec![vec![Value::Int(1)]]);
+}
+
+/// `kv` holding keys 1 and 2.
+fn kv() -> (TempDir, Session) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut s = open(dir.path()).session();
+    s.execute("CREATE TABLE kv (k INT PRIMARY KEY, v TEXT, hits INT)").unwrap();
+    s.execute("INSERT INTO kv VALUES (1, 'a', 1), (2, 'b', 1)").unwrap();
+    (dir, s)
+}
+
+#[test]
+fn on_conflict_do_nothing_skips_existing_keys() {
+    let (_dir, mut s) = kv();
+    let r = s.execute("INSERT INTO kv VALUES (2, 'x', 1), (3, 'c', 1) ON CONFLICT DO NOTHING").unwrap();
+    assert_eq!(r.tag, "INSERT 0 1");
+    assert!(r.columns.is_empty());
+    assert_eq!(query(&mut s, "SELECT v FROM kv WHERE k = 2"), vec![vec![text("b")]]);
+}
+
+#[test]
+fn on_conflict_do_update_with_returning() {
+    let (_dir, mut s) = kv();
+    let r = s
+        .execute(
+            "INSERT INTO kv VALUES (1, 'z', 1), (4, 'd', 1) ON CONFLICT (k) \
+             DO UPDATE SET v = kv.v || excluded.v, hits = kv.hits + 1 RETURNING k, v AS value, hits",
+        )
+        .unwrap();
+    assert_eq!(r.tag, "INSERT 0 2");
+    assert_eq!(r.columns[1], ("value".to_string(), ColumnType::Text));
+    assert_eq!(
+        r.rows,
+        vec![
+            vec![Value::Int(1), text("az"), Value::Int(2)],
+            vec![Value::Int(4), text("d"), Value::Int(1)],
+        ]
+    );
+    // A WHERE on DO UPDATE leaves rows failing it alone.
+    let r = s
+        .execute("INSERT INTO kv VALUES (2, 'y', 0) ON CONFLICT (k) DO UPDATE SET v = 'y' WHERE kv.hits > 5")
+        .unwrap();
+    assert_eq!(r.tag, "INSERT 0 0");
+    assert_eq!(query(&mut s, "SELECT v FROM kv WHERE k = 2"), vec![vec![text("b")]]);
+}
+
+#[test]
+fn invalid_upserts_are_rejected() {
+    let (_dir, mut s) = kv();
+    let err = s.execute("INSERT INTO kv VALUES (1, 'a', 1) ON CONFLICT (v) DO NOTHING").unwrap_err();
+    assert!(err.to_string().contains("no unique or exclusion constraint"), "{}", err);
+    let err = s.execute("INSERT INTO kv VALUES (1, 'a', 1) ON CONFLICT DO UPDATE SET v = 'b'").unwrap_err();
+    assert!(err.to_string().contains("requires inference specification"), "{}", err);
+    let err = s
+        .execute("INSERT INTO kv VALUES (1, 'p', 1), (1, 'q', 1) ON CONFLICT (k) DO UPDATE SET v = excluded.v")
+        .unwrap_err();
+    assert!(err.to_string().contains("cannot affect row a second time"), "{}", err);
+    assert_eq!(query(&mut s, "SELECT v FROM kv WHERE k = 1"), vec![vec![text("a")]]);
+}
+
+#[test]
+fn returning_on_update_and_delete() {
+    let (_dir, mut s) = kv();
+    s.execute("INSERT INTO kv VALUES (3, 'c', 1), (4, 'd', 1)").unwrap();
+    let r = s.execute("UPDATE kv SET hits = hits * 10 WHERE k <= 2 RETURNING *").unwrap();
+    assert_eq!(r.tag, "UPDATE 2");
+    assert_eq!(r.columns.len(), 3);
+    assert!(r.rows.contains(&vec![Value::Int(1), text("a"), Value::Int(10)]));
+    let r = s.execute("DELETE FROM kv d WHERE k > 2 RETURNING d.k + 100").unwrap();
+    assert_eq!(r.tag, "DELETE 2");
+    assert_eq!(r.rows, vec![vec![Value::Int(103)], vec![Value::Int(104)]]);
+    let err = s.execute("DELETE FROM kv WHERE k = 0 RETURNING nope").unwrap_err();
+    assert!(err.to_string().contains("column nope does not exist"), "{}", err);
+}
+
+#[test]
+fn upsert_is_part_of_the_enclosing_transaction() {
+    let (_dir, mut s) = kv();
+    s.execute("BEGIN").unwrap();
+    s.execute("INSERT INTO kv VALUES (1, 'gone', 0) ON CONFLICT (k) DO UPDATE SET v = excluded.v").unwrap();
+    s.execute("ROLLBACK").unwrap();
+    assert_eq!(query(&mut s, "SELECT v FROM kv WHERE k = 1"), vec![vec![text("a")]]);
+}
+
+#[test]
+fn prepared_upsert_takes_parameter_types_from_the_assignments() {
+    let (_dir, mut s) = kv();
+    let upsert = "INSERT INTO kv VALUES ($1, $2, 0) ON CONFLICT (k) DO UPDATE SET hits = $3 RETURNING hits";
+    let d = s.prepare("up", upsert, &[]).unwrap();
+    assert_eq!(d.param_types, vec![ColumnType::Int, ColumnType::Text, ColumnType::Int]);
+    assert_eq!(d.columns, vec![("hits".to_string(), ColumnType::Int)]);
+    let r = s.execute_prepared("up", vec![Value::Int(2), text("b"), Value::Int(7)]).unwrap();
+    assert_eq!(r.rows, vec![vec![Value::Int(7)]]);
+}
@@ -0,0 +1,157 @@
+//! Vectorized and parallel execution give the same results as plain row-by-row execution.
+
+mod common;
+
+use common::{config, plan_text, query};
+use rustdb::storage::Value;
+use rustdb::{Config, Database, Session};
+use tempfile::TempDir;
+
+/// Rows of `t`: some NULL `x`, 13 groups of `g`, 50 names.
+fn t_values(ids: std::ops::Range<i64>) -> String {
+    let values: Vec<String> = ids
+        .map(|i| {
+            let x = if i % 7 == 0 { "NULL".to_string() } else { format!("{}.5", i % 90) };
+            format!("({}, {}, {}, 'n{}', {})", i, i % 13, x, i % 50, i % 3 == 0)
+        })
+        .collect();
+    format!("INSERT INTO t VALUES {}", values.join(", "))
+}
+
+/// `t` with `rows` rows in chunks of 5000, and `u` with 40 rows over 20 groups of `g`.
+fn tables(s: &mut Session, rows: i64) {
+    s.execute("CREATE TABLE t (id INT PRIMARY KEY, g INT, x FLOAT, name TEXT, ok BOOL)").unwrap();
+    s.execute("CREATE TABLE u (id INT PRIMARY KEY, g INT, label TEXT)").unwrap();
+    for chunk in 0..rows / 5000 {
+        s.execute(&t_values(chunk * 5000..(chunk + 1) * 5000)).unwrap();
+    }
+    let values: Vec<String> = (0..40).map(|i| format!("({}, {}, 'l{}')", i, i % 20, i)).collect();
+    s.execute(&format!("INSERT INTO u VALUES {}", values.join(", "))).unwrap();
+    s.execute("ANALYZE").unwrap();
+}
+
+/// Runs `sql` on both sessions and compares the rows, in any order.
+fn assert_same_rows(expected: &mut Session, got: &mut Session, sql: &str) {
+    let mut expected = query(expected, sql);
+    let mut got = query(got, sql);
+    expected.sort_by_key(|r| format!("{:?}", r));
+    got.sort_by_key(|r| format!("{:?}", r));
+    assert_eq!(got, expected, "{}", sql);
+}
+
+/// The same data in a database executing row by row and in one executing in batches.
+fn row_and_batch_sessions() -> (TempDir, TempDir, Session, Session) {
+    let (dir_rows, dir_batches) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+    let open = |dir: &TempDir, vectorized_execution| {
+        let db = Database::open(Config { vectorized_execution, ..config(dir.path()) }).unwrap();
+        let mut s = db.session();
+        tables(&mut s, 5000);
+        s
+    };
+    let rows = open(&dir_rows, false);
+    let batches = open(&dir_batches, true);
+    (dir_rows, dir_batches, rows, batches)
+}
+
+#[test]
+fn vectorized_execution_matches_row_execution() {
+    let (_dir_rows, _dir_batches, mut rows, mut batches) = row_and_batch_sessions();
+    let sql = "SELECT t.g, count(*) FROM t JOIN u ON t.g = u.g GROUP BY t.g";
+    let text = plan_text(&mut batches, &format!("EXPLAIN {}", sql));
+    assert!(text.iter().any(|l| l.contains("Hash Join")), "{:?}", text);
+    assert!(text.iter().any(|l| l.contains("HashAggregate")), "{:?}", text);
+    for sql in [
+        "SELECT id, x * 2, g + 1, name || '!' FROM t WHERE x > 40 AND NOT ok",
+        "SELECT id FROM t WHERE g <> 0 AND id / g > 300 OR x IS NULL",
+        "SELECT id, x BETWEEN 10 AND 20, upper(name) FROM t WHERE name < 'n2' AND id % 3 = 1",
+        "SELECT g, count(*), count(x), sum(x), min(name), max(id), avg(g) FROM t GROUP BY g",
+        "SELECT count(*), sum(id), count(DISTINCT g), bool_or(ok) FROM t WHERE id < 0",
+        "SELECT count(*), sum(id), count(DISTINCT g), bool_or(ok) FROM t",
+        "SELECT t.id, u.label FROM t JOIN u ON t.g = u.g AND t.id > u.id * 100",
+        "SELECT t.id, u.label FROM t LEFT JOIN u ON t.g = u.g + 10",
+        "SELECT u.id, t.id FROM u LEFT JOIN t ON t.id = u.id * 200",
+        "SELECT t.g, count(*) FROM t JOIN u ON t.g = u.g GROUP BY t.g",
+        "SELECT id FROM t WHERE g IN (SELECT g FROM u WHERE id > 30)",
+        "SELECT id FROM t WHERE NOT EXISTS (SELECT 1 FROM u WHERE u.g = t.g)",
+        // Past 2^53 an INT compared with a FLOAT must not be rounded to one.
+        "SELECT id FROM t WHERE id + 9007199254740992 > 9007199254740992.0 AND id < 3",
+    ] {
+        assert_same_rows(&mut rows, &mut batches, sql);
+    }
+}
+
+#[test]
+fn vectorized_errors_match_row_execution() {
+    let (_dir_rows, _dir_batches, mut rows, mut batches) = row_and_batch_sessions();
+    let err = batches.execute("SELECT id / (g - g) FROM t").unwrap_err();
+    assert_eq!(err.to_string(), "division by zero");
+    let err = batches.execute("SELECT id FROM t WHERE name").unwrap_err();
+    assert_eq!(err.to_string(), rows.execute("SELECT id FROM t WHERE name").unwrap_err().to_string());
+}
+
+/// 20000 rows in a database that plans 4 workers for scans of 8 pages or more, and a
+/// second session on it with `parallel_workers` set to 1.
+fn parallel_and_serial_sessions() -> (TempDir, Session, Session) {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(Config { parallel_workers: 4, parallel_min_pages: 8, ..config(dir.path()) }).unwrap();
+    let mut parallel = db.session();
+    tables(&mut parallel, 20000);
+    let mut serial = db.session();
+    serial.execute("SET parallel_workers = 1").unwrap();
+    (dir, parallel, serial)
+}
+
+#[test]
+fn scans_of_large_tables_are_planned_in_parallel() {
+    let (_dir, mut parallel, mut serial) = parallel_and_serial_sessions();
+    let sql = "SELECT g, count(*) FROM t WHERE x > 10 GROUP BY g";
+    let text = plan_text(&mut parallel, &format!("EXPLAIN {}", sql));
+    assert!(text.iter().any(|l| l.contains("Gather")), "{:?}", text);
+    assert!(text.iter().any(|l| l.contains("Workers Planned: 4")), "{:?}", text);
+    assert!(text.iter().any(|l| l.contains("Partial HashAggregate")), "{:?}", text);
+    let text = plan_text(&mut serial, &format!("EXPLAIN {}", sql));
+    assert!(!text.iter().any(|l| l.contains("Gather")), "{:?}", text);
+    let text = plan_text(&mut parallel, "EXPLAIN SELECT u.label, t.id FROM u JOIN t ON t.g = u.g WHERE t.ok");
+    assert!(text.iter().any(|l| l.contains("Gather")), "{:?}", text);
+    let text = plan_text(&mut parallel, &format!("EXPLAIN ANALYZE {}", sql));
+    assert!(text.iter().any(|l| l.contains("Gather") && l.contains("rows=13")), "{:?}", text);
+}
+
+#[test]
+fn parallel_plans_match_serial_execution() {
+    let (_dir, mut parallel, mut serial) = parallel_and_serial_sessions();
+    for sql in [
+        "SELECT id, x * 2, g + 1, name || '!' FROM t WHERE x > 40 AND NOT ok",
+        "SELECT g, count(*), count(x), sum(x), min(name), max(id), avg(g) FROM t GROUP BY g",
+        "SELECT count(*), sum(id), count(DISTINCT g), bool_or(ok), bool_and(ok) FROM t",
+        "SELECT count(*), sum(id), max(x) FROM t WHERE id < 0",
+        "SELECT g, count(*) FROM t GROUP BY g HAVING count(*) > 1538",
+        "SELECT u.label, t.id FROM u JOIN t ON t.g = u.g WHERE t.ok",
+        "SELECT name, string_agg(name, ',') FROM t WHERE id < 100 GROUP BY name",
+        "SELECT id FROM t WHERE g IN (SELECT g FROM u WHERE id > 30)",
+    ] {
+        assert_same_rows(&mut serial, &mut parallel, sql);
+    }
+    assert_eq!(query(&mut parallel, "SELECT id FROM t LIMIT 5").len(), 5);
+}
+
+#[test]
+fn a_worker_error_ends_the_statement() {
+    let (_dir, mut parallel, _serial) = parallel_and_serial_sessions();
+    let err = parallel.execute("SELECT id / (g - g) FROM t").unwrap_err();
+    assert_eq!(err.to_string(), "division by zero");
+}
+
+#[test]
+fn parallel_workers_is_a_session_setting() {
+    let (_dir, _parallel, mut serial) = parallel_and_serial_sessions();
+    assert_eq!(query(&mut serial, "SHOW parallel_workers"), vec![vec![Value::Int(1)]]);
+    serial.execute("RESET parallel_workers").unwrap();
+    assert_eq!(query(&mut serial, "SHOW parallel_workers"), vec![vec![Value::Int(4)]]);
+    serial.execute("SET parallel_workers TO 2").unwrap();
+    serial.execute("SET parallel_workers = DEFAULT").unwrap();
+    assert_eq!(query(&mut serial, "SHOW parallel_workers"), vec![vec![Value::Int(4)]]);
+    assert!(serial.execute("SET parallel_workers = 0").is_err());
+    assert!(serial.execute("SET no_such_setting = 1").is_err());
+    assert!(serial.execute("SHOW no_such_setting").is_err());
+}
@@ -0,0 +1,151 @@
+//! Built-in scalar functions, conditionals, casts and pattern matching.
+
+mod common;
+
+use common::{open, plan_text, query};
+use rustdb::storage::{ColumnType, Value};
+use rustdb::Session;
+use tempfile::TempDir;
+
+fn text(v: &str) -> Value {
+    Value::Text(v.into())
+}
+
+/// Three products, with NULLs in `tags`, `price` and `qty`.
+fn products() -> (TempDir, Session) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut s = open(dir.path()).session();
+    s.execute("CREATE TABLE p (id INT PRIMARY KEY, name TEXT, tags TEXT, price FLOAT, qty INT)").unwrap();
+    s.execute(
+        "INSERT INTO p VALUES (1, ' Widget ', 'a,b,c', 2.5, 4), (2, 'gadget', 'x', NULL, 0), \
+         (3, 'Gizmo', NULL, 10.0, NULL)",
+    )
+    .unwrap();
+    (dir, s)
+}
+
+fn one(s: &mut Session, sql: &str) -> Vec<Value> {
+    query(s, sql).remove(0)
+}
+
+fn ids(s: &mut Session, sql: &str) -> Vec<Value> {
+    query(s, sql).into_iter().map(|r| r[0].clone()).collect()
+}
+
+#[test]
+fn string_functions() {
+    let (_dir, mut s) = products();
+    assert_eq!(
+        one(
+            &mut s,
+            "SELECT upper(trim(name)), lower(name), length(name), substr(name, 2, 3), \
+             replace(name, 'dg', 'D'), split_part(tags, ',', 2), position('d' IN name) FROM p WHERE id = 2"
+        ),
+        vec![text("GADGET"), text("gadget"), Value::Int(6), text("adg"), text("gaDet"), text(""), Value::Int(3)]
+    );
+    assert_eq!(
+        one(
+            &mut s,
+            "SELECT TRIM(LEADING 'G' FROM name), SUBSTRING(name FROM 3), concat(name, '-', qty, NULL) \
+             FROM p WHERE id = 3"
+        ),
+        vec![text("izmo"), text("zmo"), text("Gizmo-")]
+    );
+}
+
+#[test]
+fn numeric_functions() {
+    let (_dir, mut s) = products();
+    assert_eq!(
+        one(&mut s, "SELECT abs(-3), round(2.5), round(2.346, 2), mod(7, 3), power(2, 3), 7 % 3"),
+        vec![Value::Int(3), Value::Float(3.0), Value::Float(2.35), Value::Int(1), Value::Float(8.0), Value::Int(1)]
+    );
+}
+
+#[test]
+fn conditionals_take_the_common_type() {
+    let (_dir, mut s) = products();
+    // COALESCE takes the common type of its arguments, CASE of its results.
+    let r = s
+        .execute(
+            "SELECT id, coalesce(price, qty, 0), nullif(qty, 0), greatest(qty, 2), \
+             CASE WHEN qty > 2 THEN 'many' WHEN qty > 0 THEN 'few' ELSE 'none' END, \
+             CASE id WHEN 1 THEN 1 WHEN 2 THEN 2.5 END FROM p ORDER BY id",
+        )
+        .unwrap();
+    let types: Vec<ColumnType> = r.columns.iter().map(|c| c.1).collect();
+    use ColumnType::*;
+    assert_eq!(types, vec![Int, Float, Int, Int, Text, Float]);
+    assert_eq!(r.columns[4].0, "case");
+    assert_eq!(
+        r.rows,
+        vec![
+            vec![Value::Int(1), Value::Float(2.5), Value::Int(4), Value::Int(4), text("many"), Value::Float(1.0)],
+            vec![Value::Int(2), Value::Float(0.0), Value::Null, Value::Int(2), text("none"), Value::Float(2.5)],
+            vec![Value::Int(3), Value::Float(10.0), Value::Null, Value::Int(2), text("none"), Value::Null],
+        ]
+    );
+}
+
+#[test]
+fn casts() {
+    let (_dir, mut s) = products();
+    assert_eq!(
+        one(&mut s, "SELECT CAST('42' AS INT) + 1, CAST(price AS INT), qty::TEXT || '!' FROM p WHERE id = 1"),
+        vec![Value::Int(43), Value::Int(3), text("4!")]
+    );
+    let err = s.execute("SELECT CAST(name AS INT) FROM p").unwrap_err();
+    assert!(err.to_string().contains("invalid input syntax for type INT"), "{}", err);
+}
+
+#[test]
+fn like_ilike_and_regular_expressions() {
+    let (_dir, mut s) = products();
+    assert_eq!(ids(&mut s, "SELECT id FROM p WHERE name LIKE 'G%' ORDER BY id"), vec![Value::Int(3)]);
+    let ilike = ids(&mut s, "SELECT id FROM p WHERE name ILIKE 'g%' ORDER BY id");
+    assert_eq!(ilike, vec![Value::Int(2), Value::Int(3)]);
+    let not_like = ids(&mut s, "SELECT id FROM p WHERE name NOT LIKE '%adg%' ORDER BY id");
+    assert_eq!(not_like, vec![Value::Int(1), Value::Int(3)]);
+    assert_eq!(ids(&mut s, "SELECT id FROM p WHERE name ~ '^[A-Z]' ORDER BY id"), vec![Value::Int(3)]);
+    assert_eq!(ids(&mut s, "SELECT id FROM p WHERE name !~* '^g' ORDER BY id"), vec![Value::Int(1)]);
+    let text_plan = plan_text(&mut s, "EXPLAIN SELECT id FROM p WHERE lower(name) LIKE 'g%'");
+    assert!(text_plan.iter().any(|l| l.contains("(lower(p.name) LIKE 'g%')")), "{:?}", text_plan);
+}
+
+#[test]
+fn functions_over_aggregates() {
+    let (_dir, mut s) = products();
+    assert_eq!(
+        one(&mut s, "SELECT upper(max(name)), round(sum(price)), coalesce(sum(qty), 0) * 2 FROM p"),
+        vec![text("GADGET"), Value::Float(13.0), Value::Int(8)]
+    );
+}
+
+#[test]
+fn signatures_are_checked_when_planned() {
+    let (_dir, mut s) = products();
+    for (sql, message) in [
+        ("SELECT lower(qty) FROM p", "function lower(INT) does not exist"),
+        ("SELECT nosuch(1)", "function nosuch(INT) does not exist"),
+        ("SELECT coalesce(name, qty) FROM p", "COALESCE types TEXT and INT cannot be matched"),
+        ("SELECT CASE WHEN id = 1 THEN 'a' ELSE 2 END FROM p", "CASE types TEXT and INT cannot be matched"),
+        ("SELECT CASE WHEN qty THEN 1 END FROM p", "argument of CASE/WHEN must be type BOOL, not type INT"),
+        ("SELECT CAST(price AS BOOL) FROM p", "cannot cast type FLOAT to BOOL"),
+        ("SELECT id FROM p WHERE qty LIKE '1%'", "function like(INT, TEXT) does not exist"),
+        ("SELECT length(upper(max(qty))) FROM p", "function upper(INT) does not exist"),
+    ] {
+        let err = s.execute(sql).unwrap_err();
+        assert!(err.to_string().contains(message), "{}: {}", sql, err);
+    }
+    let err = s.execute("SELECT split_part(tags, ',', 0) FROM p").unwrap_err();
+    assert!(err.to_string().contains("field position must not be zero"), "{}", err);
+}
+
+#[test]
+fn parameters_take_their_types_from_the_signature() {
+    let (_dir, mut s) = products();
+    // Or, for COALESCE, from the other arguments.
+    let d = s.prepare("f", "SELECT substr($1, $2), coalesce(price, $3) FROM p WHERE name LIKE $4", &[]).unwrap();
+    use ColumnType::*;
+    assert_eq!(d.param_types, vec![Text, Int, Float, Text]);
+}
@@ -3,7 +3,11 @@
 use rustdb::storage::{
     row_encode, row_decode, Value, ColumnType, Page, PageFlags, HeapFile, BTree, RowRef,
 };
-use rustdb::Config;
+use rustdb::storage::Table;
+use rustdb::txn::{IsolationLevel, TxnError, TxnManager};
+use rustdb::catalog::{ColumnDef, TableDef};
+use rustdb::{Config, Database};
+use std::sync::Arc;
 use tempfile::NamedTempFile;
 
 #[test]
@@ -57,3 +61,115 @@ fn phase2_heap_btree_integration() {
     assert_eq!(decoded[0], Value::Int(20));
     assert_eq!(decoded[1], Value::Text("bob".to_string()));
 }
+
+/// Classic write skew: two doctors are on call, each transaction checks that the other is
+/// still on call and takes itself off. Returns (first commit, second commit) results.
+fn run_write_skew(isolation: IsolationLevel) -> (rustdb::Result<()>, rustdb::Result<()>) {
+    let dir = tempfile::tempdir().unwrap();
+    let schema = vec![ColumnType::Int, ColumnType::Bool];
+    let doctors = Table::create(dir.path(), "doctors", schema, 0).unwrap();
+    let mgr = Arc::new(TxnManager::new(&Config::default_config()));
+    let mut setup = mgr.begin(IsolationLevel::RepeatableRead);
+    for id in [1, 2] {
+        setup.insert(&doctors, vec![Value::Int(id), Value::Bool(true)]).unwrap();
+    }
+    setup.commit().unwrap();
+
+    let on_call = |rows: Vec<Vec<Value>>| rows.iter().filter(|r| r[1] == Value::Bool(true)).count();
+    let mut t1 = mgr.begin(isolation);
+    let mut t2 = mgr.begin(isolation);
+    assert_eq!(on_call(t1.scan(&doctors).unwrap()), 2);
+    assert_eq!(on_call(t2.scan(&doctors).unwrap()), 2);
+    t1.update(&doctors, 1, vec![Value::Int(1), Value::Bool(false)]).unwrap();
+    let w2 = t2.update(&doctors, 2, vec![Value::Int(2), Value::Bool(false)]);
+    let r1 = t1.commit();
+    let r2 = w2.and_then(|_| t2.commit());
+    (r1, r2)
+}
+
+#[test]
+fn snapshot_isolation_allows_write_skew() {
+    let (r1, r2) = run_write_skew(IsolationLevel::RepeatableRead);
+    assert!(r1.is_ok() && r2.is_ok());
+}
+
+#[test]
+fn serializable_prevents_write_skew() {
+    let (r1, r2) = run_write_skew(IsolationLevel::Serializable);
+    assert!(r1.is_ok());
+    let err = r2.unwrap_err();
+    let txn_err = err.downcast_ref::<TxnError>().expect("TxnError");
+    assert!(matches!(txn_err, TxnError::SerializationFailure { .. }));
+    assert!(txn_err.is_retryable());
+}
+
+#[test]
+fn serializable_read_only_and_disjoint_transactions_commit() {
+    let dir = tempfile::tempdir().unwrap();
+    let schema = vec![ColumnType::Int, ColumnType::Int];
+    let t = Table::create(dir.path(), "kv", schema, 0).unwrap();
+    let mgr = Arc::new(TxnManager::new(&Config::default_config()));
+    let mut a = mgr.begin(IsolationLevel::Serializable);
+    let mut b = mgr.begin(IsolationLevel::Serializable);
+    assert_eq!(a.get(&t, 1).unwrap(), None);
+    b.insert(&t, vec![Value::Int(2), Value::Int(0)]).unwrap();
+    a.insert(&t, vec![Value::Int(1), Value::Int(0)]).unwrap();
+    assert_eq!(b.get(&t, 3).unwrap(), None);
+    a.commit().unwrap();
+    b.commit().unwrap();
+}
+
+#[tokio::test]
+async fn autovacuum_reclaims_deleted_rows_in_background() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(Config {
+        data_dir: dir.path().to_string_lossy().into_owned(),
+        wal_sync: false,
+        autovacuum_naptime_ms: 20,
+        autovacuum_vacuum_threshold: 10,
+        autovacuum_vacuum_scale_factor: 0.0,
+        ..Config::default()
+    })
+    .unwrap();
+    let t = db
+        .create_table(TableDef {
+            name: "events".into(),
+            columns: vec![
+                ColumnDef::new("id", ColumnType::Int),
+                ColumnDef::new("payload", ColumnType::Text),
+            ],
+            key_col: 0,
+            constraints: Vec::new(),
+            triggers: Vec::new(),
+        })
+        .unwrap();
+    let mut w = db.begin(IsolationLevel::ReadCommitted);
+    for k in 0..1000 {
+        w.insert(&t, vec![Value::Int(k), Value::Text("x".repeat(100))]).unwrap();
+    }
+    w.commit().unwrap();
+    let full = t.num_pages();
+    let mut d = db.begin(IsolationLevel::ReadCommitted);
+    for k in 100..1000 {
+        d.delete(&t, k).unwrap();
+    }
+    d.commit().unwrap();
+
+    let task = db.spawn_autovacuum().unwrap();
+    let mut waited = 0;
+    while t.num_pages() == full && waited < 5000 {
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        waited += 20;
+    }
+    assert!(t.num_pages() < full, "autovacuum did not truncate the heap");
+    let mut r = db.begin(IsolationLevel::RepeatableRead);
+    assert_eq!(r.scan(&t).unwrap().len(), 100);
+    assert_eq!(r.range(&t, 0, 2000).unwrap().len(), 100);
+    drop(r);
+    drop(t);
+    drop(db);
+    tokio::time::timeout(std::time::Duration::from_secs(5), task)
+        .await
+        .expect("autovacuum task stops when the database is dropped")
+        .unwrap();
+}
@@ -0,0 +1,128 @@
+//! Inner, outer, semi and anti joins, and the join operators the planner picks for them.
+
+mod common;
+
+use common::{config, lines, open, plan_text, query};
+use rustdb::storage::Value;
+use rustdb::{Config, Database, Session};
+use tempfile::TempDir;
+
+/// `l` and `r` joined on `k`, with keys that match twice, once, never, and NULLs on both sides.
+fn tables() -> (TempDir, Session) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut s = open(dir.path()).session();
+    s.execute("CREATE TABLE l (id INT PRIMARY KEY, k INT)").unwrap();
+    s.execute("CREATE TABLE r (id INT PRIMARY KEY, k INT, v TEXT)").unwrap();
+    s.execute("INSERT INTO l VALUES (1, 10), (2, 20), (3, NULL), (4, 40)").unwrap();
+    s.execute("INSERT INTO r VALUES (10, 10, 'a'), (11, 10, 'b'), (30, 30, 'c'), (40, NULL, 'd')")
+        .unwrap();
+    (dir, s)
+}
+
+#[test]
+fn inner_outer_and_cross_joins() {
+    let (_dir, mut s) = tables();
+    let sql = "SELECT l.id, r.v FROM l JOIN r ON l.k = r.k ORDER BY 1, 2";
+    assert_eq!(lines(&mut s, sql), ["1 a", "1 b"]);
+    let sql = "SELECT l.id, r.v FROM l LEFT JOIN r ON l.k = r.k ORDER BY 1, 2";
+    assert_eq!(lines(&mut s, sql), ["1 a", "1 b", "2 NULL", "3 NULL", "4 NULL"]);
+    let sql = "SELECT l.id, r.v FROM l RIGHT JOIN r ON l.k = r.k ORDER BY 2, 1";
+    assert_eq!(lines(&mut s, sql), ["1 a", "1 b", "NULL c", "NULL d"]);
+    let sql = "SELECT l.id, r.v FROM l FULL OUTER JOIN r ON l.k = r.k ORDER BY 1, 2";
+    assert_eq!(lines(&mut s, sql), ["1 a", "1 b", "2 NULL", "3 NULL", "4 NULL", "NULL c", "NULL d"]);
+    assert_eq!(query(&mut s, "SELECT l.id FROM l CROSS JOIN r").len(), 16);
+}
+
+#[test]
+fn semi_and_anti_joins() {
+    let (_dir, mut s) = tables();
+    assert_eq!(lines(&mut s, "SELECT * FROM l LEFT SEMI JOIN r ON l.k = r.k"), ["1 10"]);
+    let sql = "SELECT id FROM l LEFT ANTI JOIN r ON l.k = r.k ORDER BY id";
+    assert_eq!(lines(&mut s, sql), ["2", "3", "4"]);
+    // The right side of a semi join is not in scope afterwards.
+    assert!(s.execute("SELECT r.v FROM l LEFT SEMI JOIN r ON l.k = r.k").is_err());
+}
+
+#[test]
+fn on_filters_the_inner_side_and_where_filters_the_result() {
+    let (_dir, mut s) = tables();
+    let sql = "SELECT l.id, r.v FROM l LEFT JOIN r ON l.k = r.k AND r.v = 'b' ORDER BY 1";
+    assert_eq!(lines(&mut s, sql), ["1 b", "2 NULL", "3 NULL", "4 NULL"]);
+    let sql = "SELECT l.id FROM l LEFT JOIN r ON l.k = r.k WHERE r.v IS NULL ORDER BY 1";
+    assert_eq!(lines(&mut s, sql), ["2", "3", "4"]);
+}
+
+#[test]
+fn outer_joins_followed_by_inner_joins() {
+    let (_dir, mut s) = tables();
+    // In one FROM item, and across items.
+    let sql = "SELECT a.id, b.id, r.v FROM l a LEFT JOIN l b ON a.id = b.id + 1 \
+               JOIN r ON r.id = a.k ORDER BY 1";
+    assert_eq!(lines(&mut s, sql), ["1 NULL a", "4 3 d"]);
+    let sql = "SELECT a.id, r.v FROM r, l a RIGHT JOIN l b ON a.id = b.id + 1 \
+               WHERE r.id = b.k ORDER BY 1";
+    assert_eq!(lines(&mut s, sql), ["2 a", "NULL d"]);
+}
+
+#[test]
+fn outer_join_on_the_key_of_a_large_table_uses_its_index() {
+    let (_dir, mut s) = tables();
+    s.execute("CREATE TABLE big (id INT PRIMARY KEY, v TEXT)").unwrap();
+    let values: Vec<String> = (0..2000).map(|i| format!("({}, 'v{}')", i, i)).collect();
+    s.execute(&format!("INSERT INTO big VALUES {}", values.join(", "))).unwrap();
+    let sql = "SELECT l.id, big.v FROM l LEFT JOIN big ON big.id = l.k ORDER BY 1";
+    let text = plan_text(&mut s, &format!("EXPLAIN {}", sql));
+    assert!(text.iter().any(|l| l.contains("Index Nested Loop Left Join")), "{:?}", text);
+    assert_eq!(lines(&mut s, sql), ["1 v10", "2 v20", "3 NULL", "4 v40"]);
+}
+
+/// `a` (3000 rows) and `b` (2000 rows, even ids) in a database with 16 KB of work memory.
+fn large_tables() -> (TempDir, Session) {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(Config { work_mem_kb: 16, ..config(dir.path()) }).unwrap();
+    let mut s = db.session();
+    s.execute("CREATE TABLE a (id INT PRIMARY KEY, x INT)").unwrap();
+    s.execute("CREATE TABLE b (id INT PRIMARY KEY, y INT)").unwrap();
+    let values: Vec<String> = (0..3000).map(|i| format!("({}, {})", i, i % 700)).collect();
+    s.execute(&format!("INSERT INTO a VALUES {}", values.join(", "))).unwrap();
+    let values: Vec<String> = (0..2000).map(|i| format!("({}, {})", i * 2, i % 500)).collect();
+    s.execute(&format!("INSERT INTO b VALUES {}", values.join(", "))).unwrap();
+    s.execute("ANALYZE").unwrap();
+    (dir, s)
+}
+
+#[test]
+fn full_join_keeps_unmatched_rows_of_both_sides() {
+    let (_dir, mut s) = large_tables();
+    let rows = query(&mut s, "SELECT a.id, b.y FROM a FULL JOIN b ON a.id = b.id");
+    assert_eq!(rows.len(), 3000 + 500);
+    assert_eq!(rows.iter().filter(|r| r[0].is_null()).count(), 500);
+    assert_eq!(rows.iter().filter(|r| r[1].is_null()).count(), 1500);
+}
+
+#[test]
+fn conditions_are_pushed_below_an_outer_join_where_sound() {
+    let (_dir, mut s) = large_tables();
+    let sql = "SELECT a.id, b.y FROM a LEFT JOIN b ON a.id = b.id AND b.id < 1000 WHERE a.id < 1500";
+    let text = plan_text(&mut s, &format!("EXPLAIN {}", sql));
+    assert!(text.iter().any(|l| l.trim() == "Filter: (b.id < 1000)"), "{:?}", text);
+    assert!(text.iter().any(|l| l.trim() == "Filter: (a.id < 1500)"), "{:?}", text);
+    let rows = query(&mut s, sql);
+    assert_eq!(rows.len(), 1500);
+    assert_eq!(rows.iter().filter(|r| r[1].is_null()).count(), 1000);
+}
+
+#[test]
+fn hash_join_partitions_inputs_larger_than_work_mem() {
+    let (_dir, mut s) = large_tables();
+    let sql = "SELECT a.id, b.id FROM a JOIN b ON a.x = b.y";
+    let text = plan_text(&mut s, &format!("EXPLAIN {}", sql));
+    assert!(text.iter().any(|l| l.contains("Hash Join")), "{:?}", text);
+    let rows = query(&mut s, sql);
+    let expected: usize = (0..3000).map(|i| if i % 700 < 500 { 4 } else { 0 }).sum();
+    assert_eq!(rows.len(), expected);
+    assert!(rows.iter().all(|r| match r[..] {
+        [Value::Int(a), Value::Int(b)] => a % 700 == b / 2 % 500,
+        _ => false,
+    }));
+}
@@ -0,0 +1,175 @@
+//! Statistics, EXPLAIN, and the plan cache.
+
+mod common;
+
+use common::{open, plan_text, query};
+use rustdb::storage::Value;
+use rustdb::Session;
+use tempfile::TempDir;
+
+/// `big` with 2000 rows in four groups, and `small` naming the groups.
+fn grouped() -> (TempDir, Session) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut s = open(dir.path()).session();
+    s.execute("CREATE TABLE big (id INT PRIMARY KEY, grp INT)").unwrap();
+    s.execute("CREATE TABLE small (id INT PRIMARY KEY, label TEXT)").unwrap();
+    let values: Vec<String> = (0..2000).map(|i| format!("({}, {})", i, i % 4)).collect();
+    s.execute(&format!("INSERT INTO big VALUES {}", values.join(", "))).unwrap();
+    s.execute("INSERT INTO small VALUES (0, 'zero'), (1, 'one'), (2, 'two'), (3, 'three')").unwrap();
+    (dir, s)
+}
+
+#[test]
+fn analyze_stores_table_statistics() {
+    let (_dir, mut s) = grouped();
+    let db = s.database().clone();
+    let r = s.execute("ANALYZE big").unwrap();
+    assert_eq!(r.rows[0][1], Value::Int(2000));
+    let stats = db.table_stats("big").unwrap();
+    assert_eq!(stats.row_count, 2000);
+    assert_eq!(stats.columns[1].n_distinct, 4.0);
+    assert_eq!(stats.columns[1].mcv.len(), 4);
+    assert!(db.table_stats("small").is_none());
+    s.execute("ANALYZE").unwrap();
+    assert!(db.table_stats("small").is_some());
+}
+
+#[test]
+fn planner_looks_keys_up_in_the_index() {
+    let (_dir, mut s) = grouped();
+    s.execute("ANALYZE").unwrap();
+    let text = plan_text(&mut s, "EXPLAIN SELECT grp FROM big WHERE id = 1500");
+    assert!(text.iter().any(|l| l.contains("Index Scan")), "{:?}", text);
+    assert_eq!(query(&mut s, "SELECT grp FROM big WHERE id = 1500"), vec![vec![Value::Int(0)]]);
+
+    let rows = query(
+        &mut s,
+        "SELECT big.id, small.label FROM big JOIN small ON big.grp = small.id \
+         WHERE big.id BETWEEN 10 AND 13 ORDER BY big.id",
+    );
+    let labels: Vec<Value> = rows.into_iter().map(|r| r[1].clone()).collect();
+    assert_eq!(
+        labels,
+        ["two", "three", "zero", "one"].map(|l| Value::Text(l.into())).to_vec()
+    );
+}
+
+/// `a` with 500 rows pointing into `b` with 50, analyzed.
+fn joined() -> (TempDir, Session) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut s = open(dir.path()).session();
+    s.execute("CREATE TABLE a (id INT PRIMARY KEY, b_id INT)").unwrap();
+    s.execute("CREATE TABLE b (id INT PRIMARY KEY, name TEXT)").unwrap();
+    let values: Vec<String> = (0..500).map(|i| format!("({}, {})", i, i % 50)).collect();
+    s.execute(&format!("INSERT INTO a VALUES {}", values.join(", "))).unwrap();
+    let values: Vec<String> = (0..50).map(|i| format!("({}, 'n{}')", i, i)).collect();
+    s.execute(&format!("INSERT INTO b VALUES {}", values.join(", "))).unwrap();
+    s.execute("ANALYZE").unwrap();
+    (dir, s)
+}
+
+#[test]
+fn explain_shows_the_plan_with_estimated_costs() {
+    let (_dir, mut s) = joined();
+    let text = plan_text(&mut s, "EXPLAIN SELECT name FROM a JOIN b ON a.b_id = b.id WHERE a.b_id < 10");
+    assert!(text[0].starts_with("Project  (cost="), "{:?}", text);
+    assert!(text.iter().any(|l| l.contains("->  Hash Join") || l.contains("->  Index Nested Loop")));
+    let text = plan_text(&mut s, "EXPLAIN SELECT * FROM a WHERE id = 7");
+    assert!(text.iter().any(|l| l.contains("->  Index Scan using a.idx on a  (cost=")), "{:?}", text);
+    assert!(text.iter().any(|l| l.trim() == "Index Cond: a.id = 7"));
+    assert!(!text.iter().any(|l| l.contains("actual")));
+}
+
+#[test]
+fn explain_analyze_counts_rows_and_buffers() {
+    let (dir, s) = joined();
+    // Reopen so the first scan has to read the heap from disk; the second finds it cached.
+    drop(s);
+    let db = open(dir.path());
+    let mut s = db.session();
+    let pages = db.table("a").unwrap().num_pages();
+    let scan = |s: &mut Session| {
+        let text = plan_text(s, "EXPLAIN ANALYZE SELECT * FROM a WHERE b_id = 3");
+        let line = text.iter().position(|l| l.contains("Seq Scan on a")).unwrap();
+        assert!(text[line].contains("(actual time="), "{:?}", text);
+        assert!(text[line].ends_with("rows=10 loops=1)"), "{:?}", text);
+        assert!(text.last().unwrap().starts_with("Execution Time: "));
+        text[line + 2].trim().to_string()
+    };
+    assert_eq!(scan(&mut s), format!("Buffers: hit=0 read={}", pages));
+    assert_eq!(scan(&mut s), format!("Buffers: hit={} read=0", pages));
+}
+
+#[test]
+fn explain_analyze_as_json() {
+    let (_dir, mut s) = joined();
+    let json = plan_text(&mut s, "EXPLAIN ANALYZE FORMAT JSON SELECT id FROM a ORDER BY id DESC LIMIT 3");
+    let json: serde_json::Value = serde_json::from_str(&json[0]).unwrap();
+    assert_eq!(json["plan"]["node_type"], "Limit");
+    assert_eq!(json["plan"]["actual_rows"], 3);
+    assert_eq!(json["plan"]["limit"], "3");
+    assert!(json["execution_time_ms"].is_number());
+}
+
+#[test]
+fn only_explain_analyze_runs_the_statement() {
+    let (_dir, mut s) = joined();
+    plan_text(&mut s, "EXPLAIN DELETE FROM a WHERE id < 5");
+    assert_eq!(query(&mut s, "SELECT id FROM a WHERE id < 5").len(), 5);
+    let text = plan_text(&mut s, "EXPLAIN ANALYZE DELETE FROM a WHERE id < 5");
+    assert!(text[0].starts_with("Delete on a") && text[0].contains("rows=5 loops=1"), "{:?}", text);
+    assert!(query(&mut s, "SELECT id FROM a WHERE id < 5").is_empty());
+    assert!(s.execute("EXPLAIN CREATE TABLE c (id INT)").is_err());
+}
+
+const CACHE_ENTRIES: &str =
+    "SELECT query, param_types, hits, misses, valid FROM rustdb_plan_cache WHERE query LIKE 'SELECT x %'";
+
+/// 100 rows, analyzed, and the statement `SELECT x FROM t WHERE id = <literal>` run for ids 3, 40 and 99.
+fn cached() -> (TempDir, Session) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut s = open(dir.path()).session();
+    s.execute("CREATE TABLE t (id INT PRIMARY KEY, x INT)").unwrap();
+    let values: Vec<String> = (0..100).map(|i| format!("({}, {})", i, i % 7)).collect();
+    s.execute(&format!("INSERT INTO t VALUES {}", values.join(", "))).unwrap();
+    s.execute("ANALYZE t").unwrap();
+    for id in [3, 40, 99] {
+        assert_eq!(query(&mut s, &format!("SELECT x FROM t WHERE id = {}", id)), vec![vec![Value::Int(id % 7)]]);
+    }
+    (dir, s)
+}
+
+#[test]
+fn plan_cache_shares_plans_across_literals() {
+    let (_dir, mut s) = cached();
+    // A float literal is a different parameter type, and so a different plan.
+    assert_eq!(query(&mut s, "SELECT x FROM t WHERE id = 3.0"), vec![vec![Value::Int(3)]]);
+    let entry = |ty: &str, hits, misses| {
+        let q = "SELECT x FROM t WHERE id = $1";
+        vec![Value::Text(q.into()), Value::Text(ty.into()), Value::Int(hits), Value::Int(misses), Value::Bool(true)]
+    };
+    assert_eq!(query(&mut s, CACHE_ENTRIES), vec![entry("INT", 2, 1), entry("FLOAT", 0, 1)]);
+}
+
+#[test]
+fn ddl_and_large_statistics_changes_invalidate_cached_plans() {
+    let (_dir, mut s) = cached();
+    // Statistics that barely moved keep the plans.
+    s.execute("INSERT INTO t VALUES (100, 2)").unwrap();
+    s.execute("ANALYZE t").unwrap();
+    assert_eq!(query(&mut s, CACHE_ENTRIES)[0][4], Value::Bool(true));
+    s.execute("CREATE TABLE u (a INT)").unwrap();
+    assert_eq!(query(&mut s, CACHE_ENTRIES)[0][4], Value::Bool(false));
+    query(&mut s, "SELECT x FROM t WHERE id = 5");
+    assert_eq!(query(&mut s, CACHE_ENTRIES)[0][2..], [Value::Int(2), Value::Int(2), Value::Bool(true)]);
+    let values: Vec<String> = (101..300).map(|i| format!("({}, {})", i, i % 7)).collect();
+    s.execute(&format!("INSERT INTO t VALUES {}", values.join(", "))).unwrap();
+    s.execute("ANALYZE t").unwrap();
+    assert_eq!(query(&mut s, CACHE_ENTRIES)[0][4], Value::Bool(false));
+
+    // A literal the plan depends on is left as written.
+    let grouped = query(&mut s, "SELECT x % 3, count(*) FROM t GROUP BY x % 3 ORDER BY 1");
+    assert_eq!(grouped.len(), 3);
+    let stats = query(&mut s, "SELECT entries, capacity, invalidations, evictions FROM rustdb_plan_cache_stats");
+    assert_eq!(stats[0][1..], [Value::Int(1000), Value::Int(3), Value::Int(0)]);
+}
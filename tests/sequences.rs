@@ -0,0 +1,120 @@
+//! Sequences, SERIAL and identity columns.
+
+mod common;
+
+use common::{open, query};
+use rustdb::sequence::DEFAULT_CACHE;
+use rustdb::storage::Value;
+use rustdb::Session;
+use tempfile::TempDir;
+
+fn fails(s: &mut Session, sql: &str, message: &str) {
+    let err = s.execute(sql).unwrap_err();
+    assert!(err.to_string().contains(message), "{}: {}", sql, err);
+}
+
+fn ints(rows: &[&[i64]]) -> Vec<Vec<Value>> {
+    rows.iter().map(|r| r.iter().map(|v| Value::Int(*v)).collect()).collect()
+}
+
+/// Two sessions on one database.
+fn sessions() -> (TempDir, Session, Session) {
+    let dir = tempfile::tempdir().unwrap();
+    let db = open(dir.path());
+    let (s, other) = (db.session(), db.session());
+    (dir, s, other)
+}
+
+#[test]
+fn values_are_shared_by_sessions_and_currval_is_per_session() {
+    let (_dir, mut s, mut other) = sessions();
+    s.execute("CREATE SEQUENCE s INCREMENT 5 START 10").unwrap();
+    assert!(s.execute("CREATE SEQUENCE s").is_err());
+    s.execute("CREATE SEQUENCE IF NOT EXISTS s").unwrap();
+    assert_eq!(query(&mut s, "SELECT nextval('s'), nextval('S')"), ints(&[&[10, 15]]));
+    assert_eq!(query(&mut s, "SELECT currval('s')"), ints(&[&[15]]));
+    fails(&mut other, "SELECT currval('s')", "not yet defined in this session");
+    assert_eq!(query(&mut other, "SELECT nextval('s')"), ints(&[&[20]]));
+    // A rollback keeps what was handed out.
+    s.execute("BEGIN").unwrap();
+    assert_eq!(query(&mut s, "SELECT nextval('s')"), ints(&[&[25]]));
+    s.execute("ROLLBACK").unwrap();
+    assert_eq!(query(&mut s, "SELECT nextval('s')"), ints(&[&[30]]));
+}
+
+#[test]
+fn setval_and_invalid_calls() {
+    let (_dir, mut s, _other) = sessions();
+    s.execute("CREATE SEQUENCE s INCREMENT 5 START 10").unwrap();
+    assert_eq!(query(&mut s, "SELECT setval('s', 100), nextval('s')"), ints(&[&[100, 105]]));
+    assert_eq!(query(&mut s, "SELECT setval('s', 100, false), nextval('s')"), ints(&[&[100, 100]]));
+    fails(&mut s, "SELECT setval('s', 0)", "out of bounds");
+    fails(&mut s, "SELECT nextval('nope')", "sequence nope does not exist");
+    fails(&mut s, "SELECT 1 LIMIT nextval('s')", "not allowed here");
+}
+
+#[test]
+fn serial_fills_the_column_unless_insert_sets_it() {
+    let (_dir, mut s, mut other) = sessions();
+    s.execute("CREATE TABLE orders (id SERIAL PRIMARY KEY, item TEXT)").unwrap();
+    s.execute("INSERT INTO orders (item) VALUES ('a'), ('b')").unwrap();
+    s.execute("INSERT INTO orders VALUES (10, 'x')").unwrap();
+    assert_eq!(query(&mut s, "INSERT INTO orders (item) VALUES ('c') RETURNING id"), ints(&[&[3]]));
+    // The cached plan of the INSERT calls nextval for whichever session runs it.
+    assert_eq!(query(&mut other, "INSERT INTO orders (item) VALUES ('d') RETURNING id"), ints(&[&[4]]));
+    assert_eq!(query(&mut other, "SELECT currval('orders_id_seq')"), ints(&[&[4]]));
+    assert_eq!(query(&mut s, "SELECT currval('orders_id_seq')"), ints(&[&[3]]));
+}
+
+#[test]
+fn generated_always_identity_columns_cannot_be_set() {
+    let (_dir, mut s, _other) = sessions();
+    s.execute(
+        "CREATE TABLE events (id INT GENERATED ALWAYS AS IDENTITY (INCREMENT BY 10 START WITH 100), name TEXT)",
+    )
+    .unwrap();
+    s.execute("INSERT INTO events (name) VALUES ('x'), ('y')").unwrap();
+    assert_eq!(query(&mut s, "SELECT id FROM events ORDER BY id"), ints(&[&[100], &[110]]));
+    fails(&mut s, "INSERT INTO events VALUES (1, 'z')", "GENERATED ALWAYS");
+    fails(&mut s, "UPDATE events SET id = 5", "can only be updated to DEFAULT");
+}
+
+#[test]
+fn a_table_owns_the_sequences_of_its_columns() {
+    let (_dir, mut s, _other) = sessions();
+    s.execute("CREATE TABLE orders (id SERIAL PRIMARY KEY, item TEXT)").unwrap();
+    fails(&mut s, "DROP SEQUENCE orders_id_seq", "table orders requires it");
+    fails(&mut s, "CREATE TABLE orders_id_seq (a INT)", "already exists");
+    s.execute("DROP TABLE orders").unwrap();
+    fails(&mut s, "SELECT nextval('orders_id_seq')", "does not exist");
+}
+
+#[test]
+fn sequences_resume_past_their_last_logged_block_after_a_restart() {
+    let (dir, mut s, other) = sessions();
+    s.execute("CREATE SEQUENCE s INCREMENT 5 START 10").unwrap();
+    assert_eq!(query(&mut s, "SELECT setval('s', 100, false), nextval('s')"), ints(&[&[100, 100]]));
+    s.execute("CREATE TABLE orders (id SERIAL PRIMARY KEY, item TEXT)").unwrap();
+    s.execute("INSERT INTO orders (item) VALUES ('a'), ('b')").unwrap();
+    // Also when a checkpoint truncated the log.
+    s.execute("CHECKPOINT").unwrap();
+    drop((s, other));
+    let mut s = open(dir.path()).session();
+    assert_eq!(query(&mut s, "SELECT nextval('s')"), ints(&[&[100 + DEFAULT_CACHE * 5]]));
+    s.execute("INSERT INTO orders (item) VALUES ('e')").unwrap();
+    assert_eq!(query(&mut s, "SELECT max(id) FROM orders"), ints(&[&[DEFAULT_CACHE + 1]]));
+}
+
+#[test]
+fn a_sequence_created_again_starts_over() {
+    let (dir, mut s, other) = sessions();
+    s.execute("CREATE SEQUENCE s").unwrap();
+    query(&mut s, "SELECT nextval('s')");
+    s.execute("DROP SEQUENCE s").unwrap();
+    s.execute("DROP SEQUENCE IF EXISTS s").unwrap();
+    s.execute("CREATE SEQUENCE s").unwrap();
+    s.execute("CHECKPOINT").unwrap();
+    drop((s, other));
+    let mut s = open(dir.path()).session();
+    assert_eq!(query(&mut s, "SELECT nextval('s')"), ints(&[&[1]]));
+}
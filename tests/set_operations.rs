@@ -0,0 +1,94 @@
+//! UNION, INTERSECT and EXCEPT, with and without ALL.
+
+mod common;
+
+use common::{config, plan_text, query};
+use rustdb::storage::{ColumnType, Value};
+use rustdb::{Config, Database, Session};
+use tempfile::TempDir;
+
+/// `a` and `b` with overlapping, repeated and NULL values, in a database with 16 KB of work memory.
+fn tables() -> (TempDir, Session) {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(Config { work_mem_kb: 16, ..config(dir.path()) }).unwrap();
+    let mut s = db.session();
+    s.execute("CREATE TABLE a (id INT PRIMARY KEY, v INT)").unwrap();
+    s.execute("CREATE TABLE b (id INT PRIMARY KEY, v INT)").unwrap();
+    s.execute("INSERT INTO a VALUES (1, 1), (2, 1), (3, 2), (4, 3), (5, NULL)").unwrap();
+    s.execute("INSERT INTO b VALUES (1, 1), (2, 2), (3, 2), (4, 4), (5, NULL)").unwrap();
+    (dir, s)
+}
+
+#[test]
+fn set_operations_with_and_without_all() {
+    let (_dir, mut s) = tables();
+    let ints = |rows: Vec<Vec<Value>>| -> Vec<Option<i64>> {
+        rows.into_iter()
+            .map(|r| match r[0] {
+                Value::Int(n) => Some(n),
+                _ => None,
+            })
+            .collect()
+    };
+    let cases = [
+        ("SELECT v FROM a UNION SELECT v FROM b ORDER BY v", vec![Some(1), Some(2), Some(3), Some(4), None]),
+        ("SELECT v FROM a UNION ALL SELECT v FROM b ORDER BY 1 LIMIT 4", vec![Some(1), Some(1), Some(1), Some(2)]),
+        ("SELECT v FROM a INTERSECT SELECT v FROM b ORDER BY v", vec![Some(1), Some(2), None]),
+        ("SELECT v FROM a INTERSECT ALL SELECT v FROM b ORDER BY v", vec![Some(1), Some(2), None]),
+        (
+            "SELECT v FROM b INTERSECT ALL SELECT v FROM b ORDER BY v",
+            vec![Some(1), Some(2), Some(2), Some(4), None],
+        ),
+        ("SELECT v FROM a EXCEPT SELECT v FROM b", vec![Some(3)]),
+        ("SELECT v FROM a EXCEPT ALL SELECT v FROM b ORDER BY v", vec![Some(1), Some(3)]),
+        // INTERSECT binds tighter than UNION.
+        ("SELECT 9 UNION SELECT v FROM a INTERSECT SELECT 3 ORDER BY 1", vec![Some(3), Some(9)]),
+        ("SELECT id FROM (SELECT id FROM a EXCEPT SELECT v FROM b) d ORDER BY id", vec![Some(3), Some(5)]),
+    ];
+    for (sql, expected) in cases {
+        assert_eq!(ints(query(&mut s, sql)), expected, "{}", sql);
+    }
+}
+
+#[test]
+fn result_columns_take_the_common_type_and_the_left_names() {
+    let (_dir, mut s) = tables();
+    let r = s.execute("SELECT v AS n FROM a WHERE id = 1 UNION ALL SELECT 2.5").unwrap();
+    assert_eq!(r.columns, vec![("n".to_string(), ColumnType::Float)]);
+    assert_eq!(r.rows, vec![vec![Value::Float(1.0)], vec![Value::Float(2.5)]]);
+    let r = s.execute("SELECT NULL, 1 UNION SELECT 'x', 2 ORDER BY 2").unwrap();
+    assert_eq!(r.columns[1].1, ColumnType::Int);
+    assert_eq!(r.rows[1], vec![Value::Text("x".into()), Value::Int(2)]);
+    let err = s.execute("SELECT 'a' UNION SELECT 1").unwrap_err();
+    assert!(err.to_string().contains("UNION types TEXT and INT cannot be matched"), "{}", err);
+    let err = s.execute("SELECT 1, 2 EXCEPT SELECT 1").unwrap_err();
+    assert!(err.to_string().contains("each EXCEPT query must have the same number of columns"), "{}", err);
+}
+
+#[test]
+fn union_all_chains_append_and_the_rest_use_setop() {
+    let (_dir, mut s) = tables();
+    let text = plan_text(&mut s, "EXPLAIN SELECT v FROM a UNION ALL SELECT v FROM b UNION ALL SELECT 1");
+    assert_eq!(text.iter().filter(|l| l.contains("Append")).count(), 1, "{:?}", text);
+    let text = plan_text(&mut s, "EXPLAIN SELECT v FROM a INTERSECT ALL SELECT v FROM b");
+    assert!(text.iter().any(|l| l.contains("SetOp")), "{:?}", text);
+    assert!(text.iter().any(|l| l.contains("Command: Intersect All")), "{:?}", text);
+}
+
+#[test]
+fn duplicate_elimination_spills_past_work_mem() {
+    let (_dir, mut s) = tables();
+    // Hashed or sorted, like other aggregates.
+    s.execute("CREATE TABLE big (id INT PRIMARY KEY, g INT)").unwrap();
+    let values: Vec<String> = (0..6000).map(|i| format!("({}, {})", i, i % 4000)).collect();
+    s.execute(&format!("INSERT INTO big VALUES {}", values.join(", "))).unwrap();
+    s.execute("ANALYZE").unwrap();
+    let sql = "SELECT g FROM big UNION SELECT g + 2000 FROM big";
+    let text = plan_text(&mut s, &format!("EXPLAIN {}", sql));
+    assert!(text[0].starts_with("HashAggregate"), "{:?}", text);
+    assert_eq!(query(&mut s, sql).len(), 6000);
+    let rows = query(&mut s, "SELECT g FROM big EXCEPT ALL SELECT id FROM big WHERE id < 3000");
+    assert_eq!(rows.len(), 3000);
+    let temp_dir = s.database().temp_dir();
+    assert!(std::fs::read_dir(temp_dir).map_or(true, |mut d| d.next().is_none()));
+}
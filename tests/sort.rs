@@ -0,0 +1,89 @@
+//! ORDER BY: external merge sort, top-N and ordered index scans.
+
+mod common;
+
+use common::{config, plan_text, query};
+use rustdb::storage::Value;
+use rustdb::{Config, Database, Session};
+use tempfile::TempDir;
+
+/// `v` of row `i` in `t`, NULL for every eleventh row.
+fn v(i: i64) -> Option<i64> {
+    if i % 11 == 0 {
+        None
+    } else {
+        Some(i * 37 % 1000)
+    }
+}
+
+/// 5000 rows in a database with 16 KB of work memory.
+fn table() -> (TempDir, Session) {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(Config { work_mem_kb: 16, ..config(dir.path()) }).unwrap();
+    let mut s = db.session();
+    s.execute("CREATE TABLE t (id INT PRIMARY KEY, v INT, name TEXT)").unwrap();
+    let values: Vec<String> = (0..5000)
+        .map(|i| match v(i) {
+            None => format!("({}, NULL, 'n{}')", i, i),
+            Some(v) => format!("({}, {}, 'n{}')", i, v, i),
+        })
+        .collect();
+    s.execute(&format!("INSERT INTO t VALUES {}", values.join(", "))).unwrap();
+    s.execute("ANALYZE").unwrap();
+    (dir, s)
+}
+
+fn ids(rows: &[Vec<Value>]) -> Vec<Value> {
+    rows.iter().map(|r| r[0].clone()).collect()
+}
+
+#[test]
+fn external_sort_merges_runs_from_disk() {
+    let (_dir, mut s) = table();
+    // 5000 rows do not fit in 16 KB: sorted runs go to disk and are merged.
+    let rows = query(&mut s, "SELECT id, v FROM t ORDER BY v DESC NULLS LAST, id");
+    let mut expected: Vec<i64> = (0..5000).collect();
+    expected.sort_by_key(|&i| (v(i).is_none(), std::cmp::Reverse(v(i)), i));
+    assert_eq!(ids(&rows), expected.iter().map(|&i| Value::Int(i)).collect::<Vec<_>>());
+    let temp_dir = s.database().temp_dir();
+    assert!(std::fs::read_dir(temp_dir).map_or(true, |mut d| d.next().is_none()));
+}
+
+#[test]
+fn limit_uses_a_top_n_heapsort() {
+    let (_dir, mut s) = table();
+    let sql = "SELECT id, v FROM t ORDER BY v NULLS FIRST, id DESC LIMIT 5 OFFSET 450";
+    let text = plan_text(&mut s, &format!("EXPLAIN {}", sql));
+    assert!(text.iter().any(|l| l.trim() == "Sort Method: top-N heapsort"), "{:?}", text);
+    let mut expected: Vec<i64> = (0..5000).collect();
+    expected.sort_by_key(|&i| (v(i), std::cmp::Reverse(i)));
+    let rows = query(&mut s, sql);
+    assert_eq!(ids(&rows), expected[450..455].iter().map(|&i| Value::Int(i)).collect::<Vec<_>>());
+}
+
+#[test]
+fn key_order_comes_from_the_index_without_a_sort() {
+    let (_dir, mut s) = table();
+    for sql in [
+        "SELECT id, name FROM t ORDER BY id LIMIT 3",
+        "SELECT id, name FROM t WHERE id >= 4990 ORDER BY id",
+    ] {
+        let text = plan_text(&mut s, &format!("EXPLAIN {}", sql));
+        assert!(text.iter().any(|l| l.contains("Index Scan")), "{:?}", text);
+        assert!(!text.iter().any(|l| l.contains("Sort")), "{:?}", text);
+    }
+    let rows = query(&mut s, "SELECT id FROM t ORDER BY id LIMIT 3");
+    assert_eq!(rows, vec![vec![Value::Int(0)], vec![Value::Int(1)], vec![Value::Int(2)]]);
+    let rows = query(&mut s, "SELECT id FROM t WHERE id >= 4990 ORDER BY id");
+    assert_eq!(rows.len(), 10);
+    assert!(rows.windows(2).all(|w| w[0][0].total_cmp(&w[1][0]).is_lt()));
+}
+
+#[test]
+fn descending_key_order_still_sorts() {
+    let (_dir, mut s) = table();
+    let text = plan_text(&mut s, "EXPLAIN SELECT id FROM t ORDER BY id DESC LIMIT 3");
+    assert!(text.iter().any(|l| l.contains("Sort")), "{:?}", text);
+    let rows = query(&mut s, "SELECT id FROM t ORDER BY id DESC LIMIT 3");
+    assert_eq!(rows, vec![vec![Value::Int(4999)], vec![Value::Int(4998)], vec![Value::Int(4997)]]);
+}
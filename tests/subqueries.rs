@@ -0,0 +1,210 @@
+//! Scalar, EXISTS and IN subqueries, derived tables, and common table expressions.
+
+mod common;
+
+use common::{open, plan_text, query};
+use rustdb::storage::Value;
+use rustdb::Session;
+use tempfile::TempDir;
+
+fn ints(v: &[i64]) -> Vec<Vec<Value>> {
+    v.iter().map(|&n| vec![Value::Int(n)]).collect()
+}
+
+/// Employees in departments, one of them in no department and one in a department not listed.
+fn departments() -> (TempDir, Session) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut s = open(dir.path()).session();
+    s.execute("CREATE TABLE emp (id INT PRIMARY KEY, dept INT, salary INT)").unwrap();
+    s.execute("CREATE TABLE dept (id INT PRIMARY KEY, name TEXT, boss INT)").unwrap();
+    s.execute("INSERT INTO emp VALUES (1, 10, 100), (2, 10, 200), (3, 20, 150), (4, 30, 50), (5, NULL, 70)")
+        .unwrap();
+    s.execute("INSERT INTO dept VALUES (10, 'eng', 2), (20, 'ops', NULL), (40, 'hr', 9)").unwrap();
+    (dir, s)
+}
+
+#[test]
+fn scalar_subqueries() {
+    let (_dir, mut s) = departments();
+    let rows = query(&mut s, "SELECT id FROM emp WHERE salary > (SELECT avg(salary) FROM emp) ORDER BY id");
+    assert_eq!(rows, ints(&[2, 3]));
+    let rows = query(
+        &mut s,
+        "SELECT id, (SELECT max(salary) FROM emp e2 WHERE e2.dept = emp.dept) FROM emp ORDER BY id",
+    );
+    let top: Vec<Value> = rows.iter().map(|r| r[1].clone()).collect();
+    assert_eq!(
+        top,
+        vec![Value::Int(200), Value::Int(200), Value::Int(150), Value::Int(50), Value::Null]
+    );
+    let text = plan_text(
+        &mut s,
+        "EXPLAIN ANALYZE SELECT id FROM emp \
+         WHERE salary > (SELECT avg(salary) FROM emp e2 WHERE e2.dept = emp.dept)",
+    );
+    assert!(text.iter().any(|l| l.contains("Apply")), "{:?}", text);
+    assert!(text.iter().any(|l| l.contains("Params: outer#0 = ")), "{:?}", text);
+    let err = s.execute("SELECT (SELECT id FROM emp)").unwrap_err();
+    assert!(err.to_string().contains("more than one row"), "{}", err);
+    let err = s.execute("SELECT (SELECT id, dept FROM emp)").unwrap_err();
+    assert!(err.to_string().contains("only one column"), "{}", err);
+}
+
+#[test]
+fn exists_and_in_become_semi_and_anti_joins() {
+    let (_dir, mut s) = departments();
+    let sql = "SELECT name FROM dept WHERE EXISTS (SELECT 1 FROM emp WHERE emp.dept = dept.id) ORDER BY name";
+    let text = plan_text(&mut s, &format!("EXPLAIN {}", sql));
+    assert!(text.iter().any(|l| l.contains("Semi Join")), "{:?}", text);
+    assert!(!text.iter().any(|l| l.contains("Apply")), "{:?}", text);
+    assert_eq!(query(&mut s, sql), vec![vec![Value::Text("eng".into())], vec![Value::Text("ops".into())]]);
+    let sql = "SELECT name FROM dept WHERE NOT EXISTS (SELECT 1 FROM emp WHERE emp.dept = dept.id)";
+    let text = plan_text(&mut s, &format!("EXPLAIN {}", sql));
+    assert!(text.iter().any(|l| l.contains("Anti Join")), "{:?}", text);
+    assert_eq!(query(&mut s, sql), vec![vec![Value::Text("hr".into())]]);
+    let sql = "SELECT id FROM emp WHERE dept IN (SELECT id FROM dept WHERE name <> 'hr') ORDER BY id";
+    let text = plan_text(&mut s, &format!("EXPLAIN {}", sql));
+    assert!(text.iter().any(|l| l.contains("Semi Join")), "{:?}", text);
+    assert_eq!(query(&mut s, sql), ints(&[1, 2, 3]));
+}
+
+#[test]
+fn not_in_follows_three_valued_logic() {
+    let (_dir, mut s) = departments();
+    // A NULL in the subquery leaves no row true.
+    let rows = query(&mut s, "SELECT id FROM emp WHERE dept NOT IN (SELECT id FROM dept) ORDER BY id");
+    assert_eq!(rows, ints(&[4]));
+    let rows = query(&mut s, "SELECT id FROM dept WHERE id NOT IN (SELECT boss FROM dept)");
+    assert_eq!(rows, ints(&[]));
+    let rows = query(&mut s, "SELECT id, dept IN (SELECT id FROM dept) FROM emp ORDER BY id");
+    let found: Vec<Value> = rows.iter().map(|r| r[1].clone()).collect();
+    let b = Value::Bool;
+    assert_eq!(found, vec![b(true), b(true), b(true), b(false), Value::Null]);
+}
+
+#[test]
+fn subqueries_in_having_and_over_group_keys() {
+    let (_dir, mut s) = departments();
+    let rows = query(
+        &mut s,
+        "SELECT dept, count(*) FROM emp GROUP BY dept \
+         HAVING sum(salary) > (SELECT min(salary) * 2 FROM emp) ORDER BY dept",
+    );
+    assert_eq!(rows, vec![vec![Value::Int(10), Value::Int(2)], vec![Value::Int(20), Value::Int(1)]]);
+    let rows = query(
+        &mut s,
+        "SELECT dept, (SELECT name FROM dept WHERE dept.id = emp.dept) FROM emp \
+         WHERE dept IS NOT NULL GROUP BY dept ORDER BY dept",
+    );
+    assert_eq!(rows[0], vec![Value::Int(10), Value::Text("eng".into())]);
+    assert_eq!(rows[2], vec![Value::Int(30), Value::Null]);
+    let err = s
+        .execute("SELECT dept FROM emp GROUP BY dept HAVING (SELECT salary) > 0")
+        .unwrap_err();
+    assert!(err.to_string().contains("ungrouped column"), "{}", err);
+}
+
+#[test]
+fn derived_tables() {
+    let (_dir, mut s) = departments();
+    let rows = query(
+        &mut s,
+        "SELECT d, total FROM (SELECT dept, sum(salary) FROM emp GROUP BY dept) AS t (d, total) \
+         WHERE total > 100 ORDER BY d",
+    );
+    assert_eq!(rows, vec![vec![Value::Int(10), Value::Int(300)], vec![Value::Int(20), Value::Int(150)]]);
+    let err = s.execute("SELECT * FROM (SELECT 1)").unwrap_err();
+    assert!(err.to_string().contains("must have an alias"), "{}", err);
+}
+
+#[test]
+fn update_and_delete_with_subqueries() {
+    let (_dir, mut s) = departments();
+    s.execute("UPDATE emp SET salary = (SELECT max(salary) FROM emp e2 WHERE e2.dept = emp.dept) WHERE dept = 10")
+        .unwrap();
+    let rows = query(&mut s, "SELECT salary FROM emp WHERE dept = 10");
+    assert_eq!(rows, ints(&[200, 200]));
+    s.execute("DELETE FROM emp WHERE NOT EXISTS (SELECT 1 FROM dept WHERE dept.id = emp.dept)").unwrap();
+    assert_eq!(query(&mut s, "SELECT id FROM emp ORDER BY id"), ints(&[1, 2, 3]));
+    s.execute("DELETE FROM emp WHERE salary < (SELECT max(salary) FROM emp)").unwrap();
+    assert_eq!(query(&mut s, "SELECT id FROM emp ORDER BY id"), ints(&[1, 2]));
+}
+
+/// An org chart five deep: ceo, cto and cfo under it, dev under the cto, intern under dev.
+fn org_chart() -> (TempDir, Session) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut s = open(dir.path()).session();
+    s.execute("CREATE TABLE org (id INT PRIMARY KEY, boss INT, name TEXT)").unwrap();
+    s.execute(
+        "INSERT INTO org VALUES (1, NULL, 'ceo'), (2, 1, 'cto'), (3, 1, 'cfo'), (4, 2, 'dev'), (5, 4, 'intern')",
+    )
+    .unwrap();
+    (dir, s)
+}
+
+#[test]
+fn common_table_expressions() {
+    let (_dir, mut s) = org_chart();
+    let rows = query(
+        &mut s,
+        "WITH bosses AS (SELECT boss FROM org WHERE boss IS NOT NULL) \
+         SELECT id FROM org WHERE id IN (SELECT boss FROM bosses) ORDER BY id",
+    );
+    assert_eq!(rows, ints(&[1, 2, 4]));
+    let rows = query(
+        &mut s,
+        "WITH a (n) AS (SELECT id FROM org WHERE boss = 1), b AS (SELECT n * 10 AS m FROM a) \
+         SELECT m FROM b ORDER BY m",
+    );
+    assert_eq!(rows, ints(&[20, 30]));
+    let err = s.execute("WITH a AS (SELECT 1), a AS (SELECT 2) SELECT * FROM a").unwrap_err();
+    assert!(err.to_string().contains("specified more than once"), "{}", err);
+}
+
+#[test]
+fn recursive_cte_walks_a_hierarchy() {
+    let (_dir, mut s) = org_chart();
+    // The hierarchy under the CTO, with depths.
+    let sql = "WITH RECURSIVE sub (id, depth) AS (SELECT id, 0 FROM org WHERE name = 'cto' \
+               UNION ALL SELECT org.id, depth + 1 FROM org JOIN sub ON org.boss = sub.id) \
+               SELECT id, depth FROM sub ORDER BY id";
+    let rows = query(&mut s, sql);
+    assert_eq!(
+        rows,
+        vec![
+            vec![Value::Int(2), Value::Int(0)],
+            vec![Value::Int(4), Value::Int(1)],
+            vec![Value::Int(5), Value::Int(2)]
+        ]
+    );
+    let text = plan_text(&mut s, &format!("EXPLAIN {}", sql));
+    assert!(text.iter().any(|l| l.contains("Recursive Union")), "{:?}", text);
+    assert!(text.iter().any(|l| l.contains("WorkTable Scan")), "{:?}", text);
+    let text = plan_text(&mut s, &format!("EXPLAIN ANALYZE {}", sql));
+    assert!(text.iter().any(|l| l.contains("Recursive Union") && l.contains("rows=3")), "{:?}", text);
+}
+
+#[test]
+fn recursive_cte_over_a_cycle() {
+    let (_dir, mut s) = org_chart();
+    // A cycle ends under UNION, and fails under UNION ALL once max_recursion_depth is hit.
+    s.execute("UPDATE org SET boss = 5 WHERE id = 1").unwrap();
+    let sql = "WITH RECURSIVE up (id) AS (SELECT 5 UNION SELECT org.boss FROM org JOIN up ON org.id = up.id) \
+               SELECT count(*) FROM up";
+    assert_eq!(query(&mut s, sql), ints(&[4]));
+    let err = s.execute(&sql.replace("UNION", "UNION ALL")).unwrap_err();
+    assert!(err.to_string().contains("max_recursion_depth"), "{}", err);
+}
+
+#[test]
+fn malformed_recursive_ctes_are_rejected() {
+    let (_dir, mut s) = org_chart();
+    let err = s
+        .execute("WITH RECURSIVE r (n) AS (SELECT 1 UNION ALL SELECT 2) SELECT * FROM r")
+        .unwrap_err();
+    assert!(err.to_string().contains("must refer to itself"), "{}", err);
+    let err = s
+        .execute("WITH RECURSIVE r (n) AS (SELECT 1 UNION ALL SELECT n, n FROM r) SELECT * FROM r")
+        .unwrap_err();
+    assert!(err.to_string().contains("same number of columns"), "{}", err);
+}
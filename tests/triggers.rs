@@ -0,0 +1,242 @@
+//! Row and statement triggers, with function and SQL bodies.
+
+mod common;
+
+use anyhow::{bail, Result};
+use common::{open, query};
+use rustdb::query::trigger::TriggerCall;
+use rustdb::query::udf::TriggerUdf;
+use rustdb::storage::Value;
+use rustdb::{Database, Session};
+use std::sync::Arc;
+use tempfile::TempDir;
+
+/// BEFORE ROW: no negative balances, and no accounts of nobody.
+struct Clamp;
+impl TriggerUdf for Clamp {
+    fn call(&self, call: &mut TriggerCall<'_>) -> Result<()> {
+        let Some(new) = call.new.as_mut() else {
+            bail!("clamp is a row-level INSERT or UPDATE trigger");
+        };
+        if new[2].as_f64().is_some_and(|b| b < 0.0) {
+            new[2] = Value::Int(0);
+        }
+        match &new[1] {
+            Value::Text(owner) if owner == "nobody" => call.skip(),
+            Value::Text(owner) if owner == "bad" => bail!("bad owner"),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// AFTER ROW: keeps the number of accounts in `counts`.
+struct CountAccounts;
+impl TriggerUdf for CountAccounts {
+    fn call(&self, call: &mut TriggerCall<'_>) -> Result<()> {
+        let delta = match (call.old, &call.new) {
+            (None, Some(_)) => 1,
+            (Some(_), None) => -1,
+            _ => 0,
+        };
+        call.execute("UPDATE counts SET n = n + $1 WHERE id = 1", &[Value::Int(delta)])?;
+        Ok(())
+    }
+}
+
+/// BEFORE ROW: skips every row.
+struct Keep;
+impl TriggerUdf for Keep {
+    fn call(&self, call: &mut TriggerCall<'_>) -> Result<()> {
+        call.skip();
+        Ok(())
+    }
+}
+
+fn fails(s: &mut Session, sql: &str, message: &str) {
+    let err = s.execute(sql).unwrap_err();
+    assert!(err.to_string().contains(message), "{}: {}", sql, err);
+}
+
+fn ints(rows: &[&[i64]]) -> Vec<Vec<Value>> {
+    rows.iter().map(|r| r.iter().map(|v| Value::Int(*v)).collect()).collect()
+}
+
+fn counts(s: &mut Session) -> Vec<Vec<Value>> {
+    query(s, "SELECT id, n FROM counts ORDER BY id")
+}
+
+fn register(db: &Arc<Database>) {
+    db.register_trigger_function("clamp", Clamp).unwrap();
+    db.register_trigger_function("count_accounts", CountAccounts).unwrap();
+}
+
+/// `accounts` with four triggers: `clamp` before each write, `count_accounts` keeping
+/// `counts` row 1, an SQL body writing `audit`, and a statement-level one counting
+/// DELETE statements in `counts` row 2.
+fn accounts() -> (TempDir, Session) {
+    let dir = tempfile::tempdir().unwrap();
+    let db = open(dir.path());
+    register(&db);
+    db.register_trigger_function("keep", Keep).unwrap();
+    let mut s = db.session();
+    for sql in [
+        "CREATE TABLE accounts (id INT PRIMARY KEY, owner TEXT, balance INT)",
+        "CREATE TABLE audit (id SERIAL PRIMARY KEY, account INT, old_balance INT, new_balance INT)",
+        "CREATE TABLE counts (id INT PRIMARY KEY, n INT)",
+        "INSERT INTO counts VALUES (1, 0), (2, 0)",
+        "CREATE TRIGGER a_clamp BEFORE INSERT OR UPDATE ON accounts FOR EACH ROW EXECUTE FUNCTION clamp()",
+        "CREATE TRIGGER b_count AFTER INSERT OR DELETE ON accounts FOR EACH ROW EXECUTE FUNCTION count_accounts()",
+        "CREATE TRIGGER c_audit AFTER UPDATE OR DELETE ON accounts FOR EACH ROW
+            BEGIN
+                INSERT INTO audit (account, old_balance, new_balance) VALUES (OLD.id, OLD.balance, new.balance);
+            END;",
+        "CREATE TRIGGER d_deletes AFTER DELETE ON accounts UPDATE counts SET n = n + 1 WHERE id = 2",
+    ] {
+        s.execute(sql).unwrap();
+    }
+    (dir, s)
+}
+
+#[test]
+fn invalid_triggers_are_rejected() {
+    let (_dir, mut s) = accounts();
+    assert!(s.database().register_trigger_function("Clamp", Clamp).is_err());
+    fails(&mut s, "CREATE TRIGGER a_clamp AFTER DELETE ON accounts EXECUTE FUNCTION clamp()", "already exists");
+    fails(&mut s, "CREATE TRIGGER t AFTER INSERT ON nope EXECUTE FUNCTION clamp()", "table nope does not exist");
+    fails(&mut s, "CREATE TRIGGER t AFTER INSERT ON accounts EXECUTE FUNCTION nope()", "function nope");
+    fails(&mut s, "CREATE TRIGGER t AFTER INSERT ON accounts SELECT NEW.id", "statement-level trigger t");
+    fails(&mut s, "CREATE TRIGGER t AFTER INSERT ON accounts FOR EACH ROW SELECT NEW.nope", "has no field nope");
+    fails(&mut s, "CREATE TRIGGER t AFTER INSERT ON accounts FOR EACH ROW SELECT * FROM nope", "nope");
+}
+
+#[test]
+fn before_row_triggers_change_or_skip_the_row() {
+    let (_dir, mut s) = accounts();
+    // AFTER ROW ones and SQL bodies see what was written.
+    s.execute("INSERT INTO accounts VALUES (1, 'ann', 10), (2, 'bob', -5), (3, 'nobody', 7)").unwrap();
+    assert_eq!(
+        query(&mut s, "SELECT id, balance FROM accounts ORDER BY id"),
+        ints(&[&[1, 10], &[2, 0]])
+    );
+    assert_eq!(counts(&mut s), ints(&[&[1, 2], &[2, 0]]));
+    s.execute("UPDATE accounts SET balance = balance - 20 WHERE id = 1").unwrap();
+    s.execute("INSERT INTO accounts VALUES (2, 'bob', 4) ON CONFLICT (id) DO UPDATE SET balance = 9").unwrap();
+    assert_eq!(
+        query(&mut s, "SELECT account, old_balance, new_balance FROM audit ORDER BY id"),
+        ints(&[&[1, 10, 0], &[2, 0, 9]])
+    );
+}
+
+#[test]
+fn statement_triggers_fire_once_even_for_no_rows() {
+    let (_dir, mut s) = accounts();
+    // Row-level DELETE triggers see OLD with NEW NULL.
+    s.execute("INSERT INTO accounts VALUES (1, 'ann', 10), (2, 'bob', 9)").unwrap();
+    s.execute("DELETE FROM accounts WHERE id = 100").unwrap();
+    s.execute("DELETE FROM accounts WHERE id = 2").unwrap();
+    assert_eq!(counts(&mut s), ints(&[&[1, 1], &[2, 2]]));
+    assert_eq!(
+        query(&mut s, "SELECT account, old_balance, new_balance FROM audit"),
+        vec![vec![Value::Int(2), Value::Int(9), Value::Null]]
+    );
+}
+
+#[test]
+fn triggers_run_in_the_statements_transaction() {
+    let (_dir, mut s) = accounts();
+    // A failing one undoes the statement, and a rollback what they did.
+    s.execute("INSERT INTO accounts VALUES (1, 'ann', 10)").unwrap();
+    fails(&mut s, "INSERT INTO accounts VALUES (4, 'bad', 1)", "bad owner");
+    s.execute("BEGIN").unwrap();
+    s.execute("INSERT INTO accounts VALUES (5, 'eve', 1)").unwrap();
+    fails(&mut s, "INSERT INTO accounts VALUES (6, 'bad', 1)", "bad owner");
+    assert_eq!(counts(&mut s), ints(&[&[1, 2], &[2, 0]]));
+    s.execute("ROLLBACK").unwrap();
+    assert_eq!(counts(&mut s), ints(&[&[1, 1], &[2, 0]]));
+    assert_eq!(query(&mut s, "SELECT count(*) FROM accounts"), ints(&[&[1]]));
+}
+
+#[test]
+fn triggers_firing_each_other_give_up_past_a_depth() {
+    let (_dir, mut s) = accounts();
+    s.execute("CREATE TABLE chain (n INT PRIMARY KEY)").unwrap();
+    s.execute("CREATE TRIGGER next AFTER INSERT ON chain FOR EACH ROW INSERT INTO chain VALUES (NEW.n + 1)")
+        .unwrap();
+    fails(&mut s, "INSERT INTO chain VALUES (1)", "nested too deeply");
+    s.execute("DROP TRIGGER next ON chain").unwrap();
+    s.execute("DROP TRIGGER IF EXISTS next ON chain").unwrap();
+    fails(&mut s, "DROP TRIGGER next ON chain", "trigger next for table chain does not exist");
+    s.execute("INSERT INTO chain VALUES (1)").unwrap();
+}
+
+#[test]
+fn trigger_bodies_see_the_tables_as_they_are() {
+    let (_dir, mut s) = accounts();
+    // Bodies are planned once per catalog version.
+    let db = s.database().clone();
+    s.execute("CREATE TABLE chain (n INT PRIMARY KEY)").unwrap();
+    s.execute("CREATE TABLE log (n INT, tag TEXT DEFAULT 'a')").unwrap();
+    let version = db.catalog_version();
+    s.execute("CREATE TRIGGER log_n AFTER INSERT ON chain FOR EACH ROW INSERT INTO log (n) VALUES (NEW.n)")
+        .unwrap();
+    assert!(db.catalog_version() > version);
+    s.execute("INSERT INTO chain VALUES (2)").unwrap();
+    s.execute("DROP TABLE log").unwrap();
+    s.execute("CREATE TABLE log (n INT, tag TEXT DEFAULT 'b')").unwrap();
+    s.execute("INSERT INTO chain VALUES (3)").unwrap();
+    assert_eq!(query(&mut s, "SELECT n, tag FROM log"), vec![vec![Value::Int(3), Value::Text("b".into())]]);
+    let version = db.catalog_version();
+    s.execute("DROP TRIGGER log_n ON chain").unwrap();
+    assert!(db.catalog_version() > version);
+}
+
+#[test]
+fn rows_on_delete_actions_change_fire_their_tables_triggers() {
+    let (_dir, mut s) = accounts();
+    for sql in [
+        "CREATE TABLE owners (id INT PRIMARY KEY)",
+        "CREATE TABLE pets (id INT PRIMARY KEY, owner INT REFERENCES owners ON DELETE CASCADE)",
+        "CREATE TABLE toys (id INT PRIMARY KEY, owner INT REFERENCES owners ON DELETE SET NULL)",
+        "CREATE TABLE changes (n SERIAL PRIMARY KEY, tbl TEXT, id INT, old_owner INT, new_owner INT)",
+        "CREATE TRIGGER pets_audit AFTER DELETE ON pets FOR EACH ROW
+            INSERT INTO changes (tbl, id, old_owner) VALUES ('pets', OLD.id, OLD.owner)",
+        "CREATE TRIGGER toys_audit AFTER UPDATE ON toys FOR EACH ROW
+            INSERT INTO changes (tbl, id, old_owner, new_owner) VALUES ('toys', OLD.id, OLD.owner, NEW.owner)",
+        "INSERT INTO owners VALUES (1), (2)",
+        "INSERT INTO pets VALUES (10, 1), (11, 2)",
+        "INSERT INTO toys VALUES (20, 1), (21, 2)",
+        "DELETE FROM owners WHERE id = 1",
+    ] {
+        s.execute(sql).unwrap();
+    }
+    let change = |tbl: &str, id: i64, new_owner: Value| {
+        vec![Value::Text(tbl.into()), Value::Int(id), Value::Int(1), new_owner]
+    };
+    assert_eq!(
+        query(&mut s, "SELECT tbl, id, old_owner, new_owner FROM changes ORDER BY n"),
+        vec![change("pets", 10, Value::Null), change("toys", 20, Value::Null)]
+    );
+    // A BEFORE trigger skipping a row an action changes leaves it referencing nothing.
+    s.execute("CREATE TRIGGER keep BEFORE DELETE ON pets FOR EACH ROW EXECUTE FUNCTION keep()").unwrap();
+    fails(&mut s, "DELETE FROM owners WHERE id = 2", "foreign key");
+    assert_eq!(query(&mut s, "SELECT count(*) FROM changes"), ints(&[&[2]]));
+    s.execute("DROP TRIGGER keep ON pets").unwrap();
+    s.execute("DELETE FROM owners WHERE id = 2").unwrap();
+    assert_eq!(query(&mut s, "SELECT count(*) FROM changes"), ints(&[&[4]]));
+}
+
+#[test]
+fn definitions_are_kept_and_functions_registered_again() {
+    let (dir, s) = accounts();
+    drop(s);
+    // Definitions are in the catalog; functions must be registered again.
+    let db = open(dir.path());
+    let mut s = db.session();
+    assert_eq!(db.table_def("accounts").unwrap().triggers.len(), 4);
+    fails(&mut s, "INSERT INTO accounts VALUES (7, 'dan', -1)", "function clamp does not exist");
+    register(&db);
+    s.execute("INSERT INTO accounts VALUES (7, 'dan', -1)").unwrap();
+    assert_eq!(query(&mut s, "SELECT balance FROM accounts WHERE id = 7"), ints(&[&[0]]));
+    assert_eq!(counts(&mut s), ints(&[&[1, 1], &[2, 0]]));
+}
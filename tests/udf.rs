@@ -0,0 +1,172 @@
+//! Scalar, aggregate and table functions registered on the database.
+
+mod common;
+
+use anyhow::{bail, Result};
+use common::{open, query};
+use rustdb::query::function::{ParamType, Signature};
+use rustdb::query::udf::{AggregateUdf, ScalarUdf, TableUdf};
+use rustdb::storage::{ColumnType, Value};
+use rustdb::{Database, Session};
+use std::sync::Arc;
+use tempfile::TempDir;
+
+/// `score(points INT, weight FLOAT)`.
+struct Score;
+impl ScalarUdf for Score {
+    fn signatures(&self) -> &[Signature] {
+        &[Signature {
+            params: &[ParamType::Is(ColumnType::Int), ParamType::Is(ColumnType::Float)],
+            variadic: false,
+            result: ParamType::Is(ColumnType::Float),
+        }]
+    }
+    fn call(&self, args: &[Value]) -> Result<Value> {
+        match (args[0].as_f64(), args[1].as_f64()) {
+            (Some(p), Some(w)) if w >= 0.0 => Ok(Value::Float(p * w)),
+            _ => bail!("weight must not be negative"),
+        }
+    }
+}
+
+/// Returns a value of the wrong type.
+struct Broken;
+impl ScalarUdf for Broken {
+    fn signatures(&self) -> &[Signature] {
+        &[Signature {
+            params: &[],
+            variadic: false,
+            result: ParamType::Is(ColumnType::Int),
+        }]
+    }
+    fn call(&self, _args: &[Value]) -> Result<Value> {
+        Ok(Value::Text("x".into()))
+    }
+}
+
+/// `weighted_avg(value, weight)`; state is (sum of value * weight, sum of weights).
+struct WeightedAvg;
+impl AggregateUdf for WeightedAvg {
+    fn signatures(&self) -> &[Signature] {
+        &[Signature {
+            params: &[ParamType::Is(ColumnType::Float), ParamType::Is(ColumnType::Float)],
+            variadic: false,
+            result: ParamType::Is(ColumnType::Float),
+        }]
+    }
+    fn init(&self) -> Vec<Value> {
+        vec![Value::Float(0.0), Value::Float(0.0)]
+    }
+    fn accumulate(&self, state: &mut Vec<Value>, args: &[Value]) -> Result<()> {
+        let (v, w) = (args[0].as_f64().unwrap_or(0.0), args[1].as_f64().unwrap_or(0.0));
+        let other = vec![Value::Float(v * w), Value::Float(w)];
+        self.merge(state, other)
+    }
+    fn merge(&self, state: &mut Vec<Value>, other: Vec<Value>) -> Result<()> {
+        for (s, o) in state.iter_mut().zip(other) {
+            *s = Value::Float(s.as_f64().unwrap_or(0.0) + o.as_f64().unwrap_or(0.0));
+        }
+        Ok(())
+    }
+    fn finalize(&self, state: Vec<Value>) -> Result<Value> {
+        Ok(match state[1].as_f64() {
+            Some(w) if w != 0.0 => Value::Float(state[0].as_f64().unwrap_or(0.0) / w),
+            _ => Value::Null,
+        })
+    }
+}
+
+/// `series(n INT)`: rows (1, '#1') to (n, '#n').
+struct Series;
+impl TableUdf for Series {
+    fn params(&self) -> &[ColumnType] {
+        &[ColumnType::Int]
+    }
+    fn columns(&self) -> Vec<(String, ColumnType)> {
+        vec![("i".into(), ColumnType::Int), ("label".into(), ColumnType::Text)]
+    }
+    fn call(&self, args: &[Value]) -> Result<Vec<Vec<Value>>> {
+        let Value::Int(n) = args[0] else {
+            return Ok(vec![]);
+        };
+        Ok((1..=n).map(|i| vec![Value::Int(i), Value::Text(format!("#{}", i))]).collect())
+    }
+}
+
+fn register(db: &Arc<Database>) {
+    db.register_scalar_function("Score", Score).unwrap();
+    db.register_scalar_function("broken", Broken).unwrap();
+    db.register_aggregate_function("weighted_avg", WeightedAvg).unwrap();
+    db.register_table_function("series", Series).unwrap();
+}
+
+/// A database with the functions above and a table of weighted points.
+fn scores() -> (TempDir, Session) {
+    let dir = tempfile::tempdir().unwrap();
+    let db = open(dir.path());
+    register(&db);
+    let mut s = db.session();
+    s.execute("CREATE TABLE r (id INT PRIMARY KEY, team TEXT, points INT, weight FLOAT)").unwrap();
+    s.execute("INSERT INTO r VALUES (1, 'a', 10, 1.0), (2, 'a', 20, 3.0), (3, 'b', 5, 2.0), (4, 'b', NULL, 1.0)")
+        .unwrap();
+    (dir, s)
+}
+
+#[test]
+fn registering_a_function_replans_cached_statements() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = open(dir.path());
+    let version = db.catalog_version();
+    register(&db);
+    // Each function registered plans cached statements again.
+    assert_eq!(db.catalog_version(), version + 4);
+    let err = db.register_scalar_function("score", Score).unwrap_err();
+    assert_eq!(err.to_string(), "function score already exists");
+    assert!(db.register_aggregate_function("sum", WeightedAvg).is_err());
+    assert_eq!(db.catalog_version(), version + 4);
+}
+
+#[test]
+fn scalar_functions_are_case_insensitive() {
+    let (_dir, mut s) = scores();
+    let r = s.execute("SELECT id, SCORE(points, weight), score(2, 3) FROM r ORDER BY id").unwrap();
+    assert_eq!(r.columns[1], ("score".to_string(), ColumnType::Float));
+    assert_eq!(r.rows[1], vec![Value::Int(2), Value::Float(60.0), Value::Float(6.0)]);
+    assert_eq!(r.rows[3][1], Value::Null);
+}
+
+#[test]
+fn aggregate_functions_in_groups_and_windows() {
+    let (_dir, mut s) = scores();
+    let r = query(&mut s, "SELECT team, weighted_avg(points, weight) FROM r GROUP BY team ORDER BY team");
+    assert_eq!(r[0], vec![Value::Text("a".into()), Value::Float(17.5)]);
+    assert_eq!(r[1], vec![Value::Text("b".into()), Value::Float(5.0)]);
+    let r = query(&mut s, "SELECT id, weighted_avg(points, weight) OVER (ORDER BY id) FROM r ORDER BY id");
+    assert_eq!(r[1][1], Value::Float(17.5));
+}
+
+#[test]
+fn table_functions_in_from() {
+    let (_dir, mut s) = scores();
+    let r = s.execute("SELECT s.i, s.label FROM series(3) AS s WHERE s.i > 1").unwrap();
+    let label = |i: i64| vec![Value::Int(i), Value::Text(format!("#{}", i))];
+    assert_eq!(r.rows, vec![label(2), label(3)]);
+    let r = query(&mut s, "SELECT r.id, x.label FROM r JOIN series(2) x ON r.id = x.i ORDER BY r.id");
+    assert_eq!(r.len(), 2);
+}
+
+#[test]
+fn arguments_are_checked_when_planned_and_results_when_run() {
+    let (_dir, mut s) = scores();
+    for (sql, message) in [
+        ("SELECT score(team, weight) FROM r", "function score(TEXT, FLOAT) does not exist"),
+        ("SELECT weighted_avg(team, 1) FROM r", "function weighted_avg(TEXT, INT) does not exist"),
+        ("SELECT * FROM series('x')", "function series(TEXT) does not exist"),
+        ("SELECT id FROM r WHERE weighted_avg(1, 1) > 0", "aggregate function calls are not allowed here"),
+        ("SELECT score(1, -1.0)", "weight must not be negative"),
+        ("SELECT broken()", "function broken returned a wrong value: expected INT value, got TEXT"),
+    ] {
+        let err = s.execute(sql).unwrap_err();
+        assert!(err.to_string().contains(message), "{}: {}", sql, err);
+    }
+}
@@ -0,0 +1,202 @@
+//! Views, materialized views and incrementally maintained ones, and the relations they depend on.
+
+mod common;
+
+use common::{open, query};
+use rustdb::storage::Value;
+use rustdb::Session;
+use tempfile::TempDir;
+
+fn ints(rows: &[(i64, i64)]) -> Vec<Vec<Value>> {
+    rows.iter().map(|(a, b)| vec![Value::Int(*a), Value::Int(*b)]).collect()
+}
+
+/// Four sales in two regions, one without an amount.
+fn sales() -> (TempDir, Session) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut s = open(dir.path()).session();
+    s.execute("CREATE TABLE sales (id INT PRIMARY KEY, region TEXT, amount INT)").unwrap();
+    s.execute("INSERT INTO sales VALUES (1, 'east', 10), (2, 'east', 30), (3, 'west', 5), (4, 'west', NULL)")
+        .unwrap();
+    (dir, s)
+}
+
+#[test]
+fn views_are_planned_where_they_are_read() {
+    let (_dir, mut s) = sales();
+    // So a view sees every change, but not the WITH queries of the statement reading it.
+    s.execute("CREATE VIEW big (id, amt) AS SELECT id, amount FROM sales WHERE amount > 8").unwrap();
+    assert_eq!(query(&mut s, "SELECT * FROM big ORDER BY id"), ints(&[(1, 10), (2, 30)]));
+    s.execute("UPDATE sales SET amount = 9 WHERE id = 3").unwrap();
+    let joined = "SELECT b.id, s.id FROM big b JOIN sales s ON s.amount = b.amt - 1 ORDER BY 1";
+    assert_eq!(query(&mut s, joined), ints(&[(1, 3)]));
+    let shadowed = "WITH sales AS (SELECT 1 AS id, 'x' AS region, 100 AS amount) SELECT count(*) FROM big";
+    assert_eq!(query(&mut s, shadowed), vec![vec![Value::Int(3)]]);
+}
+
+#[test]
+fn views_are_read_only_and_share_names_with_tables() {
+    let (_dir, mut s) = sales();
+    s.execute("CREATE VIEW big (id, amt) AS SELECT id, amount FROM sales WHERE amount > 8").unwrap();
+    assert!(s.execute("INSERT INTO big VALUES (9, 9)").is_err());
+    assert!(s.execute("CREATE VIEW sales AS SELECT 1").is_err());
+    assert!(s.execute("CREATE TABLE big (a INT)").is_err());
+    s.execute("DROP VIEW big").unwrap();
+    s.execute("DROP VIEW IF EXISTS big").unwrap();
+}
+
+#[test]
+fn a_view_reading_itself_fails_when_read() {
+    let (_dir, mut s) = sales();
+    s.execute("CREATE VIEW big AS SELECT id FROM sales").unwrap();
+    s.execute("CREATE OR REPLACE VIEW big AS SELECT * FROM big").unwrap();
+    let err = s.execute("SELECT * FROM big").unwrap_err();
+    assert!(err.to_string().contains("infinite recursion"), "{}", err);
+}
+
+#[test]
+fn a_materialized_view_keeps_the_rows_of_its_last_refresh() {
+    let (_dir, mut s) = sales();
+    s.execute("CREATE MATERIALIZED VIEW totals AS SELECT id % 2 AS odd, sum(amount) FROM sales GROUP BY 1")
+        .unwrap();
+    let totals = "SELECT * FROM totals ORDER BY odd";
+    assert_eq!(query(&mut s, totals), ints(&[(0, 30), (1, 15)]));
+    s.execute("INSERT INTO sales VALUES (5, 'north', 100)").unwrap();
+    assert_eq!(query(&mut s, totals), ints(&[(0, 30), (1, 15)]));
+    s.execute("REFRESH MATERIALIZED VIEW totals").unwrap();
+    assert_eq!(query(&mut s, totals), ints(&[(0, 30), (1, 115)]));
+    s.execute("DELETE FROM sales WHERE id = 2").unwrap();
+    s.execute("REFRESH MATERIALIZED VIEW CONCURRENTLY totals").unwrap();
+    assert_eq!(query(&mut s, totals), vec![vec![Value::Int(0), Value::Null], vec![Value::Int(1), Value::Int(115)]]);
+    assert!(s.execute("DELETE FROM totals").is_err());
+    assert!(s.execute("DROP TABLE totals").is_err());
+    assert!(s.execute("REFRESH MATERIALIZED VIEW sales").is_err());
+}
+
+const GROUPED: &str = "SELECT region, count(*) AS n, count(amount) AS counted, sum(amount) AS total FROM sales \
+                       GROUP BY region";
+
+/// Compares the incremental view `live` with `GROUPED` run afresh.
+fn check(s: &mut Session) {
+    let expected = query(s, &format!("{} ORDER BY region", GROUPED));
+    assert_eq!(query(s, "SELECT * FROM live ORDER BY region"), expected);
+}
+
+#[test]
+fn an_incremental_view_follows_each_change_to_its_table() {
+    let (_dir, mut s) = sales();
+    s.execute(&format!("CREATE MATERIALIZED VIEW live WITH (incremental = true) AS {}", GROUPED)).unwrap();
+    check(&mut s);
+    assert_eq!(query(&mut s, "SELECT * FROM live").len(), 2);
+    s.execute("INSERT INTO sales VALUES (6, 'east', 1), (7, 'south', NULL), (8, 'south', 4)").unwrap();
+    check(&mut s);
+    s.execute("UPDATE sales SET region = 'north' WHERE region = 'west'").unwrap();
+    s.execute("UPDATE sales SET amount = amount * 2 WHERE id = 6").unwrap();
+    check(&mut s);
+    s.execute("INSERT INTO sales VALUES (8, 'east', 50) ON CONFLICT (id) DO UPDATE SET amount = 40").unwrap();
+    check(&mut s);
+    s.execute("DELETE FROM sales WHERE amount IS NULL OR amount > 20").unwrap();
+    check(&mut s);
+    s.execute("REFRESH MATERIALIZED VIEW CONCURRENTLY live").unwrap();
+    check(&mut s);
+    assert!(s.execute("DROP TABLE sales").is_err());
+}
+
+#[test]
+fn an_incremental_view_follows_rollbacks() {
+    let (_dir, mut s) = sales();
+    s.execute(&format!("CREATE MATERIALIZED VIEW live WITH (incremental = true) AS {}", GROUPED)).unwrap();
+    s.execute("BEGIN").unwrap();
+    s.execute("DELETE FROM sales WHERE region = 'east'").unwrap();
+    check(&mut s);
+    assert_eq!(query(&mut s, "SELECT count(*) FROM live WHERE region = 'east'"), vec![vec![Value::Int(0)]]);
+    s.execute("ROLLBACK").unwrap();
+    check(&mut s);
+}
+
+#[test]
+fn incremental_views_refuse_aggregates_they_cannot_maintain() {
+    let (_dir, mut s) = sales();
+    let bad = "CREATE MATERIALIZED VIEW bad WITH (incremental = true) AS \
+               SELECT region, max(amount) FROM sales GROUP BY region";
+    let err = s.execute(bad).unwrap_err();
+    assert!(err.to_string().contains("incremental maintenance"), "{}", err);
+    assert!(s.database().table_def("bad").is_none());
+}
+
+#[test]
+fn views_outlive_the_server() {
+    let (dir, mut s) = sales();
+    s.execute("CREATE VIEW big AS SELECT id FROM sales WHERE amount > 8").unwrap();
+    s.execute("CREATE MATERIALIZED VIEW totals AS SELECT id % 2 AS odd, sum(amount) FROM sales GROUP BY 1")
+        .unwrap();
+    s.execute(&format!("CREATE MATERIALIZED VIEW live WITH (incremental = true) AS {}", GROUPED)).unwrap();
+    drop(s);
+    let mut s = open(dir.path()).session();
+    assert_eq!(query(&mut s, "SELECT * FROM big ORDER BY id"), vec![vec![Value::Int(1)], vec![Value::Int(2)]]);
+    check(&mut s);
+    s.execute("INSERT INTO sales VALUES (20, 'east', 7)").unwrap();
+    check(&mut s);
+    assert_eq!(query(&mut s, "SELECT * FROM totals ORDER BY odd"), ints(&[(0, 30), (1, 15)]));
+    assert!(s.execute("DROP VIEW live").is_err());
+    s.execute("DROP MATERIALIZED VIEW live, totals").unwrap();
+    s.execute("DROP MATERIALIZED VIEW IF EXISTS live").unwrap();
+    s.execute("DROP VIEW big").unwrap();
+    s.execute("DROP TABLE sales").unwrap();
+}
+
+#[test]
+fn relations_a_view_reads_cannot_be_dropped() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = open(dir.path());
+    let mut s = db.session();
+    s.execute("CREATE TABLE t (id INT PRIMARY KEY, a INT)").unwrap();
+    s.execute("INSERT INTO t VALUES (1, 10), (2, 20)").unwrap();
+    s.execute("CREATE VIEW v AS SELECT id, a FROM t WHERE a > 10").unwrap();
+    s.execute("CREATE VIEW w AS SELECT count(*) FROM v").unwrap();
+    s.execute("CREATE MATERIALIZED VIEW m AS SELECT sum(a) FROM t").unwrap();
+    assert_eq!(db.view_def("w").unwrap().depends_on, vec!["v".to_string()]);
+
+    let err = s.execute("DROP TABLE t").unwrap_err();
+    assert!(err.to_string().contains("materialized view m depends on it"), "{}", err);
+    s.execute("DROP MATERIALIZED VIEW m").unwrap();
+    let err = s.execute("DROP TABLE t").unwrap_err();
+    assert!(err.to_string().contains("view v depends on it"), "{}", err);
+    let err = s.execute("DROP VIEW v").unwrap_err();
+    assert!(err.to_string().contains("view w depends on it"), "{}", err);
+    s.execute("DROP VIEW w, v").unwrap();
+    s.execute("DROP TABLE t").unwrap();
+}
+
+#[test]
+fn a_table_of_the_same_name_is_new_to_views_created_over_it() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut s = open(dir.path()).session();
+    s.execute("CREATE TABLE t (id INT PRIMARY KEY, a INT)").unwrap();
+    s.execute("CREATE VIEW v AS SELECT id, a FROM t").unwrap();
+    s.execute("DROP VIEW v").unwrap();
+    s.execute("DROP TABLE t").unwrap();
+    s.execute("CREATE TABLE t (id INT PRIMARY KEY, b TEXT)").unwrap();
+    s.execute("INSERT INTO t VALUES (1, 'x')").unwrap();
+    assert!(s.execute("CREATE VIEW v AS SELECT id, a FROM t").is_err());
+    s.execute("CREATE VIEW v AS SELECT b FROM t").unwrap();
+    s.execute("CREATE MATERIALIZED VIEW m AS SELECT count(*) FROM t").unwrap();
+    assert_eq!(query(&mut s, "SELECT * FROM v"), vec![vec![Value::Text("x".into())]]);
+    assert_eq!(query(&mut s, "SELECT * FROM m"), vec![vec![Value::Int(1)]]);
+}
+
+#[test]
+fn view_dependencies_outlive_the_server() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut s = open(dir.path()).session();
+    s.execute("CREATE TABLE t (id INT PRIMARY KEY, b TEXT)").unwrap();
+    s.execute("CREATE VIEW v AS SELECT b FROM t").unwrap();
+    s.execute("CREATE MATERIALIZED VIEW m AS SELECT count(*) FROM t").unwrap();
+    drop(s);
+    let mut s = open(dir.path()).session();
+    assert!(s.execute("DROP TABLE t").is_err());
+    s.execute("DROP VIEW v").unwrap();
+    assert!(s.execute("DROP TABLE t").is_err());
+    s.execute("DROP MATERIALIZED VIEW m").unwrap();
+    s.execute("DROP TABLE t").unwrap();
+}
@@ -0,0 +1,166 @@
+//! Window functions: ranking, offsets, frames, and how windows share sorts.
+
+mod common;
+
+use common::{config, open, plan_text, query};
+use rustdb::storage::Value;
+use rustdb::{Config, Database, Session};
+use tempfile::TempDir;
+
+fn ints(v: &[i64]) -> Vec<Value> {
+    v.iter().map(|&n| Value::Int(n)).collect()
+}
+
+fn column(rows: &[Vec<Value>], c: usize) -> Vec<Value> {
+    rows.iter().map(|r| r[c].clone()).collect()
+}
+
+/// Seven employees in three departments, with a tie in `eng`.
+fn employees() -> (TempDir, Session) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut s = open(dir.path()).session();
+    s.execute("CREATE TABLE emp (id INT PRIMARY KEY, dept TEXT, salary INT)").unwrap();
+    s.execute(
+        "INSERT INTO emp VALUES (1, 'eng', 100), (2, 'eng', 120), (3, 'eng', 120), (4, 'ops', 90), \
+         (5, 'ops', 70), (6, 'eng', 80), (7, 'hr', 60)",
+    )
+    .unwrap();
+    (dir, s)
+}
+
+#[test]
+fn ranking_functions() {
+    let (_dir, mut s) = employees();
+    let rows = query(
+        &mut s,
+        "SELECT id, row_number() OVER (PARTITION BY dept ORDER BY salary DESC, id), \
+         rank() OVER (PARTITION BY dept ORDER BY salary DESC), \
+         dense_rank() OVER (PARTITION BY dept ORDER BY salary DESC) FROM emp ORDER BY id",
+    );
+    assert_eq!(
+        rows,
+        vec![
+            ints(&[1, 3, 3, 2]),
+            ints(&[2, 1, 1, 1]),
+            ints(&[3, 2, 1, 1]),
+            ints(&[4, 1, 1, 1]),
+            ints(&[5, 2, 2, 2]),
+            ints(&[6, 4, 4, 3]),
+            ints(&[7, 1, 1, 1]),
+        ]
+    );
+}
+
+#[test]
+fn offset_and_value_functions() {
+    let (_dir, mut s) = employees();
+    let rows = query(
+        &mut s,
+        "SELECT id, lag(salary) OVER (ORDER BY id), lead(salary, 2, 0) OVER (ORDER BY id), \
+         first_value(salary) OVER (PARTITION BY dept ORDER BY id), ntile(3) OVER (ORDER BY id) \
+         FROM emp ORDER BY id",
+    );
+    let mut lag = vec![Value::Null];
+    lag.extend(ints(&[100, 120, 120, 90, 70, 80]));
+    assert_eq!(column(&rows, 1), lag);
+    assert_eq!(column(&rows, 2), ints(&[120, 90, 70, 80, 60, 0, 0]));
+    assert_eq!(column(&rows, 3), ints(&[100, 100, 100, 90, 90, 100, 60]));
+    assert_eq!(column(&rows, 4), ints(&[1, 1, 1, 2, 2, 3, 3]));
+}
+
+#[test]
+fn aggregates_over_frames() {
+    let (_dir, mut s) = employees();
+    // Running totals: the default frame, explicit ROWS and RANGE frames, the whole partition.
+    let rows = query(
+        &mut s,
+        "SELECT id, sum(salary) OVER (PARTITION BY dept ORDER BY id) AS running, \
+         avg(salary) OVER (ORDER BY id ROWS BETWEEN 1 PRECEDING AND CURRENT ROW), \
+         count(*) OVER (ORDER BY salary RANGE BETWEEN 10 PRECEDING AND 10 FOLLOWING), \
+         sum(salary) OVER () FROM emp ORDER BY running DESC, id",
+    );
+    assert_eq!(column(&rows, 0), ints(&[6, 3, 2, 5, 1, 4, 7]));
+    assert_eq!(column(&rows, 1), ints(&[420, 340, 220, 160, 100, 90, 60]));
+    assert_eq!(rows[0][2], Value::Float(75.0));
+    assert_eq!(rows[6][2], Value::Float(70.0));
+    // Salaries within 10 of 90: 80, 90, 100.
+    assert_eq!(rows[5][3], Value::Int(3));
+    assert!(rows.iter().all(|r| r[4] == Value::Int(640)));
+}
+
+#[test]
+fn windows_run_after_grouping() {
+    let (_dir, mut s) = employees();
+    let rows = query(
+        &mut s,
+        "SELECT dept, sum(salary), rank() OVER (ORDER BY sum(salary) DESC) FROM emp GROUP BY dept \
+         ORDER BY 3",
+    );
+    let text = |v: &str| Value::Text(v.into());
+    assert_eq!(
+        rows,
+        vec![
+            vec![text("eng"), Value::Int(420), Value::Int(1)],
+            vec![text("ops"), Value::Int(160), Value::Int(2)],
+            vec![text("hr"), Value::Int(60), Value::Int(3)],
+        ]
+    );
+}
+
+#[test]
+fn invalid_windows_are_rejected() {
+    let (_dir, mut s) = employees();
+    for (sql, error) in [
+        ("SELECT id FROM emp WHERE row_number() OVER () > 1", "not allowed here"),
+        ("SELECT row_number() FROM emp", "requires an OVER clause"),
+        ("SELECT dept FROM emp GROUP BY dept HAVING rank() OVER () > 1", "not allowed here"),
+        ("SELECT sum(salary) OVER (ORDER BY id ROWS 1 FOLLOWING) FROM emp", "cannot end with"),
+        ("SELECT sum(salary) OVER (ORDER BY dept RANGE 1 PRECEDING) FROM emp", "numeric"),
+        ("SELECT count(DISTINCT dept) OVER () FROM emp", "DISTINCT"),
+        ("SELECT ntile(2.5) OVER () FROM emp", "does not exist"),
+    ] {
+        let err = s.execute(sql).unwrap_err();
+        assert!(err.to_string().contains(error), "{}: {}", sql, err);
+    }
+}
+
+/// 4000 rows in 40 groups of `g`, in a database with 16 KB of work memory.
+fn large_table() -> (TempDir, Session) {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(Config { work_mem_kb: 16, ..config(dir.path()) }).unwrap();
+    let mut s = db.session();
+    s.execute("CREATE TABLE t (id INT PRIMARY KEY, g INT, v INT)").unwrap();
+    let values: Vec<String> = (0..4000).map(|i| format!("({}, {}, {})", i, i % 40, i % 7)).collect();
+    s.execute(&format!("INSERT INTO t VALUES {}", values.join(", "))).unwrap();
+    s.execute("ANALYZE").unwrap();
+    (dir, s)
+}
+
+#[test]
+fn windows_share_one_sort_over_a_large_input() {
+    let (_dir, mut s) = large_table();
+    // Both windows and the final ORDER BY are satisfied by one sort on (g, id).
+    let sql = "SELECT g, id, sum(v) OVER (PARTITION BY g ORDER BY id), count(*) OVER (PARTITION BY g) \
+               FROM t ORDER BY g, id";
+    let text = plan_text(&mut s, &format!("EXPLAIN {}", sql));
+    assert_eq!(text.iter().filter(|l| l.contains("WindowAgg")).count(), 2, "{:?}", text);
+    assert_eq!(text.iter().filter(|l| l.contains("->  Sort")).count(), 1, "{:?}", text);
+    let rows = query(&mut s, sql);
+    assert_eq!(rows.len(), 4000);
+    let mut running = [0i64; 40];
+    for r in &rows {
+        let [Value::Int(g), Value::Int(id), Value::Int(sum), Value::Int(n)] = r[..] else {
+            panic!("{:?}", r);
+        };
+        running[g as usize] += id % 7;
+        assert_eq!(sum, running[g as usize], "{:?}", r);
+        assert_eq!(n, 100);
+    }
+}
+
+#[test]
+fn window_in_key_order_needs_no_sort() {
+    let (_dir, mut s) = large_table();
+    let text = plan_text(&mut s, "EXPLAIN SELECT id, row_number() OVER (ORDER BY id) FROM t WHERE id < 10");
+    assert!(!text.iter().any(|l| l.contains("Sort")), "{:?}", text);
+}